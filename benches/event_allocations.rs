@@ -0,0 +1,68 @@
+//! Heap allocations per event on the loopback event path, client and server
+//! together, including quinn's own. Fails if the count regresses.
+//!
+//! cargo bench --features alloc-audit --bench event_allocations
+
+use quic_rs_debug::proton::alloc;
+use quic_rs_debug::proton::handler::EchoHandler;
+use quic_rs_debug::proton::{ProtonClient, ProtonServer, STARTUP_DELAY};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WARMUP_EVENTS: u32 = 200;
+const EVENTS: u32 = 2000;
+// Upper bound per event round trip, client and server. All of these are
+// quinn's but one: a copy of each stream write, the transmit buffer and the
+// endpoint's per datagram bookkeeping, plus the boxed future of the server's
+// `ProtonHandler`. Proton's framing allocates nothing.
+const MAX_ALLOCATIONS_PER_EVENT: f64 = 21.0;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    // Borrow a free port for the server
+    let addr: SocketAddr = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let cert = rustls::Certificate(cert.serialize_der()?);
+    let server = Arc::new(ProtonServer::new(addr, cert, key, Arc::new(EchoHandler))?);
+    tokio::spawn(async move { server.run().await });
+
+    let mut client = ProtonClient::new("127.0.0.1:0".parse()?)?;
+    tokio::time::sleep(STARTUP_DELAY + Duration::from_millis(500)).await;
+    let mut connection = client.connect(addr, Some(Duration::ZERO)).await?;
+
+    for _ in 0..WARMUP_EVENTS {
+        connection.send_event().await?;
+    }
+    let allocations = alloc::allocations();
+    let bytes = alloc::allocated_bytes();
+    let start = Instant::now();
+    for _ in 0..EVENTS {
+        connection.send_event().await?;
+    }
+    let elapsed = start.elapsed();
+    let per_event = (alloc::allocations() - allocations) as f64 / EVENTS as f64;
+    let bytes_per_event = (alloc::allocated_bytes() - bytes) as f64 / EVENTS as f64;
+    connection.close().await;
+
+    println!(
+        "{} events in {:?}: {:.2} allocations ({:.0} bytes) per event, bound {}",
+        EVENTS, elapsed, per_event, bytes_per_event, MAX_ALLOCATIONS_PER_EVENT
+    );
+    if per_event > MAX_ALLOCATIONS_PER_EVENT {
+        return Err(format!(
+            "{:.2} allocations per event exceeds the bound of {}",
+            per_event, MAX_ALLOCATIONS_PER_EVENT
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! Throughput of the wire layer: the header, HELLO and settings codecs,
+//! payload chunk checksums and batch sizing.
+//!
+//! cargo bench --bench wire
+//!
+//! Criterion keeps the previous run under target/criterion and reports the
+//! change against it. Save and compare a baseline to catch regressions:
+//!
+//! cargo bench --bench wire -- --save-baseline main
+//! cargo bench --bench wire -- --baseline main
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use quic_rs_debug::proton::batching::BatchPolicy;
+use quic_rs_debug::proton::frame::{Headers, TraceParent};
+use quic_rs_debug::proton::hello::PeerInfo;
+use quic_rs_debug::proton::payload::{crc32, PAYLOAD_CHUNK_SIZE};
+use quic_rs_debug::proton::settings::{AckMode, ClientSettings, PowerMode};
+use std::time::Duration;
+
+fn sample_headers() -> Headers {
+    let mut headers = Headers::new();
+    headers.set_traceparent(&TraceParent::generate());
+    headers.insert("tenant", "acme").unwrap();
+    headers.insert("content-type", "application/json").unwrap();
+    headers
+}
+
+fn headers(c: &mut Criterion) {
+    let headers = sample_headers();
+    let encoded = headers.encode();
+    let mut group = c.benchmark_group("headers");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("encode", |b| b.iter(|| black_box(&headers).encode()));
+    let mut frame = Vec::with_capacity(64);
+    group.bench_function("encode_into", |b| {
+        b.iter(|| {
+            frame.clear();
+            black_box(&headers).encode_into(&mut frame);
+            black_box(&frame);
+        })
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| Headers::decode(black_box(&encoded)).unwrap())
+    });
+    group.finish();
+}
+
+fn hello(c: &mut Criterion) {
+    let mut peer = PeerInfo::local("bench/1.0");
+    peer.tenant = Some("acme".to_string());
+    let hello = peer.to_headers().encode();
+    let mut group = c.benchmark_group("hello");
+    group.throughput(Throughput::Bytes(hello.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| black_box(&peer).to_headers().encode())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let (headers, _) = Headers::decode(black_box(&hello)).unwrap();
+            PeerInfo::from_headers(&headers)
+        })
+    });
+    group.finish();
+}
+
+fn settings(c: &mut Criterion) {
+    let settings = ClientSettings {
+        event_batch_size: Some(64),
+        event_rate_limit: Some(500.0),
+        ack_mode: Some(AckMode::Auto),
+        power_mode: Some(PowerMode::Low),
+    };
+    let pushed = settings.to_headers().encode();
+    let mut group = c.benchmark_group("settings");
+    group.throughput(Throughput::Bytes(pushed.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| black_box(&settings).to_headers().encode())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let (headers, _) = Headers::decode(black_box(&pushed)).unwrap();
+            ClientSettings::from_headers(&headers)
+        })
+    });
+    group.finish();
+}
+
+fn payload(c: &mut Criterion) {
+    let chunk = vec![0xa5u8; PAYLOAD_CHUNK_SIZE];
+    let mut group = c.benchmark_group("payload");
+    group.throughput(Throughput::Bytes(chunk.len() as u64));
+    group.bench_function("crc32_chunk", |b| b.iter(|| crc32(black_box(&chunk))));
+    group.finish();
+}
+
+fn batching(c: &mut Criterion) {
+    let frame_len = 4 + sample_headers().encoded_len();
+    c.bench_function("batching/adaptive", |b| {
+        b.iter(|| {
+            BatchPolicy::adaptive(
+                black_box(Duration::from_millis(20)),
+                black_box(120_000),
+                black_box(frame_len),
+            )
+        })
+    });
+}
+
+criterion_group!(wire, headers, hello, settings, payload, batching);
+criterion_main!(wire);
@@ -0,0 +1,158 @@
+use quic_rs_debug::proton::QUOTA_EXCEEDED;
+use quic_rs_debug::ProtonConnection;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::info;
+
+// Largest length-delimited record accepted, to bound memory
+const MAX_RECORD_LEN: u32 = 16 * 1024 * 1024;
+
+/// Where the bridge reads records from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Stdin,
+    /// Listen on this TCP address and serve producers one at a time
+    Tcp(String),
+    /// Listen on this Unix socket path and serve producers one at a time
+    Unix(PathBuf),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "stdin" || s == "-" => Ok(Source::Stdin),
+            Some(("tcp", addr)) => Ok(Source::Tcp(addr.to_string())),
+            Some(("unix", path)) => Ok(Source::Unix(PathBuf::from(path))),
+            _ => Err(format!(
+                "unknown source '{}', expected stdin, tcp:<addr> or unix:<path>",
+                s
+            )),
+        }
+    }
+}
+
+/// How records are delimited in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One record per line; empty lines are skipped
+    Lines,
+    /// Each record is preceded by its length as a big-endian u32
+    Length,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(Framing::Lines),
+            "length" => Ok(Framing::Length),
+            _ => Err(format!("unknown framing '{}', expected lines or length", s)),
+        }
+    }
+}
+
+/// Forwards every record read from `source` as the payload of a proton
+/// event. The next record is read only once the previous one has been
+/// acknowledged, so a producer that outpaces the server is held back by its
+/// own socket or pipe buffer filling up.
+pub async fn run(
+    connection: &mut ProtonConnection,
+    source: &Source,
+    framing: Framing,
+) -> Result<(), Box<dyn Error>> {
+    match source {
+        Source::Stdin => {
+            let records = forward(connection, BufReader::new(tokio::io::stdin()), framing).await?;
+            info!("Forwarded {} records from stdin", records);
+        }
+        Source::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Bridging records from tcp:{}", listener.local_addr()?);
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                info!("Producer {} connected", peer);
+                let records = forward(connection, BufReader::new(stream), framing).await?;
+                info!("Producer {} done after {} records", peer, records);
+            }
+        }
+        Source::Unix(path) => {
+            let listener = UnixListener::bind(path)?;
+            info!("Bridging records from unix:{}", path.display());
+            loop {
+                let (stream, _) = listener.accept().await?;
+                info!("Producer connected");
+                let records = forward(connection, BufReader::new(stream), framing).await?;
+                info!("Producer done after {} records", records);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Forward records until the input ends, returning how many were sent
+async fn forward<R: AsyncBufRead + Unpin>(
+    connection: &mut ProtonConnection,
+    mut input: R,
+    framing: Framing,
+) -> Result<u64, Box<dyn Error>> {
+    let mut record = Vec::new();
+    let mut records = 0;
+    while read_record(&mut input, framing, &mut record).await? {
+        let ack = connection
+            .send_event_stream(&record[..], record.len() as u64)
+            .await?;
+        if ack == QUOTA_EXCEEDED {
+            return Err(format!("server refused record {}: quota exceeded", records + 1).into());
+        }
+        records += 1;
+    }
+    Ok(records)
+}
+
+// Read the next record into `record`. Returns false at the end of the input.
+async fn read_record<R: AsyncBufRead + Unpin>(
+    input: &mut R,
+    framing: Framing,
+    record: &mut Vec<u8>,
+) -> Result<bool, Box<dyn Error>> {
+    record.clear();
+    match framing {
+        Framing::Lines => loop {
+            if input.read_until(b'\n', record).await? == 0 {
+                return Ok(false);
+            }
+            while matches!(record.last(), Some(b'\n' | b'\r')) {
+                record.pop();
+            }
+            if !record.is_empty() {
+                return Ok(true);
+            }
+        },
+        Framing::Length => {
+            let mut len = [0u8; 4];
+            // The input ends where the next length prefix would start
+            match input.read_exact(&mut len).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            let len = u32::from_be_bytes(len);
+            if len > MAX_RECORD_LEN {
+                return Err(format!(
+                    "record of {} bytes exceeds the {} byte limit",
+                    len, MAX_RECORD_LEN
+                )
+                .into());
+            }
+            record.resize(len as usize, 0);
+            input.read_exact(record).await?;
+            Ok(true)
+        }
+    }
+}
@@ -1,5 +1,13 @@
-use crate::proton::client::ProtonConnection;
-use crate::proton::{ProtonClient, IDLE_TIMEOUT};
+use crate::control::{self, ControlLink};
+use quic_rs_debug::proton::frame::{FrameDump, FrameInspector};
+use quic_rs_debug::proton::offline::SendStatus;
+use quic_rs_debug::proton::settings::PowerMode;
+use quic_rs_debug::proton::streams::ChannelKind;
+use quic_rs_debug::proton::timeline::{Timeline, TimelineFormat};
+use quic_rs_debug::proton::transcript::Transcript;
+use quic_rs_debug::proton::{IDLE_TIMEOUT, STREAM_ACTION, STREAM_EVENT, STREAM_STATE_COMMIT};
+use quic_rs_debug::ProtonClient;
+use quic_rs_debug::ProtonConnection;
 use rustyline::completion::{Completer, Pair};
 use rustyline::error::ReadlineError;
 use rustyline::highlight::Highlighter;
@@ -9,24 +17,54 @@ use rustyline::validate::{MatchingBracketValidator, Validator};
 use rustyline::Helper;
 use rustyline::{CompletionType, Config, Context, Editor};
 use std::borrow::Cow::{self, Borrowed};
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::fmt;
 use std::net::SocketAddr;
-use std::time::Duration;
-use tokio::time::sleep;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout};
 
 // Define available commands for completion
 const COMMANDS: &[&str] = &[
     "connect",
     "send_event",
+    "send_payload",
+    "pipeline",
     "commit",
+    "abort",
+    "stream",
     "read_action",
+    "ack",
+    "datagram",
+    "recv_datagram",
     "close",
+    "stats",
+    "rebind",
+    "update_keys",
+    "power",
     "sleep",
+    "debug",
+    "with-delay",
+    "set",
+    "vars",
     "reset",
+    "reconnect",
+    "alias",
+    "unalias",
+    "macro",
+    "macros",
+    "unmacro",
+    "source",
     "help",
     "exit",
 ];
 
+// Macros may run other macros, and scripts source other scripts, up to
+// this depth
+const MAX_MACRO_DEPTH: usize = 8;
+
 // Helper struct for rustyline functionality
 struct ReplHelper {
     validator: MatchingBracketValidator,
@@ -73,7 +111,7 @@ impl Completer for ReplHelper {
             let last_word_start = line[..pos].rfind(last_word).unwrap_or(0);
 
             // Check if we're completing a number prefix
-            if last_word.chars().all(|c| c.is_digit(10)) && pos == line.len() {
+            if last_word.chars().all(|c| c.is_ascii_digit()) && pos == line.len() {
                 (
                     pos,
                     vec![Pair {
@@ -125,16 +163,121 @@ impl Validator for ReplHelper {
 impl Helper for ReplHelper {}
 
 pub struct ClientRepl {
-    client: ProtonClient,
-    server_addr: SocketAddr,
+    // The client connecting to the server, unless attached to another process
+    client: Option<(ProtonClient, SocketAddr)>,
     connection: Option<ProtonConnection>,
+    // Control socket of the client process this REPL is attached to
+    attached: Option<ControlLink>,
     editor: Editor<ReplHelper, FileHistory>,
+    // Persisted in ~/.proton_profile
+    aliases: BTreeMap<String, String>,
+    macros: BTreeMap<String, String>,
+    macro_depth: usize,
+    // Commands that have failed, so scripts can stop at the first
+    failures: usize,
+    // Set with `set <name> = <command>` and substituted for `$name`
+    vars: BTreeMap<String, String>,
+    // Value of the last command that produced one, for `set` to capture
+    result: Option<String>,
+    // Dump every frame on the connection, kept across connects
+    debug_frames: bool,
+    // Artificial latency per stream, kept across connects
+    stream_delays: BTreeMap<u8, Duration>,
+    // Commands and frames recorded for the timeline file written on exit
+    timeline: Option<(Arc<Timeline>, PathBuf)>,
+    // Frames recorded for the transcript file written on exit
+    transcript: Option<(Arc<Transcript>, PathBuf)>,
+}
+
+// Aliases and macros are kept in ~/.proton_profile
+fn profile_path() -> Option<PathBuf> {
+    home::home_dir().map(|home| home.join(".proton_profile"))
+}
+
+// `200ms`, `2s` or `500us`; a bare number is milliseconds
+fn parse_delay(s: &str) -> Option<Duration> {
+    let (value, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((s, "ms"), |i| s.split_at(i));
+    let value = value.parse::<u64>().ok()?;
+    match unit {
+        "us" => Some(Duration::from_micros(value)),
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        _ => None,
+    }
+}
+
+fn parse_stream(s: &str) -> Option<u8> {
+    match s {
+        "event" => Some(STREAM_EVENT),
+        "commit" => Some(STREAM_STATE_COMMIT),
+        "action" => Some(STREAM_ACTION),
+        _ => None,
+    }
+}
+
+// Open a stream of a registered type, send `message` as a request or as
+// text, and return the reply
+async fn exchange_on_stream(
+    conn: &mut ProtonConnection,
+    name: &str,
+    message: &str,
+) -> Result<String, Box<dyn Error>> {
+    let request = message.parse::<u32>();
+    let mut stream = conn.open_stream(name).await?;
+    let reply = match stream.stream_type().kind {
+        ChannelKind::Request => match request {
+            Ok(request) => stream.request(request).await?.to_string(),
+            Err(e) => {
+                stream.finish().await?;
+                return Err(e.into());
+            }
+        },
+        ChannelKind::Bytes => {
+            stream.write(message.as_bytes()).await?;
+            stream.finish().await?;
+            let mut reply = Vec::new();
+            while let Some(bytes) = stream.read().await? {
+                reply.extend_from_slice(&bytes);
+            }
+            return Ok(String::from_utf8_lossy(&reply).into_owned());
+        }
+    };
+    stream.finish().await?;
+    Ok(reply)
+}
+
+// Variable names are letters, digits and underscores
+fn is_var_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn stream_label(stream: u8) -> &'static str {
+    match stream {
+        STREAM_EVENT => "event",
+        STREAM_STATE_COMMIT => "commit",
+        _ => "action",
+    }
 }
 
 impl ClientRepl {
-    pub fn new(bind_addr: SocketAddr, server_addr: SocketAddr) -> Result<Self, Box<dyn Error>> {
-        let client = ProtonClient::new(bind_addr)?;
+    pub fn new(client: ProtonClient, server_addr: SocketAddr) -> Result<Self, Box<dyn Error>> {
+        Self::with_target(Some((client, server_addr)), None)
+    }
+
+    /// A REPL driving the connection of the client process serving the
+    /// control socket at `path`, rather than a connection of its own.
+    pub async fn attach(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let link = ControlLink::connect(path).await?;
+        println!("Attached to client process at {}", path.display());
+        Self::with_target(None, Some(link))
+    }
 
+    fn with_target(
+        client: Option<(ProtonClient, SocketAddr)>,
+        attached: Option<ControlLink>,
+    ) -> Result<Self, Box<dyn Error>> {
         // Configure readline
         let config = Config::builder()
             .history_ignore_space(true)
@@ -150,27 +293,220 @@ impl ClientRepl {
             let _ = editor.load_history(&home);
         }
 
-        Ok(Self {
+        let mut repl = Self {
             client,
-            server_addr,
             connection: None,
+            attached,
             editor,
-        })
+            aliases: BTreeMap::new(),
+            macros: BTreeMap::new(),
+            macro_depth: 0,
+            failures: 0,
+            vars: BTreeMap::new(),
+            result: None,
+            debug_frames: false,
+            stream_delays: BTreeMap::new(),
+            timeline: None,
+            transcript: None,
+        };
+        repl.load_profile();
+        Ok(repl)
+    }
+
+    /// Record every command and frame, and write them as a timeline to
+    /// `path` on exit. The format follows the extension: Mermaid for `.mmd`,
+    /// Chrome trace JSON otherwise.
+    pub fn with_timeline(mut self, path: PathBuf) -> Self {
+        self.timeline = Some((Arc::new(Timeline::new()), path));
+        self
+    }
+
+    /// Record every frame, and write them as a transcript to `path` on exit
+    /// for `replay-capture`.
+    pub fn with_transcript(mut self, path: PathBuf) -> Self {
+        self.transcript = Some((Arc::new(Transcript::new()), path));
+        self
+    }
+
+    // What the connection's frames are shown to: the frame dump, the
+    // timeline and the transcript, when enabled
+    fn frame_inspector(&self) -> Option<Arc<dyn FrameInspector>> {
+        let mut inspectors: Vec<Arc<dyn FrameInspector>> = Vec::new();
+        if self.debug_frames {
+            inspectors.push(Arc::new(FrameDump::stdout()));
+        }
+        if let Some((ref timeline, _)) = self.timeline {
+            inspectors.push(Arc::clone(timeline) as Arc<dyn FrameInspector>);
+        }
+        if let Some((ref transcript, _)) = self.transcript {
+            inspectors.push(Arc::clone(transcript) as Arc<dyn FrameInspector>);
+        }
+        match inspectors.len() {
+            0 => None,
+            1 => inspectors.pop(),
+            _ => Some(Arc::new(inspectors)),
+        }
+    }
+
+    fn load_profile(&mut self) {
+        let Some(profile) = profile_path().and_then(|p| std::fs::read_to_string(p).ok()) else {
+            return;
+        };
+        for line in profile.lines() {
+            let result = if let Some(definition) = line.strip_prefix("alias ") {
+                self.define_alias(definition)
+            } else if let Some(definition) = line.strip_prefix("macro ") {
+                self.define_macro(definition)
+            } else {
+                continue;
+            };
+            if let Err(e) = result {
+                println!("Ignoring profile line '{}': {}", line, e);
+            }
+        }
+    }
+
+    fn save_profile(&self) {
+        let Some(path) = profile_path() else {
+            return;
+        };
+        let mut profile = String::new();
+        for (name, command) in &self.aliases {
+            profile.push_str(&format!("alias {}={}\n", name, command));
+        }
+        for (name, body) in &self.macros {
+            profile.push_str(&format!("macro {} {{ {} }}\n", name, body));
+        }
+        if let Err(e) = std::fs::write(&path, profile) {
+            println!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    // Names must be one word that does not shadow a built-in command
+    fn check_name(name: &str) -> Result<(), String> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            Err(format!("invalid name '{}'", name))
+        } else if COMMANDS.contains(&name) || name.parse::<u32>().is_ok() {
+            Err(format!("'{}' is a built-in command", name))
+        } else {
+            Ok(())
+        }
+    }
+
+    // `name=command`
+    fn define_alias(&mut self, definition: &str) -> Result<(), String> {
+        let (name, command) = definition
+            .split_once('=')
+            .ok_or("usage: alias <name>=<command>")?;
+        let (name, command) = (name.trim(), command.trim());
+        Self::check_name(name)?;
+        if command.is_empty() {
+            return Err("usage: alias <name>=<command>".to_string());
+        }
+        self.macros.remove(name);
+        self.aliases.insert(name.to_string(), command.to_string());
+        Ok(())
+    }
+
+    // `name { command; command }`
+    fn define_macro(&mut self, definition: &str) -> Result<(), String> {
+        let usage = "usage: macro <name> { <command>; <command> }";
+        let (name, body) = definition.split_once('{').ok_or(usage)?;
+        let body = body.trim().strip_suffix('}').ok_or(usage)?;
+        let name = name.trim();
+        Self::check_name(name)?;
+        let body: Vec<&str> = body
+            .split(';')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect();
+        if body.is_empty() {
+            return Err(usage.to_string());
+        }
+        self.aliases.remove(name);
+        self.macros.insert(name.to_string(), body.join("; "));
+        Ok(())
+    }
+
+    // Replace each `$name` with the variable's value; a `$` not followed by
+    // a name is kept
+    fn substitute(&self, command: &str) -> Result<String, String> {
+        let mut substituted = String::with_capacity(command.len());
+        let mut rest = command;
+        while let Some(at) = rest.find('$') {
+            substituted.push_str(&rest[..at]);
+            let after = &rest[at + 1..];
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            let name = &after[..len];
+            if name.is_empty() {
+                substituted.push('$');
+            } else {
+                let value = self
+                    .vars
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown variable ${}", name))?;
+                substituted.push_str(value);
+            }
+            rest = &after[len..];
+        }
+        substituted.push_str(rest);
+        Ok(substituted)
+    }
+
+    // Replace a leading alias with its command, keeping any arguments
+    fn expand_alias<'a>(&self, command: &'a str) -> Cow<'a, str> {
+        let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+        match self.aliases.get(name) {
+            Some(expansion) if rest.is_empty() => Cow::Owned(expansion.clone()),
+            Some(expansion) => Cow::Owned(format!("{} {}", expansion, rest)),
+            None => Borrowed(command),
+        }
     }
 
     fn print_help() {
         println!("Available commands:");
         println!("  connect [secs]   - Connect to the server with optional startup delay");
         println!("  send_event       - Send an event");
-        println!("  commit <id>      - Send a state commit with given ID");
+        println!("  send_event <id>  - Send an event with the given ID");
+        println!("  send_payload <text> - Send an event carrying <text> (length-prefixed or typed framing)");
+        println!("  pipeline <n>     - Send <n> events, keeping the event window full");
+        println!("  commit <id> [text] - Send a state commit with given ID, carrying any text");
+        println!("  abort <id>       - Abort an earlier state commit so the server compensates it");
+        println!(
+            "  stream <n> <msg> - Send a request or text on a new stream of registered type <n>"
+        );
         println!("  read_action      - Read an action from server");
+        println!("  ack <id>         - Acknowledge actions up to and including <id>");
+        println!("  datagram <text>  - Send <text> as a telemetry datagram, which may be lost");
+        println!("  recv_datagram    - Wait up to 5s for a telemetry datagram from the server");
         println!("  close            - Close the connection");
+        println!("  stats            - Show connection statistics");
+        println!("  rebind [addr]    - Move the connection to a new local socket (any free port)");
+        println!("  update_keys      - Start a QUIC key update on the connection");
+        println!("  power <mode>     - Switch to normal or low power, or follow the server");
         println!("  sleep <secs>     - Sleep for specified seconds");
+        println!("  debug frames on|off - Hexdump and decode every frame sent and received");
+        println!("  with-delay <d> <cmd> - Wait <d> (e.g. 200ms, 2s) before running <cmd>");
+        println!("  set delay <stream> <d> - Delay every request on event, commit or action");
+        println!("  set <n> = <cmd>  - Run <cmd> and keep its result, e.g. an action id, as $<n>");
+        println!("  vars             - List variables");
         println!("  reset            - Reset client state and wait for connections to timeout");
+        println!("  reconnect        - Reconnect the attached client process (with --attach)");
+        println!("  alias [n=cmd]    - Define an alias, or list aliases");
+        println!("  unalias <n>      - Remove an alias");
+        println!("  macro n {{ a; b }} - Define a macro running several commands");
+        println!("  macros           - List macros");
+        println!("  unmacro <n>      - Remove a macro");
+        println!("  source <file>    - Run the commands in <file>, stopping at the first failure");
         println!("  help             - Show this help message");
         println!("  exit             - Exit the REPL");
         println!("\nCommands can be chained with semicolons:");
         println!("  Example: connect 5; sleep 2; send_event; read_action");
+        println!("\nVariables:");
+        println!("  $<n> in a command is replaced by the value of variable <n>");
+        println!("  Example: set a = read_action; commit $a; ack $a");
         println!("\nRepeat prefix:");
         println!("  Commands can be prefixed with a number to repeat them");
         println!("  Example: 5 connect    - Connects 5 times");
@@ -180,13 +516,119 @@ impl ClientRepl {
         println!("  - Use 'reset' to cleanup all connections and start fresh");
     }
 
+    // Run a command on the attached process. Returns None for commands the
+    // REPL handles itself.
+    async fn handle_attached_command(&mut self, command: &str) -> Option<bool> {
+        let link = self.attached.as_mut()?;
+        let name = command.split_whitespace().next().unwrap_or_default();
+        if !control::COMMANDS.contains(&name) {
+            if matches!(name, "connect" | "reset") {
+                self.fail(format!(
+                    "'{}' is not available when attached; use 'reconnect'",
+                    name
+                ));
+                return Some(true);
+            }
+            // These act on a connection of the REPL's own
+            if matches!(name, "debug" | "set" | "power") {
+                self.fail(format!("'{}' is not available when attached", name));
+                return Some(true);
+            }
+            return None;
+        }
+        match link.request(command).await {
+            Ok(reply) => {
+                for line in &reply {
+                    println!("{}", line);
+                }
+                if reply.last().is_some_and(|line| line.starts_with("err ")) {
+                    self.failures += 1;
+                }
+                Some(true)
+            }
+            Err(e) => {
+                self.fail(format!("Control socket closed: {}", e));
+                Some(false)
+            }
+        }
+    }
+
+    // Report a command that failed, so a script running it stops
+    fn fail(&mut self, message: impl fmt::Display) {
+        println!("{}", message);
+        self.failures += 1;
+    }
+
+    // Run the commands in the script at `path`, one line (or several
+    // separated by semicolons) at a time, skipping blank lines and `#`
+    // comments. Stops at the first line with a failed command, or at
+    // `exit`, which ends the REPL.
+    async fn source(&mut self, path: &Path) -> Result<bool, String> {
+        let script = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            return Err(format!(
+                "Not running {}: scripts nested too deeply",
+                path.display()
+            ));
+        }
+        self.macro_depth += 1;
+        let mut result = Ok(true);
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            println!("> {}", line);
+            let failures = self.failures;
+            let keep_going = Box::pin(self.handle_command(line)).await;
+            if self.failures > failures {
+                result = Err(format!(
+                    "{}:{}: '{}' failed",
+                    path.display(),
+                    number + 1,
+                    line
+                ));
+                break;
+            }
+            if !keep_going {
+                result = Ok(false);
+                break;
+            }
+        }
+        self.macro_depth -= 1;
+        result
+    }
+
     async fn handle_single_command(&mut self, command: &str) -> bool {
+        let started = Instant::now();
+        let keep_going = self.execute_single_command(command).await;
+        if let Some((ref timeline, _)) = self.timeline {
+            timeline.record_command(command.trim(), started);
+        }
+        keep_going
+    }
+
+    async fn execute_single_command(&mut self, command: &str) -> bool {
+        let command = match self.substitute(command) {
+            Ok(command) => command,
+            Err(e) => {
+                self.fail(e);
+                return true;
+            }
+        };
+        if let Some(keep_going) = self.handle_attached_command(command.trim()).await {
+            return keep_going;
+        }
         match command.trim() {
             "help" => {
                 Self::print_help();
                 true
             }
             cmd if cmd.starts_with("connect") => {
+                let Some((ref mut client, server_addr)) = self.client else {
+                    return true;
+                };
                 // Parse optional delay parameter
                 let delay = cmd
                     .split_whitespace()
@@ -196,7 +638,7 @@ impl ClientRepl {
 
                 println!(
                     "Connecting to server at {}{}...",
-                    self.server_addr,
+                    server_addr,
                     delay
                         .map(|d| format!(" with {}s startup delay", d.as_secs()))
                         .unwrap_or_default()
@@ -207,13 +649,17 @@ impl ClientRepl {
                     println!("Warning: Creating new connection while previous connection exists");
                 }
 
-                match self.client.connect(self.server_addr, delay).await {
-                    Ok(conn) => {
+                match client.connect(server_addr, delay).await {
+                    Ok(mut conn) => {
                         println!("Connected successfully!");
+                        conn.set_frame_inspector(self.frame_inspector());
+                        for (&stream, &delay) in &self.stream_delays {
+                            let _ = conn.set_stream_delay(stream, delay);
+                        }
                         // Replace any existing connection
                         self.connection = Some(conn);
                     }
-                    Err(e) => println!("Failed to connect: {}", e),
+                    Err(e) => self.fail(format!("Failed to connect: {}", e)),
                 }
                 true
             }
@@ -233,28 +679,297 @@ impl ClientRepl {
             }
             "send_event" => {
                 if let Some(ref mut conn) = self.connection {
-                    match conn.send_event().await {
-                        Ok(ack) => println!("Event acknowledged with ID: {}", ack),
-                        Err(e) => println!("Failed to send event: {}", e),
+                    match conn.send_event_or_queue().await {
+                        Ok(SendStatus::Acked(ack)) => {
+                            println!("Event acknowledged with ID: {}", ack);
+                            self.result = Some(ack.to_string());
+                        }
+                        Ok(SendStatus::Queued(id)) => {
+                            println!("Event {} queued until reconnected", id);
+                            self.result = Some(id.to_string());
+                        }
+                        Err(e) => self.fail(format!("Failed to send event: {}", e)),
                     }
                 } else {
-                    println!("Not connected! Use 'connect' first.");
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("send_event ") => {
+                if let Some(ref mut conn) = self.connection {
+                    if let Ok(id) = cmd.split_whitespace().nth(1).unwrap_or("0").parse::<u32>() {
+                        match conn.send_event_with_id(id).await {
+                            Ok(ack) => {
+                                println!("Event acknowledged with ID: {}", ack);
+                                self.result = Some(ack.to_string());
+                            }
+                            Err(e) => self.fail(format!("Failed to send event: {}", e)),
+                        }
+                    } else {
+                        self.fail("Invalid event ID. Usage: send_event <number>");
+                    }
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("send_payload ") => {
+                if let Some(ref mut conn) = self.connection {
+                    let text = cmd["send_payload ".len()..].trim();
+                    match conn
+                        .send_event_with_payload_or_queue(text.as_bytes().to_vec())
+                        .await
+                    {
+                        Ok(SendStatus::Acked(ack)) => {
+                            println!("Event acknowledged with ID: {}", ack);
+                            self.result = Some(ack.to_string());
+                        }
+                        Ok(SendStatus::Queued(id)) => {
+                            println!("Event {} queued until reconnected", id);
+                            self.result = Some(id.to_string());
+                        }
+                        Err(e) => self.fail(format!("Failed to send event: {}", e)),
+                    }
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("pipeline ") => {
+                let Some(ref mut conn) = self.connection else {
+                    self.fail("Not connected! Use 'connect' first.");
+                    return true;
+                };
+                let Ok(count) = cmd["pipeline ".len()..].trim().parse::<u32>() else {
+                    self.fail("Invalid count. Usage: pipeline <number>");
+                    return true;
+                };
+                let started = Instant::now();
+                let mut pending = Vec::with_capacity(count as usize);
+                let mut failed = None;
+                for _ in 0..count {
+                    match conn.submit_event().await {
+                        Ok(ack) => pending.push(ack),
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+                let mut acked = 0;
+                for ack in pending {
+                    match ack.await {
+                        Ok(_) => acked += 1,
+                        Err(e) => {
+                            failed.get_or_insert(e);
+                        }
+                    }
+                }
+                let elapsed = started.elapsed();
+                println!(
+                    "{} events acknowledged in {:?} ({:.0} events/s)",
+                    acked,
+                    elapsed,
+                    acked as f64 / elapsed.as_secs_f64()
+                );
+                if let Some(window) = conn.stats().window {
+                    println!("Event window: {}", window);
+                }
+                self.result = Some(acked.to_string());
+                if let Some(e) = failed {
+                    self.fail(format!("Failed to send event: {}", e));
                 }
                 true
             }
             cmd if cmd.starts_with("commit ") => {
+                if let Some(ref mut conn) = self.connection {
+                    let mut parts = cmd.splitn(3, ' ').skip(1);
+                    let id = parts.next().unwrap_or("0").parse::<u32>();
+                    let text = parts.next().map(str::trim).unwrap_or_default();
+                    if let Ok(id) = id {
+                        let result = if text.is_empty() {
+                            conn.send_state_commit(id).await
+                        } else {
+                            conn.send_state_commit_with_payload(id, text.as_bytes().to_vec())
+                                .await
+                        };
+                        match result {
+                            Ok(response) => {
+                                println!("State commit response: {}", response);
+                                self.result = Some(response.to_string());
+                            }
+                            Err(e) => self.fail(format!("Failed to commit state: {}", e)),
+                        }
+                    } else {
+                        self.fail("Invalid commit ID. Usage: commit <number> [text]");
+                    }
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("abort ") => {
                 if let Some(ref mut conn) = self.connection {
                     if let Ok(id) = cmd.split_whitespace().nth(1).unwrap_or("0").parse::<u32>() {
-                        match conn.send_state_commit(id).await {
-                            Ok(response) => println!("State commit response: {}", response),
-                            Err(e) => println!("Failed to commit state: {}", e),
+                        match conn.abort_commit(id).await {
+                            Ok(aborted) => {
+                                println!("State commit {} aborted", aborted);
+                                self.result = Some(aborted.to_string());
+                            }
+                            Err(e) => self.fail(format!("Failed to abort state commit: {}", e)),
                         }
                     } else {
-                        println!("Invalid commit ID. Usage: commit <number>");
+                        self.fail("Invalid commit ID. Usage: abort <number>");
+                    }
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("stream ") => {
+                let mut parts = cmd.splitn(3, ' ').skip(1);
+                match (self.connection.as_mut(), parts.next(), parts.next()) {
+                    (None, _, _) => self.fail("Not connected! Use 'connect' first."),
+                    (Some(conn), Some(name), Some(message)) => {
+                        match exchange_on_stream(conn, name, message).await {
+                            Ok(reply) => {
+                                println!("{} stream replied: {}", name, reply);
+                                self.result = Some(reply);
+                            }
+                            Err(e) => self.fail(format!("Failed to use {} stream: {}", name, e)),
+                        }
+                    }
+                    _ => self.fail("Usage: stream <name> <request or text>"),
+                }
+                true
+            }
+            cmd if cmd.starts_with("ack ") => {
+                if let Some(ref mut conn) = self.connection {
+                    if let Ok(id) = cmd.split_whitespace().nth(1).unwrap_or("0").parse::<u32>() {
+                        conn.ack_up_to(id);
+                        println!("Actions acknowledged up to {}", conn.action_offset());
+                        self.result = Some(conn.action_offset().to_string());
+                    } else {
+                        self.fail("Invalid action ID. Usage: ack <number>");
+                    }
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("datagram ") => {
+                if let Some(ref conn) = self.connection {
+                    let text = cmd["datagram ".len()..].trim();
+                    match conn.send_datagram(text.as_bytes()) {
+                        Ok(()) => println!("Sent {} byte datagram", text.len()),
+                        Err(e) => self.fail(format!("Failed to send datagram: {}", e)),
                     }
                 } else {
-                    println!("Not connected! Use 'connect' first.");
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            "recv_datagram" => {
+                if let Some(ref conn) = self.connection {
+                    match timeout(Duration::from_secs(5), conn.recv_datagram()).await {
+                        Ok(Ok(datagram)) => {
+                            let text = String::from_utf8_lossy(&datagram).into_owned();
+                            println!("Received datagram '{}'", text);
+                            self.result = Some(text);
+                        }
+                        Ok(Err(e)) => self.fail(format!("Failed to receive datagram: {}", e)),
+                        Err(_) => self.fail("No datagram received"),
+                    }
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("with-delay ") => {
+                let mut parts = cmd.splitn(3, char::is_whitespace).skip(1);
+                let delay = parts.next().and_then(parse_delay);
+                let (Some(delay), Some(command)) = (delay, parts.next()) else {
+                    self.fail(
+                        "Usage: with-delay <delay> <command>, e.g. with-delay 200ms send_event",
+                    );
+                    return true;
+                };
+                sleep(delay).await;
+                Box::pin(self.parse_and_handle_command(command)).await
+            }
+            cmd if cmd.starts_with("set ") && cmd.contains('=') => {
+                let (name, command) = cmd["set ".len()..].split_once('=').unwrap_or_default();
+                let (name, command) = (name.trim(), command.trim());
+                if !is_var_name(name) || command.is_empty() {
+                    self.fail("Usage: set <name> = <command>, e.g. set a = read_action");
+                    return true;
+                }
+                self.result = None;
+                let failures = self.failures;
+                let keep_going = Box::pin(self.parse_and_handle_command(command)).await;
+                if self.failures > failures {
+                    return keep_going;
+                }
+                match self.result.take() {
+                    Some(value) => {
+                        println!("{} = {}", name, value);
+                        self.vars.insert(name.to_string(), value);
+                    }
+                    None => self.fail(format!("'{}' has no result to set {} to", command, name)),
+                }
+                keep_going
+            }
+            "vars" => {
+                for (name, value) in &self.vars {
+                    println!("{} = {}", name, value);
+                }
+                true
+            }
+            "set delay" => {
+                for (&stream, delay) in &self.stream_delays {
+                    println!("{} stream delayed by {:?}", stream_label(stream), delay);
+                }
+                true
+            }
+            cmd if cmd.starts_with("set ") => {
+                let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+                let (stream, delay) = match args[..] {
+                    ["delay", stream, delay] => (parse_stream(stream), parse_delay(delay)),
+                    _ => (None, None),
+                };
+                let (Some(stream), Some(delay)) = (stream, delay) else {
+                    self.fail("Usage: set delay <event|commit|action> <delay>, e.g. set delay commit 200ms");
+                    return true;
+                };
+                if delay.is_zero() {
+                    self.stream_delays.remove(&stream);
+                } else {
+                    self.stream_delays.insert(stream, delay);
+                }
+                if let Some(ref mut conn) = self.connection {
+                    let _ = conn.set_stream_delay(stream, delay);
+                }
+                println!("{} stream delayed by {:?}", stream_label(stream), delay);
+                true
+            }
+            cmd if cmd.starts_with("debug ") => {
+                let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+                self.debug_frames = match args[..] {
+                    ["frames", "on"] => true,
+                    ["frames", "off"] => false,
+                    _ => {
+                        self.fail("Usage: debug frames on|off");
+                        return true;
+                    }
+                };
+                let inspector = self.frame_inspector();
+                if let Some(ref mut conn) = self.connection {
+                    conn.set_frame_inspector(inspector);
                 }
+                println!(
+                    "Frame dump {}",
+                    if self.debug_frames { "on" } else { "off" }
+                );
                 true
             }
             cmd if cmd.starts_with("sleep ") => {
@@ -263,18 +978,82 @@ impl ClientRepl {
                     sleep(Duration::from_secs(secs)).await;
                     println!("Awake!");
                 } else {
-                    println!("Invalid sleep duration. Usage: sleep <seconds>");
+                    self.fail("Invalid sleep duration. Usage: sleep <seconds>");
                 }
                 true
             }
             "read_action" => {
                 if let Some(ref mut conn) = self.connection {
-                    match conn.read_action().await {
-                        Ok(action) => println!("Received action: {}", action),
-                        Err(e) => println!("Failed to read action: {}", e),
+                    match conn.read_action_frame().await {
+                        Ok(action) => {
+                            if action.payload.is_empty() {
+                                println!("Received action: {}", action.id);
+                            } else {
+                                println!(
+                                    "Received action: {} with payload '{}'",
+                                    action.id,
+                                    String::from_utf8_lossy(&action.payload)
+                                );
+                            }
+                            self.result = Some(action.id.to_string());
+                        }
+                        Err(e) => self.fail(format!("Failed to read action: {}", e)),
                     }
                 } else {
-                    println!("Not connected! Use 'connect' first.");
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            "stats" => {
+                if let Some(ref conn) = self.connection {
+                    println!("{}", conn.stats());
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd == "rebind" || cmd.starts_with("rebind ") => {
+                let Some(ref conn) = self.connection else {
+                    self.fail("Not connected! Use 'connect' first.");
+                    return true;
+                };
+                let local = match cmd["rebind".len()..].trim() {
+                    "" => conn
+                        .local_addr()
+                        .map(|addr| SocketAddr::new(addr.ip(), 0))
+                        .map_err(|e| e.to_string()),
+                    addr => addr
+                        .parse::<SocketAddr>()
+                        .map_err(|e| format!("Invalid address '{}': {}", addr, e)),
+                };
+                match local.and_then(|local| conn.rebind(local).map_err(|e| e.to_string())) {
+                    Ok(bound) => println!("Rebound to {}", bound),
+                    Err(e) => self.fail(format!("Failed to rebind: {}", e)),
+                }
+                true
+            }
+            "update_keys" => {
+                if let Some(ref conn) = self.connection {
+                    conn.update_keys();
+                    println!("Key update started");
+                } else {
+                    self.fail("Not connected! Use 'connect' first.");
+                }
+                true
+            }
+            cmd if cmd.starts_with("power ") => {
+                let mode = cmd["power ".len()..].trim();
+                let mode = match mode {
+                    "server" => Ok(None),
+                    mode => mode.parse::<PowerMode>().map(Some),
+                };
+                match (mode, self.connection.as_mut()) {
+                    (Err(e), _) => self.fail(e),
+                    (Ok(_), None) => self.fail("Not connected! Use 'connect' first."),
+                    (Ok(mode), Some(conn)) => {
+                        conn.set_power_mode(mode);
+                        println!("Power mode: {}", conn.settings().power_mode());
+                    }
                 }
                 true
             }
@@ -284,7 +1063,7 @@ impl ClientRepl {
                     self.connection = None;
                     println!("Connection closed.");
                 } else {
-                    println!("Not connected!");
+                    self.fail("Not connected!");
                 }
                 true
             }
@@ -295,9 +1074,56 @@ impl ClientRepl {
                 println!("Goodbye!");
                 false
             }
+            "alias" => {
+                for (name, command) in &self.aliases {
+                    println!("alias {}={}", name, command);
+                }
+                true
+            }
+            cmd if cmd.starts_with("alias ") => {
+                match self.define_alias(&cmd["alias ".len()..]) {
+                    Ok(()) => self.save_profile(),
+                    Err(e) => self.fail(e),
+                }
+                true
+            }
+            cmd if cmd.starts_with("unalias ") => {
+                let name = cmd["unalias ".len()..].trim();
+                if self.aliases.remove(name).is_some() {
+                    self.save_profile();
+                } else {
+                    self.fail(format!("No alias named '{}'", name));
+                }
+                true
+            }
+            "macros" => {
+                for (name, body) in &self.macros {
+                    println!("macro {} {{ {} }}", name, body);
+                }
+                true
+            }
+            cmd if cmd.starts_with("unmacro ") => {
+                let name = cmd["unmacro ".len()..].trim();
+                if self.macros.remove(name).is_some() {
+                    self.save_profile();
+                } else {
+                    self.fail(format!("No macro named '{}'", name));
+                }
+                true
+            }
+            cmd if cmd.starts_with("source ") => {
+                let path = PathBuf::from(cmd["source ".len()..].trim());
+                match Box::pin(self.source(&path)).await {
+                    Ok(keep_going) => keep_going,
+                    Err(e) => {
+                        self.fail(e);
+                        true
+                    }
+                }
+            }
             "" => true,
             _ => {
-                println!("Unknown command. Type 'help' for available commands.");
+                self.fail("Unknown command. Type 'help' for available commands.");
                 true
             }
         }
@@ -309,20 +1135,36 @@ impl ClientRepl {
         // Check if first part is a number (repeat count)
         let (repeat_count, cmd) = if let Ok(count) = parts[0].parse::<u32>() {
             if parts.len() < 2 {
-                println!("Error: Repeat count needs a command");
+                self.fail("Error: Repeat count needs a command");
                 return true;
             }
             (count, parts[1])
         } else {
             (1, command)
         };
+        let cmd = self.expand_alias(cmd.trim()).into_owned();
+        let name = cmd.split_whitespace().next().unwrap_or_default();
+        let body = self.macros.get(name).cloned();
 
         // Execute the command repeat_count times
         for i in 0..repeat_count {
             if repeat_count > 1 {
                 println!("Execution {} of {}:", i + 1, repeat_count);
             }
-            if !self.handle_single_command(cmd).await {
+            let keep_going = match body {
+                Some(ref body) if self.macro_depth >= MAX_MACRO_DEPTH => {
+                    self.fail(format!("Not running '{}': macros nested too deeply", body));
+                    return true;
+                }
+                Some(ref body) => {
+                    self.macro_depth += 1;
+                    let keep_going = Box::pin(self.handle_command(body)).await;
+                    self.macro_depth -= 1;
+                    keep_going
+                }
+                None => self.handle_single_command(&cmd).await,
+            };
+            if !keep_going {
                 return false;
             }
         }
@@ -330,6 +1172,15 @@ impl ClientRepl {
     }
 
     async fn handle_command(&mut self, command: &str) -> bool {
+        // Macro bodies contain semicolons, so a definition is not split
+        if let Some(definition) = command.trim().strip_prefix("macro ") {
+            match self.define_macro(definition) {
+                Ok(()) => self.save_profile(),
+                Err(e) => self.fail(e),
+            }
+            return true;
+        }
+
         // Split commands by semicolon and handle each one
         for cmd in command.split(';') {
             if !self.parse_and_handle_command(cmd.trim()).await {
@@ -377,11 +1228,38 @@ impl ClientRepl {
             let _ = self.editor.save_history(&home);
         }
 
+        self.finish().await;
+        Ok(())
+    }
+
+    /// Run the commands in the script at `path` without prompting, as the
+    /// `source` command does, e.g. for automated protocol tests. Fails if
+    /// any command does.
+    pub async fn run_script(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let result = self.source(path).await;
+        self.finish().await;
+        result.map(|_| ()).map_err(Into::into)
+    }
+
+    // Close the connection and write out what was recorded
+    async fn finish(&mut self) {
         // Cleanup connection if exists
         if let Some(ref mut conn) = self.connection {
             conn.close().await;
         }
 
-        Ok(())
+        if let Some((ref timeline, ref path)) = self.timeline {
+            match timeline.write(path, TimelineFormat::for_path(path)) {
+                Ok(()) => println!("Timeline written to {}", path.display()),
+                Err(e) => println!("Failed to write timeline to {}: {}", path.display(), e),
+            }
+        }
+
+        if let Some((ref transcript, ref path)) = self.transcript {
+            match transcript.write(path) {
+                Ok(()) => println!("Transcript written to {}", path.display()),
+                Err(e) => println!("Failed to write transcript to {}: {}", path.display(), e),
+            }
+        }
     }
 }
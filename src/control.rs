@@ -0,0 +1,202 @@
+use quic_rs_debug::ProtonClient;
+use quic_rs_debug::ProtonConnection;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+/// Commands understood on the control socket, one per line.
+pub const COMMANDS: &[&str] = &[
+    "send_event",
+    "commit",
+    "abort",
+    "read_action",
+    "ack",
+    "stats",
+    "close",
+    "reconnect",
+];
+
+// The connection driven through the socket, replaced by `reconnect`
+struct Controlled {
+    client: ProtonClient,
+    server_addr: SocketAddr,
+    connection: Option<ProtonConnection>,
+}
+
+/// Serves a control socket at `path` driving `connection` until interrupted.
+/// Each command line is answered with any output lines followed by a final
+/// `ok [value]` or `err <message>` line. Commands from several local
+/// processes are run one at a time. The socket is only accessible to the
+/// owning user.
+pub async fn serve(
+    path: &Path,
+    client: ProtonClient,
+    server_addr: SocketAddr,
+    connection: ProtonConnection,
+) -> Result<(), Box<dyn Error>> {
+    // A socket file nobody is listening on is left over from a previous run
+    if path.exists() && UnixStream::connect(path).await.is_err() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", path.display());
+
+    let state = Arc::new(Mutex::new(Controlled {
+        client,
+        server_addr,
+        connection: Some(connection),
+    }));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    if let Err(e) = session(stream, &state).await {
+                        error!("Control session failed: {}", e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    let _ = std::fs::remove_file(path);
+    if let Some(ref mut connection) = state.lock().await.connection {
+        connection.close().await;
+    }
+    Ok(())
+}
+
+async fn session(stream: UnixStream, state: &Mutex<Controlled>) -> Result<(), Box<dyn Error>> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = {
+            let mut state = state.lock().await;
+            state.execute(line).await
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+impl Controlled {
+    async fn execute(&mut self, line: &str) -> String {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let arg = parts.next().map(str::parse::<u32>);
+
+        if command == "reconnect" {
+            if let Some(ref mut connection) = self.connection {
+                connection.close().await;
+            }
+            self.connection = None;
+            return match self
+                .client
+                .connect(self.server_addr, Some(Duration::ZERO))
+                .await
+            {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    "ok\n".to_string()
+                }
+                Err(e) => format!("err {}\n", e),
+            };
+        }
+        if !COMMANDS.contains(&command) {
+            return format!("err unknown command '{}'\n", command);
+        }
+        let Some(ref mut connection) = self.connection else {
+            return "err not connected\n".to_string();
+        };
+
+        match (command, arg) {
+            ("send_event", None) => match connection.send_event().await {
+                Ok(ack) => format!("ok {}\n", ack),
+                Err(e) => format!("err {}\n", e),
+            },
+            ("send_event", Some(Ok(id))) => match connection.send_event_with_id(id).await {
+                Ok(ack) => format!("ok {}\n", ack),
+                Err(e) => format!("err {}\n", e),
+            },
+            ("commit", Some(Ok(id))) => match connection.send_state_commit(id).await {
+                Ok(response) => format!("ok {}\n", response),
+                Err(e) => format!("err {}\n", e),
+            },
+            ("abort", Some(Ok(id))) => match connection.abort_commit(id).await {
+                Ok(aborted) => format!("ok {}\n", aborted),
+                Err(e) => format!("err {}\n", e),
+            },
+            ("read_action", None) => match connection.read_action().await {
+                Ok(action) => format!("ok {}\n", action),
+                Err(e) => format!("err {}\n", e),
+            },
+            ("ack", Some(Ok(id))) => {
+                connection.ack_up_to(id);
+                format!("ok {}\n", connection.action_offset())
+            }
+            ("stats", None) => format!("{}\nok\n", connection.stats()),
+            ("close", None) => {
+                connection.close().await;
+                self.connection = None;
+                "ok\n".to_string()
+            }
+            _ => format!("err usage: {} {}\n", command, usage(command)),
+        }
+    }
+}
+
+fn usage(command: &str) -> &'static str {
+    match command {
+        "send_event" => "[<id>]",
+        "commit" | "abort" | "ack" => "<id>",
+        _ => "(no arguments)",
+    }
+}
+
+/// Client end of a control socket, for driving another process's connection.
+pub struct ControlLink {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    write: OwnedWriteHalf,
+}
+
+impl ControlLink {
+    pub async fn connect(path: &Path) -> std::io::Result<Self> {
+        let (read, write) = UnixStream::connect(path).await?.into_split();
+        Ok(Self {
+            lines: BufReader::new(read).lines(),
+            write,
+        })
+    }
+
+    /// Sends one command and returns the reply lines, ending with the
+    /// `ok` or `err` line.
+    pub async fn request(&mut self, command: &str) -> std::io::Result<Vec<String>> {
+        self.write
+            .write_all(format!("{}\n", command.trim()).as_bytes())
+            .await?;
+        let mut reply = Vec::new();
+        while let Some(line) = self.lines.next_line().await? {
+            let done = line == "ok" || line.starts_with("ok ") || line.starts_with("err ");
+            reply.push(line);
+            if done {
+                return Ok(reply);
+            }
+        }
+        Err(std::io::ErrorKind::UnexpectedEof.into())
+    }
+}
@@ -0,0 +1,172 @@
+//! `demo`: a server and a scripted client in one process, on separate
+//! loopback endpoints, walking through each part of the protocol with a
+//! note on what goes over the wire. Any step that does not behave as
+//! described fails the demo, so it doubles as a smoke test of the stack.
+
+use quic_rs_debug::proton::datagram::EchoDatagrams;
+use quic_rs_debug::proton::handler::EchoHandler;
+use quic_rs_debug::{ProtonClient, ProtonConfig, ProtonServer};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long the echo of the demo datagram is awaited
+const DATAGRAM_WAIT: Duration = Duration::from_secs(1);
+
+// State commit id sent by the demo; the echo handler answers with id + 2
+const DEMO_COMMIT: u32 = 40;
+
+// Numbers and prints each step with its explanation
+#[derive(Default)]
+struct Narrator {
+    step: u32,
+}
+
+impl Narrator {
+    fn step(&mut self, title: &str, note: &str) {
+        self.step += 1;
+        println!();
+        println!("== {}. {}", self.step, title);
+        for line in note.lines() {
+            println!("   {}", line);
+        }
+    }
+
+    fn result(&self, text: impl AsRef<str>) {
+        println!("   -> {}", text.as_ref());
+    }
+}
+
+fn expect(ok: bool, what: String) -> Result<(), Box<dyn Error>> {
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("demo failed: {}", what).into())
+    }
+}
+
+/// Runs the demo against a server started on a free loopback port.
+pub async fn run(cert: rustls::Certificate, key: rustls::PrivateKey) -> Result<(), Box<dyn Error>> {
+    let mut narrator = Narrator::default();
+    // Both sides of the demo start at once, so there are no connections of
+    // an earlier run to wait out
+    let config = ProtonConfig {
+        startup_delay: Duration::ZERO,
+        ..ProtonConfig::default()
+    };
+
+    narrator.step(
+        "Start the server",
+        "The server binds its own UDP endpoint. Its handler acks each event with\n\
+         its id, answers state commits with id + 2 and echoes datagrams back.",
+    );
+    let addr: SocketAddr = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let server = ProtonServer::new(addr, cert, key, Arc::new(EchoHandler))?
+        .with_config(config)?
+        .with_datagram_handler(Arc::new(EchoDatagrams));
+    let drain = server.drain_handle();
+    let mut listening = server.listening();
+    let serving = tokio::spawn(async move { server.run().await });
+    listening.wait_for(|listening| *listening).await?;
+    narrator.result(format!("listening on {}", addr));
+
+    narrator.step(
+        "Connect",
+        "The client binds a second endpoint and makes the QUIC handshake. It then\n\
+         opens the control stream, exchanges HELLO metadata on it, and opens the\n\
+         event, state commit and action streams, each named by its first byte.",
+    );
+    let mut client = ProtonClient::new("127.0.0.1:0".parse()?)?
+        .with_user_agent("proton-demo")
+        .with_config(config)?;
+    let mut connection = client.connect(addr, Some(Duration::ZERO)).await?;
+    let stats = connection.stats();
+    narrator.result(format!(
+        "handshake took {:?}, streams {:?}",
+        stats.handshake, stats.stream_setup
+    ));
+    match connection.peer_info() {
+        Some(peer) => narrator.result(format!("server is {}", peer)),
+        None => expect(false, "the server sent no HELLO".to_string())?,
+    }
+
+    narrator.step(
+        "Send an event",
+        "The client allocates the next event id and writes the event frame on the\n\
+         event stream. The server hands it to its handler and writes back the ack.",
+    );
+    let ack = connection.send_event().await?;
+    let id = connection.last_event_id();
+    narrator.result(format!("event {} acknowledged with {}", id, ack));
+    expect(ack == id, format!("event {} was acked as {}", id, ack))?;
+
+    narrator.step(
+        "Commit state",
+        "State commits travel on a stream of their own, so a slow commit does not\n\
+         hold up events. Commits are idempotent and may be retried.",
+    );
+    let response = connection.send_state_commit(DEMO_COMMIT).await?;
+    narrator.result(format!("commit {} answered with {}", DEMO_COMMIT, response));
+    expect(
+        response == DEMO_COMMIT + 2,
+        format!("commit {} was answered with {}", DEMO_COMMIT, response),
+    )?;
+
+    narrator.step(
+        "Read and acknowledge an action",
+        "The client asks for the next action on the action stream. Acknowledging\n\
+         it tells the server not to deliver it again after a reconnect.",
+    );
+    let action = connection.read_action().await?;
+    connection.ack_up_to(action);
+    narrator.result(format!(
+        "received action {}, acknowledged up to {}",
+        action,
+        connection.action_offset()
+    ));
+    expect(
+        connection.action_offset() == action,
+        format!("action {} was not acknowledged", action),
+    )?;
+
+    narrator.step(
+        "Send a telemetry datagram",
+        "Datagrams go alongside the streams without retransmission, so they can\n\
+         be lost. The server's datagram handler sends this one straight back.",
+    );
+    connection.send_datagram(b"hello")?;
+    match tokio::time::timeout(DATAGRAM_WAIT, connection.recv_datagram()).await {
+        Ok(echo) => {
+            let echo = echo?;
+            narrator.result(format!("echoed: {}", String::from_utf8_lossy(&echo)));
+            expect(
+                echo == b"hello",
+                "the datagram came back altered".to_string(),
+            )?;
+        }
+        Err(_) => narrator.result("lost, as datagrams may be"),
+    }
+
+    narrator.step(
+        "Connection statistics",
+        "What the client knows about the connection after the exchange.",
+    );
+    for line in connection.stats().to_string().lines() {
+        narrator.result(line);
+    }
+
+    narrator.step(
+        "Close and drain",
+        "The client closes its connection, then the server drains: it stops\n\
+         accepting connections and returns once the ones it has are done.",
+    );
+    connection.close().await;
+    drain.drain();
+    serving.await??;
+    narrator.result("server stopped");
+
+    println!();
+    println!("Demo complete: every step behaved as described.");
+    Ok(())
+}
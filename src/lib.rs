@@ -1,3 +1,10 @@
-mod proton;
+//! Proton over QUIC: a client and server exchanging events, state commits
+//! and actions on dedicated streams. The binaries built from this crate
+//! (the server, REPL and tools under `src/main.rs`) use only this API.
+//!
+//! [`ProtonConnection`] owns everything it needs, so it can be stored in
+//! a struct or moved into a spawned task.
 
-pub use proton::{ProtonClient, ProtonError, ProtonServer};
+pub mod proton;
+
+pub use proton::{ProtonClient, ProtonConfig, ProtonConnection, ProtonError, ProtonServer};
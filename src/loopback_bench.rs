@@ -0,0 +1,289 @@
+use quic_rs_debug::proton::datagram::EchoDatagrams;
+use quic_rs_debug::proton::efficiency::DatagramEfficiency;
+use quic_rs_debug::proton::handler::EchoHandler;
+use quic_rs_debug::proton::{Framing, STARTUP_DELAY};
+use quic_rs_debug::ProtonConnection;
+use quic_rs_debug::{ProtonClient, ProtonConfig, ProtonServer};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Each event is acknowledged with a u32
+const ACK_LEN: u64 = 4;
+
+// A datagram carries an event id after its tag
+const DATAGRAM_LEN: u64 = 5;
+
+// Echoed datagrams still missing after this long are taken as lost
+const DATAGRAM_DRAIN: Duration = Duration::from_secs(1);
+
+// Throughput of one transport mode
+struct Sample {
+    mode: &'static str,
+    messages: u64,
+    // Proton frames in both directions
+    app_bytes: u64,
+    // UDP payload in both directions, including QUIC overhead and acks
+    wire_bytes: u64,
+    // How the client's frames filled the datagrams it sent
+    efficiency: DatagramEfficiency,
+    elapsed: Duration,
+}
+
+impl Sample {
+    fn row(&self) -> String {
+        let secs = self.elapsed.as_secs_f64();
+        format!(
+            "{:<10} {:>9} {:>9.3} {:>11.0} {:>10.3} {:>11.3} {:>10.1} {:>9.2} {:>6.0}% {:>8.0}%",
+            self.mode,
+            self.messages,
+            secs,
+            self.messages as f64 / secs,
+            self.app_bytes as f64 / secs / 1e6,
+            self.wire_bytes as f64 / secs / 1e6,
+            self.wire_bytes as f64 / self.messages as f64,
+            self.efficiency.frames_per_datagram(),
+            self.efficiency.fill_ratio() * 100.0,
+            self.efficiency.overhead() * 100.0,
+        )
+    }
+}
+
+fn udp_bytes(connection: &ProtonConnection) -> u64 {
+    let quic = connection.stats().quic;
+    quic.udp_tx.bytes + quic.udp_rx.bytes
+}
+
+fn efficiency(connection: &ProtonConnection) -> DatagramEfficiency {
+    connection.stats().efficiency
+}
+
+/// Runs a server and client in this process on loopback and sends `events`
+/// events through each transport mode, printing a comparison table.
+/// `batch_size` fixes the batch size; by default it adapts to the connection.
+pub async fn run(
+    events: u32,
+    batch_size: Option<u32>,
+    cert: rustls::Certificate,
+    key: rustls::PrivateKey,
+) -> Result<(), Box<dyn Error>> {
+    // Borrow a free port for the server
+    let addr: SocketAddr = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let server = ProtonServer::new(addr, cert, key, Arc::new(EchoHandler))?
+        .with_datagram_handler(Arc::new(EchoDatagrams));
+    tokio::spawn(async move { server.run().await });
+    tokio::time::sleep(STARTUP_DELAY + Duration::from_millis(500)).await;
+
+    let mut client = ProtonClient::new("127.0.0.1:0".parse()?)?;
+    let mut connection = client.connect(addr, Some(Duration::ZERO)).await?;
+    let mut samples = Vec::new();
+
+    // One event per round trip
+    let wire = udp_bytes(&connection);
+    let sent = efficiency(&connection);
+    let start = Instant::now();
+    for _ in 0..events {
+        connection.send_event().await?;
+    }
+    samples.push(Sample {
+        mode: "stream",
+        messages: events as u64,
+        app_bytes: events as u64 * (connection.event_frame_len() as u64 + ACK_LEN),
+        wire_bytes: udp_bytes(&connection) - wire,
+        efficiency: efficiency(&connection).since(&sent),
+        elapsed: start.elapsed(),
+    });
+
+    // Events pipelined in batches
+    let wire = udp_bytes(&connection);
+    let sent = efficiency(&connection);
+    let start = Instant::now();
+    let mut batched = 0;
+    while batched < events {
+        let size = batch_size.unwrap_or_else(|| connection.batch_policy().size);
+        let count = size.min(events - batched);
+        connection.send_event_batch(count).await?;
+        batched += count;
+    }
+    samples.push(Sample {
+        mode: "batched",
+        messages: events as u64,
+        app_bytes: events as u64 * (connection.event_frame_len() as u64 + ACK_LEN),
+        wire_bytes: udp_bytes(&connection) - wire,
+        efficiency: efficiency(&connection).since(&sent),
+        elapsed: start.elapsed(),
+    });
+
+    // Event ids as datagrams, echoed back by the server, without retransmits
+    let wire = udp_bytes(&connection);
+    let sent = efficiency(&connection);
+    let start = Instant::now();
+    let mut datagrams = 0;
+    for id in 0..events {
+        if connection.send_datagram(&id.to_le_bytes()).is_ok() {
+            datagrams += 1;
+        }
+    }
+    // Timed up to the last echo, leaving out the wait for lost ones
+    let mut echoed = 0;
+    let mut elapsed = start.elapsed();
+    while echoed < datagrams {
+        match tokio::time::timeout(DATAGRAM_DRAIN, connection.recv_datagram()).await {
+            Ok(Ok(_)) => {
+                echoed += 1;
+                elapsed = start.elapsed();
+            }
+            _ => break,
+        }
+    }
+    samples.push(Sample {
+        mode: "datagram",
+        messages: events as u64,
+        app_bytes: (datagrams + echoed) as u64 * DATAGRAM_LEN,
+        wire_bytes: udp_bytes(&connection) - wire,
+        efficiency: efficiency(&connection).since(&sent),
+        elapsed,
+    });
+    connection.close().await;
+
+    println!();
+    println!(
+        "{:<10} {:>9} {:>9} {:>11} {:>10} {:>11} {:>10} {:>9} {:>7} {:>9}",
+        "mode",
+        "messages",
+        "secs",
+        "msg/s",
+        "app MB/s",
+        "wire MB/s",
+        "wire B/msg",
+        "frames/dg",
+        "fill",
+        "overhead"
+    );
+    for sample in &samples {
+        println!("{}", sample.row());
+    }
+    println!(
+        "datagram: {} of {} sent, {} echoed back",
+        datagrams, events, echoed
+    );
+    Ok(())
+}
+
+/// A sustained load: `concurrency` connections each sending events with
+/// `payload_size` bytes of payload, one after another, for `duration`.
+pub struct Load {
+    pub payload_size: usize,
+    pub concurrency: u32,
+    pub duration: Duration,
+}
+
+// What one connection of a load run sent
+struct LoadSample {
+    // Round trip of each event, from sending it to its ack
+    latencies: Vec<Duration>,
+    wire_bytes: u64,
+}
+
+/// Runs a server and `load.concurrency` clients in this process on loopback
+/// and keeps each client sending events for `load.duration`, then prints the
+/// rates reached and percentiles of the event round trips.
+pub async fn run_load(
+    load: Load,
+    cert: rustls::Certificate,
+    key: rustls::PrivateKey,
+) -> Result<(), Box<dyn Error>> {
+    // Clients connect at once, so there are no earlier connections to wait out
+    let config = ProtonConfig {
+        startup_delay: Duration::ZERO,
+        ..ProtonConfig::default()
+    };
+    let addr: SocketAddr = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let server = ProtonServer::new(addr, cert, key, Arc::new(EchoHandler))?.with_config(config)?;
+    let mut listening = server.listening();
+    tokio::spawn(async move { server.run().await });
+    listening.wait_for(|listening| *listening).await?;
+
+    // Payloads need length-prefixed frames; without one, events stay fixed
+    let mut client = ProtonClient::new("127.0.0.1:0".parse()?)?.with_config(config)?;
+    if load.payload_size > 0 {
+        client = client.with_framing(Framing::LengthPrefixed);
+    }
+    let mut connections = Vec::new();
+    for _ in 0..load.concurrency {
+        connections.push(client.connect(addr, Some(Duration::ZERO)).await?);
+    }
+
+    let start = Instant::now();
+    let deadline = start + load.duration;
+    let workers: Vec<_> = connections
+        .into_iter()
+        .map(|mut connection| {
+            let payload = vec![0u8; load.payload_size];
+            tokio::spawn(async move {
+                let wire = udp_bytes(&connection);
+                let mut latencies = Vec::new();
+                while Instant::now() < deadline {
+                    let sent = Instant::now();
+                    if payload.is_empty() {
+                        connection.send_event().await?;
+                    } else {
+                        connection.send_event_with_payload(payload.clone()).await?;
+                    }
+                    latencies.push(sent.elapsed());
+                }
+                let wire_bytes = udp_bytes(&connection) - wire;
+                connection.close().await;
+                Ok::<_, quic_rs_debug::ProtonError>(LoadSample {
+                    latencies,
+                    wire_bytes,
+                })
+            })
+        })
+        .collect();
+    let mut latencies = Vec::new();
+    let mut wire_bytes = 0;
+    for worker in workers {
+        let sample = worker.await??;
+        latencies.extend(sample.latencies);
+        wire_bytes += sample.wire_bytes;
+    }
+    let secs = start.elapsed().as_secs_f64();
+    latencies.sort_unstable();
+
+    let events = latencies.len() as u64;
+    println!();
+    println!(
+        "load: {} connection(s), {} byte payloads, {:.1} s",
+        load.concurrency, load.payload_size, secs
+    );
+    println!(
+        "{:>9} {:>11} {:>14} {:>11}",
+        "events", "msg/s", "payload MB/s", "wire MB/s"
+    );
+    println!(
+        "{:>9} {:>11.0} {:>14.3} {:>11.3}",
+        events,
+        events as f64 / secs,
+        (events * load.payload_size as u64) as f64 / secs / 1e6,
+        wire_bytes as f64 / secs / 1e6,
+    );
+    if latencies.is_empty() {
+        println!("latency: no event was acknowledged");
+        return Ok(());
+    }
+    let percentile = |p: f64| {
+        let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+        latencies[rank.clamp(1, latencies.len()) - 1]
+    };
+    println!(
+        "latency: p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+        percentile(50.0),
+        percentile(90.0),
+        percentile(99.0),
+        percentile(99.9),
+        latencies[latencies.len() - 1]
+    );
+    Ok(())
+}
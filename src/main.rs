@@ -1,79 +1,1459 @@
+use clap::{Args, Parser, Subcommand};
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
 
+mod bridge;
 mod client_repl;
-mod proton;
+mod control;
+mod demo;
+mod loopback_bench;
+mod restart;
+mod storm;
 use crate::client_repl::ClientRepl;
-use crate::proton::{ProtonClient, ProtonServer};
+use quic_rs_debug::proton::access::{AccessList, Cidr};
+use quic_rs_debug::proton::admission::DuplicatePolicy;
+use quic_rs_debug::proton::breaker::BreakerConfig;
+use quic_rs_debug::proton::check::ConfigReport;
+use quic_rs_debug::proton::coalesce::CoalesceConfig;
+use quic_rs_debug::proton::compress::{Compression, CompressionConfig};
+use quic_rs_debug::proton::datagram::{EchoDatagrams, MAX_DATAGRAM_SIZE};
+use quic_rs_debug::proton::decode;
+use quic_rs_debug::proton::dedupe::FsyncPolicy;
+use quic_rs_debug::proton::drain::DEFAULT_DRAIN_TIMEOUT;
+use quic_rs_debug::proton::dscp::Dscp;
+use quic_rs_debug::proton::eventlog::Retention;
+use quic_rs_debug::proton::experiment::Experiment;
+use quic_rs_debug::proton::frame::{Headers, TraceParent};
+use quic_rs_debug::proton::grafana;
+use quic_rs_debug::proton::handler::{EchoHandler, ProtonHandler};
+use quic_rs_debug::proton::handoff::HandoffState;
+use quic_rs_debug::proton::ids::{
+    CounterIds, EpochIds, IdAllocator, IdScheme, PersistedIds, SnowflakeIds,
+};
+use quic_rs_debug::proton::keepalive::KeepAlive;
+use quic_rs_debug::proton::log::{self, LogFormat};
+use quic_rs_debug::proton::metrics::{MetricsExport, METRICS};
+use quic_rs_debug::proton::misbehave::{DelayDistribution, Misbehavior};
+use quic_rs_debug::proton::offline::{QueueConfig, QueuePolicy};
+use quic_rs_debug::proton::ordering::{EventOrdering, ACK_WINDOW};
+use quic_rs_debug::proton::outbox::DurableProducer;
+use quic_rs_debug::proton::payload::FileSink;
+use quic_rs_debug::proton::psk::load_psk;
+use quic_rs_debug::proton::quota::Quota;
+use quic_rs_debug::proton::ratelimit::RateLimitConfig;
+use quic_rs_debug::proton::reorder::ReorderConfig;
+use quic_rs_debug::proton::retry::{Idempotency, RetryPolicy};
+use quic_rs_debug::proton::settings::{AckMode, ClientSettings, PowerMode};
+use quic_rs_debug::proton::snapshot::ServerSnapshot;
+use quic_rs_debug::proton::stall::{StallConfig, StallPolicy};
+use quic_rs_debug::proton::streams::{EchoStream, StreamHandler, StreamType};
+use quic_rs_debug::proton::tls::{load_certs, load_private_key, load_root_store, TlsPolicy};
+use quic_rs_debug::proton::transcript::{self, Transcript};
+use quic_rs_debug::proton::violation::ProtocolMode;
+use quic_rs_debug::proton::window::DEFAULT_MAX_EVENT_WINDOW;
+use quic_rs_debug::proton::{
+    Framing, CERT_EXPIRY_WARNING_DAYS, HANDSHAKE_TIMEOUT, MAX_CONCURRENT_HANDSHAKES,
+    MAX_CONNECTIONS, MAX_CONNECT_RETRIES,
+};
+use quic_rs_debug::ProtonConnection;
+use quic_rs_debug::{ProtonClient, ProtonConfig, ProtonError, ProtonServer};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Parse command line arguments
-    let args: Vec<String> = std::env::args().collect();
+#[derive(Parser)]
+#[command(name = "proton", about = "Proton protocol over QUIC")]
+struct Cli {
+    /// Validate the configuration, print a report and exit without starting
+    #[arg(long, global = true)]
+    check_config: bool,
 
-    if args.len() < 2 {
-        println!(
-            "Usage: {} <server|client|client_repl> [server_addr]",
-            args[0]
-        );
-        return Ok(());
+    /// Restrict TLS 1.3 cipher suites, e.g. TLS13_AES_256_GCM_SHA384 (repeatable)
+    #[arg(long = "cipher-suite", global = true)]
+    cipher_suites: Vec<String>,
+
+    /// Restrict key exchange groups, e.g. X25519 or secp384r1 (repeatable)
+    #[arg(long = "kx-group", global = true)]
+    kx_groups: Vec<String>,
+
+    /// Log at this level and up: off, error, warn, info, debug or trace
+    #[arg(long, global = true, default_value_t = LevelFilter::INFO)]
+    log_level: LevelFilter,
+
+    /// Write logs as `text` or as one `json` object per line
+    #[arg(long, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Read transport parameters from this TOML file, e.g.
+    /// `idle_timeout_ms = 10000`; flags override it
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Close connections idle for this many milliseconds (5000 by default)
+    #[arg(long, global = true)]
+    idle_timeout_ms: Option<u64>,
+
+    /// Wait this many milliseconds before serving or connecting, so the
+    /// previous run's connections are gone (10000 by default)
+    #[arg(long, global = true)]
+    startup_delay_ms: Option<u64>,
+
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Run the Proton server
+    Server(Box<ServerArgs>),
+    /// Run the example client
+    Client(ClientArgs),
+    /// Run the interactive client REPL
+    #[command(name = "client_repl", alias = "client-repl")]
+    ClientRepl(ReplArgs),
+    /// Compare event throughput of each transport mode over loopback, or
+    /// measure a sustained load
+    Bench(BenchArgs),
+    /// Run a server and a scripted client in this process, explaining each step
+    Demo,
+    /// Forward records from stdin or a local socket as events
+    Bridge(BridgeArgs),
+    /// Print a Grafana dashboard for the server's metrics
+    Grafana,
+    /// Decode a capture of stream bytes, as hex or raw binary, offline
+    Decode(DecodeArgs),
+    /// Re-send a recorded transcript to a server and diff the responses
+    #[command(name = "replay-capture")]
+    ReplayCapture(ReplayArgs),
+}
+
+#[derive(Args)]
+struct ServerArgs {
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    bind: SocketAddr,
+    /// Serve on this inherited UDP socket instead of binding --bind; set by
+    /// a server handing over to this one on SIGUSR2
+    #[arg(long)]
+    socket_fd: Option<RawFd>,
+    /// Written to once the server listens, for the server handing over
+    #[arg(long, hide = true, requires = "socket_fd")]
+    ready_fd: Option<RawFd>,
+    /// Seconds a draining server waits for its connections to finish before
+    /// closing them
+    #[arg(long, default_value_t = DEFAULT_DRAIN_TIMEOUT.as_secs())]
+    drain_timeout_secs: u64,
+    /// Certificate chain to present, PEM or DER, instead of a generated
+    /// self-signed certificate
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// Private key belonging to --cert
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// Warn when the certificate expires in fewer than this many days
+    #[arg(long, default_value_t = CERT_EXPIRY_WARNING_DAYS)]
+    cert_warn_days: i64,
+    /// Start even if the certificate has already expired
+    #[arg(long)]
+    allow_expired_cert: bool,
+    /// Close connections that deviate from the protocol in any way, with a
+    /// diagnostic, instead of logging the deviations
+    #[arg(long)]
+    strict: bool,
+    /// Only admit clients from this CIDR block (repeatable)
+    #[arg(long = "allow")]
+    allow: Vec<Cidr>,
+    /// Reject clients from this CIDR block (repeatable)
+    #[arg(long = "deny")]
+    deny: Vec<Cidr>,
+    /// File of `allow <cidr>` / `deny <cidr>` lines, reloaded on SIGHUP
+    #[arg(long)]
+    access_list: Option<PathBuf>,
+    /// Log connection attempts rejected by the access list or rate limiter
+    #[arg(long)]
+    log_rejected: bool,
+    /// Limit new connection attempts per source IP to this many per second
+    #[arg(long, value_parser = parse_rate)]
+    conn_rate: Option<f64>,
+    /// Connection attempts a source may make in a burst
+    #[arg(long, default_value_t = 10)]
+    conn_burst: u32,
+    /// Ban a source after this many rate limited attempts
+    #[arg(long, default_value_t = 5)]
+    ban_after: u32,
+    /// How long a banned source is refused, in seconds
+    #[arg(long, default_value_t = 60)]
+    ban_secs: u64,
+    /// DER encoded OCSP response to staple, re-read periodically
+    #[arg(long)]
+    ocsp_response: Option<PathBuf>,
+    /// How often to re-read the OCSP response file, in seconds
+    #[arg(long, default_value_t = 3600)]
+    ocsp_refresh_secs: u64,
+    /// Require client certificates issued by the CAs in this file (mTLS)
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+    /// Certificate revocation list for client certificates (repeatable)
+    #[arg(long = "crl")]
+    crls: Vec<PathBuf>,
+    /// How often to re-read the CRL files, in seconds
+    #[arg(long, default_value_t = 3600)]
+    crl_refresh_secs: u64,
+    /// Abort handshakes, and pre-shared key authentication, that take longer
+    /// than this many seconds
+    #[arg(long, default_value_t = HANDSHAKE_TIMEOUT.as_secs())]
+    handshake_timeout_secs: u64,
+    /// Refuse new connections while this many handshakes are in flight
+    #[arg(long, default_value_t = MAX_CONCURRENT_HANDSHAKES)]
+    max_handshakes: u32,
+    /// Recommend clients persist their outbox cursor every this many events
+    #[arg(long)]
+    push_batch_size: Option<u32>,
+    /// Recommend clients send at most this many events per second
+    #[arg(long)]
+    push_rate_limit: Option<f64>,
+    /// Recommend clients acknowledge actions `manual`ly or `auto`matically
+    #[arg(long)]
+    push_ack_mode: Option<AckMode>,
+    /// Recommend clients run in `normal` or `low` power mode
+    #[arg(long)]
+    push_power_mode: Option<PowerMode>,
+    /// Maximum number of clients served at once
+    #[arg(long, default_value_t = MAX_CONNECTIONS)]
+    max_connections: u32,
+    /// At capacity, evict the lowest priority client for a higher priority one
+    #[arg(long)]
+    preempt: bool,
+    /// When a client connects while already connected: reject-new,
+    /// replace-old or allow-N
+    #[arg(long)]
+    duplicate_policy: Option<DuplicatePolicy>,
+    /// Require clients to authenticate with the pre-shared key in this file
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+    /// Bytes each tenant may exchange per UTC day
+    #[arg(long)]
+    daily_quota_bytes: Option<u64>,
+    /// Bytes each tenant may exchange per calendar month
+    #[arg(long)]
+    monthly_quota_bytes: Option<u64>,
+    /// Serve /metrics, /usage and /connections over HTTP on this address
+    #[arg(long)]
+    admin: Option<SocketAddr>,
+    /// Serve /metrics alone on this host:port, or with `log` or
+    /// `log:<secs>` log a summary of the counters periodically instead
+    #[arg(long)]
+    metrics_addr: Option<MetricsExport>,
+    /// Mark the server's packets with this DSCP, e.g. EF, AF11, CS1 or 0-63
+    #[arg(long)]
+    dscp: Option<Dscp>,
+    /// Write a qlog trace of each client's packets into this directory, for
+    /// qvis
+    #[arg(long)]
+    qlog: Option<PathBuf>,
+    /// Start from the protocol state in this snapshot file, exported from
+    /// another server's admin endpoint
+    #[arg(long)]
+    import_snapshot: Option<PathBuf>,
+    /// Write large event payloads to files in this directory
+    #[arg(long)]
+    payload_dir: Option<PathBuf>,
+    /// Send telemetry datagrams back to the client they came from
+    #[arg(long)]
+    echo_datagrams: bool,
+    /// Largest telemetry datagram accepted, in bytes
+    #[arg(long, default_value_t = MAX_DATAGRAM_SIZE)]
+    max_datagram_size: usize,
+    /// Coalesce tiny response frames into fewer stream writes
+    #[arg(long)]
+    coalesce: bool,
+    /// With --coalesce, write once this many bytes are buffered
+    #[arg(long, default_value_t = CoalesceConfig::default().max_bytes)]
+    coalesce_bytes: usize,
+    /// With --coalesce, write buffered frames after this many microseconds
+    #[arg(long, default_value_t = CoalesceConfig::default().max_delay.as_micros() as u64)]
+    coalesce_delay_us: u64,
+    /// Deliver events to the event sinks in id order, tolerating events that
+    /// arrive slightly out of order
+    #[arg(long)]
+    reorder: bool,
+    /// With --reorder, hold back at most this many events
+    #[arg(long, default_value_t = ReorderConfig::default().max_events)]
+    reorder_events: usize,
+    /// With --reorder, stop waiting for a missing event after this many
+    /// milliseconds
+    #[arg(long, default_value_t = ReorderConfig::default().max_delay.as_millis() as u64)]
+    reorder_delay_ms: u64,
+    /// Train zstd dictionaries on recent event payloads and push them to
+    /// clients that ask for compression
+    #[arg(long)]
+    compression: bool,
+    /// With --compression, payloads sampled for each dictionary
+    #[arg(long, default_value_t = CompressionConfig::default().samples)]
+    compression_samples: usize,
+    /// With --compression, largest dictionary trained, in bytes
+    #[arg(long, default_value_t = CompressionConfig::default().dictionary_size)]
+    compression_dictionary_size: usize,
+    /// Event ordering for clients that do not ask for one: contiguous,
+    /// monotonic or unordered
+    #[arg(long, default_value = "monotonic")]
+    event_ordering: EventOrdering,
+    /// Impose an event ordering on a tenant, as tenant=ordering (repeatable)
+    #[arg(long = "tenant-event-ordering", value_parser = parse_tenant_ordering)]
+    tenant_event_orderings: Vec<(String, EventOrdering)>,
+    /// Acknowledged events remembered per connection, acknowledged again
+    /// when retransmitted instead of processed twice (0 to disable)
+    #[arg(long, default_value_t = ACK_WINDOW)]
+    ack_window: usize,
+    /// Most events a client may keep in flight when it asks for an event
+    /// window (0 to send one at a time)
+    #[arg(long, default_value_t = DEFAULT_MAX_EVENT_WINDOW)]
+    max_event_window: u32,
+    /// Park connections idle for this many seconds, releasing their write
+    /// buffers until the next request
+    #[arg(long)]
+    park_after_secs: Option<u64>,
+    /// Send connections GOAWAY once they are this many seconds old, closing
+    /// them after --drain-timeout-secs unless the client reconnected first
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connection_age_secs: Option<u64>,
+    /// Persist the acknowledged events of clients that send a client id to
+    /// this log, so neither a reconnect nor a restart reopens them to
+    /// duplicate processing
+    #[arg(long)]
+    dedupe_log: Option<PathBuf>,
+    /// When appends to --dedupe-log are synced: always, never or
+    /// interval:<ms>
+    #[arg(long, default_value = "always")]
+    dedupe_fsync: FsyncPolicy,
+    /// Log the events handled for clients that send a client id to this
+    /// file, and ask reconnecting clients for events it lacks
+    #[arg(long)]
+    event_log: Option<PathBuf>,
+    /// When appends to --event-log are synced: always, never or
+    /// interval:<ms>
+    #[arg(long, default_value = "always")]
+    event_log_fsync: FsyncPolicy,
+    /// Drop events from --event-log once they are older than this many
+    /// seconds
+    #[arg(long)]
+    event_log_max_age_secs: Option<u64>,
+    /// Drop the oldest events from --event-log to keep it within this many
+    /// bytes
+    #[arg(long)]
+    event_log_max_bytes: Option<u64>,
+    /// Keep at most this many events in --event-log
+    #[arg(long)]
+    event_log_max_events: Option<usize>,
+    /// Count a client as stalled once a response has gone unread for this
+    /// many milliseconds
+    #[arg(long, default_value_t = StallConfig::default().after.as_millis() as u64)]
+    stall_timeout_ms: u64,
+    /// What to do about a stalled client: log, reset the stream or close the
+    /// connection
+    #[arg(long, default_value = "log")]
+    on_stall: StallPolicy,
+    /// Override --on-stall for a stream type, as stream=policy, e.g.
+    /// action=close (repeatable)
+    #[arg(long = "stream-on-stall", value_parser = parse_stream_stall)]
+    stream_on_stall: Vec<(String, StallPolicy)>,
+    /// Serve an echo stream of this type, as name:discriminator:kind with kind
+    /// request or bytes (repeatable)
+    #[arg(long = "echo-stream")]
+    echo_streams: Vec<StreamType>,
+    /// Misbehave: leave every Nth event unacknowledged
+    #[arg(long, default_value_t = 0)]
+    drop_ack_every: u64,
+    /// Misbehave: delay state commit responses, e.g. 100ms, 10ms..200ms or exp:50ms
+    #[arg(long)]
+    commit_delay: Option<DelayDistribution>,
+    /// Misbehave: answer every Nth event or state commit with the wrong id
+    #[arg(long, default_value_t = 0)]
+    wrong_id_every: u64,
+    /// Misbehave: chance from 0 to 1 of resetting a stream instead of answering
+    #[arg(long, default_value_t = 0.0)]
+    reset_probability: f64,
+    /// POST every accepted event as JSON to this http:// URL
+    #[cfg(feature = "webhook-sink")]
+    #[arg(long)]
+    webhook_sink: Option<String>,
+    /// Publish every accepted event as JSON to the NATS server at host:port
+    #[cfg(feature = "nats-sink")]
+    #[arg(long, requires = "nats_subject")]
+    nats_sink: Option<String>,
+    /// Subject to publish events to with --nats-sink
+    #[cfg(feature = "nats-sink")]
+    #[arg(long, requires = "nats_sink")]
+    nats_subject: Option<String>,
+    /// Answer events, state commits and actions with the callbacks in this
+    /// Lua script instead of echoing
+    #[cfg(feature = "lua-hooks")]
+    #[arg(long)]
+    handler_script: Option<PathBuf>,
+    /// Most memory in MiB the handler script may allocate
+    #[cfg(feature = "lua-hooks")]
+    #[arg(long, default_value_t = 16, requires = "handler_script")]
+    script_memory_mb: usize,
+    /// Most Lua instructions one handler script callback may run
+    #[cfg(feature = "lua-hooks")]
+    #[arg(long, default_value_t = 1_000_000, requires = "handler_script")]
+    script_instructions: u32,
+}
+
+#[derive(Args)]
+struct ReplArgs {
+    #[command(flatten)]
+    client: ClientArgs,
+    /// Drive the client process serving this control socket instead of
+    /// connecting directly
+    #[arg(long)]
+    attach: Option<PathBuf>,
+    /// Write a timeline of every command and frame to this file on exit:
+    /// Mermaid for `.mmd`, Chrome trace-event JSON otherwise
+    #[arg(long)]
+    timeline: Option<PathBuf>,
+    /// Write a transcript of every frame, for replay-capture, to this file
+    /// on exit
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Run the commands in this file instead of prompting, exiting with an
+    /// error at the first command that fails
+    #[arg(long)]
+    script: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// Transcript written by client_repl --record
+    transcript: PathBuf,
+    #[command(flatten)]
+    client: ClientArgs,
+    /// Send requests back to back instead of spaced as recorded
+    #[arg(long)]
+    no_timing: bool,
+}
+
+#[derive(Args)]
+struct BridgeArgs {
+    #[command(flatten)]
+    client: ClientArgs,
+    /// Read records from `stdin`, `tcp:<addr>` or `unix:<path>`
+    #[arg(long, default_value = "stdin")]
+    from: bridge::Source,
+    /// Records are `lines` or `length`-prefixed (big-endian u32)
+    #[arg(long, default_value = "lines")]
+    framing: bridge::Framing,
+}
+
+#[derive(Args)]
+struct DecodeArgs {
+    /// Capture to decode: a hex dump (plain, xxd or hexdump -C) or raw
+    /// bytes; `-` reads stdin
+    input: PathBuf,
+    /// How the capture's frames are laid out: fixed, length-prefixed or typed
+    #[arg(long, default_value = "fixed")]
+    framing: Framing,
+    /// Decode the server's responses on this stream (event, state-commit or
+    /// action) instead of what a client sent, which starts with the
+    /// discriminator
+    #[arg(long, value_parser = decode::parse_stream)]
+    responses: Option<u8>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Events sent through each mode
+    #[arg(long, default_value_t = 5000)]
+    events: u32,
+    /// Events per batch in batched mode (default: adapt to the connection)
+    #[arg(long)]
+    batch_size: Option<u32>,
+    /// Instead of comparing modes, send events for this many seconds and
+    /// report rates and latency percentiles
+    #[arg(long)]
+    duration: Option<u64>,
+    /// Payload bytes of each event sent for `--duration`
+    #[arg(long, default_value_t = 64, requires = "duration")]
+    payload_size: usize,
+    /// Connections sending events for `--duration` at once
+    #[arg(
+        long,
+        default_value_t = 1,
+        requires = "duration",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    concurrency: u32,
+}
+
+#[derive(Args)]
+struct ClientArgs {
+    #[arg(default_value = "127.0.0.1:5000")]
+    server_addr: String,
+    /// Require the server to staple a good OCSP response signed by its
+    /// certificate's issuer (needs --ca)
+    #[arg(long)]
+    require_ocsp: bool,
+    /// Verify the server certificate against the CAs in this file instead
+    /// of accepting any certificate
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// Name the server certificate must be valid for, sent as SNI
+    #[arg(long)]
+    server_name: Option<String>,
+    /// Client certificate chain to present for mTLS
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+    /// Private key for the client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+    /// Authenticate to the server with the pre-shared key in this file
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+    /// Send a header with every request, as key=value (repeatable)
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    /// Start a W3C trace and propagate its traceparent with every request
+    #[arg(long)]
+    trace: bool,
+    /// Persist the outbox cursor every this many events, overriding the server
+    #[arg(long)]
+    event_batch_size: Option<u32>,
+    /// Send at most this many events per second, overriding the server
+    #[arg(long)]
+    event_rate_limit: Option<f64>,
+    /// Acknowledge actions `manual`ly or `auto`matically, overriding the server
+    #[arg(long)]
+    ack_mode: Option<AckMode>,
+    /// Run in `normal` or `low` power mode, overriding the server
+    #[arg(long)]
+    power_mode: Option<PowerMode>,
+    /// Admission priority declared to the server (higher wins at capacity)
+    #[arg(long, default_value_t = 0)]
+    priority: u8,
+    /// Application name/version sent to the server in the HELLO
+    #[arg(long)]
+    user_agent: Option<String>,
+    /// Tenant the server accounts usage and quotas to
+    #[arg(long)]
+    tenant: Option<String>,
+    /// Identity the server's duplicate connection policy applies to
+    #[arg(long)]
+    client_id: Option<String>,
+    /// Ask the server to accept events in this order: contiguous, monotonic
+    /// or unordered
+    #[arg(long)]
+    event_ordering: Option<EventOrdering>,
+    /// Ask for `length-prefixed` or `typed` frames, so events, state commits
+    /// and actions can carry payload bytes
+    #[arg(long)]
+    framing: Option<Framing>,
+    /// Ask the server to compress event payloads with the dictionaries it
+    /// trains: zstd
+    #[arg(long)]
+    compression: Option<Compression>,
+    /// Keep up to this many events in flight instead of waiting for each
+    /// ack, if the server agrees
+    #[arg(long)]
+    event_window: Option<u32>,
+    /// Exchange heartbeats with the server every this many milliseconds,
+    /// measuring the round trip and closing the connection once the server
+    /// answers none for three intervals
+    #[arg(long)]
+    heartbeat_ms: Option<u64>,
+    /// Have the server push actions as they are produced, up to this many
+    /// unacknowledged, instead of waiting for read_action requests
+    #[arg(long)]
+    action_push: Option<u32>,
+    /// Instead of the example exchange, open and close this many
+    /// connections at random moments and summarize how each ended
+    #[arg(long)]
+    reconnect_storm: Option<u32>,
+    /// With --reconnect-storm, the window in milliseconds each connection
+    /// starts within, and the longest it is held
+    #[arg(long, default_value_t = 1000, requires = "reconnect_storm")]
+    storm_spread_ms: u64,
+    /// Bucket the client, by its --client-id, into a variant of an A/B
+    /// experiment: name=variant[:framing][*weight],... e.g.
+    /// codec=control,lp:length-prefixed
+    #[arg(long, requires = "client_id")]
+    experiment: Option<Experiment>,
+    /// Copy every acknowledged event to a secondary server at this address,
+    /// best effort, e.g. to shadow test a new server version
+    #[arg(long)]
+    mirror: Option<SocketAddr>,
+    /// Mark the client's packets with this DSCP, e.g. EF, AF11, CS1 or 0-63
+    #[arg(long)]
+    dscp: Option<Dscp>,
+    /// Write a qlog trace of the client's packets into this directory, for
+    /// qvis
+    #[arg(long)]
+    qlog: Option<PathBuf>,
+    /// Experimental: also probe the server from this local address, e.g.
+    /// that of a second uplink, and report its RTT and loss in the stats
+    /// (repeatable)
+    #[cfg(feature = "multipath")]
+    #[arg(long = "probe-path")]
+    probe_paths: Vec<std::net::IpAddr>,
+    /// Allocate event ids with a `counter`, a `persisted` counter, an
+    /// `epoch` and counter, or `snowflake` time based ids
+    #[arg(long, conflicts_with = "outbox")]
+    id_scheme: Option<IdScheme>,
+    /// File the persisted and epoch id schemes keep their state in
+    #[arg(long, requires = "id_scheme")]
+    id_file: Option<PathBuf>,
+    /// Reconnect on the next operation if the connection died while idle
+    #[arg(long)]
+    lazy_reconnect: bool,
+    /// Reconnect as soon as the connection is lost and resend the events
+    /// and action reads it was lost under
+    #[arg(long)]
+    auto_reconnect: bool,
+    /// Retries after a failed connection attempt, backing off exponentially
+    /// (5 by default)
+    #[arg(long)]
+    connect_retries: Option<u32>,
+    /// Fail connects and requests locally for a cool-down once too many of
+    /// the recent ones failed
+    #[arg(long)]
+    circuit_breaker: bool,
+    /// With --circuit-breaker, share of failures among the recent operations
+    /// that opens it
+    #[arg(long, default_value_t = BreakerConfig::default().failure_rate)]
+    breaker_failure_rate: f64,
+    /// With --circuit-breaker, number of recent operations considered
+    #[arg(long, default_value_t = BreakerConfig::default().window)]
+    breaker_window: usize,
+    /// With --circuit-breaker, seconds it stays open before a trial
+    #[arg(long, default_value_t = BreakerConfig::default().cool_down.as_secs())]
+    breaker_cool_down: u64,
+    /// Queue events sent while disconnected, flushing them on reconnect,
+    /// rather than failing them
+    #[arg(long)]
+    offline_queue: bool,
+    /// With --offline-queue, most events queued
+    #[arg(long, default_value_t = QueueConfig::default().max_events)]
+    queue_max_events: usize,
+    /// With --offline-queue, most bytes of events queued
+    #[arg(long, default_value_t = QueueConfig::default().max_bytes)]
+    queue_max_bytes: usize,
+    /// With --offline-queue, what to do when it is full: drop-oldest or
+    /// reject
+    #[arg(long, default_value = "drop-oldest")]
+    queue_policy: QueuePolicy,
+    /// Keep this many of the events sent last, to send again when a server
+    /// with an event log reports it lost them
+    #[arg(long)]
+    event_replay: Option<usize>,
+    /// Milliseconds without traffic after which a keepalive is sent (half
+    /// the idle timeout by default)
+    #[arg(long)]
+    keepalive_interval_ms: Option<u64>,
+    /// Send no keepalives while streams are exchanging data
+    #[arg(long)]
+    suppress_keepalive: bool,
+    /// Largest telemetry datagram sent or accepted, in bytes
+    #[arg(long, default_value_t = MAX_DATAGRAM_SIZE)]
+    max_datagram_size: usize,
+    /// Continue from the state in this file if it exists, and write the
+    /// state to it on exit for the next process
+    #[arg(long)]
+    handoff: Option<PathBuf>,
+    /// Allow opening streams of this type, as name:discriminator:kind
+    /// (repeatable)
+    #[arg(long = "stream-type")]
+    stream_types: Vec<StreamType>,
+    /// Write events to a durable outbox in this directory before sending
+    #[arg(long)]
+    outbox: Option<PathBuf>,
+    /// Stream this file to the server as the payload of an event
+    #[arg(long)]
+    payload_file: Option<PathBuf>,
+    /// Resume an interrupted payload of this event id instead of starting a
+    /// new event
+    #[arg(long, requires = "payload_file")]
+    payload_event_id: Option<u32>,
+    /// Memory map the payload file instead of reading it through a
+    /// buffer. The file must not be truncated or modified while it is sent.
+    #[arg(long)]
+    mmap: bool,
+    /// Keep the connection open and take commands on this Unix socket
+    /// instead of running the example loop
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .ok_or_else(|| format!("expected key=value, got '{}'", s))
+}
+
+fn parse_tenant_ordering(s: &str) -> Result<(String, EventOrdering), String> {
+    let (tenant, ordering) = parse_header(s)?;
+    let ordering = ordering.parse().map_err(|e| format!("{}", e))?;
+    Ok((tenant, ordering))
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !rate.is_finite() || rate < 0.0 {
+        return Err(format!("expected a non-negative rate, got '{}'", s));
+    }
+    Ok(rate)
+}
+
+fn parse_stream_stall(s: &str) -> Result<(String, StallPolicy), String> {
+    let (stream, policy) = parse_header(s)?;
+    Ok((stream, policy.parse()?))
+}
+
+fn generate_self_signed() -> Result<(rustls::Certificate, rustls::PrivateKey), Box<dyn Error>> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let cert = rustls::Certificate(cert.serialize_der()?);
+    Ok((cert, key))
+}
+
+fn resolve(server_addr: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    server_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to any address", server_addr).into())
+}
+
+fn load_access_list(
+    file: Option<&Path>,
+    allow: &[Cidr],
+    deny: &[Cidr],
+) -> Result<AccessList, Box<dyn Error>> {
+    let mut list = match file {
+        Some(path) => AccessList::load(path)?,
+        None => AccessList::default(),
+    };
+    list.allow.extend_from_slice(allow);
+    list.deny.extend_from_slice(deny);
+    Ok(list)
+}
+
+// Transport parameters from --config, overridden by the flags given
+fn load_transport(cli: &Cli) -> Result<ProtonConfig, ProtonError> {
+    let mut config = match cli.config {
+        Some(ref path) => ProtonConfig::load(path)?,
+        None => ProtonConfig::default(),
+    };
+    if let Some(ms) = cli.idle_timeout_ms {
+        config.idle_timeout = Duration::from_millis(ms);
+    }
+    if let Some(ms) = cli.startup_delay_ms {
+        config.startup_delay = Duration::from_millis(ms);
+    }
+    if let Mode::Client(args)
+    | Mode::ClientRepl(ReplArgs { client: args, .. })
+    | Mode::Bridge(BridgeArgs { client: args, .. })
+    | Mode::ReplayCapture(ReplayArgs { client: args, .. }) = &cli.mode
+    {
+        if let Some(retries) = args.connect_retries {
+            config.connect_retries = retries;
+        }
+        if let Some(ms) = args.keepalive_interval_ms {
+            config.keep_alive_interval = Some(Duration::from_millis(ms));
+        }
     }
+    config.validate()?;
+    Ok(config)
+}
 
-    match args[1].as_str() {
-        "server" => {
-            println!("Starting Proton server...");
-            let bind_addr: SocketAddr = "127.0.0.1:5000".parse()?;
+fn check_config(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let mut report = ConfigReport::new();
+    report.check_tls_policy(TlsPolicy::from_names(&cli.cipher_suites, &cli.kx_groups));
+    report.check_transport_config(load_transport(cli));
+    match &cli.mode {
+        Mode::Server(args) => {
+            report.check_bindable("bind address", args.bind);
+            report.check_access_list(load_access_list(
+                args.access_list.as_deref(),
+                &args.allow,
+                &args.deny,
+            ));
+            match (&args.cert, &args.key) {
+                (Some(cert), Some(key)) => report.check_tls_files(cert, key),
+                _ => report.check_no_tls_material(),
+            }
+            if let Some(ref path) = args.ocsp_response {
+                report.check_ocsp_response(path);
+            }
+            if let Some(ref path) = args.client_ca {
+                report.check_client_ca(path);
+            }
+            report.check_crls(&args.crls);
+            if let Some(ref path) = args.psk_file {
+                report.check_psk(path);
+            }
+            if let Some(ref path) = args.import_snapshot {
+                report.check_snapshot(path);
+            }
+        }
+        Mode::Client(args)
+        | Mode::ClientRepl(ReplArgs { client: args, .. })
+        | Mode::Bridge(BridgeArgs { client: args, .. })
+        | Mode::ReplayCapture(ReplayArgs { client: args, .. }) => {
+            report.check_resolvable("server address", &args.server_addr);
+            if let Some(ref ca) = args.ca {
+                report.check_root_ca(ca);
+            }
+            if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+                report.check_client_cert(cert, key);
+            }
+            if let Some(ref path) = args.psk_file {
+                report.check_psk(path);
+            }
+            if let Some(ref path) = args.handoff {
+                report.check_handoff(path);
+            }
+            if let Some(ref path) = args.payload_file {
+                report.check_readable("payload file", path);
+            }
+            report.check_bindable("client bind address", "127.0.0.1:0".parse()?);
+        }
+        Mode::Bench(_) | Mode::Demo => {
+            report.check_bindable("loopback address", "127.0.0.1:0".parse()?);
+        }
+        Mode::Grafana | Mode::Decode(_) => {}
+    }
 
+    println!("{}", report);
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn build_server(
+    args: &ServerArgs,
+    tls_policy: TlsPolicy,
+    transport: ProtonConfig,
+) -> Result<ProtonServer, Box<dyn Error>> {
+    let handler: Arc<dyn ProtonHandler> = Arc::new(EchoHandler);
+    #[cfg(feature = "lua-hooks")]
+    let handler = match args.handler_script {
+        Some(ref path) => {
+            use quic_rs_debug::proton::script::{LuaHandler, ScriptLimits};
+            let limits = ScriptLimits {
+                memory: args.script_memory_mb << 20,
+                instructions: args.script_instructions,
+            };
+            Arc::new(LuaHandler::load(path, limits)?)
+        }
+        None => handler,
+    };
+    let inherited = restart::inherited_socket(args.socket_fd)?;
+    // The server moves to the inherited socket, whose address is taken
+    let bind = match inherited {
+        Some(ref socket) => SocketAddr::new(socket.local_addr()?.ip(), 0),
+        None => args.bind,
+    };
+    let mut server = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => ProtonServer::with_cert_files(bind, cert, key, handler)?,
+        _ => {
             // Generate self-signed certificate for testing
-            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
-            let key = rustls::PrivateKey(cert.serialize_private_key_der());
-            let cert = rustls::Certificate(cert.serialize_der()?);
+            let (cert, key) = generate_self_signed()?;
+            ProtonServer::new(bind, cert, key, handler)?
+        }
+    };
+    if let Some(socket) = inherited {
+        server = server.with_socket(socket)?;
+    }
+    let mode = if args.strict {
+        ProtocolMode::Strict
+    } else {
+        ProtocolMode::Lenient
+    };
+    let mut server = server
+        .with_protocol_mode(mode)
+        .with_cert_expiry_warning(args.cert_warn_days)
+        .with_tls_policy(tls_policy)?
+        .with_config(transport)?
+        .with_allow_expired_cert(args.allow_expired_cert)
+        .with_access_list(load_access_list(
+            args.access_list.as_deref(),
+            &args.allow,
+            &args.deny,
+        )?)
+        .with_log_rejected(args.log_rejected)
+        .with_connection_limit(args.max_connections, args.preempt)?
+        .with_handshake_timeout(Duration::from_secs(args.handshake_timeout_secs))
+        .with_handshake_limit(args.max_handshakes)
+        .with_client_settings(ClientSettings {
+            event_batch_size: args.push_batch_size,
+            event_rate_limit: args.push_rate_limit,
+            ack_mode: args.push_ack_mode,
+            power_mode: args.push_power_mode,
+        })
+        .with_quota(Quota {
+            daily_bytes: args.daily_quota_bytes,
+            monthly_bytes: args.monthly_quota_bytes,
+        });
+    if let Some(addr) = args.admin {
+        server = server.with_admin_addr(addr);
+    }
+    if let Some(export) = args.metrics_addr {
+        server = server.with_metrics_export(export);
+    }
+    if let Some(dscp) = args.dscp {
+        server = server.with_dscp(dscp)?;
+    }
+    if let Some(dir) = &args.qlog {
+        server = server.with_qlog(dir)?;
+    }
+    if let Some(ref path) = args.import_snapshot {
+        server = server.with_snapshot(&ServerSnapshot::load(path)?);
+    }
+    if let Some(policy) = args.duplicate_policy {
+        server = server.with_duplicate_policy(policy)?;
+    }
+    server = server.with_drain_timeout(Duration::from_secs(args.drain_timeout_secs));
+    server = server.with_event_ordering(args.event_ordering);
+    for (tenant, ordering) in &args.tenant_event_orderings {
+        server = server.with_tenant_event_ordering(tenant, *ordering);
+    }
+    server = server.with_ack_window(args.ack_window);
+    server = server.with_max_event_window(args.max_event_window);
+    if let Some(secs) = args.park_after_secs {
+        server = server.with_parking(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.max_connection_age_secs {
+        server = server.with_max_connection_age(Duration::from_secs(secs));
+    }
+    server = server.with_stall_policy(StallConfig {
+        after: Duration::from_millis(args.stall_timeout_ms),
+        default: args.on_stall,
+        streams: args.stream_on_stall.iter().cloned().collect(),
+    });
+    if let Some(ref path) = args.dedupe_log {
+        server = server.with_dedupe_log(path, args.dedupe_fsync)?;
+    }
+    if let Some(ref path) = args.event_log {
+        let retention = Retention {
+            max_age: args.event_log_max_age_secs.map(Duration::from_secs),
+            max_bytes: args.event_log_max_bytes,
+            max_events: args.event_log_max_events,
+        };
+        server = server.with_event_log(path, args.event_log_fsync, retention)?;
+    }
+    for stream_type in &args.echo_streams {
+        let factory = |_: &str| -> Box<dyn StreamHandler> { Box::new(EchoStream) };
+        server = server.with_stream_type(stream_type.clone(), Arc::new(factory))?;
+    }
+    let misbehavior = Misbehavior {
+        drop_ack_every: args.drop_ack_every,
+        commit_delay: args.commit_delay,
+        wrong_id_every: args.wrong_id_every,
+        reset_probability: args.reset_probability,
+    };
+    if misbehavior.is_enabled() {
+        server = server.with_misbehavior(misbehavior);
+    }
+    if let Some(ref dir) = args.payload_dir {
+        server = server.with_payload_handler(Arc::new(FileSink::new(dir)?));
+    }
+    if args.echo_datagrams {
+        server = server.with_datagram_handler(Arc::new(EchoDatagrams));
+    }
+    server = server.with_max_datagram_size(args.max_datagram_size)?;
+    #[cfg(feature = "webhook-sink")]
+    if let Some(ref url) = args.webhook_sink {
+        use quic_rs_debug::proton::sink::WebhookSink;
+        server = server.with_event_sink(Arc::new(WebhookSink::new(url)?));
+    }
+    #[cfg(feature = "nats-sink")]
+    if let (Some(ref addr), Some(ref subject)) = (&args.nats_sink, &args.nats_subject) {
+        use quic_rs_debug::proton::sink::NatsSink;
+        server = server.with_event_sink(Arc::new(NatsSink::new(addr, subject)));
+    }
+    if args.coalesce {
+        server = server.with_coalescing(CoalesceConfig {
+            max_bytes: args.coalesce_bytes,
+            max_delay: Duration::from_micros(args.coalesce_delay_us),
+        });
+    }
+    if args.reorder {
+        server = server.with_reordering(ReorderConfig {
+            max_events: args.reorder_events,
+            max_delay: Duration::from_millis(args.reorder_delay_ms),
+        });
+    }
+    if args.compression {
+        server = server.with_compression(CompressionConfig {
+            samples: args.compression_samples,
+            dictionary_size: args.compression_dictionary_size,
+            ..CompressionConfig::default()
+        })?;
+    }
+    if let Some(ref path) = args.ocsp_response {
+        server = server
+            .with_ocsp_response_file(path.clone(), Duration::from_secs(args.ocsp_refresh_secs))?;
+    }
+    if let Some(ref path) = args.client_ca {
+        server = server.with_client_auth(load_root_store(path)?)?;
+    }
+    if !args.crls.is_empty() {
+        server = server.with_crl_files(
+            args.crls.clone(),
+            Duration::from_secs(args.crl_refresh_secs),
+        )?;
+    }
+    if let Some(ref path) = args.psk_file {
+        server = server.with_psk(load_psk(path)?)?;
+    }
+    if let Some(rate) = args.conn_rate {
+        server = server.with_rate_limit(RateLimitConfig {
+            rate,
+            burst: args.conn_burst,
+            ban_after: args.ban_after,
+            ban_duration: Duration::from_secs(args.ban_secs),
+        });
+    }
+    Ok(server)
+}
+
+fn build_client(
+    args: &ClientArgs,
+    tls_policy: TlsPolicy,
+    transport: ProtonConfig,
+) -> Result<ProtonClient, Box<dyn Error>> {
+    let bind_addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let mut client = ProtonClient::new(bind_addr)?
+        .with_tls_policy(tls_policy)?
+        .with_required_ocsp_staple(args.require_ocsp)?
+        .with_config(transport)?
+        .with_keepalive(KeepAlive {
+            interval: transport.keep_alive_interval(),
+            suppress_when_active: args.suppress_keepalive,
+        })?;
+    if let Some(ref ca) = args.ca {
+        client = client
+            .with_root_ca(load_root_store(ca)?)?
+            .with_ocsp_issuers(load_certs(ca)?)?;
+    } else if args.require_ocsp {
+        return Err("--require-ocsp needs --ca to verify the server certificate".into());
+    }
+    if let Some(ref name) = args.server_name {
+        client = client.with_server_name(name)?;
+    }
+    if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+        client = client.with_client_cert(load_certs(cert)?, load_private_key(key)?)?;
+    }
+    if let Some(ref path) = args.psk_file {
+        client = client.with_psk(load_psk(path)?)?;
+    }
+    if args.circuit_breaker {
+        client = client.with_circuit_breaker(BreakerConfig {
+            window: args.breaker_window,
+            min_operations: BreakerConfig::default()
+                .min_operations
+                .min(args.breaker_window),
+            failure_rate: args.breaker_failure_rate,
+            cool_down: Duration::from_secs(args.breaker_cool_down),
+        })?;
+    }
+    if args.offline_queue {
+        client = client.with_offline_queue(QueueConfig {
+            max_events: args.queue_max_events,
+            max_bytes: args.queue_max_bytes,
+            policy: args.queue_policy,
+        })?;
+    }
+    if let Some(capacity) = args.event_replay {
+        client = client.with_event_replay(capacity)?;
+    }
+    client = client
+        .with_max_datagram_size(args.max_datagram_size)?
+        .with_priority(args.priority)
+        .with_lazy_reconnect(args.lazy_reconnect)
+        .with_auto_reconnect(args.auto_reconnect)
+        .with_settings(ClientSettings {
+            event_batch_size: args.event_batch_size,
+            event_rate_limit: args.event_rate_limit,
+            ack_mode: args.ack_mode,
+            power_mode: None,
+        });
+    if args.mmap {
+        // SAFETY: --mmap documents that the payload file must stay unchanged
+        // while it is sent, and the operator passing it takes that on
+        client = unsafe { client.with_mmap_payloads() };
+    }
+    if let Some(mode) = args.power_mode {
+        client = client.with_power_mode(mode)?;
+    }
+    if let Some(ref user_agent) = args.user_agent {
+        client = client.with_user_agent(user_agent);
+    }
+    if let Some(ref tenant) = args.tenant {
+        client = client.with_tenant(tenant);
+    }
+    if let Some(ref client_id) = args.client_id {
+        client = client.with_client_id(client_id);
+    }
+    if let Some(ordering) = args.event_ordering {
+        client = client.with_event_ordering(ordering);
+    }
+    if let Some(framing) = args.framing {
+        client = client.with_framing(framing);
+    }
+    if let Some(compression) = args.compression {
+        client = client.with_compression(compression);
+    }
+    if let Some(size) = args.event_window {
+        client = client.with_event_window(size)?;
+    }
+    if let Some(ms) = args.heartbeat_ms {
+        client = client.with_heartbeat(Duration::from_millis(ms))?;
+    }
+    if let Some(window) = args.action_push {
+        client = client.with_action_push(window)?;
+    }
+    if let Some(ref experiment) = args.experiment {
+        client = client.with_experiment(experiment)?;
+    }
+    if let Some(addr) = args.mirror {
+        client = client.with_mirror(addr);
+    }
+    if let Some(dscp) = args.dscp {
+        client = client.with_dscp(dscp)?;
+    }
+    if let Some(dir) = &args.qlog {
+        client = client.with_qlog(dir)?;
+    }
+    #[cfg(feature = "multipath")]
+    if !args.probe_paths.is_empty() {
+        let locals: Vec<SocketAddr> = args
+            .probe_paths
+            .iter()
+            .map(|&ip| SocketAddr::new(ip, 0))
+            .collect();
+        client = client.with_probe_paths(&locals)?;
+    }
+    for stream_type in &args.stream_types {
+        client = client.with_stream_type(stream_type.clone())?;
+    }
+    if let Some(scheme) = args.id_scheme {
+        let ids: Arc<dyn IdAllocator> = match (scheme, &args.id_file) {
+            (IdScheme::Counter, _) => Arc::new(CounterIds::default()),
+            (IdScheme::Snowflake, _) => Arc::new(SnowflakeIds::default()),
+            (IdScheme::Persisted, Some(path)) => Arc::new(PersistedIds::open(path)?),
+            (IdScheme::Epoch, Some(path)) => Arc::new(EpochIds::open(path)?),
+            (_, None) => return Err(format!("--id-scheme {} needs --id-file", scheme).into()),
+        };
+        client = client.with_id_allocator(ids);
+    }
+    if let Some(ref path) = args.handoff {
+        if let Some(state) = HandoffState::load(path)? {
+            info!(
+                "Continuing after event {} and action {} from {}",
+                state.last_event_id,
+                state.action_offset,
+                path.display()
+            );
+            client = client.with_handoff_state(&state)?;
+        }
+    }
+    if args.trace || !args.headers.is_empty() {
+        let mut headers = Headers::new();
+        for (key, value) in &args.headers {
+            headers.insert(key, value)?;
+        }
+        if args.trace {
+            let trace = TraceParent::generate();
+            info!("Propagating trace {}", trace);
+            headers.set_traceparent(&trace);
+        }
+        client = client.with_frame_headers(headers);
+    }
+    Ok(client)
+}
+
+// Write the client's state for the next process, if asked to
+fn save_handoff(
+    args: &ClientArgs,
+    client: &ProtonClient,
+    outbox_cursor: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(ref path) = args.handoff {
+        let mut state = client.handoff_state();
+        state.outbox_cursor = outbox_cursor;
+        state.save(path)?;
+        info!(
+            "Handed off after event {} and action {} to {}",
+            state.last_event_id,
+            state.action_offset,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+// Send events through the durable outbox, resending any left over from a
+// previous run, and wait until the server has acknowledged all of them.
+// Returns the connection and the outbox cursor.
+async fn send_through_outbox(
+    dir: &Path,
+    connection: ProtonConnection,
+    handoff: Option<&Path>,
+) -> Result<(ProtonConnection, u32), Box<dyn Error>> {
+    let producer = DurableProducer::open(dir)?;
+    if let Some(state) = handoff.map(HandoffState::load).transpose()?.flatten() {
+        producer.check_handoff(&state)?;
+    }
+    info!(
+        "Outbox has {} unacknowledged events from earlier runs",
+        producer.pending()
+    );
+    let drain = producer.spawn_drain(connection);
+    // A burst is drained in batches, a trickle one event at a time
+    for _ in 0..20 {
+        producer.send_event()?;
+    }
+    info!("Stored a burst of 20 events in the outbox");
+    for _ in 0..5 {
+        let id = producer.send_event()?;
+        info!("Event {} stored in outbox", id);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    producer.close();
+    let connection = drain.await??;
+    info!("Outbox drained up to event {}", producer.durable_cursor());
+    Ok((connection, producer.durable_cursor()))
+}
+
+// Stream a file as an event payload. If the connection drops part way,
+// reconnect and resume after the chunks the server already holds.
+async fn send_payload_file(
+    client: &mut ProtonClient,
+    server_addr: SocketAddr,
+    mut connection: ProtonConnection,
+    path: &Path,
+    resume_event_id: Option<u32>,
+) -> Result<ProtonConnection, Box<dyn Error>> {
+    let mut result = match resume_event_id {
+        Some(event_id) => connection.resume_event_file(event_id, path).await,
+        None => connection.send_event_file(path).await,
+    };
+    let event_id = resume_event_id.unwrap_or_else(|| connection.last_event_id());
+    let mut attempt = 1;
+    loop {
+        match result {
+            Ok(_) => return Ok(connection),
+            Err(e) if attempt >= MAX_CONNECT_RETRIES || connection.close_reason().is_none() => {
+                return Err(e.into())
+            }
+            Err(_) => {
+                info!(
+                    "Connection lost, resuming event {} payload ({}/{})",
+                    event_id, attempt, MAX_CONNECT_RETRIES
+                );
+                attempt += 1;
+                connection = client.connect(server_addr, None).await?;
+                result = connection.resume_event_file(event_id, path).await;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    log::init(cli.log_level, cli.log_format)?;
 
-            let server = ProtonServer::new(bind_addr, cert, key)?;
+    if cli.check_config {
+        return check_config(&cli);
+    }
+    let tls_policy = TlsPolicy::from_names(&cli.cipher_suites, &cli.kx_groups)?;
+    let transport = load_transport(&cli)?;
+
+    match cli.mode {
+        Mode::Server(args) => {
+            let args = *args;
+            info!("Starting Proton server...");
+            let server = build_server(&args, tls_policy, transport)?;
+            restart::handle_handover_signal(&server)?;
+            if let Some(fd) = args.ready_fd {
+                restart::notify_ready(&server, fd);
+            }
+
+            // Re-read the access list file on SIGHUP
+            if let Some(path) = args.access_list {
+                let handle = server.access_list();
+                let mut hangup = signal(SignalKind::hangup())?;
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        match load_access_list(Some(&path), &args.allow, &args.deny) {
+                            Ok(list) => {
+                                *handle.write().unwrap() = list;
+                                info!("Reloaded access list from {}", path.display());
+                            }
+                            Err(e) => error!("Failed to reload access list: {}", e),
+                        }
+                    }
+                });
+            }
             server.run().await?;
             Ok(())
         }
-        "client" => {
-            let server_addr: SocketAddr = if args.len() > 2 {
-                args[2].parse()?
-            } else {
-                "127.0.0.1:5000".parse()?
-            };
+        Mode::Client(args) => {
+            let server_addr = resolve(&args.server_addr)?;
+            info!("Connecting to Proton server at {}...", server_addr);
 
-            let bind_addr: SocketAddr = "127.0.0.1:0".parse()?;
-            println!("Connecting to Proton server at {}...", server_addr);
-
-            let mut client = ProtonClient::new(bind_addr)?;
+            let mut client = build_client(&args, tls_policy, transport)?;
+            if let Some(connections) = args.reconnect_storm {
+                let spread = Duration::from_millis(args.storm_spread_ms);
+                let summary = storm::run(client, server_addr, connections, spread).await?;
+                println!("{}", summary);
+                return Ok(());
+            }
             let mut connection = client.connect(server_addr, None).await?;
+            let mut outbox_cursor = None;
+            if let Some(ref dir) = args.outbox {
+                let (drained, cursor) =
+                    send_through_outbox(dir, connection, args.handoff.as_deref()).await?;
+                connection = drained;
+                outbox_cursor = Some(cursor);
+            }
+            if let Some(ref path) = args.payload_file {
+                connection = send_payload_file(
+                    &mut client,
+                    server_addr,
+                    connection,
+                    path,
+                    args.payload_event_id,
+                )
+                .await?;
+            }
+            if let Some(ref path) = args.control_socket {
+                let handoff = client.clone();
+                control::serve(path, client, server_addr, connection).await?;
+                return save_handoff(&args, &handoff, outbox_cursor);
+            }
 
-            // Example: Send events and read actions in a loop
+            // Example: Send events and read actions in a loop. The built-in
+            // server answers state commits from their id alone, so they are
+            // marked idempotent and transient failures are retried.
+            let retry = RetryPolicy::default();
             for i in 0..5 {
-                connection.send_event().await?;
-                connection.send_state_commit(i).await?;
-                connection.read_action().await?;
+                let result = async {
+                    connection.send_event().await?;
+                    connection
+                        .send_state_commit_with_retry(i, &retry, Idempotency::Idempotent)
+                        .await?;
+                    let action = connection.read_action().await?;
+                    connection.ack_up_to(action);
+                    Ok::<_, ProtonError>(())
+                }
+                .await;
+                if let Err(e) = result {
+                    if let Some(reason) = connection.close_reason() {
+                        warn!("Connection closed by server: {}", reason);
+                    }
+                    // The ids used so far must not be reused by the next process
+                    save_handoff(&args, &client, outbox_cursor)?;
+                    return Err(e.into());
+                }
                 tokio::time::sleep(Duration::from_secs(1)).await;
             }
 
             // Explicitly close the connection when done
             connection.close().await;
-            Ok(())
+            save_handoff(&args, &client, outbox_cursor)
         }
-        "client_repl" => {
-            let server_addr: SocketAddr = if args.len() > 2 {
-                args[2].parse()?
-            } else {
-                "127.0.0.1:5000".parse()?
+        Mode::ClientRepl(args) => {
+            let mut repl = match args.attach {
+                Some(ref path) => ClientRepl::attach(path).await?,
+                None => {
+                    let server_addr = resolve(&args.client.server_addr)?;
+                    let client = build_client(&args.client, tls_policy, transport)?;
+                    ClientRepl::new(client, server_addr)?
+                }
             };
-
-            let bind_addr: SocketAddr = "127.0.0.1:0".parse()?;
-            let mut repl = ClientRepl::new(bind_addr, server_addr)?;
-            repl.run().await
+            if let Some(path) = args.timeline {
+                repl = repl.with_timeline(path);
+            }
+            if let Some(path) = args.record {
+                repl = repl.with_transcript(path);
+            }
+            match args.script {
+                Some(ref path) => repl.run_script(path).await,
+                None => repl.run().await,
+            }
         }
-        _ => {
-            println!("Invalid command. Use 'server', 'client' or 'client_repl'");
+        Mode::Bench(args) => {
+            let (cert, key) = generate_self_signed()?;
+            match args.duration {
+                Some(secs) => {
+                    let load = loopback_bench::Load {
+                        payload_size: args.payload_size,
+                        concurrency: args.concurrency,
+                        duration: Duration::from_secs(secs),
+                    };
+                    loopback_bench::run_load(load, cert, key).await
+                }
+                None => loopback_bench::run(args.events, args.batch_size, cert, key).await,
+            }
+        }
+        Mode::Demo => {
+            let (cert, key) = generate_self_signed()?;
+            demo::run(cert, key).await
+        }
+        Mode::Bridge(args) => {
+            let server_addr = resolve(&args.client.server_addr)?;
+            let mut client = build_client(&args.client, tls_policy, transport)?;
+            let mut connection = client.connect(server_addr, None).await?;
+            let result = bridge::run(&mut connection, &args.from, args.framing).await;
+            connection.close().await;
+            result
+        }
+        Mode::Grafana => {
+            print!("{}", grafana::dashboard(METRICS));
             Ok(())
         }
+        Mode::ReplayCapture(args) => {
+            let recorded = Transcript::read(&args.transcript)?;
+            let server_addr = resolve(&args.client.server_addr)?;
+            let mut client = build_client(&args.client, tls_policy, transport)?;
+            let mut connection = client.connect(server_addr, None).await?;
+            info!(
+                "Replaying {} frames from {}",
+                recorded.len(),
+                args.transcript.display()
+            );
+            let report = transcript::replay(&mut connection, &recorded, !args.no_timing).await;
+            connection.close().await;
+            println!("{}", report);
+            match report.differences.len() {
+                0 => Ok(()),
+                n => Err(format!("{} responses differ from the recording", n).into()),
+            }
+        }
+        Mode::Decode(args) => {
+            let input = if args.input == Path::new("-") {
+                let mut input = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut input)?;
+                input
+            } else {
+                std::fs::read(&args.input)?
+            };
+            let capture = decode::parse_capture(&input)?;
+            let mut decoder = decode::Decoder::new(args.framing);
+            if let Some(stream) = args.responses {
+                decoder = decoder.with_responses(stream);
+            }
+            let breakdown = decoder.decode(&capture);
+            print!("{}", breakdown);
+            match breakdown.error {
+                Some(_) => Err("capture has bytes that could not be decoded".into()),
+                None => Ok(()),
+            }
+        }
     }
 }
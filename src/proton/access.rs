@@ -0,0 +1,183 @@
+use crate::proton::ProtonError;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// An address block such as `10.0.0.0/8` or `::1/128`. A bare address is
+/// treated as a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Compare IPv4-mapped IPv6 peers against IPv4 blocks
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || invalid_access_list(&format!("invalid CIDR '{}'", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn invalid_access_list(msg: &str) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        msg.to_string(),
+    ))
+}
+
+/// Source address access control. Deny entries take precedence; when the allow
+/// list is non-empty only matching addresses are admitted.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    /// Parses a list file with one `allow <cidr>` or `deny <cidr>` per line.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<Self, ProtonError> {
+        let mut list = AccessList::default();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some(("allow", cidr)) => list.allow.push(cidr.parse()?),
+                Some(("deny", cidr)) => list.deny.push(cidr.parse()?),
+                _ => {
+                    return Err(invalid_access_list(&format!(
+                        "line {}: expected 'allow <cidr>' or 'deny <cidr>'",
+                        lineno + 1
+                    )))
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ProtonError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_bounds() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("10.1.2.3/32").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3/32").contains(ip("10.1.2.4")));
+        assert!(cidr("10.0.0.0/9").contains(ip("10.127.255.255")));
+        assert!(!cidr("10.0.0.0/9").contains(ip("10.128.0.0")));
+        assert_eq!(cidr("::1"), cidr("::1/128"));
+    }
+
+    #[test]
+    fn mapped_peers_match_ipv4_blocks() {
+        assert!(cidr("192.168.0.0/16").contains(ip("::ffff:192.168.4.2")));
+        assert!(!cidr("192.168.0.0/16").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn malformed_cidrs_are_rejected() {
+        for s in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let list = AccessList::parse("# office\nallow 10.0.0.0/8\n\n  deny 10.0.0.66  \n").unwrap();
+        assert!(list.is_allowed(ip("10.2.3.4")));
+        assert!(!list.is_allowed(ip("10.0.0.66")));
+        assert!(!list.is_allowed(ip("192.0.2.1")));
+
+        let open = AccessList::parse("deny 192.0.2.0/24").unwrap();
+        assert!(open.is_allowed(ip("198.51.100.1")));
+        assert!(!open.is_allowed(ip("192.0.2.200")));
+    }
+
+    #[test]
+    fn malformed_lines_name_the_line() {
+        let err = AccessList::parse("allow 10.0.0.0/8\npermit 10.0.0.1").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(AccessList::parse("allow").is_err());
+        assert!(AccessList::parse("deny 10.0.0.0/40").is_err());
+    }
+}
@@ -0,0 +1,289 @@
+#[cfg(feature = "dashboard")]
+use crate::proton::dashboard;
+use crate::proton::eventlog::EventLog;
+use crate::proton::metrics::ServerMetrics;
+use crate::proton::misbehave::{Misbehavior, MisbehaviorControl};
+use crate::proton::profile::{self, spawn_named};
+use crate::proton::quota::UsageLedger;
+use crate::proton::registry::ConnectionRegistry;
+use crate::proton::snapshot::{ServerSnapshot, SnapshotHandle};
+use crate::proton::ProtonError;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::timeout;
+use tracing::error;
+
+// Admin requests are a single request line plus headers
+const MAX_REQUEST_LEN: usize = 8192;
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const TEXT: &str = "text/plain; version=0.0.4";
+
+/// State served by the admin endpoint.
+#[derive(Clone)]
+pub struct AdminState {
+    pub metrics: Arc<ServerMetrics>,
+    pub usage: Arc<UsageLedger>,
+    pub registry: Arc<ConnectionRegistry>,
+    pub misbehavior: Arc<MisbehaviorControl>,
+    pub snapshots: SnapshotHandle,
+    pub event_log: Option<Arc<EventLog>>,
+}
+
+/// Serves a minimal plain HTTP admin endpoint on `addr`:
+///
+/// - `GET /metrics`: Prometheus text exposition of the server metrics
+/// - `GET /usage`: per tenant byte usage, one tenant per line
+/// - `GET /connections`: live connections with their counters, one per line
+/// - `GET /streams`: live connections, each followed by the protocol state
+///   of its open streams
+/// - `POST /key-update?id=<id>`: start a QUIC key update on the connection
+///   `id`, or on every live connection without `?id`
+/// - `GET /misbehavior`: the deliberate misbehavior currently configured
+/// - `POST /misbehavior?key=value&...`: change some misbehavior settings
+/// - `DELETE /misbehavior`: stop misbehaving
+/// - `GET /snapshot`: the server's protocol state as a snapshot file
+/// - `POST /snapshot?file=<path>`: import the snapshot file at `path` on
+///   the server's host
+/// - `GET /event-log`: the event log's size and what compaction dropped
+/// - `POST /event-log/compact`: compact the event log now
+/// - `GET /debug/profile`: poll time per named task in folded stack format,
+///   with the `profiling` feature
+/// - `GET /dashboard`: live connections, errors and latency charts in the
+///   browser, with the `dashboard` feature
+pub async fn spawn(addr: SocketAddr, state: AdminState) -> Result<SocketAddr, ProtonError> {
+    listen(addr, state, false).await
+}
+
+/// Serves `GET /metrics` alone on `addr`, so metrics can be scraped from a
+/// network the rest of the admin endpoint is not exposed to.
+pub async fn spawn_metrics(addr: SocketAddr, state: AdminState) -> Result<SocketAddr, ProtonError> {
+    listen(addr, state, true).await
+}
+
+async fn listen(
+    addr: SocketAddr,
+    state: AdminState,
+    metrics_only: bool,
+) -> Result<SocketAddr, ProtonError> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // A server taking over from this one binds the same address while this
+    // one drains
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    let listener = socket.listen(1024)?;
+    let local = listener.local_addr()?;
+    spawn_named("admin", async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    spawn_named("admin request", async move {
+                        if let Err(e) = serve(stream, &state, metrics_only).await {
+                            error!("Admin request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Admin endpoint accept failed: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    state: &AdminState,
+    metrics_only: bool,
+) -> Result<(), ProtonError> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    // Read until the end of the request headers
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some(_), Some(path)) if metrics_only && path != "/metrics" => {
+            ("404 Not Found", TEXT, "not found\n".to_string())
+        }
+        (Some("GET"), Some("/metrics")) => {
+            let mut body = state.metrics.render();
+            body.push_str(&state.usage.render());
+            if let Some(ref log) = state.event_log {
+                body.push_str(&log.render());
+            }
+            ("200 OK", TEXT, body)
+        }
+        (Some("GET"), Some("/usage")) => {
+            let body: String = state
+                .usage
+                .report()
+                .iter()
+                .map(|r| format!("{}\n", r))
+                .collect();
+            ("200 OK", TEXT, body)
+        }
+        (Some("GET"), Some("/connections")) => {
+            let body: String = state
+                .registry
+                .list()
+                .iter()
+                .map(|c| format!("{}\n", c.stats()))
+                .collect();
+            ("200 OK", TEXT, body)
+        }
+        (Some("GET"), Some("/streams")) => {
+            let mut body = String::new();
+            for connection in state.registry.list() {
+                body.push_str(&format!("{} {}\n", connection.id(), connection.addr()));
+                for stream in connection.streams() {
+                    body.push_str(&format!("  {}\n", stream));
+                }
+            }
+            ("200 OK", TEXT, body)
+        }
+        (Some("POST"), Some(path)) if path.split('?').next() == Some("/key-update") => {
+            let query = path.split_once('?').map_or("", |(_, query)| query);
+            let connections = match query.strip_prefix("id=").map(str::parse) {
+                _ if query.is_empty() => Ok(state.registry.list()),
+                Some(Ok(id)) => Ok(state.registry.get(id).into_iter().collect()),
+                Some(Err(_)) | None => Err("expected ?id=<id> or no query\n".to_string()),
+            };
+            match connections {
+                Ok(connections) if connections.is_empty() && !query.is_empty() => {
+                    ("404 Not Found", TEXT, "no such connection\n".to_string())
+                }
+                Ok(connections) => {
+                    let body: String = connections
+                        .iter()
+                        .map(|c| {
+                            c.update_keys();
+                            format!("{} {} key_updates={}\n", c.id(), c.addr(), c.key_updates())
+                        })
+                        .collect();
+                    ("200 OK", TEXT, body)
+                }
+                Err(e) => ("400 Bad Request", TEXT, e),
+            }
+        }
+        (Some("GET"), Some("/misbehavior")) => {
+            ("200 OK", TEXT, format!("{}\n", state.misbehavior.get()))
+        }
+        (Some("POST"), Some(path)) if path.split('?').next() == Some("/misbehavior") => {
+            let mut misbehavior = state.misbehavior.get();
+            let query = path.split_once('?').map_or("", |(_, query)| query);
+            match misbehavior.update(query) {
+                Ok(()) => {
+                    state.misbehavior.replace(misbehavior.clone());
+                    ("200 OK", TEXT, format!("{}\n", misbehavior))
+                }
+                Err(e) => ("400 Bad Request", TEXT, format!("{}\n", e)),
+            }
+        }
+        (Some("DELETE"), Some("/misbehavior")) => {
+            state.misbehavior.replace(Misbehavior::default());
+            ("200 OK", TEXT, format!("{}\n", Misbehavior::default()))
+        }
+        (Some("GET"), Some("/snapshot")) => ("200 OK", TEXT, state.snapshots.export().to_string()),
+        (Some("POST"), Some(path)) if path.split('?').next() == Some("/snapshot") => {
+            let query = path.split_once('?').map_or("", |(_, query)| query);
+            match query.strip_prefix("file=") {
+                Some(file) => match ServerSnapshot::load(Path::new(file)) {
+                    Ok(snapshot) => {
+                        state.snapshots.import(&snapshot);
+                        ("200 OK", TEXT, snapshot.to_string())
+                    }
+                    Err(e) => ("400 Bad Request", TEXT, format!("{}: {}\n", file, e)),
+                },
+                None => (
+                    "400 Bad Request",
+                    TEXT,
+                    "expected ?file=<path>\n".to_string(),
+                ),
+            }
+        }
+        (Some(_), Some("/event-log" | "/event-log/compact")) if state.event_log.is_none() => (
+            "404 Not Found",
+            TEXT,
+            "no event log configured\n".to_string(),
+        ),
+        (Some("GET"), Some("/event-log")) => {
+            let log = state.event_log.as_ref().unwrap();
+            (
+                "200 OK",
+                TEXT,
+                format!("{} retention: {}\n", log.stats(), log.retention()),
+            )
+        }
+        (Some("POST"), Some("/event-log/compact")) => {
+            let log = Arc::clone(state.event_log.as_ref().unwrap());
+            match tokio::task::spawn_blocking(move || log.compact().map(|c| (c, log.stats()))).await
+            {
+                Ok(Ok((compaction, stats))) => {
+                    ("200 OK", TEXT, format!("{}\n{}\n", compaction, stats))
+                }
+                Ok(Err(e)) => (
+                    "500 Internal Server Error",
+                    TEXT,
+                    format!("compaction failed: {}\n", e),
+                ),
+                Err(e) => (
+                    "500 Internal Server Error",
+                    TEXT,
+                    format!("compaction panicked: {}\n", e),
+                ),
+            }
+        }
+        (Some("GET"), Some("/debug/profile")) => match profile::folded() {
+            Some(body) => ("200 OK", TEXT, body),
+            None => (
+                "404 Not Found",
+                TEXT,
+                "built without the profiling feature\n".to_string(),
+            ),
+        },
+        #[cfg(feature = "dashboard")]
+        (Some("GET"), Some("/dashboard")) => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            dashboard::PAGE.to_string(),
+        ),
+        #[cfg(feature = "dashboard")]
+        (Some("GET"), Some("/dashboard/data")) => {
+            ("200 OK", "application/json", dashboard::data(state))
+        }
+        #[cfg(not(feature = "dashboard"))]
+        (Some("GET"), Some("/dashboard" | "/dashboard/data")) => (
+            "404 Not Found",
+            TEXT,
+            "built without the dashboard feature\n".to_string(),
+        ),
+        (Some(_), Some(_)) => ("404 Not Found", TEXT, "not found\n".to_string()),
+        _ => ("400 Bad Request", TEXT, "bad request\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
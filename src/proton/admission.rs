@@ -0,0 +1,225 @@
+use crate::proton::ProtonError;
+use quinn::Connection as QuinnConnection;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+pub use crate::proton::wire::{
+    CLOSE_AT_CAPACITY, CLOSE_DUPLICATE, CLOSE_PREEMPTED, CLOSE_REPLACED,
+};
+
+/// What to do when a client connects while already connected, e.g. because
+/// it lost its previous connection before the server noticed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Turn the new connection away
+    RejectNew,
+    /// Close the old connection in favor of the new one
+    ReplaceOld,
+    /// Allow up to this many connections, turning away any more
+    AllowN(u32),
+}
+
+impl DuplicatePolicy {
+    fn limit(self) -> usize {
+        match self {
+            DuplicatePolicy::RejectNew | DuplicatePolicy::ReplaceOld => 1,
+            DuplicatePolicy::AllowN(n) => n as usize,
+        }
+    }
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-new" => Ok(DuplicatePolicy::RejectNew),
+            "replace-old" => Ok(DuplicatePolicy::ReplaceOld),
+            _ => match s.strip_prefix("allow-").map(str::parse::<u32>) {
+                Some(Ok(n)) if n > 0 => Ok(DuplicatePolicy::AllowN(n)),
+                _ => Err(ProtonError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "unknown duplicate policy '{}', expected reject-new, replace-old or allow-N",
+                        s
+                    ),
+                ))),
+            },
+        }
+    }
+}
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicatePolicy::RejectNew => write!(f, "reject-new"),
+            DuplicatePolicy::ReplaceOld => write!(f, "replace-old"),
+            DuplicatePolicy::AllowN(n) => write!(f, "allow-{}", n),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Admitted {
+    id: u64,
+    addr: SocketAddr,
+    identity: String,
+    priority: u8,
+    connection: QuinnConnection,
+}
+
+#[derive(Debug)]
+pub enum AdmissionDecision {
+    /// Admitted under the connection limit
+    Admitted(u64),
+    /// Admitted by evicting the lowest priority client, which the caller
+    /// must close
+    Preempted {
+        id: u64,
+        evicted: QuinnConnection,
+        evicted_addr: SocketAddr,
+    },
+    /// Admitted in place of the same client's oldest connection, which the
+    /// caller must close
+    Replaced {
+        id: u64,
+        replaced: QuinnConnection,
+        replaced_addr: SocketAddr,
+    },
+    Rejected,
+    /// The client already has as many connections as its policy allows
+    Duplicate,
+}
+
+/// Tracks admitted connections against the connection limit. With preemption
+/// enabled a newcomer at capacity evicts the lowest priority connection, if
+/// that one has a strictly lower priority; otherwise it is rejected. With a
+/// duplicate policy, connections from a client that is already connected
+/// are handled by that policy first.
+#[derive(Debug)]
+pub struct Admission {
+    max_connections: u32,
+    preempt: bool,
+    duplicates: Option<DuplicatePolicy>,
+    next_id: u64,
+    admitted: Vec<Admitted>,
+}
+
+impl Admission {
+    pub fn new(max_connections: u32, preempt: bool) -> Self {
+        Self {
+            max_connections,
+            preempt,
+            duplicates: None,
+            next_id: 0,
+            admitted: Vec::new(),
+        }
+    }
+
+    pub fn max_connections(&self) -> u32 {
+        self.max_connections
+    }
+
+    pub fn preempt(&self) -> bool {
+        self.preempt
+    }
+
+    pub fn duplicate_policy(&self) -> Option<DuplicatePolicy> {
+        self.duplicates
+    }
+
+    pub fn set_duplicate_policy(&mut self, policy: Option<DuplicatePolicy>) {
+        self.duplicates = policy;
+    }
+
+    /// Connections to accept at the QUIC level. Preemption and duplicate
+    /// policies need one spare, so a newcomer can send its HELLO before the
+    /// server decides between it and a connected client.
+    pub fn connection_slots(&self) -> u32 {
+        self.max_connections + u32::from(self.preempt || self.duplicates.is_some())
+    }
+
+    /// Admits a connection from the client known as `identity`.
+    pub fn admit(
+        &mut self,
+        addr: SocketAddr,
+        identity: &str,
+        priority: u8,
+        connection: &QuinnConnection,
+    ) -> AdmissionDecision {
+        let mut replaced = None;
+        if let Some(policy) = self.duplicates {
+            let existing = self
+                .admitted
+                .iter()
+                .filter(|a| a.identity == identity)
+                .count();
+            if existing >= policy.limit() {
+                if policy != DuplicatePolicy::ReplaceOld {
+                    return AdmissionDecision::Duplicate;
+                }
+                // Oldest first, as newer connections are likelier to be alive
+                if let Some(i) = self.admitted.iter().position(|a| a.identity == identity) {
+                    replaced = Some(self.admitted.remove(i));
+                }
+            }
+        }
+
+        let mut evicted = None;
+        if self.admitted.len() >= self.max_connections as usize {
+            if !self.preempt {
+                return AdmissionDecision::Rejected;
+            }
+            // Evict the lowest priority, oldest first among equals
+            let victim = self
+                .admitted
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, a)| (a.priority, a.id))
+                .map(|(i, a)| (i, a.priority));
+            match victim {
+                Some((i, lowest)) if lowest < priority => {
+                    evicted = Some(self.admitted.remove(i));
+                }
+                _ => return AdmissionDecision::Rejected,
+            }
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        self.admitted.push(Admitted {
+            id,
+            addr,
+            identity: identity.to_string(),
+            priority,
+            connection: connection.clone(),
+        });
+        match (replaced, evicted) {
+            (Some(old), _) => AdmissionDecision::Replaced {
+                id,
+                replaced: old.connection,
+                replaced_addr: old.addr,
+            },
+            (None, Some(victim)) => AdmissionDecision::Preempted {
+                id,
+                evicted: victim.connection,
+                evicted_addr: victim.addr,
+            },
+            (None, None) => AdmissionDecision::Admitted(id),
+        }
+    }
+
+    /// Forgets an admitted connection once it has finished.
+    pub fn release(&mut self, id: u64) {
+        self.admitted.retain(|a| a.id != id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.admitted.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.admitted.is_empty()
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Largest batch of events pipelined before waiting for their acks.
+pub const MAX_BATCH_SIZE: u32 = 1024;
+/// Longest the outbox waits for a batch to fill before sending it.
+pub const MAX_FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How the outbox groups events: up to `size` events are written back to
+/// back and acknowledged together, and a partial batch is held for at most
+/// `flush_interval` waiting for more events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchPolicy {
+    pub size: u32,
+    pub flush_interval: Duration,
+    /// Derived from the connection rather than configured
+    pub adaptive: bool,
+}
+
+impl BatchPolicy {
+    /// A configured batch size, sent as soon as events are available.
+    pub fn fixed(size: u32) -> Self {
+        Self {
+            size: size.max(1),
+            flush_interval: Duration::ZERO,
+            adaptive: false,
+        }
+    }
+
+    /// Derives the batch from the path. A batch should fill the congestion
+    /// window, since each batch costs a round trip, but waiting for it to fill
+    /// costs latency, so the flush interval is a fraction of the RTT. On a LAN
+    /// this sends almost immediately; on a WAN it trades a little latency for
+    /// far fewer round trips.
+    pub fn adaptive(rtt: Duration, cwnd: u64, frame_len: usize) -> Self {
+        let size = (cwnd / frame_len.max(1) as u64).clamp(1, MAX_BATCH_SIZE as u64) as u32;
+        Self {
+            size,
+            flush_interval: (rtt / 4).min(MAX_FLUSH_INTERVAL),
+            adaptive: true,
+        }
+    }
+}
+
+impl fmt::Display for BatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} events, flush after {:?}{}",
+            self.size,
+            self.flush_interval,
+            if self.adaptive { " (adaptive)" } else { "" }
+        )
+    }
+}
@@ -0,0 +1,213 @@
+use crate::proton::ProtonError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::info;
+
+/// When a [`CircuitBreaker`] opens and how long it stays open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    /// Number of most recent operations the failure rate is taken over
+    pub window: usize,
+    /// Operations needed in the window before the breaker may open
+    pub min_operations: usize,
+    /// Share of failed operations in the window, in (0, 1], that opens it
+    pub failure_rate: f64,
+    /// How long the breaker fails operations locally before letting one
+    /// through as a trial
+    pub cool_down: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_operations: 5,
+            failure_rate: 0.5,
+            cool_down: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Operations go through, and their outcomes are counted
+    Closed,
+    /// Operations fail locally with `ProtonError::CircuitOpen`
+    Open,
+    /// The cool-down is over: the next outcome closes or re-opens it
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// A circuit breaker's state and what it has done so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerStats {
+    pub state: BreakerState,
+    /// Times it opened
+    pub opened: u64,
+    /// Operations failed locally while it was open
+    pub rejected: u64,
+}
+
+impl fmt::Display for BreakerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, opened {} times, {} operations rejected",
+            self.state, self.opened, self.rejected
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    // Most recent outcomes, true for a failure
+    outcomes: VecDeque<bool>,
+    opened_at: Option<Instant>,
+}
+
+/// Stops a client from hammering a server that is down: once too many of
+/// its recent connects and requests failed, operations fail fast with
+/// `ProtonError::CircuitOpen` for a cool-down period. The operation after
+/// that is a trial, which closes the breaker if it succeeds.
+///
+/// Only transport failures count: connection errors and timeouts. An
+/// answer from the server, even a refusal such as `QuotaExceeded`, shows
+/// it is up.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    window: Mutex<Window>,
+    state: watch::Sender<BreakerState>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Result<Self, ProtonError> {
+        if config.window == 0
+            || config.min_operations > config.window
+            || !(config.failure_rate > 0.0 && config.failure_rate <= 1.0)
+        {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "circuit breaker needs a window of at least min operations and a failure rate in (0, 1]",
+            )));
+        }
+        Ok(Self {
+            config,
+            window: Mutex::new(Window::default()),
+            state: watch::channel(BreakerState::Closed).0,
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> BreakerConfig {
+        self.config
+    }
+
+    pub fn state(&self) -> BreakerState {
+        *self.state.borrow()
+    }
+
+    /// Follows the breaker's state as it opens and closes.
+    pub fn subscribe(&self) -> watch::Receiver<BreakerState> {
+        self.state.subscribe()
+    }
+
+    pub fn stats(&self) -> BreakerStats {
+        BreakerStats {
+            state: self.state(),
+            opened: self.opened.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Called before an operation: fails it if the breaker is open.
+    pub fn check(&self) -> Result<(), ProtonError> {
+        let window = self.window.lock().unwrap();
+        if self.state() != BreakerState::Open {
+            return Ok(());
+        }
+        if window
+            .opened_at
+            .is_some_and(|at| at.elapsed() >= self.config.cool_down)
+        {
+            self.transition(BreakerState::HalfOpen);
+            return Ok(());
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        Err(ProtonError::CircuitOpen)
+    }
+
+    /// Called with the result of an operation let through by `check`.
+    pub fn record<T>(&self, result: &Result<T, ProtonError>) {
+        let failed = match result {
+            Ok(_) => false,
+            // Failed locally, nothing was sent
+            Err(ProtonError::CircuitOpen) => return,
+            Err(ProtonError::IoError(e)) if e.kind() == std::io::ErrorKind::InvalidInput => return,
+            Err(
+                ProtonError::ConnectionError
+                | ProtonError::ClosedByPeer(_)
+                | ProtonError::Timeout
+                | ProtonError::IoError(_),
+            ) => true,
+            Err(_) => false,
+        };
+        let mut window = self.window.lock().unwrap();
+        match self.state() {
+            BreakerState::Closed => {
+                if window.outcomes.len() == self.config.window {
+                    window.outcomes.pop_front();
+                }
+                window.outcomes.push_back(failed);
+                let failures = window.outcomes.iter().filter(|&&f| f).count();
+                let total = window.outcomes.len();
+                if total >= self.config.min_operations
+                    && failures as f64 >= self.config.failure_rate * total as f64
+                {
+                    self.open(&mut window);
+                }
+            }
+            BreakerState::HalfOpen if failed => self.open(&mut window),
+            BreakerState::HalfOpen => {
+                window.outcomes.clear();
+                self.transition(BreakerState::Closed);
+            }
+            // Finished after another operation opened it
+            BreakerState::Open => {}
+        }
+    }
+
+    fn open(&self, window: &mut Window) {
+        window.opened_at = Some(Instant::now());
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        self.transition(BreakerState::Open);
+    }
+
+    fn transition(&self, to: BreakerState) {
+        let from = self.state.send_replace(to);
+        match to {
+            BreakerState::Open => info!(
+                "Circuit breaker {} -> open, failing operations for {:?}",
+                from, self.config.cool_down
+            ),
+            _ => info!("Circuit breaker {} -> {}", from, to),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use crate::proton::access::AccessList;
+use crate::proton::handoff::HandoffState;
+use crate::proton::psk::load_psk;
+use crate::proton::snapshot::ServerSnapshot;
+use crate::proton::tls::{
+    certificate_validity, check_ocsp_response, inspect_chain, load_certs, load_crls,
+    load_private_key, load_root_store, verify_key_pair, OcspStatus, TlsPolicy,
+};
+use crate::proton::{ProtonConfig, ProtonError, CERT_EXPIRY_WARNING_DAYS};
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct CheckItem {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// Result of a `--check-config` run. Each validation step adds one item.
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub items: Vec<CheckItem>,
+}
+
+impl ConfigReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, name: &str, status: CheckStatus, detail: String) {
+        self.items.push(CheckItem {
+            name: name.to_string(),
+            status,
+            detail,
+        });
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.items.iter().all(|i| i.status != CheckStatus::Failed)
+    }
+
+    /// Verifies the address can be bound as a UDP socket.
+    pub fn check_bindable(&mut self, name: &str, addr: SocketAddr) {
+        match UdpSocket::bind(addr) {
+            Ok(socket) => {
+                let local = socket
+                    .local_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                self.push(
+                    name,
+                    CheckStatus::Ok,
+                    format!("{} is bindable ({})", addr, local),
+                );
+            }
+            Err(e) => self.push(
+                name,
+                CheckStatus::Failed,
+                format!("cannot bind {}: {}", addr, e),
+            ),
+        }
+    }
+
+    /// Resolves `host:port`, returning the first address found.
+    pub fn check_resolvable(&mut self, name: &str, target: &str) -> Option<SocketAddr> {
+        match target.to_socket_addrs() {
+            Ok(addrs) => {
+                let addrs: Vec<SocketAddr> = addrs.collect();
+                match addrs.first() {
+                    Some(first) => {
+                        let all: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+                        self.push(
+                            name,
+                            CheckStatus::Ok,
+                            format!("{} resolves to {}", target, all.join(", ")),
+                        );
+                        Some(*first)
+                    }
+                    None => {
+                        self.push(
+                            name,
+                            CheckStatus::Failed,
+                            format!("{} has no addresses", target),
+                        );
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                self.push(
+                    name,
+                    CheckStatus::Failed,
+                    format!("cannot resolve {}: {}", target, e),
+                );
+                None
+            }
+        }
+    }
+
+    /// Reports the outcome of parsing the source address access list.
+    pub fn check_access_list<E: fmt::Display>(&mut self, list: Result<AccessList, E>) {
+        match list {
+            Ok(list) => self.push(
+                "access list",
+                CheckStatus::Ok,
+                format!(
+                    "{} allow, {} deny entries",
+                    list.allow.len(),
+                    list.deny.len()
+                ),
+            ),
+            Err(e) => self.push("access list", CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    /// Reports the cipher suites and key exchange groups that will be offered.
+    pub fn check_transport_config<E: fmt::Display>(&mut self, config: Result<ProtonConfig, E>) {
+        match config {
+            Ok(config) => self.push("transport config", CheckStatus::Ok, config.to_string()),
+            Err(e) => self.push("transport config", CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    pub fn check_tls_policy<E: fmt::Display>(&mut self, policy: Result<TlsPolicy, E>) {
+        match policy {
+            Ok(policy) => {
+                let suites: Vec<String> = policy
+                    .cipher_suites
+                    .iter()
+                    .map(|s| format!("{:?}", s.suite()))
+                    .collect();
+                let groups: Vec<String> = policy
+                    .kx_groups
+                    .iter()
+                    .map(|g| format!("{:?}", g.name))
+                    .collect();
+                self.push(
+                    "tls policy",
+                    CheckStatus::Ok,
+                    format!(
+                        "suites [{}], groups [{}]",
+                        suites.join(", "),
+                        groups.join(", ")
+                    ),
+                );
+            }
+            Err(e) => self.push("tls policy", CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    /// Validates that the key matches the certificate and the certificate is
+    /// currently valid.
+    pub fn check_tls_material(&mut self, cert: &rustls::Certificate, key: &rustls::PrivateKey) {
+        match verify_key_pair(cert, key) {
+            Ok(()) => self.push(
+                "tls key pair",
+                CheckStatus::Ok,
+                "private key matches certificate".to_string(),
+            ),
+            Err(e) => self.push("tls key pair", CheckStatus::Failed, e.to_string()),
+        }
+
+        match certificate_validity(cert) {
+            Ok(validity) if validity.is_expired() => self.push(
+                "tls expiry",
+                CheckStatus::Failed,
+                format!(
+                    "certificate expired {} days ago",
+                    -validity.days_until_expiry()
+                ),
+            ),
+            Ok(validity) if validity.is_not_yet_valid() => self.push(
+                "tls expiry",
+                CheckStatus::Failed,
+                "certificate is not yet valid".to_string(),
+            ),
+            Ok(validity) if validity.days_until_expiry() < CERT_EXPIRY_WARNING_DAYS => self.push(
+                "tls expiry",
+                CheckStatus::Warning,
+                format!(
+                    "certificate expires in {} days",
+                    validity.days_until_expiry()
+                ),
+            ),
+            Ok(validity) => self.push(
+                "tls expiry",
+                CheckStatus::Ok,
+                format!(
+                    "certificate expires in {} days",
+                    validity.days_until_expiry()
+                ),
+            ),
+            Err(e) => self.push("tls expiry", CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    /// Loads the certificate chain and key the server is configured with and
+    /// validates them as `check_tls_material` does, then reports the rest of
+    /// the chain.
+    pub fn check_tls_files(&mut self, cert: &Path, key: &Path) {
+        match (load_certs(cert), load_private_key(key)) {
+            (Ok(chain), Ok(key)) => {
+                self.check_tls_material(&chain[0], &key);
+                self.check_tls_chain(&chain);
+            }
+            (Err(e), _) | (_, Err(e)) => self.push("tls files", CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    /// Reports whom each certificate after the leaf was issued to and by and
+    /// when it expires, and whether each certificate was issued by the next.
+    pub fn check_tls_chain(&mut self, chain: &[rustls::Certificate]) {
+        let inspected = match inspect_chain(chain) {
+            Ok(inspected) => inspected,
+            Err(e) => return self.push("tls chain", CheckStatus::Failed, e.to_string()),
+        };
+        for (i, cert) in inspected.iter().enumerate().skip(1) {
+            let days = cert.validity.days_until_expiry();
+            let status = if cert.validity.is_expired() || cert.validity.is_not_yet_valid() {
+                CheckStatus::Failed
+            } else if days < CERT_EXPIRY_WARNING_DAYS {
+                CheckStatus::Warning
+            } else {
+                CheckStatus::Ok
+            };
+            let expiry = if cert.validity.is_expired() {
+                format!("expired {} days ago", -days)
+            } else {
+                format!("expires in {} days", days)
+            };
+            self.push(
+                "tls chain",
+                status,
+                format!(
+                    "intermediate {} '{}' issued by '{}' {}",
+                    i, cert.subject, cert.issuer, expiry
+                ),
+            );
+        }
+        for pair in inspected.windows(2) {
+            if pair[0].issuer != pair[1].subject {
+                self.push(
+                    "tls chain",
+                    CheckStatus::Warning,
+                    format!(
+                        "'{}' is not issued by the next certificate, '{}'",
+                        pair[0].subject, pair[1].subject
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Reports that the server has no certificate configured, so it would
+    /// start with a generated self-signed one.
+    pub fn check_no_tls_material(&mut self) {
+        self.push(
+            "tls files",
+            CheckStatus::Warning,
+            "no TLS material configured, a self-signed certificate is generated at startup"
+                .to_string(),
+        );
+    }
+
+    /// Verifies the CA file server certificates are checked against loads.
+    pub fn check_root_ca(&mut self, path: &Path) {
+        self.check_ca("root ca", path);
+    }
+
+    /// Verifies the CA file client certificates are checked against loads.
+    pub fn check_client_ca(&mut self, path: &Path) {
+        self.check_ca("client ca", path);
+    }
+
+    fn check_ca(&mut self, name: &str, path: &Path) {
+        match load_root_store(path) {
+            Ok(roots) => self.push(
+                name,
+                CheckStatus::Ok,
+                format!("{} trusted certificates", roots.len()),
+            ),
+            Err(e) => self.push(name, CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    /// Loads the client certificate chain and key and verifies they match.
+    pub fn check_client_cert(&mut self, cert: &Path, key: &Path) {
+        let checked = load_certs(cert).and_then(|chain| {
+            verify_key_pair(&chain[0], &load_private_key(key)?)?;
+            Ok(chain.len())
+        });
+        match checked {
+            Ok(len) => self.push(
+                "client certificate",
+                CheckStatus::Ok,
+                format!("chain of {}, private key matches certificate", len),
+            ),
+            Err(e) => self.push("client certificate", CheckStatus::Failed, e.to_string()),
+        }
+    }
+
+    /// Parses the OCSP response to staple. A response that is not good
+    /// is stapled anyway, so it is only a warning.
+    pub fn check_ocsp_response(&mut self, path: &Path) {
+        match std::fs::read(path)
+            .map_err(ProtonError::from)
+            .and_then(|der| check_ocsp_response(&der))
+        {
+            Ok(OcspStatus::Good) => self.push(
+                "ocsp response",
+                CheckStatus::Ok,
+                format!("{} reports the certificate good", path.display()),
+            ),
+            Ok(status) => self.push(
+                "ocsp response",
+                CheckStatus::Warning,
+                format!("{} reports {:?}", path.display(), status),
+            ),
+            Err(e) => self.push(
+                "ocsp response",
+                CheckStatus::Failed,
+                format!("{}: {}", path.display(), e),
+            ),
+        }
+    }
+
+    /// Loads each certificate revocation list file.
+    pub fn check_crls(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            match load_crls(path) {
+                Ok(crls) => self.push(
+                    "crl",
+                    CheckStatus::Ok,
+                    format!("{} revocation lists in {}", crls.len(), path.display()),
+                ),
+                Err(e) => self.push(
+                    "crl",
+                    CheckStatus::Failed,
+                    format!("{}: {}", path.display(), e),
+                ),
+            }
+        }
+    }
+
+    /// Loads the pre-shared key, which must be long enough.
+    pub fn check_psk(&mut self, path: &Path) {
+        match load_psk(path) {
+            Ok(psk) => self.push(
+                "pre-shared key",
+                CheckStatus::Ok,
+                format!("{} byte key", psk.len()),
+            ),
+            Err(e) => self.push(
+                "pre-shared key",
+                CheckStatus::Failed,
+                format!("{}: {}", path.display(), e),
+            ),
+        }
+    }
+
+    /// Parses the snapshot the server is to start from.
+    pub fn check_snapshot(&mut self, path: &Path) {
+        match ServerSnapshot::load(path) {
+            Ok(snapshot) => self.push(
+                "snapshot",
+                CheckStatus::Ok,
+                format!(
+                    "{} action offsets, {} usage records, {} transfers",
+                    snapshot.action_offsets.len(),
+                    snapshot.usage.len(),
+                    snapshot.transfers.len()
+                ),
+            ),
+            Err(e) => self.push(
+                "snapshot",
+                CheckStatus::Failed,
+                format!("{}: {}", path.display(), e),
+            ),
+        }
+    }
+
+    /// Parses the hand-off file the client continues from, if it exists yet.
+    pub fn check_handoff(&mut self, path: &Path) {
+        match HandoffState::load(path) {
+            Ok(Some(_)) => self.push(
+                "hand-off",
+                CheckStatus::Ok,
+                format!("continuing from {}", path.display()),
+            ),
+            Ok(None) => self.push(
+                "hand-off",
+                CheckStatus::Ok,
+                format!("{} does not exist yet", path.display()),
+            ),
+            Err(e) => self.push(
+                "hand-off",
+                CheckStatus::Failed,
+                format!("{}: {}", path.display(), e),
+            ),
+        }
+    }
+
+    /// Verifies a file the mode reads, such as a payload to send, can be
+    /// opened.
+    pub fn check_readable(&mut self, name: &str, path: &Path) {
+        match std::fs::File::open(path) {
+            Ok(_) => self.push(
+                name,
+                CheckStatus::Ok,
+                format!("{} is readable", path.display()),
+            ),
+            Err(e) => self.push(
+                name,
+                CheckStatus::Failed,
+                format!("cannot open {}: {}", path.display(), e),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Configuration check:")?;
+        for item in &self.items {
+            let status = match item.status {
+                CheckStatus::Ok => "ok",
+                CheckStatus::Warning => "warn",
+                CheckStatus::Failed => "FAIL",
+            };
+            writeln!(f, "  [{:>4}] {}: {}", status, item.name, item.detail)?;
+        }
+        if self.is_ok() {
+            write!(f, "Configuration is valid")
+        } else {
+            write!(f, "Configuration has errors")
+        }
+    }
+}
@@ -1,16 +1,272 @@
+use crate::proton::batching::BatchPolicy;
+use crate::proton::breaker::{BreakerConfig, BreakerStats, CircuitBreaker};
+use crate::proton::commit::{ABORT_COMMIT, ABORT_REFUSED};
+use crate::proton::compress::{Compression, Dictionary, PayloadCompressor};
+use crate::proton::datagram::{self, check_max_datagram_size, MAX_DATAGRAM_SIZE};
+use crate::proton::drain::goaway_grace;
+use crate::proton::dscp::{self, Dscp, Marking};
+use crate::proton::efficiency::{DatagramEfficiency, SentFrames};
+use crate::proton::eventlog::ReplayBuffer;
+use crate::proton::experiment::Experiment;
+use crate::proton::frame::{stream_name, Direction, FrameInspector, Headers, FLAG_HEADERS};
+use crate::proton::fsm::{StreamState, StreamStates, StreamStatus};
+use crate::proton::handoff::HandoffState;
+use crate::proton::heartbeat::{self, Heartbeat, HeartbeatStats};
+use crate::proton::hello::{send_hello, send_hello_on, PeerInfo};
+use crate::proton::ids::{CounterIds, IdAllocator};
+use crate::proton::keepalive::{self, KeepAlive, KeepAliveCounters, KeepAliveStats};
+use crate::proton::mirror::{Mirror, MirrorStats};
+use crate::proton::mmap::MappedFile;
+#[cfg(feature = "multipath")]
+use crate::proton::multipath::{PathProbes, PathStats, PROBE_INTERVAL};
+use crate::proton::offline::{OfflineQueue, QueueConfig, QueueStats, SendStatus};
+use crate::proton::ordering::EventOrdering;
+use crate::proton::payload::{send_mapped_payload, send_payload};
+use crate::proton::power::{Wakeups, LOW_POWER_IDLE_TIMEOUT, LOW_POWER_WAKEUP_INTERVAL};
+use crate::proton::profile::spawn_named;
+use crate::proton::psk::authenticate_client;
+use crate::proton::push::{ActionPush, ActionStream};
+use crate::proton::qlog::{PacketTrace, Vantage};
+use crate::proton::retry::{Idempotency, RetryPolicy};
+use crate::proton::settings::{AckMode, ClientSettings, PowerMode};
+use crate::proton::streams::{ChannelKind, StreamRegistry, StreamType};
+use crate::proton::tls::{
+    negotiated_alpn, verify_ocsp_response, NegotiatedTls, OcspStatus, TlsPolicy,
+};
+use crate::proton::window::{EventWindow, PendingAck, WindowStats};
+use crate::proton::wire::{
+    decode_response, encode_commit, encode_discriminator, encode_u32, DATAGRAM_TELEMETRY,
+};
 use crate::proton::{
-    ProtonError, CONNECT_RETRY_DELAY, IDLE_TIMEOUT, MAX_BIDIRECTIONAL_STREAMS, MAX_CONNECT_RETRIES,
-    STARTUP_DELAY, STREAM_ACTION, STREAM_EVENT, STREAM_STATE_COMMIT, STREAM_TIMEOUT,
+    CloseReason, Frame, Framing, MessageKind, ProtonConfig, ProtonError, STREAM_ACTION,
+    STREAM_CONTROL, STREAM_EVENT, STREAM_HEARTBEAT, STREAM_STATE_COMMIT, STREAM_TIMEOUT,
 };
 use quinn::{ClientConfig, Connection as QuinnConnection, Endpoint, RecvStream, SendStream};
+use quinn_proto::TransportErrorCode;
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption, WebPkiVerifier};
+use rustls::RootCertStore;
+use smallvec::SmallVec;
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::time::{sleep, timeout};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+use tokio::time::{sleep, sleep_until, timeout};
+use tracing::{error, info, info_span, warn, Instrument};
 
 struct StreamPair {
     send: SendStream,
     recv: RecvStream,
+    // Requests whose response timed out but may still arrive
+    unanswered: u32,
+    // Whether each request is followed by a headers section
+    headers: bool,
+    // Stream discriminator, without flags
+    stream: u8,
+    // Layout of requests and responses, agreed in the HELLO
+    framing: Framing,
+    inspector: Option<Arc<dyn FrameInspector>>,
+    // Artificial latency added before each write
+    delay: Duration,
+    // Where this and the connection's other streams are in their exchanges
+    states: StreamStates,
+    // Frames sent on all of the connection's streams
+    sent: SentFrames,
+}
+
+impl StreamPair {
+    fn new(
+        send: SendStream,
+        recv: RecvStream,
+        headers: bool,
+        stream: u8,
+        states: StreamStates,
+        sent: SentFrames,
+        name: &str,
+    ) -> Self {
+        states.track(send.id(), name, StreamState::Ready);
+        Self {
+            send,
+            recv,
+            unanswered: 0,
+            headers,
+            stream,
+            framing: Framing::default(),
+            inspector: None,
+            delay: Duration::ZERO,
+            states,
+            sent,
+        }
+    }
+
+    // Record how a request ended. A response that timed out may still
+    // arrive, so the request is still awaited.
+    fn settle<T>(&self, result: &Result<T, ProtonError>) {
+        let state = match result {
+            Ok(_) | Err(ProtonError::QuotaExceeded | ProtonError::AbortRefused) => {
+                StreamState::Ready
+            }
+            Err(ProtonError::Timeout) => return,
+            Err(e) => StreamState::Errored(e.to_string()),
+        };
+        self.states.enter(self.send.id(), state);
+    }
+
+    // Inspectors are shown frames in the fixed layout, the id and any
+    // headers section, so they decode the same whatever the framing
+    fn inspect(&self, direction: Direction, id: u32, headers: &[u8]) {
+        if let Some(ref inspector) = self.inspector {
+            let mut frame: SmallVec<[u8; 64]> = SmallVec::from_slice(&encode_u32(id));
+            frame.extend_from_slice(headers);
+            inspector.on_frame(direction, self.stream, &frame);
+        }
+    }
+
+    // Read the next response, up to `deadline`
+    async fn read_response(&mut self, deadline: Duration) -> Result<Frame, ProtonError> {
+        let kind = MessageKind::response(self.stream);
+        let response = match timeout(deadline, self.framing.read_from(kind, &mut self.recv)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(ProtonError::Timeout),
+        };
+        self.inspect(Direction::Received, response.id, &[]);
+        self.unanswered -= 1;
+        Ok(response)
+    }
+
+    async fn request(
+        &mut self,
+        request: u32,
+        headers: &Headers,
+        deadline: Duration,
+    ) -> Result<u32, ProtonError> {
+        let response = self
+            .request_frame(&Frame::new(request), headers, deadline)
+            .await?;
+        Ok(response.id)
+    }
+
+    // Write a request and wait up to `deadline` for its response. Responses
+    // arrive in request order, so late responses to earlier requests that
+    // timed out are read and discarded first.
+    async fn request_frame(
+        &mut self,
+        request: &Frame,
+        headers: &Headers,
+        deadline: Duration,
+    ) -> Result<Frame, ProtonError> {
+        let result = self.exchange_frame(request, headers, deadline).await;
+        self.settle(&result);
+        result
+    }
+
+    async fn exchange_frame(
+        &mut self,
+        request: &Frame,
+        headers: &Headers,
+        deadline: Duration,
+    ) -> Result<Frame, ProtonError> {
+        // Frames without payload or headers never touch the heap
+        let mut frame: SmallVec<[u8; 64]> = SmallVec::new();
+        self.framing
+            .encode_into(MessageKind::request(self.stream), request, &mut frame)?;
+        let body_len = frame.len();
+        if self.headers {
+            headers.encode_into(&mut frame);
+        }
+        self.inspect(Direction::Sent, request.id, &frame[body_len..]);
+        if !self.delay.is_zero() {
+            sleep(self.delay).await;
+        }
+        timeout(STREAM_TIMEOUT, self.send.write_all(&frame)).await??;
+        self.sent.record(1, frame.len());
+        self.unanswered += 1;
+        self.states
+            .enter(self.send.id(), StreamState::AwaitingResponse(request.id));
+        let mut response = Frame::default();
+        while self.unanswered > 0 {
+            response = self.read_response(deadline).await?;
+        }
+        decode_response(encode_u32(response.id))?;
+        Ok(response)
+    }
+
+    // Write several requests in one go and wait for all their responses,
+    // paying one round trip for the whole batch
+    async fn request_batch(
+        &mut self,
+        requests: &[u32],
+        headers: &Headers,
+        deadline: Duration,
+    ) -> Result<Vec<u32>, ProtonError> {
+        let result = self.exchange_batch(requests, headers, deadline).await;
+        self.settle(&result);
+        result
+    }
+
+    async fn exchange_batch(
+        &mut self,
+        requests: &[u32],
+        headers: &Headers,
+        deadline: Duration,
+    ) -> Result<Vec<u32>, ProtonError> {
+        let encoded = if self.headers {
+            headers.encode()
+        } else {
+            Vec::new()
+        };
+        let kind = MessageKind::request(self.stream);
+        let frame_len = self.framing.encoded_len(kind, &Frame::default());
+        let mut frames = Vec::with_capacity(requests.len() * (frame_len + encoded.len()));
+        for &request in requests {
+            self.framing
+                .encode_into(kind, &Frame::new(request), &mut frames)?;
+            frames.extend_from_slice(&encoded);
+            self.inspect(Direction::Sent, request, &encoded);
+        }
+        if !self.delay.is_zero() {
+            sleep(self.delay).await;
+        }
+        timeout(STREAM_TIMEOUT, self.send.write_all(&frames)).await??;
+        self.sent.record(requests.len() as u64, frames.len());
+        self.unanswered += requests.len() as u32;
+        if let Some(&first) = requests.first() {
+            self.states
+                .enter(self.send.id(), StreamState::AwaitingResponse(first));
+        }
+
+        let mut responses = Vec::with_capacity(requests.len());
+        while self.unanswered > 0 {
+            let response = self.read_response(deadline).await?;
+            // Earlier responses belong to requests that already timed out
+            if (self.unanswered as usize) < requests.len() {
+                responses.push(decode_response(encode_u32(response.id))?);
+            }
+        }
+        Ok(responses)
+    }
+}
+
+// Streams opened in 0-RTT data, their discriminators written, to be set up
+// once the handshake completes
+struct EarlyStreams {
+    control: (SendStream, RecvStream),
+    event: StreamPair,
+    state_commit: StreamPair,
+    action: StreamPair,
+}
+
+// The top bit of a commit id on the wire marks an abort
+fn check_commit_id(commit_id: u32) -> Result<(), ProtonError> {
+    if commit_id & ABORT_COMMIT != 0 {
+        return Err(ProtonError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("state commit id {} does not fit in 31 bits", commit_id),
+        )));
+    }
+    Ok(())
 }
 
 struct ProtonStreamHandler {
@@ -18,265 +274,2691 @@ struct ProtonStreamHandler {
     event_stream: Option<StreamPair>,
     state_commit_stream: Option<StreamPair>,
     action_stream: Option<StreamPair>,
+    // Replaces the event stream when the server agreed to a window
+    event_window: Option<EventWindow>,
+    frame_headers: bool,
+    headers: Headers,
+    // Layout of requests and responses the server agreed to
+    framing: Framing,
+    // Compresses event payloads, if the server agreed to compression
+    compressor: Option<Arc<PayloadCompressor>>,
+    // Kept open so the server can keep pushing settings
+    control_send: Option<SendStream>,
+    peer: Option<PeerInfo>,
+    pushed_settings: Arc<std::sync::Mutex<ClientSettings>>,
+    // Set once the server sent GOAWAY on the control stream
+    goaway: Arc<AtomicBool>,
+    // Time taken by the QUIC handshake and by opening the streams
+    handshake: Duration,
+    stream_setup: Duration,
+    // Whether the server accepted the streams opened in 0-RTT data, if
+    // they were
+    zero_rtt: Option<bool>,
+    states: StreamStates,
+    // Round trips measured on the heartbeat stream, if the server agreed
+    heartbeat: Arc<Heartbeat>,
+    // Frames sent, for the datagram efficiency in the stats
+    sent: SentFrames,
+    // Replaces action requests when the server agreed to push actions
+    action_push: Option<ActionPush>,
 }
 
 impl ProtonStreamHandler {
-    fn new(connection: QuinnConnection) -> Self {
+    fn new(connection: QuinnConnection, headers: Option<Headers>) -> Self {
         Self {
             connection,
             event_stream: None,
             state_commit_stream: None,
             action_stream: None,
+            event_window: None,
+            frame_headers: headers.is_some(),
+            headers: headers.unwrap_or_default(),
+            framing: Framing::default(),
+            compressor: None,
+            control_send: None,
+            peer: None,
+            pushed_settings: Arc::new(std::sync::Mutex::new(ClientSettings::default())),
+            goaway: Arc::new(AtomicBool::new(false)),
+            handshake: Duration::ZERO,
+            stream_setup: Duration::ZERO,
+            zero_rtt: None,
+            states: StreamStates::new(),
+            heartbeat: Arc::new(Heartbeat::new()),
+            sent: SentFrames::new(),
+            action_push: None,
         }
     }
 
-    async fn establish_streams(&mut self) -> Result<(), ProtonError> {
-        // Open event stream
-        let (mut send, recv) = self.connection.open_bi().await?;
-        println!("Opening event stream...");
-        timeout(STREAM_TIMEOUT, send.write_all(&[STREAM_EVENT])).await??;
-        self.event_stream = Some(StreamPair { send, recv });
-        println!("Event stream established");
+    fn discriminator(&self, stream: u8) -> u8 {
+        if self.frame_headers {
+            stream | FLAG_HEADERS
+        } else {
+            stream
+        }
+    }
+
+    // Exchange HELLO metadata on the control stream, opening it unless it
+    // was opened in 0-RTT data, then read the recommended settings that
+    // follow it
+    async fn open_control(
+        &self,
+        local: &PeerInfo,
+        early: Option<(SendStream, RecvStream)>,
+    ) -> Result<(SendStream, RecvStream, PeerInfo, ClientSettings), ProtonError> {
+        let (send, mut recv, peer) = match early {
+            Some((send, recv)) => send_hello_on(send, recv, local).await?,
+            None => send_hello(&self.connection, local).await?,
+        };
+        let settings = ClientSettings::from_headers(&Headers::read_from(&mut recv).await?);
+        Ok((send, recv, peer, settings))
+    }
 
-        // Open state commit stream
+    async fn open_stream(&self, stream: u8) -> Result<StreamPair, ProtonError> {
         let (mut send, recv) = self.connection.open_bi().await?;
-        println!("Opening state commit stream...");
-        timeout(STREAM_TIMEOUT, send.write_all(&[STREAM_STATE_COMMIT])).await??;
-        self.state_commit_stream = Some(StreamPair { send, recv });
-        println!("State commit stream established");
+        let discriminator = self.discriminator(stream);
+        timeout(STREAM_TIMEOUT, send.write_all(&[discriminator])).await??;
+        Ok(StreamPair::new(
+            send,
+            recv,
+            self.frame_headers,
+            stream,
+            self.states.clone(),
+            self.sent.clone(),
+            stream_name(stream),
+        ))
+    }
 
-        // Open action stream
+    // Opens the control, event, state commit and action streams and writes
+    // their discriminators, which with a session ticket can be done before
+    // the handshake completes. The HELLO waits for the handshake, so a
+    // replay of the 0-RTT data carries no client metadata.
+    async fn open_early_streams(&self) -> Result<EarlyStreams, ProtonError> {
         let (mut send, recv) = self.connection.open_bi().await?;
-        println!("Opening action stream...");
-        timeout(STREAM_TIMEOUT, send.write_all(&[STREAM_ACTION])).await??;
-        self.action_stream = Some(StreamPair { send, recv });
-        println!("Action stream established");
+        timeout(STREAM_TIMEOUT, send.write_all(&[STREAM_CONTROL])).await??;
+        let (event, state_commit, action) = tokio::try_join!(
+            self.open_stream(STREAM_EVENT),
+            self.open_stream(STREAM_STATE_COMMIT),
+            self.open_stream(STREAM_ACTION),
+        )?;
+        Ok(EarlyStreams {
+            control: (send, recv),
+            event,
+            state_commit,
+            action,
+        })
+    }
+
+    async fn establish_streams(
+        &mut self,
+        local: &PeerInfo,
+        early: Option<EarlyStreams>,
+    ) -> Result<(), ProtonError> {
+        let started = Instant::now();
+        let ((send, mut recv, peer, settings), mut event, mut state_commit, mut action) =
+            match early {
+                Some(early) => {
+                    info!("Sending HELLO on the streams opened in 0-RTT data...");
+                    (
+                        self.open_control(local, Some(early.control)).await?,
+                        early.event,
+                        early.state_commit,
+                        early.action,
+                    )
+                }
+                // Nothing orders the streams against each other, so they are
+                // set up concurrently and cost one round trip between them.
+                // The control stream is opened first, so the server reads the
+                // HELLO first.
+                None => {
+                    info!("Opening control, event, state commit and action streams...");
+                    tokio::try_join!(
+                        self.open_control(local, None),
+                        self.open_stream(STREAM_EVENT),
+                        self.open_stream(STREAM_STATE_COMMIT),
+                        self.open_stream(STREAM_ACTION),
+                    )?
+                }
+            };
+        info!("Server is {}", peer);
+        // Streams were opened before the HELLO reply said which framing the
+        // server agreed to, which takes effect from their first request
+        self.framing = peer.framing.unwrap_or_default();
+        if local.framing.is_some() && peer.framing != local.framing {
+            info!(
+                "Server did not agree to {} framing, using {}",
+                local.framing.unwrap_or_default(),
+                self.framing
+            );
+        }
+        for pair in [&mut event, &mut state_commit, &mut action] {
+            pair.framing = self.framing;
+        }
+        if local.compression.is_some() && peer.compression.is_none() {
+            info!("Server did not agree to compression, sending payloads as they are");
+        }
+        self.compressor = peer.compression.map(|_| Arc::new(PayloadCompressor::new()));
+        match peer.event_window.filter(|_| local.event_window.is_some()) {
+            Some(size) => {
+                info!("Keeping up to {} events in flight", size);
+                let StreamPair { send, recv, .. } = event;
+                self.event_window = Some(EventWindow::start(
+                    send,
+                    recv,
+                    self.framing,
+                    self.frame_headers,
+                    size,
+                    self.states.clone(),
+                    self.sent.clone(),
+                ));
+            }
+            None => {
+                if local.event_window.is_some() {
+                    info!("Server did not agree to an event window, sending one event at a time");
+                }
+                self.event_stream = Some(event);
+            }
+        }
+        self.control_send = Some(send);
+        let peer_heartbeat = peer.heartbeat;
+        self.peer = Some(peer);
+        self.state_commit_stream = Some(state_commit);
+        self.action_stream = Some(action);
+        info!("Event, state commit and action streams established");
+
+        // The server may push updated settings at any time
+        if !settings.is_empty() {
+            info!("Server recommends: {}", settings);
+        }
+        *self.pushed_settings.lock().unwrap() = settings;
+        let pushed = Arc::clone(&self.pushed_settings);
+        let goaway = Arc::clone(&self.goaway);
+        let compressor = self.compressor.clone();
+        let states = self.states.clone();
+        let control = recv.id();
+        states.track(control, stream_name(STREAM_CONTROL), StreamState::Ready);
+        let span = info_span!("stream", stream = "control", discriminator = STREAM_CONTROL);
+        spawn_named(
+            "control stream",
+            async move {
+                while let Ok(headers) = Headers::read_from(&mut recv).await {
+                    // Dictionaries are pushed alongside settings
+                    if Dictionary::is_dictionary(&headers) {
+                        match (Dictionary::from_headers(&headers), &compressor) {
+                            (Ok(dictionary), Some(compressor)) => {
+                                info!("Server pushed compression {}", dictionary);
+                                compressor.set_dictionary(&dictionary);
+                            }
+                            (Ok(_), None) => {}
+                            (Err(e), _) => warn!("Ignoring pushed dictionary: {}", e),
+                        }
+                        continue;
+                    }
+                    // A GOAWAY repeats the settings in effect
+                    if let Some(grace) = goaway_grace(&headers) {
+                        warn!("Server sent GOAWAY, reconnect within {:?}", grace);
+                        goaway.store(true, Ordering::Relaxed);
+                    }
+                    let settings = ClientSettings::from_headers(&headers);
+                    info!("Server pushed settings: {}", settings);
+                    *pushed.lock().unwrap() = settings;
+                }
+                states.enter(
+                    control,
+                    StreamState::Errored("closed by server".to_string()),
+                );
+            }
+            .instrument(span),
+        );
+
+        match peer_heartbeat.filter(|_| local.heartbeat.is_some()) {
+            Some(interval) => {
+                self.start_heartbeat(Duration::from_millis(interval.into()))
+                    .await?
+            }
+            None if local.heartbeat.is_some() => {
+                info!("Server did not agree to heartbeats");
+            }
+            None => {}
+        }
 
+        self.stream_setup = started.elapsed();
         Ok(())
     }
 
-    async fn send_event(&mut self, event_id: u32) -> Result<u32, ProtonError> {
-        if let Some(StreamPair {
-            ref mut send,
-            ref mut recv,
-        }) = self.event_stream
+    // Opens the heartbeat stream and exchanges heartbeats on it from a
+    // background task
+    async fn start_heartbeat(&mut self, every: Duration) -> Result<(), ProtonError> {
+        let (mut send, recv) = self.connection.open_bi().await?;
+        timeout(STREAM_TIMEOUT, send.write_all(&[STREAM_HEARTBEAT])).await??;
+        info!("Exchanging heartbeats every {:?}", every);
+        let states = self.states.clone();
+        states.track(recv.id(), stream_name(STREAM_HEARTBEAT), StreamState::Ready);
+        let connection = self.connection.clone();
+        let heartbeat = Arc::clone(&self.heartbeat);
+        let span = info_span!(
+            "stream",
+            stream = "heartbeat",
+            discriminator = STREAM_HEARTBEAT
+        );
+        spawn_named(
+            "heartbeat",
+            async move { heartbeat::run(connection, send, recv, every, &heartbeat, states).await }
+                .instrument(span),
+        );
+        Ok(())
+    }
+
+    fn set_inspector(&mut self, inspector: Option<Arc<dyn FrameInspector>>) {
+        for pair in [
+            &mut self.event_stream,
+            &mut self.state_commit_stream,
+            &mut self.action_stream,
+        ]
+        .into_iter()
+        .flatten()
         {
-            timeout(STREAM_TIMEOUT, send.write_all(&event_id.to_le_bytes())).await??;
-            let mut response = [0u8; 4];
-            timeout(STREAM_TIMEOUT, recv.read_exact(&mut response)).await??;
-            Ok(u32::from_le_bytes(response))
-        } else {
-            Err(ProtonError::InvalidStream)
+            pair.inspector = inspector.clone();
+        }
+        if let Some(ref window) = self.event_window {
+            window.set_inspector(inspector);
         }
     }
 
-    async fn send_state_commit(&mut self, commit_id: u32) -> Result<u32, ProtonError> {
-        if let Some(StreamPair {
-            ref mut send,
-            ref mut recv,
-        }) = self.state_commit_stream
-        {
-            timeout(STREAM_TIMEOUT, send.write_all(&commit_id.to_le_bytes())).await??;
-            let mut response = [0u8; 4];
-            timeout(STREAM_TIMEOUT, recv.read_exact(&mut response)).await??;
-            Ok(u32::from_le_bytes(response))
-        } else {
-            Err(ProtonError::InvalidStream)
+    fn pair(&self, stream: u8) -> Option<&StreamPair> {
+        match stream {
+            STREAM_EVENT => self.event_stream.as_ref(),
+            STREAM_STATE_COMMIT => self.state_commit_stream.as_ref(),
+            STREAM_ACTION => self.action_stream.as_ref(),
+            _ => None,
         }
     }
 
-    async fn read_action(&mut self) -> Result<u32, ProtonError> {
-        if let Some(StreamPair {
-            ref mut send,
-            ref mut recv,
-        }) = self.action_stream
-        {
-            let request_id = 42u32; // Example request ID
-            timeout(STREAM_TIMEOUT, send.write_all(&request_id.to_le_bytes())).await??;
-            let mut data = [0u8; 4];
-            timeout(STREAM_TIMEOUT, recv.read_exact(&mut data)).await??;
-            Ok(u32::from_le_bytes(data))
+    fn pair_mut(&mut self, stream: u8) -> Option<&mut StreamPair> {
+        match stream {
+            STREAM_EVENT => self.event_stream.as_mut(),
+            STREAM_STATE_COMMIT => self.state_commit_stream.as_mut(),
+            STREAM_ACTION => self.action_stream.as_mut(),
+            _ => None,
+        }
+    }
+
+    fn inspector(&self) -> Option<Arc<dyn FrameInspector>> {
+        match self.event_window {
+            Some(ref window) => window.inspector(),
+            None => self.event_stream.as_ref()?.inspector.clone(),
+        }
+    }
+
+    async fn send_event(&mut self, event: &Frame) -> Result<u32, ProtonError> {
+        let compressed;
+        let event = match self.compressor {
+            Some(ref compressor) if !event.payload.is_empty() => {
+                compressed = Frame::with_payload(event.id, compressor.compress(&event.payload));
+                &compressed
+            }
+            _ => event,
+        };
+        if let Some(ref mut window) = self.event_window {
+            return window.submit(event, &self.headers).await?.await;
+        }
+        match self.event_stream {
+            Some(ref mut pair) => Ok(pair
+                .request_frame(event, &self.headers, STREAM_TIMEOUT)
+                .await?
+                .id),
+            None => Err(ProtonError::InvalidStream),
+        }
+    }
+
+    // Write an event without waiting for its ack, once the window has room.
+    // Without a window the event is sent and acknowledged first.
+    async fn submit_event(&mut self, event: &Frame) -> Result<PendingAck, ProtonError> {
+        let Some(ref mut window) = self.event_window else {
+            let ack = self.send_event(event).await;
+            return Ok(PendingAck::ready(event.id, ack));
+        };
+        let compressed;
+        let event = match self.compressor {
+            Some(ref compressor) if !event.payload.is_empty() => {
+                compressed = Frame::with_payload(event.id, compressor.compress(&event.payload));
+                &compressed
+            }
+            _ => event,
+        };
+        window.submit(event, &self.headers).await
+    }
+
+    async fn send_events(&mut self, event_ids: &[u32]) -> Result<Vec<u32>, ProtonError> {
+        if let Some(ref mut window) = self.event_window {
+            // Acks are collected once all are written, or the window fills
+            let mut pending = Vec::with_capacity(event_ids.len());
+            for &id in event_ids {
+                pending.push(window.submit(&Frame::new(id), &self.headers).await?);
+            }
+            let mut acks = Vec::with_capacity(event_ids.len());
+            for ack in pending {
+                acks.push(ack.await?);
+            }
+            return Ok(acks);
+        }
+        match self.event_stream {
+            Some(ref mut pair) => {
+                pair.request_batch(event_ids, &self.headers, STREAM_TIMEOUT)
+                    .await
+            }
+            None => Err(ProtonError::InvalidStream),
+        }
+    }
+
+    // Size of one event request on the wire
+    fn event_frame_len(&self) -> usize {
+        let frame_len = self
+            .framing
+            .encoded_len(MessageKind::Event, &Frame::default());
+        if self.frame_headers {
+            frame_len + self.headers.encoded_len()
         } else {
-            Err(ProtonError::InvalidStream)
+            frame_len
+        }
+    }
+
+    async fn send_state_commit(
+        &mut self,
+        commit: &Frame,
+        deadline: Duration,
+    ) -> Result<u32, ProtonError> {
+        check_commit_id(commit.id)?;
+        match self.state_commit_stream {
+            Some(ref mut pair) => Ok(pair
+                .request_frame(commit, &self.headers, deadline)
+                .await?
+                .id),
+            None => Err(ProtonError::InvalidStream),
+        }
+    }
+
+    async fn abort_commit(&mut self, commit_id: u32) -> Result<u32, ProtonError> {
+        check_commit_id(commit_id)?;
+        match self.state_commit_stream {
+            Some(ref mut pair) => {
+                match pair
+                    .request(
+                        encode_commit(commit_id, true),
+                        &self.headers,
+                        STREAM_TIMEOUT,
+                    )
+                    .await?
+                {
+                    ABORT_REFUSED => Err(ProtonError::AbortRefused),
+                    response => Ok(response),
+                }
+            }
+            None => Err(ProtonError::InvalidStream),
+        }
+    }
+
+    // Once the server agreed to push actions, the action stream carries the
+    // consumer offset alone
+    fn start_action_push(&mut self, offset: Arc<AtomicU32>) {
+        let Some(window) = self.peer.as_ref().and_then(|peer| peer.action_push) else {
+            return;
+        };
+        let Some(StreamPair {
+            send,
+            recv,
+            headers,
+            ..
+        }) = self.action_stream.take()
+        else {
+            return;
+        };
+        info!("Server pushes up to {} actions ahead", window);
+        self.action_push = Some(ActionPush::start(
+            self.connection.clone(),
+            send,
+            recv,
+            self.framing,
+            headers.then(|| self.headers.clone()),
+            window,
+            offset,
+            self.states.clone(),
+            self.sent.clone(),
+        ));
+    }
+
+    async fn read_action(&mut self, offset: u32, deadline: Duration) -> Result<Frame, ProtonError> {
+        if let Some(ref mut push) = self.action_push {
+            return push.next(deadline).await;
         }
+        match self.action_stream {
+            Some(ref mut pair) => {
+                pair.request_frame(&Frame::new(offset), &self.headers, deadline)
+                    .await
+            }
+            None => Err(ProtonError::InvalidStream),
+        }
+    }
+
+    // A timed out response is worth retrying as long as the connection is up
+    fn is_transient(&self, error: &ProtonError) -> bool {
+        matches!(error, ProtonError::Timeout) && self.connection.close_reason().is_none()
     }
 }
 
+// Servers whose session tickets are kept, as many as rustls keeps by default
+const SESSION_CACHE_SIZE: usize = 256;
+
+#[derive(Clone)]
 pub struct ProtonClient {
     endpoint: Endpoint,
-    last_event_id: u32,
+    ids: Arc<dyn IdAllocator>,
+    action_offset: Arc<AtomicU32>,
+    tls_policy: TlsPolicy,
+    require_ocsp_staple: bool,
+    // Certificates that may have issued the server's, to check OCSP staples
+    ocsp_issuers: Vec<rustls::Certificate>,
+    client_cert: Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>,
+    // Trusted CAs for server certificates; without them any is accepted
+    root_ca: Option<Arc<RootCertStore>>,
+    server_name: String,
+    psk: Option<Vec<u8>>,
+    frame_headers: Option<Headers>,
+    info: PeerInfo,
+    settings: ClientSettings,
+    mmap_payloads: bool,
+    lazy_reconnect: bool,
+    auto_reconnect: bool,
+    connect_retry: RetryPolicy,
+    config: ProtonConfig,
+    streams: StreamRegistry,
+    keepalive: KeepAlive,
+    // Secondary server every sent event is copied to
+    mirror: Option<SocketAddr>,
+    // Shared by every connection made by this client and its clones
+    breaker: Option<Arc<CircuitBreaker>>,
+    // Events sent while disconnected, shared like the breaker
+    queue: Option<Arc<std::sync::Mutex<OfflineQueue>>>,
+    // Events sent most recently, kept for a server that lost them
+    replay: Option<Arc<std::sync::Mutex<ReplayBuffer>>>,
+    max_datagram_size: usize,
+    // DSCP the endpoint's packets, and those of its probe paths, carry
+    marking: Marking,
+    // Where the packets of the endpoint and its probe paths are traced
+    trace: PacketTrace,
+    // Session tickets for resumption and 0-RTT, kept across rebuilds of the
+    // TLS config and shared by the client's clones
+    sessions: Arc<dyn ClientSessionStore>,
+    // Alternate local addresses each connection probes the server from
+    #[cfg(feature = "multipath")]
+    probe_paths: Vec<(SocketAddr, Endpoint)>,
 }
 
 impl ProtonClient {
     pub fn new(bind_addr: SocketAddr) -> Result<Self, ProtonError> {
-        // Configure TLS (skip verification since we're on localhost)
-        let mut client_crypto = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
-            .with_no_client_auth();
+        // Create endpoint
+        let marking = Marking::default();
+        let trace = PacketTrace::default();
+        let endpoint = dscp::bind(bind_addr, None, marking.clone(), trace.clone())?;
+
+        let mut client = ProtonClient {
+            endpoint,
+            ids: Arc::new(CounterIds::default()),
+            action_offset: Arc::new(AtomicU32::new(0)),
+            tls_policy: TlsPolicy::default(),
+            require_ocsp_staple: false,
+            ocsp_issuers: Vec::new(),
+            client_cert: None,
+            root_ca: None,
+            server_name: "localhost".to_string(),
+            psk: None,
+            frame_headers: None,
+            info: PeerInfo::default(),
+            settings: ClientSettings::default(),
+            mmap_payloads: false,
+            lazy_reconnect: false,
+            auto_reconnect: false,
+            connect_retry: RetryPolicy::connect(),
+            config: ProtonConfig::default(),
+            streams: StreamRegistry::default(),
+            keepalive: KeepAlive::default(),
+            mirror: None,
+            breaker: None,
+            queue: None,
+            replay: None,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            marking,
+            trace,
+            sessions: Arc::new(ClientSessionMemoryCache::new(SESSION_CACHE_SIZE)),
+            #[cfg(feature = "multipath")]
+            probe_paths: Vec::new(),
+        };
+        client.reload_client_config()?;
+        Ok(client)
+    }
+
+    fn build_client_config(&self) -> Result<ClientConfig, ProtonError> {
+        // Configure TLS, verifying the server against the root CAs if set
+        let verifier = ServerVerification {
+            webpki: self
+                .root_ca
+                .as_ref()
+                .map(|roots| WebPkiVerifier::new(Arc::clone(roots), None)),
+            require_ocsp_staple: self.require_ocsp_staple,
+            ocsp_issuers: self.ocsp_issuers.clone(),
+        };
+        let builder = self
+            .tls_policy
+            .client_builder()?
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        let mut client_crypto = match self.client_cert {
+            Some((ref chain, ref key)) => builder
+                .with_client_auth_cert(chain.clone(), key.clone())
+                .map_err(|e| ProtonError::IoError(std::io::Error::other(e)))?,
+            None => builder.with_no_client_auth(),
+        };
         client_crypto.alpn_protocols = vec![b"proton".to_vec()];
+        client_crypto.resumption = Resumption::store(Arc::clone(&self.sessions));
+        client_crypto.enable_early_data = true;
 
         // Configure QUIC client
         let mut client_config = ClientConfig::new(Arc::new(client_crypto));
+        // Keepalives are sent by the connection itself, see `keepalive`
+        let idle_timeout = match self.settings.power_mode() {
+            PowerMode::Normal => self.config.idle_timeout,
+            PowerMode::Low => LOW_POWER_IDLE_TIMEOUT.max(self.config.idle_timeout),
+        };
         let mut transport_config = quinn::TransportConfig::default();
         transport_config
-            .keep_alive_interval(Some(std::time::Duration::from_secs(5)))
-            .max_idle_timeout(Some(IDLE_TIMEOUT.try_into().unwrap()))
-            .max_concurrent_bidi_streams(MAX_BIDIRECTIONAL_STREAMS.into());
+            .keep_alive_interval(None)
+            .max_idle_timeout(Some(idle_timeout.try_into().unwrap()))
+            .max_concurrent_bidi_streams(self.config.max_bidi_streams.into());
+        datagram::configure_transport(&mut transport_config, self.max_datagram_size);
         client_config.transport_config(Arc::new(transport_config));
 
-        // Create endpoint
-        let mut endpoint = Endpoint::client(bind_addr)?;
-        endpoint.set_default_client_config(client_config);
+        Ok(client_config)
+    }
 
-        Ok(ProtonClient {
-            endpoint,
-            last_event_id: 0,
-        })
+    fn reload_client_config(&mut self) -> Result<(), ProtonError> {
+        let config = self.build_client_config()?;
+        self.endpoint.set_default_client_config(config);
+        Ok(())
     }
 
-    pub async fn connect(
-        &mut self,
-        server_addr: SocketAddr,
-        startup_delay: Option<Duration>,
-    ) -> Result<ProtonConnection, ProtonError> {
-        let delay = startup_delay.unwrap_or(STARTUP_DELAY);
-        // Wait for startup delay to ensure old connections are cleaned up
-        println!("Waiting {} seconds for startup delay...", delay.as_secs());
-        sleep(delay).await;
+    /// Restrict the cipher suites and key exchange groups offered to the server.
+    pub fn with_tls_policy(mut self, policy: TlsPolicy) -> Result<Self, ProtonError> {
+        self.tls_policy = policy;
+        self.reload_client_config()?;
+        Ok(self)
+    }
 
-        // Try connecting to server with retries
-        let mut retry_count = 0;
+    /// Fail the handshake unless the server staples a fresh OCSP response
+    /// reporting its certificate as good, signed by the certificate's issuer
+    /// or a responder it delegated to. Needs `with_root_ca`, the issuer is
+    /// looked up in the server's chain and `with_ocsp_issuers`.
+    pub fn with_required_ocsp_staple(mut self, require: bool) -> Result<Self, ProtonError> {
+        self.require_ocsp_staple = require;
+        self.reload_client_config()?;
+        Ok(self)
+    }
 
-        loop {
-            match self.endpoint.connect(server_addr, "localhost")?.await {
-                Ok(connection) => {
-                    println!("Connected to server at {}", server_addr);
-
-                    // Create protocol client
-                    let mut handler = ProtonStreamHandler::new(connection.clone());
-
-                    // Establish all streams
-                    match handler.establish_streams().await {
-                        Ok(_) => {
-                            println!("All streams established");
-                            return Ok(ProtonConnection {
-                                handler,
-                                last_event_id: &mut self.last_event_id,
-                            });
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to establish streams: {}", e);
-                            if retry_count >= MAX_CONNECT_RETRIES {
-                                return Err(e);
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to connect: {}", e);
-                    if retry_count >= MAX_CONNECT_RETRIES {
-                        return Err(ProtonError::ConnectionError);
-                    }
-                }
-            }
+    /// Certificates that may have issued the server's certificate without
+    /// being sent in its chain, typically the root CAs, used to verify
+    /// stapled OCSP responses.
+    pub fn with_ocsp_issuers(
+        mut self,
+        issuers: Vec<rustls::Certificate>,
+    ) -> Result<Self, ProtonError> {
+        self.ocsp_issuers = issuers;
+        self.reload_client_config()?;
+        Ok(self)
+    }
 
-            retry_count += 1;
-            println!(
-                "Retrying connection ({}/{})",
-                retry_count, MAX_CONNECT_RETRIES
-            );
-            sleep(CONNECT_RETRY_DELAY).await;
+    /// Present `chain` to servers that require client certificates (mTLS).
+    pub fn with_client_cert(
+        mut self,
+        chain: Vec<rustls::Certificate>,
+        key: rustls::PrivateKey,
+    ) -> Result<Self, ProtonError> {
+        self.client_cert = Some((chain, key));
+        self.reload_client_config()?;
+        Ok(self)
+    }
+
+    /// Verify server certificates against `roots`, e.g. loaded with
+    /// `tls::load_root_store`, instead of accepting any certificate. The
+    /// server's certificate must be valid for the name set with
+    /// `with_server_name`.
+    pub fn with_root_ca(mut self, roots: RootCertStore) -> Result<Self, ProtonError> {
+        if roots.is_empty() {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "root CA store has no certificates",
+            )));
         }
+        self.root_ca = Some(Arc::new(roots));
+        self.reload_client_config()?;
+        Ok(self)
     }
-}
 
-pub struct ProtonConnection {
-    handler: ProtonStreamHandler,
-    last_event_id: *mut u32,
-}
+    /// Name the server's certificate is verified against and sent as SNI,
+    /// `localhost` by default.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self, ProtonError> {
+        rustls::ServerName::try_from(name).map_err(|_| {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid server name '{}'", name),
+            ))
+        })?;
+        self.server_name = name.to_string();
+        Ok(self)
+    }
 
-impl ProtonConnection {
-    pub async fn send_event(&mut self) -> Result<u32, ProtonError> {
-        unsafe {
-            *self.last_event_id += 1;
-            let event_id = *self.last_event_id;
-            match self.handler.send_event(event_id).await {
-                Ok(ack) => {
-                    println!("Event {} acknowledged with {}", event_id, ack);
-                    Ok(ack)
-                }
-                Err(e) => {
-                    eprintln!("Failed to send event {}: {}", event_id, e);
-                    Err(e)
-                }
-            }
+    /// Authenticate to the server with a pre-shared key before opening the
+    /// protocol streams.
+    pub fn with_psk(mut self, psk: Vec<u8>) -> Result<Self, ProtonError> {
+        self.psk = Some(psk);
+        Ok(self)
+    }
+
+    /// Open streams whose request frames carry a headers section, starting
+    /// each connection with `headers`. Trace context and other headers set on
+    /// the connection then reach the server.
+    pub fn with_frame_headers(mut self, headers: Headers) -> Self {
+        self.frame_headers = Some(headers);
+        self
+    }
+
+    /// Settings that take precedence over those recommended by the server.
+    pub fn with_settings(mut self, settings: ClientSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Identify the application in the HELLO sent to servers, e.g.
+    /// `my-service/2.3.1`.
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.info.user_agent = user_agent.to_string();
+        self
+    }
+
+    /// Admission priority declared in the HELLO. Servers that allow
+    /// preemption evict lower priority clients to make room at capacity.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.info.priority = priority;
+        self
+    }
+
+    /// Let connections replace themselves when they find they have died, e.g.
+    /// of the idle timeout while the application was quiet. The next
+    /// operation reconnects and re-establishes the streams first instead of
+    /// failing. Connections closed deliberately, by `close()` or by the
+    /// server's application (preemption, failed authentication), are not
+    /// revived.
+    pub fn with_lazy_reconnect(mut self, enabled: bool) -> Self {
+        self.lazy_reconnect = enabled;
+        self
+    }
+
+    /// Reconnect as soon as the connection is lost, including under a
+    /// request, and repeat the requests that are safe to repeat on the new
+    /// connection: events, which keep their ids so numbering resumes after
+    /// the last one, and action reads. State commits are not repeated; they
+    /// fail and the next operation runs on the new connection. An event the
+    /// server received before the connection dropped is delivered to it
+    /// again. Implies lazy reconnect.
+    pub fn with_auto_reconnect(mut self, enabled: bool) -> Self {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// How `connect` retries failed attempts, and so how a lost connection
+    /// is replaced: after each failure it waits for the policy's backoff,
+    /// doubling up to its maximum and randomly shortened by up to half.
+    /// Defaults to `RetryPolicy::connect()`.
+    pub fn with_connect_retry(mut self, policy: RetryPolicy) -> Result<Self, ProtonError> {
+        if policy.max_attempts == 0 {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "connect retry policy must allow at least one attempt",
+            )));
         }
+        self.connect_retry = policy;
+        Ok(self)
     }
 
-    pub async fn send_state_commit(&mut self, commit_id: u32) -> Result<u32, ProtonError> {
-        match self.handler.send_state_commit(commit_id).await {
-            Ok(response) => {
-                println!(
-                    "State commit {} completed with response {}",
-                    commit_id, response
-                );
-                Ok(response)
-            }
-            Err(e) => {
-                eprintln!("Failed to send state commit {}: {}", commit_id, e);
-                Err(e)
-            }
+    /// Use the transport parameters in `config`: its idle timeout, stream
+    /// limit and startup delay, and its keepalive interval and connect
+    /// retries in place of those set so far. Later calls to `with_keepalive`
+    /// or `with_connect_retry` override the latter.
+    pub fn with_config(mut self, config: ProtonConfig) -> Result<Self, ProtonError> {
+        config.validate()?;
+        self.config = config;
+        self.keepalive.interval = config.keep_alive_interval();
+        self.connect_retry = RetryPolicy {
+            max_attempts: config.connect_retries.saturating_add(1),
+            initial_backoff: config.connect_retry_delay,
+            ..RetryPolicy::connect()
+        };
+        self.reload_client_config()?;
+        Ok(self)
+    }
+
+    /// Puts a circuit breaker around connects and requests: once too many
+    /// recent ones failed, they fail locally with `ProtonError::CircuitOpen`
+    /// until the breaker's cool-down is over. Clones of the client, and the
+    /// connections they make, share the breaker.
+    pub fn with_circuit_breaker(mut self, config: BreakerConfig) -> Result<Self, ProtonError> {
+        self.breaker = Some(Arc::new(CircuitBreaker::new(config)?));
+        Ok(self)
+    }
+
+    /// The circuit breaker set by `with_circuit_breaker`, e.g. to follow its
+    /// state with `CircuitBreaker::subscribe`.
+    pub fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+        self.breaker.clone()
+    }
+
+    fn check_circuit(&self) -> Result<(), ProtonError> {
+        self.breaker.as_ref().map_or(Ok(()), |b| b.check())
+    }
+
+    /// Lets `ProtonConnection::send_event_or_queue` queue events while the
+    /// client is disconnected, within `config`'s limits, rather than fail
+    /// them. The queue is flushed once a connection is made again. Clones
+    /// of the client share it.
+    pub fn with_offline_queue(mut self, config: QueueConfig) -> Result<Self, ProtonError> {
+        self.queue = Some(Arc::new(std::sync::Mutex::new(OfflineQueue::new(config)?)));
+        Ok(self)
+    }
+
+    /// Tenant the server accounts this client's usage and quota to.
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.info.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Durable protocol state to hand to a replacement process. Connections
+    /// made by this client share it, so it is current while they run.
+    pub fn handoff_state(&self) -> HandoffState {
+        HandoffState {
+            last_event_id: self.ids.last(),
+            id_scheme: self.ids.scheme(),
+            action_offset: self.action_offset.load(Ordering::Relaxed),
+            outbox_cursor: None,
         }
     }
 
-    pub async fn read_action(&mut self) -> Result<u32, ProtonError> {
-        match self.handler.read_action().await {
-            Ok(action) => {
-                println!("Received action: {}", action);
-                Ok(action)
-            }
-            Err(e) => {
-                eprintln!("Failed to read action: {}", e);
-                Err(e)
-            }
+    /// Continue from the state a previous process exported: event ids carry
+    /// on past its last one and its acknowledged actions stay acknowledged.
+    /// Set the id allocator first; it must use the same scheme.
+    pub fn with_handoff_state(self, state: &HandoffState) -> Result<Self, ProtonError> {
+        if state.id_scheme != self.ids.scheme() {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "hand-off has {} ids but the client allocates {} ids",
+                    state.id_scheme,
+                    self.ids.scheme()
+                ),
+            )));
         }
+        self.ids.advance_past(state.last_event_id)?;
+        self.action_offset
+            .fetch_max(state.action_offset, Ordering::Relaxed);
+        Ok(self)
     }
 
-    pub async fn close(&mut self) {
-        if self.handler.connection.close_reason().is_none() {
-            println!("Closing connection to server");
-            self.handler
-                .connection
-                .close(0u32.into(), b"Client closed connection");
+    /// Identity the server's duplicate connection policy applies to. Without
+    /// one the server goes by tenant, then source address.
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.info.client_id = Some(client_id.to_string());
+        self
+    }
+
+    /// Ask the server to accept events in this order. The server may impose
+    /// another; the agreed ordering is in the server's `PeerInfo`.
+    pub fn with_event_ordering(mut self, ordering: EventOrdering) -> Self {
+        self.info.event_ordering = Some(ordering);
+        self
+    }
+
+    /// Ask the server for length-prefixed or typed framing, so events, state
+    /// commits and actions can carry payload bytes. Servers that do not
+    /// support it keep to fixed frames; the agreed framing is in the server's `PeerInfo`.
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.info.framing = Some(framing);
+        self
+    }
+
+    /// Ask the server for payload compression. Once the server has trained
+    /// a dictionary and pushed it, event payloads are compressed with it;
+    /// until then they go out as they are. Only payloads need it, so it is
+    /// of use with length-prefixed or typed framing.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.info.compression = Some(compression);
+        self
+    }
+
+    /// Ask the server to let connections keep up to `size` events in flight
+    /// instead of waiting for each ack before sending the next event (see
+    /// `ProtonConnection::submit_event`). The server may allow fewer; servers
+    /// that do not agree get one event at a time.
+    pub fn with_event_window(mut self, size: u32) -> Result<Self, ProtonError> {
+        if size == 0 {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "event window must not be zero",
+            )));
         }
+        self.info.event_window = Some(size);
+        Ok(self)
     }
-}
 
-impl Drop for ProtonConnection {
-    fn drop(&mut self) {
-        if self.handler.connection.close_reason().is_none() {
-            println!("Warning: ProtonConnection dropped without explicit close()");
-            self.handler
-                .connection
-                .close(0u32.into(), b"Client dropped without explicit close");
+    /// Ask the server to exchange heartbeats every `interval` on a stream of
+    /// their own, measuring the application level round trip (see
+    /// `ProtonConnection::rtt`) and closing the connection once the server
+    /// answers none for three intervals. The server may pick a longer
+    /// interval; servers that do not agree exchange none.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Result<Self, ProtonError> {
+        let millis = u32::try_from(interval.as_millis()).unwrap_or(u32::MAX);
+        if millis == 0 {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "heartbeat interval must be at least 1ms",
+            )));
         }
+        self.info.heartbeat = Some(millis);
+        Ok(self)
+    }
+
+    /// Ask the server to push actions as its handler produces them, keeping
+    /// up to `window` of them unacknowledged, instead of waiting for each
+    /// to be requested. Read them from `ProtonConnection::actions`, or one
+    /// at a time with `read_action`. The server may push fewer ahead;
+    /// servers that do not agree wait for requests as before.
+    pub fn with_action_push(mut self, window: u32) -> Result<Self, ProtonError> {
+        if window == 0 {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "action push window must not be zero",
+            )));
+        }
+        self.info.action_push = Some(window);
+        Ok(self)
     }
-}
 
-// Certificate verifier that accepts any certificate
-struct SkipServerVerification;
+    /// Keep the last `capacity` events the server has received, and say in
+    /// each HELLO which was sent last. A server with an event log that
+    /// lacks some of them, e.g. after restarting before syncing them, asks
+    /// for them in its reply and they are sent again before anything else.
+    /// Batches and streamed payloads are not kept. Clones of the client
+    /// share the events kept.
+    pub fn with_event_replay(mut self, capacity: usize) -> Result<Self, ProtonError> {
+        if capacity == 0 {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "event replay capacity must not be zero",
+            )));
+        }
+        self.replay = Some(Arc::new(std::sync::Mutex::new(ReplayBuffer::new(capacity))));
+        Ok(self)
+    }
 
-impl rustls::client::ServerCertVerifier for SkipServerVerification {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &rustls::Certificate,
-        _intermediates: &[rustls::Certificate],
-        _server_name: &rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+    /// Allocate event ids with `ids` instead of an in memory counter, and
+    /// declare its scheme to the server so it can validate them.
+    pub fn with_id_allocator(mut self, ids: Arc<dyn IdAllocator>) -> Self {
+        self.info.id_scheme = Some(ids.scheme());
+        self.ids = ids;
+        self
+    }
+
+    /// Memory map files sent with `ProtonConnection::send_event_file` and
+    /// queue slices of the mapping, instead of reading them through a buffer.
+    ///
+    /// # Safety
+    ///
+    /// No file sent by a connection of this client may be truncated or
+    /// modified, by this or any other process, while it is being sent. A
+    /// truncated mapping faults the process (SIGBUS) on the next read, and
+    /// a modified one changes bytes the client holds as immutable.
+    pub unsafe fn with_mmap_payloads(mut self) -> Self {
+        self.mmap_payloads = true;
+        self
+    }
+
+    /// Allow opening streams of `stream_type` with
+    /// `ProtonConnection::open_stream`. The server must register the same
+    /// type.
+    pub fn with_stream_type(mut self, stream_type: StreamType) -> Result<Self, ProtonError> {
+        self.streams.register(stream_type, None)?;
+        Ok(self)
+    }
+
+    /// How connections keep themselves alive while idle. With suppression
+    /// they send nothing while streams are busy, saving packets and, on
+    /// battery powered devices, radio wakeups.
+    pub fn with_keepalive(mut self, keepalive: KeepAlive) -> Result<Self, ProtonError> {
+        if keepalive.interval.is_zero() {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "keepalive interval must not be zero",
+            )));
+        }
+        self.keepalive = keepalive;
+        Ok(self)
+    }
+
+    /// Start connections in `mode`, overriding the server. Connections made
+    /// in low power mode also ask for a longer idle timeout. The mode can be
+    /// changed later with `ProtonConnection::set_power_mode`.
+    pub fn with_power_mode(mut self, mode: PowerMode) -> Result<Self, ProtonError> {
+        self.settings.power_mode = Some(mode);
+        self.reload_client_config()?;
+        Ok(self)
+    }
+
+    /// Send and accept telemetry datagrams of up to `max` bytes, tag
+    /// included, and size the transport's datagram buffers for them
+    /// (`MAX_DATAGRAM_SIZE` by default).
+    pub fn with_max_datagram_size(mut self, max: usize) -> Result<Self, ProtonError> {
+        check_max_datagram_size(max)?;
+        self.max_datagram_size = max;
+        self.reload_client_config()?;
+        Ok(self)
+    }
+
+    /// Copy every event acknowledged by the server to a secondary server at
+    /// `addr`, e.g. a new server version being shadow tested. Mirroring is
+    /// best effort: events go through a queue drained by a task of its own,
+    /// and are dropped rather than wait when the secondary server falls
+    /// behind or is unavailable. Payload streams are not mirrored.
+    pub fn with_mirror(mut self, addr: SocketAddr) -> Self {
+        self.mirror = Some(addr);
+        self
+    }
+
+    /// Mark every packet this client sends, on all of its connections and
+    /// probe paths, with `dscp`, e.g. `Dscp::EF` for latency critical
+    /// traffic. Streams share their connection's packets, so bulk transfers
+    /// to be marked differently need a client of their own.
+    pub fn with_dscp(self, dscp: Dscp) -> Result<Self, ProtonError> {
+        self.marking.set(Some(dscp));
+        info!(
+            "Marking packets from {} with DSCP {}",
+            self.endpoint.local_addr()?,
+            dscp
+        );
+        Ok(self)
+    }
+
+    /// Write a qlog trace of this client's packets into `dir`, one `.sqlog`
+    /// file per server address, for loading into qvis. Probe paths are
+    /// traced too, a socket swapped in by `ProtonConnection::rebind` is not.
+    pub fn with_qlog(self, dir: &Path) -> Result<Self, ProtonError> {
+        self.trace.enable(dir, Vantage::Client)?;
+        Ok(self)
+    }
+
+    /// Experimental: alongside each connection, keep a probe connection to
+    /// the same server open from each of `locals`, e.g. the addresses of a
+    /// second uplink, and report their RTT and loss in the connection's
+    /// stats. Probes carry only keepalives, sent every `PROBE_INTERVAL`,
+    /// and declare a client id of their own so admission does not take
+    /// them for duplicates. Port 0 binds any free port.
+    #[cfg(feature = "multipath")]
+    pub fn with_probe_paths(mut self, locals: &[SocketAddr]) -> Result<Self, ProtonError> {
+        self.probe_paths = locals
+            .iter()
+            .map(|&local| {
+                let endpoint = dscp::bind(local, None, self.marking.clone(), self.trace.clone())?;
+                Ok((endpoint.local_addr()?, endpoint))
+            })
+            .collect::<Result<_, ProtonError>>()?;
+        Ok(self)
+    }
+
+    /// Bucket this client into one of `experiment`'s variants by its client
+    /// id and apply the variant's options, which win over those set before.
+    /// The variant is declared in the HELLO as `experiment/variant`, so the
+    /// server can report metrics per variant, and shown in the connection's
+    /// stats. Set the client id first.
+    pub fn with_experiment(mut self, experiment: &Experiment) -> Result<Self, ProtonError> {
+        let invalid = |message: String| {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            ))
+        };
+        let client_id = self.info.client_id.as_deref().ok_or_else(|| {
+            invalid(format!(
+                "experiment {} needs a client id to bucket by",
+                experiment.name
+            ))
+        })?;
+        let variant = experiment.assign(client_id).ok_or_else(|| {
+            invalid(format!(
+                "experiment {} has no variant with any weight",
+                experiment.name
+            ))
+        })?;
+        if let Some(framing) = variant.framing {
+            self.info.framing = Some(framing);
+        }
+        self.settings = variant.settings.or(&self.settings);
+        self.info.experiment = Some(format!("{}/{}", experiment.name, variant.name));
+        self.reload_client_config()?;
+        Ok(self)
+    }
+
+    // The client a mirror connects with: same configuration, but ids are
+    // taken from the mirrored events and nothing is mirrored further
+    fn mirror_client(&self) -> Self {
+        let mut client = self.clone();
+        client.ids = Arc::new(CounterIds::default());
+        client.action_offset = Arc::new(AtomicU32::new(0));
+        client.lazy_reconnect = false;
+        client.auto_reconnect = false;
+        // Events are dropped rather than wait for retries
+        client.connect_retry = RetryPolicy::none();
+        client.mirror = None;
+        client.breaker = None;
+        client.queue = None;
+        #[cfg(feature = "multipath")]
+        client.probe_paths.clear();
+        client
+    }
+
+    // The client probing the path from `local`: a mirror client bound to
+    // `endpoint` that keeps its connection busy enough to measure
+    #[cfg(feature = "multipath")]
+    fn path_client(&self, local: SocketAddr, endpoint: &Endpoint) -> Result<Self, ProtonError> {
+        let mut client = self.mirror_client();
+        client.endpoint = endpoint.clone();
+        client.keepalive = KeepAlive {
+            interval: PROBE_INTERVAL,
+            suppress_when_active: false,
+        };
+        let identity = self
+            .info
+            .client_id
+            .as_deref()
+            .or(self.info.tenant.as_deref())
+            .unwrap_or("client");
+        client.info.client_id = Some(format!("{}@{}", identity, local));
+        client.reload_client_config()?;
+        Ok(client)
+    }
+
+    /// Connects to `server_addr` after `startup_delay` (the configured
+    /// startup delay by default), retrying failed attempts as set by `with_connect_retry`.
+    pub async fn connect(
+        &mut self,
+        server_addr: SocketAddr,
+        startup_delay: Option<Duration>,
+    ) -> Result<ProtonConnection, ProtonError> {
+        self.connect_with(server_addr, startup_delay, false).await
+    }
+
+    /// Like `connect`, but resumes the TLS session of an earlier connection
+    /// to the same server, if the client made one, and opens the streams in
+    /// 0-RTT data rather than a round trip after the handshake. The HELLO
+    /// still waits for the handshake. Without a session ticket, or with a
+    /// PSK, whose proof needs the completed handshake, the streams are opened
+    /// as usual. `ProtonConnection::zero_rtt_accepted` tells whether the
+    /// server took the 0-RTT data; if not, the streams are opened again.
+    /// Reconnects of the connection use 0-RTT as well.
+    ///
+    /// Tickets are kept in memory by the client and its clones, so the first
+    /// connection of a process makes a full handshake: rustls keeps tickets
+    /// opaque, which leaves no way to save them.
+    pub async fn connect_0rtt(
+        &mut self,
+        server_addr: SocketAddr,
+        startup_delay: Option<Duration>,
+    ) -> Result<ProtonConnection, ProtonError> {
+        self.connect_with(server_addr, startup_delay, true).await
+    }
+
+    async fn connect_with(
+        &mut self,
+        server_addr: SocketAddr,
+        startup_delay: Option<Duration>,
+        zero_rtt: bool,
+    ) -> Result<ProtonConnection, ProtonError> {
+        self.check_circuit()?;
+        let delay = startup_delay.unwrap_or(self.config.startup_delay);
+        // Wait for startup delay to ensure old connections are cleaned up
+        info!("Waiting {:?} for startup delay...", delay);
+        sleep(delay).await;
+
+        let policy = self.connect_retry;
+        let mut retry_count = 0;
+        loop {
+            self.check_circuit()?;
+            let attempt = self
+                .try_connect(server_addr, zero_rtt)
+                .instrument(info_span!("connection", remote = %server_addr));
+            let result = match timeout(policy.attempt_timeout, attempt).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!(
+                        "Connection attempt timed out after {:?}",
+                        policy.attempt_timeout
+                    );
+                    Err(ProtonError::Timeout)
+                }
+            };
+            if let Some(ref breaker) = self.breaker {
+                breaker.record(&result);
+            }
+            let error = match result {
+                Ok(mut connection) => {
+                    // Events the server lost go out first, then those
+                    // queued while disconnected; any that fail stay queued
+                    let _ = connection.replay_events().await;
+                    let _ = connection.flush_queue().await;
+                    return Ok(connection);
+                }
+                // A wrong key will not get better by retrying
+                Err(ProtonError::AuthenticationFailed) => {
+                    return Err(ProtonError::AuthenticationFailed)
+                }
+                Err(e) => e,
+            };
+            retry_count += 1;
+            if retry_count >= policy.max_attempts {
+                return Err(error);
+            }
+            let backoff = policy.jittered_backoff(retry_count);
+            info!(
+                "Retrying connection ({}/{}) in {:?}",
+                retry_count,
+                policy.max_attempts - 1,
+                backoff
+            );
+            sleep(backoff).await;
+        }
+    }
+
+    // One attempt at connecting and establishing the streams
+    async fn try_connect(
+        &self,
+        server_addr: SocketAddr,
+        zero_rtt: bool,
+    ) -> Result<ProtonConnection, ProtonError> {
+        let started = Instant::now();
+        let connecting = self.endpoint.connect(server_addr, &self.server_name)?;
+        // The auth stream goes first, and its proof needs the handshake
+        let attempt = if zero_rtt && self.psk.is_none() {
+            connecting.into_0rtt()
+        } else {
+            Err(connecting)
+        };
+        let (mut handler, early) = match attempt {
+            Ok((connection, accepted)) => {
+                let handler =
+                    ProtonStreamHandler::new(connection.clone(), self.frame_headers.clone());
+                let early = handler.open_early_streams().await.ok();
+                let accepted = accepted.await;
+                if let Some(e) = connection.close_reason() {
+                    return Err(connect_failed(e));
+                }
+                info!(
+                    "Server {} 0-RTT data",
+                    if accepted { "accepted" } else { "rejected" }
+                );
+                let (mut handler, early) = match early {
+                    Some(early) if accepted => (handler, Some(early)),
+                    // Streams of rejected 0-RTT data are gone; start afresh
+                    _ => (
+                        ProtonStreamHandler::new(connection, self.frame_headers.clone()),
+                        None,
+                    ),
+                };
+                handler.zero_rtt = Some(accepted);
+                (handler, early)
+            }
+            Err(connecting) => {
+                if zero_rtt && self.psk.is_none() {
+                    info!("No session ticket for 0-RTT, making a full handshake");
+                }
+                let connection = connecting.await.map_err(connect_failed)?;
+                let handler = ProtonStreamHandler::new(connection, self.frame_headers.clone());
+                (handler, None)
+            }
+        };
+        let connection = handler.connection.clone();
+        handler.handshake = started.elapsed();
+        info!("Connected to server at {}", server_addr);
+
+        if let Some(ref psk) = self.psk {
+            if let Err(e) = authenticate_client(&connection, psk).await {
+                warn!("PSK authentication failed: {}", e);
+                connection.close(CloseReason::Normal.into(), b"Authentication failed");
+                return Err(ProtonError::AuthenticationFailed);
+            }
+            info!("Authenticated with pre-shared key");
+        }
+
+        // Establish all streams
+        let mut info = self.info.clone();
+        info.last_event_id = self
+            .replay
+            .as_ref()
+            .and_then(|replay| replay.lock().unwrap().last_event_id());
+        if let Err(e) = handler.establish_streams(&info, early).await {
+            // Admission is decided after the HELLO, so a server turning the
+            // connection away closes it while the streams are set up
+            if let Some(quinn::ConnectionError::ApplicationClosed(close)) =
+                connection.close_reason()
+            {
+                warn!(
+                    "Server refused the connection: {}",
+                    String::from_utf8_lossy(&close.reason)
+                );
+                return Err(ProtonError::ConnectionRefused);
+            }
+            error!("Failed to establish streams: {}", e);
+            return Err(e);
+        }
+        handler.start_action_push(Arc::clone(&self.action_offset));
+        info!("All streams established");
+        let tls = self.tls_policy.negotiated(negotiated_alpn(&connection));
+        let local_settings = Arc::new(std::sync::Mutex::new(self.settings));
+        let keepalive_counters = Arc::new(KeepAliveCounters::default());
+        let (local, pushed) = (
+            Arc::clone(&local_settings),
+            Arc::clone(&handler.pushed_settings),
+        );
+        #[cfg(feature = "multipath")]
+        let paths = if self.probe_paths.is_empty() {
+            None
+        } else {
+            let clients = self
+                .probe_paths
+                .iter()
+                .map(|(local, endpoint)| Ok((*local, self.path_client(*local, endpoint)?)))
+                .collect::<Result<_, ProtonError>>()?;
+            Some(PathProbes::spawn(clients, server_addr))
+        };
+        keepalive::spawn(
+            connection.clone(),
+            self.keepalive,
+            Arc::clone(&keepalive_counters),
+            move || {
+                let local = *local.lock().unwrap();
+                local.or(&pushed.lock().unwrap()).power_mode()
+            },
+        );
+        Ok(ProtonConnection {
+            handler,
+            endpoint: self.endpoint.clone(),
+            ids: Arc::clone(&self.ids),
+            tls,
+            action_offset: Arc::clone(&self.action_offset),
+            local_settings,
+            wakeups: Wakeups::default(),
+            last_event_at: None,
+            mmap_payloads: self.mmap_payloads,
+            streams: self.streams.clone(),
+            keepalive: self.keepalive,
+            keepalive_counters,
+            mirror: self
+                .mirror
+                .map(|addr| Mirror::spawn(self.mirror_client(), addr)),
+            experiment: self.info.experiment.clone(),
+            breaker: self.breaker.clone(),
+            queue: self.queue.clone(),
+            replay: self.replay.clone(),
+            max_datagram_size: self.max_datagram_size,
+            #[cfg(feature = "multipath")]
+            paths,
+            auto_reconnect: self.auto_reconnect,
+            reconnect: (self.lazy_reconnect || self.auto_reconnect)
+                .then(|| (self.clone(), server_addr)),
+            reconnect_0rtt: zero_rtt,
+        })
+    }
+}
+
+// Reports a failed handshake as the error `connect` returns
+// QUIC carries TLS alerts as the CRYPTO_ERROR range of transport codes
+fn is_tls_alert(code: TransportErrorCode) -> bool {
+    (0x100..0x200).contains(&u64::from(code))
+}
+
+fn connect_failed(error: quinn::ConnectionError) -> ProtonError {
+    match error {
+        quinn::ConnectionError::ConnectionClosed(close)
+            if close.error_code == TransportErrorCode::CONNECTION_REFUSED =>
+        {
+            warn!("Server refused the connection");
+            ProtonError::ConnectionRefused
+        }
+        // TLS alerts carry the verifier's reason, e.g. why a certificate
+        // was rejected
+        quinn::ConnectionError::ConnectionClosed(close) if is_tls_alert(close.error_code) => {
+            let reason = String::from_utf8_lossy(&close.reason).into_owned();
+            warn!("Server rejected the TLS handshake: {}", reason);
+            ProtonError::HandshakeRejected(reason)
+        }
+        quinn::ConnectionError::TransportError(e) if is_tls_alert(e.code) => {
+            warn!("Rejected the server during the TLS handshake: {}", e.reason);
+            ProtonError::HandshakeRejected(e.reason)
+        }
+        e => match CloseReason::from_error(&e) {
+            Some(reason) => {
+                warn!(
+                    "Server closed the connection during the handshake: {}",
+                    reason
+                );
+                ProtonError::ClosedByPeer(reason)
+            }
+            None => {
+                error!("Failed to connect: {}", e);
+                ProtonError::ConnectionError
+            }
+        },
+    }
+}
+
+/// Snapshot of an established connection's state.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub rtt: Duration,
+    pub tls: NegotiatedTls,
+    pub quic: quinn_proto::ConnectionStats,
+    pub peer: Option<PeerInfo>,
+    pub batch: BatchPolicy,
+    /// How long the QUIC/TLS handshake took
+    pub handshake: Duration,
+    /// How long opening the Proton streams took after the handshake
+    pub stream_setup: Duration,
+    /// Whether the server accepted the streams opened in 0-RTT data, if
+    /// they were
+    pub zero_rtt: Option<bool>,
+    pub keepalive: KeepAliveStats,
+    pub mirror: Option<MirrorStats>,
+    pub breaker: Option<BreakerStats>,
+    pub queue: Option<QueueStats>,
+    /// Events in flight, if the server agreed to a window
+    pub window: Option<WindowStats>,
+    /// Where each stream is in its request/response exchange
+    pub streams: Vec<StreamStatus>,
+    /// Round trips measured by heartbeats, if the server agreed to them
+    pub heartbeat: Option<HeartbeatStats>,
+    /// How well the frames sent filled their datagrams
+    pub efficiency: DatagramEfficiency,
+    /// Alternate paths probed alongside the connection
+    #[cfg(feature = "multipath")]
+    pub paths: Vec<PathStats>,
+    /// Experiment variant the client was bucketed into, as
+    /// `experiment/variant`
+    pub experiment: Option<String>,
+}
+
+impl fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rtt: {:?}", self.rtt)?;
+        writeln!(f, "tls: {}", self.tls)?;
+        write!(
+            f,
+            "setup: handshake {:?}, streams {:?}",
+            self.handshake, self.stream_setup
+        )?;
+        match self.zero_rtt {
+            Some(true) => writeln!(f, ", 0-RTT accepted")?,
+            Some(false) => writeln!(f, ", 0-RTT rejected")?,
+            None => writeln!(f)?,
+        }
+        if let Some(ref peer) = self.peer {
+            writeln!(f, "server: {}", peer)?;
+        }
+        writeln!(
+            f,
+            "udp: sent {} datagrams / {} bytes, received {} datagrams / {} bytes",
+            self.quic.udp_tx.datagrams,
+            self.quic.udp_tx.bytes,
+            self.quic.udp_rx.datagrams,
+            self.quic.udp_rx.bytes
+        )?;
+        writeln!(f, "efficiency: {}", self.efficiency)?;
+        writeln!(
+            f,
+            "cwnd: {} bytes, lost packets: {}",
+            self.quic.path.cwnd, self.quic.path.lost_packets
+        )?;
+        if let Some(ref experiment) = self.experiment {
+            writeln!(f, "experiment: {}", experiment)?;
+        }
+        writeln!(f, "batching: {}", self.batch)?;
+        write!(f, "keepalive: {}", self.keepalive)?;
+        if let Some(ref mirror) = self.mirror {
+            write!(f, "\nmirror: {}", mirror)?;
+        }
+        if let Some(ref breaker) = self.breaker {
+            write!(f, "\ncircuit breaker: {}", breaker)?;
+        }
+        if let Some(ref queue) = self.queue {
+            write!(f, "\noffline queue: {}", queue)?;
+        }
+        if let Some(ref window) = self.window {
+            write!(f, "\nevent window: {}", window)?;
+        }
+        if let Some(ref heartbeat) = self.heartbeat {
+            write!(f, "\nheartbeat: {}", heartbeat)?;
+        }
+        for stream in &self.streams {
+            write!(f, "\n{}", stream)?;
+        }
+        #[cfg(feature = "multipath")]
+        for path in &self.paths {
+            write!(f, "\npath {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ProtonConnection {
+    handler: ProtonStreamHandler,
+    // The client's endpoint, shared with the client and its other connections
+    endpoint: Endpoint,
+    ids: Arc<dyn IdAllocator>,
+    tls: NegotiatedTls,
+    action_offset: Arc<AtomicU32>,
+    // Shared with the keepalive task, which follows the power mode
+    local_settings: Arc<std::sync::Mutex<ClientSettings>>,
+    wakeups: Wakeups,
+    last_event_at: Option<Instant>,
+    mmap_payloads: bool,
+    streams: StreamRegistry,
+    keepalive: KeepAlive,
+    keepalive_counters: Arc<KeepAliveCounters>,
+    mirror: Option<Mirror>,
+    experiment: Option<String>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    queue: Option<Arc<std::sync::Mutex<OfflineQueue>>>,
+    replay: Option<Arc<std::sync::Mutex<ReplayBuffer>>>,
+    max_datagram_size: usize,
+    #[cfg(feature = "multipath")]
+    paths: Option<PathProbes>,
+    auto_reconnect: bool,
+    // With lazy reconnect, how to replace this connection once it has died
+    reconnect: Option<(ProtonClient, SocketAddr)>,
+    // Whether to reconnect with 0-RTT, as this connection was made
+    reconnect_0rtt: bool,
+}
+
+impl ProtonConnection {
+    pub fn stats(&self) -> ConnectionStats {
+        let quic = self.handler.connection.stats();
+        ConnectionStats {
+            rtt: self.handler.connection.rtt(),
+            tls: self.tls.clone(),
+            quic,
+            peer: self.handler.peer.clone(),
+            batch: self.batch_policy(),
+            handshake: self.handler.handshake,
+            stream_setup: self.handler.stream_setup,
+            zero_rtt: self.handler.zero_rtt,
+            keepalive: KeepAliveStats::new(
+                self.keepalive,
+                self.settings().power_mode(),
+                &self.keepalive_counters,
+            ),
+            mirror: self.mirror.as_ref().map(Mirror::stats),
+            breaker: self.breaker.as_ref().map(|b| b.stats()),
+            queue: self.queue.as_ref().map(|q| q.lock().unwrap().stats()),
+            window: self.handler.event_window.as_ref().map(EventWindow::stats),
+            streams: self.stream_states(),
+            heartbeat: self.handler.heartbeat.stats(),
+            efficiency: self.handler.sent.efficiency(&quic.udp_tx),
+            #[cfg(feature = "multipath")]
+            paths: self.path_stats(),
+            experiment: self.experiment.clone(),
+        }
+    }
+
+    /// Where each of the connection's streams is in its request/response
+    /// exchange, e.g. awaiting the response to a state commit. Once the
+    /// connection is lost, every stream has errored with its close reason.
+    pub fn stream_states(&self) -> Vec<StreamStatus> {
+        let mut streams = self.handler.states.list();
+        if let Some(reason) = self.handler.connection.close_reason() {
+            for stream in &mut streams {
+                if !matches!(stream.state, StreamState::Errored(_)) {
+                    stream.state = StreamState::Errored(reason.to_string());
+                }
+            }
+        }
+        streams
+    }
+
+    /// Whether the server accepted the streams opened in 0-RTT data, or
+    /// `None` if they were not, see `ProtonClient::connect_0rtt`.
+    pub fn zero_rtt_accepted(&self) -> Option<bool> {
+        self.handler.zero_rtt
+    }
+
+    /// Smoothed round trip time measured by heartbeats, including time spent
+    /// queued in either peer, unlike QUIC's RTT in `stats`. None until a
+    /// heartbeat has been answered, or if the server did not agree to
+    /// heartbeats (see `ProtonClient::with_heartbeat`).
+    pub fn rtt(&self) -> Option<Duration> {
+        self.handler.heartbeat.rtt()
+    }
+
+    /// Move the connection to a new UDP socket bound on `local`, as if the
+    /// client had changed networks or its NAT had rebound it, and return
+    /// the address bound. Port 0 binds any free port. QUIC migrates the
+    /// connection, streams included, once the server sees packets from the
+    /// new address. The socket belongs to the client's endpoint, so its
+    /// other connections and later reconnects move with it, and being
+    /// quinn's own it no longer carries a DSCP set by `with_dscp` or is
+    /// traced by `with_qlog`.
+    pub fn rebind(&self, local: SocketAddr) -> Result<SocketAddr, ProtonError> {
+        let from = self.endpoint.local_addr()?;
+        let socket = std::net::UdpSocket::bind(local)?;
+        let bound = socket.local_addr()?;
+        self.endpoint.rebind(socket)?;
+        info!("Rebound from {} to {}", from, bound);
+        Ok(bound)
+    }
+
+    /// Starts a QUIC key update: both sides move to traffic keys derived
+    /// from the current ones, e.g. to keep to a crypto period on a
+    /// connection that lives for weeks. quinn also updates keys on its own
+    /// before the AEAD limits are reached, without reporting it.
+    pub fn update_keys(&self) {
+        info!(
+            "Updating keys of the connection to {}",
+            self.handler.connection.remote_address()
+        );
+        self.handler.connection.force_key_update();
+    }
+
+    /// The local address the connection's packets are sent from.
+    pub fn local_addr(&self) -> Result<SocketAddr, ProtonError> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// RTT and loss of the alternate paths probed alongside this
+    /// connection, see `ProtonClient::with_probe_paths`.
+    #[cfg(feature = "multipath")]
+    pub fn path_stats(&self) -> Vec<PathStats> {
+        self.paths.as_ref().map_or_else(Vec::new, PathProbes::stats)
+    }
+
+    // Operations fail fast while the circuit breaker is open
+    fn check_circuit(&self) -> Result<(), ProtonError> {
+        self.breaker.as_ref().map_or(Ok(()), |b| b.check())
+    }
+
+    fn record_outcome<T>(&self, result: &Result<T, ProtonError>) {
+        if let Some(ref breaker) = self.breaker {
+            breaker.record(result);
+        }
+    }
+
+    /// How the durable outbox batches events on this connection: the
+    /// configured batch size, or one derived from the current RTT and
+    /// congestion window.
+    pub fn batch_policy(&self) -> BatchPolicy {
+        match self.settings().event_batch_size {
+            Some(size) => BatchPolicy::fixed(size),
+            None => BatchPolicy::adaptive(
+                self.handler.connection.rtt(),
+                self.handler.connection.stats().path.cwnd,
+                self.handler.event_frame_len(),
+            ),
+        }
+    }
+
+    /// Settings in effect: local ones, then those pushed by the server.
+    pub fn settings(&self) -> ClientSettings {
+        let local = *self.local_settings.lock().unwrap();
+        local.or(&self.handler.pushed_settings.lock().unwrap())
+    }
+
+    /// Switch power mode while connected, or with `None` follow the server
+    /// again. The idle timeout stays as negotiated when the connection was
+    /// made.
+    pub fn set_power_mode(&mut self, mode: Option<PowerMode>) {
+        self.local_settings.lock().unwrap().power_mode = mode;
+    }
+
+    // In low power mode, hold events until the next aligned wakeup
+    async fn await_wakeup(&mut self) {
+        if self.settings().power_mode() == PowerMode::Low {
+            self.wakeups.wait(LOW_POWER_WAKEUP_INTERVAL).await;
+        }
+    }
+
+    /// Whether the server sent GOAWAY because the connection reached its
+    /// maximum age. It is closed with `CloseReason::MaxAge` after the grace
+    /// period; with auto or lazy reconnect the next operation reconnects.
+    pub fn going_away(&self) -> bool {
+        self.handler.goaway.load(Ordering::Relaxed)
+    }
+
+    /// Why the connection was closed, e.g. "Preempted by higher priority
+    /// client", or `None` while it is open.
+    pub fn close_reason(&self) -> Option<String> {
+        self.handler.connection.close_reason().map(|e| match e {
+            quinn::ConnectionError::ApplicationClosed(close) => format!(
+                "{} (code {})",
+                String::from_utf8_lossy(&close.reason),
+                close.error_code
+            ),
+            other => other.to_string(),
+        })
+    }
+
+    /// The code the server closed the connection with, e.g.
+    /// `CLOSE_AT_CAPACITY`, or `None` while it is open or if it ended
+    /// otherwise.
+    pub fn close_code(&self) -> Option<u32> {
+        self.peer_close_reason().map(CloseReason::code)
+    }
+
+    /// Why the server closed the connection, or `None` while it is open or
+    /// if it ended otherwise.
+    pub fn peer_close_reason(&self) -> Option<CloseReason> {
+        self.handler
+            .connection
+            .close_reason()
+            .and_then(|e| CloseReason::from_error(&e))
+    }
+
+    // After a failed operation, show why the server closed the connection,
+    // e.g. the diagnostic of a protocol violation, and report the failure
+    // as the close rather than the stream error it surfaced as
+    fn closed_by_server(&self, error: ProtonError) -> ProtonError {
+        let Some(quinn::ConnectionError::ApplicationClosed(close)) =
+            self.handler.connection.close_reason()
+        else {
+            return error;
+        };
+        let reason = CloseReason::from_code(
+            u32::try_from(close.error_code.into_inner()).unwrap_or(u32::MAX),
+        );
+        warn!(
+            "Server closed the connection: {} ({})",
+            String::from_utf8_lossy(&close.reason),
+            reason
+        );
+        match error {
+            ProtonError::ConnectionError | ProtonError::IoError(_) | ProtonError::Timeout => {
+                ProtonError::ClosedByPeer(reason)
+            }
+            other => other,
+        }
+    }
+
+    // Why the connection died, unless it is open or was closed on purpose.
+    // A connection closed for its age was meant to be replaced.
+    fn lost_reason(&self) -> Option<quinn::ConnectionError> {
+        match self.handler.connection.close_reason() {
+            Some(reason) if CloseReason::from_error(&reason) == Some(CloseReason::MaxAge) => {
+                Some(reason)
+            }
+            None
+            | Some(quinn::ConnectionError::LocallyClosed)
+            | Some(quinn::ConnectionError::ApplicationClosed(_)) => None,
+            reason => reason,
+        }
+    }
+
+    // With auto reconnect, replace the connection if a request failed
+    // because it was lost. Returns whether the request should be repeated.
+    async fn resume_after_loss(&mut self) -> Result<bool, ProtonError> {
+        if !self.auto_reconnect || self.lost_reason().is_none() {
+            return Ok(false);
+        }
+        self.ensure_connected().await?;
+        Ok(true)
+    }
+
+    // With lazy reconnect, replace a connection that has died since it was
+    // last used. Operations call this first.
+    async fn ensure_connected(&mut self) -> Result<(), ProtonError> {
+        let reason = match self.lost_reason() {
+            Some(reason) => format!("Connection lost ({})", reason),
+            // Replaced before the server closes it, so nothing is cut off
+            None if self.going_away() && self.handler.connection.close_reason().is_none() => {
+                "Server sent GOAWAY".to_string()
+            }
+            None => return Ok(()),
+        };
+        let Some((ref mut client, server_addr)) = self.reconnect else {
+            return Ok(());
+        };
+        info!("{}, reconnecting", reason);
+        let mut fresh = client
+            .connect_with(server_addr, Some(Duration::ZERO), self.reconnect_0rtt)
+            .await?;
+        // Headers and the frame inspector carry over to the new connection
+        std::mem::swap(&mut self.handler.headers, &mut fresh.handler.headers);
+        fresh.handler.set_inspector(self.handler.inspector());
+        for stream in [STREAM_EVENT, STREAM_STATE_COMMIT, STREAM_ACTION] {
+            let delay = self.stream_delay(stream);
+            let _ = fresh.set_stream_delay(stream, delay);
+        }
+        std::mem::swap(self, &mut fresh);
+        if fresh.going_away() {
+            fresh
+                .handler
+                .connection
+                .close(CloseReason::Normal.into(), b"Reconnected after GOAWAY");
+        }
+        Ok(())
+    }
+
+    /// Show every frame sent and received on the event, state commit and
+    /// action streams to `inspector`, or stop with `None`.
+    pub fn set_frame_inspector(&mut self, inspector: Option<Arc<dyn FrameInspector>>) {
+        self.handler.set_inspector(inspector);
+    }
+
+    /// Hold back every request on `stream` (`STREAM_EVENT`,
+    /// `STREAM_STATE_COMMIT` or `STREAM_ACTION`) by `delay` before writing
+    /// it, to explore timeout and ordering behaviour. Zero removes the delay.
+    pub fn set_stream_delay(&mut self, stream: u8, delay: Duration) -> Result<(), ProtonError> {
+        if let (STREAM_EVENT, Some(window)) = (stream, self.handler.event_window.as_mut()) {
+            window.delay = delay;
+            return Ok(());
+        }
+        let pair = self
+            .handler
+            .pair_mut(stream)
+            .ok_or(ProtonError::InvalidStream)?;
+        pair.delay = delay;
+        Ok(())
+    }
+
+    /// Latency added to requests on `stream` by `set_stream_delay`.
+    pub fn stream_delay(&self, stream: u8) -> Duration {
+        if let (STREAM_EVENT, Some(window)) = (stream, self.handler.event_window.as_ref()) {
+            return window.delay;
+        }
+        self.handler
+            .pair(stream)
+            .map_or(Duration::ZERO, |pair| pair.delay)
+    }
+
+    /// Opens a stream of the type registered as `name` with
+    /// `ProtonClient::with_stream_type`. Request streams send the connection's
+    /// headers when the client was built `with_frame_headers`.
+    pub async fn open_stream(&mut self, name: &str) -> Result<StreamChannel, ProtonError> {
+        self.ensure_connected().await?;
+        let stream_type = self
+            .streams
+            .registered()
+            .find(|t| t.name == name)
+            .cloned()
+            .ok_or(ProtonError::InvalidStream)?;
+        let headers = stream_type.kind == ChannelKind::Request && self.handler.frame_headers;
+        let (mut send, recv) = self.handler.connection.open_bi().await?;
+        let discriminator = encode_discriminator(stream_type.discriminator, headers);
+        timeout(STREAM_TIMEOUT, send.write_all(&[discriminator])).await??;
+        let mut pair = StreamPair::new(
+            send,
+            recv,
+            headers,
+            stream_type.discriminator,
+            self.handler.states.clone(),
+            self.handler.sent.clone(),
+            &stream_type.name,
+        );
+        pair.inspector = self.handler.inspector();
+        Ok(StreamChannel {
+            stream_type,
+            pair,
+            headers: self.handler.headers.clone(),
+        })
+    }
+
+    /// Layout of requests and responses the server agreed to.
+    pub fn framing(&self) -> Framing {
+        self.handler.framing
+    }
+
+    /// Metadata the server sent in its HELLO.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.handler.peer.as_ref()
+    }
+
+    pub async fn send_event(&mut self) -> Result<u32, ProtonError> {
+        // Checked before allocating, so an open breaker leaves no gap in ids
+        self.check_circuit()?;
+        let event_id = self.ids.allocate(1)?;
+        self.send_event_with_id(event_id).await
+    }
+
+    /// Send an event whose id was assigned elsewhere (e.g. by the outbox).
+    /// The id must suit the agreed event ordering. The client's counter is
+    /// moved past it, so later `send_event` ids stay unique.
+    pub async fn send_event_with_id(&mut self, event_id: u32) -> Result<u32, ProtonError> {
+        self.send_event_frame(Frame::new(event_id)).await
+    }
+
+    /// Sends a new event carrying `payload` in its frame. Needs length-prefixed
+    /// or typed framing (see `ProtonClient::with_framing`); payloads too large
+    /// for a frame go through [`ProtonConnection::send_event_stream`] instead.
+    pub async fn send_event_with_payload(&mut self, payload: Vec<u8>) -> Result<u32, ProtonError> {
+        self.check_circuit()?;
+        let event_id = self.ids.allocate(1)?;
+        self.send_event_frame(Frame::with_payload(event_id, payload))
+            .await
+    }
+
+    /// Sends a new event without waiting for its ack, returning a future
+    /// that resolves with it. If the server agreed to an event window (see
+    /// `ProtonClient::with_event_window`) this only waits while the window
+    /// is full; otherwise the event is acknowledged before it returns.
+    /// Events lost in flight with the connection are not resent.
+    pub async fn submit_event(&mut self) -> Result<PendingAck, ProtonError> {
+        self.check_circuit()?;
+        let event_id = self.ids.allocate(1)?;
+        self.submit_event_frame(Frame::new(event_id)).await
+    }
+
+    /// `submit_event` with `payload` in the event's frame, which needs
+    /// length-prefixed or typed framing.
+    pub async fn submit_event_with_payload(
+        &mut self,
+        payload: Vec<u8>,
+    ) -> Result<PendingAck, ProtonError> {
+        self.check_circuit()?;
+        let event_id = self.ids.allocate(1)?;
+        self.submit_event_frame(Frame::with_payload(event_id, payload))
+            .await
+    }
+
+    async fn submit_event_frame(&mut self, event: Frame) -> Result<PendingAck, ProtonError> {
+        self.ensure_connected().await?;
+        self.ids.advance_past(event.id)?;
+        if let (Some(interval), Some(last)) = (self.settings().event_interval(), self.last_event_at)
+        {
+            sleep_until((last + interval).into()).await;
+        }
+        self.await_wakeup().await;
+        self.last_event_at = Some(Instant::now());
+        let result = self.handler.submit_event(&event).await;
+        self.record_outcome(&result);
+        match result {
+            Ok(pending) => {
+                if let Some(ref mirror) = self.mirror {
+                    mirror.send(&event);
+                }
+                self.keep_for_replay(&event);
+                Ok(pending)
+            }
+            Err(e) => {
+                error!("Failed to send event {}: {}", event.id, e);
+                Err(self.closed_by_server(e))
+            }
+        }
+    }
+
+    /// Like `send_event`, but while the client is disconnected, and cannot
+    /// reconnect, the event is queued rather than failed if the client was
+    /// built `with_offline_queue`. An event lost with the connection while
+    /// in flight is queued too, and sent again after the reconnect.
+    pub async fn send_event_or_queue(&mut self) -> Result<SendStatus, ProtonError> {
+        self.check_circuit()?;
+        let event_id = self.ids.allocate(1)?;
+        self.send_or_queue(Frame::new(event_id)).await
+    }
+
+    /// `send_event_with_payload`, queued while disconnected like
+    /// `send_event_or_queue`. The payload counts towards the queue's bytes.
+    pub async fn send_event_with_payload_or_queue(
+        &mut self,
+        payload: Vec<u8>,
+    ) -> Result<SendStatus, ProtonError> {
+        self.check_circuit()?;
+        let event_id = self.ids.allocate(1)?;
+        self.send_or_queue(Frame::with_payload(event_id, payload))
+            .await
+    }
+
+    async fn send_or_queue(&mut self, event: Frame) -> Result<SendStatus, ProtonError> {
+        let Some(queue) = self.queue.clone() else {
+            return self.send_event_frame(event).await.map(SendStatus::Acked);
+        };
+        // A lazy reconnect flushes the queue, which must be empty before new
+        // events are sent to keep them in order
+        let connected = self.ensure_connected().await.is_ok() && !self.is_disconnected();
+        if connected && self.flush_queue().await.is_ok() {
+            match self.send_event_frame(event.clone()).await {
+                Ok(ack) => return Ok(SendStatus::Acked(ack)),
+                Err(e) if !self.is_disconnected() => return Err(e),
+                // Lost with the connection, maybe after the server got it
+                Err(_) => {}
+            }
+        }
+        let event_id = event.id;
+        queue.lock().unwrap().push(event)?;
+        info!("Disconnected, queued event {}", event_id);
+        Ok(SendStatus::Queued(event_id))
+    }
+
+    fn keep_for_replay(&self, event: &Frame) {
+        if let Some(ref replay) = self.replay {
+            replay.lock().unwrap().sent(event);
+        }
+    }
+
+    /// Sends again the kept events the server asked for in its HELLO reply
+    /// (see `ProtonClient::with_event_replay`), oldest first, and returns
+    /// how many were sent. Connecting does this on its own.
+    pub async fn replay_events(&mut self) -> Result<usize, ProtonError> {
+        let (Some(replay), Some(from)) = (
+            self.replay.clone(),
+            self.peer_info().and_then(|peer| peer.replay_from),
+        ) else {
+            return Ok(0);
+        };
+        let (events, complete) = replay.lock().unwrap().from(from);
+        if !complete {
+            warn!(
+                "Server lacks events from {}, some of which are no longer kept",
+                from
+            );
+        }
+        for event in &events {
+            let result = self.handler.send_event(event).await;
+            self.record_outcome(&result);
+            if let Err(e) = result {
+                error!("Failed to replay event {}: {}", event.id, e);
+                return Err(e);
+            }
+        }
+        info!("Replayed {} events from {}", events.len(), from);
+        Ok(events.len())
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.handler.connection.close_reason().is_some()
+    }
+
+    /// Sends the events queued while disconnected, oldest first, and returns
+    /// how many were sent. It stops at the first that fails, which stays
+    /// queued. Connecting flushes the queue on its own.
+    pub async fn flush_queue(&mut self) -> Result<usize, ProtonError> {
+        let Some(queue) = self.queue.clone() else {
+            return Ok(0);
+        };
+        let mut sent = 0;
+        loop {
+            let Some(event) = queue.lock().unwrap().pop() else {
+                break;
+            };
+            let result = match self.check_circuit() {
+                Ok(()) => self.handler.send_event(&event).await,
+                Err(e) => Err(e),
+            };
+            self.record_outcome(&result);
+            match result {
+                Ok(ack) => {
+                    info!("Queued event {} acknowledged with {}", event.id, ack);
+                    if let Some(ref mirror) = self.mirror {
+                        mirror.send(&event);
+                    }
+                    self.keep_for_replay(&event);
+                    queue.lock().unwrap().sent();
+                    sent += 1;
+                }
+                Err(e) => {
+                    error!("Failed to flush queued event {}: {}", event.id, e);
+                    queue.lock().unwrap().unpop(event);
+                    return Err(e);
+                }
+            }
+        }
+        if sent > 0 {
+            info!("Flushed {} queued events", sent);
+        }
+        Ok(sent)
+    }
+
+    pub(crate) async fn send_event_frame(&mut self, event: Frame) -> Result<u32, ProtonError> {
+        let event_id = event.id;
+        self.check_circuit()?;
+        self.ensure_connected().await?;
+        self.ids.advance_past(event_id)?;
+        if let (Some(interval), Some(last)) = (self.settings().event_interval(), self.last_event_at)
+        {
+            sleep_until((last + interval).into()).await;
+        }
+        self.await_wakeup().await;
+        self.last_event_at = Some(Instant::now());
+        let mut result = self.handler.send_event(&event).await;
+        if result.is_err() && self.resume_after_loss().await? {
+            info!("Resending event {} after reconnecting", event_id);
+            result = self.handler.send_event(&event).await;
+        }
+        self.record_outcome(&result);
+        match result {
+            Ok(ack) => {
+                info!("Event {} acknowledged with {}", event_id, ack);
+                if let Some(ref mirror) = self.mirror {
+                    mirror.send(&event);
+                }
+                self.keep_for_replay(&event);
+                Ok(ack)
+            }
+            Err(e) => {
+                error!("Failed to send event {}: {}", event_id, e);
+                Err(self.closed_by_server(e))
+            }
+        }
+    }
+
+    /// Sends the next `len` bytes of `reader` as the payload of a new event,
+    /// streamed in chunks on a stream of its own so that large payloads are
+    /// never buffered whole. Resolves with the server's ack once all of it has
+    /// been received. If the transfer is interrupted, pass the event id (see
+    /// [`ProtonConnection::last_event_id`]) and a fresh reader to
+    /// [`ProtonConnection::resume_event_stream`], on this or a new connection.
+    pub async fn send_event_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: R,
+        len: u64,
+    ) -> Result<u32, ProtonError> {
+        let event_id = self.ids.allocate(1)?;
+        self.resume_event_stream(event_id, reader, len).await
+    }
+
+    /// Sends the payload of `event_id` again after an interrupted transfer.
+    /// `reader` must yield the same bytes from the start; chunks the server
+    /// already holds are skipped rather than sent.
+    pub async fn resume_event_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        event_id: u32,
+        reader: R,
+        len: u64,
+    ) -> Result<u32, ProtonError> {
+        self.ensure_connected().await?;
+        self.ids.advance_past(event_id)?;
+        let headers = self.handler.frame_headers.then_some(&self.handler.headers);
+        match send_payload(&self.handler.connection, event_id, reader, len, headers).await {
+            Ok(ack) => {
+                info!(
+                    "Event {} payload ({} bytes) acknowledged with {}",
+                    event_id, len, ack
+                );
+                Ok(ack)
+            }
+            Err(e) => {
+                error!("Failed to send event {} payload: {}", event_id, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends the contents of the file at `path` as the payload of a new event,
+    /// memory mapped if the client was built `with_mmap_payloads`.
+    pub async fn send_event_file(&mut self, path: &Path) -> Result<u32, ProtonError> {
+        let event_id = self.ids.allocate(1)?;
+        self.resume_event_file(event_id, path).await
+    }
+
+    /// Sends the file payload of `event_id` again after an interrupted
+    /// transfer, skipping the chunks the server already holds.
+    pub async fn resume_event_file(
+        &mut self,
+        event_id: u32,
+        path: &Path,
+    ) -> Result<u32, ProtonError> {
+        self.ensure_connected().await?;
+        self.ids.advance_past(event_id)?;
+        let headers = self.handler.frame_headers.then_some(&self.handler.headers);
+        let started = Instant::now();
+        let (len, result) = if self.mmap_payloads {
+            // SAFETY: whoever called the unsafe `with_mmap_payloads`
+            // promised to leave files unchanged while they are being sent
+            let file = unsafe { MappedFile::open(path)? };
+            let len = file.len() as u64;
+            let result = send_mapped_payload(&self.handler.connection, event_id, file, headers);
+            (len, result.await)
+        } else {
+            let file = tokio::fs::File::open(path).await?;
+            let len = file.metadata().await?.len();
+            let result = send_payload(&self.handler.connection, event_id, file, len, headers);
+            (len, result.await)
+        };
+        match result {
+            Ok(ack) => {
+                let elapsed = started.elapsed();
+                info!(
+                    "Event {} file payload ({} bytes{}) acknowledged with {} after {:?} ({:.1} MB/s)",
+                    event_id,
+                    len,
+                    if self.mmap_payloads { ", mmap" } else { "" },
+                    ack,
+                    elapsed,
+                    len as f64 / elapsed.as_secs_f64() / 1e6
+                );
+                Ok(ack)
+            }
+            Err(e) => {
+                error!("Failed to send event {} payload: {}", event_id, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends the next `count` events as one batch: all are written back to
+    /// back and their acks collected together, costing one round trip.
+    pub async fn send_event_batch(&mut self, count: u32) -> Result<Vec<u32>, ProtonError> {
+        self.check_circuit()?;
+        let first = self.ids.allocate(count)?;
+        let ids: Vec<u32> = (first..first + count).collect();
+        self.send_events_with_ids(&ids).await
+    }
+
+    /// Size of one event request on the wire, including any headers.
+    pub fn event_frame_len(&self) -> usize {
+        self.handler.event_frame_len()
+    }
+
+    // Send a batch of events with ids assigned elsewhere, writing them back to
+    // back and then collecting their acks. A rate limit still paces events
+    // one at a time.
+    pub(crate) async fn send_events_with_ids(
+        &mut self,
+        event_ids: &[u32],
+    ) -> Result<Vec<u32>, ProtonError> {
+        self.ensure_connected().await?;
+        if event_ids.len() <= 1 || self.settings().event_interval().is_some() {
+            let mut acks = Vec::with_capacity(event_ids.len());
+            for &id in event_ids {
+                acks.push(self.send_event_with_id(id).await?);
+            }
+            return Ok(acks);
+        }
+        if let Some(&last) = event_ids.last() {
+            self.ids.advance_past(last)?;
+        }
+        self.check_circuit()?;
+        self.await_wakeup().await;
+        let mut result = self.handler.send_events(event_ids).await;
+        if result.is_err() && self.resume_after_loss().await? {
+            info!(
+                "Resending batch of {} events after reconnecting",
+                event_ids.len()
+            );
+            result = self.handler.send_events(event_ids).await;
+        }
+        self.record_outcome(&result);
+        match result {
+            Ok(acks) => {
+                if let Some(ref mirror) = self.mirror {
+                    for &id in event_ids {
+                        mirror.send(&Frame::new(id));
+                    }
+                }
+                info!(
+                    "Events {}..={} acknowledged as a batch of {}",
+                    event_ids[0],
+                    event_ids[event_ids.len() - 1],
+                    acks.len()
+                );
+                Ok(acks)
+            }
+            Err(e) => {
+                error!("Failed to send batch of {} events: {}", event_ids.len(), e);
+                Err(self.closed_by_server(e))
+            }
+        }
+    }
+
+    pub async fn send_state_commit(&mut self, commit_id: u32) -> Result<u32, ProtonError> {
+        self.send_state_commit_frame(Frame::new(commit_id)).await
+    }
+
+    /// Makes `commit_id` with `payload` attached, handed to the server's
+    /// `CommitHandler`. Needs length-prefixed or typed framing.
+    pub async fn send_state_commit_with_payload(
+        &mut self,
+        commit_id: u32,
+        payload: Vec<u8>,
+    ) -> Result<u32, ProtonError> {
+        self.send_state_commit_frame(Frame::with_payload(commit_id, payload))
+            .await
+    }
+
+    async fn send_state_commit_frame(&mut self, commit: Frame) -> Result<u32, ProtonError> {
+        let commit_id = commit.id;
+        self.check_circuit()?;
+        self.ensure_connected().await?;
+        let result = self
+            .handler
+            .send_state_commit(&commit, STREAM_TIMEOUT)
+            .await;
+        // The server may have made the commit, so it is not repeated. A
+        // failed reconnect is tried again by the next operation.
+        self.record_outcome(&result);
+        if result.is_err() {
+            let _ = self.resume_after_loss().await;
+        }
+        match result {
+            Ok(response) => {
+                info!(
+                    "State commit {} completed with response {}",
+                    commit_id, response
+                );
+                Ok(response)
+            }
+            Err(e) => {
+                error!("Failed to send state commit {}: {}", commit_id, e);
+                Err(self.closed_by_server(e))
+            }
+        }
+    }
+
+    /// Aborts `commit_id`, made earlier on this connection, so the server
+    /// compensates it. Returns the aborted commit id, or `AbortRefused` when
+    /// the server does not know the commit or could not compensate it.
+    pub async fn abort_commit(&mut self, commit_id: u32) -> Result<u32, ProtonError> {
+        self.check_circuit()?;
+        self.ensure_connected().await?;
+        let result = self.handler.abort_commit(commit_id).await;
+        self.record_outcome(&result);
+        match result {
+            Ok(response) => {
+                info!("State commit {} aborted", commit_id);
+                Ok(response)
+            }
+            Err(e) => {
+                error!("Failed to abort state commit {}: {}", commit_id, e);
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn read_action(&mut self) -> Result<u32, ProtonError> {
+        Ok(self.read_action_frame().await?.id)
+    }
+
+    /// Actions as the server pushes them, with their payloads, if it agreed
+    /// to push (see `ProtonClient::with_action_push`). They can be taken
+    /// once per connection and end with it. In the auto ack mode each action
+    /// is acknowledged as it is yielded; otherwise use
+    /// `ActionStream::ack_up_to`.
+    pub fn actions(&mut self) -> Result<ActionStream, ProtonError> {
+        let auto_ack = self.settings().ack_mode() == AckMode::Auto;
+        self.handler
+            .action_push
+            .as_mut()
+            .and_then(|push| push.take(auto_ack))
+            .ok_or(ProtonError::InvalidStream)
+    }
+
+    /// Reads an action together with any payload the server attached to it,
+    /// which it only does with length-prefixed or typed framing.
+    pub async fn read_action_frame(&mut self) -> Result<Frame, ProtonError> {
+        self.check_circuit()?;
+        self.ensure_connected().await?;
+        let mut result = self
+            .handler
+            .read_action(self.action_offset(), STREAM_TIMEOUT)
+            .await;
+        if result.is_err() && self.resume_after_loss().await? {
+            result = self
+                .handler
+                .read_action(self.action_offset(), STREAM_TIMEOUT)
+                .await;
+        }
+        self.record_outcome(&result);
+        match result {
+            Ok(action) => {
+                if action.payload.is_empty() {
+                    info!("Received action: {}", action.id);
+                } else {
+                    info!(
+                        "Received action: {} ({} payload bytes)",
+                        action.id,
+                        action.payload.len()
+                    );
+                }
+                self.auto_ack(action.id);
+                Ok(action)
+            }
+            Err(e) => {
+                error!("Failed to read action: {}", e);
+                Err(self.closed_by_server(e))
+            }
+        }
+    }
+
+    /// Sends a state commit, retrying transient failures per `policy` only if
+    /// the caller marks the commit as idempotent. The built-in server derives
+    /// its response from the commit id alone, so replaying a commit there is
+    /// safe; commit handlers with side effects may not be.
+    pub async fn send_state_commit_with_retry(
+        &mut self,
+        commit_id: u32,
+        policy: &RetryPolicy,
+        idempotency: Idempotency,
+    ) -> Result<u32, ProtonError> {
+        let max_attempts = match idempotency {
+            Idempotency::Idempotent => policy.max_attempts,
+            Idempotency::NonIdempotent => 1,
+        };
+        self.ensure_connected().await?;
+        let mut attempt = 1;
+        loop {
+            self.check_circuit()?;
+            let result = self
+                .handler
+                .send_state_commit(&Frame::new(commit_id), policy.attempt_timeout)
+                .await;
+            self.record_outcome(&result);
+            match result {
+                Ok(response) => {
+                    info!(
+                        "State commit {} completed with response {}",
+                        commit_id, response
+                    );
+                    return Ok(response);
+                }
+                Err(e) if attempt < max_attempts && self.handler.is_transient(&e) => {
+                    warn!(
+                        "State commit {} attempt {}/{} failed: {}, retrying",
+                        commit_id, attempt, max_attempts, e
+                    );
+                    sleep(policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("Failed to send state commit {}: {}", commit_id, e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Reads an action, retrying transient failures per `policy` only if the
+    /// caller marks the read as idempotent. Each request consumes an action on
+    /// the server, so a retried read may skip the action that timed out.
+    pub async fn read_action_with_retry(
+        &mut self,
+        policy: &RetryPolicy,
+        idempotency: Idempotency,
+    ) -> Result<u32, ProtonError> {
+        let max_attempts = match idempotency {
+            Idempotency::Idempotent => policy.max_attempts,
+            Idempotency::NonIdempotent => 1,
+        };
+        self.ensure_connected().await?;
+        let mut attempt = 1;
+        loop {
+            self.check_circuit()?;
+            let result = self
+                .handler
+                .read_action(self.action_offset(), policy.attempt_timeout)
+                .await;
+            self.record_outcome(&result);
+            match result {
+                Ok(action) => {
+                    info!("Received action: {}", action.id);
+                    self.auto_ack(action.id);
+                    return Ok(action.id);
+                }
+                Err(e) if attempt < max_attempts && self.handler.is_transient(&e) => {
+                    warn!(
+                        "Action read attempt {}/{} failed: {}, retrying",
+                        attempt, max_attempts, e
+                    );
+                    sleep(policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("Failed to read action: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Headers sent with every subsequent request. Only transmitted when the
+    /// client was built `with_frame_headers`.
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.handler.headers
+    }
+
+    /// Acknowledges every action up to and including `id` as consumed. The
+    /// offset is sent with the next action request; actions not acknowledged
+    /// by the time the connection drops are delivered again on reconnect.
+    pub fn ack_up_to(&mut self, id: u32) {
+        self.action_offset.fetch_max(id, Ordering::Relaxed);
+        if let Some(ref push) = self.handler.action_push {
+            push.acked();
+        }
+    }
+
+    fn auto_ack(&mut self, action: u32) {
+        if self.settings().ack_mode() == AckMode::Auto {
+            self.ack_up_to(action);
+        }
+    }
+
+    /// Largest telemetry `send_datagram` takes on this connection: the
+    /// configured maximum or what the path fits, whichever is smaller, less
+    /// the tag. `None` if the server does not accept datagrams.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.handler
+            .connection
+            .max_datagram_size()
+            .map(|path| path.min(self.max_datagram_size) - 1)
+    }
+
+    /// Sends `telemetry` to the server as a QUIC datagram, alongside the
+    /// streams. Datagrams are not retransmitted: they may be lost, and are
+    /// dropped rather than queued when the connection is congested.
+    pub fn send_datagram(&self, telemetry: &[u8]) -> Result<(), ProtonError> {
+        let max = self.max_datagram_size().ok_or_else(|| {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "server does not accept datagrams",
+            ))
+        })?;
+        if telemetry.len() > max {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes of telemetry do not fit in a datagram of at most {}",
+                    telemetry.len(),
+                    max
+                ),
+            )));
+        }
+        let mut datagram = Vec::with_capacity(telemetry.len() + 1);
+        datagram.push(DATAGRAM_TELEMETRY);
+        datagram.extend_from_slice(telemetry);
+        let len = datagram.len();
+        self.handler
+            .connection
+            .send_datagram(datagram.into())
+            .map_err(|e| match e {
+                quinn::SendDatagramError::ConnectionLost(_) => ProtonError::ConnectionError,
+                e => ProtonError::IoError(std::io::Error::other(e)),
+            })?;
+        self.handler.sent.record(1, len);
+        Ok(())
+    }
+
+    /// Waits for the next telemetry datagram from the server, e.g. one sent
+    /// back by its `DatagramHandler`, and returns it without the tag.
+    pub async fn recv_datagram(&self) -> Result<Vec<u8>, ProtonError> {
+        loop {
+            let datagram = self.handler.connection.read_datagram().await?;
+            if datagram.first() == Some(&DATAGRAM_TELEMETRY) {
+                return Ok(datagram[1..].to_vec());
+            }
+        }
+    }
+
+    /// Id of the most recent event sent on this client.
+    pub fn last_event_id(&self) -> u32 {
+        self.ids.last()
+    }
+
+    /// Highest action id acknowledged with `ack_up_to`.
+    pub fn action_offset(&self) -> u32 {
+        self.action_offset.load(Ordering::Relaxed)
+    }
+
+    pub async fn close(&mut self) {
+        if self.handler.connection.close_reason().is_none() {
+            info!("Closing connection to server");
+            self.handler
+                .connection
+                .close(CloseReason::Normal.into(), b"Client closed connection");
+        }
+        #[cfg(feature = "multipath")]
+        if let Some(paths) = self.paths.take() {
+            paths.close().await;
+        }
+    }
+}
+
+/// A stream of an application registered type, opened with
+/// `ProtonConnection::open_stream`.
+pub struct StreamChannel {
+    stream_type: StreamType,
+    pair: StreamPair,
+    headers: Headers,
+}
+
+impl Drop for StreamChannel {
+    fn drop(&mut self) {
+        self.pair.states.forget(self.pair.send.id());
+    }
+}
+
+impl StreamChannel {
+    pub fn stream_type(&self) -> &StreamType {
+        &self.stream_type
+    }
+
+    /// Sends `request` on a request stream and waits for the response.
+    pub async fn request(&mut self, request: u32) -> Result<u32, ProtonError> {
+        if self.stream_type.kind != ChannelKind::Request {
+            return Err(ProtonError::InvalidStream);
+        }
+        self.pair
+            .request(request, &self.headers, STREAM_TIMEOUT)
+            .await
+    }
+
+    /// Writes `data` on a byte stream.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), ProtonError> {
+        if self.stream_type.kind != ChannelKind::Bytes {
+            return Err(ProtonError::InvalidStream);
+        }
+        timeout(STREAM_TIMEOUT, self.pair.send.write_all(data)).await??;
+        Ok(())
+    }
+
+    /// The next bytes the server wrote on a byte stream, or `None` once it
+    /// has finished the stream.
+    pub async fn read(&mut self) -> Result<Option<Vec<u8>>, ProtonError> {
+        if self.stream_type.kind != ChannelKind::Bytes {
+            return Err(ProtonError::InvalidStream);
+        }
+        let chunk = timeout(STREAM_TIMEOUT, self.pair.recv.read_chunk(usize::MAX, true))
+            .await?
+            .map_err(|_| ProtonError::ConnectionError)?;
+        Ok(chunk.map(|chunk| chunk.bytes.to_vec()))
+    }
+
+    /// Tells the server nothing more will be sent. On a request stream, also
+    /// waits for the server to finish its side.
+    pub async fn finish(&mut self) -> Result<(), ProtonError> {
+        self.pair.send.finish().await?;
+        if self.stream_type.kind == ChannelKind::Request {
+            // Responses to requests that timed out may still arrive
+            timeout(STREAM_TIMEOUT, self.pair.recv.read_to_end(usize::MAX))
+                .await?
+                .map_err(|_| ProtonError::ConnectionError)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ProtonConnection {
+    fn drop(&mut self) {
+        if self.handler.connection.close_reason().is_none() {
+            warn!("ProtonConnection dropped without explicit close()");
+            self.handler.connection.close(
+                CloseReason::Normal.into(),
+                b"Client dropped without explicit close",
+            );
+        }
+    }
+}
+
+// Certificate verifier that checks the chain against the root CAs if there
+// are any, and accepts any certificate otherwise, optionally insisting on a
+// good stapled OCSP response signed for the verified certificate
+struct ServerVerification {
+    webpki: Option<WebPkiVerifier>,
+    require_ocsp_staple: bool,
+    ocsp_issuers: Vec<rustls::Certificate>,
+}
+
+impl rustls::client::ServerCertVerifier for ServerVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if let Some(ref webpki) = self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if self.require_ocsp_staple {
+            // A staple says nothing about a certificate that was not verified
+            if self.webpki.is_none() {
+                return Err(rustls::Error::General(
+                    "OCSP stapling requires root CAs to verify the server against".into(),
+                ));
+            }
+            if ocsp_response.is_empty() {
+                return Err(rustls::Error::General(
+                    "server did not staple an OCSP response".into(),
+                ));
+            }
+            let issuers: Vec<_> = intermediates
+                .iter()
+                .chain(&self.ocsp_issuers)
+                .cloned()
+                .collect();
+            match verify_ocsp_response(ocsp_response, end_entity, &issuers) {
+                Ok(OcspStatus::Good) => {}
+                Ok(status) => {
+                    return Err(rustls::Error::General(format!(
+                        "stapled OCSP response reports {:?}",
+                        status
+                    )))
+                }
+                Err(e) => return Err(rustls::Error::General(e.to_string())),
+            }
+        }
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
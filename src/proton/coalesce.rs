@@ -0,0 +1,217 @@
+use crate::proton::park::Parking;
+use crate::proton::profile::spawn_named;
+use crate::proton::ProtonError;
+use quinn::{SendStream, StreamId, VarInt};
+use smallvec::SmallVec;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{timeout_at, Instant};
+
+// Frames queued for the writer task before writes wait
+const QUEUE_DEPTH: usize = 1024;
+// Frames up to this size are queued without allocating
+const INLINE_FRAME: usize = 16;
+
+/// When coalesced frames are written to the stream: once `max_bytes` have
+/// accumulated, or `max_delay` after the first buffered frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalesceConfig {
+    pub max_bytes: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        Self {
+            // Roughly one datagram's worth of stream data
+            max_bytes: 1200,
+            max_delay: Duration::from_millis(1),
+        }
+    }
+}
+
+enum Command {
+    Write(SmallVec<[u8; INLINE_FRAME]>),
+    Flush(oneshot::Sender<Result<(), ProtonError>>),
+    Reset(u32),
+}
+
+// Resets the stream even while the writer task is blocked writing to a peer
+// that stopped reading, when a queued `Command::Reset` would never be seen
+#[derive(Default)]
+struct Abort {
+    notify: Notify,
+    code: AtomicU32,
+}
+
+/// Writes frames to a stream, either straight through or coalesced so that
+/// many tiny frames become a single stream write and share datagrams.
+pub struct FrameWriter {
+    id: StreamId,
+    inner: Inner,
+}
+
+enum Inner {
+    Direct(SendStream),
+    Coalescing(mpsc::Sender<Command>, Arc<Abort>),
+}
+
+impl FrameWriter {
+    /// Coalesces writes per `config`, or writes them through if `None`.
+    pub fn new(send: SendStream, config: Option<CoalesceConfig>) -> Self {
+        Self::with_parking(send, config, Parking::default())
+    }
+
+    /// Like `new`, releasing the coalescing buffer while `parking` says the
+    /// connection is parked.
+    pub fn with_parking(
+        send: SendStream,
+        config: Option<CoalesceConfig>,
+        parking: Parking,
+    ) -> Self {
+        let id = send.id();
+        let inner = match config {
+            None => Inner::Direct(send),
+            Some(config) => {
+                let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+                let abort = Arc::new(Abort::default());
+                spawn_named(
+                    "coalescer",
+                    run_coalescer(send, rx, Arc::clone(&abort), config, parking),
+                );
+                Inner::Coalescing(tx, abort)
+            }
+        };
+        Self { id, inner }
+    }
+
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Queues `frame`. Coalesced frames reach the stream at the next flush;
+    /// an error from an earlier flush is reported here.
+    pub async fn write(&mut self, frame: &[u8]) -> Result<(), ProtonError> {
+        match &mut self.inner {
+            Inner::Direct(send) => Ok(send.write_all(frame).await?),
+            Inner::Coalescing(tx, _) => tx
+                .send(Command::Write(SmallVec::from_slice(frame)))
+                .await
+                .map_err(|_| ProtonError::ConnectionError),
+        }
+    }
+
+    /// Abandons the stream, discarding anything buffered, and resets it with
+    /// `code`.
+    pub async fn reset(&mut self, code: u32) {
+        match &mut self.inner {
+            Inner::Direct(send) => {
+                let _ = send.reset(VarInt::from_u32(code));
+            }
+            Inner::Coalescing(tx, abort) => {
+                abort.code.store(code, Ordering::Relaxed);
+                abort.notify.notify_one();
+                let _ = tx.try_send(Command::Reset(code));
+            }
+        }
+    }
+
+    /// Writes anything buffered now, for latency-critical frames such as a
+    /// request whose response is awaited.
+    pub async fn flush(&mut self) -> Result<(), ProtonError> {
+        match &mut self.inner {
+            Inner::Direct(_) => Ok(()),
+            Inner::Coalescing(tx, _) => {
+                let (done, result) = oneshot::channel();
+                tx.send(Command::Flush(done))
+                    .await
+                    .map_err(|_| ProtonError::ConnectionError)?;
+                result.await.map_err(|_| ProtonError::ConnectionError)?
+            }
+        }
+    }
+}
+
+// Owns the stream while coalescing, so the flush timer never has to cancel a
+// write or a read in the caller
+async fn run_coalescer(
+    mut send: SendStream,
+    mut commands: mpsc::Receiver<Command>,
+    abort: Arc<Abort>,
+    config: CoalesceConfig,
+    parking: Parking,
+) {
+    let mut buf = Vec::with_capacity(config.max_bytes);
+    let mut deadline = None;
+    loop {
+        let command = match deadline {
+            Some(at) => match timeout_at(at, commands.recv()).await {
+                Ok(command) => command,
+                Err(_) => {
+                    // Timer flush
+                    deadline = None;
+                    if write(&mut send, &buf, &abort).await.is_err() {
+                        return;
+                    }
+                    buf.clear();
+                    continue;
+                }
+            },
+            None => tokio::select! {
+                command = commands.recv() => command,
+                // Nothing is buffered, so the buffer can go while parked
+                _ = parking.parked() => {
+                    buf = Vec::new();
+                    commands.recv().await
+                }
+            },
+        };
+        match command {
+            Some(Command::Write(frame)) => {
+                if buf.is_empty() {
+                    deadline = Some(Instant::now() + config.max_delay);
+                }
+                buf.extend_from_slice(&frame);
+                if buf.len() >= config.max_bytes {
+                    deadline = None;
+                    if write(&mut send, &buf, &abort).await.is_err() {
+                        return;
+                    }
+                    buf.clear();
+                }
+            }
+            Some(Command::Flush(done)) => {
+                deadline = None;
+                let result = write(&mut send, &buf, &abort).await;
+                buf.clear();
+                let failed = result.is_err();
+                let _ = done.send(result);
+                if failed {
+                    return;
+                }
+            }
+            Some(Command::Reset(code)) => {
+                let _ = send.reset(VarInt::from_u32(code));
+                return;
+            }
+            None => {
+                // Writer dropped; deliver what is left
+                let _ = write(&mut send, &buf, &abort).await;
+                return;
+            }
+        }
+    }
+}
+
+// Writes `buf`, unless the stream is reset while the write is blocked
+async fn write(send: &mut SendStream, buf: &[u8], abort: &Abort) -> Result<(), ProtonError> {
+    tokio::select! {
+        written = send.write_all(buf) => Ok(written?),
+        _ = abort.notify.notified() => {
+            let _ = send.reset(VarInt::from_u32(abort.code.load(Ordering::Relaxed)));
+            Err(ProtonError::ConnectionError)
+        }
+    }
+}
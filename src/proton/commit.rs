@@ -0,0 +1,49 @@
+use crate::proton::ProtonError;
+use std::collections::VecDeque;
+
+pub use crate::proton::wire::{ABORT_COMMIT, ABORT_REFUSED};
+
+// Commits per connection that can still be aborted
+const ABORTABLE_COMMITS: usize = 1024;
+
+/// Observes state commits and compensates aborted ones, so applications can
+/// exercise their rollback paths.
+pub trait CommitHandler: Send + Sync {
+    /// `commit_id` from `tenant` has been made and is about to be answered.
+    fn on_commit(&self, _tenant: &str, _commit_id: u32) {}
+
+    /// Like `on_commit`, with the payload the commit carried. Only clients
+    /// using length-prefixed or typed framing send one; it is empty otherwise.
+    fn on_commit_with_payload(&self, tenant: &str, commit_id: u32, _payload: &[u8]) {
+        self.on_commit(tenant, commit_id)
+    }
+
+    /// The client aborted `commit_id`, which was made earlier on the same
+    /// connection. An error refuses the abort and the commit stands.
+    fn on_abort(&self, tenant: &str, commit_id: u32) -> Result<(), ProtonError>;
+}
+
+// The most recent commits made on one connection, which are the ones an
+// abort may name
+#[derive(Debug, Default)]
+pub(crate) struct RecentCommits(VecDeque<u32>);
+
+impl RecentCommits {
+    pub(crate) fn record(&mut self, commit_id: u32) {
+        if self.0.len() == ABORTABLE_COMMITS {
+            self.0.pop_front();
+        }
+        self.0.push_back(commit_id);
+    }
+
+    pub(crate) fn contains(&self, commit_id: u32) -> bool {
+        self.0.contains(&commit_id)
+    }
+
+    // Forget the latest occurrence of `commit_id` once it has been aborted
+    pub(crate) fn forget(&mut self, commit_id: u32) {
+        if let Some(i) = self.0.iter().rposition(|&id| id == commit_id) {
+            self.0.remove(i);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Dictionary compression of event payloads, for deployments whose payloads
+//! are small and alike. The server samples recent payloads, trains a zstd
+//! dictionary on them and pushes it on the control stream to clients that
+//! asked for compression; those clients then compress each payload with the
+//! latest dictionary and open it with the dictionary's id.
+
+use crate::proton::frame::Headers;
+use crate::proton::metrics::ServerMetrics;
+use crate::proton::wire::{DICTIONARY_NONE, KEY_DICTIONARY, KEY_DICTIONARY_ID, MAX_FRAME_LEN};
+use crate::proton::ProtonError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Write;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{info, warn};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// Largest dictionary that fits in one headers section, hex encoded.
+pub const MAX_DICTIONARY_SIZE: usize = 31 * CHUNK_LEN;
+
+// Dictionary bytes per header value; hex encoding doubles them to the
+// longest value a header may carry
+const CHUNK_LEN: usize = 512;
+const LEVEL: i32 = 3;
+// Dictionaries the server still decompresses with once replaced, since
+// clients switch only when the push reaches them
+const RETAINED_DICTIONARIES: usize = 4;
+
+/// Payload compression a client asks for in its HELLO. The server echoes it
+/// in its reply if it trains dictionaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd with dictionaries trained by the server
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown compression '{}', expected zstd", s),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// When the server trains a dictionary: once `samples` payloads have been
+/// sampled, and again with fresh samples at most every `retrain_interval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub samples: usize,
+    /// Largest dictionary trained, at most `MAX_DICTIONARY_SIZE`
+    pub dictionary_size: usize,
+    /// Larger payloads are not sampled; they compress well on their own
+    pub max_sample_len: usize,
+    pub retrain_interval: Duration,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            samples: 1000,
+            dictionary_size: 8 * 1024,
+            max_sample_len: 4 * 1024,
+            retrain_interval: Duration::from_secs(600),
+        }
+    }
+}
+
+/// A trained dictionary and the id payloads compressed with it carry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    pub id: u8,
+    pub bytes: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Whether `headers` pushed on the control stream carry a dictionary
+    /// rather than settings.
+    pub fn is_dictionary(headers: &Headers) -> bool {
+        headers.get(KEY_DICTIONARY_ID).is_some()
+    }
+
+    pub fn to_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        // Dictionaries are trained no larger than fits the header limits
+        let _ = headers.insert(KEY_DICTIONARY_ID, &self.id.to_string());
+        for (i, chunk) in self.bytes.chunks(CHUNK_LEN).enumerate() {
+            let mut hex = String::with_capacity(chunk.len() * 2);
+            for byte in chunk {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            let _ = headers.insert(&format!("{}-{}", KEY_DICTIONARY, i), &hex);
+        }
+        headers
+    }
+
+    pub fn from_headers(headers: &Headers) -> Result<Self, ProtonError> {
+        let invalid = |what: &str| {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid compression dictionary: {}", what),
+            ))
+        };
+        let id = match headers.get(KEY_DICTIONARY_ID).map(str::parse::<u8>) {
+            Some(Ok(id)) if id != DICTIONARY_NONE => id,
+            _ => return Err(invalid("bad id")),
+        };
+        let mut bytes = Vec::new();
+        for i in 0.. {
+            let Some(hex) = headers.get(&format!("{}-{}", KEY_DICTIONARY, i)) else {
+                break;
+            };
+            if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid("bytes are not hex"));
+            }
+            for pair in hex.as_bytes().chunks(2) {
+                // Checked to be ASCII hex digits above
+                let pair = std::str::from_utf8(pair).unwrap();
+                bytes.push(u8::from_str_radix(pair, 16).unwrap());
+            }
+        }
+        if bytes.is_empty() {
+            return Err(invalid("no bytes"));
+        }
+        Ok(Self { id, bytes })
+    }
+}
+
+impl fmt::Display for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dictionary {} ({} bytes)", self.id, self.bytes.len())
+    }
+}
+
+// Payloads collected for the next dictionary
+#[derive(Default)]
+struct Samples {
+    payloads: Vec<Vec<u8>>,
+    // Whether a dictionary is being trained on a blocking thread
+    training: bool,
+    trained_at: Option<Instant>,
+    last_id: u8,
+}
+
+/// Server side of compression, shared by all connections: samples the event
+/// payloads received, trains dictionaries on them, publishes each new one
+/// for the connections to push, and decompresses payloads with any
+/// dictionary still retained.
+pub struct DictionaryTrainer {
+    config: CompressionConfig,
+    samples: Mutex<Samples>,
+    retained: RwLock<VecDeque<(u8, Arc<DecoderDictionary<'static>>)>>,
+    published: watch::Sender<Option<Arc<Dictionary>>>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl DictionaryTrainer {
+    pub fn new(config: CompressionConfig, metrics: Arc<ServerMetrics>) -> Self {
+        Self {
+            config,
+            samples: Mutex::new(Samples::default()),
+            retained: RwLock::new(VecDeque::new()),
+            published: watch::Sender::new(None),
+            metrics,
+        }
+    }
+
+    /// The latest dictionary, and each one trained after it.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Arc<Dictionary>>> {
+        self.published.subscribe()
+    }
+
+    /// Keeps `payload` for training, starting a training run once enough
+    /// payloads have been collected.
+    pub fn sample(self: &Arc<Self>, payload: &[u8]) {
+        if payload.is_empty() || payload.len() > self.config.max_sample_len {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        let due = samples
+            .trained_at
+            .is_none_or(|at| at.elapsed() >= self.config.retrain_interval);
+        if samples.training || !due {
+            return;
+        }
+        samples.payloads.push(payload.to_vec());
+        if samples.payloads.len() < self.config.samples {
+            return;
+        }
+        samples.training = true;
+        let payloads = std::mem::take(&mut samples.payloads);
+        let trainer = Arc::clone(self);
+        tokio::task::spawn_blocking(move || trainer.train(payloads));
+    }
+
+    fn train(&self, payloads: Vec<Vec<u8>>) {
+        let trained = zstd::dict::from_samples(&payloads, self.config.dictionary_size);
+        let mut samples = self.samples.lock().unwrap();
+        samples.training = false;
+        samples.trained_at = Some(Instant::now());
+        let bytes = match trained {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!(
+                    "Could not train a compression dictionary on {} payloads: {}",
+                    payloads.len(),
+                    e
+                );
+                return;
+            }
+        };
+        // Ids wrap around, skipping the one marking uncompressed payloads
+        let id = match samples.last_id.wrapping_add(1) {
+            DICTIONARY_NONE => DICTIONARY_NONE + 1,
+            id => id,
+        };
+        samples.last_id = id;
+        drop(samples);
+
+        let mut retained = self.retained.write().unwrap();
+        if retained.len() == RETAINED_DICTIONARIES {
+            retained.pop_front();
+        }
+        retained.push_back((id, Arc::new(DecoderDictionary::copy(&bytes))));
+        drop(retained);
+
+        let dictionary = Dictionary { id, bytes };
+        info!(
+            "Trained compression {} on {} payloads",
+            dictionary,
+            payloads.len()
+        );
+        self.metrics
+            .dictionaries_trained
+            .fetch_add(1, Ordering::Relaxed);
+        self.published.send_replace(Some(Arc::new(dictionary)));
+    }
+
+    /// The payload a client compressed into `payload`, or why it could not
+    /// be opened.
+    pub fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let Some((&id, rest)) = payload.split_first() else {
+            return Ok(Vec::new());
+        };
+        if id == DICTIONARY_NONE {
+            return Ok(rest.to_vec());
+        }
+        let dictionary = self
+            .retained
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(retained, _)| *retained == id)
+            .map(|(_, dictionary)| Arc::clone(dictionary))
+            .ok_or_else(|| format!("payload compressed with unknown dictionary {}", id))?;
+        let describe = |e: std::io::Error| format!("payload not decompressible: {}", e);
+        let decoder = zstd::stream::read::Decoder::with_prepared_dictionary(rest, &dictionary)
+            .map_err(describe)?;
+        // Bounded like any payload, however well it compressed
+        let mut out = Vec::new();
+        decoder
+            .take(MAX_FRAME_LEN as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(describe)?;
+        if out.len() > MAX_FRAME_LEN as usize {
+            return Err(format!(
+                "payload decompresses to more than {} bytes",
+                MAX_FRAME_LEN
+            ));
+        }
+        self.metrics
+            .compressed_payload_bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        self.metrics
+            .decompressed_payload_bytes
+            .fetch_add(out.len() as u64, Ordering::Relaxed);
+        Ok(out)
+    }
+}
+
+/// Client side of compression: compresses event payloads with the latest
+/// dictionary the server pushed, or sends them as they are until one
+/// arrives.
+#[derive(Default)]
+pub struct PayloadCompressor {
+    dictionary: Mutex<Option<(u8, Arc<EncoderDictionary<'static>>)>>,
+}
+
+impl PayloadCompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_dictionary(&self, dictionary: &Dictionary) {
+        let prepared = EncoderDictionary::copy(&dictionary.bytes, LEVEL);
+        *self.dictionary.lock().unwrap() = Some((dictionary.id, Arc::new(prepared)));
+    }
+
+    /// `payload` as sent on the wire: the dictionary id, then the payload
+    /// compressed with that dictionary, or as it is if that is no larger.
+    pub fn compress(&self, payload: &[u8]) -> Vec<u8> {
+        let current = self.dictionary.lock().unwrap().clone();
+        if let Some((id, dictionary)) = current {
+            let compressed = zstd::bulk::Compressor::with_prepared_dictionary(&dictionary)
+                .and_then(|mut compressor| compressor.compress(payload));
+            if let Ok(compressed) = compressed {
+                if compressed.len() < payload.len() {
+                    let mut out = Vec::with_capacity(1 + compressed.len());
+                    out.push(id);
+                    out.extend_from_slice(&compressed);
+                    return out;
+                }
+            }
+        }
+        let mut out = Vec::with_capacity(1 + payload.len());
+        out.push(DICTIONARY_NONE);
+        out.extend_from_slice(payload);
+        out
+    }
+}
@@ -0,0 +1,199 @@
+//! Transport parameters, defaulting to the constants in `proton` and
+//! loadable from a TOML file so they can be tuned per deployment without a
+//! rebuild.
+
+use crate::proton::{
+    ProtonError, CONNECT_RETRY_DELAY, IDLE_TIMEOUT, MAX_BIDIRECTIONAL_STREAMS, MAX_CONNECT_RETRIES,
+    MAX_PAYLOAD_STREAMS, MAX_STREAMS_PER_TYPE, STARTUP_DELAY,
+};
+use quinn::IdleTimeout;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+fn invalid_config(message: &str) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid config: {}", message),
+    ))
+}
+
+/// Transport parameters shared by `ProtonClient::with_config` and
+/// `ProtonServer::with_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtonConfig {
+    /// Idle time after which QUIC closes a connection
+    pub idle_timeout: Duration,
+    /// Idle time after which a keepalive is sent, or `None` for half the
+    /// idle timeout
+    pub keep_alive_interval: Option<Duration>,
+    /// Event, state commit, action and control streams
+    pub max_bidi_streams: u32,
+    /// Large payloads in flight at once
+    pub max_payload_streams: u32,
+    /// Streams of each application registered type open at once
+    pub max_streams_per_type: u32,
+    /// How long a server waits before accepting connections, and a client
+    /// before its first connect, so the previous run's connections are gone
+    pub startup_delay: Duration,
+    /// Retries after a failed connection attempt
+    pub connect_retries: u32,
+    /// Delay before the first retry, doubled on each further retry
+    pub connect_retry_delay: Duration,
+}
+
+impl Default for ProtonConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: IDLE_TIMEOUT,
+            keep_alive_interval: None,
+            max_bidi_streams: MAX_BIDIRECTIONAL_STREAMS,
+            max_payload_streams: MAX_PAYLOAD_STREAMS,
+            max_streams_per_type: MAX_STREAMS_PER_TYPE,
+            startup_delay: STARTUP_DELAY,
+            connect_retries: MAX_CONNECT_RETRIES,
+            connect_retry_delay: CONNECT_RETRY_DELAY,
+        }
+    }
+}
+
+// The file's keys, each overriding its default when present
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    idle_timeout_ms: Option<u64>,
+    keep_alive_interval_ms: Option<u64>,
+    max_bidi_streams: Option<u32>,
+    max_payload_streams: Option<u32>,
+    max_streams_per_type: Option<u32>,
+    startup_delay_ms: Option<u64>,
+    connect_retries: Option<u32>,
+    connect_retry_delay_ms: Option<u64>,
+}
+
+impl ProtonConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the TOML file at `path`. Keys it leaves out keep their
+    /// defaults; unknown keys are an error.
+    pub fn load(path: &Path) -> Result<Self, ProtonError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Idle time after which a keepalive is sent: the one set, or half the
+    /// idle timeout.
+    pub fn keep_alive_interval(&self) -> Duration {
+        self.keep_alive_interval.unwrap_or(self.idle_timeout / 2)
+    }
+
+    /// Checks that the parameters make a working connection.
+    pub fn validate(&self) -> Result<(), ProtonError> {
+        if self.idle_timeout.is_zero() || IdleTimeout::try_from(self.idle_timeout).is_err() {
+            return Err(invalid_config("idle timeout out of range"));
+        }
+        if self.keep_alive_interval().is_zero() {
+            return Err(invalid_config("keep-alive interval must not be zero"));
+        }
+        if self.keep_alive_interval() >= self.idle_timeout {
+            return Err(invalid_config(
+                "keep-alive interval must be shorter than the idle timeout",
+            ));
+        }
+        if self.max_bidi_streams < MAX_BIDIRECTIONAL_STREAMS {
+            return Err(invalid_config(&format!(
+                "max_bidi_streams must be at least {} for the built-in streams",
+                MAX_BIDIRECTIONAL_STREAMS
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ProtonConfig {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: ConfigFile = toml::from_str(s).map_err(|e| invalid_config(e.message()))?;
+        let defaults = Self::default();
+        let config = Self {
+            idle_timeout: file
+                .idle_timeout_ms
+                .map_or(defaults.idle_timeout, Duration::from_millis),
+            keep_alive_interval: file
+                .keep_alive_interval_ms
+                .map(Duration::from_millis)
+                .or(defaults.keep_alive_interval),
+            max_bidi_streams: file.max_bidi_streams.unwrap_or(defaults.max_bidi_streams),
+            max_payload_streams: file
+                .max_payload_streams
+                .unwrap_or(defaults.max_payload_streams),
+            max_streams_per_type: file
+                .max_streams_per_type
+                .unwrap_or(defaults.max_streams_per_type),
+            startup_delay: file
+                .startup_delay_ms
+                .map_or(defaults.startup_delay, Duration::from_millis),
+            connect_retries: file.connect_retries.unwrap_or(defaults.connect_retries),
+            connect_retry_delay: file
+                .connect_retry_delay_ms
+                .map_or(defaults.connect_retry_delay, Duration::from_millis),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ProtonConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "idle timeout {:?}, keep-alive {:?}, streams {}+{} payload+{} per type, \
+             startup delay {:?}, {} connect retries from {:?}",
+            self.idle_timeout,
+            self.keep_alive_interval(),
+            self.max_bidi_streams,
+            self.max_payload_streams,
+            self.max_streams_per_type,
+            self.startup_delay,
+            self.connect_retries,
+            self.connect_retry_delay
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_defaults_to_half_the_idle_timeout() {
+        let config = ProtonConfig::default();
+        assert_eq!(config.keep_alive_interval(), IDLE_TIMEOUT / 2);
+
+        let config: ProtonConfig = "idle_timeout_ms = 2000".parse().unwrap();
+        assert_eq!(config.keep_alive_interval(), Duration::from_secs(1));
+        let config: ProtonConfig = "idle_timeout_ms = 1".parse().unwrap();
+        assert_eq!(config.keep_alive_interval(), Duration::from_micros(500));
+    }
+
+    #[test]
+    fn keepalive_set_explicitly_is_checked() {
+        let config: ProtonConfig = "idle_timeout_ms = 10000\nkeep_alive_interval_ms = 2000"
+            .parse()
+            .unwrap();
+        assert_eq!(config.keep_alive_interval(), Duration::from_secs(2));
+        for toml in [
+            "idle_timeout_ms = 2000\nkeep_alive_interval_ms = 2000",
+            "keep_alive_interval_ms = 0",
+            "idle_timeout_ms = 0",
+            "max_bidi_streams = 1",
+            "idle_timeout = 5",
+        ] {
+            assert!(toml.parse::<ProtonConfig>().is_err(), "{:?} parsed", toml);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::proton::admin::AdminState;
+use crate::proton::json::json_string;
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+/// The dashboard page. It polls `/dashboard/data` every second and keeps the
+/// RTT history for its charts itself, so the server holds no extra state.
+pub const PAGE: &str = include_str!("dashboard.html");
+
+/// Live connections, recent errors and handshake latencies as JSON, for the
+/// dashboard page.
+pub fn data(state: &AdminState) -> String {
+    let mut out = String::from("{\"connections\":[");
+    for (i, connection) in state.registry.list().iter().enumerate() {
+        let stats = connection.stats();
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"addr\":{},\"tenant\":{},\"user_agent\":{},\"uptime_secs\":{},\
+             \"rtt_ms\":{:.3},\"events\":{},\"state_commits\":{},\"actions\":{},\
+             \"lost_packets\":{},\"udp_tx_bytes\":{},\"udp_rx_bytes\":{}}}",
+            stats.id,
+            json_string(&stats.addr.to_string()),
+            json_string(&stats.tenant),
+            json_string(&stats.user_agent),
+            stats.uptime.as_secs(),
+            stats.rtt.as_secs_f64() * 1e3,
+            stats.events,
+            stats.state_commits,
+            stats.actions,
+            stats.quic.path.lost_packets,
+            stats.quic.udp_tx.bytes,
+            stats.quic.udp_rx.bytes,
+        );
+    }
+
+    out.push_str("],\"errors\":[");
+    for (i, error) in state.metrics.recent_errors().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let at = error
+            .at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let _ = write!(
+            out,
+            "{{\"at_ms\":{},\"remote\":{},\"message\":{}}}",
+            at,
+            json_string(&error.remote.to_string()),
+            json_string(&error.message)
+        );
+    }
+
+    out.push_str("],\"handshakes\":[");
+    for (i, (le, count)) in state
+        .metrics
+        .handshake_duration
+        .buckets()
+        .iter()
+        .enumerate()
+    {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"le_ms\":{},\"count\":{}}}", le * 1e3, count);
+    }
+
+    out.push_str("],\"outcomes\":{");
+    for (i, (outcome, count)) in state.metrics.connection_outcomes().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}:{}", json_string(&outcome.to_string()), count);
+    }
+    out.push_str("}}");
+    out
+}
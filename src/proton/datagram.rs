@@ -0,0 +1,47 @@
+use crate::proton::ProtonError;
+
+pub use crate::proton::wire::DATAGRAM_TELEMETRY;
+
+/// Largest telemetry datagram accepted by default, tag included: what fits
+/// in a packet on any path QUIC runs over.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+// Datagrams of the largest size each side buffers before dropping new ones
+const BUFFERED_DATAGRAMS: usize = 64;
+
+/// Sizes the transport's datagram buffers for datagrams of up to `max` bytes.
+pub(crate) fn configure_transport(transport: &mut quinn::TransportConfig, max: usize) {
+    transport
+        .datagram_receive_buffer_size(Some(max * BUFFERED_DATAGRAMS))
+        .datagram_send_buffer_size(max * BUFFERED_DATAGRAMS);
+}
+
+pub(crate) fn check_max_datagram_size(max: usize) -> Result<(), ProtonError> {
+    if max < 2 {
+        return Err(ProtonError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "max datagram size must leave room for the tag and a byte of telemetry",
+        )));
+    }
+    Ok(())
+}
+
+/// Receives the telemetry clients send as QUIC datagrams, alongside the
+/// reliable streams. Datagrams may be lost, duplicated or reordered, and
+/// arrive without the tag.
+pub trait DatagramHandler: Send + Sync {
+    /// Telemetry from `tenant`. Returned bytes are sent back to the client
+    /// as a telemetry datagram of their own, on a best effort basis.
+    fn on_datagram(&self, tenant: &str, telemetry: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Sends every telemetry datagram back to the client it came from, e.g. to
+/// measure datagram delivery.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EchoDatagrams;
+
+impl DatagramHandler for EchoDatagrams {
+    fn on_datagram(&self, _tenant: &str, telemetry: &[u8]) -> Option<Vec<u8>> {
+        Some(telemetry.to_vec())
+    }
+}
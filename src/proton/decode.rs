@@ -0,0 +1,335 @@
+use crate::proton::frame::{describe_frame, hexdump, stream_name, Direction, Headers};
+use crate::proton::wire::{
+    decode_discriminator, decode_payload_header, PAYLOAD_HEADER_LEN, STREAM_ACTION, STREAM_AUTH,
+    STREAM_CONTROL, STREAM_EVENT, STREAM_PAYLOAD, STREAM_STATE_COMMIT,
+};
+use crate::proton::{Frame, Framing, MessageKind, ProtonError};
+use std::fmt;
+
+fn invalid_input(message: String) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        message,
+    ))
+}
+
+/// The bytes of a capture given either as a hex dump or as raw binary.
+///
+/// Hex may be plain (`01 e8 03 00 00`, `0x01,0xe8`), from `xxd`, from
+/// `hexdump -C` or from the REPL's `debug frames on`. Blank lines, lines
+/// starting with `#` and the `>>`/`<<` summaries of `debug frames on` are
+/// skipped, so notes can stay in a pasted capture.
+pub fn parse_capture(input: &[u8]) -> Result<Vec<u8>, ProtonError> {
+    let text = match std::str::from_utf8(input) {
+        Ok(text)
+            if text
+                .chars()
+                .all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace()) =>
+        {
+            text
+        }
+        // Anything else is a raw capture
+        _ => return Ok(input.to_vec()),
+    };
+    let mut bytes = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || ["#", ">>", "<<"].iter().any(|p| line.starts_with(p)) {
+            continue;
+        }
+        parse_hex_line(line, &mut bytes)
+            .map_err(|token| invalid_input(format!("line {}: not hex: '{}'", n + 1, token)))?;
+    }
+    if bytes.is_empty() {
+        return Err(invalid_input("capture is empty".to_string()));
+    }
+    Ok(bytes)
+}
+
+// Appends the bytes of one line, or returns the token that is not hex
+fn parse_hex_line<'a>(line: &'a str, out: &mut Vec<u8>) -> Result<(), &'a str> {
+    let mut hex = line;
+    let mut skip_offset = false;
+    // hexdump -C and `debug frames on` put the printable ASCII between bars
+    if let Some((before, _)) = line.split_once('|') {
+        hex = before;
+        skip_offset = true;
+    }
+    // xxd ends the offset with a colon and has no bars around the ASCII
+    if let Some((offset, rest)) = hex.split_once(": ") {
+        if !offset.contains(char::is_whitespace) {
+            hex = rest.trim_start();
+            hex = hex.split_once("  ").map_or(hex, |(before, _)| before);
+        }
+    }
+    let mut tokens = hex.split_whitespace().peekable();
+    if skip_offset && tokens.peek().is_some_and(|t| t.len() > 2) {
+        tokens.next();
+    }
+    for token in tokens.flat_map(|t| t.split(',')).filter(|t| !t.is_empty()) {
+        let digits = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if digits.is_empty() || digits.len() % 2 != 0 {
+            return Err(token);
+        }
+        for pair in digits.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).map_err(|_| token)?;
+            out.push(u8::from_str_radix(pair, 16).map_err(|_| token)?);
+        }
+    }
+    Ok(())
+}
+
+/// The stream discriminator named `s`: `event`, `state-commit`, `action`,
+/// `auth`, `control`, `payload` or a number.
+pub fn parse_stream(s: &str) -> Result<u8, ProtonError> {
+    match s.to_ascii_lowercase().as_str() {
+        "event" => Ok(STREAM_EVENT),
+        "state-commit" | "commit" => Ok(STREAM_STATE_COMMIT),
+        "action" => Ok(STREAM_ACTION),
+        "auth" => Ok(STREAM_AUTH),
+        "control" => Ok(STREAM_CONTROL),
+        "payload" => Ok(STREAM_PAYLOAD),
+        other => other.parse().map_err(|_| {
+            invalid_input(format!(
+                "unknown stream '{}', expected event, state-commit, action, auth, control, payload or a discriminator",
+                s
+            ))
+        }),
+    }
+}
+
+/// One frame or field found in a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Where it starts in the capture
+    pub offset: usize,
+    pub len: usize,
+    pub description: String,
+}
+
+/// A capture broken down into records, up to the first bytes that could not
+/// be decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Breakdown {
+    pub records: Vec<Record>,
+    /// Offset of the undecodable bytes and why they could not be decoded
+    pub error: Option<(usize, String)>,
+    // The undecodable bytes, shown as a hexdump
+    rest: Vec<u8>,
+}
+
+impl fmt::Display for Breakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for record in &self.records {
+            writeln!(
+                f,
+                "{:04x}  {:>4} bytes  {}",
+                record.offset, record.len, record.description
+            )?;
+        }
+        if let Some((offset, ref reason)) = self.error {
+            writeln!(
+                f,
+                "{:04x}  {:>4} bytes  error: {}",
+                offset,
+                self.rest.len(),
+                reason
+            )?;
+            write!(f, "{}", hexdump(&self.rest))?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes captured stream bytes offline with the crate's own codec, e.g.
+/// bytes pasted into a bug report.
+///
+/// A capture of what a client sent starts with the stream discriminator,
+/// which says how the rest is laid out. The server's responses carry no
+/// discriminator, so their stream is given with [`Decoder::with_responses`].
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    framing: Framing,
+    responses: Option<u8>,
+}
+
+impl Decoder {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            responses: None,
+        }
+    }
+
+    /// Decode the capture as the server's responses on `stream`.
+    pub fn with_responses(mut self, stream: u8) -> Self {
+        self.responses = Some(stream);
+        self
+    }
+
+    pub fn decode(&self, capture: &[u8]) -> Breakdown {
+        let mut cursor = Cursor {
+            capture,
+            pos: 0,
+            breakdown: Breakdown::default(),
+        };
+        let result = match self.responses {
+            Some(stream) => self.decode_responses(&mut cursor, stream),
+            None => self.decode_requests(&mut cursor),
+        };
+        if let Err(reason) = result {
+            cursor.breakdown.error = Some((cursor.pos, reason));
+            cursor.breakdown.rest = capture[cursor.pos..].to_vec();
+        }
+        cursor.breakdown
+    }
+
+    fn decode_requests(&self, cursor: &mut Cursor) -> Result<(), String> {
+        let byte = cursor.take(1, "a stream discriminator")?[0];
+        let (kind, headers) = decode_discriminator(byte);
+        cursor.record(
+            1,
+            format!(
+                "discriminator 0x{:02x} ({}){}",
+                byte,
+                stream_name(kind),
+                if headers {
+                    ", requests carry headers"
+                } else {
+                    ""
+                }
+            ),
+        );
+        match kind {
+            STREAM_EVENT | STREAM_STATE_COMMIT | STREAM_ACTION => {
+                while !cursor.is_done() {
+                    let start = cursor.pos;
+                    let frame = self.frame(cursor, MessageKind::request(kind))?;
+                    let mut described = frame.id.to_le_bytes().to_vec();
+                    if headers {
+                        described.extend_from_slice(cursor.headers()?.1);
+                    }
+                    let mut description = describe_frame(Direction::Sent, kind, &described);
+                    if !frame.payload.is_empty() {
+                        description.push_str(&format!(", {} byte payload", frame.payload.len()));
+                    }
+                    cursor.record(cursor.pos - start, description);
+                }
+            }
+            STREAM_CONTROL => {
+                while !cursor.is_done() {
+                    let start = cursor.pos;
+                    let (hello, _) = cursor.headers()?;
+                    cursor.record(cursor.pos - start, format!("HELLO {}", hello));
+                }
+            }
+            STREAM_PAYLOAD => {
+                let header = cursor.take(PAYLOAD_HEADER_LEN, "a payload header")?;
+                let (event_id, len) = decode_payload_header(header.try_into().unwrap());
+                cursor.record(
+                    PAYLOAD_HEADER_LEN,
+                    format!("payload of event {}, {} bytes", event_id, len),
+                );
+                if headers {
+                    let start = cursor.pos;
+                    let (headers, _) = cursor.headers()?;
+                    cursor.record(cursor.pos - start, format!("headers {}", headers));
+                }
+                cursor.rest("payload bytes");
+            }
+            STREAM_AUTH => cursor.rest("PSK proof"),
+            _ => cursor.rest("stream bytes"),
+        }
+        Ok(())
+    }
+
+    fn decode_responses(&self, cursor: &mut Cursor, stream: u8) -> Result<(), String> {
+        while !cursor.is_done() {
+            let start = cursor.pos;
+            let frame = self.frame(cursor, MessageKind::response(stream))?;
+            let mut description =
+                describe_frame(Direction::Received, stream, &frame.id.to_le_bytes());
+            if !frame.payload.is_empty() {
+                description.push_str(&format!(", {} byte payload", frame.payload.len()));
+            }
+            cursor.record(cursor.pos - start, description);
+        }
+        Ok(())
+    }
+
+    fn frame(&self, cursor: &mut Cursor, kind: MessageKind) -> Result<Frame, String> {
+        match self.framing {
+            Framing::Fixed => {
+                let id = cursor.take(4, "a 4-byte frame")?;
+                Ok(Frame::new(u32::from_le_bytes(id.try_into().unwrap())))
+            }
+            framing => {
+                let (frame, len) = framing
+                    .decode(kind, &cursor.capture[cursor.pos..])
+                    .map_err(reason)?;
+                cursor.pos += len;
+                Ok(frame)
+            }
+        }
+    }
+}
+
+// The error without its "IO error" prefix, which says nothing offline
+fn reason(error: ProtonError) -> String {
+    match error {
+        ProtonError::IoError(e) => e.to_string(),
+        e => e.to_string(),
+    }
+}
+
+// Walks a capture, recording what was found at each offset
+struct Cursor<'a> {
+    capture: &'a [u8],
+    pos: usize,
+    breakdown: Breakdown,
+}
+
+impl<'a> Cursor<'a> {
+    fn is_done(&self) -> bool {
+        self.pos >= self.capture.len()
+    }
+
+    fn take(&mut self, len: usize, expected: &str) -> Result<&'a [u8], String> {
+        let bytes = self.capture.get(self.pos..self.pos + len).ok_or_else(|| {
+            format!(
+                "expected {}, only {} bytes left",
+                expected,
+                self.capture.len() - self.pos
+            )
+        })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn headers(&mut self) -> Result<(Headers, &'a [u8]), String> {
+        let rest = &self.capture[self.pos..];
+        let (headers, len) = Headers::decode(rest).map_err(reason)?;
+        self.pos += len;
+        Ok((headers, &rest[..len]))
+    }
+
+    fn rest(&mut self, what: &str) {
+        let len = self.capture.len() - self.pos;
+        if len > 0 {
+            self.pos += len;
+            self.record(len, format!("{}, not decoded further", what));
+        }
+    }
+
+    // Records the `len` bytes just before the cursor
+    fn record(&mut self, len: usize, description: String) {
+        self.breakdown.records.push(Record {
+            offset: self.pos - len,
+            len,
+            description,
+        });
+    }
+}
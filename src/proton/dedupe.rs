@@ -0,0 +1,380 @@
+use crate::proton::journal::{encode_record, Journal, Record};
+use crate::proton::ProtonError;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Below this many records the log is never compacted
+const MIN_COMPACT_RECORDS: usize = 1024;
+
+/// When appends to the dedupe log are synced to disk. Acks synced before a
+/// crash are remembered after it; later ones may be forgotten, reopening
+/// those events to duplicate processing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Before each ack is sent
+    #[default]
+    Always,
+    /// At most this often, and from a background task while idle
+    Interval(Duration),
+    /// Left to the operating system
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// Parses `always`, `never` or `interval:<ms>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid fsync policy '{}', expected always, never or interval:<ms>",
+                s
+            )
+        };
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.strip_prefix("interval:").map(str::parse::<u64>) {
+                Some(Ok(ms)) if ms > 0 => Ok(FsyncPolicy::Interval(Duration::from_millis(ms))),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::Interval(interval) => write!(f, "interval:{}", interval.as_millis()),
+            FsyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+// Acks of one client's most recently acknowledged events, oldest first in
+// `recent`
+#[derive(Debug, Default)]
+struct ClientWindow {
+    acked: HashMap<u32, u32>,
+    recent: VecDeque<u32>,
+}
+
+impl ClientWindow {
+    // Forgets the oldest acks beyond `window`, returning how many
+    fn trim(&mut self, window: usize) -> usize {
+        let mut forgotten = 0;
+        while self.recent.len() > window {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.acked.remove(&oldest);
+                forgotten += 1;
+            }
+        }
+        forgotten
+    }
+}
+
+// A record: an event and the ack it was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ack {
+    event_id: u32,
+    ack: u32,
+}
+
+impl Record for Ack {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.event_id.to_le_bytes());
+        out.extend_from_slice(&self.ack.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<(Self, &[u8])> {
+        let event_id = u32::from_le_bytes(buf.get(..4)?.try_into().unwrap());
+        let ack = u32::from_le_bytes(buf.get(4..8)?.try_into().unwrap());
+        Some((Self { event_id, ack }, &buf[8..]))
+    }
+}
+
+#[derive(Debug)]
+struct Index {
+    log: Journal,
+    clients: HashMap<String, ClientWindow>,
+    // Records in the log, live or not
+    logged: usize,
+    // Records still in a window
+    live: usize,
+}
+
+impl Index {
+    // Replaces the log with the live records
+    fn compact(&mut self) -> Result<(), ProtonError> {
+        let mut records = Vec::new();
+        for (client, window) in &self.clients {
+            for &event_id in &window.recent {
+                let ack = window.acked[&event_id];
+                encode_record(client, &Ack { event_id, ack }, &mut records);
+            }
+        }
+        self.log.rewrite(&records)?;
+        self.logged = self.live;
+        Ok(())
+    }
+}
+
+/// Acks of recently acknowledged events, by client, shared by all of a
+/// server's connections and persisted to a log so that neither a reconnect
+/// nor a restart of the server reopens the window in which a retransmitted
+/// event would be processed twice.
+///
+/// Each record is a little-endian u16 key length, the client key, then the
+/// u32 event id and u32 ack. The log is compacted to the live records when
+/// opened and whenever it grows to twice their number.
+#[derive(Debug)]
+pub struct DedupeIndex {
+    fsync: FsyncPolicy,
+    window: usize,
+    index: Mutex<Index>,
+}
+
+impl DedupeIndex {
+    /// Opens or creates the log at `path`, loading the last `window` acks
+    /// it holds of each client.
+    pub fn open(path: &Path, fsync: FsyncPolicy, window: usize) -> Result<Self, ProtonError> {
+        let mut clients: HashMap<String, ClientWindow> = HashMap::new();
+        let mut live = 0;
+        // A partial record left by a crash mid-append was never acked
+        let log = Journal::open(path, fsync, |key, Ack { event_id, ack }, _, _| {
+            let window = clients.entry(key.to_string()).or_default();
+            if window.acked.insert(event_id, ack).is_none() {
+                window.recent.push_back(event_id);
+                live += 1;
+            }
+        })?;
+        // Acks forgotten since the last compaction are still in the log,
+        // and the window may have shrunk since it was written
+        for acks in clients.values_mut() {
+            live -= acks.trim(window);
+        }
+        clients.retain(|_, w| !w.recent.is_empty());
+        let mut index = Index {
+            log,
+            clients,
+            logged: live,
+            live,
+        };
+        index.compact()?;
+        Ok(Self {
+            fsync,
+            window,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn fsync(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    /// Acks remembered per client.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Events remembered across all clients.
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The ack `client` was sent for `event_id`, if it is still remembered.
+    pub fn lookup(&self, client: &str, event_id: u32) -> Option<u32> {
+        let index = self.index.lock().unwrap();
+        index.clients.get(client)?.acked.get(&event_id).copied()
+    }
+
+    /// Remembers the ack of an event of `client`, forgetting its oldest
+    /// once it has more than the window. Returns once the record is as
+    /// durable as the fsync policy makes it.
+    pub fn record(&self, client: &str, event_id: u32, ack: u32) -> Result<(), ProtonError> {
+        let mut index = self.index.lock().unwrap();
+        let entry = index.clients.entry(client.to_string()).or_default();
+        // The first ack stands, as it is the one in the log
+        if entry.acked.contains_key(&event_id) {
+            return Ok(());
+        }
+        entry.acked.insert(event_id, ack);
+        entry.recent.push_back(event_id);
+        let forgotten = entry.trim(self.window);
+        index.live = index.live + 1 - forgotten;
+
+        let mut record = Vec::new();
+        encode_record(client, &Ack { event_id, ack }, &mut record);
+        index.log.append(&record)?;
+        index.logged += 1;
+        if index.logged >= MIN_COMPACT_RECORDS && index.logged >= 2 * index.live {
+            index.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs appends not yet synced, for the `Interval` policy's background
+    /// task.
+    pub fn flush(&self) -> Result<(), ProtonError> {
+        self.index.lock().unwrap().log.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proton::journal::decode_record;
+    use std::path::PathBuf;
+
+    // A log path of its own for each test, with no log there yet
+    fn scratch(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("proton-dedupe-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn record_len(client: &str) -> u64 {
+        (2 + client.len() + 8) as u64
+    }
+
+    #[test]
+    fn fsync_policy_round_trips() {
+        for s in ["always", "never", "interval:250"] {
+            assert_eq!(s.parse::<FsyncPolicy>().unwrap().to_string(), s);
+        }
+        for s in ["", "sometimes", "interval:", "interval:0", "interval:-5"] {
+            assert!(s.parse::<FsyncPolicy>().is_err(), "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn window_forgets_the_oldest_acks() {
+        let path = scratch("window");
+        let index = DedupeIndex::open(&path, FsyncPolicy::Never, 2).unwrap();
+        assert!(index.is_empty());
+        for id in 1..=3 {
+            index.record("acme/a", id, id + 100).unwrap();
+        }
+        index.record("acme/b", 1, 7).unwrap();
+        assert_eq!(index.lookup("acme/a", 1), None);
+        assert_eq!(index.lookup("acme/a", 3), Some(103));
+        assert_eq!(index.lookup("acme/b", 1), Some(7));
+        assert_eq!(index.lookup("acme/c", 1), None);
+        // The first ack sent for an event stands
+        index.record("acme/b", 1, 8).unwrap();
+        assert_eq!(index.lookup("acme/b", 1), Some(7));
+        assert_eq!(index.len(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn acks_survive_a_reopen() {
+        let path = scratch("reopen");
+        let index = DedupeIndex::open(&path, FsyncPolicy::Always, 2).unwrap();
+        index.record("acme/a", 1, 1).unwrap();
+        index.record("acme/a", 2, 2).unwrap();
+        index.record("acme/a", 3, 3).unwrap();
+        drop(index);
+
+        // Only the window is reloaded, and the log is trimmed to it
+        let index = DedupeIndex::open(&path, FsyncPolicy::Always, 2).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.lookup("acme/a", 1), None);
+        assert_eq!(index.lookup("acme/a", 2), Some(2));
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            2 * record_len("acme/a")
+        );
+        index.record("acme/a", 4, 4).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.lookup("acme/a", 2), None);
+        assert_eq!(index.lookup("acme/a", 3), Some(3));
+        drop(index);
+
+        // Acks forgotten before the restart stay forgotten
+        let index = DedupeIndex::open(&path, FsyncPolicy::Always, 2).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.lookup("acme/a", 2), None);
+        assert_eq!(index.lookup("acme/a", 4), Some(4));
+        drop(index);
+
+        // A smaller window trims what is reloaded too
+        let index = DedupeIndex::open(&path, FsyncPolicy::Always, 1).unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.lookup("acme/a", 3), None);
+        assert_eq!(index.lookup("acme/a", 4), Some(4));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_record_is_dropped_on_reload() {
+        let path = scratch("truncated");
+        let index = DedupeIndex::open(&path, FsyncPolicy::Always, 8).unwrap();
+        index.record("acme/a", 1, 1).unwrap();
+        drop(index);
+        let mut partial = Vec::new();
+        let ack = Ack {
+            event_id: 2,
+            ack: 2,
+        };
+        encode_record("acme/a", &ack, &mut partial);
+        for cut in 1..partial.len() {
+            let mut log = std::fs::read(&path).unwrap();
+            log.truncate(record_len("acme/a") as usize);
+            log.extend_from_slice(&partial[..cut]);
+            std::fs::write(&path, log).unwrap();
+
+            let index = DedupeIndex::open(&path, FsyncPolicy::Always, 8).unwrap();
+            assert_eq!(index.len(), 1, "cut at {}", cut);
+            assert_eq!(index.lookup("acme/a", 2), None);
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().len(),
+                record_len("acme/a")
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn malformed_records_are_not_decoded() {
+        assert!(decode_record::<Ack>(&[]).is_none());
+        assert!(decode_record::<Ack>(&[5, 0, b'a']).is_none());
+        let ack = Ack {
+            event_id: 1,
+            ack: 2,
+        };
+        let mut record = Vec::new();
+        encode_record("ab", &ack, &mut record);
+        assert_eq!(decode_record(&record), Some(("ab", ack, &[][..])));
+        assert!(decode_record::<Ack>(&record[..record.len() - 1]).is_none());
+        // A key that is not UTF-8 ends the log
+        record[2] = 0xff;
+        assert!(decode_record::<Ack>(&record).is_none());
+    }
+
+    #[test]
+    fn log_is_compacted_as_it_grows() {
+        let path = scratch("compact");
+        let index = DedupeIndex::open(&path, FsyncPolicy::Never, 1).unwrap();
+        for id in 0..MIN_COMPACT_RECORDS as u32 {
+            index.record("acme/a", id, id).unwrap();
+        }
+        assert_eq!(index.len(), 1);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            record_len("acme/a")
+        );
+        index.flush().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! Draining a server for a zero-downtime restart. A draining server stops
+//! accepting connections and lets the ones it has finish, closing those
+//! still open after the drain timeout with `CLOSE_SERVER_RESTARTING`. Its
+//! listening socket stays open meanwhile, typically shared with the process
+//! taking over, so new connections land there instead of being refused.
+//!
+//! A server with a maximum connection age drains single connections the
+//! same way once they reach it: it pushes a GOAWAY on the control stream,
+//! gives the client the drain timeout to reconnect, then closes the old
+//! connection with `CLOSE_MAX_AGE`. Clients reconnect through the load
+//! balancer, so this spreads them across a pool and makes them
+//! authenticate again periodically.
+
+use crate::proton::frame::Headers;
+use crate::proton::settings::ClientSettings;
+use crate::proton::wire::KEY_GOAWAY;
+use quinn::Endpoint;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::info;
+
+/// How long a draining server waits for its connections to finish unless
+/// configured otherwise.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Starts draining a server, from a signal handler or another task.
+#[derive(Debug, Clone)]
+pub struct DrainHandle {
+    pub(crate) endpoint: Endpoint,
+    draining: Arc<AtomicBool>,
+    requested: Arc<Notify>,
+}
+
+impl DrainHandle {
+    pub(crate) fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            draining: Arc::new(AtomicBool::new(false)),
+            requested: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Stops accepting connections and makes `ProtonServer::run` return
+    /// once the current ones are done. Draining cannot be undone.
+    pub fn drain(&self) {
+        if self.draining.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        info!("Draining: no longer accepting connections");
+        // Handshakes of connections not yet accepted are abandoned too
+        self.endpoint.set_server_config(None);
+        self.requested.notify_one();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    // Resolves once draining was requested, even if before the call
+    pub(crate) async fn requested(&self) {
+        self.requested.notified().await
+    }
+}
+
+// Connections reach the maximum age up to this fraction of it early, so
+// those opened together, e.g. after a restart, do not all reconnect at once
+const MAX_AGE_JITTER: f64 = 0.1;
+
+// How long one connection is served before its GOAWAY: `max_age` less up
+// to a tenth, at random
+pub(crate) fn connection_age(max_age: Duration) -> Duration {
+    let mut bytes = [0u8; 4];
+    // Without randomness, every connection gets the full age
+    let _ = SystemRandom::new().fill(&mut bytes);
+    let early = MAX_AGE_JITTER * f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX);
+    max_age - max_age.mul_f64(early)
+}
+
+// GOAWAY pushed on the control stream. It repeats the current settings so
+// clients that predate GOAWAY, which read it as settings, keep them.
+pub(crate) fn goaway_headers(settings: &ClientSettings, grace: Duration) -> Headers {
+    let mut headers = settings.to_headers();
+    let millis = u64::try_from(grace.as_millis()).unwrap_or(u64::MAX);
+    let _ = headers.insert(KEY_GOAWAY, &millis.to_string());
+    headers
+}
+
+/// Grace period of the GOAWAY among headers pushed on the control stream,
+/// or `None` if they carry none.
+pub fn goaway_grace(headers: &Headers) -> Option<Duration> {
+    headers
+        .get(KEY_GOAWAY)
+        .and_then(|millis| millis.parse().ok())
+        .map(Duration::from_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_age_is_jittered_early() {
+        let max_age = Duration::from_secs(3600);
+        for _ in 0..1000 {
+            let age = connection_age(max_age);
+            assert!(age <= max_age, "{:?}", age);
+            assert!(age >= max_age.mul_f64(1.0 - MAX_AGE_JITTER), "{:?}", age);
+        }
+    }
+
+    #[test]
+    fn connection_age_extremes() {
+        assert_eq!(connection_age(Duration::ZERO), Duration::ZERO);
+        assert!(connection_age(Duration::from_nanos(1)) <= Duration::from_nanos(1));
+        let age = connection_age(Duration::MAX);
+        assert!(age >= Duration::MAX.mul_f64(1.0 - MAX_AGE_JITTER));
+    }
+
+    #[test]
+    fn goaway_carries_grace_and_settings() {
+        let settings = ClientSettings {
+            event_batch_size: Some(8),
+            ..ClientSettings::default()
+        };
+        let headers = goaway_headers(&settings, Duration::from_millis(1500));
+        assert_eq!(goaway_grace(&headers), Some(Duration::from_millis(1500)));
+        assert_eq!(ClientSettings::from_headers(&headers), settings);
+
+        assert_eq!(goaway_grace(&settings.to_headers()), None);
+        let mut malformed = Headers::new();
+        malformed.insert(KEY_GOAWAY, "soon").unwrap();
+        assert_eq!(goaway_grace(&malformed), None);
+    }
+}
@@ -0,0 +1,395 @@
+use crate::proton::qlog::PacketTrace;
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::{AsyncUdpSocket, Endpoint, EndpointConfig, Runtime, ServerConfig, TokioRuntime};
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::Interest;
+use tracing::debug;
+
+// Stored in a `Marking` while packets go out unmarked
+const UNMARKED: u8 = u8::MAX;
+
+/// A Differentiated Services code point: the upper six bits of the IPv4 TOS
+/// byte or IPv6 traffic class, telling routers how to queue the packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// Best effort, the default for unmarked traffic
+    pub const BE: Dscp = Dscp(0);
+    /// Expedited forwarding, for latency critical traffic
+    pub const EF: Dscp = Dscp(46);
+
+    /// The code point `value`, if it fits in six bits.
+    pub const fn new(value: u8) -> Option<Self> {
+        if value < 64 {
+            Some(Dscp(value))
+        } else {
+            None
+        }
+    }
+
+    /// Assured forwarding class `class` (1-4) with drop precedence `drop`
+    /// (1-3), e.g. `af(1, 1)` for AF11, suited to bulk transfers.
+    pub const fn af(class: u8, drop: u8) -> Option<Self> {
+        if class >= 1 && class <= 4 && drop >= 1 && drop <= 3 {
+            Some(Dscp(class * 8 + drop * 2))
+        } else {
+            None
+        }
+    }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    // TOS or traffic class byte, with `ecn` in the low two bits
+    fn tos(self, ecn: u8) -> u8 {
+        self.0 << 2 | ecn & 0b11
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    /// Parses `EF`, `AF11` to `AF43`, `CS0` to `CS7`, `BE` or a number
+    /// from 0 to 63, in any case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid DSCP '{}', expected EF, AF11..AF43, CS0..CS7, BE or 0-63",
+                s
+            )
+        };
+        let name = s.to_ascii_uppercase();
+        let digits = |prefix: &str| -> Option<Vec<u8>> {
+            let rest = name.strip_prefix(prefix)?;
+            rest.bytes()
+                .map(|b| b.is_ascii_digit().then(|| b - b'0'))
+                .collect()
+        };
+        let dscp = match name.as_str() {
+            "EF" => Some(Dscp::EF),
+            "BE" => Some(Dscp::BE),
+            _ => match (digits("AF").as_deref(), digits("CS").as_deref()) {
+                (Some(&[class, drop]), _) => Dscp::af(class, drop),
+                (_, Some(&[class])) if class < 8 => Some(Dscp(class * 8)),
+                _ => s.parse().ok().and_then(Dscp::new),
+            },
+        };
+        dscp.ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.0;
+        match value {
+            0 => write!(f, "BE"),
+            46 => write!(f, "EF"),
+            _ if value.is_multiple_of(8) => write!(f, "CS{}", value / 8),
+            _ if (1..=4).contains(&(value / 8)) && [2, 4, 6].contains(&(value % 8)) => {
+                write!(f, "AF{}{}", value / 8, value % 8 / 2)
+            }
+            _ => write!(f, "{}", value),
+        }?;
+        write!(f, " ({})", value)
+    }
+}
+
+/// The DSCP an endpoint marks its packets with, shared with the endpoint's
+/// socket so it can be changed while the endpoint runs.
+#[derive(Debug, Clone)]
+pub struct Marking(Arc<AtomicU8>);
+
+impl Default for Marking {
+    fn default() -> Self {
+        Marking(Arc::new(AtomicU8::new(UNMARKED)))
+    }
+}
+
+impl Marking {
+    pub fn set(&self, dscp: Option<Dscp>) {
+        let value = dscp.map_or(UNMARKED, |dscp| dscp.0);
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<Dscp> {
+        Dscp::new(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Binds a QUIC endpoint on `addr` whose packets are marked as `marking`
+/// says and traced as `trace` says. All streams of a connection share its packets, so the marking
+/// applies to whole connections; traffic marked differently needs an
+/// endpoint of its own.
+pub fn bind(
+    addr: SocketAddr,
+    server_config: Option<ServerConfig>,
+    marking: Marking,
+    trace: PacketTrace,
+) -> io::Result<Endpoint> {
+    bind_socket(
+        std::net::UdpSocket::bind(addr)?,
+        server_config,
+        marking,
+        trace,
+    )
+}
+
+/// Like `bind`, on a socket bound already, e.g. one inherited from another
+/// process.
+pub fn bind_socket(
+    socket: std::net::UdpSocket,
+    server_config: Option<ServerConfig>,
+    marking: Marking,
+    trace: PacketTrace,
+) -> io::Result<Endpoint> {
+    let runtime = Arc::new(TokioRuntime);
+    let socket = MarkedSocket::new(socket, marking, trace, runtime.as_ref())?;
+    Endpoint::new_with_abstract_socket(EndpointConfig::default(), server_config, socket, runtime)
+}
+
+/// quinn's socket, except that while a DSCP is set, datagrams are sent with
+/// it here: quinn sets the TOS byte of every packet to its ECN bits alone,
+/// which would clear a marking set on the socket. Every datagram passes
+/// through here, so this is also where packet traces are taken.
+#[derive(Debug)]
+struct MarkedSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    // The same socket, for sending marked datagrams
+    io: tokio::net::UdpSocket,
+    ipv6: bool,
+    marking: Marking,
+    trace: PacketTrace,
+}
+
+impl MarkedSocket {
+    fn new(
+        socket: std::net::UdpSocket,
+        marking: Marking,
+        trace: PacketTrace,
+        runtime: &dyn Runtime,
+    ) -> io::Result<Self> {
+        // Wrapping configures the socket, non-blocking included
+        let inner = runtime.wrap_udp_socket(socket.try_clone()?)?;
+        Ok(Self {
+            inner,
+            ipv6: socket.local_addr()?.is_ipv6(),
+            io: tokio::net::UdpSocket::from_std(socket)?,
+            marking,
+            trace,
+        })
+    }
+
+    fn trace_sent(&self, transmits: &[Transmit]) {
+        if self.trace.is_enabled() {
+            for transmit in transmits {
+                self.trace.sent(
+                    transmit.destination,
+                    &transmit.contents,
+                    transmit.segment_size,
+                );
+            }
+        }
+    }
+}
+
+impl AsyncUdpSocket for MarkedSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let Some(dscp) = self.marking.get() else {
+            let poll = self.inner.poll_send(state, cx, transmits);
+            if let Poll::Ready(Ok(sent)) = poll {
+                self.trace_sent(&transmits[..sent]);
+            }
+            return poll;
+        };
+        let mut sent = 0;
+        while sent < transmits.len() {
+            match self.io.poll_send_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending if sent == 0 => return Poll::Pending,
+                Poll::Pending => break,
+            }
+            let transmit = &transmits[sent];
+            let tos = dscp.tos(transmit.ecn.map_or(0, |ecn| ecn as u8));
+            match self.io.try_io(Interest::WRITABLE, || {
+                send(self.io.as_raw_fd(), self.ipv6, transmit, tos)
+            }) {
+                Ok(()) => sent += 1,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                    ) => {}
+                // Like quinn, drop the datagram and leave it to retransmission
+                Err(e) => {
+                    debug!("Dropping datagram to {}: {}", transmit.destination, e);
+                    sent += 1;
+                }
+            }
+        }
+        self.trace_sent(&transmits[..sent]);
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.inner.poll_recv(cx, bufs, meta);
+        if let Poll::Ready(Ok(received)) = poll {
+            if self.trace.is_enabled() {
+                for (buf, meta) in bufs.iter().zip(meta.iter()).take(received) {
+                    self.trace
+                        .received(meta.addr, &buf[..meta.len], meta.stride);
+                }
+            }
+        }
+        poll
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+// Room for the TOS, segment size and source address control messages
+const CONTROL_LEN: usize = 128;
+
+// Sends `transmit` with its TOS or traffic class byte set to `tos`
+fn send(fd: RawFd, ipv6: bool, transmit: &Transmit, tos: u8) -> io::Result<()> {
+    let destination = match transmit.destination {
+        SocketAddr::V4(v4) if ipv6 => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        destination => destination,
+    };
+    // SAFETY: all-zero is a valid value of these C structs
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let name_len = match destination {
+        SocketAddr::V4(v4) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for any
+            // socket address
+            let sin = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as _;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            // SAFETY: as above
+            let sin6 = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as _;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_addr.s6_addr = v6.ip().octets();
+            sin6.sin6_scope_id = v6.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    let mut iov = libc::iovec {
+        iov_base: transmit.contents.as_ptr() as *mut libc::c_void,
+        iov_len: transmit.contents.len(),
+    };
+    let mut control = [0u64; CONTROL_LEN / 8];
+    // SAFETY: as above
+    let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+    hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    hdr.msg_namelen = name_len as _;
+    hdr.msg_iov = &mut iov;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = CONTROL_LEN as _;
+
+    // SAFETY: the control messages are written within `control`, which is
+    // aligned for cmsghdr and large enough for all of them
+    unsafe {
+        let mut len = 0;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        let mut push = |level: libc::c_int, kind: libc::c_int, data: &[u8]| {
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = kind;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data.len() as _) as _;
+            ptr::copy_nonoverlapping(data.as_ptr(), libc::CMSG_DATA(cmsg), data.len());
+            len += libc::CMSG_SPACE(data.len() as _) as usize;
+            cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        };
+        let tos = (tos as libc::c_int).to_ne_bytes();
+        if destination.is_ipv4() {
+            push(libc::IPPROTO_IP, libc::IP_TOS, &tos);
+        } else {
+            push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &tos);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(segment_size) = transmit.segment_size {
+                push(
+                    libc::SOL_UDP,
+                    libc::UDP_SEGMENT,
+                    &(segment_size as u16).to_ne_bytes(),
+                );
+            }
+            match transmit.src_ip {
+                Some(IpAddr::V4(src)) if destination.is_ipv4() => {
+                    let info = libc::in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: libc::in_addr {
+                            s_addr: u32::from_ne_bytes(src.octets()),
+                        },
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    };
+                    push(libc::IPPROTO_IP, libc::IP_PKTINFO, as_bytes(&info));
+                }
+                Some(src) => {
+                    let src = match src {
+                        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                        IpAddr::V6(v6) => v6,
+                    };
+                    let info = libc::in6_pktinfo {
+                        ipi6_addr: libc::in6_addr {
+                            s6_addr: src.octets(),
+                        },
+                        ipi6_ifindex: 0,
+                    };
+                    push(libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, as_bytes(&info));
+                }
+                None => {}
+            }
+        }
+        hdr.msg_controllen = len as _;
+    }
+
+    // SAFETY: `hdr` points at buffers that outlive the call
+    if unsafe { libc::sendmsg(fd, &hdr, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The bytes of a plain C struct
+#[cfg(target_os = "linux")]
+fn as_bytes<T>(value: &T) -> &[u8] {
+    // SAFETY: only called with padding-free libc structs
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
@@ -0,0 +1,111 @@
+//! How well application frames are packed into UDP datagrams. A frame of a
+//! few bytes sent in a datagram of its own spends most of the datagram on
+//! QUIC headers and packet protection; batching and coalescing exist to let
+//! frames share datagrams, and these figures show whether they do.
+
+use quinn_proto::UdpStats;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Datagram size counted as full: QUIC's minimum, which every path carries.
+/// On paths with a larger MTU, datagrams can be more than full.
+pub const FULL_DATAGRAM: u64 = 1200;
+
+#[derive(Debug, Default)]
+struct Counts {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Application frames a connection sent, counted by whoever writes them.
+/// Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct SentFrames {
+    counts: Arc<Counts>,
+}
+
+impl SentFrames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts `frames` frames totalling `bytes` bytes.
+    pub fn record(&self, frames: u64, bytes: usize) {
+        self.counts.frames.fetch_add(frames, Ordering::Relaxed);
+        self.counts.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// How the frames counted so far filled the datagrams in `udp`, the
+    /// connection's sent datagrams.
+    pub fn efficiency(&self, udp: &UdpStats) -> DatagramEfficiency {
+        DatagramEfficiency {
+            frames: self.counts.frames.load(Ordering::Relaxed),
+            frame_bytes: self.counts.bytes.load(Ordering::Relaxed),
+            datagrams: udp.datagrams,
+            datagram_bytes: udp.bytes,
+        }
+    }
+}
+
+/// Application frames sent and the UDP datagrams that carried them,
+/// including datagrams that carried no frame, such as acks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DatagramEfficiency {
+    pub frames: u64,
+    pub frame_bytes: u64,
+    pub datagrams: u64,
+    /// UDP payload bytes
+    pub datagram_bytes: u64,
+}
+
+impl DatagramEfficiency {
+    pub fn frames_per_datagram(&self) -> f64 {
+        if self.datagrams == 0 {
+            return 0.0;
+        }
+        self.frames as f64 / self.datagrams as f64
+    }
+
+    /// Average datagram size as a share of `FULL_DATAGRAM`.
+    pub fn fill_ratio(&self) -> f64 {
+        if self.datagrams == 0 {
+            return 0.0;
+        }
+        self.datagram_bytes as f64 / (self.datagrams * FULL_DATAGRAM) as f64
+    }
+
+    /// Share of the bytes sent that were not application frames: QUIC
+    /// headers, packet protection, acks and other control frames.
+    pub fn overhead(&self) -> f64 {
+        if self.datagram_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.frame_bytes.min(self.datagram_bytes) as f64 / self.datagram_bytes as f64
+    }
+
+    /// What was sent after `earlier`, a previous reading of the same
+    /// connection.
+    pub fn since(&self, earlier: &DatagramEfficiency) -> DatagramEfficiency {
+        DatagramEfficiency {
+            frames: self.frames.saturating_sub(earlier.frames),
+            frame_bytes: self.frame_bytes.saturating_sub(earlier.frame_bytes),
+            datagrams: self.datagrams.saturating_sub(earlier.datagrams),
+            datagram_bytes: self.datagram_bytes.saturating_sub(earlier.datagram_bytes),
+        }
+    }
+}
+
+impl fmt::Display for DatagramEfficiency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames in {} datagrams, {:.2} per datagram, {:.0}% full, {:.0}% overhead",
+            self.frames,
+            self.datagrams,
+            self.frames_per_datagram(),
+            self.fill_ratio() * 100.0,
+            self.overhead() * 100.0
+        )
+    }
+}
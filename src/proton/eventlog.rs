@@ -0,0 +1,677 @@
+//! Write-ahead log of received events. The server appends each event it
+//! handled, payload included, before acknowledging it. A reconnecting
+//! client says in its HELLO which event it sent last; if the log ends
+//! before that event, e.g. because the server restarted before syncing its
+//! latest appends, the HELLO reply asks the client to send the rest again
+//! from the events it kept.
+
+use crate::proton::dedupe::FsyncPolicy;
+use crate::proton::journal::{decode_record, encode_record, Journal, Record};
+use crate::proton::{Frame, ProtonError};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fmt::Write;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the server compacts an event log with a retention limit.
+pub const COMPACT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How much of the event log compaction keeps. Past any limit, the oldest
+/// events are dropped; without limits the log grows without bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Drop events received longer ago than this
+    pub max_age: Option<Duration>,
+    /// Keep the log within this many bytes
+    pub max_bytes: Option<u64>,
+    /// Keep at most this many events across all clients
+    pub max_events: Option<usize>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_bytes.is_none() && self.max_events.is_none()
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unlimited() {
+            return write!(f, "unlimited");
+        }
+        let mut limits = Vec::new();
+        if let Some(age) = self.max_age {
+            limits.push(format!("max_age={}s", age.as_secs()));
+        }
+        if let Some(bytes) = self.max_bytes {
+            limits.push(format!("max_bytes={}", bytes));
+        }
+        if let Some(events) = self.max_events {
+            limits.push(format!("max_events={}", events));
+        }
+        write!(f, "{}", limits.join(" "))
+    }
+}
+
+/// What a compaction dropped from the front of the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Compaction {
+    pub events: usize,
+    pub bytes: u64,
+}
+
+impl fmt::Display for Compaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dropped {} events, {} bytes", self.events, self.bytes)
+    }
+}
+
+/// The size of the log, and what compaction has dropped from it since it
+/// was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventLogStats {
+    pub events: usize,
+    pub bytes: u64,
+    pub clients: usize,
+    /// Age of the oldest event kept
+    pub oldest: Option<Duration>,
+    pub compactions: u64,
+    pub dropped: Compaction,
+}
+
+impl fmt::Display for EventLogStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "events={} bytes={} clients={}",
+            self.events, self.bytes, self.clients
+        )?;
+        if let Some(oldest) = self.oldest {
+            write!(f, " oldest_age={}s", oldest.as_secs())?;
+        }
+        write!(
+            f,
+            " compactions={} dropped_events={} dropped_bytes={}",
+            self.compactions, self.dropped.events, self.dropped.bytes
+        )
+    }
+}
+
+// A record: an event and when it was received, in milliseconds since the
+// Unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Logged {
+    event: Frame,
+    received_at: u64,
+}
+
+impl Record for Logged {
+    fn encode(&self, out: &mut Vec<u8>) {
+        // Payloads are bounded by the frame limit
+        out.extend_from_slice(&self.event.id.to_le_bytes());
+        out.extend_from_slice(&self.received_at.to_le_bytes());
+        out.extend_from_slice(&(self.event.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.event.payload);
+    }
+
+    fn decode(buf: &[u8]) -> Option<(Self, &[u8])> {
+        let event_id = u32::from_le_bytes(buf.get(..4)?.try_into().unwrap());
+        let received_at = u64::from_le_bytes(buf.get(4..12)?.try_into().unwrap());
+        let payload_len = u32::from_le_bytes(buf.get(12..16)?.try_into().unwrap()) as usize;
+        let payload = buf.get(16..16 + payload_len)?.to_vec();
+        let logged = Logged {
+            event: Frame::with_payload(event_id, payload),
+            received_at,
+        };
+        Some((logged, &buf[16 + payload_len..]))
+    }
+}
+
+// Where one logged event's record is. Offsets count from the start of the
+// log as first written, so they stay put when compaction drops its front.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    event_id: u32,
+    offset: u64,
+    len: usize,
+}
+
+// The events logged for one client, in the order they were received
+#[derive(Debug, Default)]
+struct ClientLog {
+    // Highest event id logged, kept once its events are dropped
+    last: u32,
+    spans: VecDeque<Span>,
+}
+
+impl ClientLog {
+    fn add(&mut self, span: Span) {
+        self.last = self.last.max(span.event_id);
+        self.spans.push_back(span);
+    }
+}
+
+// One record of any client, in log order
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    received_at: u64,
+}
+
+#[derive(Debug)]
+struct Log {
+    journal: Journal,
+    // Replays read records through this, outside the lock
+    reader: Arc<File>,
+    // Offset of the first byte still in the file
+    base: u64,
+    clients: HashMap<String, ClientLog>,
+    entries: VecDeque<Entry>,
+    compactions: u64,
+    dropped: Compaction,
+}
+
+impl Log {
+    fn add(&mut self, client: &str, event_id: u32, received_at: u64, offset: u64, len: usize) {
+        let span = Span {
+            event_id,
+            offset,
+            len,
+        };
+        match self.clients.get_mut(client) {
+            Some(logged) => logged.add(span),
+            None => {
+                let mut logged = ClientLog::default();
+                logged.add(span);
+                self.clients.insert(client.to_string(), logged);
+            }
+        }
+        self.entries.push_back(Entry {
+            offset,
+            received_at,
+        });
+    }
+
+    // How many of the oldest events `retention` drops at `now`
+    fn expired(&self, retention: &Retention, now: u64) -> usize {
+        let end = self.base + self.journal.len();
+        let max_age = retention.max_age.map(|age| age.as_millis() as u64);
+        let over = |dropped: usize, entry: &Entry| {
+            max_age.is_some_and(|age| now.saturating_sub(entry.received_at) > age)
+                || retention
+                    .max_events
+                    .is_some_and(|max| self.entries.len() - dropped > max)
+                || retention
+                    .max_bytes
+                    .is_some_and(|max| end - entry.offset > max)
+        };
+        self.entries
+            .iter()
+            .enumerate()
+            .take_while(|(dropped, entry)| over(*dropped, entry))
+            .count()
+    }
+}
+
+/// Events received from clients that send a client id, shared by all of a
+/// server's connections.
+///
+/// Each record is a little-endian u16 key length, the client key, the u32
+/// event id, the u64 time it was received in milliseconds since the Unix
+/// epoch, then the u32 payload length and the payload. Records are only
+/// appended, and compaction drops the oldest past the [`Retention`] limits.
+/// Where each client's records are is kept in memory, so a replay reads
+/// only those.
+#[derive(Debug)]
+pub struct EventLog {
+    fsync: FsyncPolicy,
+    retention: Retention,
+    log: Mutex<Log>,
+    // Held for the whole of a compaction, so only one runs at a time
+    compacting: Mutex<()>,
+}
+
+impl EventLog {
+    /// Opens or creates the log at `path`, noting where each client's
+    /// events are in it.
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> Result<Self, ProtonError> {
+        let mut loaded = Vec::new();
+        // A partial record left by a crash mid-append was never acked
+        let journal = Journal::open(path, fsync, |key, logged: Logged, offset, len| {
+            loaded.push((
+                key.to_string(),
+                logged.event.id,
+                logged.received_at,
+                offset,
+                len,
+            ));
+        })?;
+        let mut log = Log {
+            reader: Arc::new(File::open(path)?),
+            journal,
+            base: 0,
+            clients: HashMap::new(),
+            entries: VecDeque::with_capacity(loaded.len()),
+            compactions: 0,
+            dropped: Compaction::default(),
+        };
+        for (client, event_id, received_at, offset, len) in loaded {
+            log.add(&client, event_id, received_at, offset, len);
+        }
+        Ok(Self {
+            fsync,
+            retention: Retention::default(),
+            log: Mutex::new(log),
+            compacting: Mutex::new(()),
+        })
+    }
+
+    /// Limits what compaction keeps to `retention`.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn fsync(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Events logged across all clients.
+    pub fn len(&self) -> usize {
+        self.log.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> EventLogStats {
+        let log = self.log.lock().unwrap();
+        EventLogStats {
+            events: log.entries.len(),
+            bytes: log.journal.len(),
+            clients: log.clients.values().filter(|c| !c.spans.is_empty()).count(),
+            oldest: log
+                .entries
+                .front()
+                .map(|entry| Duration::from_millis(now_millis().saturating_sub(entry.received_at))),
+            compactions: log.compactions,
+            dropped: log.dropped,
+        }
+    }
+
+    /// Highest event id logged for `client`.
+    pub fn last_event_id(&self, client: &str) -> Option<u32> {
+        Some(self.log.lock().unwrap().clients.get(client)?.last)
+    }
+
+    /// Where a client that last sent `last_event_id` has to resend from to
+    /// fill the end of its log, or None if nothing is missing.
+    pub fn missing_from(&self, client: &str, last_event_id: u32) -> Option<u32> {
+        match self.last_event_id(client) {
+            Some(logged) if logged >= last_event_id => None,
+            Some(logged) => Some(logged + 1),
+            None => Some(0),
+        }
+    }
+
+    /// Appends `event` of `client`, returning once it is as durable as the
+    /// fsync policy makes it.
+    pub fn append(&self, client: &str, event: &Frame) -> Result<(), ProtonError> {
+        let logged = Logged {
+            event: event.clone(),
+            received_at: now_millis(),
+        };
+        let mut record = Vec::with_capacity(18 + client.len() + event.payload.len());
+        encode_record(client, &logged, &mut record);
+        let mut log = self.log.lock().unwrap();
+        let offset = log.base + log.journal.append(&record)?;
+        log.add(client, event.id, logged.received_at, offset, record.len());
+        Ok(())
+    }
+
+    /// Events of `client` logged with an id of at least `event_id`, in the
+    /// order they were received.
+    pub fn replay_from(&self, client: &str, event_id: u32) -> Result<Vec<Frame>, ProtonError> {
+        let (reader, base, spans) = {
+            let log = self.log.lock().unwrap();
+            let Some(logged) = log.clients.get(client) else {
+                return Ok(Vec::new());
+            };
+            let spans: Vec<Span> = logged
+                .spans
+                .iter()
+                .filter(|span| span.event_id >= event_id)
+                .copied()
+                .collect();
+            (Arc::clone(&log.reader), log.base, spans)
+        };
+        // Appends only go after the records found, and compaction replaces
+        // the file rather than changing it, so they read the same without
+        // the lock
+        let mut events = Vec::with_capacity(spans.len());
+        let mut record = Vec::new();
+        for span in spans {
+            record.resize(span.len, 0);
+            reader.read_exact_at(&mut record, span.offset - base)?;
+            let (_, logged, _) = decode_record::<Logged>(&record).ok_or_else(|| {
+                ProtonError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("corrupt event log record at offset {}", span.offset),
+                ))
+            })?;
+            events.push(logged.event);
+        }
+        Ok(events)
+    }
+
+    /// Drops the oldest events past the retention limits. The events kept
+    /// are copied to a new file while appends go on, which then replaces
+    /// the log.
+    pub fn compact(&self) -> Result<Compaction, ProtonError> {
+        let _compacting = self.compacting.lock().unwrap();
+        let (dropped, cut, mut trim) = {
+            let mut log = self.log.lock().unwrap();
+            let dropped = log.expired(&self.retention, now_millis());
+            if dropped == 0 {
+                log.compactions += 1;
+                return Ok(Compaction::default());
+            }
+            let cut = match log.entries.get(dropped) {
+                Some(entry) => entry.offset,
+                None => log.base + log.journal.len(),
+            };
+            let trim = log.journal.trim(cut - log.base);
+            (dropped, cut, trim)
+        };
+        trim.copy()?;
+
+        let mut log = self.log.lock().unwrap();
+        let compaction = Compaction {
+            events: dropped,
+            bytes: cut - log.base,
+        };
+        log.journal.finish_trim(trim)?;
+        log.reader = Arc::new(File::open(log.journal.path())?);
+        log.base = cut;
+        log.entries.drain(..dropped);
+        for logged in log.clients.values_mut() {
+            while logged.spans.front().is_some_and(|span| span.offset < cut) {
+                logged.spans.pop_front();
+            }
+        }
+        log.compactions += 1;
+        log.dropped.events += compaction.events;
+        log.dropped.bytes += compaction.bytes;
+        Ok(compaction)
+    }
+
+    /// Syncs appends not yet synced, for the `Interval` policy's background
+    /// task.
+    pub fn flush(&self) -> Result<(), ProtonError> {
+        self.log.lock().unwrap().journal.flush()
+    }
+
+    /// The log's size and compactions in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let stats = self.stats();
+        let mut out = String::new();
+        for (name, kind, help, value) in [
+            (
+                "proton_event_log_events",
+                "gauge",
+                "Events held in the event log",
+                stats.events as u64,
+            ),
+            (
+                "proton_event_log_bytes",
+                "gauge",
+                "Size of the event log file",
+                stats.bytes,
+            ),
+            (
+                "proton_event_log_compactions_total",
+                "counter",
+                "Compactions of the event log",
+                stats.compactions,
+            ),
+            (
+                "proton_event_log_dropped_events_total",
+                "counter",
+                "Events dropped from the event log by retention",
+                stats.dropped.events as u64,
+            ),
+            (
+                "proton_event_log_dropped_bytes_total",
+                "counter",
+                "Bytes dropped from the event log by retention",
+                stats.dropped.bytes,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The events a client sent most recently, oldest first, kept to send
+/// again when a server asks for them after a reconnect.
+#[derive(Debug)]
+pub(crate) struct ReplayBuffer {
+    capacity: usize,
+    events: VecDeque<Frame>,
+    // Highest id of the events forgotten to make room
+    forgotten: Option<u32>,
+}
+
+impl ReplayBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+            forgotten: None,
+        }
+    }
+
+    // Keeps an event the server has received, forgetting the oldest once
+    // full
+    pub(crate) fn sent(&mut self, event: &Frame) {
+        if self.events.len() == self.capacity {
+            if let Some(oldest) = self.events.pop_front() {
+                self.forgotten = Some(self.forgotten.map_or(oldest.id, |id| id.max(oldest.id)));
+            }
+        }
+        self.events.push_back(event.clone());
+    }
+
+    pub(crate) fn last_event_id(&self) -> Option<u32> {
+        self.events.iter().map(|event| event.id).max()
+    }
+
+    // The kept events from `event_id` on, and whether the buffer reaches
+    // back far enough to hold all of them
+    pub(crate) fn from(&self, event_id: u32) -> (Vec<Frame>, bool) {
+        let complete = self.forgotten.is_none_or(|forgotten| forgotten < event_id);
+        let events = self
+            .events
+            .iter()
+            .filter(|event| event.id >= event_id)
+            .cloned()
+            .collect();
+        (events, complete)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A log path of its own for each test, with no log there yet
+    fn scratch(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("proton-eventlog-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn replays_one_clients_events_from_an_id() {
+        let path = scratch("replay");
+        let log = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        for id in 1..=3 {
+            log.append("acme/a", &Frame::with_payload(id, vec![id as u8; 3]))
+                .unwrap();
+            log.append("acme/b", &Frame::new(id + 10)).unwrap();
+        }
+        assert_eq!(log.len(), 6);
+        let replayed = log.replay_from("acme/a", 2).unwrap();
+        assert_eq!(
+            replayed,
+            [
+                Frame::with_payload(2, vec![2; 3]),
+                Frame::with_payload(3, vec![3; 3])
+            ]
+        );
+        assert!(log.replay_from("acme/c", 0).unwrap().is_empty());
+        drop(log);
+
+        // The index is rebuilt from the log
+        let log = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(log.replay_from("acme/a", 2).unwrap(), replayed);
+        assert_eq!(log.replay_from("acme/b", 0).unwrap().len(), 3);
+        assert_eq!(log.last_event_id("acme/b"), Some(13));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_events_are_asked_for_from_the_next_id() {
+        let path = scratch("missing");
+        let log = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(log.missing_from("acme/a", 5), Some(0));
+        log.append("acme/a", &Frame::new(3)).unwrap();
+        assert_eq!(log.missing_from("acme/a", 5), Some(4));
+        assert_eq!(log.missing_from("acme/a", 3), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn partial_record_is_dropped_on_reopen() {
+        let path = scratch("partial");
+        let log = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+        log.append("acme/a", &Frame::with_payload(1, b"one".to_vec()))
+            .unwrap();
+        drop(log);
+        let whole = std::fs::metadata(&path).unwrap().len();
+        let mut record = Vec::new();
+        let logged = Logged {
+            event: Frame::with_payload(2, b"two".to_vec()),
+            received_at: now_millis(),
+        };
+        encode_record("acme/a", &logged, &mut record);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&record[..record.len() - 1]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let log = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), whole);
+        // Appends after the cut are read back whole
+        log.append("acme/a", &Frame::new(2)).unwrap();
+        assert_eq!(
+            log.replay_from("acme/a", 0).unwrap(),
+            [Frame::with_payload(1, b"one".to_vec()), Frame::new(2)]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction_drops_the_oldest_events() {
+        let path = scratch("compact");
+        let retention = Retention {
+            max_events: Some(3),
+            ..Retention::default()
+        };
+        let log = EventLog::open(&path, FsyncPolicy::Never)
+            .unwrap()
+            .with_retention(retention);
+        for id in 1..=3 {
+            log.append("acme/a", &Frame::with_payload(id, vec![id as u8; 4]))
+                .unwrap();
+            if id < 3 {
+                log.append("acme/b", &Frame::new(id)).unwrap();
+            }
+        }
+        let before = log.stats().bytes;
+        let compaction = log.compact().unwrap();
+        assert_eq!(compaction.events, 2);
+        let stats = log.stats();
+        assert_eq!(stats.events, 3);
+        assert_eq!(stats.bytes, before - compaction.bytes);
+        assert_eq!(stats.dropped, compaction);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), stats.bytes);
+        assert_eq!(
+            log.replay_from("acme/a", 0).unwrap(),
+            [
+                Frame::with_payload(2, vec![2; 4]),
+                Frame::with_payload(3, vec![3; 4])
+            ]
+        );
+        assert_eq!(log.replay_from("acme/b", 0).unwrap(), [Frame::new(2)]);
+        assert_eq!(log.last_event_id("acme/a"), Some(3));
+        // Appends after a compaction land after the events kept
+        log.append("acme/b", &Frame::new(3)).unwrap();
+        assert_eq!(log.compact().unwrap().events, 1);
+        drop(log);
+
+        let log = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            log.replay_from("acme/b", 0).unwrap(),
+            [Frame::new(2), Frame::new(3)]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn retention_limits_age_and_size() {
+        let path = scratch("retention");
+        let log = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        for id in 1..=4 {
+            log.append("acme/a", &Frame::new(id)).unwrap();
+        }
+        let record_len = log.stats().bytes / 4;
+        let log = log.log.lock().unwrap();
+        let received_at = log.entries[0].received_at;
+        let by_age = Retention {
+            max_age: Some(Duration::from_secs(60)),
+            ..Retention::default()
+        };
+        assert_eq!(log.expired(&by_age, received_at + 60_000), 0);
+        assert_eq!(log.expired(&by_age, received_at + 60_001), 4);
+        let by_size = Retention {
+            max_bytes: Some(2 * record_len + 1),
+            ..Retention::default()
+        };
+        assert_eq!(log.expired(&by_size, received_at), 2);
+        assert_eq!(log.expired(&Retention::default(), u64::MAX), 0);
+        drop(log);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use crate::proton::settings::ClientSettings;
+use crate::proton::{Framing, ProtonError};
+use std::fmt;
+use std::str::FromStr;
+
+// FNV-1a, chosen because its output is fixed by its definition: a client
+// must land in the same bucket across processes, versions and platforms
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash = FNV_OFFSET;
+    for part in parts {
+        for &byte in *part {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+fn invalid_input(message: String) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        message,
+    ))
+}
+
+/// One arm of an experiment: the connection options its clients use.
+/// Options left unset keep whatever the client was configured with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    /// Share of clients in this variant, relative to the other variants
+    pub weight: u32,
+    pub framing: Option<Framing>,
+    /// Settings that take precedence over the client's own
+    pub settings: ClientSettings,
+}
+
+impl Variant {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            weight: 1,
+            framing: None,
+            settings: ClientSettings::default(),
+        }
+    }
+
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = Some(framing);
+        self
+    }
+
+    pub fn with_settings(mut self, settings: ClientSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+}
+
+/// Splits clients between variants by a hash of the experiment name and the
+/// client id, so each client keeps its variant across connections and
+/// restarts while different experiments bucket independently.
+///
+/// Parsed from `name=variant[:framing][*weight],...`, e.g.
+/// `codec=control,lp:length-prefixed*3`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Experiment {
+    pub name: String,
+    variants: Vec<Variant>,
+}
+
+impl Experiment {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            variants: Vec::new(),
+        }
+    }
+
+    pub fn with_variant(mut self, variant: Variant) -> Self {
+        self.variants.push(variant);
+        self
+    }
+
+    pub fn variants(&self) -> &[Variant] {
+        &self.variants
+    }
+
+    /// The variant `client_id` falls in, or `None` if no variant has any
+    /// weight.
+    pub fn assign(&self, client_id: &str) -> Option<&Variant> {
+        let total: u64 = self.variants.iter().map(|v| u64::from(v.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut bucket = fnv1a(&[self.name.as_bytes(), b"/", client_id.as_bytes()]) % total;
+        for variant in &self.variants {
+            let weight = u64::from(variant.weight);
+            if bucket < weight {
+                return Some(variant);
+            }
+            bucket -= weight;
+        }
+        None
+    }
+}
+
+impl FromStr for Experiment {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, variants) = s.split_once('=').ok_or_else(|| {
+            invalid_input(format!(
+                "invalid experiment '{}', expected name=variant[:framing][*weight],...",
+                s
+            ))
+        })?;
+        if name.is_empty() {
+            return Err(invalid_input(format!("experiment '{}' has no name", s)));
+        }
+        let mut experiment = Experiment::new(name);
+        for spec in variants.split(',') {
+            let (spec, weight) = match spec.split_once('*') {
+                Some((spec, weight)) => (
+                    spec,
+                    weight.parse().map_err(|_| {
+                        invalid_input(format!("invalid weight '{}' in experiment", weight))
+                    })?,
+                ),
+                None => (spec, 1),
+            };
+            let (variant_name, framing) = match spec.split_once(':') {
+                Some((variant_name, framing)) => (variant_name, Some(framing.parse()?)),
+                None => (spec, None),
+            };
+            if variant_name.is_empty() {
+                return Err(invalid_input(format!(
+                    "experiment '{}' has a variant without a name",
+                    s
+                )));
+            }
+            let mut variant = Variant::new(variant_name).with_weight(weight);
+            variant.framing = framing;
+            experiment = experiment.with_variant(variant);
+        }
+        Ok(experiment)
+    }
+}
+
+impl fmt::Display for Experiment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=", self.name)?;
+        for (i, variant) in self.variants.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(&variant.name)?;
+            if let Some(framing) = variant.framing {
+                write!(f, ":{}", framing)?;
+            }
+            if variant.weight != 1 {
+                write!(f, "*{}", variant.weight)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+use crate::proton::commit::ABORT_REFUSED;
+use crate::proton::wire::decode_commit;
+use crate::proton::{
+    ProtonError, QUOTA_EXCEEDED, STREAM_ACTION, STREAM_ACTION_PUSH, STREAM_AUTH, STREAM_CONTROL,
+    STREAM_EVENT, STREAM_HEARTBEAT, STREAM_PAYLOAD, STREAM_STATE_COMMIT, STREAM_TIMEOUT,
+};
+use quinn::RecvStream;
+use std::fmt::{self, Write};
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::time::timeout;
+
+pub use crate::proton::wire::FLAG_HEADERS;
+
+/// Header key used for W3C trace context propagation.
+pub const TRACEPARENT: &str = "traceparent";
+
+const MAX_HEADERS: usize = 32;
+const MAX_KEY_LEN: usize = u8::MAX as usize;
+const MAX_VALUE_LEN: usize = 1024;
+
+/// Key-value pairs carried after a request frame.
+///
+/// Wire format: a u8 count, then per header a u8 key length, the key, a
+/// little-endian u16 value length and the value, all UTF-8.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key`, replacing any existing value. Keys are case-insensitive.
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), ProtonError> {
+        let key = key.to_ascii_lowercase();
+        if key.is_empty() || key.len() > MAX_KEY_LEN || value.len() > MAX_VALUE_LEN {
+            return Err(invalid_headers("header key or value too long"));
+        }
+        if let Some((_, v)) = self.0.iter_mut().find(|(k, _)| *k == key) {
+            *v = value.to_string();
+        } else if self.0.len() < MAX_HEADERS {
+            self.0.push((key, value.to_string()));
+        } else {
+            return Err(invalid_headers("too many headers"));
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let key = key.to_ascii_lowercase();
+        self.0.retain(|(k, _)| *k != key);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        // Stored keys are lowercase
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The W3C trace context, if a valid `traceparent` header is present.
+    pub fn traceparent(&self) -> Option<TraceParent> {
+        self.get(TRACEPARENT).and_then(|v| v.parse().ok())
+    }
+
+    pub fn set_traceparent(&mut self, trace: &TraceParent) {
+        // Always within the length limits
+        let _ = self.insert(TRACEPARENT, &trace.to_string());
+    }
+
+    /// Size of the encoded headers section.
+    pub fn encoded_len(&self) -> usize {
+        1 + self
+            .0
+            .iter()
+            .map(|(k, v)| 3 + k.len() + v.len())
+            .sum::<usize>()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut out);
+        out
+    }
+
+    /// Appends the encoded section to `out`, e.g. a frame buffer that is
+    /// reused or lives on the stack.
+    pub fn encode_into(&self, out: &mut impl Extend<u8>) {
+        out.extend([self.0.len() as u8]);
+        for (k, v) in &self.0 {
+            out.extend([k.len() as u8]);
+            out.extend(k.bytes());
+            out.extend((v.len() as u16).to_le_bytes());
+            out.extend(v.bytes());
+        }
+    }
+
+    /// Decodes a headers section at the start of `buf`, returning it and the
+    /// number of bytes it took up.
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), ProtonError> {
+        let truncated = || invalid_headers("truncated headers section");
+        let mut pos = 0;
+        let mut take = |len: usize| {
+            let field = buf.get(pos..pos + len).ok_or_else(truncated)?;
+            pos += len;
+            Ok::<_, ProtonError>(field)
+        };
+
+        let count = take(1)?[0];
+        if count as usize > MAX_HEADERS {
+            return Err(invalid_headers("too many headers"));
+        }
+        let mut headers = Headers::new();
+        for _ in 0..count {
+            let key_len = take(1)?[0] as usize;
+            let key = take(key_len)?;
+            let value_len = u16::from_le_bytes(take(2)?.try_into().unwrap()) as usize;
+            if value_len > MAX_VALUE_LEN {
+                return Err(invalid_headers("header value too long"));
+            }
+            let value = take(value_len)?;
+
+            let key = std::str::from_utf8(key).map_err(|_| invalid_headers("key is not UTF-8"))?;
+            let value =
+                std::str::from_utf8(value).map_err(|_| invalid_headers("value is not UTF-8"))?;
+            headers.insert(key, value)?;
+        }
+        Ok((headers, pos))
+    }
+
+    /// Reads a headers section from `recv`.
+    pub async fn read_from(recv: &mut RecvStream) -> Result<Self, ProtonError> {
+        let mut count = [0u8; 1];
+        timeout(STREAM_TIMEOUT, recv.read_exact(&mut count)).await??;
+        if count[0] as usize > MAX_HEADERS {
+            return Err(invalid_headers("too many headers"));
+        }
+
+        let mut headers = Headers::new();
+        for _ in 0..count[0] {
+            let mut key_len = [0u8; 1];
+            timeout(STREAM_TIMEOUT, recv.read_exact(&mut key_len)).await??;
+            let mut key = vec![0u8; key_len[0] as usize];
+            timeout(STREAM_TIMEOUT, recv.read_exact(&mut key)).await??;
+
+            let mut value_len = [0u8; 2];
+            timeout(STREAM_TIMEOUT, recv.read_exact(&mut value_len)).await??;
+            let value_len = u16::from_le_bytes(value_len) as usize;
+            if value_len > MAX_VALUE_LEN {
+                return Err(invalid_headers("header value too long"));
+            }
+            let mut value = vec![0u8; value_len];
+            timeout(STREAM_TIMEOUT, recv.read_exact(&mut value)).await??;
+
+            let key = String::from_utf8(key).map_err(|_| invalid_headers("key is not UTF-8"))?;
+            let value =
+                String::from_utf8(value).map_err(|_| invalid_headers("value is not UTF-8"))?;
+            headers.insert(&key, &value)?;
+        }
+        Ok(headers)
+    }
+}
+
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (k, v)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_headers(msg: &str) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("frame headers: {}", msg),
+    ))
+}
+
+/// W3C trace context `traceparent` value (version 00).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceParent {
+    /// Starts a new sampled trace with random ids.
+    pub fn generate() -> Self {
+        use ring::rand::{SecureRandom, SystemRandom};
+        let rng = SystemRandom::new();
+        let mut trace_id = [0u8; 16];
+        let mut parent_id = [0u8; 8];
+        let _ = rng.fill(&mut trace_id);
+        let _ = rng.fill(&mut parent_id);
+        Self {
+            trace_id,
+            parent_id,
+            flags: 0x01,
+        }
+    }
+
+    /// Same trace, new span id for the next hop.
+    pub fn child(&self) -> Self {
+        let mut child = Self::generate();
+        child.trace_id = self.trace_id;
+        child.flags = self.flags;
+        child
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-")?;
+        self.trace_id
+            .iter()
+            .try_for_each(|b| write!(f, "{:02x}", b))?;
+        write!(f, "-")?;
+        self.parent_id
+            .iter()
+            .try_for_each(|b| write!(f, "{:02x}", b))?;
+        write!(f, "-{:02x}", self.flags)
+    }
+}
+
+impl FromStr for TraceParent {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || invalid_headers("malformed traceparent");
+        let parts: Vec<&str> = s.trim().split('-').collect();
+        if parts.len() != 4 || parts[0] != "00" {
+            return Err(invalid());
+        }
+        let mut trace_id = [0u8; 16];
+        let mut parent_id = [0u8; 8];
+        let mut flags = [0u8; 1];
+        decode_hex(parts[1], &mut trace_id).ok_or_else(invalid)?;
+        decode_hex(parts[2], &mut parent_id).ok_or_else(invalid)?;
+        decode_hex(parts[3], &mut flags).ok_or_else(invalid)?;
+        // All-zero ids are invalid per the spec
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return Err(invalid());
+        }
+        Ok(Self {
+            trace_id,
+            parent_id,
+            flags: flags[0],
+        })
+    }
+}
+
+fn decode_hex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(())
+}
+
+/// Observes request frames as the server receives them, e.g. to continue a
+/// trace from the `traceparent` header.
+pub trait FrameInterceptor: Send + Sync {
+    /// Called with the stream discriminator, the request id and its headers.
+    fn on_frame(&self, stream: u8, id: u32, headers: &Headers);
+}
+
+/// Which way a frame seen by a [`FrameInspector`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Observes the raw frames a client sends and receives on its event, state
+/// commit and action streams, e.g. to debug codec mismatches with peers
+/// written in other languages.
+pub trait FrameInspector: Send + Sync {
+    /// Called with the stream discriminator, without flags, and the frame as
+    /// it went over the wire: a request id and any headers section when sent,
+    /// a 4-byte response when received. With length-prefixed or typed
+    /// framing only the id is kept, so frames read the same.
+    fn on_frame(&self, direction: Direction, stream: u8, frame: &[u8]);
+}
+
+/// Several inspectors, each shown every frame in turn.
+impl FrameInspector for Vec<std::sync::Arc<dyn FrameInspector>> {
+    fn on_frame(&self, direction: Direction, stream: u8, frame: &[u8]) {
+        for inspector in self {
+            inspector.on_frame(direction, stream, frame);
+        }
+    }
+}
+
+/// Writes a hexdump and a decoded view of every frame to a sink, e.g.
+/// stdout for the REPL. Frames that cannot be written are dropped.
+pub struct FrameDump {
+    out: Mutex<Box<dyn io::Write + Send>>,
+}
+
+impl FrameDump {
+    pub fn new(out: Box<dyn io::Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+}
+
+impl FrameInspector for FrameDump {
+    fn on_frame(&self, direction: Direction, stream: u8, frame: &[u8]) {
+        let arrow = match direction {
+            Direction::Sent => ">>",
+            Direction::Received => "<<",
+        };
+        let mut out = self.out.lock().unwrap();
+        let _ = write!(
+            out,
+            "{} {} {} bytes: {}\n{}",
+            arrow,
+            stream_name(stream),
+            frame.len(),
+            describe_frame(direction, stream, frame),
+            hexdump(frame)
+        );
+        let _ = out.flush();
+    }
+}
+
+pub(crate) fn stream_name(stream: u8) -> &'static str {
+    match stream {
+        STREAM_EVENT => "event",
+        STREAM_STATE_COMMIT => "state commit",
+        STREAM_ACTION => "action",
+        STREAM_AUTH => "auth",
+        STREAM_CONTROL => "control",
+        STREAM_PAYLOAD => "payload",
+        STREAM_HEARTBEAT => "heartbeat",
+        STREAM_ACTION_PUSH => "action push",
+        _ => "unknown stream",
+    }
+}
+
+/// Decodes a request or response frame as seen by a [`FrameInspector`].
+pub fn describe_frame(direction: Direction, stream: u8, frame: &[u8]) -> String {
+    let Some(id) = frame
+        .get(..4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    else {
+        return "truncated frame".to_string();
+    };
+    let mut out = match (direction, stream, id) {
+        (Direction::Sent, STREAM_EVENT, _) => format!("event {}", id),
+        (Direction::Sent, STREAM_STATE_COMMIT, _) if decode_commit(id).1 => {
+            format!("abort of state commit {}", decode_commit(id).0)
+        }
+        (Direction::Sent, STREAM_STATE_COMMIT, _) => format!("state commit {}", id),
+        (Direction::Sent, STREAM_ACTION, _) => format!("read action after offset {}", id),
+        (Direction::Received, _, QUOTA_EXCEEDED) => "quota exceeded".to_string(),
+        (Direction::Received, STREAM_STATE_COMMIT, ABORT_REFUSED) => "abort refused".to_string(),
+        (Direction::Received, _, _) => format!("response {}", id),
+        (Direction::Sent, _, _) => format!("request {}", id),
+    };
+    if frame.len() > 4 {
+        match Headers::decode(&frame[4..]) {
+            Ok((headers, _)) if headers.is_empty() => out.push_str(", no headers"),
+            Ok((headers, _)) => {
+                let _ = write!(out, ", headers {}", headers);
+            }
+            Err(e) => {
+                let _ = write!(out, ", {}", e);
+            }
+        }
+    }
+    out
+}
+
+/// Formats `bytes` 16 to a line: offset, hex bytes and printable ASCII.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "  {:04x}  ", i * 16);
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+            if j == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // A sink the test can read back after handing it to a FrameDump
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frame_dump_writes_to_its_sink() {
+        let sink = Shared::default();
+        let dump = FrameDump::new(Box::new(sink.clone()));
+        dump.on_frame(
+            Direction::Received,
+            STREAM_STATE_COMMIT,
+            &9u32.to_le_bytes(),
+        );
+        let written = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            written,
+            concat!(
+                "<< state commit 4 bytes: response 9\n",
+                "  0000  09 00 00 00                                       |....|\n",
+            )
+        );
+    }
+}
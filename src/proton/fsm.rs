@@ -0,0 +1,140 @@
+//! Protocol state of each stream of a connection, so a stuck request can be
+//! diagnosed by asking where each side is rather than by adding logs: a
+//! commit the client is awaiting a response to while the server's state
+//! commit stream is ready was lost on the way, while one the server is still
+//! handling is held up by its handler.
+
+use quinn::{StreamId, VarInt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where a stream is in the request/response exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamState {
+    /// Accepted, its type not yet known
+    AwaitingDiscriminator,
+    /// Idle, waiting for the next request
+    Ready,
+    /// The server read this request and its handler is running
+    Handling(u32),
+    /// The client wrote this request, the oldest still unanswered
+    AwaitingResponse(u32),
+    /// The connection is closing; no new requests are taken
+    Draining,
+    /// Unusable until reconnected, for this reason
+    Errored(String),
+}
+
+impl fmt::Display for StreamState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamState::AwaitingDiscriminator => write!(f, "awaiting discriminator"),
+            StreamState::Ready => write!(f, "ready"),
+            StreamState::Handling(id) => write!(f, "handling {}", id),
+            StreamState::AwaitingResponse(id) => write!(f, "awaiting response to {}", id),
+            StreamState::Draining => write!(f, "draining"),
+            StreamState::Errored(reason) => write!(f, "errored: {}", reason),
+        }
+    }
+}
+
+/// A stream's state and how long it has been in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamStatus {
+    /// QUIC stream id
+    pub id: u64,
+    /// Stream type, e.g. `event`, or `new` until the discriminator is read
+    pub stream: String,
+    pub state: StreamState,
+    pub since: Duration,
+}
+
+impl fmt::Display for StreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} stream {}: {} for {:.1?}",
+            self.stream, self.id, self.state, self.since
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Tracked {
+    stream: String,
+    state: StreamState,
+    entered: Instant,
+}
+
+/// States of a connection's streams, updated as requests come and go and
+/// shared with whoever inspects them. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct StreamStates {
+    streams: Arc<Mutex<BTreeMap<u64, Tracked>>>,
+}
+
+impl StreamStates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking stream `id` as a stream of type `stream`, or renames
+    /// it once its type is known.
+    pub fn track(&self, id: StreamId, stream: &str, state: StreamState) {
+        self.streams.lock().unwrap().insert(
+            key(id),
+            Tracked {
+                stream: stream.to_string(),
+                state,
+                entered: Instant::now(),
+            },
+        );
+    }
+
+    /// Moves stream `id` to `state`, unless it is already there.
+    pub fn enter(&self, id: StreamId, state: StreamState) {
+        if let Some(tracked) = self.streams.lock().unwrap().get_mut(&key(id)) {
+            if tracked.state != state {
+                tracked.state = state;
+                tracked.entered = Instant::now();
+            }
+        }
+    }
+
+    /// Stops tracking stream `id`, e.g. once it has finished.
+    pub fn forget(&self, id: StreamId) {
+        self.streams.lock().unwrap().remove(&key(id));
+    }
+
+    /// Moves every stream that has not failed to `state`.
+    pub fn enter_all(&self, state: StreamState) {
+        let now = Instant::now();
+        for tracked in self.streams.lock().unwrap().values_mut() {
+            if !matches!(tracked.state, StreamState::Errored(_)) && tracked.state != state {
+                tracked.state = state.clone();
+                tracked.entered = now;
+            }
+        }
+    }
+
+    /// Every tracked stream, in the order they were opened.
+    pub fn list(&self) -> Vec<StreamStatus> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, tracked)| StreamStatus {
+                id,
+                stream: tracked.stream.clone(),
+                state: tracked.state.clone(),
+                since: tracked.entered.elapsed(),
+            })
+            .collect()
+    }
+}
+
+fn key(id: StreamId) -> u64 {
+    VarInt::from(id).into_inner()
+}
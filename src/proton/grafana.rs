@@ -0,0 +1,127 @@
+use crate::proton::json::json_string;
+use crate::proton::metrics::{MetricDef, MetricKind};
+use std::fmt::Write;
+
+// Panels per row and their size in grid units
+const PANELS_PER_ROW: usize = 2;
+const PANEL_WIDTH: usize = 12;
+const PANEL_HEIGHT: usize = 8;
+
+/// A Grafana dashboard charting `metrics` from a Prometheus data source
+/// chosen on import. Labelled metrics are charted per label value, so new
+/// stream types show up without editing the dashboard.
+///
+/// The dashboard shipped in `dashboards/proton.json` is this function's
+/// output for [`crate::proton::metrics::METRICS`].
+pub fn dashboard(metrics: &[MetricDef]) -> String {
+    let panels: Vec<String> = metrics
+        .iter()
+        .enumerate()
+        .map(|(i, metric)| panel(i, metric))
+        .collect();
+    let mut out = String::new();
+    let _ = writeln!(out, "{{");
+    let _ = writeln!(out, "  \"__inputs\": [");
+    let _ = writeln!(out, "    {{");
+    let _ = writeln!(out, "      \"name\": \"DS_PROMETHEUS\",");
+    let _ = writeln!(out, "      \"label\": \"Prometheus\",");
+    let _ = writeln!(out, "      \"type\": \"datasource\",");
+    let _ = writeln!(out, "      \"pluginId\": \"prometheus\"");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "  ],");
+    let _ = writeln!(out, "  \"title\": \"Proton\",");
+    let _ = writeln!(out, "  \"uid\": \"proton\",");
+    let _ = writeln!(out, "  \"schemaVersion\": 39,");
+    let _ = writeln!(out, "  \"refresh\": \"10s\",");
+    let _ = writeln!(
+        out,
+        "  \"time\": {{ \"from\": \"now-1h\", \"to\": \"now\" }},"
+    );
+    let _ = writeln!(out, "  \"panels\": [");
+    let _ = writeln!(out, "{}", panels.join(",\n"));
+    let _ = writeln!(out, "  ]");
+    let _ = writeln!(out, "}}");
+    out
+}
+
+fn panel(index: usize, metric: &MetricDef) -> String {
+    let x = (index % PANELS_PER_ROW) * PANEL_WIDTH;
+    let y = (index / PANELS_PER_ROW) * PANEL_HEIGHT;
+    let targets: Vec<String> = queries(metric)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (expr, legend))| {
+            format!(
+                "        {{ \"refId\": \"{}\", \"expr\": {}, \"legendFormat\": {} }}",
+                (b'A' + i as u8) as char,
+                json_string(&expr),
+                json_string(&legend)
+            )
+        })
+        .collect();
+    let unit = match metric.kind {
+        MetricKind::Counter => "ops",
+        MetricKind::Gauge => "short",
+        MetricKind::Histogram => "s",
+    };
+    let mut out = String::new();
+    let _ = writeln!(out, "    {{");
+    let _ = writeln!(out, "      \"id\": {},", index + 1);
+    let _ = writeln!(out, "      \"type\": \"timeseries\",");
+    let _ = writeln!(out, "      \"title\": {},", json_string(metric.help));
+    let _ = writeln!(
+        out,
+        "      \"datasource\": {{ \"type\": \"prometheus\", \"uid\": \"${{DS_PROMETHEUS}}\" }},"
+    );
+    let _ = writeln!(
+        out,
+        "      \"gridPos\": {{ \"x\": {}, \"y\": {}, \"w\": {}, \"h\": {} }},",
+        x, y, PANEL_WIDTH, PANEL_HEIGHT
+    );
+    let _ = writeln!(
+        out,
+        "      \"fieldConfig\": {{ \"defaults\": {{ \"unit\": \"{}\" }}, \"overrides\": [] }},",
+        unit
+    );
+    let _ = writeln!(out, "      \"targets\": [");
+    let _ = writeln!(out, "{}", targets.join(",\n"));
+    let _ = writeln!(out, "      ]");
+    let _ = write!(out, "    }}");
+    out
+}
+
+// PromQL expressions charting `metric`, with their legends
+fn queries(metric: &MetricDef) -> Vec<(String, String)> {
+    let name = metric.name;
+    let legend = match metric.label {
+        Some(label) => format!("{{{{{}}}}}", label),
+        None => name.to_string(),
+    };
+    // Sum over everything but the label, if there is one
+    let sum = |expr: String| match metric.label {
+        Some(label) => format!("sum by ({}) ({})", label, expr),
+        None => expr,
+    };
+    match metric.kind {
+        MetricKind::Counter => vec![(sum(format!("rate({}[$__rate_interval])", name)), legend)],
+        MetricKind::Gauge => vec![(sum(name.to_string()), legend)],
+        MetricKind::Histogram => {
+            let labels = match metric.label {
+                Some(label) => format!("le, {}", label),
+                None => "le".to_string(),
+            };
+            [0.5, 0.99]
+                .into_iter()
+                .map(|quantile| {
+                    (
+                        format!(
+                            "histogram_quantile({}, sum by ({}) (rate({}_bucket[$__rate_interval])))",
+                            quantile, labels, name
+                        ),
+                        format!("p{} {}", quantile * 100.0, legend),
+                    )
+                })
+                .collect()
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::proton::frame::Headers;
+use crate::proton::{Frame, ProtonError};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+pub type HandlerFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ProtonError>> + Send + 'a>>;
+
+/// Who a request came from, as passed to a [`ProtonHandler`].
+#[derive(Debug, Clone, Copy)]
+pub struct RequestContext<'a> {
+    /// Identity usage is accounted to, from the HELLO
+    pub tenant: &'a str,
+    pub peer: SocketAddr,
+    /// Headers that followed the request, empty unless the client sends them
+    pub headers: &'a Headers,
+}
+
+/// What the server does with the events, state commits and action requests
+/// it accepts. Requests reach the handler after the server has checked
+/// quotas, ordering and ids, and duplicates are answered without it.
+///
+/// Returning `ProtonError::QuotaExceeded` answers the client with
+/// `QUOTA_EXCEEDED`; any other error fails the stream, and with it the
+/// connection.
+pub trait ProtonHandler: Send + Sync {
+    /// An accepted event, answered with the returned ack.
+    fn on_event<'a>(&'a self, ctx: RequestContext<'a>, event: &'a Frame) -> HandlerFuture<'a, u32>;
+
+    /// A state commit, answered with the returned response. Aborts go to
+    /// the server's `CommitHandler` instead.
+    fn on_state_commit<'a>(
+        &'a self,
+        ctx: RequestContext<'a>,
+        commit: &'a Frame,
+    ) -> HandlerFuture<'a, u32>;
+
+    /// Payload of the action delivered as `action_id`. The server numbers
+    /// actions so that those the client has not acknowledged are delivered
+    /// again after a reconnect, under the same id. The handler may wait
+    /// until it has an action to give. Clients with fixed framing receive
+    /// the id alone.
+    fn next_action<'a>(
+        &'a self,
+        ctx: RequestContext<'a>,
+        action_id: u32,
+    ) -> HandlerFuture<'a, Vec<u8>>;
+}
+
+/// The demo behaviour: acks events with their own id, answers each state
+/// commit with its id plus two and hands out actions without payloads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EchoHandler;
+
+impl ProtonHandler for EchoHandler {
+    fn on_event<'a>(
+        &'a self,
+        _ctx: RequestContext<'a>,
+        event: &'a Frame,
+    ) -> HandlerFuture<'a, u32> {
+        Box::pin(async move { Ok(event.id) })
+    }
+
+    fn on_state_commit<'a>(
+        &'a self,
+        _ctx: RequestContext<'a>,
+        commit: &'a Frame,
+    ) -> HandlerFuture<'a, u32> {
+        Box::pin(async move { Ok(commit.id + 2) })
+    }
+
+    fn next_action<'a>(
+        &'a self,
+        _ctx: RequestContext<'a>,
+        _action_id: u32,
+    ) -> HandlerFuture<'a, Vec<u8>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+}
@@ -0,0 +1,89 @@
+use crate::proton::ids::IdScheme;
+use crate::proton::journal::write_atomic;
+use crate::proton::ProtonError;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+const KEY_LAST_EVENT_ID: &str = "last_event_id";
+const KEY_ID_SCHEME: &str = "id_scheme";
+const KEY_ACTION_OFFSET: &str = "action_offset";
+const KEY_OUTBOX_CURSOR: &str = "outbox_cursor";
+
+/// A client's durable protocol state, exported by a producer process that
+/// is shutting down and imported by its replacement, so a rolling upgrade
+/// neither reuses event ids nor loses unacknowledged events.
+///
+/// TLS session tickets are not included: rustls keeps them opaque, so the
+/// new process makes a full handshake.
+///
+/// Written as `key=value` lines; unknown keys are ignored so newer processes
+/// can add state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandoffState {
+    /// Highest event id the exporting process sent or allocated
+    pub last_event_id: u32,
+    /// Scheme `last_event_id` was allocated by
+    pub id_scheme: IdScheme,
+    /// Highest action id acknowledged
+    pub action_offset: u32,
+    /// Highest event id acknowledged from the durable outbox, if one was used
+    pub outbox_cursor: Option<u32>,
+}
+
+impl HandoffState {
+    /// Reads the state from `path`, or `None` if there is no file.
+    pub fn load(path: &Path) -> Result<Option<Self>, ProtonError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => text.parse().map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the file at `path` atomically, synced to disk.
+    pub fn save(&self, path: &Path) -> Result<(), ProtonError> {
+        write_atomic(path, self.to_string().as_bytes())
+    }
+}
+
+impl FromStr for HandoffState {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |line: &str| {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid hand-off line '{}'", line),
+            ))
+        };
+        let mut state = HandoffState::default();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid(line))?;
+            let number = || value.parse::<u32>().map_err(|_| invalid(line));
+            match key {
+                KEY_LAST_EVENT_ID => state.last_event_id = number()?,
+                KEY_ID_SCHEME => state.id_scheme = value.parse()?,
+                KEY_ACTION_OFFSET => state.action_offset = number()?,
+                KEY_OUTBOX_CURSOR => state.outbox_cursor = Some(number()?),
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl fmt::Display for HandoffState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}={}", KEY_LAST_EVENT_ID, self.last_event_id)?;
+        writeln!(f, "{}={}", KEY_ID_SCHEME, self.id_scheme)?;
+        writeln!(f, "{}={}", KEY_ACTION_OFFSET, self.action_offset)?;
+        if let Some(cursor) = self.outbox_cursor {
+            writeln!(f, "{}={}", KEY_OUTBOX_CURSOR, cursor)?;
+        }
+        Ok(())
+    }
+}
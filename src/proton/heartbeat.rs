@@ -0,0 +1,190 @@
+//! Heartbeats on a stream of their own. Each side pings the other at the
+//! interval agreed in the HELLO and echoes the pings it receives, so both
+//! measure the round trip as the application sees it, queueing in either
+//! peer included, and both notice a peer that stopped answering well before
+//! QUIC's idle timeout would.
+
+use crate::proton::fsm::{StreamState, StreamStates};
+use crate::proton::wire::{HEARTBEAT_FRAME_LEN, HEARTBEAT_PING, HEARTBEAT_PONG};
+use crate::proton::{CloseReason, ProtonError};
+use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+/// Shortest heartbeat interval a server agrees to.
+pub const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+// Intervals without an echo after which the peer counts as lost
+const MISSED_HEARTBEATS: u32 = 3;
+
+#[derive(Debug, Default)]
+struct Measured {
+    interval: Option<Duration>,
+    latest: Option<Duration>,
+    smoothed: Option<Duration>,
+    sent: u64,
+    answered: u64,
+}
+
+/// Round trips measured by a connection's heartbeats, shared by its
+/// heartbeat task and whoever asks.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    measured: Mutex<Measured>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The heartbeat's state, or None if the peers did not agree to
+    /// exchange heartbeats.
+    pub fn stats(&self) -> Option<HeartbeatStats> {
+        let measured = self.measured.lock().unwrap();
+        Some(HeartbeatStats {
+            interval: measured.interval?,
+            latest: measured.latest,
+            smoothed: measured.smoothed,
+            sent: measured.sent,
+            answered: measured.answered,
+        })
+    }
+
+    /// Smoothed round trip time, once a ping has been echoed.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.measured.lock().unwrap().smoothed
+    }
+
+    fn observe(&self, rtt: Duration) {
+        let mut measured = self.measured.lock().unwrap();
+        measured.answered += 1;
+        measured.latest = Some(rtt);
+        // Weighted like TCP's smoothed RTT
+        measured.smoothed = Some(match measured.smoothed {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+}
+
+/// What a connection's heartbeats measured, as reported in its stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatStats {
+    pub interval: Duration,
+    /// Round trip of the latest echoed ping
+    pub latest: Option<Duration>,
+    pub smoothed: Option<Duration>,
+    pub sent: u64,
+    pub answered: u64,
+}
+
+impl fmt::Display for HeartbeatStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "every {:?}", self.interval)?;
+        if let (Some(latest), Some(smoothed)) = (self.latest, self.smoothed) {
+            write!(f, ", rtt {:?} (smoothed {:?})", latest, smoothed)?;
+        }
+        write!(f, ", {} sent, {} answered", self.sent, self.answered)
+    }
+}
+
+fn encode(kind: u8, seq: u32, timestamp: u64) -> [u8; HEARTBEAT_FRAME_LEN] {
+    let mut frame = [0u8; HEARTBEAT_FRAME_LEN];
+    frame[0] = kind;
+    frame[1..5].copy_from_slice(&seq.to_le_bytes());
+    frame[5..].copy_from_slice(&timestamp.to_le_bytes());
+    frame
+}
+
+/// Exchanges heartbeats on the heartbeat stream `send`/`recv` every
+/// `every`, recording round trips in `heartbeat`, until the stream or
+/// connection fails. A peer that answers no ping for several intervals gets
+/// the connection closed with `CloseReason::HeartbeatLost`.
+pub(crate) async fn run(
+    connection: QuinnConnection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    every: Duration,
+    heartbeat: &Heartbeat,
+    states: StreamStates,
+) {
+    let id = recv.id();
+    heartbeat.measured.lock().unwrap().interval = Some(every);
+    let epoch = Instant::now();
+    let last_heard = Mutex::new(epoch);
+    // Echoes are written by the task that writes pings
+    let (echoes, mut to_echo) = mpsc::channel::<[u8; HEARTBEAT_FRAME_LEN]>(16);
+
+    let read = async {
+        let mut frame = [0u8; HEARTBEAT_FRAME_LEN];
+        loop {
+            recv.read_exact(&mut frame).await?;
+            match frame[0] {
+                HEARTBEAT_PING => {
+                    frame[0] = HEARTBEAT_PONG;
+                    echoes
+                        .send(frame)
+                        .await
+                        .map_err(|_| ProtonError::ConnectionError)?;
+                }
+                HEARTBEAT_PONG => {
+                    let sent = u64::from_le_bytes(frame[5..].try_into().unwrap());
+                    let now = epoch.elapsed().as_micros() as u64;
+                    heartbeat.observe(Duration::from_micros(now.saturating_sub(sent)));
+                    *last_heard.lock().unwrap() = Instant::now();
+                }
+                _ => return Err(ProtonError::InvalidStream),
+            }
+        }
+    };
+
+    let write = async {
+        let mut ticks = interval(every);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut seq: u32 = 0;
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {
+                    let silent = last_heard.lock().unwrap().elapsed();
+                    if silent >= every * MISSED_HEARTBEATS {
+                        return Ok(Some(silent));
+                    }
+                    seq = seq.wrapping_add(1);
+                    let timestamp = epoch.elapsed().as_micros() as u64;
+                    send.write_all(&encode(HEARTBEAT_PING, seq, timestamp)).await?;
+                    heartbeat.measured.lock().unwrap().sent += 1;
+                }
+                Some(echo) = to_echo.recv() => send.write_all(&echo).await?,
+            }
+        }
+    };
+
+    let result: Result<Option<Duration>, ProtonError> = tokio::select! {
+        r = read => r.map(|()| None),
+        r = write => r,
+    };
+    let reason = match result {
+        Ok(Some(silent)) => {
+            warn!(
+                "No heartbeat from {} for {:?}, closing the connection",
+                connection.remote_address(),
+                silent
+            );
+            connection.close(CloseReason::HeartbeatLost.into(), b"heartbeat lost");
+            "heartbeat lost".to_string()
+        }
+        Ok(None) => "closed".to_string(),
+        Err(e) => {
+            if connection.close_reason().is_none() {
+                info!("Heartbeat stream ended: {}", e);
+            }
+            e.to_string()
+        }
+    };
+    states.enter(id, StreamState::Errored(reason));
+}
@@ -0,0 +1,287 @@
+use crate::proton::compress::Compression;
+use crate::proton::frame::Headers;
+use crate::proton::ids::IdScheme;
+use crate::proton::ordering::EventOrdering;
+use crate::proton::wire::{
+    KEY_ACTION_PUSH, KEY_ARCH, KEY_CLIENT_ID, KEY_COMPRESSION, KEY_CRATE_VERSION,
+    KEY_EVENT_ORDERING, KEY_EVENT_WINDOW, KEY_EXPERIMENT, KEY_FRAMING, KEY_HEARTBEAT,
+    KEY_ID_SCHEME, KEY_LAST_EVENT_ID, KEY_OS, KEY_PRIORITY, KEY_PROTOCOL, KEY_REPLAY_FROM,
+    KEY_TENANT, KEY_USER_AGENT,
+};
+use crate::proton::{Framing, ProtonError, STREAM_CONTROL, STREAM_TIMEOUT};
+use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use tokio::time::timeout;
+
+pub use crate::proton::wire::PROTOCOL_VERSION;
+
+/// Metadata each side sends in its HELLO on the control stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Application name and version, e.g. `my-service/2.3.1`
+    pub user_agent: String,
+    pub crate_version: String,
+    pub protocol_version: u32,
+    pub os: String,
+    pub arch: String,
+    /// Admission priority; higher priority clients may preempt lower ones
+    /// when the server is at capacity
+    pub priority: u8,
+    /// Tenant the client's usage is accounted to
+    pub tenant: Option<String>,
+    /// Identifies the client across its connections, for the server's
+    /// duplicate connection policy
+    pub client_id: Option<String>,
+    /// Event ordering the client asks for, and in the server's reply the
+    /// ordering it applies to the connection
+    pub event_ordering: Option<EventOrdering>,
+    /// How the client allocates event ids, and in the server's reply the
+    /// scheme it validates them against
+    pub id_scheme: Option<IdScheme>,
+    /// Framing the client asks for on its data streams, and in the server's
+    /// reply the framing it agreed to
+    pub framing: Option<Framing>,
+    /// Experiment and variant the client was bucketed into, as
+    /// `experiment/variant`
+    pub experiment: Option<String>,
+    /// Payload compression the client asks for, and in the server's reply
+    /// the compression it agreed to
+    pub compression: Option<Compression>,
+    /// Unacknowledged events the client asks to keep in flight, and in the
+    /// server's reply how many it allows
+    pub event_window: Option<u32>,
+    /// Interval in milliseconds at which the client asks to exchange
+    /// heartbeats, and in the server's reply the interval it agreed to
+    pub heartbeat: Option<u32>,
+    /// Unacknowledged actions the client asks the server to push, and in
+    /// the server's reply how many it pushes ahead of the acknowledgements
+    pub action_push: Option<u32>,
+    /// Most recent event the client sent and still keeps to send again
+    pub last_event_id: Option<u32>,
+    /// In the server's reply, the event id from which its event log lacks
+    /// the client's events, which the client sends again
+    pub replay_from: Option<u32>,
+}
+
+impl PeerInfo {
+    /// Describes this process, identifying the application as `user_agent`.
+    pub fn local(user_agent: &str) -> Self {
+        Self {
+            user_agent: user_agent.to_string(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            priority: 0,
+            tenant: None,
+            client_id: None,
+            event_ordering: None,
+            id_scheme: None,
+            framing: None,
+            experiment: None,
+            compression: None,
+            event_window: None,
+            heartbeat: None,
+            action_push: None,
+            last_event_id: None,
+            replay_from: None,
+        }
+    }
+
+    pub fn to_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        // Field lengths are bounded well below the header limits
+        let _ = headers.insert(KEY_USER_AGENT, &self.user_agent);
+        let _ = headers.insert(KEY_CRATE_VERSION, &self.crate_version);
+        let _ = headers.insert(KEY_PROTOCOL, &self.protocol_version.to_string());
+        let _ = headers.insert(KEY_OS, &self.os);
+        let _ = headers.insert(KEY_ARCH, &self.arch);
+        let _ = headers.insert(KEY_PRIORITY, &self.priority.to_string());
+        if let Some(ref tenant) = self.tenant {
+            let _ = headers.insert(KEY_TENANT, tenant);
+        }
+        if let Some(ref client_id) = self.client_id {
+            let _ = headers.insert(KEY_CLIENT_ID, client_id);
+        }
+        if let Some(ordering) = self.event_ordering {
+            let _ = headers.insert(KEY_EVENT_ORDERING, &ordering.to_string());
+        }
+        if let Some(scheme) = self.id_scheme {
+            let _ = headers.insert(KEY_ID_SCHEME, &scheme.to_string());
+        }
+        if let Some(framing) = self.framing {
+            let _ = headers.insert(KEY_FRAMING, &framing.to_string());
+        }
+        if let Some(ref experiment) = self.experiment {
+            let _ = headers.insert(KEY_EXPERIMENT, experiment);
+        }
+        if let Some(compression) = self.compression {
+            let _ = headers.insert(KEY_COMPRESSION, &compression.to_string());
+        }
+        if let Some(window) = self.event_window {
+            let _ = headers.insert(KEY_EVENT_WINDOW, &window.to_string());
+        }
+        if let Some(interval) = self.heartbeat {
+            let _ = headers.insert(KEY_HEARTBEAT, &interval.to_string());
+        }
+        if let Some(window) = self.action_push {
+            let _ = headers.insert(KEY_ACTION_PUSH, &window.to_string());
+        }
+        if let Some(event_id) = self.last_event_id {
+            let _ = headers.insert(KEY_LAST_EVENT_ID, &event_id.to_string());
+        }
+        if let Some(event_id) = self.replay_from {
+            let _ = headers.insert(KEY_REPLAY_FROM, &event_id.to_string());
+        }
+        headers
+    }
+
+    /// Missing fields are reported as "unknown" so newer peers can drop them.
+    pub fn from_headers(headers: &Headers) -> Self {
+        let field = |key| headers.get(key).unwrap_or("unknown").to_string();
+        Self {
+            user_agent: field(KEY_USER_AGENT),
+            crate_version: field(KEY_CRATE_VERSION),
+            protocol_version: headers
+                .get(KEY_PROTOCOL)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            os: field(KEY_OS),
+            arch: field(KEY_ARCH),
+            priority: headers
+                .get(KEY_PRIORITY)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            tenant: headers.get(KEY_TENANT).map(str::to_string),
+            client_id: headers.get(KEY_CLIENT_ID).map(str::to_string),
+            event_ordering: headers.get(KEY_EVENT_ORDERING).and_then(|v| v.parse().ok()),
+            id_scheme: headers.get(KEY_ID_SCHEME).and_then(|v| v.parse().ok()),
+            framing: headers.get(KEY_FRAMING).and_then(|v| v.parse().ok()),
+            experiment: headers.get(KEY_EXPERIMENT).map(str::to_string),
+            compression: headers.get(KEY_COMPRESSION).and_then(|v| v.parse().ok()),
+            event_window: headers.get(KEY_EVENT_WINDOW).and_then(|v| v.parse().ok()),
+            heartbeat: headers.get(KEY_HEARTBEAT).and_then(|v| v.parse().ok()),
+            action_push: headers.get(KEY_ACTION_PUSH).and_then(|v| v.parse().ok()),
+            last_event_id: headers.get(KEY_LAST_EVENT_ID).and_then(|v| v.parse().ok()),
+            replay_from: headers.get(KEY_REPLAY_FROM).and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+impl Default for PeerInfo {
+    fn default() -> Self {
+        Self::local(concat!("proton/", env!("CARGO_PKG_VERSION")))
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (crate {}, protocol {}, {}/{}, priority {})",
+            self.user_agent,
+            self.crate_version,
+            self.protocol_version,
+            self.os,
+            self.arch,
+            self.priority
+        )?;
+        if let Some(ref tenant) = self.tenant {
+            write!(f, " tenant {}", tenant)?;
+        }
+        if let Some(ref client_id) = self.client_id {
+            write!(f, " client {}", client_id)?;
+        }
+        if let Some(ordering) = self.event_ordering {
+            write!(f, ", {} events", ordering)?;
+        }
+        if let Some(scheme) = self.id_scheme {
+            write!(f, ", {} ids", scheme)?;
+        }
+        if let Some(framing) = self.framing {
+            write!(f, ", {} frames", framing)?;
+        }
+        if let Some(ref experiment) = self.experiment {
+            write!(f, ", experiment {}", experiment)?;
+        }
+        if let Some(compression) = self.compression {
+            write!(f, ", {} compression", compression)?;
+        }
+        if let Some(window) = self.event_window {
+            write!(f, ", {} events in flight", window)?;
+        }
+        if let Some(interval) = self.heartbeat {
+            write!(f, ", heartbeat every {}ms", interval)?;
+        }
+        if let Some(window) = self.action_push {
+            write!(f, ", {} actions pushed ahead", window)?;
+        }
+        if let Some(event_id) = self.last_event_id {
+            write!(f, ", last sent event {}", event_id)?;
+        }
+        if let Some(event_id) = self.replay_from {
+            write!(f, ", replay from event {}", event_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// A client currently connected to the server, as listed by
+/// `ProtonServer::peers()`.
+#[derive(Debug, Clone)]
+pub struct ConnectedPeer {
+    pub addr: SocketAddr,
+    pub info: PeerInfo,
+    pub connected_at: SystemTime,
+}
+
+/// Client side of the HELLO exchange: opens the control stream, sends our
+/// metadata and waits for the server's.
+pub async fn send_hello(
+    connection: &QuinnConnection,
+    local: &PeerInfo,
+) -> Result<(SendStream, RecvStream, PeerInfo), ProtonError> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut hello = vec![STREAM_CONTROL];
+    hello.extend_from_slice(&local.to_headers().encode());
+    timeout(STREAM_TIMEOUT, send.write_all(&hello)).await??;
+    let peer = PeerInfo::from_headers(&Headers::read_from(&mut recv).await?);
+    Ok((send, recv, peer))
+}
+
+/// Like `send_hello`, on a control stream whose `STREAM_CONTROL`
+/// discriminator was written already, e.g. in 0-RTT data.
+pub async fn send_hello_on(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    local: &PeerInfo,
+) -> Result<(SendStream, RecvStream, PeerInfo), ProtonError> {
+    timeout(STREAM_TIMEOUT, send.write_all(&local.to_headers().encode())).await??;
+    let peer = PeerInfo::from_headers(&Headers::read_from(&mut recv).await?);
+    Ok((send, recv, peer))
+}
+
+/// Server side of the HELLO exchange, run once the `STREAM_CONTROL`
+/// discriminator has been read.
+pub async fn accept_hello(
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    local: &PeerInfo,
+) -> Result<PeerInfo, ProtonError> {
+    accept_hello_with(send, recv, |_| local.clone()).await
+}
+
+/// Like `accept_hello`, answering with metadata that depends on the
+/// client's, e.g. the outcome of a negotiation.
+pub async fn accept_hello_with(
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    reply: impl FnOnce(&PeerInfo) -> PeerInfo,
+) -> Result<PeerInfo, ProtonError> {
+    let peer = PeerInfo::from_headers(&Headers::read_from(recv).await?);
+    let local = reply(&peer);
+    timeout(STREAM_TIMEOUT, send.write_all(&local.to_headers().encode())).await??;
+    Ok(peer)
+}
@@ -0,0 +1,355 @@
+use crate::proton::journal::write_atomic;
+use crate::proton::ProtonError;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Ids reserved on disk at a time by `PersistedIds`
+const PERSIST_BLOCK: u32 = 1000;
+// Epoch ids keep the epoch in the high bits and a counter in the rest
+const EPOCH_SHIFT: u32 = 24;
+// Snowflake ids keep seconds since `SNOWFLAKE_EPOCH` in the high bits and a
+// sequence within the second in the rest
+const SNOWFLAKE_SHIFT: u32 = 4;
+// 2026-01-01T00:00:00Z, so snowflake ids last until mid 2034
+const SNOWFLAKE_EPOCH: u64 = 1_767_225_600;
+// How far ahead of the server's clock a snowflake id may be, allowing for
+// clock skew and bursts that borrow sequence numbers from later seconds
+const SNOWFLAKE_MAX_AHEAD_SECS: u64 = 600;
+
+/// How a client allocates event ids, declared to the server in the HELLO so
+/// it can validate them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdScheme {
+    /// In memory counter starting from 1 in each process
+    #[default]
+    Counter,
+    /// Counter that continues where the previous process left off
+    Persisted,
+    /// Epoch bumped on each start in the high 8 bits, counter in the low 24
+    Epoch,
+    /// Seconds since 2026-01-01 in the high 28 bits, sequence in the low 4
+    Snowflake,
+}
+
+impl FromStr for IdScheme {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "counter" => Ok(IdScheme::Counter),
+            "persisted" => Ok(IdScheme::Persisted),
+            "epoch" => Ok(IdScheme::Epoch),
+            "snowflake" => Ok(IdScheme::Snowflake),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown id scheme '{}', expected counter, persisted, epoch or snowflake",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for IdScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdScheme::Counter => write!(f, "counter"),
+            IdScheme::Persisted => write!(f, "persisted"),
+            IdScheme::Epoch => write!(f, "epoch"),
+            IdScheme::Snowflake => write!(f, "snowflake"),
+        }
+    }
+}
+
+/// Hands out the ids of the events a client sends. Ids increase within a
+/// process; schemes other than `Counter` keep increasing across restarts.
+pub trait IdAllocator: Send + Sync {
+    fn scheme(&self) -> IdScheme;
+
+    /// Allocates `count` consecutive ids and returns the first.
+    fn allocate(&self, count: u32) -> Result<u32, ProtonError>;
+
+    /// Moves past `id`, which was assigned elsewhere (e.g. by the outbox).
+    fn advance_past(&self, id: u32) -> Result<(), ProtonError>;
+
+    /// The highest id allocated or moved past, 0 if none.
+    fn last(&self) -> u32;
+}
+
+/// In memory counter; the default.
+#[derive(Debug, Default)]
+pub struct CounterIds(AtomicU32);
+
+impl IdAllocator for CounterIds {
+    fn scheme(&self) -> IdScheme {
+        IdScheme::Counter
+    }
+
+    fn allocate(&self, count: u32) -> Result<u32, ProtonError> {
+        Ok(self.0.fetch_add(count, Ordering::Relaxed) + 1)
+    }
+
+    fn advance_past(&self, id: u32) -> Result<(), ProtonError> {
+        self.0.fetch_max(id, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn last(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counter persisted to a file. Ids are reserved on disk 1000 at a time, so
+/// a restart skips the unused rest of a block instead of writing every id.
+#[derive(Debug)]
+pub struct PersistedIds {
+    path: PathBuf,
+    // Last id handed out and the highest id reserved on disk
+    state: Mutex<(u32, u32)>,
+}
+
+impl PersistedIds {
+    /// Continues from the ids reserved in `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, ProtonError> {
+        let reserved = read_u32(path)?.unwrap_or(0);
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new((reserved, reserved)),
+        })
+    }
+
+    // Reserve up to `id` and a block beyond it
+    fn reserve(&self, state: &mut (u32, u32), id: u32) -> Result<(), ProtonError> {
+        if id > state.1 {
+            let reserved = id.saturating_add(PERSIST_BLOCK);
+            write_u32(&self.path, reserved)?;
+            state.1 = reserved;
+        }
+        Ok(())
+    }
+}
+
+impl IdAllocator for PersistedIds {
+    fn scheme(&self) -> IdScheme {
+        IdScheme::Persisted
+    }
+
+    fn allocate(&self, count: u32) -> Result<u32, ProtonError> {
+        let mut state = self.state.lock().unwrap();
+        let first = state.0.checked_add(1).ok_or_else(exhausted)?;
+        let last = first
+            .checked_add(count.saturating_sub(1))
+            .ok_or_else(exhausted)?;
+        self.reserve(&mut state, last)?;
+        state.0 = last;
+        Ok(first)
+    }
+
+    fn advance_past(&self, id: u32) -> Result<(), ProtonError> {
+        let mut state = self.state.lock().unwrap();
+        if id > state.0 {
+            self.reserve(&mut state, id)?;
+            state.0 = id;
+        }
+        Ok(())
+    }
+
+    fn last(&self) -> u32 {
+        self.state.lock().unwrap().0
+    }
+}
+
+/// Epoch and counter. Only the epoch is persisted, once per start and
+/// whenever the counter runs out, so a process can send 16M events before
+/// moving to the next epoch. Up to 255 epochs can be used.
+#[derive(Debug)]
+pub struct EpochIds {
+    path: PathBuf,
+    // Last id handed out
+    last: Mutex<u32>,
+}
+
+impl EpochIds {
+    /// Starts the epoch after the one recorded in `path`.
+    pub fn open(path: &Path) -> Result<Self, ProtonError> {
+        let epoch = read_u32(path)?.unwrap_or(0) + 1;
+        if epoch > u32::MAX >> EPOCH_SHIFT {
+            return Err(exhausted());
+        }
+        write_u32(path, epoch)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            last: Mutex::new(epoch << EPOCH_SHIFT),
+        })
+    }
+
+    // Persist the epoch of `id` if it moved to a later one
+    fn enter_epoch(&self, last: u32, id: u32) -> Result<(), ProtonError> {
+        if id >> EPOCH_SHIFT > last >> EPOCH_SHIFT {
+            write_u32(&self.path, id >> EPOCH_SHIFT)?;
+        }
+        Ok(())
+    }
+}
+
+impl IdAllocator for EpochIds {
+    fn scheme(&self) -> IdScheme {
+        IdScheme::Epoch
+    }
+
+    fn allocate(&self, count: u32) -> Result<u32, ProtonError> {
+        let mut last = self.last.lock().unwrap();
+        // A zero counter is never used
+        let mut first = last.checked_add(1).ok_or_else(exhausted)?;
+        if first & ((1 << EPOCH_SHIFT) - 1) == 0 {
+            first |= 1;
+        }
+        let mut end = first
+            .checked_add(count.saturating_sub(1))
+            .ok_or_else(exhausted)?;
+        // A batch stays in one epoch
+        if end >> EPOCH_SHIFT != first >> EPOCH_SHIFT {
+            first = (end >> EPOCH_SHIFT << EPOCH_SHIFT) | 1;
+            end = first
+                .checked_add(count.saturating_sub(1))
+                .ok_or_else(exhausted)?;
+        }
+        self.enter_epoch(*last, end)?;
+        *last = end;
+        Ok(first)
+    }
+
+    fn advance_past(&self, id: u32) -> Result<(), ProtonError> {
+        let mut last = self.last.lock().unwrap();
+        if id > *last {
+            self.enter_epoch(*last, id)?;
+            *last = id;
+        }
+        Ok(())
+    }
+
+    fn last(&self) -> u32 {
+        *self.last.lock().unwrap()
+    }
+}
+
+/// Time based ids that need no persistence. A second holds 16 ids; faster
+/// senders borrow from the following seconds, up to 10 minutes ahead.
+#[derive(Debug, Default)]
+pub struct SnowflakeIds {
+    last: Mutex<u32>,
+}
+
+impl IdAllocator for SnowflakeIds {
+    fn scheme(&self) -> IdScheme {
+        IdScheme::Snowflake
+    }
+
+    fn allocate(&self, count: u32) -> Result<u32, ProtonError> {
+        let now = u32::try_from(snowflake_seconds() << SNOWFLAKE_SHIFT).map_err(|_| exhausted())?;
+        let mut last = self.last.lock().unwrap();
+        let first = last.checked_add(1).ok_or_else(exhausted)?.max(now);
+        let end = first
+            .checked_add(count.saturating_sub(1))
+            .ok_or_else(exhausted)?;
+        if u64::from(end >> SNOWFLAKE_SHIFT) > snowflake_seconds() + SNOWFLAKE_MAX_AHEAD_SECS {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "snowflake ids are running too far ahead of the clock",
+            )));
+        }
+        *last = end;
+        Ok(first)
+    }
+
+    fn advance_past(&self, id: u32) -> Result<(), ProtonError> {
+        let mut last = self.last.lock().unwrap();
+        *last = (*last).max(id);
+        Ok(())
+    }
+
+    fn last(&self) -> u32 {
+        *self.last.lock().unwrap()
+    }
+}
+
+fn snowflake_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        .saturating_sub(SNOWFLAKE_EPOCH)
+}
+
+// Validates the ids a connection receives against the scheme its client
+// declared
+#[derive(Debug, Default)]
+pub(crate) struct IdCheck {
+    scheme: IdScheme,
+    // Epoch of the first event, which later events may not go below
+    epoch: Option<u32>,
+}
+
+impl IdCheck {
+    pub(crate) fn new(scheme: IdScheme) -> Self {
+        Self {
+            scheme,
+            epoch: None,
+        }
+    }
+
+    pub(crate) fn scheme(&self) -> IdScheme {
+        self.scheme
+    }
+
+    // Why `id` cannot have come from the declared scheme, if it cannot
+    pub(crate) fn check(&mut self, id: u32) -> Option<&'static str> {
+        match self.scheme {
+            IdScheme::Counter | IdScheme::Persisted => None,
+            IdScheme::Epoch => {
+                let epoch = id >> EPOCH_SHIFT;
+                if epoch == 0 || id & ((1 << EPOCH_SHIFT) - 1) == 0 {
+                    Some("epoch or counter is zero")
+                } else if epoch < *self.epoch.get_or_insert(epoch) {
+                    Some("epoch went backwards")
+                } else {
+                    None
+                }
+            }
+            IdScheme::Snowflake => {
+                if u64::from(id >> SNOWFLAKE_SHIFT) > snowflake_seconds() + SNOWFLAKE_MAX_AHEAD_SECS
+                {
+                    Some("timestamp is in the future")
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+fn read_u32(path: &Path) -> Result<Option<u32>, ProtonError> {
+    match std::fs::read(path) {
+        Ok(bytes) => match <[u8; 4]>::try_from(bytes.as_slice()) {
+            Ok(bytes) => Ok(Some(u32::from_le_bytes(bytes))),
+            Err(_) => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: id file has the wrong length", path.display()),
+            ))),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_u32(path: &Path, value: u32) -> Result<(), ProtonError> {
+    write_atomic(path, &value.to_le_bytes())
+}
+
+fn exhausted() -> ProtonError {
+    ProtonError::IoError(std::io::Error::other("event ids exhausted"))
+}
@@ -0,0 +1,290 @@
+//! Append-only logs of keyed records, the storage behind the event log and
+//! the dedupe index.
+//!
+//! Each record is a little-endian u16 key length, the key, then a body laid
+//! out by the log's [`Record`] type. A crash mid-append can leave a partial
+//! record at the end, which is cut off when the log is opened.
+//!
+//! Also home to [`write_atomic`], which every small state file is saved
+//! with.
+
+use crate::proton::dedupe::FsyncPolicy;
+use crate::proton::ProtonError;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::warn;
+
+/// The body of a record, after its key.
+pub(crate) trait Record: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// The body at the start of `buf` and what follows it, or None if `buf`
+    /// holds no whole valid body.
+    fn decode(buf: &[u8]) -> Option<(Self, &[u8])>;
+}
+
+pub(crate) fn encode_record(key: &str, record: &impl Record, out: &mut Vec<u8>) {
+    // Keys are a tenant and client id from the HELLO, each bounded by the
+    // header value limit
+    out.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    out.extend_from_slice(key.as_bytes());
+    record.encode(out);
+}
+
+// The record at the start of `buf` and what follows it, or None if `buf`
+// holds no whole valid record
+pub(crate) fn decode_record<R: Record>(buf: &[u8]) -> Option<(&str, R, &[u8])> {
+    let key_len = u16::from_le_bytes(buf.get(..2)?.try_into().unwrap()) as usize;
+    let key = std::str::from_utf8(buf.get(2..2 + key_len)?).ok()?;
+    let (record, rest) = R::decode(&buf[2 + key_len..])?;
+    Some((key, record, rest))
+}
+
+/// Replaces the file at `path` atomically with `bytes`, synced to disk:
+/// written beside it, synced, renamed over it, then the rename synced too.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), ProtonError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    rename_synced(Path::new(&tmp), path)
+}
+
+/// Renames `from` over `to` and syncs their directory, without which a
+/// crash can undo the rename.
+pub(crate) fn rename_synced(from: &Path, to: &Path) -> Result<(), ProtonError> {
+    std::fs::rename(from, to)?;
+    let dir = match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// A log file that is only appended to, or replaced whole, and synced as
+/// its fsync policy says.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+    // Bytes in the log, where the next record starts
+    len: u64,
+    // Whether the log has appends not yet synced, and when it last was
+    dirty: bool,
+    synced_at: Instant,
+}
+
+impl Journal {
+    /// Opens or creates the log at `path`, handing each whole record to
+    /// `load` along with its offset and length in bytes.
+    pub(crate) fn open<R: Record>(
+        path: &Path,
+        fsync: FsyncPolicy,
+        mut load: impl FnMut(&str, R, u64, usize),
+    ) -> Result<Self, ProtonError> {
+        let records = match std::fs::read(path) {
+            Ok(records) => records,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut valid = 0;
+        let mut rest = &records[..];
+        while let Some((key, record, next)) = decode_record::<R>(rest) {
+            let len = rest.len() - next.len();
+            load(key, record, valid as u64, len);
+            valid += len;
+            rest = next;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if valid < records.len() {
+            warn!(
+                "Dropping {} bytes of a partial record at the end of {}",
+                records.len() - valid,
+                path.display()
+            );
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            fsync,
+            len: valid as u64,
+            dirty: false,
+            synced_at: Instant::now(),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes in the log.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends encoded `records`, returning the offset they start at once
+    /// they are as durable as the fsync policy makes them.
+    pub(crate) fn append(&mut self, records: &[u8]) -> Result<u64, ProtonError> {
+        let offset = self.len;
+        self.file.write_all(records)?;
+        self.len += records.len() as u64;
+        self.dirty = true;
+        match self.fsync {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Interval(interval) if self.synced_at.elapsed() >= interval => {
+                self.sync()?
+            }
+            _ => {}
+        }
+        Ok(offset)
+    }
+
+    /// Replaces the log atomically with encoded `records`, synced.
+    pub(crate) fn rewrite(&mut self, records: &[u8]) -> Result<(), ProtonError> {
+        write_atomic(&self.path, records)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = records.len() as u64;
+        self.dirty = false;
+        self.synced_at = Instant::now();
+        Ok(())
+    }
+
+    /// Starts dropping the log's bytes before `offset`. The bytes after it
+    /// are copied aside by [`Trim::copy`], which needs no access to the
+    /// journal, so appends can go on meanwhile.
+    pub(crate) fn trim(&self, offset: u64) -> Trim {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".trim");
+        Trim {
+            path: self.path.clone(),
+            tmp: tmp.into(),
+            file: None,
+            from: offset,
+            to: self.len,
+        }
+    }
+
+    /// Copies what was appended since `trim` began and replaces the log
+    /// with the trimmed copy, synced.
+    pub(crate) fn finish_trim(&mut self, mut trim: Trim) -> Result<(), ProtonError> {
+        trim.to = self.len;
+        trim.copy()?;
+        if let Some(ref file) = trim.file {
+            file.sync_all()?;
+        }
+        rename_synced(&trim.tmp, &self.path)?;
+        trim.file = None;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len -= trim.from;
+        self.dirty = false;
+        self.synced_at = Instant::now();
+        Ok(())
+    }
+
+    /// Syncs appends not yet synced.
+    pub(crate) fn flush(&mut self) -> Result<(), ProtonError> {
+        if self.dirty {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), ProtonError> {
+        self.file.sync_data()?;
+        self.dirty = false;
+        self.synced_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// A log being trimmed: its bytes from one offset on, copied aside.
+#[derive(Debug)]
+pub(crate) struct Trim {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: Option<File>,
+    // The offset the copy starts at, and the one it has reached
+    from: u64,
+    to: u64,
+}
+
+impl Trim {
+    /// Copies the bytes the log held when the trim began, or since the
+    /// last copy. Appends only ever go after them.
+    pub(crate) fn copy(&mut self) -> Result<(), ProtonError> {
+        let copied = match self.file {
+            Some(ref file) => self.from + file.metadata()?.len(),
+            None => self.from,
+        };
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => self.file.insert(File::create(&self.tmp)?),
+        };
+        let mut log = File::open(&self.path)?;
+        log.seek(SeekFrom::Start(copied))?;
+        std::io::copy(&mut log.take(self.to - copied), file)?;
+        Ok(())
+    }
+}
+
+impl Drop for Trim {
+    fn drop(&mut self) {
+        // A trim that never finished leaves the log as it was
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_keeps_appends_made_while_copying() {
+        let path = std::env::temp_dir().join(format!("proton-journal-trim-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut journal = Journal::open::<Raw>(&path, FsyncPolicy::Never, |_, _, _, _| {}).unwrap();
+        journal.append(b"oldnew").unwrap();
+        let mut trim = journal.trim(3);
+        trim.copy().unwrap();
+        journal.append(b"er").unwrap();
+        journal.finish_trim(trim).unwrap();
+        assert_eq!(journal.len(), 5);
+        assert_eq!(std::fs::read(&path).unwrap(), b"newer");
+        assert_eq!(journal.append(b"!").unwrap(), 5);
+        assert_eq!(std::fs::read(&path).unwrap(), b"newer!");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("proton-journal-atomic-{}", std::process::id()));
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        assert!(!Path::new(&tmp).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Never decodes; the test appends raw bytes
+    struct Raw;
+
+    impl Record for Raw {
+        fn encode(&self, _: &mut Vec<u8>) {}
+
+        fn decode(_: &[u8]) -> Option<(Self, &[u8])> {
+            None
+        }
+    }
+}
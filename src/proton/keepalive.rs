@@ -0,0 +1,168 @@
+use crate::proton::power::LOW_POWER_KEEPALIVE_STRETCH;
+use crate::proton::profile::spawn_named;
+use crate::proton::settings::PowerMode;
+use crate::proton::wire::DATAGRAM_KEEPALIVE;
+use crate::proton::IDLE_TIMEOUT;
+use bytes::Bytes;
+use quinn::Connection as QuinnConnection;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Default time without traffic after which a keepalive is sent, half the
+/// default idle timeout so a keepalive lands well before the connection
+/// would time out.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(IDLE_TIMEOUT.as_millis() as u64 / 2);
+
+// Times traffic is sampled per keepalive interval. A keepalive goes out at
+// most this fraction of an interval late.
+const SAMPLES_PER_INTERVAL: u32 = 4;
+
+/// How a client keeps an idle connection from timing out. The client sends
+/// its own keepalives rather than QUIC's, so their interval can change with
+/// the power mode while the connection is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// Like QUIC's keep-alive timer, a keepalive is sent once nothing has
+    /// been received for this long
+    pub interval: Duration,
+    /// Skip keepalives while streams are exchanging data: one is sent only
+    /// once no stream data has moved in either direction for `interval`.
+    /// Received packets alone, e.g. acks, no longer count as traffic.
+    pub suppress_when_active: bool,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: KEEPALIVE_INTERVAL,
+            suppress_when_active: false,
+        }
+    }
+}
+
+impl KeepAlive {
+    /// The interval in effect in `mode`.
+    pub fn interval_for(&self, mode: PowerMode) -> Duration {
+        match mode {
+            PowerMode::Normal => self.interval,
+            PowerMode::Low => self.interval * LOW_POWER_KEEPALIVE_STRETCH,
+        }
+    }
+}
+
+impl fmt::Display for KeepAlive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "every {:?} of idleness", self.interval)?;
+        if self.suppress_when_active {
+            write!(f, ", suppressed during traffic")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct KeepAliveCounters {
+    sent: AtomicU64,
+    suppressed: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// What the keepalive policy did on a connection, as reported in its stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAliveStats {
+    pub policy: KeepAlive,
+    /// The interval in effect, stretched in low power mode
+    pub interval: Duration,
+    pub sent: u64,
+    /// Keepalives that were due but skipped because streams were active
+    pub suppressed: u64,
+    /// Keepalives the connection refused, e.g. because the server does not
+    /// accept datagrams
+    pub failed: u64,
+}
+
+impl KeepAliveStats {
+    pub(crate) fn new(policy: KeepAlive, mode: PowerMode, counters: &KeepAliveCounters) -> Self {
+        Self {
+            policy,
+            interval: policy.interval_for(mode),
+            sent: counters.sent.load(Ordering::Relaxed),
+            suppressed: counters.suppressed.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for KeepAliveStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.policy)?;
+        if self.interval != self.policy.interval {
+            write!(f, ", stretched to {:?} for low power", self.interval)?;
+        }
+        write!(f, ", {} sent", self.sent)?;
+        if self.policy.suppress_when_active {
+            write!(f, ", {} skipped", self.suppressed)?;
+        }
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+// What counts as traffic under `policy`: stream frames moved in either
+// direction with suppression, otherwise packets received
+fn activity(connection: &QuinnConnection, policy: &KeepAlive) -> u64 {
+    let stats = connection.stats();
+    if policy.suppress_when_active {
+        stats.frame_tx.stream + stats.frame_rx.stream
+    } else {
+        stats.udp_rx.datagrams
+    }
+}
+
+// Send keepalives on `connection` whenever it has seen no traffic for the
+// interval `power` currently calls for, until the connection closes
+pub(crate) fn spawn<P>(
+    connection: QuinnConnection,
+    policy: KeepAlive,
+    counters: Arc<KeepAliveCounters>,
+    power: P,
+) where
+    P: Fn() -> PowerMode + Send + 'static,
+{
+    spawn_named("keepalive", async move {
+        let mut seen = activity(&connection, &policy);
+        let mut last_active = Instant::now();
+        // When a keepalive was last sent or skipped, so a long burst of
+        // traffic counts one skipped keepalive per interval
+        let mut skipped_since = last_active;
+        while connection.close_reason().is_none() {
+            let interval = policy.interval_for(power());
+            sleep(interval / SAMPLES_PER_INTERVAL).await;
+            let now = Instant::now();
+            let current = activity(&connection, &policy);
+            if current != seen {
+                seen = current;
+                last_active = now;
+                if policy.suppress_when_active && now.duration_since(skipped_since) >= interval {
+                    counters.suppressed.fetch_add(1, Ordering::Relaxed);
+                    skipped_since = now;
+                }
+                continue;
+            }
+            if now.duration_since(last_active) < interval {
+                continue;
+            }
+            match connection.send_datagram(Bytes::from_static(&[DATAGRAM_KEEPALIVE])) {
+                Ok(()) => counters.sent.fetch_add(1, Ordering::Relaxed),
+                Err(_) => counters.failed.fetch_add(1, Ordering::Relaxed),
+            };
+            last_active = now;
+            skipped_since = now;
+        }
+    });
+}
@@ -0,0 +1,302 @@
+use crate::proton::json::json_string;
+use crate::proton::ProtonError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+// Logs of other crates, quinn's in particular, are shown from this level up
+const DEPENDENCY_LEVEL: LevelFilter = LevelFilter::WARN;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `12:00:01.250  INFO connection{id=1 remote=..}: message key=value`
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown log format '{}', expected text or json", s),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Installs a [`LogSubscriber`] for the whole process.
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), ProtonError> {
+    tracing::subscriber::set_global_default(LogSubscriber::new(level, format))
+        .map_err(|e| ProtonError::IoError(std::io::Error::other(e)))
+}
+
+// Fields of a span or event, in the order they were recorded
+#[derive(Debug, Default)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Fields {
+    fn set(&mut self, name: &'static str, value: String) {
+        match self.0.iter_mut().find(|(n, _)| *n == name) {
+            Some(field) => field.1 = value,
+            None => self.0.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), value.to_string());
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+    // Kept open for as long as this span is
+    parent: Option<u64>,
+    refs: usize,
+}
+
+thread_local! {
+    // Spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Writes the crate's log events at `level` and up to stdout, each prefixed
+/// with the spans it happened in, e.g. the connection and stream. Logs of
+/// dependencies are only shown from warnings up.
+#[derive(Debug)]
+pub struct LogSubscriber {
+    level: LevelFilter,
+    format: LogFormat,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+impl LogSubscriber {
+    pub fn new(level: LevelFilter, format: LogFormat) -> Self {
+        Self {
+            level,
+            format,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn format_text(&self, event: &Event<'_>, fields: &Fields, spans: &[&SpanData]) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let secs = (millis / 1000) % 86400;
+        let mut line = format!(
+            "{:02}:{:02}:{:02}.{:03} {:>5} ",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            millis % 1000,
+            event.metadata().level()
+        );
+        for span in spans {
+            line.push_str(span.metadata.name());
+            if !span.fields.0.is_empty() {
+                line.push('{');
+                for (i, (name, value)) in span.fields.0.iter().enumerate() {
+                    let sep = if i > 0 { " " } else { "" };
+                    let _ = write!(line, "{}{}={}", sep, name, value);
+                }
+                line.push('}');
+            }
+            line.push(':');
+        }
+        if !spans.is_empty() {
+            line.push(' ');
+        }
+        for (name, value) in &fields.0 {
+            match *name {
+                "message" => line.push_str(value),
+                name => {
+                    let _ = write!(line, " {}={}", name, value);
+                }
+            }
+        }
+        line
+    }
+
+    fn format_json(&self, event: &Event<'_>, fields: &Fields, spans: &[&SpanData]) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let metadata = event.metadata();
+        let mut line = format!(
+            "{{\"ts_ms\":{},\"level\":{},\"target\":{}",
+            millis,
+            json_string(metadata.level().as_str()),
+            json_string(metadata.target())
+        );
+        for (name, value) in &fields.0 {
+            let _ = write!(line, ",{}:{}", json_string(name), json_string(value));
+        }
+        line.push_str(",\"spans\":[");
+        for (i, span) in spans.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            let _ = write!(line, "{{\"name\":{}", json_string(span.metadata.name()));
+            for (name, value) in &span.fields.0 {
+                let _ = write!(line, ",{}:{}", json_string(name), json_string(value));
+            }
+            line.push('}');
+        }
+        line.push_str("]}");
+        line
+    }
+}
+
+impl Subscriber for LogSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let level = if metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            self.level
+        } else {
+            self.level.min(DEPENDENCY_LEVEL)
+        };
+        *metadata.level() <= level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Fields::default();
+        span.record(&mut fields);
+        let parent = match span.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if span.is_root() => None,
+            None => ENTERED.with(|entered| entered.borrow().last().copied()),
+        };
+        let mut spans = self.spans.lock().unwrap();
+        let parent = parent.filter(|parent| match spans.get_mut(parent) {
+            Some(data) => {
+                data.refs += 1;
+                true
+            }
+            None => false,
+        });
+        spans.insert(
+            id,
+            SpanData {
+                metadata: span.metadata(),
+                fields,
+                parent,
+                refs: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut data.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let innermost = match event.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if event.is_root() => None,
+            None => ENTERED.with(|entered| entered.borrow().last().copied()),
+        };
+        let spans = self.spans.lock().unwrap();
+        // Outermost first
+        let mut context = Vec::new();
+        let mut next = innermost;
+        while let Some(data) = next.and_then(|id| spans.get(&id)) {
+            context.push(data);
+            next = data.parent;
+        }
+        context.reverse();
+        let line = match self.format {
+            LogFormat::Text => self.format_text(event, &fields, &context),
+            LogFormat::Json => self.format_json(event, &fields, &context),
+        };
+        drop(spans);
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|&id| id == span.into_u64()) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let mut next = Some(span.into_u64());
+        let mut closed = false;
+        // Closing a span releases its parent, which may close in turn
+        while let Some(id) = next.take() {
+            let Some(data) = spans.get_mut(&id) else {
+                break;
+            };
+            data.refs -= 1;
+            if data.refs > 0 {
+                break;
+            }
+            next = spans.remove(&id).and_then(|data| data.parent);
+            closed |= id == span.into_u64();
+        }
+        closed
+    }
+}
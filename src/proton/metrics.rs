@@ -0,0 +1,996 @@
+use crate::proton::ProtonError;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fmt::Write;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+// Buckets in every histogram
+const BUCKETS: usize = 10;
+// Connection errors kept for the dashboard, oldest dropped first
+const RECENT_ERRORS: usize = 32;
+// Experiment variants are declared by clients, so their series are capped;
+// further variants, and names unfit for a label, are counted as `other`
+const MAX_EXPERIMENT_VARIANTS: usize = 64;
+const MAX_VARIANT_LEN: usize = 64;
+/// How often `--metrics-addr log` writes a summary when no interval is given
+pub const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the server exports its metrics, besides the admin endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsExport {
+    /// Serve `GET /metrics` alone over HTTP on this address
+    Http(SocketAddr),
+    /// Log a one line summary of the main counters at this interval
+    Log(Duration),
+}
+
+impl FromStr for MetricsExport {
+    type Err = String;
+
+    /// Parses `host:port`, `log` or `log:<secs>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid metrics export '{}', expected host:port, log or log:<secs>",
+                s
+            )
+        };
+        if s == "log" {
+            return Ok(MetricsExport::Log(METRICS_LOG_INTERVAL));
+        }
+        if let Some(secs) = s.strip_prefix("log:") {
+            return match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => Ok(MetricsExport::Log(Duration::from_secs(secs))),
+                _ => Err(invalid()),
+            };
+        }
+        s.parse().map(MetricsExport::Http).map_err(|_| invalid())
+    }
+}
+
+/// Why a server side handshake did not complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// The handshake deadline passed
+    Timeout,
+    /// The TLS layer rejected the handshake (certificate, ALPN, cipher suite)
+    Tls,
+    /// Any other QUIC transport error
+    Transport,
+    /// The peer closed or reset the connection
+    Closed,
+}
+
+impl HandshakeFailure {
+    const ALL: [HandshakeFailure; 4] = [
+        HandshakeFailure::Timeout,
+        HandshakeFailure::Tls,
+        HandshakeFailure::Transport,
+        HandshakeFailure::Closed,
+    ];
+
+    /// Classifies an error returned by a `quinn::Connecting` future.
+    pub fn from_connection_error(error: &quinn::ConnectionError) -> Self {
+        match error {
+            // Transport error codes 0x100-0x1ff carry a TLS alert
+            quinn::ConnectionError::TransportError(e) if u64::from(e.code) & !0xff == 0x100 => {
+                HandshakeFailure::Tls
+            }
+            quinn::ConnectionError::TransportError(_) | quinn::ConnectionError::VersionMismatch => {
+                HandshakeFailure::Transport
+            }
+            quinn::ConnectionError::TimedOut => HandshakeFailure::Timeout,
+            _ => HandshakeFailure::Closed,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            HandshakeFailure::Timeout => "timeout",
+            HandshakeFailure::Tls => "tls",
+            HandshakeFailure::Transport => "transport",
+            HandshakeFailure::Closed => "closed",
+        }
+    }
+}
+
+/// How a connection task ended, for aggregate error reporting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionOutcome {
+    /// The connection was handled to completion
+    Completed,
+    Io,
+    Connection,
+    InvalidStream,
+    Timeout,
+    CertificateExpired,
+    AuthenticationFailed,
+    QuotaExceeded,
+    AbortRefused,
+    ProtocolViolation,
+    /// The connection task panicked
+    Panicked,
+}
+
+impl ConnectionOutcome {
+    const ALL: [ConnectionOutcome; 11] = [
+        ConnectionOutcome::Completed,
+        ConnectionOutcome::Io,
+        ConnectionOutcome::Connection,
+        ConnectionOutcome::InvalidStream,
+        ConnectionOutcome::Timeout,
+        ConnectionOutcome::CertificateExpired,
+        ConnectionOutcome::AuthenticationFailed,
+        ConnectionOutcome::QuotaExceeded,
+        ConnectionOutcome::AbortRefused,
+        ConnectionOutcome::ProtocolViolation,
+        ConnectionOutcome::Panicked,
+    ];
+
+    pub fn from_result(result: &Result<(), ProtonError>) -> Self {
+        match result {
+            Ok(()) => ConnectionOutcome::Completed,
+            Err(ProtonError::IoError(_)) => ConnectionOutcome::Io,
+            // Circuit breakers, offline queues, refusals and decoded close
+            // reasons only exist on clients
+            Err(
+                ProtonError::ConnectionError
+                | ProtonError::CircuitOpen
+                | ProtonError::QueueFull
+                | ProtonError::ConnectionRefused
+                | ProtonError::ClosedByPeer(_),
+            ) => ConnectionOutcome::Connection,
+            Err(ProtonError::InvalidStream) => ConnectionOutcome::InvalidStream,
+            Err(ProtonError::Timeout) => ConnectionOutcome::Timeout,
+            Err(ProtonError::CertificateExpired) => ConnectionOutcome::CertificateExpired,
+            Err(ProtonError::AuthenticationFailed | ProtonError::HandshakeRejected(_)) => {
+                ConnectionOutcome::AuthenticationFailed
+            }
+            Err(ProtonError::QuotaExceeded) => ConnectionOutcome::QuotaExceeded,
+            Err(ProtonError::AbortRefused) => ConnectionOutcome::AbortRefused,
+            Err(ProtonError::ProtocolViolation(_)) => ConnectionOutcome::ProtocolViolation,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ConnectionOutcome::Completed => "completed",
+            ConnectionOutcome::Io => "io",
+            ConnectionOutcome::Connection => "connection",
+            ConnectionOutcome::InvalidStream => "invalid_stream",
+            ConnectionOutcome::Timeout => "timeout",
+            ConnectionOutcome::CertificateExpired => "certificate_expired",
+            ConnectionOutcome::AuthenticationFailed => "authentication_failed",
+            ConnectionOutcome::QuotaExceeded => "quota_exceeded",
+            ConnectionOutcome::AbortRefused => "abort_refused",
+            ConnectionOutcome::ProtocolViolation => "protocol_violation",
+            ConnectionOutcome::Panicked => "panicked",
+        }
+    }
+}
+
+impl fmt::Display for ConnectionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Upper bounds, in seconds, of a histogram's buckets.
+pub trait Bounds {
+    const BOUNDS: [f64; BUCKETS];
+}
+
+/// Buckets for QUIC/TLS handshakes, from 5ms to 5s.
+#[derive(Debug, Default)]
+pub struct HandshakeBounds;
+
+impl Bounds for HandshakeBounds {
+    const BOUNDS: [f64; BUCKETS] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+}
+
+/// Buckets for request handling, from 100us to 1s.
+#[derive(Debug, Default)]
+pub struct RequestBounds;
+
+impl Bounds for RequestBounds {
+    const BOUNDS: [f64; BUCKETS] = [
+        0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0,
+    ];
+}
+
+/// Fixed bucket histogram of durations.
+#[derive(Debug)]
+pub struct Histogram<B> {
+    buckets: [AtomicU64; BUCKETS],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+    bounds: PhantomData<B>,
+}
+
+pub type HandshakeHistogram = Histogram<HandshakeBounds>;
+pub type RequestHistogram = Histogram<RequestBounds>;
+
+impl<B> Default for Histogram<B> {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            bounds: PhantomData,
+        }
+    }
+}
+
+impl<B: Bounds> Histogram<B> {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = B::BOUNDS.iter().position(|&le| secs <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Observations per bucket as (upper bound in seconds, count), not
+    /// cumulative. Durations above the last bound are not included.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        B::BOUNDS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&le, bucket)| (le, bucket.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    // Write the series, with `label` (e.g. `stream="event",`) on each
+    fn render_series(&self, out: &mut String, name: &str, label: &str) {
+        let mut cumulative = 0;
+        for (le, bucket) in B::BOUNDS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, label, le, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, label, count);
+        let label = label.trim_end_matches(',');
+        let braces = if label.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", label)
+        };
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{} {}", name, braces, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces, count);
+    }
+}
+
+/// Activity on the streams of one type, across all connections.
+#[derive(Debug, Default)]
+pub struct StreamTypeMetrics {
+    pub opened: AtomicU64,
+    /// Requests answered, or for byte streams reads handled
+    pub requests: AtomicU64,
+    /// Streams that ended in an error
+    pub errors: AtomicU64,
+    /// Responses the client stopped reading for longer than the stall timeout
+    pub stalls: AtomicU64,
+    /// Time from reading a request to writing its response
+    pub request_duration: RequestHistogram,
+}
+
+impl StreamTypeMetrics {
+    /// Counts a request read at `started` and answered now.
+    pub fn observe(&self, started: Instant) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.request_duration.observe(started.elapsed());
+    }
+
+    /// Counts an error if `result` is one, and passes it on.
+    pub fn check<T>(&self, result: Result<T, ProtonError>) -> Result<T, ProtonError> {
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Connections from the clients in one experiment variant, to compare the
+/// variants against each other.
+#[derive(Debug, Default)]
+pub struct ExperimentMetrics {
+    pub connections: AtomicU64,
+    /// Connections whose streams ended in an error
+    pub errors: AtomicU64,
+}
+
+/// How a metric's series behave, which decides how dashboards chart it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl fmt::Display for MetricKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricKind::Counter => write!(f, "counter"),
+            MetricKind::Gauge => write!(f, "gauge"),
+            MetricKind::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+/// A metric the server exports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricDef {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    /// Label the series are split by, if any
+    pub label: Option<&'static str>,
+}
+
+impl MetricDef {
+    const fn new(name: &'static str, kind: MetricKind, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            label: None,
+        }
+    }
+
+    const fn by(self, label: &'static str) -> Self {
+        Self {
+            label: Some(label),
+            ..self
+        }
+    }
+
+    fn header(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+    }
+}
+
+use MetricKind::{Counter, Gauge};
+
+const CERT_DAYS_UNTIL_EXPIRY: MetricDef = MetricDef::new(
+    "proton_cert_days_until_expiry",
+    Gauge,
+    "Days until the first certificate of the server chain expires",
+);
+const CONNECTIONS_REJECTED_ACCESS: MetricDef = MetricDef::new(
+    "proton_connections_rejected_access_total",
+    Counter,
+    "Connection attempts rejected by the source address access list",
+);
+const CONNECTIONS_RATE_LIMITED: MetricDef = MetricDef::new(
+    "proton_connections_rate_limited_total",
+    Counter,
+    "Connection attempts refused by the per source rate limiter",
+);
+const SOURCES_BANNED: MetricDef = MetricDef::new(
+    "proton_sources_banned",
+    Gauge,
+    "Source addresses currently banned by the rate limiter",
+);
+const CONNECTIONS_REFUSED_HANDSHAKES: MetricDef = MetricDef::new(
+    "proton_connections_refused_handshake_limit_total",
+    Counter,
+    "Connection attempts refused because too many handshakes were in flight",
+);
+const HANDSHAKES_IN_FLIGHT: MetricDef = MetricDef::new(
+    "proton_handshakes_in_flight",
+    Gauge,
+    "Handshakes currently in progress",
+);
+const HANDSHAKE_DURATION: MetricDef = MetricDef::new(
+    "proton_handshake_duration_seconds",
+    MetricKind::Histogram,
+    "Time taken by completed QUIC/TLS handshakes",
+);
+const HANDSHAKE_FAILURES: MetricDef = MetricDef::new(
+    "proton_handshake_failures_total",
+    Counter,
+    "Handshakes that did not complete, by cause",
+)
+.by("cause");
+const CONNECTIONS_ACCEPTED: MetricDef = MetricDef::new(
+    "proton_connections_accepted_total",
+    Counter,
+    "Connections admitted after their handshake and HELLO",
+);
+const CONNECTIONS_REJECTED_ADMISSION: MetricDef = MetricDef::new(
+    "proton_connections_rejected_admission_total",
+    Counter,
+    "Connections closed after the HELLO because the server was at capacity or the client was already connected",
+);
+const CONNECTION_TASKS: MetricDef = MetricDef::new(
+    "proton_connection_tasks",
+    Gauge,
+    "Connection tasks currently running",
+);
+const CONNECTIONS_ENDED: MetricDef = MetricDef::new(
+    "proton_connections_ended_total",
+    Counter,
+    "Connection tasks that ended, by outcome",
+)
+.by("outcome");
+const CONNECTION_MIGRATIONS: MetricDef = MetricDef::new(
+    "proton_connection_migrations_total",
+    Counter,
+    "Address changes of live connections, by connection migration or NAT rebinding",
+);
+const CONNECTIONS_ACTIVE: MetricDef = MetricDef::new(
+    "proton_connections_active",
+    Gauge,
+    "Registered connections that are not parked",
+);
+const CONNECTIONS_PARKED: MetricDef = MetricDef::new(
+    "proton_connections_parked",
+    Gauge,
+    "Registered connections parked for being idle",
+);
+const CONNECTIONS_EXPIRED: MetricDef = MetricDef::new(
+    "proton_connections_expired_total",
+    Counter,
+    "Connections sent GOAWAY for reaching the maximum connection age",
+);
+const STREAM_TIMEOUTS: MetricDef = MetricDef::new(
+    "proton_stream_timeouts_total",
+    Counter,
+    "Connections closed because a stream read or write timed out",
+);
+const STREAMS_OPENED: MetricDef = MetricDef::new(
+    "proton_streams_opened_total",
+    Counter,
+    "Streams opened by clients, by stream type",
+)
+.by("stream");
+const STREAM_REQUESTS: MetricDef = MetricDef::new(
+    "proton_stream_requests_total",
+    Counter,
+    "Requests answered, or reads handled on byte streams, by stream type",
+)
+.by("stream");
+const STREAM_ERRORS: MetricDef = MetricDef::new(
+    "proton_stream_errors_total",
+    Counter,
+    "Streams that ended in an error, by stream type",
+)
+.by("stream");
+const STREAM_STALLS: MetricDef = MetricDef::new(
+    "proton_stream_stalls_total",
+    Counter,
+    "Writes blocked past the stall timeout on a client that stopped reading, by stream type",
+)
+.by("stream");
+const STREAM_REQUEST_DURATION: MetricDef = MetricDef::new(
+    "proton_stream_request_duration_seconds",
+    MetricKind::Histogram,
+    "Time from reading a request to writing its response, by stream type",
+)
+.by("stream");
+const ACKS_SENT: MetricDef = MetricDef::new(
+    "proton_acks_sent_total",
+    Counter,
+    "Event acknowledgements written to clients",
+);
+const DEDUPE_LOOKUPS: MetricDef = MetricDef::new(
+    "proton_dedupe_lookups_total",
+    Counter,
+    "Event ids looked up among the acks of recently acknowledged events",
+);
+const DEDUPE_HITS: MetricDef = MetricDef::new(
+    "proton_dedupe_hits_total",
+    Counter,
+    "Retransmitted events acknowledged again instead of processed",
+);
+const DICTIONARIES_TRAINED: MetricDef = MetricDef::new(
+    "proton_compression_dictionaries_trained_total",
+    Counter,
+    "Compression dictionaries trained on sampled event payloads",
+);
+const COMPRESSED_PAYLOAD_BYTES: MetricDef = MetricDef::new(
+    "proton_compressed_payload_bytes_total",
+    Counter,
+    "Event payload bytes received compressed with a dictionary",
+);
+const DECOMPRESSED_PAYLOAD_BYTES: MetricDef = MetricDef::new(
+    "proton_decompressed_payload_bytes_total",
+    Counter,
+    "Bytes the dictionary compressed event payloads decompressed to",
+);
+const EXPERIMENT_CONNECTIONS: MetricDef = MetricDef::new(
+    "proton_experiment_connections_total",
+    Counter,
+    "Connections admitted, by the experiment variant the client declared",
+)
+.by("variant");
+const EXPERIMENT_ERRORS: MetricDef = MetricDef::new(
+    "proton_experiment_errors_total",
+    Counter,
+    "Connections whose streams ended in an error, by experiment variant",
+)
+.by("variant");
+const EVENTS_FORWARDED: MetricDef = MetricDef::new(
+    "proton_events_forwarded_total",
+    Counter,
+    "Events forwarded to event sinks, once per sink",
+);
+const SINK_ERRORS: MetricDef = MetricDef::new(
+    "proton_sink_errors_total",
+    Counter,
+    "Events an event sink failed to forward",
+);
+const SINK_EVENTS_DROPPED: MetricDef = MetricDef::new(
+    "proton_sink_events_dropped_total",
+    Counter,
+    "Accepted events dropped because the event sink queue was full",
+);
+const REORDER_LATE: MetricDef = MetricDef::new(
+    "proton_reorder_late_total",
+    Counter,
+    "Events not delivered because they arrived after the reorder window",
+);
+const REORDER_SKIPPED: MetricDef = MetricDef::new(
+    "proton_reorder_skipped_total",
+    Counter,
+    "Event ids the reorder buffer stopped waiting for",
+);
+const ALLOCATIONS: MetricDef = MetricDef::new(
+    "proton_allocations_total",
+    Counter,
+    "Heap allocations made by the process",
+);
+const FRAMES_PROCESSED: MetricDef = MetricDef::new(
+    "proton_frames_processed_total",
+    Counter,
+    "Request frames processed, to compare against allocations",
+);
+
+/// Every metric the server exports, in the order they are rendered. The
+/// allocation metrics are only exported with the `alloc-audit` feature.
+pub const METRICS: &[MetricDef] = &[
+    CERT_DAYS_UNTIL_EXPIRY,
+    CONNECTIONS_REJECTED_ACCESS,
+    CONNECTIONS_RATE_LIMITED,
+    SOURCES_BANNED,
+    CONNECTIONS_REFUSED_HANDSHAKES,
+    HANDSHAKES_IN_FLIGHT,
+    HANDSHAKE_DURATION,
+    HANDSHAKE_FAILURES,
+    CONNECTIONS_ACCEPTED,
+    CONNECTIONS_REJECTED_ADMISSION,
+    CONNECTION_TASKS,
+    CONNECTIONS_ENDED,
+    CONNECTION_MIGRATIONS,
+    CONNECTIONS_ACTIVE,
+    CONNECTIONS_PARKED,
+    CONNECTIONS_EXPIRED,
+    STREAM_TIMEOUTS,
+    STREAMS_OPENED,
+    STREAM_REQUESTS,
+    STREAM_ERRORS,
+    STREAM_STALLS,
+    STREAM_REQUEST_DURATION,
+    ACKS_SENT,
+    DEDUPE_LOOKUPS,
+    DEDUPE_HITS,
+    DICTIONARIES_TRAINED,
+    COMPRESSED_PAYLOAD_BYTES,
+    DECOMPRESSED_PAYLOAD_BYTES,
+    EXPERIMENT_CONNECTIONS,
+    EXPERIMENT_ERRORS,
+    EVENTS_FORWARDED,
+    SINK_ERRORS,
+    SINK_EVENTS_DROPPED,
+    REORDER_LATE,
+    REORDER_SKIPPED,
+    ALLOCATIONS,
+    FRAMES_PROCESSED,
+];
+
+/// A handshake or connection that failed, as listed on the dashboard.
+#[derive(Debug, Clone)]
+pub struct RecentError {
+    pub at: SystemTime,
+    pub remote: SocketAddr,
+    pub message: String,
+}
+
+/// Server side metrics, rendered in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    pub cert_days_until_expiry: AtomicI64,
+    pub connections_rejected_access: AtomicU64,
+    pub connections_rate_limited: AtomicU64,
+    pub sources_banned: AtomicI64,
+    pub connections_refused_handshakes: AtomicU64,
+    pub handshakes_in_flight: AtomicI64,
+    pub handshake_duration: HandshakeHistogram,
+    handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    /// Connections admitted once their HELLO was received
+    pub connections_accepted: AtomicU64,
+    /// Connections refused at admission, for capacity or as duplicates
+    pub connections_rejected_admission: AtomicU64,
+    /// Connection tasks currently running, from accept until cleanup
+    pub connection_tasks: AtomicI64,
+    connection_outcomes: [AtomicU64; ConnectionOutcome::ALL.len()],
+    recent_errors: Mutex<VecDeque<RecentError>>,
+    /// Times a live connection's client address changed
+    pub connection_migrations: AtomicU64,
+    /// Registered connections, by whether they are parked for being idle
+    pub connections_active: AtomicI64,
+    pub connections_parked: AtomicI64,
+    /// Connections drained for reaching the maximum connection age
+    pub connections_expired: AtomicU64,
+    /// Connections closed because a stream operation timed out
+    pub stream_timeouts: AtomicU64,
+    /// Event acknowledgements written, including repeated and quota acks
+    pub acks_sent: AtomicU64,
+    /// Event ids checked against recent acks, and those found there
+    pub dedupe_lookups: AtomicU64,
+    pub dedupe_hits: AtomicU64,
+    pub dictionaries_trained: AtomicU64,
+    /// Event payloads compressed with a dictionary, as received and once
+    /// decompressed
+    pub compressed_payload_bytes: AtomicU64,
+    pub decompressed_payload_bytes: AtomicU64,
+    /// Events forwarded to event sinks, counted once per sink
+    pub events_forwarded: AtomicU64,
+    pub sink_errors: AtomicU64,
+    /// Accepted events not forwarded because the sink queue was full
+    pub sink_events_dropped: AtomicU64,
+    /// Events not delivered by a reorder buffer because they arrived after
+    /// a later event had been delivered
+    pub reorder_late: AtomicU64,
+    /// Event ids a reorder buffer stopped waiting for
+    pub reorder_skipped: AtomicU64,
+    streams: RwLock<BTreeMap<String, Arc<StreamTypeMetrics>>>,
+    experiments: RwLock<BTreeMap<String, Arc<ExperimentMetrics>>>,
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_handshake_failure(&self, cause: HandshakeFailure) {
+        self.handshake_failures[cause as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_outcome(&self, outcome: ConnectionOutcome) {
+        self.connection_outcomes[outcome as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, remote: SocketAddr, message: String) {
+        let mut errors = self.recent_errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            at: SystemTime::now(),
+            remote,
+            message,
+        });
+    }
+
+    /// Metrics for the streams of type `name`, e.g. `event`.
+    pub fn stream(&self, name: &str) -> Arc<StreamTypeMetrics> {
+        if let Some(metrics) = self.streams.read().unwrap().get(name) {
+            return Arc::clone(metrics);
+        }
+        let mut streams = self.streams.write().unwrap();
+        Arc::clone(streams.entry(name.to_string()).or_default())
+    }
+
+    /// Metrics for every stream type seen or registered, by name.
+    pub fn streams(&self) -> Vec<(String, Arc<StreamTypeMetrics>)> {
+        self.streams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, metrics)| (name.clone(), Arc::clone(metrics)))
+            .collect()
+    }
+
+    /// Metrics for the clients in experiment variant `variant`, as declared
+    /// in their HELLO, e.g. `codec/control`.
+    pub fn experiment(&self, variant: &str) -> Arc<ExperimentMetrics> {
+        let valid = !variant.is_empty()
+            && variant.len() <= MAX_VARIANT_LEN
+            && variant
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_./".contains(&b));
+        let variant = if valid { variant } else { "other" };
+        if let Some(metrics) = self.experiments.read().unwrap().get(variant) {
+            return Arc::clone(metrics);
+        }
+        let mut experiments = self.experiments.write().unwrap();
+        let variant = if experiments.len() < MAX_EXPERIMENT_VARIANTS {
+            variant
+        } else {
+            "other"
+        };
+        Arc::clone(experiments.entry(variant.to_string()).or_default())
+    }
+
+    /// The last few handshake and connection errors, oldest first.
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        self.recent_errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Connection tasks that have ended, by outcome.
+    pub fn connection_outcomes(&self) -> Vec<(ConnectionOutcome, u64)> {
+        ConnectionOutcome::ALL
+            .iter()
+            .map(|&o| {
+                (
+                    o,
+                    self.connection_outcomes[o as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    /// The main counters on one line, for `--metrics-addr log`.
+    pub fn summary(&self) -> String {
+        let streams = self.streams();
+        let total = |value: fn(&StreamTypeMetrics) -> &AtomicU64| -> u64 {
+            streams
+                .iter()
+                .map(|(_, m)| value(m).load(Ordering::Relaxed))
+                .sum()
+        };
+        let rejected = [
+            &self.connections_rejected_access,
+            &self.connections_rate_limited,
+            &self.connections_refused_handshakes,
+            &self.connections_rejected_admission,
+        ]
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed))
+        .sum::<u64>();
+        let timeouts = self.stream_timeouts.load(Ordering::Relaxed)
+            + self.handshake_failures[HandshakeFailure::Timeout as usize].load(Ordering::Relaxed);
+        format!(
+            "connections accepted={} rejected={} active={} requests={} acks={} timeouts={} stream_errors={}",
+            self.connections_accepted.load(Ordering::Relaxed),
+            rejected,
+            self.connection_tasks.load(Ordering::Relaxed),
+            total(|m| &m.requests),
+            self.acks_sent.load(Ordering::Relaxed),
+            timeouts,
+            total(|m| &m.errors),
+        )
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        gauge(
+            &mut out,
+            &CERT_DAYS_UNTIL_EXPIRY,
+            self.cert_days_until_expiry.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &CONNECTIONS_REJECTED_ACCESS,
+            self.connections_rejected_access.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &CONNECTIONS_RATE_LIMITED,
+            self.connections_rate_limited.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            &SOURCES_BANNED,
+            self.sources_banned.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &CONNECTIONS_REFUSED_HANDSHAKES,
+            self.connections_refused_handshakes.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            &HANDSHAKES_IN_FLIGHT,
+            self.handshakes_in_flight.load(Ordering::Relaxed),
+        );
+        HANDSHAKE_DURATION.header(&mut out);
+        self.handshake_duration
+            .render_series(&mut out, HANDSHAKE_DURATION.name, "");
+        labelled(
+            &mut out,
+            &HANDSHAKE_FAILURES,
+            HandshakeFailure::ALL.iter().map(|&cause| {
+                (
+                    cause.label(),
+                    self.handshake_failures[cause as usize].load(Ordering::Relaxed),
+                )
+            }),
+        );
+        counter(
+            &mut out,
+            &CONNECTIONS_ACCEPTED,
+            self.connections_accepted.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &CONNECTIONS_REJECTED_ADMISSION,
+            self.connections_rejected_admission.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            &CONNECTION_TASKS,
+            self.connection_tasks.load(Ordering::Relaxed),
+        );
+        labelled(
+            &mut out,
+            &CONNECTIONS_ENDED,
+            self.connection_outcomes()
+                .into_iter()
+                .map(|(outcome, value)| (outcome.label(), value)),
+        );
+        counter(
+            &mut out,
+            &CONNECTION_MIGRATIONS,
+            self.connection_migrations.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            &CONNECTIONS_ACTIVE,
+            self.connections_active.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            &CONNECTIONS_PARKED,
+            self.connections_parked.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &CONNECTIONS_EXPIRED,
+            self.connections_expired.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &STREAM_TIMEOUTS,
+            self.stream_timeouts.load(Ordering::Relaxed),
+        );
+        let streams = self.streams();
+        for (def, value) in [
+            (
+                &STREAMS_OPENED,
+                (|m| &m.opened) as fn(&StreamTypeMetrics) -> &AtomicU64,
+            ),
+            (&STREAM_REQUESTS, |m| &m.requests),
+            (&STREAM_ERRORS, |m| &m.errors),
+            (&STREAM_STALLS, |m| &m.stalls),
+        ] {
+            labelled(
+                &mut out,
+                def,
+                streams
+                    .iter()
+                    .map(|(name, m)| (name.as_str(), value(m).load(Ordering::Relaxed))),
+            );
+        }
+        STREAM_REQUEST_DURATION.header(&mut out);
+        for (name, m) in &streams {
+            m.request_duration.render_series(
+                &mut out,
+                STREAM_REQUEST_DURATION.name,
+                &format!("stream=\"{}\",", name),
+            );
+        }
+        counter(&mut out, &ACKS_SENT, self.acks_sent.load(Ordering::Relaxed));
+        counter(
+            &mut out,
+            &DEDUPE_LOOKUPS,
+            self.dedupe_lookups.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &DEDUPE_HITS,
+            self.dedupe_hits.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &DICTIONARIES_TRAINED,
+            self.dictionaries_trained.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &COMPRESSED_PAYLOAD_BYTES,
+            self.compressed_payload_bytes.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &DECOMPRESSED_PAYLOAD_BYTES,
+            self.decompressed_payload_bytes.load(Ordering::Relaxed),
+        );
+        let experiments = self.experiments.read().unwrap();
+        for (def, value) in [
+            (
+                &EXPERIMENT_CONNECTIONS,
+                (|m| &m.connections) as fn(&ExperimentMetrics) -> &AtomicU64,
+            ),
+            (&EXPERIMENT_ERRORS, |m| &m.errors),
+        ] {
+            labelled(
+                &mut out,
+                def,
+                experiments
+                    .iter()
+                    .map(|(variant, m)| (variant.as_str(), value(m).load(Ordering::Relaxed))),
+            );
+        }
+        drop(experiments);
+        counter(
+            &mut out,
+            &EVENTS_FORWARDED,
+            self.events_forwarded.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &SINK_ERRORS,
+            self.sink_errors.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &SINK_EVENTS_DROPPED,
+            self.sink_events_dropped.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &REORDER_LATE,
+            self.reorder_late.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            &REORDER_SKIPPED,
+            self.reorder_skipped.load(Ordering::Relaxed),
+        );
+        #[cfg(feature = "alloc-audit")]
+        {
+            use crate::proton::alloc;
+            counter(&mut out, &ALLOCATIONS, alloc::allocations());
+            counter(&mut out, &FRAMES_PROCESSED, alloc::frames());
+        }
+        out
+    }
+}
+
+fn gauge(out: &mut String, def: &MetricDef, value: i64) {
+    def.header(out);
+    let _ = writeln!(out, "{} {}", def.name, value);
+}
+
+fn counter(out: &mut String, def: &MetricDef, value: u64) {
+    def.header(out);
+    let _ = writeln!(out, "{} {}", def.name, value);
+}
+
+// One series per value of the metric's label
+fn labelled<'a>(out: &mut String, def: &MetricDef, series: impl Iterator<Item = (&'a str, u64)>) {
+    def.header(out);
+    let label = def.label.unwrap_or_default();
+    for (value_label, value) in series {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            def.name, label, value_label, value
+        );
+    }
+}
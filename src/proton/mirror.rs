@@ -0,0 +1,108 @@
+use crate::proton::client::{ProtonClient, ProtonConnection};
+use crate::proton::profile::spawn_named;
+use crate::proton::Frame;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Events waiting to be mirrored; further events are dropped until the
+/// secondary server catches up.
+pub const MIRROR_QUEUE_LEN: usize = 1024;
+
+#[derive(Debug, Default)]
+struct MirrorCounters {
+    mirrored: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// How mirroring to the secondary server is going, as reported in the
+/// connection's stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MirrorStats {
+    pub addr: SocketAddr,
+    /// Events the secondary server acknowledged
+    pub mirrored: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+    /// Events lost to a failed connection or request
+    pub failed: u64,
+}
+
+impl fmt::Display for MirrorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} mirrored, {} dropped, {} failed",
+            self.addr, self.mirrored, self.dropped, self.failed
+        )
+    }
+}
+
+// Copies events to a secondary server from a task of its own, so the
+// primary connection never waits on it
+pub(crate) struct Mirror {
+    addr: SocketAddr,
+    tx: mpsc::Sender<Frame>,
+    counters: Arc<MirrorCounters>,
+}
+
+impl Mirror {
+    // Start mirroring to `addr` with `client`, which must not mirror itself.
+    // The task ends once the mirror is dropped.
+    pub(crate) fn spawn(mut client: ProtonClient, addr: SocketAddr) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Frame>(MIRROR_QUEUE_LEN);
+        let counters = Arc::new(MirrorCounters::default());
+        let task_counters = Arc::clone(&counters);
+        spawn_named("mirror", async move {
+            let mut connection: Option<ProtonConnection> = None;
+            while let Some(event) = rx.recv().await {
+                if connection.is_none() {
+                    match client.connect(addr, Some(Duration::ZERO)).await {
+                        Ok(c) => connection = Some(c),
+                        Err(e) => {
+                            warn!("Mirror to {} unavailable: {}", addr, e);
+                            task_counters.failed.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                    }
+                }
+                let Some(ref mut mirror) = connection else {
+                    continue;
+                };
+                match mirror.send_event_frame(event).await {
+                    Ok(_) => task_counters.mirrored.fetch_add(1, Ordering::Relaxed),
+                    Err(_) => {
+                        // Reconnect for the next event
+                        connection = None;
+                        task_counters.failed.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+            }
+            if let Some(ref mut mirror) = connection {
+                mirror.close().await;
+            }
+        });
+        Self { addr, tx, counters }
+    }
+
+    // Queue `event` for the secondary server, dropping it if the queue is full
+    pub(crate) fn send(&self, event: &Frame) {
+        if self.tx.try_send(event.clone()).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> MirrorStats {
+        MirrorStats {
+            addr: self.addr,
+            mirrored: self.counters.mirrored.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::info;
+
+pub use crate::proton::wire::RESET_BY_MISBEHAVIOR;
+
+/// How long to hold back a response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayDistribution {
+    /// Always the same delay, e.g. `100ms`
+    Fixed(Duration),
+    /// Uniformly between two delays, e.g. `10ms..200ms`
+    Uniform(Duration, Duration),
+    /// Exponentially distributed around a mean, e.g. `exp:50ms`
+    Exponential(Duration),
+}
+
+impl DelayDistribution {
+    fn sample(&self, rng: &SystemRandom) -> Duration {
+        match *self {
+            DelayDistribution::Fixed(delay) => delay,
+            DelayDistribution::Uniform(min, max) => min + (max - min).mul_f64(uniform(rng)),
+            DelayDistribution::Exponential(mean) => {
+                // Inverse transform; 1 - u is never zero
+                mean.mul_f64(-(1.0 - uniform(rng)).ln())
+            }
+        }
+    }
+}
+
+impl FromStr for DelayDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid delay '{}', expected e.g. 100ms, 10ms..200ms or exp:50ms",
+                s
+            )
+        };
+        if let Some(mean) = s.strip_prefix("exp:") {
+            return parse_duration(mean)
+                .map(DelayDistribution::Exponential)
+                .ok_or_else(invalid);
+        }
+        match s.split_once("..") {
+            Some((min, max)) => match (parse_duration(min), parse_duration(max)) {
+                (Some(min), Some(max)) if min <= max => Ok(DelayDistribution::Uniform(min, max)),
+                _ => Err(invalid()),
+            },
+            None => parse_duration(s)
+                .map(DelayDistribution::Fixed)
+                .ok_or_else(invalid),
+        }
+    }
+}
+
+impl fmt::Display for DelayDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelayDistribution::Fixed(delay) => write!(f, "{}ms", delay.as_millis()),
+            DelayDistribution::Uniform(min, max) => {
+                write!(f, "{}ms..{}ms", min.as_millis(), max.as_millis())
+            }
+            DelayDistribution::Exponential(mean) => write!(f, "exp:{}ms", mean.as_millis()),
+        }
+    }
+}
+
+// `500us`, `200ms` or `2s`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let i = s.find(|c: char| !c.is_ascii_digit())?;
+    let value = s[..i].parse::<u64>().ok()?;
+    match &s[i..] {
+        "us" => Some(Duration::from_micros(value)),
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        _ => None,
+    }
+}
+
+// Uniform in [0, 1)
+fn uniform(rng: &SystemRandom) -> f64 {
+    let mut bytes = [0u8; 8];
+    let _ = rng.fill(&mut bytes);
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Ways the server can deliberately misbehave, so client resilience can be
+/// tested against defined failure modes. Everything is off by default.
+///
+/// Written and parsed as `key=value` pairs separated by `&`, e.g.
+/// `drop_ack_every=5&commit_delay=10ms..200ms`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Misbehavior {
+    /// Leave every Nth event unacknowledged; 0 never does
+    pub drop_ack_every: u64,
+    /// Hold back each state commit response by a delay from this distribution
+    pub commit_delay: Option<DelayDistribution>,
+    /// Answer every Nth event or state commit with the wrong id; 0 never does
+    pub wrong_id_every: u64,
+    /// Chance per event or state commit of resetting its stream instead of
+    /// answering, from 0 to 1
+    pub reset_probability: f64,
+}
+
+impl Misbehavior {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Sets one setting by its key; `off` turns it off.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let off = value == "off";
+        let count = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} must be a count, got '{}'", key, value))
+        };
+        match key {
+            "drop_ack_every" => self.drop_ack_every = if off { 0 } else { count()? },
+            "wrong_id_every" => self.wrong_id_every = if off { 0 } else { count()? },
+            "commit_delay" => self.commit_delay = if off { None } else { Some(value.parse()?) },
+            "reset_probability" => {
+                self.reset_probability = match value.parse::<f64>() {
+                    _ if off => 0.0,
+                    Ok(p) if (0.0..=1.0).contains(&p) => p,
+                    _ => return Err(format!("reset_probability must be 0 to 1, got '{}'", value)),
+                }
+            }
+            _ => return Err(format!("unknown misbehavior '{}'", key)),
+        }
+        Ok(())
+    }
+
+    /// Applies `key=value` pairs separated by `&`, leaving other settings
+    /// as they are. Nothing changes if any pair is invalid.
+    pub fn update(&mut self, pairs: &str) -> Result<(), String> {
+        let mut updated = self.clone();
+        for pair in pairs.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", pair))?;
+            updated.set(key, value)?;
+        }
+        *self = updated;
+        Ok(())
+    }
+}
+
+impl FromStr for Misbehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut misbehavior = Misbehavior::default();
+        misbehavior.update(s)?;
+        Ok(misbehavior)
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop_ack_every={}&wrong_id_every={}&commit_delay=",
+            self.drop_ack_every, self.wrong_id_every
+        )?;
+        match self.commit_delay {
+            Some(delay) => write!(f, "{}", delay)?,
+            None => write!(f, "off")?,
+        }
+        write!(f, "&reset_probability={}", self.reset_probability)
+    }
+}
+
+/// The server's current misbehavior, shared by all connections and
+/// changeable at runtime through the admin endpoint.
+#[derive(Debug)]
+pub struct MisbehaviorControl {
+    config: RwLock<Misbehavior>,
+    rng: SystemRandom,
+    acks: AtomicU64,
+    responses: AtomicU64,
+}
+
+impl MisbehaviorControl {
+    pub fn new(config: Misbehavior) -> Self {
+        Self {
+            config: RwLock::new(config),
+            rng: SystemRandom::new(),
+            acks: AtomicU64::new(0),
+            responses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self) -> Misbehavior {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: Misbehavior) {
+        if config.is_enabled() {
+            info!("Server now misbehaves: {}", config);
+        } else {
+            info!("Server misbehavior turned off");
+        }
+        *self.config.write().unwrap() = config;
+    }
+
+    // Whether to leave this event ack unsent
+    pub(crate) fn drop_ack(&self) -> bool {
+        let every = self.config.read().unwrap().drop_ack_every;
+        every > 0 && (self.acks.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(every)
+    }
+
+    // Wrong id to answer with instead of `id`, if this response is to be one
+    pub(crate) fn wrong_id(&self, id: u32) -> u32 {
+        let every = self.config.read().unwrap().wrong_id_every;
+        if every > 0 && (self.responses.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(every) {
+            id.wrapping_add(1000)
+        } else {
+            id
+        }
+    }
+
+    pub(crate) fn commit_delay(&self) -> Option<Duration> {
+        let delay = self.config.read().unwrap().commit_delay?;
+        Some(delay.sample(&self.rng))
+    }
+
+    pub(crate) fn reset_stream(&self) -> bool {
+        let p = self.config.read().unwrap().reset_probability;
+        p > 0.0 && uniform(&self.rng) < p
+    }
+}
@@ -0,0 +1,81 @@
+use crate::proton::ProtonError;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// A file mapped read-only into memory. Payload chunks are handed to quinn as
+/// slices of the mapping, so file bytes are not copied into user space
+/// buffers before being queued for sending.
+#[derive(Debug)]
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and lives until drop
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    /// Maps the file at `path`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified, by this or any other
+    /// process, until the mapping is dropped. Truncating it makes reads
+    /// through [`AsRef`] fault, and modifying it changes bytes behind a
+    /// shared slice.
+    pub unsafe fn open(path: &Path) -> Result<Self, ProtonError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // mmap rejects empty mappings
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // SAFETY: mapping a file we opened for reading; the result is checked
+        // below and the mapping is released in Drop. The caller keeps the
+        // file unchanged while mapped.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Payloads are read front to back once; failure only loses readahead
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: ptr maps len readable bytes for the lifetime of self, and
+        // `open`'s caller guarantees the file is not changed underneath them
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
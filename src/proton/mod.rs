@@ -1,21 +1,44 @@
+use crate::proton::wire::{decode_commit, encode_commit, MESSAGE_VERSION};
+use crate::proton::wire::{
+    CLOSE_AT_CAPACITY, CLOSE_AUTH_FAILED, CLOSE_BY_OPERATOR, CLOSE_DUPLICATE, CLOSE_HEARTBEAT_LOST,
+    CLOSE_MAX_AGE, CLOSE_NORMAL, CLOSE_PEER_STALLED, CLOSE_PREEMPTED, CLOSE_PROTOCOL_VIOLATION,
+    CLOSE_REPLACED, CLOSE_SERVER_RESTARTING, CLOSE_SETUP_TIMEOUT, CLOSE_STREAM_ACCEPT,
+    CLOSE_STREAM_ERROR, CLOSE_STREAM_SETUP, CLOSE_STREAM_TIMEOUT, MAX_FRAME_LEN,
+};
+use bincode::Options;
+use quinn::{RecvStream, VarInt};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
 use std::time::Duration;
+use tokio::time::timeout;
 
-pub const STREAM_EVENT: u8 = 1;
-pub const STREAM_STATE_COMMIT: u8 = 2;
-pub const STREAM_ACTION: u8 = 3;
-pub const MAX_BIDIRECTIONAL_STREAMS: u32 = 3;
+// Event, state commit, action and control streams
+pub const MAX_BIDIRECTIONAL_STREAMS: u32 = 4;
+// Large payloads in flight at once, each on a stream of its own
+pub const MAX_PAYLOAD_STREAMS: u32 = 4;
+// Streams of each application registered type open at once
+pub const MAX_STREAMS_PER_TYPE: u32 = 4;
 pub const MAX_CONNECTIONS: u32 = 1;
 
-// Connect retry delay
+// Connect retries, backing off exponentially from the retry delay
 pub const MAX_CONNECT_RETRIES: u32 = 5;
 pub const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(2);
+pub const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(30);
+pub const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Protocol timeouts
 pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
 pub const STARTUP_DELAY: Duration = Duration::from_secs(10); // 2 * IDLE_TIMEOUT
 pub const STREAM_TIMEOUT: Duration = Duration::from_secs(300); // 5 minutes
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Handshakes in flight at once; further attempts are refused
+pub const MAX_CONCURRENT_HANDSHAKES: u32 = 64;
+
+// Certificate expiry monitoring
+pub const CERT_EXPIRY_WARNING_DAYS: i64 = 30;
+pub const CERT_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
 
 #[derive(Debug)]
 pub enum ProtonError {
@@ -23,6 +46,24 @@ pub enum ProtonError {
     ConnectionError,
     InvalidStream,
     Timeout,
+    CertificateExpired,
+    AuthenticationFailed,
+    QuotaExceeded,
+    AbortRefused,
+    /// The peer deviated from the protocol; see `ProtocolMode`
+    ProtocolViolation(violation::Violation),
+    /// Failed locally because the client's circuit breaker is open
+    CircuitOpen,
+    /// The client is disconnected and its offline queue refused the event
+    QueueFull,
+    /// The server refused the connection attempt, e.g. because it was at
+    /// its connection limit or already had this client connected
+    ConnectionRefused,
+    /// The peer closed the connection, for the reason its close code gives
+    ClosedByPeer(CloseReason),
+    /// The TLS handshake failed, with the reason given by whichever side
+    /// rejected it, e.g. "client certificate revoked: KeyCompromise"
+    HandshakeRejected(String),
 }
 
 impl fmt::Display for ProtonError {
@@ -32,6 +73,18 @@ impl fmt::Display for ProtonError {
             ProtonError::ConnectionError => write!(f, "Connection error"),
             ProtonError::InvalidStream => write!(f, "Invalid stream"),
             ProtonError::Timeout => write!(f, "Operation timed out"),
+            ProtonError::CertificateExpired => write!(f, "Certificate has expired"),
+            ProtonError::AuthenticationFailed => write!(f, "Peer authentication failed"),
+            ProtonError::QuotaExceeded => write!(f, "Tenant quota exceeded"),
+            ProtonError::AbortRefused => write!(f, "State commit abort refused"),
+            ProtonError::ProtocolViolation(v) => write!(f, "{}", v),
+            ProtonError::CircuitOpen => write!(f, "Circuit breaker open"),
+            ProtonError::QueueFull => write!(f, "Offline event queue full"),
+            ProtonError::ConnectionRefused => write!(f, "Connection refused by the server"),
+            ProtonError::ClosedByPeer(reason) => write!(f, "Connection closed by peer: {}", reason),
+            ProtonError::HandshakeRejected(reason) => {
+                write!(f, "TLS handshake rejected: {}", reason)
+            }
         }
     }
 }
@@ -81,8 +134,642 @@ impl From<quinn::ReadExactError> for ProtonError {
     }
 }
 
+/// Why a connection was closed, as carried by the application error code of
+/// QUIC's CONNECTION_CLOSE frame. The codes are the `CLOSE_*` constants in
+/// `wire` and never change meaning; the close frame's reason bytes carry the
+/// details, e.g. which rule a protocol violation broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// The connection was done with, e.g. the client closed it
+    Normal,
+    StreamSetup,
+    StreamAccept,
+    SetupTimeout,
+    StreamTimeout,
+    StreamError,
+    AuthFailed,
+    /// A higher priority client took the connection's slot
+    Preempted,
+    /// The server is at its connection limit and nothing could be preempted
+    AtCapacity,
+    /// An operator closed the connection through the control API
+    ByOperator,
+    /// A newer connection from the same client took over
+    Replaced,
+    /// The client already has as many connections as the server allows
+    Duplicate,
+    ProtocolViolation,
+    PeerStalled,
+    HeartbeatLost,
+    /// Reconnecting reaches the process that took over
+    ServerRestarting,
+    /// The connection outlived the server's maximum connection age;
+    /// reconnecting starts a fresh one
+    MaxAge,
+    /// A code this version does not know, e.g. from a newer peer
+    Unknown(u32),
+}
+
+impl CloseReason {
+    const KNOWN: [CloseReason; 17] = [
+        CloseReason::Normal,
+        CloseReason::StreamSetup,
+        CloseReason::StreamAccept,
+        CloseReason::SetupTimeout,
+        CloseReason::StreamTimeout,
+        CloseReason::StreamError,
+        CloseReason::AuthFailed,
+        CloseReason::Preempted,
+        CloseReason::AtCapacity,
+        CloseReason::ByOperator,
+        CloseReason::Replaced,
+        CloseReason::Duplicate,
+        CloseReason::ProtocolViolation,
+        CloseReason::PeerStalled,
+        CloseReason::HeartbeatLost,
+        CloseReason::ServerRestarting,
+        CloseReason::MaxAge,
+    ];
+
+    pub const fn code(self) -> u32 {
+        match self {
+            CloseReason::Normal => CLOSE_NORMAL,
+            CloseReason::StreamSetup => CLOSE_STREAM_SETUP,
+            CloseReason::StreamAccept => CLOSE_STREAM_ACCEPT,
+            CloseReason::SetupTimeout => CLOSE_SETUP_TIMEOUT,
+            CloseReason::StreamTimeout => CLOSE_STREAM_TIMEOUT,
+            CloseReason::StreamError => CLOSE_STREAM_ERROR,
+            CloseReason::AuthFailed => CLOSE_AUTH_FAILED,
+            CloseReason::Preempted => CLOSE_PREEMPTED,
+            CloseReason::AtCapacity => CLOSE_AT_CAPACITY,
+            CloseReason::ByOperator => CLOSE_BY_OPERATOR,
+            CloseReason::Replaced => CLOSE_REPLACED,
+            CloseReason::Duplicate => CLOSE_DUPLICATE,
+            CloseReason::ProtocolViolation => CLOSE_PROTOCOL_VIOLATION,
+            CloseReason::PeerStalled => CLOSE_PEER_STALLED,
+            CloseReason::HeartbeatLost => CLOSE_HEARTBEAT_LOST,
+            CloseReason::ServerRestarting => CLOSE_SERVER_RESTARTING,
+            CloseReason::MaxAge => CLOSE_MAX_AGE,
+            CloseReason::Unknown(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Self {
+        Self::KNOWN
+            .into_iter()
+            .find(|reason| reason.code() == code)
+            .unwrap_or(CloseReason::Unknown(code))
+    }
+
+    /// The reason the peer gave for closing, if it closed the connection
+    /// with an application error code rather than it being lost or closed
+    /// locally. Codes beyond u32 are unknown, as `u32::MAX`.
+    pub fn from_error(error: &quinn::ConnectionError) -> Option<Self> {
+        match error {
+            quinn::ConnectionError::ApplicationClosed(close) => Some(Self::from_code(
+                u32::try_from(close.error_code.into_inner()).unwrap_or(u32::MAX),
+            )),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "normal",
+            CloseReason::StreamSetup => "stream setup failed",
+            CloseReason::StreamAccept => "stream accept failed",
+            CloseReason::SetupTimeout => "stream setup timed out",
+            CloseReason::StreamTimeout => "stream timed out",
+            CloseReason::StreamError => "stream error",
+            CloseReason::AuthFailed => "authentication failed",
+            CloseReason::Preempted => "preempted",
+            CloseReason::AtCapacity => "server at capacity",
+            CloseReason::ByOperator => "closed by operator",
+            CloseReason::Replaced => "replaced",
+            CloseReason::Duplicate => "duplicate client",
+            CloseReason::ProtocolViolation => "protocol violation",
+            CloseReason::PeerStalled => "peer stalled",
+            CloseReason::HeartbeatLost => "heartbeat lost",
+            CloseReason::ServerRestarting => "server restarting",
+            CloseReason::MaxAge => "max connection age",
+            CloseReason::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl From<CloseReason> for VarInt {
+    fn from(reason: CloseReason) -> Self {
+        VarInt::from_u32(reason.code())
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.name(), self.code())
+    }
+}
+
+/// How requests and responses are laid out on the event, state commit and
+/// action streams. The client asks for one in its HELLO; servers that do not
+/// answer with it get fixed frames.
+///
+/// The fixed and length-prefixed layouts are the protocol that deployed
+/// peers, not all written in Rust, speak, so they are hand-encoded. Typed
+/// framing carries versioned [`Message`]s instead and is only used when both
+/// ends agree to it, so existing peers keep their framing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// A bare u32 id, offset or ack per frame
+    #[default]
+    Fixed,
+    /// Every frame is a [`Frame`], so it may carry payload bytes
+    LengthPrefixed,
+    /// Every frame is a [`Message`] of the kind the stream expects
+    Typed,
+}
+
+impl Framing {
+    /// Size of `frame`, sent as a `kind` message, on the wire, not counting
+    /// any headers section.
+    pub fn encoded_len(&self, kind: MessageKind, frame: &Frame) -> usize {
+        match self {
+            Framing::Fixed => 4,
+            Framing::LengthPrefixed => frame.encoded_len(),
+            Framing::Typed => Message::encoded_len(kind, frame),
+        }
+    }
+
+    /// Appends `frame`, sent as a `kind` message, as laid out on the wire.
+    /// Fixed frames and acks have no room for a payload, so one is refused.
+    pub fn encode_into(
+        &self,
+        kind: MessageKind,
+        frame: &Frame,
+        out: &mut impl Extend<u8>,
+    ) -> Result<(), ProtonError> {
+        match self {
+            Framing::Fixed if !frame.payload.is_empty() => {
+                Err(ProtonError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "payloads need length-prefixed framing, which the server did not agree to",
+                )))
+            }
+            Framing::Fixed => {
+                out.extend(frame.id.to_le_bytes());
+                Ok(())
+            }
+            Framing::LengthPrefixed => {
+                check_payload_len(frame.payload.len())?;
+                frame.encode_into(out);
+                Ok(())
+            }
+            Framing::Typed => {
+                let len = Message::encoded_len(kind, frame) - 4;
+                if len > MAX_FRAME_LEN as usize {
+                    return Err(ProtonError::IoError(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} message of {} bytes exceeds the {} byte frame limit",
+                            kind, len, MAX_FRAME_LEN
+                        ),
+                    )));
+                }
+                Message::from_frame(kind, frame)?.encode_into(out);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads one frame laid out this way from `recv`, which must be a
+    /// `kind` message.
+    pub async fn read_from(
+        &self,
+        kind: MessageKind,
+        recv: &mut RecvStream,
+    ) -> Result<Frame, ProtonError> {
+        let mut prefix = [0u8; 4];
+        timeout(STREAM_TIMEOUT, recv.read_exact(&mut prefix)).await??;
+        self.read_rest(kind, recv, prefix).await
+    }
+
+    /// Reads the rest of a frame whose first four bytes were `prefix`: the
+    /// id itself when fixed, the body length otherwise.
+    pub async fn read_rest(
+        &self,
+        kind: MessageKind,
+        recv: &mut RecvStream,
+        prefix: [u8; 4],
+    ) -> Result<Frame, ProtonError> {
+        match self {
+            Framing::Fixed => Ok(Frame::new(u32::from_le_bytes(prefix))),
+            Framing::LengthPrefixed => {
+                let len = check_frame_len(u32::from_le_bytes(prefix))?;
+                let mut body = vec![0u8; len];
+                timeout(STREAM_TIMEOUT, recv.read_exact(&mut body)).await??;
+                let payload = body.split_off(4);
+                Ok(Frame {
+                    id: u32::from_le_bytes(body[..].try_into().unwrap()),
+                    payload,
+                })
+            }
+            Framing::Typed => {
+                let len = check_frame_len(u32::from_le_bytes(prefix))?;
+                let mut body = vec![0u8; len];
+                timeout(STREAM_TIMEOUT, recv.read_exact(&mut body)).await??;
+                Message::decode_body(&body)?.into_frame(kind)
+            }
+        }
+    }
+
+    /// Decodes a frame laid out this way at the start of `buf`, which must be
+    /// a `kind` message, returning it and the number of bytes it took up.
+    pub fn decode(&self, kind: MessageKind, buf: &[u8]) -> Result<(Frame, usize), ProtonError> {
+        match self {
+            Framing::Fixed => {
+                let id = buf
+                    .get(..4)
+                    .ok_or_else(|| invalid_frame("truncated frame".to_string()))?;
+                Ok((Frame::new(u32::from_le_bytes(id.try_into().unwrap())), 4))
+            }
+            Framing::LengthPrefixed => Frame::decode(buf),
+            Framing::Typed => {
+                let (message, len) = Message::decode(buf)?;
+                Ok((message.into_frame(kind)?, len))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::Fixed => write!(f, "fixed"),
+            Framing::LengthPrefixed => write!(f, "length-prefixed"),
+            Framing::Typed => write!(f, "typed"),
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(Framing::Fixed),
+            "length-prefixed" => Ok(Framing::LengthPrefixed),
+            "typed" => Ok(Framing::Typed),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown framing '{}', expected fixed, length-prefixed or typed",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+/// A request or response on a stream with length-prefixed framing.
+///
+/// Wire format: a little-endian u32 body length, then the body: the u32 id,
+/// offset or ack, followed by the payload. Any headers section comes after
+/// the frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn with_payload(id: u32, payload: Vec<u8>) -> Self {
+        Self { id, payload }
+    }
+
+    /// Size of the encoded frame, length prefix included.
+    pub fn encoded_len(&self) -> usize {
+        8 + self.payload.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut impl Extend<u8>) {
+        out.extend((4 + self.payload.len() as u32).to_le_bytes());
+        out.extend(self.id.to_le_bytes());
+        out.extend(self.payload.iter().copied());
+    }
+
+    /// Decodes a frame at the start of `buf`, returning it and the number of
+    /// bytes it took up.
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), ProtonError> {
+        let truncated = || invalid_frame("truncated frame".to_string());
+        let prefix = buf.get(..4).ok_or_else(truncated)?;
+        let len = check_frame_len(u32::from_le_bytes(prefix.try_into().unwrap()))?;
+        let body = buf.get(4..4 + len).ok_or_else(truncated)?;
+        let frame = Frame {
+            id: u32::from_le_bytes(body[..4].try_into().unwrap()),
+            payload: body[4..].to_vec(),
+        };
+        Ok((frame, 4 + len))
+    }
+}
+
+/// An event sent on the event stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+/// A state commit, or the abort of one, sent on the state commit stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateCommit {
+    pub id: u32,
+    pub abort: bool,
+    pub payload: Vec<u8>,
+}
+
+/// An action handed out on the action stream, or pushed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Action {
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Which [`Message`] a stream carries in one direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Event,
+    StateCommit,
+    Action,
+    Ack,
+}
+
+impl MessageKind {
+    /// What a client sends on streams of type `stream`.
+    pub fn request(stream: u8) -> Self {
+        match stream {
+            STREAM_EVENT => MessageKind::Event,
+            STREAM_STATE_COMMIT => MessageKind::StateCommit,
+            _ => MessageKind::Ack,
+        }
+    }
+
+    /// What the server answers with on streams of type `stream`.
+    pub fn response(stream: u8) -> Self {
+        match stream {
+            STREAM_ACTION | STREAM_ACTION_PUSH => MessageKind::Action,
+            _ => MessageKind::Ack,
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageKind::Event => write!(f, "event"),
+            MessageKind::StateCommit => write!(f, "state commit"),
+            MessageKind::Action => write!(f, "action"),
+            MessageKind::Ack => write!(f, "ack"),
+        }
+    }
+}
+
+/// A request or response on a stream with typed framing.
+///
+/// Wire format: a little-endian u32 body length, then the body: the
+/// [`MESSAGE_VERSION`] byte, followed by the message as bincode with fixed
+/// size integers. Any headers section comes after the frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    Event(Event),
+    StateCommit(StateCommit),
+    Action(Action),
+    /// An event ack, a state commit response or the consumer's action offset
+    Ack(u32),
+}
+
+impl Message {
+    /// The `kind` message carrying `frame`. Acks have no payload.
+    pub fn from_frame(kind: MessageKind, frame: &Frame) -> Result<Self, ProtonError> {
+        let payload = frame.payload.clone();
+        Ok(match kind {
+            MessageKind::Event => Message::Event(Event {
+                id: frame.id,
+                payload,
+            }),
+            MessageKind::StateCommit => {
+                let (id, abort) = decode_commit(frame.id);
+                Message::StateCommit(StateCommit { id, abort, payload })
+            }
+            MessageKind::Action => Message::Action(Action {
+                id: frame.id,
+                payload,
+            }),
+            MessageKind::Ack if payload.is_empty() => Message::Ack(frame.id),
+            MessageKind::Ack => {
+                return Err(ProtonError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "acks carry no payload",
+                )))
+            }
+        })
+    }
+
+    /// The frame this message carries, if it is the `kind` expected.
+    pub fn into_frame(self, kind: MessageKind) -> Result<Frame, ProtonError> {
+        if self.kind() != kind {
+            return Err(invalid_frame(format!(
+                "expected {} message, got {}",
+                kind,
+                self.kind()
+            )));
+        }
+        Ok(match self {
+            Message::Event(Event { id, payload }) | Message::Action(Action { id, payload }) => {
+                Frame { id, payload }
+            }
+            Message::StateCommit(StateCommit { id, abort, payload }) => {
+                Frame::with_payload(encode_commit(id, abort), payload)
+            }
+            Message::Ack(id) => Frame::new(id),
+        })
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Event(_) => MessageKind::Event,
+            Message::StateCommit(_) => MessageKind::StateCommit,
+            Message::Action(_) => MessageKind::Action,
+            Message::Ack(_) => MessageKind::Ack,
+        }
+    }
+
+    /// Size of `frame` encoded as a `kind` message, length prefix included.
+    pub fn encoded_len(kind: MessageKind, frame: &Frame) -> usize {
+        // Length prefix, version, variant tag and id
+        let header = 4 + 1 + 4 + 4;
+        match kind {
+            // The payload is preceded by its u64 length
+            MessageKind::Event | MessageKind::Action => header + 8 + frame.payload.len(),
+            MessageKind::StateCommit => header + 1 + 8 + frame.payload.len(),
+            MessageKind::Ack => header,
+        }
+    }
+
+    pub fn encode_into(&self, out: &mut impl Extend<u8>) {
+        let body = message_codec()
+            .serialize(self)
+            .expect("messages always encode");
+        out.extend((1 + body.len() as u32).to_le_bytes());
+        out.extend([MESSAGE_VERSION]);
+        out.extend(body);
+    }
+
+    /// Decodes a message at the start of `buf`, returning it and the number
+    /// of bytes it took up.
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), ProtonError> {
+        let truncated = || invalid_frame("truncated frame".to_string());
+        let prefix = buf.get(..4).ok_or_else(truncated)?;
+        let len = check_frame_len(u32::from_le_bytes(prefix.try_into().unwrap()))?;
+        let body = buf.get(4..4 + len).ok_or_else(truncated)?;
+        Ok((Self::decode_body(body)?, 4 + len))
+    }
+
+    // The body after the length prefix: the version, then the message
+    fn decode_body(body: &[u8]) -> Result<Self, ProtonError> {
+        match body.split_first() {
+            Some((&MESSAGE_VERSION, message)) => message_codec()
+                .deserialize(message)
+                .map_err(|e| invalid_frame(format!("invalid message: {}", e))),
+            Some((version, _)) => Err(invalid_frame(format!(
+                "unsupported message version {}, expected {}",
+                version, MESSAGE_VERSION
+            ))),
+            None => Err(invalid_frame("truncated frame".to_string())),
+        }
+    }
+}
+
+// Fixed size integers keep a message's size a function of its payload, and a
+// message must fill its body exactly
+fn message_codec() -> impl bincode::Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+// A payload must leave room for the id within the frame limit
+pub(crate) fn check_payload_len(len: usize) -> Result<(), ProtonError> {
+    if len > (MAX_FRAME_LEN - 4) as usize {
+        return Err(ProtonError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "payload of {} bytes exceeds the {} byte frame limit",
+                len,
+                MAX_FRAME_LEN - 4
+            ),
+        )));
+    }
+    Ok(())
+}
+
+// The body must hold at least the id and stay within the frame limit
+fn check_frame_len(len: u32) -> Result<usize, ProtonError> {
+    if !(4..=MAX_FRAME_LEN).contains(&len) {
+        return Err(invalid_frame(format!("invalid frame length {}", len)));
+    }
+    Ok(len as usize)
+}
+
+fn invalid_frame(msg: String) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+pub mod access;
+pub mod admin;
+pub mod admission;
+#[cfg(feature = "alloc-audit")]
+pub mod alloc;
+pub mod batching;
+pub mod breaker;
+pub mod check;
 pub mod client;
+pub mod coalesce;
+pub mod commit;
+pub mod compress;
+pub mod config;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
+pub mod datagram;
+pub mod decode;
+pub mod dedupe;
+pub mod drain;
+pub mod dscp;
+pub mod efficiency;
+pub mod eventlog;
+pub mod experiment;
+pub mod frame;
+pub mod fsm;
+pub mod grafana;
+pub mod handler;
+pub mod handoff;
+pub mod heartbeat;
+pub mod hello;
+pub mod ids;
+pub(crate) mod journal;
+pub(crate) mod json;
+pub mod keepalive;
+pub mod log;
+pub mod metrics;
+pub mod mirror;
+pub mod misbehave;
+pub(crate) mod mmap;
+#[cfg(feature = "multipath")]
+pub mod multipath;
+pub mod offline;
+pub mod ordering;
+pub mod outbox;
+pub mod park;
+pub mod payload;
+pub mod power;
+pub mod profile;
+pub mod psk;
+pub mod push;
+pub mod qlog;
+pub mod quota;
+pub mod ratelimit;
+pub mod registry;
+pub mod reorder;
+pub mod retry;
+pub mod schema;
+#[cfg(feature = "lua-hooks")]
+pub mod script;
 mod server;
+pub mod settings;
+pub mod sink;
+pub mod snapshot;
+pub mod stall;
+pub mod streams;
+pub mod timeline;
+pub mod tls;
+pub mod transcript;
+pub mod violation;
+pub mod window;
+pub mod wire;
 
-pub use client::ProtonClient;
+pub use client::{ProtonClient, ProtonConnection};
+pub use config::ProtonConfig;
 pub use server::ProtonServer;
+pub use wire::{
+    QUOTA_EXCEEDED, STREAM_ACTION, STREAM_ACTION_PUSH, STREAM_AUTH, STREAM_CONTROL, STREAM_EVENT,
+    STREAM_HEARTBEAT, STREAM_PAYLOAD, STREAM_STATE_COMMIT,
+};
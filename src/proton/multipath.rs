@@ -0,0 +1,199 @@
+use crate::proton::client::{ProtonClient, ProtonConnection};
+use crate::proton::profile::spawn_named;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{info, info_span, Instrument};
+
+/// How often a probed path is sampled. Probe connections also send a
+/// keepalive this often, so their RTT and loss stay current while they
+/// carry no events.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Wait before reconnecting a probe whose path went down
+const PROBE_RETRY: Duration = Duration::from_secs(5);
+
+/// Whether a probed path currently reaches the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathState {
+    Connecting,
+    Up,
+    /// Down for this reason; the probe reconnects after a while
+    Down(String),
+}
+
+impl fmt::Display for PathState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathState::Connecting => write!(f, "connecting"),
+            PathState::Up => write!(f, "up"),
+            PathState::Down(reason) => write!(f, "down ({})", reason),
+        }
+    }
+}
+
+/// RTT and loss measured over one local address, as reported in the
+/// connection's stats. Packet counts add up over the probe's reconnects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathStats {
+    /// Local address the probe connection is bound to
+    pub local: SocketAddr,
+    pub state: PathState,
+    /// Latest RTT estimate while the path was up
+    pub rtt: Duration,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    /// Times the probe connected
+    pub connects: u64,
+}
+
+impl PathStats {
+    fn new(local: SocketAddr) -> Self {
+        Self {
+            local,
+            state: PathState::Connecting,
+            rtt: Duration::ZERO,
+            sent_packets: 0,
+            lost_packets: 0,
+            connects: 0,
+        }
+    }
+
+    /// Fraction of sent packets that were lost, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        if self.sent_packets == 0 {
+            return 0.0;
+        }
+        self.lost_packets as f64 / self.sent_packets as f64
+    }
+}
+
+impl fmt::Display for PathStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, rtt {:?}, {} of {} packets lost ({:.1}%), {} connects",
+            self.local,
+            self.state,
+            self.rtt,
+            self.lost_packets,
+            self.sent_packets,
+            self.loss() * 100.0,
+            self.connects
+        )
+    }
+}
+
+// Connections to the server over alternate local addresses, each kept open
+// by a task of its own that measures its path. The tasks end, closing their
+// connections, once the probes are closed or dropped.
+pub(crate) struct PathProbes {
+    paths: Vec<Arc<Mutex<PathStats>>>,
+    tasks: Vec<JoinHandle<()>>,
+    stop: watch::Sender<()>,
+}
+
+impl PathProbes {
+    // Probe `server_addr` with each of `clients`, bound to the local address
+    // paired with it. The clients must not probe themselves.
+    pub(crate) fn spawn(clients: Vec<(SocketAddr, ProtonClient)>, server_addr: SocketAddr) -> Self {
+        let (stop, stopped) = watch::channel(());
+        let (paths, tasks) = clients
+            .into_iter()
+            .map(|(local, client)| {
+                let stats = Arc::new(Mutex::new(PathStats::new(local)));
+                let span = info_span!("path", local = %local);
+                let task = spawn_named(
+                    format!("path probe {}", local),
+                    probe(client, server_addr, Arc::clone(&stats), stopped.clone())
+                        .instrument(span),
+                );
+                (stats, task)
+            })
+            .unzip();
+        Self { paths, tasks, stop }
+    }
+
+    // Stop probing and wait for the probe connections to close
+    pub(crate) async fn close(self) {
+        drop(self.stop);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<PathStats> {
+        self.paths
+            .iter()
+            .map(|path| path.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+// Keep a connection open over one path and sample it until stopped
+async fn probe(
+    mut client: ProtonClient,
+    server_addr: SocketAddr,
+    stats: Arc<Mutex<PathStats>>,
+    mut stopped: watch::Receiver<()>,
+) {
+    loop {
+        let result = tokio::select! {
+            result = client.connect(server_addr, Some(Duration::ZERO)) => result,
+            _ = stopped.changed() => return,
+        };
+        match result {
+            Ok(mut connection) => {
+                {
+                    let mut stats = stats.lock().unwrap();
+                    stats.state = PathState::Up;
+                    stats.connects += 1;
+                }
+                info!("Path up");
+                let stop = sample(&mut connection, &stats, &mut stopped).await;
+                connection.close().await;
+                if stop {
+                    return;
+                }
+            }
+            Err(e) => stats.lock().unwrap().state = PathState::Down(e.to_string()),
+        }
+        info!("Path down, probing again in {:?}", PROBE_RETRY);
+        tokio::select! {
+            _ = sleep(PROBE_RETRY) => {}
+            _ = stopped.changed() => return,
+        }
+    }
+}
+
+// Sample `connection` until it closes, or until stopped, which returns true
+async fn sample(
+    connection: &mut ProtonConnection,
+    stats: &Mutex<PathStats>,
+    stopped: &mut watch::Receiver<()>,
+) -> bool {
+    // Totals of earlier connections, which the current one's add to
+    let (sent_before, lost_before) = {
+        let stats = stats.lock().unwrap();
+        (stats.sent_packets, stats.lost_packets)
+    };
+    loop {
+        tokio::select! {
+            _ = sleep(PROBE_INTERVAL) => {}
+            _ = stopped.changed() => return true,
+        }
+        let current = connection.stats();
+        let mut stats = stats.lock().unwrap();
+        stats.rtt = current.rtt;
+        stats.sent_packets = sent_before + current.quic.path.sent_packets;
+        stats.lost_packets = lost_before + current.quic.path.lost_packets;
+        if let Some(reason) = connection.close_reason() {
+            stats.state = PathState::Down(reason.to_string());
+            return false;
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::proton::{Frame, ProtonError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use tracing::info;
+
+/// What an [`OfflineQueue`] does with an event that would exceed its limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Drop the oldest queued events to make room; the newest data wins
+    #[default]
+    DropOldest,
+    /// Fail the new event with `ProtonError::QueueFull`
+    Reject,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            "reject" => Ok(QueuePolicy::Reject),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown queue policy '{}', expected drop-oldest or reject",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueuePolicy::DropOldest => write!(f, "drop-oldest"),
+            QueuePolicy::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Bounds of the queue events wait in while the client is disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub max_events: usize,
+    /// Bytes of queued event frames, ids and payloads
+    pub max_bytes: usize,
+    pub policy: QueuePolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_events: 1000,
+            max_bytes: 1 << 20,
+            policy: QueuePolicy::default(),
+        }
+    }
+}
+
+/// How an event given to `ProtonConnection::send_event_or_queue` went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendStatus {
+    /// Sent and acknowledged with this ack
+    Acked(u32),
+    /// Queued under this event id, to be sent once the client reconnects
+    Queued(u32),
+}
+
+impl fmt::Display for SendStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendStatus::Acked(ack) => write!(f, "acknowledged with {}", ack),
+            SendStatus::Queued(event_id) => write!(f, "queued as event {}", event_id),
+        }
+    }
+}
+
+/// What the offline queue holds and has done so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub config: QueueConfig,
+    pub events: usize,
+    pub bytes: usize,
+    /// Events sent after a reconnect
+    pub flushed: u64,
+    /// Oldest events dropped to make room
+    pub dropped: u64,
+    /// New events refused because the queue was full
+    pub rejected: u64,
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} events, {} of {} bytes ({}), {} flushed, {} dropped, {} rejected",
+            self.events,
+            self.config.max_events,
+            self.bytes,
+            self.config.max_bytes,
+            self.config.policy,
+            self.flushed,
+            self.dropped,
+            self.rejected
+        )
+    }
+}
+
+// Queued bytes an event accounts for
+fn frame_bytes(frame: &Frame) -> usize {
+    4 + frame.payload.len()
+}
+
+/// Events sent while the client was disconnected, oldest first, waiting for
+/// the next connection. Ids are allocated when an event is queued, so a
+/// dropped event leaves a gap in them.
+#[derive(Debug)]
+pub struct OfflineQueue {
+    config: QueueConfig,
+    frames: VecDeque<Frame>,
+    bytes: usize,
+    flushed: u64,
+    dropped: u64,
+    rejected: u64,
+}
+
+impl OfflineQueue {
+    pub fn new(config: QueueConfig) -> Result<Self, ProtonError> {
+        if config.max_events == 0 || config.max_bytes == 0 {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "offline queue limits must not be zero",
+            )));
+        }
+        Ok(Self {
+            config,
+            frames: VecDeque::new(),
+            bytes: 0,
+            flushed: 0,
+            dropped: 0,
+            rejected: 0,
+        })
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            config: self.config,
+            events: self.frames.len(),
+            bytes: self.bytes,
+            flushed: self.flushed,
+            dropped: self.dropped,
+            rejected: self.rejected,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Queues `event` behind the others, making room as the policy says.
+    pub fn push(&mut self, event: Frame) -> Result<(), ProtonError> {
+        let len = frame_bytes(&event);
+        let fits = |queue: &Self| {
+            queue.frames.len() < queue.config.max_events
+                && queue.bytes + len <= queue.config.max_bytes
+        };
+        if len > self.config.max_bytes || (self.config.policy == QueuePolicy::Reject && !fits(self))
+        {
+            self.rejected += 1;
+            return Err(ProtonError::QueueFull);
+        }
+        while !fits(self) {
+            if let Some(oldest) = self.frames.pop_front() {
+                self.bytes -= frame_bytes(&oldest);
+                self.dropped += 1;
+                info!("Offline queue full, dropped event {}", oldest.id);
+            }
+        }
+        self.bytes += len;
+        self.frames.push_back(event);
+        Ok(())
+    }
+
+    // Takes the oldest event to send it
+    pub(crate) fn pop(&mut self) -> Option<Frame> {
+        let event = self.frames.pop_front()?;
+        self.bytes -= frame_bytes(&event);
+        Some(event)
+    }
+
+    // Puts back an event that could not be sent, ahead of the others
+    pub(crate) fn unpop(&mut self, event: Frame) {
+        self.bytes += frame_bytes(&event);
+        self.frames.push_front(event);
+    }
+
+    pub(crate) fn sent(&mut self) {
+        self.flushed += 1;
+    }
+}
@@ -0,0 +1,191 @@
+use crate::proton::dedupe::DedupeIndex;
+use crate::proton::hello::PeerInfo;
+use crate::proton::ProtonError;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Acknowledged event ids remembered per connection by default, so that a
+/// client retransmitting an event whose ack it never saw gets the same ack
+/// again instead of having the event processed twice.
+pub const ACK_WINDOW: usize = 4096;
+
+/// Which event ids the server accepts on a connection, agreed in the HELLO.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventOrdering {
+    /// Each id is one more than the previous one
+    Contiguous,
+    /// Each id is higher than the previous one; gaps are allowed
+    #[default]
+    Monotonic,
+    /// Ids may arrive in any order, e.g. from several producer threads
+    Unordered,
+}
+
+impl FromStr for EventOrdering {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "contiguous" => Ok(EventOrdering::Contiguous),
+            "monotonic" => Ok(EventOrdering::Monotonic),
+            "unordered" => Ok(EventOrdering::Unordered),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown event ordering '{}', expected contiguous, monotonic or unordered",
+                    s
+                ),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EventOrdering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventOrdering::Contiguous => write!(f, "contiguous"),
+            EventOrdering::Monotonic => write!(f, "monotonic"),
+            EventOrdering::Unordered => write!(f, "unordered"),
+        }
+    }
+}
+
+// What to do with an incoming event id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Admit {
+    Accept,
+    // Acknowledged recently: acknowledge again, with the same ack, without
+    // processing
+    Duplicate(u32),
+    Reject,
+}
+
+// Applies a connection's ordering policy to the event ids it receives
+#[derive(Debug, Default)]
+pub(crate) struct EventOrderCheck {
+    ordering: EventOrdering,
+    // Highest id accepted so far, if any
+    last: Option<u32>,
+    // Acks of the last `window` events acknowledged, oldest first in
+    // `recent`
+    window: usize,
+    acked: HashMap<u32, u32>,
+    recent: VecDeque<u32>,
+    // The server's persisted acks and the key of this connection's client,
+    // which outlive the connection and the server process
+    persisted: Option<(Arc<DedupeIndex>, String)>,
+}
+
+impl EventOrderCheck {
+    pub(crate) fn new(ordering: EventOrdering, window: usize) -> Self {
+        Self {
+            ordering,
+            window,
+            ..Self::default()
+        }
+    }
+
+    // Also remember acks in `index`, under `client`, and look them up
+    // there, so a retransmission on a later connection is recognised
+    pub(crate) fn persist(&mut self, index: Arc<DedupeIndex>, client: String) {
+        self.persisted = Some((index, client));
+    }
+
+    // Retransmissions of an acknowledged event are answered the same way
+    // whatever the ordering, rather than taken for an out of order event
+    pub(crate) fn admit(&mut self, event_id: u32) -> Admit {
+        let persisted = || {
+            let (index, client) = self.persisted.as_ref()?;
+            index.lookup(client, event_id)
+        };
+        if let Some(ack) = self.acked.get(&event_id).copied().or_else(persisted) {
+            return Admit::Duplicate(ack);
+        }
+        let admit = match (self.ordering, self.last) {
+            // Contiguous ids continue from wherever the first event starts,
+            // so a producer can reconnect part way through its sequence
+            (EventOrdering::Contiguous, Some(last)) if Some(event_id) != last.checked_add(1) => {
+                Admit::Reject
+            }
+            (EventOrdering::Monotonic, Some(last)) if event_id <= last => Admit::Reject,
+            _ => Admit::Accept,
+        };
+        if admit == Admit::Accept {
+            self.last = Some(self.last.map_or(event_id, |last| last.max(event_id)));
+        }
+        admit
+    }
+
+    // Remembers the ack of an event once it has been processed
+    pub(crate) fn acked(&mut self, event_id: u32, ack: u32) {
+        if self.window == 0 {
+            return;
+        }
+        if let Some((ref index, ref client)) = self.persisted {
+            // The ack goes out regardless; only a restart could then
+            // process the event twice
+            if let Err(e) = index.record(client, event_id, ack) {
+                warn!("Failed to persist the ack of event {}: {}", event_id, e);
+            }
+        }
+        if self.recent.len() == self.window {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.acked.remove(&oldest);
+            }
+        }
+        if self.acked.insert(event_id, ack).is_none() {
+            self.recent.push_back(event_id);
+        }
+    }
+
+    // The ids that would have been accepted, for diagnostics
+    pub(crate) fn expected(&self) -> String {
+        match (self.ordering, self.last) {
+            (EventOrdering::Contiguous, Some(last)) => {
+                format!("event id {} (contiguous)", last.wrapping_add(1))
+            }
+            (EventOrdering::Monotonic, Some(last)) => {
+                format!("event id above {} (monotonic)", last)
+            }
+            _ => "any event id".to_string(),
+        }
+    }
+}
+
+/// The server's choice of event ordering for each connection.
+#[derive(Debug, Clone)]
+pub(crate) struct OrderingPolicy {
+    // For clients that do not ask for an ordering
+    pub(crate) default: EventOrdering,
+    // Imposed on a tenant's connections whatever they ask for
+    pub(crate) tenants: HashMap<String, EventOrdering>,
+    // Acknowledged events remembered per connection; 0 forgets them at once
+    pub(crate) ack_window: usize,
+}
+
+impl Default for OrderingPolicy {
+    fn default() -> Self {
+        Self {
+            default: EventOrdering::default(),
+            tenants: HashMap::new(),
+            ack_window: ACK_WINDOW,
+        }
+    }
+}
+
+impl OrderingPolicy {
+    pub(crate) fn check(&self, ordering: EventOrdering) -> EventOrderCheck {
+        EventOrderCheck::new(ordering, self.ack_window)
+    }
+
+    pub(crate) fn negotiate(&self, peer: &PeerInfo) -> EventOrdering {
+        peer.tenant
+            .as_ref()
+            .and_then(|tenant| self.tenants.get(tenant).copied())
+            .or(peer.event_ordering)
+            .unwrap_or(self.default)
+    }
+}
@@ -0,0 +1,325 @@
+use crate::proton::client::ProtonConnection;
+use crate::proton::handoff::HandoffState;
+use crate::proton::journal::write_atomic;
+use crate::proton::profile::spawn_named;
+use crate::proton::ProtonError;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+use tracing::warn;
+
+const LOG_FILE: &str = "outbox.log";
+const CURSOR_FILE: &str = "outbox.cursor";
+const RECORD_LEN: usize = 4;
+
+/// Write-ahead log of event ids that have been accepted from the application
+/// but not yet acknowledged by the server.
+///
+/// `outbox.log` holds one little-endian u32 per appended event and
+/// `outbox.cursor` the highest id the server has acknowledged. Both are synced
+/// to disk before the call that changes them returns.
+pub struct Outbox {
+    dir: PathBuf,
+    log: File,
+    next_id: u32,
+    cursor: u32,
+}
+
+impl Outbox {
+    /// Opens or creates an outbox in `dir`, recovering any events that were
+    /// appended but never acknowledged.
+    pub fn open(dir: &Path) -> Result<Self, ProtonError> {
+        std::fs::create_dir_all(dir)?;
+        let cursor = match std::fs::read(dir.join(CURSOR_FILE)) {
+            Ok(bytes) if bytes.len() == RECORD_LEN => u32::from_le_bytes(bytes.try_into().unwrap()),
+            Ok(_) => return Err(corrupt("cursor file has the wrong length")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut records = Vec::new();
+        log.read_to_end(&mut records)?;
+
+        // A crash mid-append can leave a partial record, which was never
+        // reported as sent and is dropped
+        let whole = records.len() - records.len() % RECORD_LEN;
+        if whole != records.len() {
+            log.set_len(whole as u64)?;
+        }
+        let last_logged = records[..whole]
+            .chunks_exact(RECORD_LEN)
+            .map(|r| u32::from_le_bytes(r.try_into().unwrap()))
+            .max()
+            .unwrap_or(0);
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            log,
+            next_id: last_logged.max(cursor) + 1,
+            cursor,
+        })
+    }
+
+    /// Durably records a new event and returns its id.
+    pub fn append(&mut self) -> Result<u32, ProtonError> {
+        let id = self.next_id;
+        self.log.write_all(&id.to_le_bytes())?;
+        self.log.sync_data()?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    /// Ids appended but not yet acknowledged, oldest first.
+    pub fn pending(&self) -> std::ops::Range<u32> {
+        self.cursor + 1..self.next_id
+    }
+
+    /// Highest event id acknowledged by the server.
+    pub fn cursor(&self) -> u32 {
+        self.cursor
+    }
+
+    /// Advances the durable cursor to `id`, truncating the log once every
+    /// appended event has been acknowledged.
+    pub fn ack(&mut self, id: u32) -> Result<(), ProtonError> {
+        if id <= self.cursor {
+            return Ok(());
+        }
+        write_atomic(&self.dir.join(CURSOR_FILE), &id.to_le_bytes())?;
+        self.cursor = id;
+
+        if self.pending().is_empty() {
+            self.log.set_len(0)?;
+            self.log.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn corrupt(msg: &str) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("outbox: {}", msg),
+    ))
+}
+
+/// Producer that only reports an event as sent once it is in the outbox, and
+/// drains the outbox to the server from a background task.
+#[derive(Clone)]
+pub struct DurableProducer {
+    outbox: Arc<Mutex<Outbox>>,
+    notify: Arc<Notify>,
+    closing: Arc<AtomicBool>,
+}
+
+impl DurableProducer {
+    pub fn open(dir: &Path) -> Result<Self, ProtonError> {
+        Ok(Self {
+            outbox: Arc::new(Mutex::new(Outbox::open(dir)?)),
+            notify: Arc::new(Notify::new()),
+            closing: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Appends an event to the outbox and wakes the drain task. Once this
+    /// returns the event survives a crash of this process.
+    pub fn send_event(&self) -> Result<u32, ProtonError> {
+        let id = self.outbox.lock().unwrap().append()?;
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Highest event id the server has acknowledged.
+    pub fn durable_cursor(&self) -> u32 {
+        self.outbox.lock().unwrap().cursor()
+    }
+
+    /// Fails if the outbox is behind the cursor a previous process handed
+    /// off, e.g. because it was restored from an older copy, as events
+    /// would then be sent twice.
+    pub fn check_handoff(&self, state: &HandoffState) -> Result<(), ProtonError> {
+        match state.outbox_cursor {
+            Some(cursor) if cursor > self.durable_cursor() => Err(corrupt(&format!(
+                "cursor {} is behind the hand-off cursor {}",
+                self.durable_cursor(),
+                cursor
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of events waiting to be acknowledged.
+    pub fn pending(&self) -> usize {
+        self.outbox.lock().unwrap().pending().len()
+    }
+
+    /// Drains the outbox over `connection` until `close` is called and every
+    /// event is acknowledged, then hands the connection back. On error the
+    /// unacknowledged events stay in the outbox and are resent by the next
+    /// drain, so a caller can reconnect and spawn again.
+    pub fn spawn_drain(
+        &self,
+        mut connection: ProtonConnection,
+    ) -> JoinHandle<Result<ProtonConnection, ProtonError>> {
+        let producer = self.clone();
+        spawn_named("outbox drain", async move {
+            loop {
+                let pending = producer.outbox.lock().unwrap().pending();
+                if pending.is_empty() {
+                    if producer.closing.load(Ordering::Acquire) {
+                        return Ok(connection);
+                    }
+                    producer.notify.notified().await;
+                    continue;
+                }
+                // Give the producer a moment to fill a partial batch
+                let policy = connection.batch_policy();
+                let mut pending = pending;
+                if (pending.len() as u32) < policy.size
+                    && !policy.flush_interval.is_zero()
+                    && !producer.closing.load(Ordering::Acquire)
+                {
+                    let deadline = Instant::now() + policy.flush_interval;
+                    while (pending.len() as u32) < policy.size
+                        && timeout_at(deadline, producer.notify.notified())
+                            .await
+                            .is_ok()
+                    {
+                        pending = producer.outbox.lock().unwrap().pending();
+                    }
+                }
+
+                // Persist the cursor once per batch; a crash mid-batch only
+                // causes events to be sent again
+                let end = pending.end.min(pending.start + policy.size);
+                let ids: Vec<u32> = (pending.start..end).collect();
+                let acks = connection.send_events_with_ids(&ids).await?;
+                if let Some((id, ack)) = ids.iter().zip(&acks).find(|(id, ack)| id != ack) {
+                    warn!("Outbox: event {} acknowledged as {}", id, ack);
+                    return Err(ProtonError::InvalidStream);
+                }
+                if let Some(&id) = ids.last() {
+                    producer.outbox.lock().unwrap().ack(id)?;
+                }
+            }
+        })
+    }
+
+    /// Asks the drain task to finish once the outbox is empty.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("proton-outbox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn unacknowledged_events_are_recovered() {
+        let dir = scratch("recover");
+        let mut outbox = Outbox::open(&dir).unwrap();
+        assert!(outbox.pending().is_empty());
+        assert_eq!(outbox.append().unwrap(), 1);
+        assert_eq!(outbox.append().unwrap(), 2);
+        assert_eq!(outbox.append().unwrap(), 3);
+        outbox.ack(1).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir).unwrap();
+        assert_eq!(outbox.cursor(), 1);
+        assert_eq!(outbox.pending(), 2..4);
+        assert_eq!(outbox.append().unwrap(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_is_truncated_once_everything_is_acknowledged() {
+        let dir = scratch("truncate");
+        let mut outbox = Outbox::open(&dir).unwrap();
+        outbox.append().unwrap();
+        outbox.append().unwrap();
+        outbox.ack(2).unwrap();
+        assert_eq!(std::fs::metadata(dir.join(LOG_FILE)).unwrap().len(), 0);
+        // Stale acks leave the cursor alone
+        outbox.ack(1).unwrap();
+        assert_eq!(outbox.cursor(), 2);
+        drop(outbox);
+
+        // Ids carry on from the cursor rather than reusing acknowledged ones
+        let mut outbox = Outbox::open(&dir).unwrap();
+        assert!(outbox.pending().is_empty());
+        assert_eq!(outbox.append().unwrap(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_record_is_dropped_on_recovery() {
+        let dir = scratch("partial");
+        let mut outbox = Outbox::open(&dir).unwrap();
+        outbox.append().unwrap();
+        drop(outbox);
+        // A crash part way through appending event 2
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(&[2, 0]).unwrap();
+        drop(log);
+
+        let mut outbox = Outbox::open(&dir).unwrap();
+        assert_eq!(outbox.pending(), 1..2);
+        let len = std::fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+        assert_eq!(len, RECORD_LEN as u64);
+        assert_eq!(outbox.append().unwrap(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_cursor_is_rejected() {
+        let dir = scratch("cursor");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(CURSOR_FILE), [1, 0]).unwrap();
+        let err = Outbox::open(&dir).err().unwrap();
+        assert!(err.to_string().contains("wrong length"), "{}", err);
+
+        // Recovery relies on the cursor, so an empty one is not taken as 0
+        std::fs::write(dir.join(CURSOR_FILE), []).unwrap();
+        assert!(Outbox::open(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn handoff_cursor_ahead_of_the_outbox_is_rejected() {
+        let dir = scratch("handoff");
+        let producer = DurableProducer::open(&dir).unwrap();
+        producer.send_event().unwrap();
+        assert_eq!(producer.pending(), 1);
+        let mut state = HandoffState::default();
+        assert!(producer.check_handoff(&state).is_ok());
+        state.outbox_cursor = Some(0);
+        assert!(producer.check_handoff(&state).is_ok());
+        state.outbox_cursor = Some(1);
+        assert!(producer.check_handoff(&state).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+//! Parking of idle connections, for servers with many clients that are
+//! quiet most of the time. A connection without requests for the server's
+//! park period is parked: its coalescing write buffers are released and its
+//! periodic checks slow down. The next request wakes it, and is served as
+//! quickly as on any other connection; buffers come back as it is answered.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How often a parked connection's client address is checked, in place of
+/// once a second.
+pub const PARKED_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether a connection is parked, shared by the tasks serving it. Cheap to
+/// clone; a fresh one is never parked.
+#[derive(Debug, Clone)]
+pub struct Parking(Arc<watch::Sender<bool>>);
+
+impl Default for Parking {
+    fn default() -> Self {
+        Parking(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Parking {
+    pub fn is_parked(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    // Parks the connection, returning whether it was active
+    pub(crate) fn park(&self) -> bool {
+        self.0
+            .send_if_modified(|parked| !std::mem::replace(parked, true))
+    }
+
+    // Wakes the connection on a request; cheap while it is active
+    pub(crate) fn wake(&self) {
+        if self.is_parked() {
+            self.0
+                .send_if_modified(|parked| std::mem::replace(parked, false));
+        }
+    }
+
+    // Resolves once the connection is parked
+    pub(crate) async fn parked(&self) {
+        let _ = self.0.subscribe().wait_for(|parked| *parked).await;
+    }
+
+    // Resolves once the connection is woken
+    pub(crate) async fn woken(&self) {
+        let _ = self.0.subscribe().wait_for(|parked| !*parked).await;
+    }
+}
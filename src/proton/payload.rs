@@ -0,0 +1,625 @@
+use crate::proton::frame::Headers;
+use crate::proton::journal::rename_synced;
+use crate::proton::mmap::MappedFile;
+use crate::proton::quota::UsageLedger;
+use crate::proton::snapshot::TransferRecord;
+use crate::proton::wire::{
+    decode_payload_header, decode_response, decode_u32, encode_discriminator,
+    encode_payload_header, encode_u32, CLOSE_NORMAL, PAYLOAD_HEADER_LEN,
+};
+use crate::proton::{ProtonError, QUOTA_EXCEEDED, STREAM_PAYLOAD, STREAM_TIMEOUT};
+use bytes::Bytes;
+use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+use tracing::{error, info};
+
+/// Largest piece of a payload held in memory at once on either side.
+pub const PAYLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+// CRC-32 (IEEE 802.3) lookup table
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 checksum carried after every payload chunk.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn chunk_count(len: u64) -> u32 {
+    len.div_ceil(PAYLOAD_CHUNK_SIZE as u64) as u32
+}
+
+fn chunk_len(len: u64, index: u32) -> usize {
+    (len - index as u64 * PAYLOAD_CHUNK_SIZE as u64).min(PAYLOAD_CHUNK_SIZE as u64) as usize
+}
+
+fn invalid_payload(msg: String) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+// A payload whose transfer was interrupted, kept so it can be resumed
+#[derive(Debug, Clone, Copy)]
+struct PartialPayload {
+    len: u64,
+    // Chunks received and verified so far
+    chunks: u32,
+}
+
+/// Payload transfers in progress on the server, by client and event id.
+/// Survives reconnects so an interrupted transfer resumes where it stopped.
+#[derive(Debug, Default)]
+pub struct PayloadTransfers(Mutex<HashMap<(String, u32), PartialPayload>>);
+
+impl PayloadTransfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Chunks already held for this payload, registering it if it is new or
+    // its length has changed
+    fn begin(&self, client: &str, event_id: u32, len: u64) -> u32 {
+        let mut transfers = self.0.lock().unwrap();
+        let partial = transfers
+            .entry((client.to_string(), event_id))
+            .or_insert(PartialPayload { len, chunks: 0 });
+        if partial.len != len {
+            *partial = PartialPayload { len, chunks: 0 };
+        }
+        partial.chunks
+    }
+
+    fn advance(&self, client: &str, event_id: u32, chunks: u32) {
+        if let Some(partial) = self
+            .0
+            .lock()
+            .unwrap()
+            .get_mut(&(client.to_string(), event_id))
+        {
+            partial.chunks = chunks;
+        }
+    }
+
+    fn finish(&self, client: &str, event_id: u32) {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&(client.to_string(), event_id));
+    }
+
+    pub(crate) fn export(&self) -> Vec<TransferRecord> {
+        let mut records: Vec<TransferRecord> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((client, event_id), partial)| TransferRecord {
+                client: client.clone(),
+                event_id: *event_id,
+                len: partial.len,
+                chunks: partial.chunks,
+            })
+            .collect();
+        records.sort_by(|a, b| (&a.client, a.event_id).cmp(&(&b.client, b.event_id)));
+        records
+    }
+
+    pub(crate) fn import(&self, records: &[TransferRecord]) {
+        let mut transfers = self.0.lock().unwrap();
+        for r in records {
+            transfers.insert(
+                (r.client.clone(), r.event_id),
+                PartialPayload {
+                    len: r.len,
+                    chunks: r.chunks,
+                },
+            );
+        }
+    }
+
+    /// Number of interrupted transfers waiting to be resumed.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Receives large event payloads chunk by chunk as the server reads them, so
+/// they never have to be held in memory whole.
+///
+/// Every client numbers its own events, so a payload is identified by the
+/// client sending it and its event id. `client` is the client's key: its
+/// tenant and client id if its HELLO names one, otherwise its tenant and IP
+/// address, or the address alone if it names no tenant either.
+pub trait PayloadHandler: Send + Sync {
+    /// A payload of `len` bytes for `event_id` is about to arrive, starting
+    /// at `offset`. A non-zero offset resumes an interrupted transfer: the
+    /// handler keeps the first `offset` bytes and drops anything after them.
+    fn on_start(
+        &self,
+        _client: &str,
+        _event_id: u32,
+        _len: u64,
+        _offset: u64,
+    ) -> Result<(), ProtonError> {
+        Ok(())
+    }
+
+    /// The next chunk, starting `offset` bytes into the payload.
+    fn on_chunk(
+        &self,
+        client: &str,
+        event_id: u32,
+        offset: u64,
+        chunk: &[u8],
+    ) -> Result<(), ProtonError>;
+
+    /// Every byte has arrived; the event is acknowledged once this returns.
+    fn on_complete(&self, _client: &str, _event_id: u32, _len: u64) -> Result<(), ProtonError> {
+        Ok(())
+    }
+
+    /// The transfer failed part way. Verified chunks already delivered are
+    /// kept by the server and the transfer may later resume after them.
+    fn on_abort(&self, _client: &str, _event_id: u32) {}
+}
+
+/// Writes each payload to `<dir>/<client>/event-<id>.bin`, via a `.part`
+/// file while it is being received. Interrupted transfers resume into the
+/// `.part` file. Characters of the client key other than ASCII letters,
+/// digits, `-`, `_` and non-leading `.` are written as `%XX`.
+pub struct FileSink {
+    dir: PathBuf,
+    open: Mutex<HashMap<(String, u32), File>>,
+}
+
+impl FileSink {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, ProtonError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn path(&self, client: &str, event_id: u32, ext: &str) -> PathBuf {
+        self.dir
+            .join(client_dir(client))
+            .join(format!("event-{}.{}", event_id, ext))
+    }
+}
+
+// Directory name of a client key, escaped so that no key can reach outside
+// the sink's directory or share a directory with another
+fn client_dir(client: &str) -> String {
+    let mut dir = String::with_capacity(client.len());
+    for (i, b) in client.bytes().enumerate() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => dir.push(b as char),
+            b'.' if i > 0 => dir.push('.'),
+            _ => dir.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    if dir.is_empty() {
+        dir.push('%');
+    }
+    dir
+}
+
+impl PayloadHandler for FileSink {
+    fn on_start(
+        &self,
+        client: &str,
+        event_id: u32,
+        _len: u64,
+        offset: u64,
+    ) -> Result<(), ProtonError> {
+        let path = self.path(client, event_id, "part");
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(path)?;
+        if file.metadata()?.len() < offset {
+            return Err(invalid_payload(format!(
+                "cannot resume event {} of {} at {}: partial file is shorter",
+                event_id, client, offset
+            )));
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        self.open
+            .lock()
+            .unwrap()
+            .insert((client.to_string(), event_id), file);
+        Ok(())
+    }
+
+    fn on_chunk(
+        &self,
+        client: &str,
+        event_id: u32,
+        _offset: u64,
+        chunk: &[u8],
+    ) -> Result<(), ProtonError> {
+        match self
+            .open
+            .lock()
+            .unwrap()
+            .get_mut(&(client.to_string(), event_id))
+        {
+            Some(file) => Ok(file.write_all(chunk)?),
+            None => Err(ProtonError::InvalidStream),
+        }
+    }
+
+    fn on_complete(&self, client: &str, event_id: u32, _len: u64) -> Result<(), ProtonError> {
+        let key = (client.to_string(), event_id);
+        if let Some(file) = self.open.lock().unwrap().remove(&key) {
+            file.sync_all()?;
+        }
+        rename_synced(
+            &self.path(client, event_id, "part"),
+            &self.path(client, event_id, "bin"),
+        )
+    }
+
+    fn on_abort(&self, client: &str, event_id: u32) {
+        self.open
+            .lock()
+            .unwrap()
+            .remove(&(client.to_string(), event_id));
+    }
+}
+
+// Opens a payload stream and negotiates the resume point, returning the
+// streams and the number of chunks the server already holds
+async fn open_payload(
+    connection: &QuinnConnection,
+    event_id: u32,
+    len: u64,
+    headers: Option<&Headers>,
+) -> Result<(SendStream, RecvStream, u32), ProtonError> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut header = vec![encode_discriminator(STREAM_PAYLOAD, headers.is_some())];
+    header.extend_from_slice(&encode_payload_header(event_id, len));
+    if let Some(headers) = headers {
+        header.extend_from_slice(&headers.encode());
+    }
+    timeout(STREAM_TIMEOUT, send.write_all(&header)).await??;
+
+    // Resume negotiation: the server says how many chunks it already has
+    let mut have = [0u8; 4];
+    timeout(STREAM_TIMEOUT, recv.read_exact(&mut have)).await??;
+    let have = decode_response(have)?.min(chunk_count(len));
+    if have > 0 {
+        info!(
+            "Resuming event {} payload after chunk {} ({} bytes already sent)",
+            event_id,
+            have,
+            (have as u64 * PAYLOAD_CHUNK_SIZE as u64).min(len)
+        );
+    }
+    Ok((send, recv, have))
+}
+
+async fn finish_payload(mut send: SendStream, mut recv: RecvStream) -> Result<u32, ProtonError> {
+    send.finish().await?;
+    let mut ack = [0u8; 4];
+    timeout(STREAM_TIMEOUT, recv.read_exact(&mut ack)).await??;
+    Ok(decode_u32(ack))
+}
+
+/// Client side: sends the `len` bytes read from `reader` as the payload of
+/// `event_id` on a stream of its own, and waits for the server's ack. If the
+/// server already holds the first chunks from an interrupted attempt, those
+/// are skipped in `reader` rather than sent again.
+///
+/// Wire format: after the discriminator, the event id (u32 LE), the length
+/// (u64 LE) and optional headers. The server answers with the number of
+/// chunks it already holds (u32 LE), then each remaining chunk is sent as its
+/// length (u32 LE), the bytes and their CRC-32 (u32 LE).
+pub async fn send_payload<R: AsyncRead + Unpin>(
+    connection: &QuinnConnection,
+    event_id: u32,
+    reader: R,
+    len: u64,
+    headers: Option<&Headers>,
+) -> Result<u32, ProtonError> {
+    let (mut send, recv, have) = open_payload(connection, event_id, len, headers).await?;
+    let mut reader = reader.take(len);
+    if have > 0 {
+        let skip = have as u64 * PAYLOAD_CHUNK_SIZE as u64;
+        tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+    }
+
+    let mut buf = vec![0u8; PAYLOAD_CHUNK_SIZE];
+    for index in have..chunk_count(len) {
+        let chunk = &mut buf[..chunk_len(len, index)];
+        reader.read_exact(chunk).await?;
+        let mut frame = Vec::with_capacity(chunk.len() + 8);
+        frame.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        frame.extend_from_slice(chunk);
+        frame.extend_from_slice(&crc32(chunk).to_le_bytes());
+        timeout(STREAM_TIMEOUT, send.write_all(&frame)).await??;
+    }
+    finish_payload(send, recv).await
+}
+
+/// Like [`send_payload`], for a memory mapped file. Chunks are queued on the
+/// stream as slices of the mapping, without copying them into a buffer, and
+/// resuming skips chunks without reading them.
+pub(crate) async fn send_mapped_payload(
+    connection: &QuinnConnection,
+    event_id: u32,
+    file: MappedFile,
+    headers: Option<&Headers>,
+) -> Result<u32, ProtonError> {
+    let len = file.len() as u64;
+    let (mut send, recv, have) = open_payload(connection, event_id, len, headers).await?;
+    let mapped = Bytes::from_owner(file);
+    for index in have..chunk_count(len) {
+        let start = index as usize * PAYLOAD_CHUNK_SIZE;
+        let chunk = mapped.slice(start..start + chunk_len(len, index));
+        let checksum = crc32(&chunk);
+        let mut frame = [
+            Bytes::copy_from_slice(&(chunk.len() as u32).to_le_bytes()),
+            chunk,
+            Bytes::copy_from_slice(&checksum.to_le_bytes()),
+        ];
+        timeout(STREAM_TIMEOUT, send.write_all_chunks(&mut frame)).await??;
+    }
+    finish_payload(send, recv).await
+}
+
+/// Server side, run once a `STREAM_PAYLOAD` discriminator has been read:
+/// streams the payload to `handler`, verifying every chunk's checksum, and
+/// acknowledges the event. Progress is recorded in `transfers` under
+/// `client` so a client that reconnects can resume after the last verified
+/// chunk. Bytes are accounted to `tenant`.
+#[allow(clippy::too_many_arguments)]
+pub async fn receive_payload(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    with_headers: bool,
+    handler: Option<&dyn PayloadHandler>,
+    transfers: &PayloadTransfers,
+    usage: &UsageLedger,
+    tenant: &str,
+    client: &str,
+) -> Result<u32, ProtonError> {
+    let mut header = [0u8; PAYLOAD_HEADER_LEN];
+    timeout(STREAM_TIMEOUT, recv.read_exact(&mut header)).await??;
+    let (event_id, len) = decode_payload_header(header);
+    let header_len = if with_headers {
+        Headers::read_from(&mut recv).await?.encoded_len()
+    } else {
+        0
+    };
+
+    // The quota is checked when the transfer starts, so a payload that is
+    // under way is always completed
+    if let Err(e) = usage.record_received(tenant, (PAYLOAD_HEADER_LEN + header_len) as u64) {
+        info!(
+            "Refusing payload for event {} from {}: {}",
+            event_id, tenant, e
+        );
+        let _ = recv.stop(CLOSE_NORMAL.into());
+        timeout(STREAM_TIMEOUT, send.write_all(&encode_u32(QUOTA_EXCEEDED))).await??;
+        return Err(e);
+    }
+
+    let mut have = transfers.begin(client, event_id, len);
+    let offset = have as u64 * PAYLOAD_CHUNK_SIZE as u64;
+    if let Some(handler) = handler {
+        if let Err(e) = handler.on_start(client, event_id, len, offset) {
+            // Start over if the handler cannot pick up where it left off
+            error!("Cannot resume payload for event {}: {}", event_id, e);
+            have = 0;
+            transfers.advance(client, event_id, 0);
+            handler.on_start(client, event_id, len, 0)?;
+        }
+    }
+    timeout(STREAM_TIMEOUT, send.write_all(&encode_u32(have))).await??;
+    usage.record_sent(tenant, 4);
+    if have > 0 {
+        info!(
+            "Resuming {} byte payload for event {} after chunk {}",
+            len, event_id, have
+        );
+    } else {
+        info!("Receiving {} byte payload for event {}", len, event_id);
+    }
+
+    let result = async {
+        let mut buf = vec![0u8; PAYLOAD_CHUNK_SIZE];
+        for index in have..chunk_count(len) {
+            let expected = chunk_len(len, index);
+            let mut chunk_header = [0u8; 4];
+            timeout(STREAM_TIMEOUT, recv.read_exact(&mut chunk_header)).await??;
+            if decode_u32(chunk_header) as usize != expected {
+                return Err(invalid_payload(format!(
+                    "chunk {} of event {} has length {}, expected {}",
+                    index,
+                    event_id,
+                    decode_u32(chunk_header),
+                    expected
+                )));
+            }
+            let chunk = &mut buf[..expected];
+            timeout(STREAM_TIMEOUT, recv.read_exact(chunk)).await??;
+            let mut checksum = [0u8; 4];
+            timeout(STREAM_TIMEOUT, recv.read_exact(&mut checksum)).await??;
+            if decode_u32(checksum) != crc32(chunk) {
+                return Err(invalid_payload(format!(
+                    "checksum mismatch on chunk {} of event {}",
+                    index, event_id
+                )));
+            }
+            if let Some(handler) = handler {
+                handler.on_chunk(
+                    client,
+                    event_id,
+                    index as u64 * PAYLOAD_CHUNK_SIZE as u64,
+                    chunk,
+                )?;
+            }
+            transfers.advance(client, event_id, index + 1);
+            let _ = usage.record_received(tenant, (expected + 8) as u64);
+        }
+        if let Some(handler) = handler {
+            handler.on_complete(client, event_id, len)?;
+        }
+        Ok::<_, ProtonError>(())
+    }
+    .await;
+    if let Err(e) = result {
+        if let Some(handler) = handler {
+            handler.on_abort(client, event_id);
+        }
+        return Err(e);
+    }
+    transfers.finish(client, event_id);
+
+    timeout(STREAM_TIMEOUT, send.write_all(&encode_u32(event_id))).await??;
+    usage.record_sent(tenant, 4);
+    send.finish().await?;
+    info!("Payload for event {} received ({} bytes)", event_id, len);
+    Ok(event_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("proton-payload-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn client_dirs_stay_apart_and_inside() {
+        assert_eq!(client_dir("acme/a-1"), "acme%2Fa-1");
+        assert_eq!(client_dir("acme@10.0.0.1"), "acme%4010.0.0.1");
+        assert_eq!(client_dir("::1"), "%3A%3A1");
+        assert_eq!(client_dir(".."), "%2E.");
+        assert_eq!(client_dir(""), "%");
+        // The escape character is escaped too, so keys map to distinct dirs
+        assert_ne!(client_dir("a/b"), client_dir("a%2Fb"));
+    }
+
+    #[test]
+    fn same_event_id_from_two_clients() {
+        let dir = scratch("clients");
+        let sink = FileSink::new(&dir).unwrap();
+        sink.on_start("acme/a", 1, 2, 0).unwrap();
+        sink.on_start("acme/b", 1, 2, 0).unwrap();
+        sink.on_chunk("acme/a", 1, 0, b"aa").unwrap();
+        sink.on_chunk("acme/b", 1, 0, b"bb").unwrap();
+        sink.on_complete("acme/a", 1, 2).unwrap();
+        sink.on_complete("acme/b", 1, 2).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("acme%2Fa/event-1.bin")).unwrap(),
+            b"aa"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("acme%2Fb/event-1.bin")).unwrap(),
+            b"bb"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume_appends_to_the_clients_own_part() {
+        let dir = scratch("resume");
+        let sink = FileSink::new(&dir).unwrap();
+        sink.on_start("acme/a", 7, 6, 0).unwrap();
+        sink.on_chunk("acme/a", 7, 0, b"abcdef").unwrap();
+        sink.on_abort("acme/a", 7);
+        sink.on_start("acme/b", 7, 4, 0).unwrap();
+        sink.on_chunk("acme/b", 7, 0, b"wxyz").unwrap();
+        sink.on_abort("acme/b", 7);
+
+        // Bytes past the resume point are dropped
+        sink.on_start("acme/a", 7, 6, 3).unwrap();
+        sink.on_chunk("acme/a", 7, 3, b"DEF").unwrap();
+        sink.on_complete("acme/a", 7, 6).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("acme%2Fa/event-7.bin")).unwrap(),
+            b"abcDEF"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("acme%2Fb/event-7.part")).unwrap(),
+            b"wxyz"
+        );
+
+        // A partial file shorter than the resume point cannot be resumed
+        assert!(sink.on_start("acme/b", 7, 8, 6).is_err());
+        assert!(sink.on_chunk("acme/c", 7, 0, b"x").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transfers_are_kept_per_client() {
+        let transfers = PayloadTransfers::new();
+        assert_eq!(transfers.begin("acme/a", 1, 200_000), 0);
+        transfers.advance("acme/a", 1, 2);
+        assert_eq!(transfers.begin("acme/b", 1, 200_000), 0);
+        assert_eq!(transfers.begin("acme/a", 1, 200_000), 2);
+        // A different length is a different payload
+        assert_eq!(transfers.begin("acme/a", 1, 100), 0);
+        transfers.finish("acme/b", 1);
+
+        let restored = PayloadTransfers::new();
+        restored.import(&transfers.export());
+        assert_eq!(restored.export(), transfers.export());
+        assert_eq!(restored.len(), 1);
+    }
+
+    #[test]
+    fn chunking() {
+        let chunk = PAYLOAD_CHUNK_SIZE as u64;
+        assert_eq!(chunk_count(0), 0);
+        assert_eq!(chunk_count(1), 1);
+        assert_eq!(chunk_count(chunk), 1);
+        assert_eq!(chunk_count(chunk + 1), 2);
+        assert_eq!(chunk_len(chunk + 1, 0), PAYLOAD_CHUNK_SIZE);
+        assert_eq!(chunk_len(chunk + 1, 1), 1);
+        // The IEEE CRC-32 check value
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+/// In low power mode, events wait for the next multiple of this interval
+/// on the wall clock. Aligning to the clock rather than to the connection
+/// lets every client on a device share the same wakeups.
+pub const LOW_POWER_WAKEUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keepalive intervals are this many times longer in low power mode.
+pub const LOW_POWER_KEEPALIVE_STRETCH: u32 = 4;
+
+/// Idle timeout asked for by connections made in low power mode. The
+/// connection uses the shorter of this and the server's.
+pub const LOW_POWER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Wakeup slot the wall clock is in, and the time left until the next one
+fn slot(interval: Duration) -> (u128, Duration) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let interval = interval.as_nanos().max(1);
+    let left = interval - now % interval;
+    (now / interval, Duration::from_nanos(left as u64))
+}
+
+/// Coalesces outgoing events into aligned wakeups. The first event in a
+/// slot waits for the slot's end; events following it before the next slot
+/// begins go straight out, since the radio is already awake.
+#[derive(Debug, Default)]
+pub(crate) struct Wakeups {
+    // Slot in which events were last released
+    awake: Option<u128>,
+}
+
+impl Wakeups {
+    pub(crate) async fn wait(&mut self, interval: Duration) {
+        let (current, left) = slot(interval);
+        if self.awake == Some(current) {
+            return;
+        }
+        sleep(left).await;
+        self.awake = Some(slot(interval).0);
+    }
+}
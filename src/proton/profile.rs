@@ -0,0 +1,116 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Spawns `fut` as a task named `name`. With the `profiling` feature the
+/// task's poll time is attributed to `name`, nested under the task or named
+/// future that spawned it. The task logs within the current span, e.g. that
+/// of the connection it works for.
+pub fn spawn_named<F>(name: impl Into<String>, fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(profiled(&name.into(), fut).in_current_span())
+}
+
+/// Names a future polled inline (e.g. one arm of a `select!`), so its poll
+/// time is split out from the task polling it.
+#[cfg(feature = "profiling")]
+pub fn profiled<F: Future>(name: &str, fut: F) -> impl Future<Output = F::Output> {
+    imp::Profiled::new(name, fut)
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn profiled<F: Future>(_name: &str, fut: F) -> impl Future<Output = F::Output> {
+    fut
+}
+
+/// Self time per named task path in folded stack format (`a;b;c <micros>`),
+/// which flamegraph tools read directly. `None` without the `profiling`
+/// feature.
+#[cfg(feature = "profiling")]
+pub fn folded() -> Option<String> {
+    Some(imp::folded())
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn folded() -> Option<String> {
+    None
+}
+
+#[cfg(feature = "profiling")]
+mod imp {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    // Time spent polling each path, excluding named futures polled within it
+    static SELF_TIME: OnceLock<Mutex<HashMap<Arc<str>, Duration>>> = OnceLock::new();
+
+    thread_local! {
+        // Named futures currently being polled on this thread, with the time
+        // spent in their named children so far
+        static POLLING: RefCell<Vec<(Arc<str>, Duration)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub struct Profiled<F> {
+        path: Arc<str>,
+        fut: Pin<Box<F>>,
+    }
+
+    impl<F: Future> Profiled<F> {
+        pub fn new(name: &str, fut: F) -> Self {
+            // Frame separators in names would split the stack
+            let name = name.replace(';', ",");
+            let path = POLLING.with(|polling| match polling.borrow().last() {
+                Some((parent, _)) => format!("{};{}", parent, name),
+                None => name,
+            });
+            Self {
+                path: path.into(),
+                fut: Box::pin(fut),
+            }
+        }
+    }
+
+    impl<F: Future> Future for Profiled<F> {
+        type Output = F::Output;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+            POLLING.with(|polling| {
+                polling
+                    .borrow_mut()
+                    .push((Arc::clone(&self.path), Duration::ZERO))
+            });
+            let start = Instant::now();
+            let result = self.fut.as_mut().poll(cx);
+            let elapsed = start.elapsed();
+            let children = POLLING.with(|polling| {
+                let mut polling = polling.borrow_mut();
+                let (_, children) = polling.pop().unwrap_or_default();
+                if let Some((_, parent_children)) = polling.last_mut() {
+                    *parent_children += elapsed;
+                }
+                children
+            });
+            let mut times = SELF_TIME.get_or_init(Default::default).lock().unwrap();
+            *times.entry(Arc::clone(&self.path)).or_default() += elapsed.saturating_sub(children);
+            result
+        }
+    }
+
+    pub fn folded() -> String {
+        let times = SELF_TIME.get_or_init(Default::default).lock().unwrap();
+        let mut lines: Vec<String> = times
+            .iter()
+            .map(|(path, time)| format!("{} {}\n", path, time.as_micros()))
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
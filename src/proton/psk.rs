@@ -0,0 +1,104 @@
+use crate::proton::{ProtonError, STREAM_AUTH, STREAM_TIMEOUT};
+use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
+use ring::hmac;
+use std::path::Path;
+use tokio::time::timeout;
+
+// Proofs are bound to the TLS session through a keying material exporter so
+// they cannot be replayed on another connection
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-proton-psk";
+const CLIENT_ROLE: &[u8] = b"proton psk client";
+const SERVER_ROLE: &[u8] = b"proton psk server";
+const PROOF_LEN: usize = 32;
+
+/// Shortest shared secret accepted for PSK authentication.
+pub const MIN_PSK_LEN: usize = 16;
+
+/// Reads a shared secret from a file, ignoring trailing whitespace.
+pub fn load_psk(path: &Path) -> Result<Vec<u8>, ProtonError> {
+    let mut psk = std::fs::read(path)?;
+    while psk.last().is_some_and(|b| b.is_ascii_whitespace()) {
+        psk.pop();
+    }
+    if psk.len() < MIN_PSK_LEN {
+        return Err(ProtonError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("pre-shared key must be at least {} bytes", MIN_PSK_LEN),
+        )));
+    }
+    Ok(psk)
+}
+
+fn session_binding(connection: &QuinnConnection) -> Result<[u8; 32], ProtonError> {
+    let mut binding = [0u8; 32];
+    connection
+        .export_keying_material(&mut binding, EXPORTER_LABEL, b"")
+        .map_err(|_| ProtonError::AuthenticationFailed)?;
+    Ok(binding)
+}
+
+fn proof_key(
+    psk: &[u8],
+    connection: &QuinnConnection,
+) -> Result<(hmac::Key, [u8; 32]), ProtonError> {
+    Ok((
+        hmac::Key::new(hmac::HMAC_SHA256, psk),
+        session_binding(connection)?,
+    ))
+}
+
+fn sign(key: &hmac::Key, role: &[u8], binding: &[u8]) -> Vec<u8> {
+    let mut ctx = hmac::Context::with_key(key);
+    ctx.update(role);
+    ctx.update(binding);
+    ctx.sign().as_ref().to_vec()
+}
+
+fn verify(key: &hmac::Key, role: &[u8], binding: &[u8], proof: &[u8]) -> Result<(), ProtonError> {
+    let mut msg = role.to_vec();
+    msg.extend_from_slice(binding);
+    hmac::verify(key, &msg, proof).map_err(|_| ProtonError::AuthenticationFailed)
+}
+
+/// Client half of the PSK handshake: opens the auth stream, proves knowledge
+/// of the key and checks the server's proof in return.
+pub async fn authenticate_client(
+    connection: &QuinnConnection,
+    psk: &[u8],
+) -> Result<(), ProtonError> {
+    let (key, binding) = proof_key(psk, connection)?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    let mut request = vec![STREAM_AUTH];
+    request.extend_from_slice(&sign(&key, CLIENT_ROLE, &binding));
+    timeout(STREAM_TIMEOUT, send.write_all(&request)).await??;
+
+    let mut server_proof = [0u8; PROOF_LEN];
+    timeout(STREAM_TIMEOUT, recv.read_exact(&mut server_proof)).await??;
+    verify(&key, SERVER_ROLE, &binding, &server_proof)?;
+    let _ = send.finish().await;
+    Ok(())
+}
+
+/// Server half of the PSK handshake, run on an accepted stream whose
+/// `STREAM_AUTH` discriminator has already been read.
+pub async fn authenticate_server(
+    connection: &QuinnConnection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    psk: &[u8],
+) -> Result<(), ProtonError> {
+    let (key, binding) = proof_key(psk, connection)?;
+
+    let mut client_proof = [0u8; PROOF_LEN];
+    timeout(STREAM_TIMEOUT, recv.read_exact(&mut client_proof)).await??;
+    verify(&key, CLIENT_ROLE, &binding, &client_proof)?;
+
+    timeout(
+        STREAM_TIMEOUT,
+        send.write_all(&sign(&key, SERVER_ROLE, &binding)),
+    )
+    .await??;
+    let _ = send.finish().await;
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! Actions pushed by the server. Instead of the client asking for each
+//! action, the server writes actions to a unidirectional stream as its
+//! handler produces them, keeping up to a window of them unacknowledged.
+//! The client writes its acknowledged offset on the action stream whenever
+//! it moves, which makes room for more.
+
+use crate::proton::efficiency::SentFrames;
+use crate::proton::frame::Headers;
+use crate::proton::fsm::{StreamState, StreamStates};
+use crate::proton::profile::spawn_named;
+use crate::proton::wire::STREAM_ACTION_PUSH;
+use crate::proton::{Frame, Framing, MessageKind, ProtonError, STREAM_TIMEOUT};
+use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
+use smallvec::SmallVec;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+/// Most unacknowledged actions a server pushes to a client.
+pub const MAX_PUSHED_ACTIONS: u32 = 64;
+
+// Wait before pushing again once the handler refused an action for quota
+pub(crate) const QUOTA_BACKOFF: Duration = Duration::from_secs(1);
+
+// The consumer offset, shared with the connection, and a wakeup for the
+// task writing it to the server
+#[derive(Clone)]
+struct Acks {
+    offset: Arc<AtomicU32>,
+    moved: Arc<Notify>,
+}
+
+impl Acks {
+    fn ack_up_to(&self, id: u32) {
+        if self.offset.fetch_max(id, Ordering::Relaxed) < id {
+            self.moved.notify_one();
+        }
+    }
+}
+
+/// Pushed actions of one connection, read by a background task.
+pub(crate) struct ActionPush {
+    // Until taken by `ProtonConnection::actions`
+    actions: Option<mpsc::Receiver<Frame>>,
+    acks: Acks,
+}
+
+impl ActionPush {
+    /// Takes over the action stream `send`/`recv` for acknowledgements and
+    /// reads the actions pushed on the stream the server opens, up to
+    /// `window` of them ahead of `offset`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn start(
+        connection: QuinnConnection,
+        send: SendStream,
+        recv: RecvStream,
+        framing: Framing,
+        headers: Option<Headers>,
+        window: u32,
+        offset: Arc<AtomicU32>,
+        states: StreamStates,
+        sent: SentFrames,
+    ) -> Self {
+        let (tx, actions) = mpsc::channel(window as usize);
+        let acks = Acks {
+            offset,
+            moved: Arc::new(Notify::new()),
+        };
+        let task = run(
+            connection,
+            send,
+            recv,
+            framing,
+            headers,
+            acks.clone(),
+            tx,
+            states,
+            sent,
+        );
+        spawn_named("action push", task);
+        Self {
+            actions: Some(actions),
+            acks,
+        }
+    }
+
+    /// The next pushed action, unless `ProtonConnection::actions` took them.
+    pub(crate) async fn next(&mut self, deadline: Duration) -> Result<Frame, ProtonError> {
+        let actions = self.actions.as_mut().ok_or(ProtonError::InvalidStream)?;
+        match timeout(deadline, actions.recv()).await {
+            Ok(Some(action)) => Ok(action),
+            Ok(None) => Err(ProtonError::ConnectionError),
+            Err(_) => Err(ProtonError::Timeout),
+        }
+    }
+
+    pub(crate) fn take(&mut self, auto_ack: bool) -> Option<ActionStream> {
+        Some(ActionStream {
+            actions: self.actions.take()?,
+            acks: self.acks.clone(),
+            auto_ack,
+        })
+    }
+
+    /// Sends the consumer offset, which the caller has just moved.
+    pub(crate) fn acked(&self) {
+        self.acks.moved.notify_one();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    connection: QuinnConnection,
+    mut send: SendStream,
+    recv: RecvStream,
+    framing: Framing,
+    headers: Option<Headers>,
+    acks: Acks,
+    actions: mpsc::Sender<Frame>,
+    states: StreamStates,
+    sent: SentFrames,
+) {
+    let read = async {
+        let mut push = timeout(STREAM_TIMEOUT, connection.accept_uni()).await??;
+        let mut discriminator = [0u8; 1];
+        push.read_exact(&mut discriminator).await?;
+        if discriminator[0] != STREAM_ACTION_PUSH {
+            return Err(ProtonError::InvalidStream);
+        }
+        let id = push.id();
+        states.track(id, "action push", StreamState::Ready);
+        loop {
+            // Actions come whenever the server has one, so only the rest of
+            // a frame is read under the stream timeout
+            let mut prefix = [0u8; 4];
+            if let Err(e) = push.read_exact(&mut prefix).await {
+                states.enter(id, StreamState::Errored(e.to_string()));
+                return Err(e.into());
+            }
+            let action = framing
+                .read_rest(MessageKind::Action, &mut push, prefix)
+                .await?;
+            if actions.send(action).await.is_err() {
+                info!("Pushed actions are no longer read");
+                states.forget(id);
+                return Ok(());
+            }
+        }
+    };
+
+    // The offset goes out once up front, then each time it moves
+    let write = async {
+        let mut written = None;
+        loop {
+            let offset = acks.offset.load(Ordering::Relaxed);
+            if written != Some(offset) {
+                let mut frame: SmallVec<[u8; 64]> = SmallVec::new();
+                framing.encode_into(MessageKind::Ack, &Frame::new(offset), &mut frame)?;
+                if let Some(ref headers) = headers {
+                    headers.encode_into(&mut frame);
+                }
+                timeout(STREAM_TIMEOUT, send.write_all(&frame)).await??;
+                sent.record(1, frame.len());
+                written = Some(offset);
+            }
+            acks.moved.notified().await;
+        }
+    };
+
+    let result: Result<(), ProtonError> = tokio::select! {
+        r = read => r,
+        r = write => r,
+    };
+    if let Err(e) = result {
+        if connection.close_reason().is_none() {
+            warn!("Action push stopped: {}", e);
+        }
+    }
+    // Kept until here so the server does not see the action stream stopped
+    drop(recv);
+}
+
+/// Actions as the server pushes them, returned by
+/// `ProtonConnection::actions`. The stream ends when the connection is
+/// lost; actions it yielded but nobody acknowledged are pushed again on the
+/// next connection.
+pub struct ActionStream {
+    actions: mpsc::Receiver<Frame>,
+    acks: Acks,
+    // Whether each action is acknowledged as it is yielded
+    auto_ack: bool,
+}
+
+impl ActionStream {
+    /// Acknowledges every action up to and including `id` as consumed,
+    /// like `ProtonConnection::ack_up_to`.
+    pub fn ack_up_to(&self, id: u32) {
+        self.acks.ack_up_to(id);
+    }
+}
+
+impl futures_core::Stream for ActionStream {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Frame>> {
+        let polled = self.actions.poll_recv(cx);
+        if let Poll::Ready(Some(ref action)) = polled {
+            info!("Received pushed action: {}", action.id);
+            if self.auto_ack {
+                self.acks.ack_up_to(action.id);
+            }
+        }
+        polled
+    }
+}
+
+impl fmt::Debug for ActionStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActionStream")
+            .field("offset", &self.acks.offset.load(Ordering::Relaxed))
+            .field("auto_ack", &self.auto_ack)
+            .finish()
+    }
+}
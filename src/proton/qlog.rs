@@ -0,0 +1,196 @@
+//! Packet traces in qlog's JSON-SEQ format (`.sqlog`), for loading into
+//! qvis. quinn 0.10 has no qlog support of its own, so the trace is taken
+//! at the endpoint's socket: one event per UDP datagram sent or received,
+//! typed by the header of its first QUIC packet. Packet numbers and frames
+//! are encrypted and not shown, but the timing, sizes and packet types of
+//! the handshake, losses and retransmission bursts are.
+
+use crate::proton::ProtonError;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+// Trace files kept open at once; the least recently used is closed when a
+// new peer would exceed this, and a peer seen again starts a new file
+const MAX_OPEN_TRACES: usize = 256;
+
+// Long header packet types, RFC 9000 section 17.2
+const LONG_PACKET_TYPES: [&str; 4] = ["initial", "0RTT", "handshake", "retry"];
+
+/// Which end of the connections a trace is taken at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vantage {
+    Client,
+    Server,
+}
+
+impl Vantage {
+    fn name(self) -> &'static str {
+        match self {
+            Vantage::Client => "client",
+            Vantage::Server => "server",
+        }
+    }
+}
+
+/// Whether, and where, an endpoint traces its packets, shared with the
+/// endpoint's socket like its `Marking` so tracing can start while it runs.
+#[derive(Debug, Clone, Default)]
+pub struct PacketTrace(Arc<OnceLock<Traces>>);
+
+impl PacketTrace {
+    /// Start writing a trace per peer into `dir`, created if missing.
+    /// Tracing cannot be moved to another directory once started.
+    pub fn enable(&self, dir: &Path, vantage: Vantage) -> Result<(), ProtonError> {
+        std::fs::create_dir_all(dir)?;
+        let traces = Traces {
+            dir: dir.to_path_buf(),
+            vantage,
+            files: Mutex::new(HashMap::new()),
+        };
+        if self.0.set(traces).is_err() {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "packet tracing is already enabled",
+            )));
+        }
+        info!("Writing qlog packet traces to {}", dir.display());
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.get().is_some()
+    }
+
+    // Records `contents` sent to `peer`, split into `segment_size` datagrams
+    // when the socket sends several at once
+    pub(crate) fn sent(&self, peer: SocketAddr, contents: &[u8], segment_size: Option<usize>) {
+        if let Some(traces) = self.0.get() {
+            traces.record(peer, "transport:packet_sent", contents, segment_size);
+        }
+    }
+
+    // Records `contents` received from `peer`, `stride` bytes per datagram
+    pub(crate) fn received(&self, peer: SocketAddr, contents: &[u8], stride: usize) {
+        if let Some(traces) = self.0.get() {
+            traces.record(peer, "transport:packet_received", contents, Some(stride));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Traces {
+    dir: PathBuf,
+    vantage: Vantage,
+    files: Mutex<HashMap<SocketAddr, Trace>>,
+}
+
+#[derive(Debug)]
+struct Trace {
+    file: LineWriter<File>,
+    started: Instant,
+    last_used: Instant,
+}
+
+impl Traces {
+    fn record(&self, peer: SocketAddr, name: &str, contents: &[u8], segment_size: Option<usize>) {
+        let mut files = self.files.lock().unwrap();
+        let now = Instant::now();
+        if !files.contains_key(&peer) {
+            if files.len() >= MAX_OPEN_TRACES {
+                if let Some(oldest) = files
+                    .iter()
+                    .min_by_key(|(_, trace)| trace.last_used)
+                    .map(|(addr, _)| *addr)
+                {
+                    files.remove(&oldest);
+                }
+            }
+            match self.open(peer, now) {
+                Ok(trace) => {
+                    files.insert(peer, trace);
+                }
+                Err(e) => {
+                    debug!("Not tracing packets of {}: {}", peer, e);
+                    return;
+                }
+            }
+        }
+        let trace = files.get_mut(&peer).unwrap();
+        trace.last_used = now;
+        let time = now.duration_since(trace.started).as_secs_f64() * 1000.0;
+        let size = segment_size
+            .filter(|&size| size > 0)
+            .unwrap_or(contents.len());
+        let result = contents.chunks(size.max(1)).try_for_each(|datagram| {
+            let event = format!(
+                "\x1e{{\"time\":{:.3},\"name\":\"{}\",\"data\":{{\"header\":{{\"packet_type\":\"{}\"}},\"raw\":{{\"length\":{}}}}}}}\n",
+                time,
+                name,
+                packet_type(datagram),
+                datagram.len()
+            );
+            trace.file.write_all(event.as_bytes())
+        });
+        if let Err(e) = result {
+            debug!("Dropping packet trace of {}: {}", peer, e);
+            files.remove(&peer);
+        }
+    }
+
+    fn open(&self, peer: SocketAddr, now: Instant) -> std::io::Result<Trace> {
+        let reference = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let peer_name: String = peer
+            .to_string()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = self.dir.join(format!(
+            "{}-{}-{}.sqlog",
+            self.vantage.name(),
+            peer_name,
+            reference
+        ));
+        let mut file = LineWriter::new(File::create(&path)?);
+        writeln!(
+            file,
+            "\x1e{{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"title\":\"proton {} {}\",\
+             \"trace\":{{\"vantage_point\":{{\"type\":\"{}\"}},\
+             \"common_fields\":{{\"time_format\":\"relative\",\"reference_time\":{}}}}}}}",
+            self.vantage.name(),
+            peer,
+            self.vantage.name(),
+            reference
+        )?;
+        debug!("Tracing packets of {} to {}", peer, path.display());
+        Ok(Trace {
+            file,
+            started: now,
+            last_used: now,
+        })
+    }
+}
+
+// qlog name of the type of the first QUIC packet in `datagram`
+fn packet_type(datagram: &[u8]) -> &'static str {
+    match datagram {
+        [] => "unknown",
+        [first, ..] if first & 0x80 == 0 => "1RTT",
+        [_, 0, 0, 0, 0, ..] => "version_negotiation",
+        [first, ..] => LONG_PACKET_TYPES[usize::from(first >> 4 & 0b11)],
+    }
+}
@@ -0,0 +1,342 @@
+use crate::proton::snapshot::UsageRecord;
+use crate::proton::ProtonError;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Byte quotas applied to every tenant. Both directions count towards them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Bytes per UTC day
+    pub daily_bytes: Option<u64>,
+    /// Bytes per UTC calendar month
+    pub monthly_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct TenantUsage {
+    received: u64,
+    sent: u64,
+    day: i64,
+    day_bytes: u64,
+    month: i64,
+    month_bytes: u64,
+}
+
+impl TenantUsage {
+    // Start new quota windows when the day or month has rolled over
+    fn roll(&mut self, day: i64) {
+        if self.day != day {
+            self.day = day;
+            self.day_bytes = 0;
+        }
+        let month = month_index(day);
+        if self.month != month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.day_bytes += bytes;
+        self.month_bytes += bytes;
+    }
+
+    fn exceeds(&self, quota: &Quota) -> bool {
+        quota
+            .daily_bytes
+            .is_some_and(|limit| self.day_bytes >= limit)
+            || quota
+                .monthly_bytes
+                .is_some_and(|limit| self.month_bytes >= limit)
+    }
+}
+
+/// Usage of one tenant, as reported by `UsageLedger::report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantReport {
+    pub tenant: String,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_today: u64,
+    pub bytes_this_month: u64,
+    pub over_quota: bool,
+}
+
+impl fmt::Display for TenantReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} received={} sent={} today={} month={}{}",
+            self.tenant,
+            self.bytes_received,
+            self.bytes_sent,
+            self.bytes_today,
+            self.bytes_this_month,
+            if self.over_quota { " over-quota" } else { "" }
+        )
+    }
+}
+
+/// Application-layer bytes exchanged per tenant: request frames and their
+/// headers received, responses sent. Transport overhead is not counted.
+#[derive(Debug, Default)]
+pub struct UsageLedger {
+    quota: Quota,
+    tenants: Mutex<HashMap<String, TenantUsage>>,
+}
+
+impl UsageLedger {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Records a request received from `tenant`. Fails if the tenant had
+    /// already used up its quota, in which case the request must not be
+    /// processed; its bytes are still counted.
+    pub fn record_received(&self, tenant: &str, bytes: u64) -> Result<(), ProtonError> {
+        let mut tenants = self.tenants.lock().unwrap();
+        let usage = tenant_usage(&mut tenants, tenant);
+        usage.roll(today());
+        let exceeded = usage.exceeds(&self.quota);
+        usage.received += bytes;
+        usage.add(bytes);
+        if exceeded {
+            return Err(ProtonError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    pub fn record_sent(&self, tenant: &str, bytes: u64) {
+        let mut tenants = self.tenants.lock().unwrap();
+        let usage = tenant_usage(&mut tenants, tenant);
+        usage.roll(today());
+        usage.sent += bytes;
+        usage.add(bytes);
+    }
+
+    pub(crate) fn export(&self) -> Vec<UsageRecord> {
+        let mut records: Vec<UsageRecord> = self
+            .tenants
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tenant, usage)| UsageRecord {
+                tenant: tenant.clone(),
+                received: usage.received,
+                sent: usage.sent,
+                day: usage.day,
+                day_bytes: usage.day_bytes,
+                month: usage.month,
+                month_bytes: usage.month_bytes,
+            })
+            .collect();
+        records.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        records
+    }
+
+    pub(crate) fn import(&self, records: &[UsageRecord]) {
+        let mut tenants = self.tenants.lock().unwrap();
+        for r in records {
+            tenants.insert(
+                r.tenant.clone(),
+                TenantUsage {
+                    received: r.received,
+                    sent: r.sent,
+                    day: r.day,
+                    day_bytes: r.day_bytes,
+                    month: r.month,
+                    month_bytes: r.month_bytes,
+                },
+            );
+        }
+    }
+
+    /// Usage of every tenant seen since the server started, by tenant name.
+    pub fn report(&self) -> Vec<TenantReport> {
+        let day = today();
+        let mut tenants = self.tenants.lock().unwrap();
+        let mut report: Vec<TenantReport> = tenants
+            .iter_mut()
+            .map(|(tenant, usage)| {
+                usage.roll(day);
+                TenantReport {
+                    tenant: tenant.clone(),
+                    bytes_received: usage.received,
+                    bytes_sent: usage.sent,
+                    bytes_today: usage.day_bytes,
+                    bytes_this_month: usage.month_bytes,
+                    over_quota: usage.exceeds(&self.quota),
+                }
+            })
+            .collect();
+        report.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        report
+    }
+
+    /// Per tenant byte counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let report = self.report();
+        let mut out = String::new();
+        tenant_counter(
+            &mut out,
+            "proton_tenant_bytes_received_total",
+            "Application bytes received from each tenant",
+            &report,
+            |r| r.bytes_received,
+        );
+        tenant_counter(
+            &mut out,
+            "proton_tenant_bytes_sent_total",
+            "Application bytes sent to each tenant",
+            &report,
+            |r| r.bytes_sent,
+        );
+        out
+    }
+}
+
+// Looks the tenant up by reference, so the key is only allocated the first
+// time a tenant is seen rather than on every request
+fn tenant_usage<'a>(
+    tenants: &'a mut HashMap<String, TenantUsage>,
+    tenant: &str,
+) -> &'a mut TenantUsage {
+    if !tenants.contains_key(tenant) {
+        tenants.insert(tenant.to_string(), TenantUsage::default());
+    }
+    tenants.get_mut(tenant).unwrap()
+}
+
+fn tenant_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    report: &[TenantReport],
+    value: impl Fn(&TenantReport) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for r in report {
+        let _ = writeln!(out, "{}{{tenant=\"{}\"}} {}", name, r.tenant, value(r));
+    }
+}
+
+// Days since the Unix epoch, in UTC
+fn today() -> i64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    (secs / 86400) as i64
+}
+
+// Months since year 0 for a day since the Unix epoch, using the civil from
+// days algorithm (proleptic Gregorian calendar)
+fn month_index(days: i64) -> i64 {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    year * 12 + month - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn month(year: i64, month: i64) -> i64 {
+        year * 12 + month - 1
+    }
+
+    #[test]
+    fn month_boundaries() {
+        assert_eq!(month_index(0), month(1970, 1));
+        assert_eq!(month_index(-1), month(1969, 12));
+        assert_eq!(month_index(19722), month(2023, 12));
+        assert_eq!(month_index(19723), month(2024, 1));
+        // Leap days belong to February, including in a leap century
+        assert_eq!(month_index(19782), month(2024, 2));
+        assert_eq!(month_index(19783), month(2024, 3));
+        assert_eq!(month_index(11016), month(2000, 2));
+        assert_eq!(month_index(11017), month(2000, 3));
+    }
+
+    #[test]
+    fn windows_roll_over() {
+        let mut usage = TenantUsage::default();
+        usage.roll(19781);
+        usage.add(10);
+        // A new day in the same month keeps the month's bytes
+        usage.roll(19782);
+        assert_eq!((usage.day_bytes, usage.month_bytes), (0, 10));
+        usage.add(5);
+        // A new month starts both windows again
+        usage.roll(19783);
+        assert_eq!((usage.day_bytes, usage.month_bytes), (0, 0));
+    }
+
+    #[test]
+    fn requests_past_the_quota_are_refused() {
+        let ledger = UsageLedger::new(Quota {
+            daily_bytes: Some(100),
+            monthly_bytes: None,
+        });
+        // The request crossing the limit is still handled
+        ledger.record_received("acme", 60).unwrap();
+        ledger.record_received("acme", 60).unwrap();
+        assert!(matches!(
+            ledger.record_received("acme", 1),
+            Err(ProtonError::QuotaExceeded)
+        ));
+        // Other tenants have their own quota
+        ledger.record_received("globex", 60).unwrap();
+
+        let report = ledger.report();
+        assert_eq!(report[0].tenant, "acme");
+        assert_eq!(report[0].bytes_received, 121);
+        assert!(report[0].over_quota);
+        assert!(!report[1].over_quota);
+    }
+
+    #[test]
+    fn responses_count_towards_the_quota() {
+        let ledger = UsageLedger::new(Quota {
+            daily_bytes: None,
+            monthly_bytes: Some(10),
+        });
+        ledger.record_sent("acme", 10);
+        assert!(ledger.record_received("acme", 1).is_err());
+        assert_eq!(ledger.report()[0].bytes_sent, 10);
+    }
+
+    #[test]
+    fn usage_survives_export_and_import() {
+        let ledger = UsageLedger::default();
+        ledger.record_received("b", 3).unwrap();
+        ledger.record_sent("a", 4);
+        let records = ledger.export();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tenant, "a");
+
+        let restored = UsageLedger::default();
+        restored.import(&records);
+        assert_eq!(restored.report(), ledger.report());
+        assert_eq!(restored.render(), ledger.render());
+        assert!(ledger
+            .render()
+            .contains("proton_tenant_bytes_received_total{tenant=\"b\"} 3"));
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Prune idle buckets once the table grows past this many sources
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Connection attempts per second refilled into each source's bucket
+    pub rate: f64,
+    /// Maximum bucket size, i.e. attempts allowed in a burst
+    pub burst: u32,
+    /// Number of rate limited attempts in a row after which a source is
+    /// banned
+    pub ban_after: u32,
+    /// How long a banned source is refused outright
+    pub ban_duration: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            burst: 10,
+            ban_after: 5,
+            ban_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    Allowed,
+    Limited,
+    Banned,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Per source IP token bucket for new connection attempts.
+#[derive(Debug)]
+pub struct ConnectionRateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, ip: IpAddr) -> RateDecision {
+        let now = Instant::now();
+        if self.buckets.len() > PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+
+        let config = self.config;
+        let bucket = self.buckets.entry(ip).or_insert_with(|| Bucket {
+            tokens: config.burst as f64,
+            last_refill: now,
+            strikes: 0,
+            banned_until: None,
+        });
+
+        if let Some(until) = bucket.banned_until {
+            if now < until {
+                return RateDecision::Banned;
+            }
+            bucket.banned_until = None;
+            bucket.strikes = 0;
+        }
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * config.rate).min(config.burst as f64);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            // Strikes count limited attempts in a row, so a source that backs
+            // off to its rate is not banned for occasional bursts
+            bucket.strikes = 0;
+            return RateDecision::Allowed;
+        }
+
+        bucket.strikes += 1;
+        if bucket.strikes >= config.ban_after {
+            bucket.banned_until = Some(now + config.ban_duration);
+            RateDecision::Banned
+        } else {
+            RateDecision::Limited
+        }
+    }
+
+    pub fn banned_sources(&self) -> usize {
+        let now = Instant::now();
+        self.buckets
+            .values()
+            .filter(|b| b.banned_until.is_some_and(|until| now < until))
+            .count()
+    }
+
+    // Drop sources that are not banned and whose bucket has fully refilled
+    fn prune(&mut self, now: Instant) {
+        let config = self.config;
+        self.buckets.retain(|_, b| {
+            let banned = b.banned_until.is_some_and(|until| now < until);
+            let refilled = b.tokens + now.duration_since(b.last_refill).as_secs_f64() * config.rate
+                >= config.burst as f64;
+            banned || !refilled
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // No refill, so only the burst decides what is allowed
+    fn limiter(burst: u32, ban_after: u32, ban_duration: Duration) -> ConnectionRateLimiter {
+        ConnectionRateLimiter::new(RateLimitConfig {
+            rate: 0.0,
+            burst,
+            ban_after,
+            ban_duration,
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn burst_then_limit_then_ban() {
+        let mut limiter = limiter(2, 2, Duration::from_secs(60));
+        let source = ip("192.0.2.1");
+        assert_eq!(limiter.check(source), RateDecision::Allowed);
+        assert_eq!(limiter.check(source), RateDecision::Allowed);
+        assert_eq!(limiter.check(source), RateDecision::Limited);
+        assert_eq!(limiter.check(source), RateDecision::Banned);
+        assert_eq!(limiter.check(source), RateDecision::Banned);
+        assert_eq!(limiter.banned_sources(), 1);
+        // Other sources have their own bucket
+        assert_eq!(limiter.check(ip("192.0.2.2")), RateDecision::Allowed);
+    }
+
+    #[test]
+    fn zero_burst_limits_every_attempt() {
+        let mut limiter = limiter(0, 3, Duration::from_secs(60));
+        let source = ip("::1");
+        assert_eq!(limiter.check(source), RateDecision::Limited);
+        assert_eq!(limiter.check(source), RateDecision::Limited);
+        assert_eq!(limiter.check(source), RateDecision::Banned);
+    }
+
+    #[test]
+    fn expired_bans_clear_strikes() {
+        let mut limiter = limiter(0, 2, Duration::ZERO);
+        let source = ip("192.0.2.1");
+        assert_eq!(limiter.check(source), RateDecision::Limited);
+        assert_eq!(limiter.check(source), RateDecision::Banned);
+        assert_eq!(limiter.banned_sources(), 0);
+        // The ban is over, so the source starts again from its first strike
+        assert_eq!(limiter.check(source), RateDecision::Limited);
+    }
+
+    #[test]
+    fn allowed_attempts_clear_strikes() {
+        let mut limiter = limiter(1, 2, Duration::from_secs(60));
+        let source = ip("192.0.2.1");
+        for _ in 0..3 {
+            assert_eq!(limiter.check(source), RateDecision::Allowed);
+            assert_eq!(limiter.check(source), RateDecision::Limited);
+            // Stand in for the source waiting out its refill
+            limiter.buckets.get_mut(&source).unwrap().tokens = 1.0;
+        }
+        assert_eq!(limiter.banned_sources(), 0);
+    }
+
+    #[test]
+    fn tokens_refill_up_to_the_burst() {
+        let mut limiter = ConnectionRateLimiter::new(RateLimitConfig {
+            rate: 1e9,
+            burst: 1,
+            ban_after: 1,
+            ban_duration: Duration::from_secs(60),
+        });
+        let source = ip("192.0.2.1");
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(1));
+            assert_eq!(limiter.check(source), RateDecision::Allowed);
+        }
+        assert!(limiter.buckets[&source].tokens <= 1.0);
+    }
+
+    #[test]
+    fn pruning_keeps_banned_and_draining_sources() {
+        let mut limiter = limiter(1, 1, Duration::from_secs(60));
+        let banned = ip("192.0.2.1");
+        limiter.check(banned);
+        limiter.check(banned);
+        for i in 0..=PRUNE_THRESHOLD as u32 {
+            limiter.check(IpAddr::from((0x0a00_0000 + i).to_be_bytes()));
+        }
+        limiter.prune(Instant::now());
+        // Without refill no bucket is full again, so none are dropped
+        assert_eq!(limiter.buckets.len(), PRUNE_THRESHOLD + 2);
+
+        let mut refilled = ConnectionRateLimiter::new(RateLimitConfig {
+            rate: 1e9,
+            ..limiter.config
+        });
+        refilled.check(ip("192.0.2.9"));
+        std::thread::sleep(Duration::from_millis(1));
+        refilled.prune(Instant::now());
+        assert!(refilled.buckets.is_empty());
+    }
+}
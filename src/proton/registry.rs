@@ -0,0 +1,495 @@
+use crate::proton::efficiency::{DatagramEfficiency, SentFrames};
+use crate::proton::fsm::{StreamStates, StreamStatus};
+use crate::proton::heartbeat::{Heartbeat, HeartbeatStats};
+use crate::proton::hello::PeerInfo;
+use crate::proton::park::Parking;
+use crate::proton::{check_payload_len, CloseReason, Frame, ProtonError};
+use quinn::Connection as QuinnConnection;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tracing::info;
+
+pub use crate::proton::wire::CLOSE_BY_OPERATOR;
+
+/// Identifies a connection for as long as the server runs; never reused.
+pub type ConnectionId = u64;
+
+// Requests served on one connection
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionCounters {
+    pub(crate) events: AtomicU64,
+    pub(crate) state_commits: AtomicU64,
+    pub(crate) actions: AtomicU64,
+    pub(crate) keepalives: AtomicU64,
+    pub(crate) datagrams: AtomicU64,
+}
+
+// Remote addresses of a connection kept, the current one included
+const ADDR_HISTORY: usize = 8;
+
+#[derive(Debug)]
+struct Entry {
+    id: ConnectionId,
+    // Most recent last, and never empty
+    addrs: Mutex<VecDeque<SocketAddr>>,
+    migrations: AtomicU64,
+    key_updates: AtomicU64,
+    peer: PeerInfo,
+    tenant: String,
+    connected_at: SystemTime,
+    connection: QuinnConnection,
+    labels: Mutex<BTreeMap<String, String>>,
+    counters: ConnectionCounters,
+    // Actions pushed to this connection, delivered ahead of the sequence
+    pushed: Mutex<VecDeque<Frame>>,
+    // Wakes a connection pushing actions once one is queued
+    pushed_ready: Notify,
+    streams: StreamStates,
+    heartbeat: Arc<Heartbeat>,
+    sent: SentFrames,
+    parking: Parking,
+}
+
+/// A live connection in the registry. Cheap to clone; it stays usable after
+/// the connection ends, when `close` and `push_action` have no effect.
+#[derive(Debug, Clone)]
+pub struct RegisteredConnection {
+    entry: Arc<Entry>,
+}
+
+impl RegisteredConnection {
+    pub fn id(&self) -> ConnectionId {
+        self.entry.id
+    }
+
+    /// The client's current address, which changes if it migrates.
+    pub fn addr(&self) -> SocketAddr {
+        *self.entry.addrs.lock().unwrap().back().unwrap()
+    }
+
+    /// The last few addresses the client used, oldest first and the
+    /// current one last.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.entry.addrs.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Times the client's address changed, by connection migration or NAT
+    /// rebinding.
+    pub fn migrations(&self) -> u64 {
+        self.entry.migrations.load(Ordering::Relaxed)
+    }
+
+    /// Metadata the client sent in its HELLO.
+    pub fn peer(&self) -> &PeerInfo {
+        &self.entry.peer
+    }
+
+    /// Identity usage is accounted to: the HELLO tenant or the source address.
+    pub fn tenant(&self) -> &str {
+        &self.entry.tenant
+    }
+
+    pub fn connected_at(&self) -> SystemTime {
+        self.entry.connected_at
+    }
+
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        self.entry.labels.lock().unwrap().clone()
+    }
+
+    /// Tags the connection, replacing any value already set for `key`.
+    pub fn set_label(&self, key: &str, value: &str) {
+        self.entry
+            .labels
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Protocol state of each of the connection's open streams.
+    pub fn streams(&self) -> Vec<StreamStatus> {
+        self.entry.streams.list()
+    }
+
+    /// Round trips measured by heartbeats, if the client asked for them.
+    /// Whether the connection is parked for being idle, see
+    /// `ProtonServer::with_parking`.
+    pub fn is_parked(&self) -> bool {
+        self.entry.parking.is_parked()
+    }
+
+    pub fn heartbeat(&self) -> Option<HeartbeatStats> {
+        self.entry.heartbeat.stats()
+    }
+
+    pub fn stats(&self) -> ConnectionSnapshot {
+        let counters = &self.entry.counters;
+        let quic = self.entry.connection.stats();
+        ConnectionSnapshot {
+            id: self.entry.id,
+            addr: self.addr(),
+            migrations: self.migrations(),
+            key_updates: self.key_updates(),
+            parked: self.is_parked(),
+            previous_addrs: {
+                let mut addrs = self.addrs();
+                addrs.pop();
+                addrs
+            },
+            tenant: self.entry.tenant.clone(),
+            user_agent: self.entry.peer.user_agent.clone(),
+            labels: self.labels(),
+            uptime: self.entry.connected_at.elapsed().unwrap_or_default(),
+            rtt: self.entry.connection.rtt(),
+            events: counters.events.load(Ordering::Relaxed),
+            state_commits: counters.state_commits.load(Ordering::Relaxed),
+            actions: counters.actions.load(Ordering::Relaxed),
+            keepalives: counters.keepalives.load(Ordering::Relaxed),
+            datagrams: counters.datagrams.load(Ordering::Relaxed),
+            efficiency: self.entry.sent.efficiency(&quic.udp_tx),
+            quic,
+            streams: self.streams(),
+            heartbeat: self.heartbeat(),
+        }
+    }
+
+    /// Queues `action` for the client's next action request, ahead of the
+    /// action sequence, or for pushing right away if the client asked for
+    /// pushed actions. Pushed actions are out of band: they are not
+    /// redelivered after a reconnect and do not move the consumer offset.
+    pub fn push_action(&self, action: u32) {
+        self.entry
+            .pushed
+            .lock()
+            .unwrap()
+            .push_back(Frame::new(action));
+        self.entry.pushed_ready.notify_one();
+    }
+
+    /// Like `push_action`, with `payload` delivered alongside the action.
+    /// Clients that kept to fixed framing receive the action alone.
+    pub fn push_action_with_payload(
+        &self,
+        action: u32,
+        payload: Vec<u8>,
+    ) -> Result<(), ProtonError> {
+        check_payload_len(payload.len())?;
+        self.entry
+            .pushed
+            .lock()
+            .unwrap()
+            .push_back(Frame::with_payload(action, payload));
+        self.entry.pushed_ready.notify_one();
+        Ok(())
+    }
+
+    /// Starts a QUIC key update: both sides move to traffic keys derived
+    /// from the current ones, e.g. to keep to a crypto period on connections
+    /// that live for weeks. Logged and counted in the connection's stats.
+    /// quinn updates keys on its own too, before the AEAD limits are
+    /// reached, and does not report those or the client's.
+    pub fn update_keys(&self) {
+        let count = self.entry.key_updates.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(
+            "Updating keys of connection {} to {} (update {})",
+            self.id(),
+            self.addr(),
+            count
+        );
+        self.entry.connection.force_key_update();
+    }
+
+    /// Key updates started with `update_keys`.
+    pub fn key_updates(&self) -> u64 {
+        self.entry.key_updates.load(Ordering::Relaxed)
+    }
+
+    /// Closes the connection with `CloseReason::ByOperator` and `reason`.
+    pub fn close(&self, reason: &str) {
+        info!(
+            "Closing connection {} to {}: {}",
+            self.id(),
+            self.addr(),
+            reason
+        );
+        self.entry
+            .connection
+            .close(CloseReason::ByOperator.into(), reason.as_bytes());
+    }
+
+    // Notes a change of the client's address since the last call, returning
+    // the address it moved from
+    pub(crate) fn check_migrated(&self) -> Option<SocketAddr> {
+        let remote = self.entry.connection.remote_address();
+        let mut addrs = self.entry.addrs.lock().unwrap();
+        let previous = *addrs.back().unwrap();
+        if previous == remote {
+            return None;
+        }
+        if addrs.len() == ADDR_HISTORY {
+            addrs.pop_front();
+        }
+        addrs.push_back(remote);
+        self.entry.migrations.fetch_add(1, Ordering::Relaxed);
+        Some(previous)
+    }
+
+    pub(crate) fn counters(&self) -> &ConnectionCounters {
+        &self.entry.counters
+    }
+
+    // Requests and telemetry served so far; keepalives do not count, they
+    // are what an idle client sends
+    pub(crate) fn activity(&self) -> u64 {
+        let counters = &self.entry.counters;
+        [
+            &counters.events,
+            &counters.state_commits,
+            &counters.actions,
+            &counters.datagrams,
+        ]
+        .iter()
+        .map(|counter| counter.load(Ordering::Relaxed))
+        .sum()
+    }
+
+    pub(crate) fn parking(&self) -> &Parking {
+        &self.entry.parking
+    }
+
+    pub(crate) fn next_pushed_action(&self) -> Option<Frame> {
+        self.entry.pushed.lock().unwrap().pop_front()
+    }
+
+    // Resolves once an action may have been queued since the last call
+    pub(crate) async fn action_queued(&self) {
+        self.entry.pushed_ready.notified().await
+    }
+}
+
+/// Point in time view of a connection, as returned by
+/// `RegisteredConnection::stats`.
+#[derive(Debug, Clone)]
+pub struct ConnectionSnapshot {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    /// Times the client's address changed
+    pub migrations: u64,
+    /// The last few addresses the client moved from, oldest first
+    pub previous_addrs: Vec<SocketAddr>,
+    /// Key updates the server started, see `RegisteredConnection::update_keys`
+    pub key_updates: u64,
+    /// Parked for being idle
+    pub parked: bool,
+    pub tenant: String,
+    pub user_agent: String,
+    pub labels: BTreeMap<String, String>,
+    pub uptime: Duration,
+    pub rtt: Duration,
+    pub events: u64,
+    pub state_commits: u64,
+    pub actions: u64,
+    /// Keepalives the client sent itself, while it suppresses QUIC's
+    pub keepalives: u64,
+    /// Telemetry datagrams received
+    pub datagrams: u64,
+    pub quic: quinn_proto::ConnectionStats,
+    pub streams: Vec<StreamStatus>,
+    pub heartbeat: Option<HeartbeatStats>,
+    /// How well the responses and pushes sent filled their datagrams
+    pub efficiency: DatagramEfficiency,
+}
+
+impl fmt::Display for ConnectionSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} tenant={} agent={} up={}s rtt={:?} events={} commits={} actions={} keepalives={} datagrams={}",
+            self.id,
+            self.addr,
+            self.tenant,
+            self.user_agent,
+            self.uptime.as_secs(),
+            self.rtt,
+            self.events,
+            self.state_commits,
+            self.actions,
+            self.keepalives,
+            self.datagrams
+        )?;
+        if self.migrations > 0 {
+            let from: Vec<String> = self.previous_addrs.iter().map(|a| a.to_string()).collect();
+            write!(f, " migrations={} from={}", self.migrations, from.join(","))?;
+        }
+        if self.parked {
+            write!(f, " parked")?;
+        }
+        if self.key_updates > 0 {
+            write!(f, " key_updates={}", self.key_updates)?;
+        }
+        if let Some(rtt) = self.heartbeat.and_then(|h| h.smoothed) {
+            write!(f, " heartbeat_rtt={:?}", rtt)?;
+        }
+        write!(
+            f,
+            " frames_per_datagram={:.2} datagram_fill={:.0}% overhead={:.0}%",
+            self.efficiency.frames_per_datagram(),
+            self.efficiency.fill_ratio() * 100.0,
+            self.efficiency.overhead() * 100.0
+        )?;
+        for (key, value) in &self.labels {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Live connections on the server, registered once admitted and removed when
+/// their task ends. Lookups by ID, address, tenant or label return handles
+/// for inspecting, pushing actions to, or closing a specific connection.
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: RwLock<HashMap<ConnectionId, RegisteredConnection>>,
+}
+
+// State a connection's task shares with its registry entry
+pub(crate) struct SharedState {
+    pub(crate) streams: StreamStates,
+    pub(crate) heartbeat: Arc<Heartbeat>,
+    pub(crate) sent: SentFrames,
+    pub(crate) parking: Parking,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds an admitted connection; it is removed when the guard is dropped,
+    // including when the connection task panics
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        connection: &QuinnConnection,
+        peer: PeerInfo,
+        tenant: String,
+        shared: SharedState,
+    ) -> Registration {
+        let SharedState {
+            streams,
+            heartbeat,
+            sent,
+            parking,
+        } = shared;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let handle = RegisteredConnection {
+            entry: Arc::new(Entry {
+                id,
+                addrs: Mutex::new(VecDeque::from([connection.remote_address()])),
+                migrations: AtomicU64::new(0),
+                key_updates: AtomicU64::new(0),
+                peer,
+                tenant,
+                connected_at: SystemTime::now(),
+                connection: connection.clone(),
+                labels: Mutex::new(BTreeMap::new()),
+                counters: ConnectionCounters::default(),
+                pushed: Mutex::new(VecDeque::new()),
+                pushed_ready: Notify::new(),
+                streams,
+                heartbeat,
+                sent,
+                parking,
+            }),
+        };
+        self.connections.write().unwrap().insert(id, handle.clone());
+        Registration {
+            registry: Arc::clone(self),
+            handle,
+        }
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<RegisteredConnection> {
+        self.connections.read().unwrap().get(&id).cloned()
+    }
+
+    /// All live connections, oldest first.
+    pub fn list(&self) -> Vec<RegisteredConnection> {
+        let mut all: Vec<_> = self.connections.read().unwrap().values().cloned().collect();
+        all.sort_by_key(|c| c.id());
+        all
+    }
+
+    pub fn find_by_addr(&self, addr: SocketAddr) -> Option<RegisteredConnection> {
+        self.list().into_iter().find(|c| c.addr() == addr)
+    }
+
+    pub fn find_by_tenant(&self, tenant: &str) -> Vec<RegisteredConnection> {
+        self.list()
+            .into_iter()
+            .filter(|c| c.tenant() == tenant)
+            .collect()
+    }
+
+    pub fn find_by_label(&self, key: &str, value: &str) -> Vec<RegisteredConnection> {
+        self.list()
+            .into_iter()
+            .filter(|c| c.entry.labels.lock().unwrap().get(key).map(String::as_str) == Some(value))
+            .collect()
+    }
+
+    pub fn stats(&self, id: ConnectionId) -> Option<ConnectionSnapshot> {
+        self.get(id).map(|c| c.stats())
+    }
+
+    /// Queues an action for one connection. Returns false if it is not live.
+    pub fn push_action(&self, id: ConnectionId, action: u32) -> bool {
+        self.get(id).map(|c| c.push_action(action)).is_some()
+    }
+
+    /// Starts a key update on one connection. Returns false if it is not
+    /// live.
+    pub fn update_keys(&self, id: ConnectionId) -> bool {
+        self.get(id).map(|c| c.update_keys()).is_some()
+    }
+
+    /// Closes one connection. Returns false if it is not live.
+    pub fn close(&self, id: ConnectionId, reason: &str) -> bool {
+        self.get(id).map(|c| c.close(reason)).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.read().unwrap().is_empty()
+    }
+}
+
+// Keeps a connection registered for as long as its task holds this
+pub(crate) struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    handle: RegisteredConnection,
+}
+
+impl Registration {
+    pub(crate) fn handle(&self) -> &RegisteredConnection {
+        &self.handle
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry
+            .connections
+            .write()
+            .unwrap()
+            .remove(&self.handle.id());
+    }
+}
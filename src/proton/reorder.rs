@@ -0,0 +1,316 @@
+use crate::proton::metrics::ServerMetrics;
+use crate::proton::profile::spawn_named;
+use crate::proton::sink::{SinkEvent, SinkQueue};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::sleep_until;
+use tracing::info;
+
+/// How far out of order events may arrive and still be delivered in id
+/// order: at most `max_events` are held back waiting for a missing id, each
+/// for at most `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderConfig {
+    pub max_events: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for ReorderConfig {
+    fn default() -> Self {
+        Self {
+            max_events: 64,
+            max_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+// What the buffer let go of after an event arrived or time passed
+#[derive(Debug)]
+pub(crate) struct Released<T> {
+    // In id order
+    pub(crate) delivered: Vec<T>,
+    // Ranges of ids given up on, first and last inclusive
+    pub(crate) skipped: Vec<(u32, u32)>,
+    // Arrived after a later id had been delivered
+    pub(crate) late: Option<u32>,
+}
+
+impl<T> Default for Released<T> {
+    fn default() -> Self {
+        Self {
+            delivered: Vec::new(),
+            skipped: Vec::new(),
+            late: None,
+        }
+    }
+}
+
+// Holds events until every lower id has been delivered or given up on
+#[derive(Debug)]
+pub(crate) struct ReorderBuffer<T> {
+    config: ReorderConfig,
+    // Id to deliver next, known once the first event arrives
+    next: Option<u32>,
+    held: BTreeMap<u32, (Instant, T)>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub(crate) fn new(config: ReorderConfig) -> Self {
+        Self {
+            config,
+            next: None,
+            held: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, event_id: u32, event: T, now: Instant) -> Released<T> {
+        let mut released = Released::default();
+        let next = *self.next.get_or_insert(event_id);
+        if event_id < next || self.held.contains_key(&event_id) {
+            released.late = Some(event_id);
+            return released;
+        }
+        self.held.insert(event_id, (now, event));
+        self.release(now, &mut released);
+        released
+    }
+
+    // Gives up on missing ids once their window has passed
+    pub(crate) fn expire(&mut self, now: Instant) -> Released<T> {
+        let mut released = Released::default();
+        self.release(now, &mut released);
+        released
+    }
+
+    // Delivers everything held, e.g. when the connection ends
+    pub(crate) fn flush(&mut self) -> Released<T> {
+        let mut released = Released::default();
+        while !self.held.is_empty() {
+            self.skip_gap(&mut released);
+            self.deliver_ready(&mut released);
+        }
+        released
+    }
+
+    // When the oldest held event must be released, if any are held
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.held
+            .values()
+            .map(|(at, _)| *at)
+            .min()
+            .map(|at| at + self.config.max_delay)
+    }
+
+    fn release(&mut self, now: Instant, released: &mut Released<T>) {
+        self.deliver_ready(released);
+        while self.held.len() > self.config.max_events
+            || self.deadline().is_some_and(|deadline| deadline <= now)
+        {
+            self.skip_gap(released);
+            self.deliver_ready(released);
+        }
+    }
+
+    fn deliver_ready(&mut self, released: &mut Released<T>) {
+        while let Some(next) = self.next {
+            let Some((_, event)) = self.held.remove(&next) else {
+                break;
+            };
+            released.delivered.push(event);
+            self.next = next.checked_add(1);
+        }
+    }
+
+    // Stop waiting for the ids below the lowest one held
+    fn skip_gap(&mut self, released: &mut Released<T>) {
+        if let (Some(next), Some(&lowest)) = (self.next, self.held.keys().next()) {
+            if lowest > next {
+                released.skipped.push((next, lowest - 1));
+            }
+            self.next = Some(lowest);
+        }
+    }
+}
+
+// Feeds one connection's accepted events to its reordering task
+pub(crate) struct ReorderQueue {
+    tx: mpsc::UnboundedSender<SinkEvent>,
+}
+
+impl ReorderQueue {
+    // Never waits; the task holds at most `max_events` at a time
+    pub(crate) fn push(&self, event: SinkEvent) {
+        let _ = self.tx.send(event);
+    }
+}
+
+// Delivers `tenant`'s events to the sink queue in id order, until the queue
+// is dropped with the connection
+pub(crate) fn spawn_reorderer(
+    config: ReorderConfig,
+    sink: Option<SinkQueue>,
+    metrics: Arc<ServerMetrics>,
+    tenant: String,
+) -> ReorderQueue {
+    let (tx, mut rx) = mpsc::unbounded_channel::<SinkEvent>();
+    spawn_named("reorder buffer", async move {
+        let mut buffer = ReorderBuffer::new(config);
+        loop {
+            let released = match buffer.deadline() {
+                Some(deadline) => tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(event) => buffer.push(event.event_id, event, Instant::now()),
+                        None => break,
+                    },
+                    _ = sleep_until(deadline.into()) => buffer.expire(Instant::now()),
+                },
+                None => match rx.recv().await {
+                    Some(event) => buffer.push(event.event_id, event, Instant::now()),
+                    None => break,
+                },
+            };
+            deliver(released, &sink, &metrics, &tenant);
+        }
+        deliver(buffer.flush(), &sink, &metrics, &tenant);
+    });
+    ReorderQueue { tx }
+}
+
+fn deliver(
+    released: Released<SinkEvent>,
+    sink: &Option<SinkQueue>,
+    metrics: &ServerMetrics,
+    tenant: &str,
+) {
+    if let Some(event_id) = released.late {
+        metrics.reorder_late.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Dropping event {} from {}: arrived after the reorder window",
+            event_id, tenant
+        );
+    }
+    for (first, last) in released.skipped {
+        metrics
+            .reorder_skipped
+            .fetch_add(u64::from(last - first) + 1, Ordering::Relaxed);
+        if first == last {
+            info!(
+                "Reorder window exceeded for {}: skipped event {}",
+                tenant, first
+            );
+        } else {
+            info!(
+                "Reorder window exceeded for {}: skipped events {} to {}",
+                tenant, first, last
+            );
+        }
+    }
+    if let Some(sink) = sink {
+        for event in released.delivered {
+            sink.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(max_events: usize) -> ReorderBuffer<u32> {
+        ReorderBuffer::new(ReorderConfig {
+            max_events,
+            max_delay: Duration::from_millis(50),
+        })
+    }
+
+    fn push(buffer: &mut ReorderBuffer<u32>, id: u32, now: Instant) -> Released<u32> {
+        buffer.push(id, id, now)
+    }
+
+    #[test]
+    fn gap_is_filled_in_order() {
+        let now = Instant::now();
+        let mut buffer = buffer(64);
+        assert_eq!(push(&mut buffer, 5, now).delivered, [5]);
+        assert!(push(&mut buffer, 7, now).delivered.is_empty());
+        assert!(push(&mut buffer, 8, now).delivered.is_empty());
+        assert_eq!(buffer.deadline(), Some(now + Duration::from_millis(50)));
+        assert_eq!(push(&mut buffer, 6, now).delivered, [6, 7, 8]);
+        assert_eq!(buffer.deadline(), None);
+    }
+
+    #[test]
+    fn late_and_duplicate_events_are_reported() {
+        let now = Instant::now();
+        let mut buffer = buffer(64);
+        push(&mut buffer, 10, now);
+        push(&mut buffer, 12, now);
+        let late = push(&mut buffer, 9, now);
+        assert_eq!((late.late, late.delivered.len()), (Some(9), 0));
+        assert_eq!(push(&mut buffer, 10, now).late, Some(10));
+        // Held but not yet delivered
+        assert_eq!(push(&mut buffer, 12, now).late, Some(12));
+    }
+
+    #[test]
+    fn full_buffer_skips_the_gap() {
+        let now = Instant::now();
+        let mut buffer = buffer(2);
+        push(&mut buffer, 1, now);
+        push(&mut buffer, 3, now);
+        push(&mut buffer, 5, now);
+        let released = push(&mut buffer, 6, now);
+        assert_eq!(released.skipped, [(2, 2)]);
+        assert_eq!(released.delivered, [3]);
+        // 4 is still awaited, with 5 and 6 held
+        assert_eq!(push(&mut buffer, 4, now).delivered, [4, 5, 6]);
+    }
+
+    #[test]
+    fn missing_ids_expire() {
+        let start = Instant::now();
+        let mut buffer = buffer(64);
+        push(&mut buffer, 1, start);
+        push(&mut buffer, 4, start);
+        push(&mut buffer, 6, start + Duration::from_millis(30));
+        assert!(buffer
+            .expire(start + Duration::from_millis(49))
+            .delivered
+            .is_empty());
+
+        // Only the gap below the expired event is given up on
+        let released = buffer.expire(start + Duration::from_millis(50));
+        assert_eq!(released.skipped, [(2, 3)]);
+        assert_eq!(released.delivered, [4]);
+        let released = buffer.expire(start + Duration::from_millis(80));
+        assert_eq!(released.skipped, [(5, 5)]);
+        assert_eq!(released.delivered, [6]);
+    }
+
+    #[test]
+    fn flush_delivers_everything_held() {
+        let now = Instant::now();
+        let mut buffer = buffer(64);
+        push(&mut buffer, 1, now);
+        push(&mut buffer, 3, now);
+        push(&mut buffer, 7, now);
+        let released = buffer.flush();
+        assert_eq!(released.skipped, [(2, 2), (4, 6)]);
+        assert_eq!(released.delivered, [3, 7]);
+        assert!(buffer.flush().delivered.is_empty());
+    }
+
+    #[test]
+    fn ids_wrap_around() {
+        let now = Instant::now();
+        let mut buffer = buffer(64);
+        push(&mut buffer, u32::MAX - 1, now);
+        assert_eq!(push(&mut buffer, u32::MAX, now).delivered, [u32::MAX]);
+        // Nothing follows the last id, so the next event starts over
+        assert_eq!(push(&mut buffer, 0, now).delivered, [0]);
+        assert_eq!(push(&mut buffer, 1, now).delivered, [1]);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::proton::{
+    CONNECT_ATTEMPT_TIMEOUT, CONNECT_RETRY_DELAY, MAX_CONNECT_RETRIES, MAX_CONNECT_RETRY_DELAY,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::Duration;
+
+/// How a client operation is retried after a transient failure, i.e. when its
+/// response did not arrive in time but the connection is still open.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first
+    pub max_attempts: u32,
+    /// How long each attempt waits for its response
+    pub attempt_timeout: Duration,
+    /// Delay before the first retry, doubled on each further retry
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            attempt_timeout: Duration::from_secs(5),
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that makes a single attempt.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// How `ProtonClient::connect` retries by default: each attempt covers
+    /// the handshake and opening the streams.
+    pub fn connect() -> Self {
+        Self {
+            max_attempts: MAX_CONNECT_RETRIES + 1,
+            attempt_timeout: CONNECT_ATTEMPT_TIMEOUT,
+            initial_backoff: CONNECT_RETRY_DELAY,
+            max_backoff: MAX_CONNECT_RETRY_DELAY,
+        }
+    }
+
+    /// Delay before retry number `retry` (starting at 1).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// `backoff(retry)` scaled by a random factor between one half and one,
+    /// so that clients which failed together do not retry in lockstep.
+    pub fn jittered_backoff(&self, retry: u32) -> Duration {
+        let mut byte = [u8::MAX];
+        // Without randomness, retry after the full backoff
+        let _ = SystemRandom::new().fill(&mut byte);
+        let backoff = self.backoff(retry);
+        backoff / 2 + backoff.mul_f64(f64::from(byte[0]) / 510.0)
+    }
+}
+
+/// Whether repeating an operation is safe. Only idempotent operations are
+/// retried; a repeated request may be applied by the server more than once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idempotency {
+    Idempotent,
+    NonIdempotent,
+}
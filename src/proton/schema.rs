@@ -0,0 +1,95 @@
+//! Evolving event and action payload schemas while a fleet runs mixed
+//! versions. Each payload opens with the version of the schema its body was
+//! encoded with (see `wire::encode_versioned`). A [`Schema`] decodes every
+//! version it knows and migrates older ones to the latest, so application
+//! code only ever sees the latest type, and senders can be upgraded before
+//! or after the servers reading what they send.
+
+use crate::proton::wire::{decode_versioned, encode_versioned};
+use crate::proton::ProtonError;
+use std::collections::BTreeMap;
+
+/// Converts a payload decoded with an older schema version into `T`, the
+/// latest. A type two versions behind can migrate through the one between,
+/// e.g. `Migration::migrate(V2::from(v1))`.
+pub trait Migration<T> {
+    fn migrate(self) -> T;
+}
+
+type Decoder<T> = Box<dyn Fn(&[u8]) -> Result<T, ProtonError> + Send + Sync>;
+
+/// Decoders for each known version of a payload schema, all yielding the
+/// latest version's type.
+pub struct Schema<T> {
+    latest: u16,
+    decoders: BTreeMap<u16, Decoder<T>>,
+}
+
+impl<T: 'static> Schema<T> {
+    /// A schema whose latest version is `latest`, with bodies of that
+    /// version decoded by `decode`.
+    pub fn new(
+        latest: u16,
+        decode: impl Fn(&[u8]) -> Result<T, ProtonError> + Send + Sync + 'static,
+    ) -> Self {
+        let mut decoders: BTreeMap<u16, Decoder<T>> = BTreeMap::new();
+        decoders.insert(latest, Box::new(decode));
+        Self { latest, decoders }
+    }
+
+    /// Also accept bodies of the older `version`, decoded by `decode` and
+    /// migrated to the latest. Registering a version again replaces its
+    /// decoder.
+    pub fn with_version<V: Migration<T> + 'static>(
+        mut self,
+        version: u16,
+        decode: impl Fn(&[u8]) -> Result<V, ProtonError> + Send + Sync + 'static,
+    ) -> Self {
+        assert!(
+            version < self.latest,
+            "schema version {} is not older than the latest, {}",
+            version,
+            self.latest
+        );
+        self.decoders
+            .insert(version, Box::new(move |body| decode(body).map(V::migrate)));
+        self
+    }
+
+    /// Version payloads are encoded with.
+    pub fn latest(&self) -> u16 {
+        self.latest
+    }
+
+    /// Versions that can be decoded, oldest first.
+    pub fn versions(&self) -> impl Iterator<Item = u16> + '_ {
+        self.decoders.keys().copied()
+    }
+
+    /// Tags `body`, encoded with the latest version, for sending.
+    pub fn encode(&self, body: &[u8]) -> Vec<u8> {
+        encode_versioned(self.latest, body)
+    }
+
+    /// Decodes a versioned payload of any known version as the latest.
+    /// A payload from a newer sender is refused with an error naming both
+    /// versions, so the reader can be upgraded.
+    pub fn decode(&self, payload: &[u8]) -> Result<T, ProtonError> {
+        let (version, body) = decode_versioned(payload)?;
+        let Some(decode) = self.decoders.get(&version) else {
+            let reason = if version > self.latest {
+                "is newer than the latest known"
+            } else {
+                "is no longer supported, latest"
+            };
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "payload schema version {} {} {}",
+                    version, reason, self.latest
+                ),
+            )));
+        };
+        decode(body)
+    }
+}
@@ -0,0 +1,271 @@
+use crate::proton::handler::{HandlerFuture, ProtonHandler, RequestContext};
+use crate::proton::{Frame, ProtonError};
+use mlua::{FromLua, Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Most memory a script's Lua state may allocate, 16 MiB.
+pub const DEFAULT_SCRIPT_MEMORY: usize = 16 << 20;
+
+/// Most Lua VM instructions one callback may run.
+pub const DEFAULT_SCRIPT_INSTRUCTIONS: u32 = 1_000_000;
+
+/// Resource limits a handler script runs under.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptLimits {
+    pub memory: usize,
+    pub instructions: u32,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            memory: DEFAULT_SCRIPT_MEMORY,
+            instructions: DEFAULT_SCRIPT_INSTRUCTIONS,
+        }
+    }
+}
+
+// Converts a callback's return value to an ack or commit response
+fn response(value: Value, lua: &Lua) -> mlua::Result<u32> {
+    u32::from_lua(value, lua)
+}
+
+fn script_error(e: mlua::Error) -> ProtonError {
+    ProtonError::IoError(std::io::Error::other(format!("handler script: {}", e)))
+}
+
+/// A [`ProtonHandler`] whose callbacks are Lua functions, so a test server
+/// can emulate a backend without being rebuilt.
+///
+/// The script may define any of
+///
+/// - `on_event(request)`, returning the ack
+/// - `on_state_commit(request)`, returning the response
+/// - `next_action(request)`, returning the action's payload as a string
+///
+/// `request` is a table with `id`, `payload`, `tenant` and `peer`. A missing
+/// function behaves like `EchoHandler`. Scripts get only the `table`,
+/// `string`, `math` and `utf8` libraries, so they cannot reach files,
+/// processes or the network, and run under [`ScriptLimits`]. A callback that
+/// fails or exceeds its limits fails the stream like any handler error.
+pub struct LuaHandler {
+    lua: Mutex<Lua>,
+    instructions: u32,
+}
+
+impl LuaHandler {
+    pub fn load(path: &Path, limits: ScriptLimits) -> Result<Self, ProtonError> {
+        let source = std::fs::read_to_string(path)?;
+        Self::from_source(&source, &path.display().to_string(), limits)
+    }
+
+    /// Runs `source`, named `name` in error messages, to define the
+    /// callbacks.
+    pub fn from_source(
+        source: &str,
+        name: &str,
+        limits: ScriptLimits,
+    ) -> Result<Self, ProtonError> {
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::default()).map_err(script_error)?;
+        lua.set_memory_limit(limits.memory).map_err(script_error)?;
+        let handler = Self {
+            lua: Mutex::new(lua),
+            instructions: limits.instructions,
+        };
+        handler.limited(|lua| lua.load(source).set_name(name).exec())?;
+        Ok(handler)
+    }
+
+    // Runs `f` with the instruction budget reset
+    fn limited<T>(&self, f: impl FnOnce(&Lua) -> mlua::Result<T>) -> Result<T, ProtonError> {
+        let lua = self.lua.lock().unwrap();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(self.instructions),
+            |_, _| Err(mlua::Error::runtime("instruction limit exceeded")),
+        );
+        let result = f(&lua);
+        lua.remove_hook();
+        result.map_err(script_error)
+    }
+
+    // Calls the script's `name` with a request table and converts what it
+    // returns, or returns None if the script does not define `name`
+    fn call<T>(
+        &self,
+        name: &str,
+        ctx: &RequestContext,
+        id: u32,
+        payload: &[u8],
+        convert: impl for<'lua> FnOnce(Value<'lua>, &'lua Lua) -> mlua::Result<T>,
+    ) -> Result<Option<T>, ProtonError> {
+        self.limited(|lua| {
+            let Some(function) = lua.globals().get::<_, Option<Function>>(name)? else {
+                return Ok(None);
+            };
+            let request: Table = lua.create_table()?;
+            request.set("id", id)?;
+            request.set("payload", lua.create_string(payload)?)?;
+            request.set("tenant", ctx.tenant)?;
+            request.set("peer", ctx.peer.to_string())?;
+            let value = function.call(request)?;
+            convert(value, lua).map(Some)
+        })
+    }
+}
+
+impl ProtonHandler for LuaHandler {
+    fn on_event<'a>(&'a self, ctx: RequestContext<'a>, event: &'a Frame) -> HandlerFuture<'a, u32> {
+        let result = self.call("on_event", &ctx, event.id, &event.payload, response);
+        Box::pin(async move { Ok(result?.unwrap_or(event.id)) })
+    }
+
+    fn on_state_commit<'a>(
+        &'a self,
+        ctx: RequestContext<'a>,
+        commit: &'a Frame,
+    ) -> HandlerFuture<'a, u32> {
+        let result = self.call(
+            "on_state_commit",
+            &ctx,
+            commit.id,
+            &commit.payload,
+            response,
+        );
+        Box::pin(async move { Ok(result?.unwrap_or(commit.id + 2)) })
+    }
+
+    fn next_action<'a>(
+        &'a self,
+        ctx: RequestContext<'a>,
+        action_id: u32,
+    ) -> HandlerFuture<'a, Vec<u8>> {
+        let result = self.call("next_action", &ctx, action_id, &[], |value, lua| {
+            mlua::String::from_lua(value, lua).map(|s| s.as_bytes().to_vec())
+        });
+        Box::pin(async move { Ok(result?.unwrap_or_default()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proton::frame::Headers;
+
+    fn handler(source: &str) -> LuaHandler {
+        LuaHandler::from_source(source, "test", ScriptLimits::default()).unwrap()
+    }
+
+    fn ctx(headers: &Headers) -> RequestContext<'_> {
+        RequestContext {
+            tenant: "acme",
+            peer: "127.0.0.1:4433".parse().unwrap(),
+            headers,
+        }
+    }
+
+    fn event(id: u32, payload: &[u8]) -> Frame {
+        Frame {
+            id,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_callbacks_echo() {
+        let lua = handler("");
+        let headers = Headers::new();
+        assert_eq!(
+            lua.on_event(ctx(&headers), &event(7, b"")).await.unwrap(),
+            7
+        );
+        assert_eq!(
+            lua.on_state_commit(ctx(&headers), &event(7, b""))
+                .await
+                .unwrap(),
+            9
+        );
+        assert!(lua.next_action(ctx(&headers), 1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn callbacks_see_the_request() {
+        let lua = handler(
+            r#"
+            function on_event(request)
+                if request.tenant == "acme" and request.payload == "hi" then
+                    return request.id + 100
+                end
+                return 0
+            end
+            function next_action(request)
+                return "action " .. request.id .. " for " .. request.peer
+            end
+            "#,
+        );
+        let headers = Headers::new();
+        assert_eq!(
+            lua.on_event(ctx(&headers), &event(1, b"hi")).await.unwrap(),
+            101
+        );
+        assert_eq!(
+            lua.next_action(ctx(&headers), 3).await.unwrap(),
+            b"action 3 for 127.0.0.1:4433"
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_return_fails_the_request() {
+        let lua = handler("function on_event(request) return 'ack' end");
+        let headers = Headers::new();
+        assert!(lua.on_event(ctx(&headers), &event(1, b"")).await.is_err());
+    }
+
+    #[test]
+    fn scripts_cannot_reach_the_system() {
+        for source in ["io.open('/etc/passwd')", "os.exit(1)", "require('socket')"] {
+            assert!(LuaHandler::from_source(source, "test", ScriptLimits::default()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn runaway_callbacks_are_stopped() {
+        let limits = ScriptLimits {
+            instructions: 10_000,
+            ..ScriptLimits::default()
+        };
+        assert!(LuaHandler::from_source("while true do end", "test", limits).is_err());
+        let lua = LuaHandler::from_source(
+            "function on_event(request) while true do end end
+             function on_state_commit(request) return request.id end",
+            "test",
+            limits,
+        )
+        .unwrap();
+        let headers = Headers::new();
+        let err = lua
+            .on_event(ctx(&headers), &event(1, b""))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("instruction limit"));
+
+        // The budget is per callback, so the handler keeps working
+        let commit = event(5, b"");
+        let response = lua.on_state_commit(ctx(&headers), &commit).await;
+        assert_eq!(response.unwrap(), 5);
+    }
+
+    #[test]
+    fn memory_is_limited() {
+        let limits = ScriptLimits {
+            memory: 1 << 20,
+            ..ScriptLimits::default()
+        };
+        let source = "big = string.rep('x', 4 << 20)";
+        let err = LuaHandler::from_source(source, "test", limits)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("memory"));
+    }
+}
@@ -1,70 +1,558 @@
+use crate::proton::access::AccessList;
+use crate::proton::admin::{self, AdminState};
+use crate::proton::admission::{Admission, AdmissionDecision, DuplicatePolicy};
+use crate::proton::coalesce::{CoalesceConfig, FrameWriter};
+use crate::proton::commit::{CommitHandler, RecentCommits, ABORT_REFUSED};
+use crate::proton::compress::{
+    CompressionConfig, Dictionary, DictionaryTrainer, MAX_DICTIONARY_SIZE,
+};
+use crate::proton::datagram::{self, check_max_datagram_size, DatagramHandler, MAX_DATAGRAM_SIZE};
+use crate::proton::dedupe::{DedupeIndex, FsyncPolicy};
+use crate::proton::drain::{self, DrainHandle, DEFAULT_DRAIN_TIMEOUT};
+use crate::proton::dscp::{self, Dscp, Marking};
+use crate::proton::efficiency::SentFrames;
+use crate::proton::eventlog::{self, EventLog, Retention};
+use crate::proton::frame::{stream_name, FrameInterceptor, Headers};
+use crate::proton::fsm::{StreamState, StreamStates};
+use crate::proton::handler::{EchoHandler, ProtonHandler, RequestContext};
+use crate::proton::heartbeat::{self, Heartbeat, MIN_HEARTBEAT_INTERVAL};
+use crate::proton::hello::{accept_hello_with, ConnectedPeer, PeerInfo};
+use crate::proton::ids::IdCheck;
+use crate::proton::metrics::{
+    ConnectionOutcome, HandshakeFailure, MetricsExport, ServerMetrics, StreamTypeMetrics,
+};
+use crate::proton::misbehave::{Misbehavior, MisbehaviorControl, RESET_BY_MISBEHAVIOR};
+use crate::proton::ordering::{Admit, EventOrderCheck, EventOrdering, OrderingPolicy};
+use crate::proton::park::{Parking, PARKED_CHECK_INTERVAL};
+use crate::proton::payload::{receive_payload, PayloadHandler, PayloadTransfers};
+use crate::proton::profile::{profiled, spawn_named};
+use crate::proton::psk::authenticate_server;
+use crate::proton::push::{MAX_PUSHED_ACTIONS, QUOTA_BACKOFF};
+use crate::proton::qlog::{PacketTrace, Vantage};
+use crate::proton::quota::{Quota, UsageLedger};
+use crate::proton::ratelimit::{ConnectionRateLimiter, RateDecision, RateLimitConfig};
+use crate::proton::registry::{ConnectionRegistry, RegisteredConnection, SharedState};
+use crate::proton::reorder::{spawn_reorderer, ReorderConfig, ReorderQueue};
+use crate::proton::settings::ClientSettings;
+use crate::proton::sink::{self, EventSink, SinkEvent, SinkQueue};
+use crate::proton::snapshot::{ServerSnapshot, SnapshotHandle};
+use crate::proton::stall::{StallConfig, StallWatch};
+use crate::proton::streams::{serve_stream, StreamHandlerFactory, StreamRegistry, StreamType};
+use crate::proton::tls::{
+    check_ocsp_response, inspect_chain, load_certs, load_crls, load_private_key, negotiated_alpn,
+    verify_key_pair, ChainCertificate, OcspStatus, RevocationCheckingVerifier, TlsPolicy,
+};
+use crate::proton::violation::{ProtocolMode, Violation};
+use crate::proton::window::DEFAULT_MAX_EVENT_WINDOW;
+use crate::proton::wire::{
+    decode_commit, decode_discriminator, DATAGRAM_KEEPALIVE, DATAGRAM_TELEMETRY,
+};
 use crate::proton::{
-    ProtonError, IDLE_TIMEOUT, MAX_BIDIRECTIONAL_STREAMS, MAX_CONNECTIONS, STARTUP_DELAY,
-    STREAM_ACTION, STREAM_EVENT, STREAM_STATE_COMMIT, STREAM_TIMEOUT,
+    check_payload_len, CloseReason, Frame, Framing, MessageKind, ProtonConfig, ProtonError,
+    CERT_EXPIRY_CHECK_INTERVAL, CERT_EXPIRY_WARNING_DAYS, HANDSHAKE_TIMEOUT,
+    MAX_CONCURRENT_HANDSHAKES, MAX_CONNECTIONS, QUOTA_EXCEEDED, STREAM_ACTION, STREAM_ACTION_PUSH,
+    STREAM_AUTH, STREAM_CONTROL, STREAM_EVENT, STREAM_HEARTBEAT, STREAM_PAYLOAD,
+    STREAM_STATE_COMMIT, STREAM_TIMEOUT,
 };
-use quinn::{Connection as QuinnConnection, Endpoint, RecvStream, SendStream, ServerConfig};
+use quinn::{
+    Connection as QuinnConnection, Endpoint, RecvStream, SendStream, ServerConfig, StreamId, VarInt,
+};
+use rustls::RootCertStore;
+use smallvec::SmallVec;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, timeout};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::{sleep, sleep_until, timeout};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 struct StreamPair {
-    send: SendStream,
+    send: FrameWriter,
     recv: RecvStream,
+    // Whether each request frame is followed by a headers section
+    headers: bool,
+}
+
+// Read the headers section following a request, if the stream carries them,
+// and hand the frame to the interceptors. Returns the size of the section and
+// the headers.
+async fn intercept_frame(
+    recv: &mut RecvStream,
+    with_headers: bool,
+    interceptors: &[Arc<dyn FrameInterceptor>],
+    stream: u8,
+    id: u32,
+) -> Result<(usize, Headers), ProtonError> {
+    let headers = if with_headers {
+        Headers::read_from(recv).await?
+    } else {
+        Headers::new()
+    };
+    if let Some(trace) = headers.traceparent() {
+        info!(
+            "Request {} on stream {} is part of trace {}",
+            id, stream, trace
+        );
+    }
+    for interceptor in interceptors {
+        interceptor.on_frame(stream, id, &headers);
+    }
+    let len = if with_headers {
+        headers.encoded_len()
+    } else {
+        0
+    };
+    Ok((len, headers))
+}
+
+// A `kind` response laid out per `framing`. Clients with fixed framing get pushed
+// actions without their payload.
+fn encode_response(
+    framing: Framing,
+    kind: MessageKind,
+    response: &Frame,
+) -> Result<SmallVec<[u8; 64]>, ProtonError> {
+    let mut out = SmallVec::new();
+    match framing {
+        Framing::Fixed => out.extend(response.id.to_le_bytes()),
+        framing => framing.encode_into(kind, response, &mut out)?,
+    }
+    Ok(out)
+}
+
+// Log a deviation the server can read past, or in strict mode fail with it
+fn deviate(mode: ProtocolMode, tenant: &str, violation: Violation) -> Result<(), ProtonError> {
+    match mode {
+        ProtocolMode::Lenient => {
+            info!("Ignoring deviation by {}: {}", tenant, violation);
+            Ok(())
+        }
+        ProtocolMode::Strict => Err(ProtonError::ProtocolViolation(violation)),
+    }
+}
+
+// Delivery position of one client's action stream. Survives reconnects so
+// actions the client has not acknowledged are delivered again.
+#[derive(Debug, Default)]
+pub(crate) struct ActionOffsets {
+    // Highest action id the consumer has acknowledged
+    pub(crate) committed: u32,
+    // Next action id to deliver on the current connection
+    next: u32,
+    // Whether to take the first offset the client reports as acknowledged,
+    // for a connection-scoped cursor that has no history of its own
+    adopt_offset: bool,
+}
+
+impl ActionOffsets {
+    // Restart delivery after the last acknowledged action
+    fn rewind(&mut self) {
+        if self.next > self.committed + 1 {
+            info!(
+                "Redelivering actions from {} (acknowledged up to {})",
+                self.committed + 1,
+                self.committed
+            );
+        }
+        self.next = self.committed + 1;
+    }
+
+    // Record the consumer's offset; it can only acknowledge what was delivered
+    fn ack_up_to(&mut self, offset: u32) {
+        if std::mem::take(&mut self.adopt_offset) && offset > self.committed {
+            self.committed = offset;
+            self.next = self.next.max(offset + 1);
+        } else if offset > self.committed && offset < self.next {
+            self.committed = offset;
+        }
+    }
+
+    // Take on an offset acknowledged on another server
+    pub(crate) fn restore(&mut self, committed: u32) {
+        self.committed = self.committed.max(committed);
+        self.rewind();
+    }
+
+    fn deliver(&mut self) -> u32 {
+        let action = self.next;
+        self.next += 1;
+        action
+    }
+
+    // Give back the action just delivered, to deliver it again later
+    fn undeliver(&mut self, action: u32) {
+        if self.next == action + 1 {
+            self.next = action;
+        }
+    }
+
+    // Actions delivered and not yet acknowledged
+    fn unacknowledged(&self) -> u32 {
+        self.next.saturating_sub(self.committed + 1)
+    }
+}
+
+// Action cursors of the clients that named themselves in their HELLO, by
+// "tenant/client id". Clients without an id get a cursor of their own that
+// ends with their connection, so one client's reconnect never rewinds
+// another's delivery.
+#[derive(Debug, Default)]
+pub(crate) struct ActionCursors {
+    clients: HashMap<String, Arc<std::sync::Mutex<ActionOffsets>>>,
+}
+
+impl ActionCursors {
+    // Cursor for a connection of `client`, restarted after its last
+    // acknowledged action
+    fn cursor(&mut self, client: &str) -> Arc<std::sync::Mutex<ActionOffsets>> {
+        let cursor = self.clients.entry(client.to_string()).or_default();
+        cursor.lock().unwrap().rewind();
+        Arc::clone(cursor)
+    }
+
+    // Highest action acknowledged by each client
+    pub(crate) fn committed(&self) -> Vec<(String, u32)> {
+        let mut committed: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(client, cursor)| (client.clone(), cursor.lock().unwrap().committed))
+            .collect();
+        committed.sort();
+        committed
+    }
+
+    // Take on an offset `client` acknowledged on another server
+    pub(crate) fn restore(&mut self, client: &str, committed: u32) {
+        self.clients
+            .entry(client.to_string())
+            .or_default()
+            .lock()
+            .unwrap()
+            .restore(committed);
+    }
+}
+
+// A cursor for a client that did not name itself. It resumes from the
+// offset the client reports first.
+fn anonymous_cursor() -> Arc<std::sync::Mutex<ActionOffsets>> {
+    let mut offsets = ActionOffsets {
+        adopt_offset: true,
+        ..ActionOffsets::default()
+    };
+    offsets.rewind();
+    Arc::new(std::sync::Mutex::new(offsets))
 }
 
 struct ProtonStreamHandler {
     event_stream: Option<StreamPair>,
     state_commit_stream: Option<StreamPair>,
     action_stream: Option<StreamPair>,
-    last_event_id: u32,
+    control_stream: Option<StreamPair>,
+    peer: Option<PeerInfo>,
+    settings: watch::Receiver<ClientSettings>,
+    // Checks event ids against the ordering agreed in the HELLO
+    order: EventOrderCheck,
+    ordering: Arc<OrderingPolicy>,
+    // Checks event ids against the allocation scheme the client declared
+    ids: IdCheck,
+    // Layout of requests and responses agreed in the HELLO
+    framing: Framing,
+    // Samples event payloads for dictionaries, if the server compresses
+    dictionaries: Option<Arc<DictionaryTrainer>>,
+    // Dictionaries to push, once the client has agreed to compression
+    dictionary_updates: Option<watch::Receiver<Option<Arc<Dictionary>>>>,
+    // Most events a client may keep in flight
+    max_event_window: u32,
+    stall: Arc<StallConfig>,
+    mode: ProtocolMode,
+    // This connection's action cursor, the client's own once its HELLO
+    // names it
+    actions: Arc<std::sync::Mutex<ActionOffsets>>,
+    action_cursors: Arc<std::sync::Mutex<ActionCursors>>,
+    interceptors: Vec<Arc<dyn FrameInterceptor>>,
+    usage: Arc<UsageLedger>,
+    // Identity usage is accounted to, known once the HELLO has been received
+    tenant: String,
+    // Log of the events handled, and the client they are logged under once
+    // its HELLO named one
+    event_log: Option<Arc<EventLog>>,
+    logged_client: Option<String>,
+    payloads: Option<Arc<dyn PayloadHandler>>,
+    transfers: Arc<PayloadTransfers>,
+    // Key payload transfers are kept under, known once the HELLO has been
+    // received
+    payload_client: String,
+    coalesce: Option<CoalesceConfig>,
+    datagrams: Option<Arc<dyn DatagramHandler>>,
+    max_datagram_size: usize,
+    // Set once the connection is admitted and registered
+    registered: Option<RegisteredConnection>,
+    // Queue to the event sinks, if any are configured
+    sink: Option<SinkQueue>,
+    // Puts events back in id order before the sinks, if configured
+    reorder: Option<ReorderQueue>,
+    handler: Arc<dyn ProtonHandler>,
+    commits: Option<Arc<dyn CommitHandler>>,
+    // Commits made on this connection that may still be aborted
+    recent_commits: RecentCommits,
+    misbehavior: Arc<MisbehaviorControl>,
+    streams: Arc<StreamRegistry>,
+    metrics: Arc<ServerMetrics>,
+    // Where each stream is in its exchange, shared with the registry
+    states: StreamStates,
+    // Round trips measured on the heartbeat stream, shared with the registry
+    heartbeat: Arc<Heartbeat>,
+    // Frames written, shared with the registry
+    sent: SentFrames,
+    // Whether the connection is parked, shared with the registry and the
+    // coalescers of its streams
+    parking: Parking,
+    // When the connection reaches its maximum age and is sent GOAWAY, and
+    // how long the client then has to reconnect
+    goaway_at: Option<Instant>,
+    goaway_grace: Duration,
 }
 
 impl ProtonStreamHandler {
-    fn new() -> Self {
+    fn new(
+        action_cursors: Arc<std::sync::Mutex<ActionCursors>>,
+        interceptors: Vec<Arc<dyn FrameInterceptor>>,
+        settings: watch::Receiver<ClientSettings>,
+        usage: Arc<UsageLedger>,
+        payloads: Option<Arc<dyn PayloadHandler>>,
+        transfers: Arc<PayloadTransfers>,
+        coalesce: Option<CoalesceConfig>,
+    ) -> Self {
         Self {
             event_stream: None,
             state_commit_stream: None,
             action_stream: None,
-            last_event_id: 0,
+            control_stream: None,
+            peer: None,
+            settings,
+            order: EventOrderCheck::default(),
+            ordering: Arc::new(OrderingPolicy::default()),
+            ids: IdCheck::default(),
+            framing: Framing::default(),
+            dictionaries: None,
+            dictionary_updates: None,
+            max_event_window: DEFAULT_MAX_EVENT_WINDOW,
+            stall: Arc::new(StallConfig::default()),
+            mode: ProtocolMode::default(),
+            actions: anonymous_cursor(),
+            action_cursors,
+            interceptors,
+            usage,
+            tenant: String::new(),
+            event_log: None,
+            logged_client: None,
+            payloads,
+            transfers,
+            payload_client: String::new(),
+            coalesce,
+            datagrams: None,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            registered: None,
+            sink: None,
+            reorder: None,
+            handler: Arc::new(EchoHandler),
+            commits: None,
+            recent_commits: RecentCommits::default(),
+            misbehavior: Arc::new(MisbehaviorControl::new(Misbehavior::default())),
+            streams: Arc::new(StreamRegistry::default()),
+            metrics: Arc::new(ServerMetrics::new()),
+            states: StreamStates::new(),
+            heartbeat: Arc::new(Heartbeat::new()),
+            sent: SentFrames::new(),
+            parking: Parking::default(),
+            goaway_at: None,
+            goaway_grace: DEFAULT_DRAIN_TIMEOUT,
         }
     }
 
+    // Register a newly accepted stream, returning its type
     async fn handle_stream(
         &mut self,
-        send: SendStream,
+        mut send: SendStream,
         mut recv: RecvStream,
-    ) -> Result<(), ProtonError> {
+    ) -> Result<u8, ProtonError> {
+        let id = recv.id();
+        self.states
+            .track(id, "new", StreamState::AwaitingDiscriminator);
         let mut discriminator = [0u8; 1];
-        timeout(STREAM_TIMEOUT, recv.read_exact(&mut discriminator)).await??;
+        let read = match timeout(STREAM_TIMEOUT, recv.read_exact(&mut discriminator)).await {
+            Ok(read) => read.map_err(ProtonError::from),
+            Err(elapsed) => Err(elapsed.into()),
+        };
+        if let Err(e) = read {
+            self.states.forget(id);
+            return Err(e);
+        }
+        let (kind, headers) = decode_discriminator(discriminator[0]);
+        let violation = |expected: &str| {
+            ProtonError::ProtocolViolation(Violation::new(
+                "new",
+                0,
+                expected,
+                format!("discriminator {:#04x}", discriminator[0]),
+            ))
+        };
 
-        match discriminator[0] {
+        let result = match kind {
             STREAM_EVENT => {
                 if self.event_stream.is_none() {
-                    self.event_stream = Some(StreamPair { send, recv });
-                    Ok(())
+                    self.event_stream = Some(StreamPair {
+                        send: FrameWriter::with_parking(send, self.coalesce, self.parking.clone()),
+                        recv,
+                        headers,
+                    });
+                    Ok(kind)
                 } else {
-                    Err(ProtonError::InvalidStream)
+                    Err(violation("one event stream per connection"))
                 }
             }
             STREAM_STATE_COMMIT => {
                 if self.state_commit_stream.is_none() {
-                    self.state_commit_stream = Some(StreamPair { send, recv });
-                    Ok(())
+                    self.state_commit_stream = Some(StreamPair {
+                        send: FrameWriter::with_parking(send, self.coalesce, self.parking.clone()),
+                        recv,
+                        headers,
+                    });
+                    Ok(kind)
                 } else {
-                    Err(ProtonError::InvalidStream)
+                    Err(violation("one state commit stream per connection"))
                 }
             }
             STREAM_ACTION => {
                 if self.action_stream.is_none() {
-                    self.action_stream = Some(StreamPair { send, recv });
-                    Ok(())
+                    self.action_stream = Some(StreamPair {
+                        send: FrameWriter::with_parking(send, self.coalesce, self.parking.clone()),
+                        recv,
+                        headers,
+                    });
+                    Ok(kind)
                 } else {
-                    Err(ProtonError::InvalidStream)
+                    Err(violation("one action stream per connection"))
                 }
             }
-            _ => Err(ProtonError::InvalidStream),
+            STREAM_CONTROL => {
+                if self.control_stream.is_none() {
+                    let mut ordering = EventOrdering::default();
+                    let peer = accept_hello_with(&mut send, &mut recv, |peer| {
+                        ordering = self.ordering.negotiate(peer);
+                        PeerInfo {
+                            event_ordering: Some(ordering),
+                            id_scheme: peer.id_scheme,
+                            // Every framing is served
+                            framing: peer.framing,
+                            compression: peer.compression.filter(|_| self.dictionaries.is_some()),
+                            event_window: peer
+                                .event_window
+                                .map(|window| window.min(self.max_event_window))
+                                .filter(|&window| window > 0),
+                            heartbeat: peer
+                                .heartbeat
+                                .map(|ms| ms.max(MIN_HEARTBEAT_INTERVAL.as_millis() as u32)),
+                            action_push: peer
+                                .action_push
+                                .map(|window| window.min(MAX_PUSHED_ACTIONS))
+                                .filter(|&window| window > 0),
+                            replay_from: self.missing_events(peer),
+                            ..PeerInfo::default()
+                        }
+                    })
+                    .await?;
+                    self.order = self.ordering.check(ordering);
+                    self.ids = IdCheck::new(peer.id_scheme.unwrap_or_default());
+                    self.framing = peer.framing.unwrap_or_default();
+                    self.logged_client =
+                        self.event_log.as_ref().and_then(|_| self.client_key(&peer));
+                    if let Some(client) = self.client_key(&peer) {
+                        self.actions = self.action_cursors.lock().unwrap().cursor(&client);
+                    }
+                    if peer.compression.is_some() {
+                        self.dictionary_updates = self.dictionaries.as_ref().map(|d| d.subscribe());
+                    }
+                    self.peer = Some(peer);
+
+                    // Follow the HELLO with the current recommended settings,
+                    // and the current dictionary if the client compresses
+                    let settings = *self.settings.borrow_and_update();
+                    timeout(
+                        STREAM_TIMEOUT,
+                        send.write_all(&settings.to_headers().encode()),
+                    )
+                    .await??;
+                    let dictionary = self
+                        .dictionary_updates
+                        .as_mut()
+                        .and_then(|updates| updates.borrow_and_update().clone());
+                    if let Some(dictionary) = dictionary {
+                        info!("Pushing compression {}", dictionary);
+                        timeout(
+                            STREAM_TIMEOUT,
+                            send.write_all(&dictionary.to_headers().encode()),
+                        )
+                        .await??;
+                    }
+                    self.control_stream = Some(StreamPair {
+                        send: FrameWriter::new(send, None),
+                        recv,
+                        headers: false,
+                    });
+                    Ok(kind)
+                } else {
+                    Err(violation("one control stream per connection"))
+                }
+            }
+            _ => Err(violation(
+                "an event, state commit, action or control stream",
+            )),
+        };
+        match result {
+            Ok(kind) => {
+                self.stream_metrics(kind)
+                    .opened
+                    .fetch_add(1, Ordering::Relaxed);
+                self.states.track(id, stream_name(kind), StreamState::Ready);
+            }
+            Err(_) => self.states.forget(id),
         }
+        result
+    }
+
+    // Key of a client that named itself in its HELLO, under the tenant its
+    // usage is accounted to, which until then is its address
+    fn client_key(&self, peer: &PeerInfo) -> Option<String> {
+        let tenant = peer.tenant.as_deref().unwrap_or(&self.tenant);
+        Some(format!("{}/{}", tenant, peer.client_id.as_ref()?))
+    }
+
+    // Where the event log lacks events the client says it sent, to ask for
+    // them again in the HELLO reply
+    fn missing_events(&self, peer: &PeerInfo) -> Option<u32> {
+        let log = self.event_log.as_ref()?;
+        let client = self.client_key(peer)?;
+        let from = log.missing_from(&client, peer.last_event_id?)?;
+        info!(
+            "Event log of {} lacks events from {} to {}, asking for them again",
+            client, from, peer.last_event_id?
+        );
+        Some(from)
+    }
+
+    // Metrics for the streams with discriminator `kind`
+    fn stream_metrics(&self, kind: u8) -> Arc<StreamTypeMetrics> {
+        let name = self
+            .streams
+            .get(kind)
+            .map_or("unknown", |t| t.name.as_str());
+        self.metrics.stream(name)
     }
 
     async fn handle_all_streams(
@@ -72,48 +560,272 @@ impl ProtonStreamHandler {
         connection: &QuinnConnection,
     ) -> Result<(), ProtonError> {
         let closed = connection.closed();
+        let event_metrics = self.stream_metrics(STREAM_EVENT);
+        let state_commit_metrics = self.stream_metrics(STREAM_STATE_COMMIT);
+        let action_metrics = self.stream_metrics(STREAM_ACTION);
+        let control_metrics = self.stream_metrics(STREAM_CONTROL);
+        let payload_metrics = self.stream_metrics(STREAM_PAYLOAD);
+        let framing = self.framing;
+        let compressed = self.dictionary_updates.is_some();
+        let mode = self.mode;
+        let peer = connection.remote_address();
+        let states = self.states.clone();
+        let sent = self.sent.clone();
+        let parking = self.parking.clone();
+        let stall_config = Arc::clone(&self.stall);
+        let stall = StallWatch {
+            config: &stall_config,
+            connection,
+            states: &states,
+            sent: &sent,
+        };
+        let push_window = self.peer.as_ref().and_then(|peer| peer.action_push);
+        let stream_ids = [
+            &self.event_stream,
+            &self.state_commit_stream,
+            &self.action_stream,
+        ]
+        .map(|pair| pair.as_ref().map(|pair| pair.recv.id()));
 
         let event_stream_fut = async {
             if let Some(StreamPair {
                 ref mut send,
                 ref mut recv,
+                headers,
             }) = self.event_stream
             {
+                let stream_id = recv.id();
+                // Byte offset of the next request, past the discriminator
+                let mut position: u64 = 1;
                 loop {
                     let mut data = [0u8; 4];
                     match timeout(STREAM_TIMEOUT, recv.read_exact(&mut data)).await {
                         Ok(Ok(_)) => {
-                            let event_id = u32::from_le_bytes(data);
-
-                            // Verify monotonicity
-                            if event_id <= self.last_event_id {
-                                return Err(ProtonError::InvalidStream);
+                            let started = Instant::now();
+                            parking.wake();
+                            if let Some(v) = Violation::frame_len("event", position, framing, data)
+                            {
+                                return Err(ProtonError::ProtocolViolation(v));
                             }
-                            self.last_event_id = event_id;
+                            let mut frame =
+                                framing.read_rest(MessageKind::Event, recv, data).await?;
+                            let event_id = frame.id;
+                            states.enter(stream_id, StreamState::Handling(event_id));
+                            let (header_len, frame_headers) = intercept_frame(
+                                recv,
+                                headers,
+                                &self.interceptors,
+                                STREAM_EVENT,
+                                event_id,
+                            )
+                            .await?;
 
-                            // Send acknowledgment
-                            match timeout(STREAM_TIMEOUT, send.write_all(&event_id.to_le_bytes()))
-                                .await
+                            let mut duplicate = None;
+                            let request_len =
+                                framing.encoded_len(MessageKind::Event, &frame) + header_len;
+                            position += request_len as u64;
+                            if let Some(ref dictionaries) = self.dictionaries {
+                                // A payload that cannot be opened cannot be
+                                // handled, however lenient the server is
+                                if compressed {
+                                    frame.payload =
+                                        dictionaries.decompress(&frame.payload).map_err(|e| {
+                                            ProtonError::ProtocolViolation(Violation::new(
+                                                "event",
+                                                position - request_len as u64,
+                                                "a payload compressed with a pushed dictionary",
+                                                e,
+                                            ))
+                                        })?;
+                                }
+                                dictionaries.sample(&frame.payload);
+                            }
+                            let ack = match self
+                                .usage
+                                .record_received(&self.tenant, request_len as u64)
                             {
-                                Ok(Ok(_)) => {
-                                    println!("Event {} acknowledged", event_id);
+                                Ok(()) => {
+                                    let at = position - request_len as u64;
+                                    if let Some(reason) = self.ids.check(event_id) {
+                                        deviate(
+                                            mode,
+                                            &self.tenant,
+                                            Violation::new(
+                                                "event",
+                                                at,
+                                                format!("{} event id", self.ids.scheme()),
+                                                format!("event id {} ({})", event_id, reason),
+                                            ),
+                                        )?;
+                                    }
+                                    self.metrics.dedupe_lookups.fetch_add(1, Ordering::Relaxed);
+                                    match self.order.admit(event_id) {
+                                        Admit::Accept => {}
+                                        Admit::Duplicate(ack) => {
+                                            self.metrics
+                                                .dedupe_hits
+                                                .fetch_add(1, Ordering::Relaxed);
+                                            duplicate = Some(ack)
+                                        }
+                                        Admit::Reject => deviate(
+                                            mode,
+                                            &self.tenant,
+                                            Violation::new(
+                                                "event",
+                                                at,
+                                                self.order.expected(),
+                                                format!("event id {}", event_id),
+                                            ),
+                                        )?,
+                                    }
+                                    let ctx = RequestContext {
+                                        tenant: &self.tenant,
+                                        peer,
+                                        headers: &frame_headers,
+                                    };
+                                    // Duplicates were handled the first time
+                                    let handled = match duplicate {
+                                        Some(ack) => Ok(ack),
+                                        None => self
+                                            .handler
+                                            .on_event(ctx, &frame)
+                                            .await
+                                            .inspect(|&ack| self.order.acked(event_id, ack)),
+                                    };
+                                    if let (None, Ok(_), Some(log), Some(client)) =
+                                        (duplicate, &handled, &self.event_log, &self.logged_client)
+                                    {
+                                        // The ack goes out regardless; the client
+                                        // is asked for the event again when it
+                                        // reconnects
+                                        if let Err(e) = log.append(client, &frame) {
+                                            warn!("Failed to log event {}: {}", event_id, e);
+                                        }
+                                    }
+                                    match handled {
+                                        Ok(ack) => ack,
+                                        Err(ProtonError::QuotaExceeded) => QUOTA_EXCEEDED,
+                                        Err(e) => {
+                                            warn!("Handler failed on event {}: {}", event_id, e);
+                                            return Err(e);
+                                        }
+                                    }
                                 }
-                                Ok(Err(e)) => {
-                                    eprintln!("Failed to send event ack: {}", e);
-                                    return Err(ProtonError::ConnectionError);
+                                Err(e) => {
+                                    info!(
+                                        "Refusing event {} from {}: {}",
+                                        event_id, self.tenant, e
+                                    );
+                                    QUOTA_EXCEEDED
                                 }
-                                Err(_) => {
-                                    eprintln!("Timeout sending event ack");
+                            };
+
+                            // Deliberate misbehavior, for testing clients
+                            if self.misbehavior.reset_stream() {
+                                info!("Misbehaving: resetting event stream at event {}", event_id);
+                                send.reset(RESET_BY_MISBEHAVIOR).await;
+                                let _ = recv.stop(VarInt::from_u32(RESET_BY_MISBEHAVIOR));
+                                states.enter(
+                                    stream_id,
+                                    StreamState::Errored("reset by misbehavior".to_string()),
+                                );
+                                // The connection carries on without this stream
+                                std::future::pending::<()>().await;
+                            }
+                            let ack = match ack {
+                                QUOTA_EXCEEDED => ack,
+                                ack => self.misbehavior.wrong_id(ack),
+                            };
+                            let dropped = ack != QUOTA_EXCEEDED && self.misbehavior.drop_ack();
+
+                            // Send acknowledgment
+                            let response =
+                                encode_response(framing, MessageKind::Ack, &Frame::new(ack))?;
+                            let sent = if dropped {
+                                info!("Misbehaving: not acknowledging event {}", event_id);
+                                Ok(())
+                            } else {
+                                stall
+                                    .write(
+                                        stream_name(STREAM_EVENT),
+                                        send,
+                                        Some(recv),
+                                        &response,
+                                        false,
+                                        &event_metrics,
+                                    )
+                                    .await
+                            };
+                            match sent {
+                                Ok(()) => {
+                                    states.enter(stream_id, StreamState::Ready);
+                                    event_metrics.observe(started);
+                                    if !dropped {
+                                        self.metrics.acks_sent.fetch_add(1, Ordering::Relaxed);
+                                        self.usage.record_sent(&self.tenant, response.len() as u64);
+                                        if duplicate.is_some() {
+                                            info!(
+                                                "Duplicate event {} acknowledged again",
+                                                event_id
+                                            );
+                                            continue;
+                                        }
+                                        if frame.payload.is_empty() {
+                                            info!("Event {} acknowledged", event_id);
+                                        } else {
+                                            info!(
+                                                "Event {} acknowledged ({} payload bytes)",
+                                                event_id,
+                                                frame.payload.len()
+                                            );
+                                        }
+                                    }
+                                    if duplicate.is_some() {
+                                        continue;
+                                    }
+                                    if let Some(ref registered) = self.registered {
+                                        registered
+                                            .counters()
+                                            .events
+                                            .fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    #[cfg(feature = "alloc-audit")]
+                                    crate::proton::alloc::frame_processed();
+                                    // Accepted events go on to the event sinks
+                                    if ack != QUOTA_EXCEEDED
+                                        && (self.sink.is_some() || self.reorder.is_some())
+                                    {
+                                        let event = SinkEvent {
+                                            event_id,
+                                            tenant: self.tenant.clone(),
+                                            peer: connection.remote_address(),
+                                            headers: frame_headers,
+                                            payload: frame.payload,
+                                            received_at: SystemTime::now(),
+                                        };
+                                        match (&self.reorder, &self.sink) {
+                                            (Some(reorder), _) => reorder.push(event),
+                                            (None, Some(sink)) => sink.push(event),
+                                            (None, None) => {}
+                                        }
+                                    }
+                                }
+                                Err(ProtonError::Timeout) => {
+                                    warn!("Timeout sending event ack");
                                     return Err(ProtonError::Timeout);
                                 }
+                                Err(e) => {
+                                    error!("Failed to send event ack: {}", e);
+                                    return Err(ProtonError::ConnectionError);
+                                }
                             }
                         }
                         Ok(Err(e)) => {
-                            eprintln!("Failed to read event: {}", e);
+                            error!("Failed to read event: {}", e);
                             return Err(ProtonError::ConnectionError);
                         }
                         Err(_) => {
-                            eprintln!("Timeout reading event");
+                            warn!("Timeout reading event");
                             return Err(ProtonError::Timeout);
                         }
                     }
@@ -126,39 +838,180 @@ impl ProtonStreamHandler {
             if let Some(StreamPair {
                 ref mut send,
                 ref mut recv,
+                headers,
             }) = self.state_commit_stream
             {
+                let stream_id = recv.id();
+                // Byte offset of the next request, past the discriminator
+                let mut position: u64 = 1;
                 loop {
                     let mut data = [0u8; 4];
                     match timeout(STREAM_TIMEOUT, recv.read_exact(&mut data)).await {
                         Ok(Ok(_)) => {
-                            let commit_id = u32::from_le_bytes(data);
-                            println!("Received state commit: {}", commit_id);
+                            let started = Instant::now();
+                            parking.wake();
+                            if let Some(v) =
+                                Violation::frame_len("state commit", position, framing, data)
+                            {
+                                return Err(ProtonError::ProtocolViolation(v));
+                            }
+                            let frame = framing
+                                .read_rest(MessageKind::StateCommit, recv, data)
+                                .await?;
+                            let (header_len, commit_headers) = intercept_frame(
+                                recv,
+                                headers,
+                                &self.interceptors,
+                                STREAM_STATE_COMMIT,
+                                frame.id,
+                            )
+                            .await?;
+                            let (commit_id, abort) = decode_commit(frame.id);
+                            states.enter(stream_id, StreamState::Handling(commit_id));
+                            if abort {
+                                info!("Received abort of state commit: {}", commit_id);
+                            } else {
+                                info!("Received state commit: {}", commit_id);
+                            }
 
                             // Send response
-                            let response = commit_id + 2;
-                            match timeout(STREAM_TIMEOUT, send.write_all(&response.to_le_bytes()))
-                                .await
+                            let request_len =
+                                framing.encoded_len(MessageKind::StateCommit, &frame) + header_len;
+                            position += request_len as u64;
+                            let response = match self
+                                .usage
+                                .record_received(&self.tenant, request_len as u64)
                             {
-                                Ok(Ok(_)) => {
-                                    println!("State commit {} response sent", commit_id);
+                                Ok(()) if abort => {
+                                    // Only commits made on this connection can be aborted
+                                    let compensated = if !self.recent_commits.contains(commit_id) {
+                                        Err("unknown or already aborted".to_string())
+                                    } else if let Some(ref handler) = self.commits {
+                                        handler
+                                            .on_abort(&self.tenant, commit_id)
+                                            .map_err(|e| e.to_string())
+                                    } else {
+                                        Ok(())
+                                    };
+                                    match compensated {
+                                        Ok(()) => {
+                                            self.recent_commits.forget(commit_id);
+                                            info!("State commit {} aborted", commit_id);
+                                            commit_id
+                                        }
+                                        Err(e) => {
+                                            info!(
+                                                "Refusing abort of state commit {}: {}",
+                                                commit_id, e
+                                            );
+                                            ABORT_REFUSED
+                                        }
+                                    }
                                 }
-                                Ok(Err(e)) => {
-                                    eprintln!("Failed to send state commit response: {}", e);
-                                    return Err(ProtonError::ConnectionError);
+                                Ok(()) => {
+                                    let ctx = RequestContext {
+                                        tenant: &self.tenant,
+                                        peer,
+                                        headers: &commit_headers,
+                                    };
+                                    match self.handler.on_state_commit(ctx, &frame).await {
+                                        Ok(response) => {
+                                            self.recent_commits.record(commit_id);
+                                            if let Some(ref handler) = self.commits {
+                                                handler.on_commit_with_payload(
+                                                    &self.tenant,
+                                                    commit_id,
+                                                    &frame.payload,
+                                                );
+                                            }
+                                            response
+                                        }
+                                        Err(ProtonError::QuotaExceeded) => QUOTA_EXCEEDED,
+                                        Err(e) => {
+                                            warn!(
+                                                "Handler failed on state commit {}: {}",
+                                                commit_id, e
+                                            );
+                                            return Err(e);
+                                        }
+                                    }
                                 }
-                                Err(_) => {
-                                    eprintln!("Timeout sending state commit response");
+                                Err(e) => {
+                                    info!(
+                                        "Refusing state commit {} from {}: {}",
+                                        commit_id, self.tenant, e
+                                    );
+                                    QUOTA_EXCEEDED
+                                }
+                            };
+
+                            // Deliberate misbehavior, for testing clients
+                            if let Some(delay) = self.misbehavior.commit_delay() {
+                                info!(
+                                    "Misbehaving: delaying state commit {} by {:?}",
+                                    commit_id, delay
+                                );
+                                sleep(delay).await;
+                            }
+                            if self.misbehavior.reset_stream() {
+                                info!(
+                                    "Misbehaving: resetting state commit stream at commit {}",
+                                    commit_id
+                                );
+                                send.reset(RESET_BY_MISBEHAVIOR).await;
+                                let _ = recv.stop(VarInt::from_u32(RESET_BY_MISBEHAVIOR));
+                                states.enter(
+                                    stream_id,
+                                    StreamState::Errored("reset by misbehavior".to_string()),
+                                );
+                                // The connection carries on without this stream
+                                std::future::pending::<()>().await;
+                            }
+                            let response = match response {
+                                QUOTA_EXCEEDED | ABORT_REFUSED => response,
+                                response => self.misbehavior.wrong_id(response),
+                            };
+                            let response =
+                                encode_response(framing, MessageKind::Ack, &Frame::new(response))?;
+                            let sent = stall
+                                .write(
+                                    stream_name(STREAM_STATE_COMMIT),
+                                    send,
+                                    Some(recv),
+                                    &response,
+                                    false,
+                                    &state_commit_metrics,
+                                )
+                                .await;
+                            match sent {
+                                Ok(()) => {
+                                    states.enter(stream_id, StreamState::Ready);
+                                    state_commit_metrics.observe(started);
+                                    self.usage.record_sent(&self.tenant, response.len() as u64);
+                                    if let Some(ref registered) = self.registered {
+                                        registered
+                                            .counters()
+                                            .state_commits
+                                            .fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    info!("State commit {} response sent", commit_id);
+                                }
+                                Err(ProtonError::Timeout) => {
+                                    warn!("Timeout sending state commit response");
                                     return Err(ProtonError::Timeout);
                                 }
+                                Err(e) => {
+                                    error!("Failed to send state commit response: {}", e);
+                                    return Err(ProtonError::ConnectionError);
+                                }
                             }
                         }
                         Ok(Err(e)) => {
-                            eprintln!("Failed to read state commit: {}", e);
+                            error!("Failed to read state commit: {}", e);
                             return Err(ProtonError::ConnectionError);
                         }
                         Err(_) => {
-                            eprintln!("Timeout reading state commit");
+                            warn!("Timeout reading state commit");
                             return Err(ProtonError::Timeout);
                         }
                     }
@@ -171,41 +1024,255 @@ impl ProtonStreamHandler {
             if let Some(StreamPair {
                 ref mut send,
                 ref mut recv,
+                headers,
             }) = self.action_stream
             {
-                let mut counter = 0u32;
+                let stream_id = recv.id();
+                // Byte offset of the next request, past the discriminator
+                let mut position: u64 = 1;
+                if let Some(window) = push_window {
+                    // Actions go out on a stream of their own as the handler
+                    // produces them; the client's offsets arriving on the
+                    // action stream make room for more
+                    let mut push = connection.open_uni().await?;
+                    timeout(STREAM_TIMEOUT, push.write_all(&[STREAM_ACTION_PUSH])).await??;
+                    let push_id = push.id();
+                    states.track(push_id, stream_name(STREAM_ACTION_PUSH), StreamState::Ready);
+                    let mut push =
+                        FrameWriter::with_parking(push, self.coalesce, self.parking.clone());
+                    let room = Notify::new();
+                    let no_headers = Headers::new();
+
+                    let read_offsets = async {
+                        loop {
+                            let mut data = [0u8; 4];
+                            if let Err(e) = recv.read_exact(&mut data).await {
+                                error!("Failed to read action offset: {}", e);
+                                return Err(ProtonError::ConnectionError);
+                            }
+                            parking.wake();
+                            if let Some(v) = Violation::frame_len("action", position, framing, data)
+                            {
+                                return Err(ProtonError::ProtocolViolation(v));
+                            }
+                            let frame = framing.read_rest(MessageKind::Ack, recv, data).await?;
+                            let (header_len, _) = intercept_frame(
+                                recv,
+                                headers,
+                                &self.interceptors,
+                                STREAM_ACTION,
+                                frame.id,
+                            )
+                            .await?;
+                            let len = framing.encoded_len(MessageKind::Ack, &frame) + header_len;
+                            position += len as u64;
+                            // A tenant over quota is pushed nothing more
+                            match self.usage.record_received(&self.tenant, len as u64) {
+                                Ok(()) => {
+                                    info!("Actions acked up to {}", frame.id);
+                                    self.actions.lock().unwrap().ack_up_to(frame.id);
+                                    room.notify_one();
+                                }
+                                Err(e) => {
+                                    info!("Ignoring action offset from {}: {}", self.tenant, e)
+                                }
+                            }
+                        }
+                    };
+
+                    let push_actions = async {
+                        loop {
+                            let started = Instant::now();
+                            let queued = self
+                                .registered
+                                .as_ref()
+                                .and_then(|r| r.next_pushed_action());
+                            let unacknowledged = self.actions.lock().unwrap().unacknowledged();
+                            let action = match queued {
+                                Some(queued) => queued,
+                                None if unacknowledged < window => {
+                                    let action_id = self.actions.lock().unwrap().deliver();
+                                    let ctx = RequestContext {
+                                        tenant: &self.tenant,
+                                        peer,
+                                        headers: &no_headers,
+                                    };
+                                    match self.handler.next_action(ctx, action_id).await {
+                                        Ok(payload) => {
+                                            check_payload_len(payload.len())?;
+                                            Frame::with_payload(action_id, payload)
+                                        }
+                                        Err(ProtonError::QuotaExceeded) => {
+                                            // Pushed once the quota allows
+                                            self.actions.lock().unwrap().undeliver(action_id);
+                                            sleep(QUOTA_BACKOFF).await;
+                                            continue;
+                                        }
+                                        Err(e) => {
+                                            warn!("Handler failed on action {}: {}", action_id, e);
+                                            return Err(e);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    let queued = async {
+                                        match self.registered {
+                                            Some(ref registered) => {
+                                                registered.action_queued().await
+                                            }
+                                            None => std::future::pending().await,
+                                        }
+                                    };
+                                    tokio::select! {
+                                        _ = room.notified() => {}
+                                        _ = queued => {}
+                                    }
+                                    continue;
+                                }
+                            };
+                            let response = encode_response(framing, MessageKind::Action, &action)?;
+                            let pushed = stall
+                                .write(
+                                    stream_name(STREAM_ACTION_PUSH),
+                                    &mut push,
+                                    None,
+                                    &response,
+                                    true,
+                                    &action_metrics,
+                                )
+                                .await;
+                            if let Err(e) = pushed {
+                                error!("Failed to push action: {}", e);
+                                return Err(e);
+                            }
+                            action_metrics.observe(started);
+                            self.usage.record_sent(&self.tenant, response.len() as u64);
+                            if let Some(ref registered) = self.registered {
+                                registered
+                                    .counters()
+                                    .actions
+                                    .fetch_add(1, Ordering::Relaxed);
+                            }
+                            info!("Action {} pushed", action.id);
+                        }
+                    };
+
+                    return tokio::try_join!(read_offsets, push_actions).map(|((), ())| ());
+                }
                 loop {
                     let mut data = [0u8; 4];
                     match timeout(STREAM_TIMEOUT, recv.read_exact(&mut data)).await {
                         Ok(Ok(_)) => {
-                            let request_id = u32::from_le_bytes(data);
-                            println!("Received action request: {}", request_id);
+                            // Each request carries the consumer's offset
+                            let started = Instant::now();
+                            parking.wake();
+                            if let Some(v) = Violation::frame_len("action", position, framing, data)
+                            {
+                                return Err(ProtonError::ProtocolViolation(v));
+                            }
+                            let frame = framing.read_rest(MessageKind::Ack, recv, data).await?;
+                            let offset = frame.id;
+                            states.enter(stream_id, StreamState::Handling(offset));
+                            let (header_len, action_headers) = intercept_frame(
+                                recv,
+                                headers,
+                                &self.interceptors,
+                                STREAM_ACTION,
+                                offset,
+                            )
+                            .await?;
+                            info!("Received action request (acked up to {})", offset);
 
                             // Send action
-                            let action = counter;
-                            match timeout(STREAM_TIMEOUT, send.write_all(&action.to_le_bytes()))
-                                .await
+                            let request_len =
+                                framing.encoded_len(MessageKind::Ack, &frame) + header_len;
+                            position += request_len as u64;
+                            let action = match self
+                                .usage
+                                .record_received(&self.tenant, request_len as u64)
                             {
-                                Ok(Ok(_)) => {
-                                    println!("Action {} sent", action);
-                                    counter += 1;
+                                Ok(()) => {
+                                    self.actions.lock().unwrap().ack_up_to(offset);
+                                    // Actions pushed to this connection go first
+                                    match self
+                                        .registered
+                                        .as_ref()
+                                        .and_then(|r| r.next_pushed_action())
+                                    {
+                                        Some(pushed) => pushed,
+                                        None => {
+                                            let action_id = self.actions.lock().unwrap().deliver();
+                                            let ctx = RequestContext {
+                                                tenant: &self.tenant,
+                                                peer,
+                                                headers: &action_headers,
+                                            };
+                                            match self.handler.next_action(ctx, action_id).await {
+                                                Ok(payload) => {
+                                                    check_payload_len(payload.len())?;
+                                                    Frame::with_payload(action_id, payload)
+                                                }
+                                                Err(ProtonError::QuotaExceeded) => {
+                                                    Frame::new(QUOTA_EXCEEDED)
+                                                }
+                                                Err(e) => {
+                                                    warn!(
+                                                        "Handler failed on action {}: {}",
+                                                        action_id, e
+                                                    );
+                                                    return Err(e);
+                                                }
+                                            }
+                                        }
+                                    }
                                 }
-                                Ok(Err(e)) => {
-                                    eprintln!("Failed to send action: {}", e);
-                                    return Err(ProtonError::ConnectionError);
+                                Err(e) => {
+                                    info!("Refusing action request from {}: {}", self.tenant, e);
+                                    Frame::new(QUOTA_EXCEEDED)
                                 }
-                                Err(_) => {
-                                    eprintln!("Timeout sending action");
+                            };
+                            let response = encode_response(framing, MessageKind::Action, &action)?;
+                            // The consumer is waiting on this action, so it is
+                            // never held back for coalescing
+                            let sent = stall
+                                .write(
+                                    stream_name(STREAM_ACTION),
+                                    send,
+                                    Some(recv),
+                                    &response,
+                                    true,
+                                    &action_metrics,
+                                )
+                                .await;
+                            match sent {
+                                Ok(()) => {
+                                    states.enter(stream_id, StreamState::Ready);
+                                    action_metrics.observe(started);
+                                    self.usage.record_sent(&self.tenant, response.len() as u64);
+                                    if let Some(ref registered) = self.registered {
+                                        registered
+                                            .counters()
+                                            .actions
+                                            .fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    info!("Action {} sent", action.id);
+                                }
+                                Err(ProtonError::Timeout) => {
+                                    warn!("Timeout sending action");
                                     return Err(ProtonError::Timeout);
                                 }
+                                Err(e) => {
+                                    error!("Failed to send action: {}", e);
+                                    return Err(ProtonError::ConnectionError);
+                                }
                             }
                         }
                         Ok(Err(e)) => {
-                            eprintln!("Failed to read action request: {}", e);
+                            error!("Failed to read action request: {}", e);
                             return Err(ProtonError::ConnectionError);
                         }
                         Err(_) => {
-                            eprintln!("Timeout reading action request");
+                            warn!("Timeout reading action request");
                             return Err(ProtonError::Timeout);
                         }
                     }
@@ -214,175 +1281,2062 @@ impl ProtonStreamHandler {
             Ok(())
         };
 
+        // Push updated client settings as the operator changes them, and a
+        // GOAWAY once the connection reaches its maximum age
+        let goaway_grace = self.goaway_grace;
+        let expired = &self.metrics.connections_expired;
+        let control_stream_fut = async {
+            let mut goaway_at = self.goaway_at.map(tokio::time::Instant::from_std);
+            let mut close_at = None;
+            if let Some(StreamPair {
+                ref mut send,
+                ref mut recv,
+                ..
+            }) = self.control_stream
+            {
+                let control = stream_name(STREAM_CONTROL);
+                loop {
+                    tokio::select! {
+                        _ = sleep_until(goaway_at.unwrap_or_else(tokio::time::Instant::now)),
+                            if goaway_at.is_some() =>
+                        {
+                            goaway_at = None;
+                            close_at = Some(tokio::time::Instant::now() + goaway_grace);
+                            info!(
+                                "Connection reached its maximum age, sending GOAWAY with {:?} to reconnect",
+                                goaway_grace
+                            );
+                            expired.fetch_add(1, Ordering::Relaxed);
+                            let settings = *self.settings.borrow();
+                            let pushed = drain::goaway_headers(&settings, goaway_grace).encode();
+                            stall
+                                .write(control, send, Some(recv), &pushed, false, &control_metrics)
+                                .await?;
+                        }
+                        _ = sleep_until(close_at.unwrap_or_else(tokio::time::Instant::now)),
+                            if close_at.is_some() => break,
+                        changed = self.settings.changed() => {
+                            if changed.is_err() {
+                                break;
+                            }
+                            let settings = *self.settings.borrow_and_update();
+                            info!("Pushing client settings: {}", settings);
+                            let pushed = settings.to_headers().encode();
+                            stall
+                                .write(control, send, Some(recv), &pushed, false, &control_metrics)
+                                .await?;
+                        }
+                        Some(dictionary) = next_dictionary(&mut self.dictionary_updates) => {
+                            info!("Pushing compression {}", dictionary);
+                            let pushed = dictionary.to_headers().encode();
+                            stall
+                                .write(control, send, Some(recv), &pushed, false, &control_metrics)
+                                .await?;
+                        }
+                    }
+                }
+            }
+            // Without a control stream there is no GOAWAY to send, so an old
+            // connection is closed as soon as it reaches its age
+            if let Some(at) = close_at.or(goaway_at) {
+                sleep_until(at).await;
+                info!("Closing connection past its maximum age");
+                connection.close(
+                    CloseReason::MaxAge.into(),
+                    b"Maximum connection age reached",
+                );
+                return Ok(());
+            }
+            // Nothing more to push; the data streams decide when we are done
+            std::future::pending::<Result<(), ProtonError>>().await
+        };
+
+        // Large payloads arrive on streams opened per event, alongside
+        // streams of the types the application registered
+        let payload_fut = async {
+            let mut heartbeat_every = self
+                .peer
+                .as_ref()
+                .and_then(|peer| peer.heartbeat)
+                .map(|ms| Duration::from_millis(ms.into()));
+            while let Ok((send, mut recv)) = connection.accept_bi().await {
+                let id = recv.id();
+                states.track(id, "new", StreamState::AwaitingDiscriminator);
+                let mut discriminator = [0u8; 1];
+                timeout(STREAM_TIMEOUT, recv.read_exact(&mut discriminator)).await??;
+                let (kind, headers) = decode_discriminator(discriminator[0]);
+                // One heartbeat stream, if heartbeats were agreed in the HELLO
+                if let (STREAM_HEARTBEAT, Some(every)) = (kind, heartbeat_every.take()) {
+                    states.track(id, stream_name(kind), StreamState::Ready);
+                    let heartbeat = Arc::clone(&self.heartbeat);
+                    let (connection, states) = (connection.clone(), states.clone());
+                    spawn_named(
+                        "heartbeat",
+                        async move {
+                            heartbeat::run(connection, send, recv, every, &heartbeat, states).await
+                        }
+                        .instrument(stream_span(stream_name(kind), kind)),
+                    );
+                    continue;
+                }
+                if kind != STREAM_PAYLOAD {
+                    let (stream_type, factory) = self
+                        .streams
+                        .handler(kind)
+                        .ok_or(ProtonError::InvalidStream)?;
+                    let handler = factory.create(&self.tenant);
+                    let tenant = self.tenant.clone();
+                    let metrics = self.metrics.stream(&stream_type.name);
+                    metrics.opened.fetch_add(1, Ordering::Relaxed);
+                    let span = stream_span(&stream_type.name, kind);
+                    states.track(id, &stream_type.name, StreamState::Ready);
+                    let states = states.clone();
+                    spawn_named(
+                        "registered stream",
+                        async move {
+                            let result = serve_stream(
+                                stream_type.kind,
+                                handler,
+                                send,
+                                recv,
+                                headers,
+                                &metrics,
+                            )
+                            .await;
+                            states.forget(id);
+                            if let Err(e) = metrics.check(result) {
+                                warn!("{} stream from {} failed: {}", stream_type.name, tenant, e);
+                            }
+                        }
+                        .instrument(span),
+                    );
+                    continue;
+                }
+                let handler = self.payloads.clone();
+                let transfers = Arc::clone(&self.transfers);
+                let usage = Arc::clone(&self.usage);
+                let tenant = self.tenant.clone();
+                let client = self.payload_client.clone();
+                let metrics = Arc::clone(&payload_metrics);
+                metrics.opened.fetch_add(1, Ordering::Relaxed);
+                let span = stream_span(stream_name(STREAM_PAYLOAD), STREAM_PAYLOAD);
+                states.track(id, stream_name(STREAM_PAYLOAD), StreamState::Ready);
+                let states = states.clone();
+                spawn_named(
+                    "payload stream",
+                    async move {
+                        let started = Instant::now();
+                        let result = receive_payload(
+                            send,
+                            recv,
+                            headers,
+                            handler.as_deref(),
+                            &transfers,
+                            &usage,
+                            &tenant,
+                            &client,
+                        )
+                        .await;
+                        states.forget(id);
+                        if result.is_ok() {
+                            metrics.observe(started);
+                        }
+                        if let Err(e) = metrics.check(result) {
+                            error!("Payload transfer failed: {}", e);
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+            // The connection is closing, which the select below notices
+            std::future::pending::<Result<(), ProtonError>>().await
+        };
+
+        // Datagrams from the client keep the connection alive or carry
+        // telemetry, which goes to the datagram handler
+        let datagram_fut = async {
+            while let Ok(datagram) = connection.read_datagram().await {
+                let counters = self.registered.as_ref().map(|r| r.counters());
+                match datagram.first() {
+                    Some(&DATAGRAM_KEEPALIVE) => {
+                        if let Some(counters) = counters {
+                            counters.keepalives.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Some(&DATAGRAM_TELEMETRY) if datagram.len() <= self.max_datagram_size => {
+                        parking.wake();
+                        if let Some(counters) = counters {
+                            counters.datagrams.fetch_add(1, Ordering::Relaxed);
+                        }
+                        let reply = self
+                            .datagrams
+                            .as_ref()
+                            .and_then(|handler| handler.on_datagram(&self.tenant, &datagram[1..]));
+                        if let Some(reply) = reply {
+                            let mut bytes = Vec::with_capacity(reply.len() + 1);
+                            bytes.push(DATAGRAM_TELEMETRY);
+                            bytes.extend_from_slice(&reply);
+                            // Telemetry is lossy: a reply that does not fit is dropped
+                            let _ = connection.send_datagram(bytes.into());
+                        }
+                    }
+                    Some(&DATAGRAM_TELEMETRY) => warn!(
+                        "Ignoring {} byte datagram from {}, larger than {} bytes",
+                        datagram.len(),
+                        self.tenant,
+                        self.max_datagram_size
+                    ),
+                    _ => warn!("Ignoring unexpected datagram from {}", self.tenant),
+                }
+            }
+            std::future::pending::<Result<(), ProtonError>>().await
+        };
+
         tokio::select! {
             _ = closed => {
-                println!("Client closed connection");
+                info!("Client closed connection");
                 Ok(())
             }
-            r = event_stream_fut => r,
-            r = state_commit_stream_fut => r,
-            r = action_stream_fut => r,
+            r = profiled("event stream", event_stream_fut)
+                .instrument(stream_span(stream_name(STREAM_EVENT), STREAM_EVENT)) => {
+                settle(&states, stream_ids[0], &r);
+                event_metrics.check(r)
+            }
+            r = profiled("state commit stream", state_commit_stream_fut)
+                .instrument(stream_span(stream_name(STREAM_STATE_COMMIT), STREAM_STATE_COMMIT)) => {
+                settle(&states, stream_ids[1], &r);
+                state_commit_metrics.check(r)
+            }
+            r = profiled("action stream", action_stream_fut)
+                .instrument(stream_span(stream_name(STREAM_ACTION), STREAM_ACTION)) => {
+                settle(&states, stream_ids[2], &r);
+                action_metrics.check(r)
+            }
+            r = profiled("control stream", control_stream_fut)
+                .instrument(stream_span(stream_name(STREAM_CONTROL), STREAM_CONTROL)) => r,
+            r = profiled("payload streams", payload_fut) => r,
+            r = profiled("datagrams", datagram_fut) => r,
+        }
+    }
+}
+
+// The next dictionary published for a client that compresses; never
+// resolves for one that does not
+async fn next_dictionary(
+    updates: &mut Option<watch::Receiver<Option<Arc<Dictionary>>>>,
+) -> Option<Arc<Dictionary>> {
+    let Some(updates) = updates else {
+        return std::future::pending().await;
+    };
+    match updates.changed().await {
+        Ok(()) => updates.borrow_and_update().clone(),
+        Err(_) => std::future::pending().await,
+    }
+}
+
+// Record how a data stream's task ended
+fn settle(states: &StreamStates, id: Option<StreamId>, result: &Result<(), ProtonError>) {
+    if let Some(id) = id {
+        let state = match result {
+            Ok(()) => StreamState::Draining,
+            Err(e) => StreamState::Errored(e.to_string()),
+        };
+        states.enter(id, state);
+    }
+}
+
+// How often a connection's client address is checked for a change; quinn
+// gives no notice of a migration
+const MIGRATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Counts a connection as active or parked for as long as it is watched
+struct ParkGauge<'a> {
+    metrics: &'a ServerMetrics,
+    parked: bool,
+}
+
+impl<'a> ParkGauge<'a> {
+    fn new(metrics: &'a ServerMetrics) -> Self {
+        metrics.connections_active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            metrics,
+            parked: false,
+        }
+    }
+
+    fn gauge(&self) -> &AtomicI64 {
+        if self.parked {
+            &self.metrics.connections_parked
+        } else {
+            &self.metrics.connections_active
+        }
+    }
+
+    fn set(&mut self, parked: bool) {
+        self.gauge().fetch_sub(1, Ordering::Relaxed);
+        self.parked = parked;
+        self.gauge().fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ParkGauge<'_> {
+    fn drop(&mut self) {
+        self.gauge().fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Logs and counts each change of a connection's client address, and parks
+// the connection once it has served nothing for `park_after`, for as long
+// as the connection is served
+async fn watch_connection(
+    connection: RegisteredConnection,
+    metrics: Arc<ServerMetrics>,
+    park_after: Option<Duration>,
+) {
+    let mut gauge = ParkGauge::new(&metrics);
+    let parking = connection.parking().clone();
+    let mut activity = connection.activity();
+    let mut idle_since = Instant::now();
+    loop {
+        if gauge.parked {
+            // The next request wakes the connection before the interval ends
+            let _ = timeout(PARKED_CHECK_INTERVAL, parking.woken()).await;
+        } else {
+            sleep(MIGRATION_CHECK_INTERVAL).await;
+        }
+        if let Some(from) = connection.check_migrated() {
+            metrics
+                .connection_migrations
+                .fetch_add(1, Ordering::Relaxed);
+            info!(
+                "Client migrated from {} to {} ({} migrations)",
+                from,
+                connection.addr(),
+                connection.migrations()
+            );
+        }
+        let latest = connection.activity();
+        if latest != activity {
+            activity = latest;
+            idle_since = Instant::now();
+            // Actions pushed to the client count too
+            parking.wake();
+        }
+        match (gauge.parked, parking.is_parked()) {
+            (true, false) => {
+                gauge.set(false);
+                idle_since = Instant::now();
+                debug!("Resumed connection {}", connection.id());
+            }
+            (false, false)
+                if park_after.is_some_and(|after| idle_since.elapsed() >= after)
+                    && parking.park() =>
+            {
+                gauge.set(true);
+                debug!(
+                    "Parked connection {}, idle for {:?}",
+                    connection.id(),
+                    idle_since.elapsed()
+                );
+            }
+            _ => {}
         }
     }
 }
 
+// Span of a stream of the connection whose span is current
+fn stream_span(name: &str, discriminator: u8) -> tracing::Span {
+    info_span!("stream", stream = name, discriminator = discriminator)
+}
+
+// Days until the first certificate of the chain expires, as the chain is
+// rejected once any of them has
+fn chain_days_until_expiry(chain: &[ChainCertificate]) -> i64 {
+    chain
+        .iter()
+        .map(|cert| cert.validity.days_until_expiry())
+        .min()
+        .unwrap_or(i64::MAX)
+}
+
+fn chain_position(index: usize) -> &'static str {
+    if index == 0 {
+        "Server certificate"
+    } else {
+        "Intermediate certificate"
+    }
+}
+
 pub struct ProtonServer {
     endpoint: Endpoint,
-    active_connection: Arc<Mutex<Option<ProtonStreamHandler>>>,
+    // The endpoint's socket, kept to hand over to a successor process
+    socket: std::net::UdpSocket,
+    drain: DrainHandle,
+    drain_timeout: Duration,
+    listening: watch::Sender<bool>,
+    // DSCP the server's packets carry
+    marking: Marking,
+    // Where the server's packets are traced, if anywhere
+    trace: PacketTrace,
+    metrics: Arc<ServerMetrics>,
+    // Every certificate of the chain presented, leaf first
+    cert_chain: Vec<ChainCertificate>,
+    cert_expiry_warning_days: i64,
+    allow_expired_cert: bool,
+    access_list: Arc<RwLock<AccessList>>,
+    log_rejected: bool,
+    rate_limiter: Option<std::sync::Mutex<ConnectionRateLimiter>>,
+    tls: Arc<std::sync::Mutex<TlsMaterial>>,
+    ocsp_file: Option<(PathBuf, Duration)>,
+    crl_files: Option<(Vec<PathBuf>, Duration)>,
+    handshake_timeout: Duration,
+    handshakes: Arc<Semaphore>,
+    actions: Arc<std::sync::Mutex<ActionCursors>>,
+    interceptors: Vec<Arc<dyn FrameInterceptor>>,
+    sinks: Vec<Arc<dyn EventSink>>,
+    handler: Arc<dyn ProtonHandler>,
+    commits: Option<Arc<dyn CommitHandler>>,
+    misbehavior: Arc<MisbehaviorControl>,
+    ordering: Arc<OrderingPolicy>,
+    registry: Arc<ConnectionRegistry>,
+    client_settings: Arc<watch::Sender<ClientSettings>>,
+    admission: Arc<std::sync::Mutex<Admission>>,
+    usage: Arc<UsageLedger>,
+    admin_addr: Option<SocketAddr>,
+    metrics_export: Option<MetricsExport>,
+    payloads: Option<Arc<dyn PayloadHandler>>,
+    transfers: Arc<PayloadTransfers>,
+    coalesce: Option<CoalesceConfig>,
+    reorder: Option<ReorderConfig>,
+    dictionaries: Option<Arc<DictionaryTrainer>>,
+    dedupe: Option<Arc<DedupeIndex>>,
+    event_log: Option<Arc<EventLog>>,
+    max_event_window: u32,
+    park_after: Option<Duration>,
+    max_connection_age: Option<Duration>,
+    stall: Arc<StallConfig>,
+    streams: Arc<StreamRegistry>,
+    mode: ProtocolMode,
+    datagrams: Option<Arc<dyn DatagramHandler>>,
+}
+
+// Everything needed to (re)build the rustls server configuration
+#[derive(Clone)]
+struct TlsMaterial {
+    cert_chain: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+    policy: TlsPolicy,
+    ocsp_response: Vec<u8>,
+    client_roots: Option<RootCertStore>,
+    crls: Vec<Vec<u8>>,
+    psk: Option<Vec<u8>>,
+    connection_limit: u32,
+    // Application registered stream types, each needing room for its streams
+    stream_types: u32,
+    // Largest telemetry datagram accepted, which sizes the datagram buffers
+    max_datagram_size: usize,
+    // Idle timeout, keepalive and stream limits
+    transport: ProtonConfig,
+}
+
+// A place among the handshakes allowed in flight, given up once the
+// handshake has finished either way
+struct HandshakeSlot {
+    _permit: OwnedSemaphorePermit,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl HandshakeSlot {
+    fn new(permit: OwnedSemaphorePermit, metrics: Arc<ServerMetrics>) -> Self {
+        metrics.handshakes_in_flight.fetch_add(1, Ordering::Relaxed);
+        Self {
+            _permit: permit,
+            metrics,
+        }
+    }
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        self.metrics
+            .handshakes_in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Per connection settings handed to each connection task
+struct ConnectionContext {
+    tls_policy: TlsPolicy,
+    psk: Option<Vec<u8>>,
+    handshake_timeout: Duration,
+    handshake: Option<HandshakeSlot>,
+    metrics: Arc<ServerMetrics>,
+    actions: Arc<std::sync::Mutex<ActionCursors>>,
+    interceptors: Vec<Arc<dyn FrameInterceptor>>,
+    sink: Option<SinkQueue>,
+    handler: Arc<dyn ProtonHandler>,
+    commits: Option<Arc<dyn CommitHandler>>,
+    misbehavior: Arc<MisbehaviorControl>,
+    ordering: Arc<OrderingPolicy>,
+    registry: Arc<ConnectionRegistry>,
+    settings: watch::Receiver<ClientSettings>,
+    admission: Arc<std::sync::Mutex<Admission>>,
+    usage: Arc<UsageLedger>,
+    payloads: Option<Arc<dyn PayloadHandler>>,
+    transfers: Arc<PayloadTransfers>,
+    coalesce: Option<CoalesceConfig>,
+    reorder: Option<ReorderConfig>,
+    dictionaries: Option<Arc<DictionaryTrainer>>,
+    dedupe: Option<Arc<DedupeIndex>>,
+    event_log: Option<Arc<EventLog>>,
+    max_event_window: u32,
+    park_after: Option<Duration>,
+    max_connection_age: Option<Duration>,
+    goaway_grace: Duration,
+    stall: Arc<StallConfig>,
+    streams: Arc<StreamRegistry>,
+    mode: ProtocolMode,
+    datagrams: Option<Arc<dyn DatagramHandler>>,
+    max_datagram_size: usize,
+    // Span the connection's task runs in
+    span: tracing::Span,
 }
 
 impl ProtonServer {
+    /// A server answering requests through `handler`; `EchoHandler` gives
+    /// the demo behaviour.
     pub fn new(
         addr: SocketAddr,
         cert: rustls::Certificate,
         key: rustls::PrivateKey,
+        handler: Arc<dyn ProtonHandler>,
     ) -> Result<Self, ProtonError> {
-        // Configure TLS
-        let mut server_crypto = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
-            .map_err(|e| ProtonError::IoError(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        Self::with_cert_chain(addr, vec![cert], key, handler)
+    }
+
+    /// A server presenting the certificate chain and private key in PEM or
+    /// DER files, e.g. issued by a real CA, instead of one passed in memory.
+    /// The chain starts with the server's own certificate; the key must
+    /// belong to it.
+    pub fn with_cert_files(
+        addr: SocketAddr,
+        cert: &Path,
+        key: &Path,
+        handler: Arc<dyn ProtonHandler>,
+    ) -> Result<Self, ProtonError> {
+        let chain = load_certs(cert)?;
+        let key = load_private_key(key)?;
+        verify_key_pair(&chain[0], &key)?;
+        Self::with_cert_chain(addr, chain, key, handler)
+    }
+
+    fn with_cert_chain(
+        addr: SocketAddr,
+        cert_chain: Vec<rustls::Certificate>,
+        key: rustls::PrivateKey,
+        handler: Arc<dyn ProtonHandler>,
+    ) -> Result<Self, ProtonError> {
+        // Parse our own chain so expiry can be monitored
+        let inspected = inspect_chain(&cert_chain)?;
+        let metrics = Arc::new(ServerMetrics::new());
+        // Built-in stream types are reported from the start, even when idle
+        for stream_type in [
+            STREAM_EVENT,
+            STREAM_STATE_COMMIT,
+            STREAM_ACTION,
+            STREAM_PAYLOAD,
+        ] {
+            if let Some(stream_type) = StreamRegistry::default().get(stream_type) {
+                metrics.stream(&stream_type.name);
+            }
+        }
+        metrics
+            .cert_days_until_expiry
+            .store(chain_days_until_expiry(&inspected), Ordering::Relaxed);
+
+        let tls = TlsMaterial {
+            cert_chain,
+            key,
+            policy: TlsPolicy::default(),
+            ocsp_response: Vec::new(),
+            client_roots: None,
+            crls: Vec::new(),
+            psk: None,
+            connection_limit: MAX_CONNECTIONS,
+            stream_types: 0,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            transport: ProtonConfig::default(),
+        };
+        let server_config = Self::build_server_config(&tls)?;
+
+        // Create endpoint
+        let marking = Marking::default();
+        let trace = PacketTrace::default();
+        let socket = std::net::UdpSocket::bind(addr)?;
+        let endpoint = dscp::bind_socket(
+            socket.try_clone()?,
+            Some(server_config),
+            marking.clone(),
+            trace.clone(),
+        )?;
+
+        Ok(ProtonServer {
+            drain: DrainHandle::new(endpoint.clone()),
+            endpoint,
+            socket,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            listening: watch::channel(false).0,
+            marking,
+            trace,
+            metrics,
+            cert_chain: inspected,
+            cert_expiry_warning_days: CERT_EXPIRY_WARNING_DAYS,
+            allow_expired_cert: false,
+            access_list: Arc::new(RwLock::new(AccessList::default())),
+            log_rejected: false,
+            rate_limiter: None,
+            tls: Arc::new(std::sync::Mutex::new(tls)),
+            ocsp_file: None,
+            crl_files: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            handshakes: Arc::new(Semaphore::new(MAX_CONCURRENT_HANDSHAKES as usize)),
+            actions: Arc::new(std::sync::Mutex::new(ActionCursors::default())),
+            interceptors: Vec::new(),
+            sinks: Vec::new(),
+            handler,
+            commits: None,
+            misbehavior: Arc::new(MisbehaviorControl::new(Misbehavior::default())),
+            ordering: Arc::new(OrderingPolicy::default()),
+            registry: Arc::new(ConnectionRegistry::new()),
+            client_settings: Arc::new(watch::channel(ClientSettings::default()).0),
+            admission: Arc::new(std::sync::Mutex::new(Admission::new(
+                MAX_CONNECTIONS,
+                false,
+            ))),
+            usage: Arc::new(UsageLedger::new(Quota::default())),
+            admin_addr: None,
+            metrics_export: None,
+            payloads: None,
+            transfers: Arc::new(PayloadTransfers::new()),
+            coalesce: None,
+            reorder: None,
+            dictionaries: None,
+            dedupe: None,
+            event_log: None,
+            max_event_window: DEFAULT_MAX_EVENT_WINDOW,
+            park_after: None,
+            max_connection_age: None,
+            stall: Arc::new(StallConfig::default()),
+            streams: Arc::new(StreamRegistry::default()),
+            mode: ProtocolMode::default(),
+            datagrams: None,
+        })
+    }
+
+    fn build_server_config(tls: &TlsMaterial) -> Result<ServerConfig, ProtonError> {
+        let builder = tls.policy.server_builder()?;
+        let builder = match tls.client_roots {
+            Some(ref roots) => builder.with_client_cert_verifier(Arc::new(
+                RevocationCheckingVerifier::new(roots.clone(), &tls.crls)?,
+            )),
+            None => builder.with_no_client_auth(),
+        };
+
+        // Configure TLS, stapling the OCSP response if we have one
+        let mut server_crypto = builder
+            .with_single_cert_with_ocsp_and_sct(
+                tls.cert_chain.clone(),
+                tls.key.clone(),
+                tls.ocsp_response.clone(),
+                Vec::new(),
+            )
+            .map_err(|e| ProtonError::IoError(std::io::Error::other(e)))?;
         server_crypto.alpn_protocols = vec![b"proton".to_vec()];
+        // Accept 0-RTT data from clients resuming a session. Clients open
+        // their streams in it but hold back the HELLO, and streams are only
+        // accepted once the handshake completes, so a replay achieves nothing
+        server_crypto.max_early_data_size = u32::MAX;
 
-        // Configure QUIC server
+        // Configure QUIC server, leaving room for payload streams, for streams
+        // of registered types and for the auth stream in PSK mode
+        let transport = &tls.transport;
+        let max_streams = transport.max_bidi_streams
+            + transport.max_payload_streams
+            + tls.stream_types * transport.max_streams_per_type
+            + u32::from(tls.psk.is_some());
         let mut server_config = ServerConfig::with_crypto(Arc::new(server_crypto));
         let mut transport_config = quinn::TransportConfig::default();
         transport_config
-            .keep_alive_interval(Some(std::time::Duration::from_secs(5)))
-            .max_idle_timeout(Some(IDLE_TIMEOUT.try_into().unwrap()))
-            .max_concurrent_bidi_streams(MAX_BIDIRECTIONAL_STREAMS.into());
+            .keep_alive_interval(Some(transport.keep_alive_interval()))
+            .max_idle_timeout(Some(transport.idle_timeout.try_into().unwrap()))
+            .max_concurrent_bidi_streams(max_streams.into());
+        datagram::configure_transport(&mut transport_config, tls.max_datagram_size);
         server_config.transport_config(Arc::new(transport_config));
 
-        // Only allow one connection
-        server_config.concurrent_connections(MAX_CONNECTIONS.into());
+        // Limit connections, leaving room for a newcomer to handshake and
+        // declare its priority when preemption is enabled
+        server_config.concurrent_connections(tls.connection_limit);
 
-        // Create endpoint
-        let endpoint = Endpoint::server(server_config, addr)?;
+        Ok(server_config)
+    }
 
-        Ok(ProtonServer {
-            endpoint,
-            active_connection: Arc::new(Mutex::new(None)),
-        })
+    // Applies changed TLS settings to the endpoint
+    fn reload_server_config(&mut self) -> Result<(), ProtonError> {
+        let config = Self::build_server_config(&self.tls.lock().unwrap())?;
+        self.endpoint.set_server_config(Some(config));
+        Ok(())
     }
 
-    pub async fn run(&self) -> Result<(), ProtonError> {
-        // Wait for startup delay to ensure old connections are cleaned up
-        println!(
-            "Waiting {} seconds for startup delay...",
-            STARTUP_DELAY.as_secs()
+    /// Restrict the cipher suites and key exchange groups offered to clients.
+    pub fn with_tls_policy(mut self, policy: TlsPolicy) -> Result<Self, ProtonError> {
+        self.tls.lock().unwrap().policy = policy;
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Staple the DER encoded OCSP response in `path` into every handshake.
+    /// The file is re-read every `refresh` so an external fetcher can keep it
+    /// current.
+    pub fn with_ocsp_response_file(
+        mut self,
+        path: PathBuf,
+        refresh: Duration,
+    ) -> Result<Self, ProtonError> {
+        self.tls.lock().unwrap().ocsp_response = Self::load_ocsp_response(&path)?;
+        self.reload_server_config()?;
+        self.ocsp_file = Some((path, refresh));
+        Ok(self)
+    }
+
+    /// Require clients to present a certificate chaining to `roots` (mTLS).
+    pub fn with_client_auth(mut self, roots: RootCertStore) -> Result<Self, ProtonError> {
+        self.tls.lock().unwrap().client_roots = Some(roots);
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Require clients to authenticate with a pre-shared key. The TLS
+    /// certificate is then only used for encryption and need not be managed.
+    pub fn with_psk(mut self, psk: Vec<u8>) -> Result<Self, ProtonError> {
+        self.tls.lock().unwrap().psk = Some(psk);
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Reject client certificates revoked by the CRLs in `paths`. The files
+    /// are re-read every `refresh`. Only meaningful together with
+    /// [`ProtonServer::with_client_auth`].
+    pub fn with_crl_files(
+        mut self,
+        paths: Vec<PathBuf>,
+        refresh: Duration,
+    ) -> Result<Self, ProtonError> {
+        self.tls.lock().unwrap().crls = Self::load_crl_files(&paths)?;
+        self.reload_server_config()?;
+        self.crl_files = Some((paths, refresh));
+        Ok(self)
+    }
+
+    fn load_ocsp_response(path: &Path) -> Result<Vec<u8>, ProtonError> {
+        let der = std::fs::read(path)?;
+        match check_ocsp_response(&der)? {
+            OcspStatus::Good => {}
+            status => warn!("stapled OCSP response reports {:?}", status),
+        }
+        Ok(der)
+    }
+
+    fn load_crl_files(paths: &[PathBuf]) -> Result<Vec<Vec<u8>>, ProtonError> {
+        let mut crls = Vec::new();
+        for path in paths {
+            crls.extend(load_crls(path)?);
+        }
+        Ok(crls)
+    }
+
+    // Periodically re-reads a TLS input file and rebuilds the endpoint's
+    // configuration when its contents change
+    fn spawn_tls_refresher<T, L, A>(&self, what: &'static str, refresh: Duration, load: L, apply: A)
+    where
+        T: Send + 'static,
+        L: Fn() -> Result<T, ProtonError> + Send + 'static,
+        A: Fn(&mut TlsMaterial, T) -> bool + Send + 'static,
+    {
+        let drain = self.drain.clone();
+        let tls = Arc::clone(&self.tls);
+        spawn_named(format!("{} refresh", what), async move {
+            loop {
+                sleep(refresh).await;
+                let loaded = match load() {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        warn!("Keeping previous {}: {}", what, e);
+                        continue;
+                    }
+                };
+                let mut tls = tls.lock().unwrap();
+                let previous = tls.clone();
+                if !apply(&mut tls, loaded) {
+                    continue;
+                }
+                match Self::build_server_config(&tls) {
+                    // A draining endpoint must keep refusing connections
+                    Ok(_) if drain.is_draining() => {}
+                    Ok(config) => {
+                        drain.endpoint.set_server_config(Some(config));
+                        info!("Reloaded {}", what);
+                    }
+                    Err(e) => {
+                        error!("Failed to apply {}: {}", what, e);
+                        *tls = previous;
+                    }
+                }
+            }
+        });
+    }
+
+    fn spawn_tls_refreshers(&self) {
+        if let Some((path, refresh)) = self.ocsp_file.clone() {
+            self.spawn_tls_refresher(
+                "OCSP response",
+                refresh,
+                move || Self::load_ocsp_response(&path),
+                |tls, response| {
+                    let changed = tls.ocsp_response != response;
+                    tls.ocsp_response = response;
+                    changed
+                },
+            );
+        }
+        if let Some((paths, refresh)) = self.crl_files.clone() {
+            self.spawn_tls_refresher(
+                "certificate revocation lists",
+                refresh,
+                move || Self::load_crl_files(&paths),
+                |tls, crls| {
+                    let changed = tls.crls != crls;
+                    tls.crls = crls;
+                    changed
+                },
+            );
+        }
+    }
+
+    /// Warn at startup and on every expiry check when fewer than `days`
+    /// remain for any certificate of the chain.
+    pub fn with_cert_expiry_warning(mut self, days: i64) -> Self {
+        self.cert_expiry_warning_days = days;
+        self
+    }
+
+    /// Start even if a certificate of the chain has already expired.
+    pub fn with_allow_expired_cert(mut self, allow: bool) -> Self {
+        self.allow_expired_cert = allow;
+        self
+    }
+
+    /// Restrict which source addresses may connect.
+    pub fn with_access_list(self, list: AccessList) -> Self {
+        *self.access_list.write().unwrap() = list;
+        self
+    }
+
+    /// Log every connection attempt rejected by the access list.
+    pub fn with_log_rejected(mut self, log: bool) -> Self {
+        self.log_rejected = log;
+        self
+    }
+
+    /// Abort handshakes that have not completed within `deadline`. With a
+    /// pre-shared key, the client has as long again to authenticate.
+    pub fn with_handshake_timeout(mut self, deadline: Duration) -> Self {
+        self.handshake_timeout = deadline;
+        self
+    }
+
+    /// Allow at most `max` handshakes in flight at once. Further connection
+    /// attempts are refused straight away instead of queueing, so a
+    /// reconnect storm after a restart cannot starve established
+    /// connections of CPU.
+    pub fn with_handshake_limit(mut self, max: u32) -> Self {
+        self.handshakes = Arc::new(Semaphore::new(max as usize));
+        self
+    }
+
+    /// Observe every request frame and its headers as it is received.
+    pub fn with_frame_interceptor(mut self, interceptor: Arc<dyn FrameInterceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Forward every accepted event to `sink` as well, after it has been
+    /// acknowledged. Sinks are fed in the order they were added.
+    pub fn with_event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Notify `handler` of every state commit and let it compensate commits
+    /// that clients abort. Without a handler aborts of known commits are
+    /// simply accepted.
+    pub fn with_commit_handler(mut self, handler: Arc<dyn CommitHandler>) -> Self {
+        self.commits = Some(handler);
+        self
+    }
+
+    /// Deliberately misbehave as configured, to test client resilience. The
+    /// misbehavior can be changed at runtime through the admin endpoint.
+    pub fn with_misbehavior(self, misbehavior: Misbehavior) -> Self {
+        self.misbehavior.replace(misbehavior);
+        self
+    }
+
+    /// Event ordering for clients that do not ask for one in their HELLO.
+    /// Monotonic by default.
+    pub fn with_event_ordering(mut self, ordering: EventOrdering) -> Self {
+        Arc::make_mut(&mut self.ordering).default = ordering;
+        self
+    }
+
+    /// Event ordering imposed on `tenant`'s connections, whatever they ask for.
+    pub fn with_tenant_event_ordering(mut self, tenant: &str, ordering: EventOrdering) -> Self {
+        Arc::make_mut(&mut self.ordering)
+            .tenants
+            .insert(tenant.to_string(), ordering);
+        self
+    }
+
+    /// How many acknowledged events each connection remembers, `ACK_WINDOW`
+    /// by default. A retransmission of one of them, e.g. by a client whose
+    /// ack was lost, is acknowledged again with the same ack and not
+    /// processed a second time. With 0, retransmissions are handled like any
+    /// other event, so they break a monotonic or contiguous ordering.
+    pub fn with_ack_window(mut self, window: usize) -> Self {
+        Arc::make_mut(&mut self.ordering).ack_window = window;
+        self
+    }
+
+    /// Remember the acks of clients that send a client id in a log at
+    /// `path`, synced as `fsync` says, instead of per connection. A
+    /// retransmission is then recognised on a later connection, and after
+    /// the server restarts, within the same ack window. The log keeps the
+    /// window set by [`Self::with_ack_window`], so set that first.
+    pub fn with_dedupe_log(mut self, path: &Path, fsync: FsyncPolicy) -> Result<Self, ProtonError> {
+        let index = DedupeIndex::open(path, fsync, self.ordering.ack_window)?;
+        info!(
+            "Loaded {} acknowledged events from {} (fsync {})",
+            index.len(),
+            path.display(),
+            fsync
+        );
+        self.dedupe = Some(Arc::new(index));
+        Ok(self)
+    }
+
+    /// Log the events handled for clients that send a client id to `path`,
+    /// synced as `fsync` says. A client reconnecting with events the log
+    /// lacks, e.g. after the server restarted before syncing them, is
+    /// asked in the HELLO reply to send them again. With a `retention`
+    /// limit, the log is compacted in the background.
+    pub fn with_event_log(
+        mut self,
+        path: &Path,
+        fsync: FsyncPolicy,
+        retention: Retention,
+    ) -> Result<Self, ProtonError> {
+        let log = EventLog::open(path, fsync)?.with_retention(retention);
+        info!(
+            "Opened event log {} with {} events (fsync {}, retention {})",
+            path.display(),
+            log.len(),
+            fsync,
+            retention
+        );
+        self.event_log = Some(Arc::new(log));
+        Ok(self)
+    }
+
+    /// The log configured with `with_event_log`, e.g. to replay a client's
+    /// events with `EventLog::replay_from`.
+    pub fn event_log(&self) -> Option<Arc<EventLog>> {
+        self.event_log.clone()
+    }
+
+    /// Settings recommended to clients in the HELLO response. Clients apply
+    /// them unless configured locally.
+    pub fn with_client_settings(self, settings: ClientSettings) -> Self {
+        self.client_settings.send_replace(settings);
+        self
+    }
+
+    /// Use the transport parameters in `config`: its idle timeout,
+    /// keepalive interval, stream limits and startup delay.
+    pub fn with_config(mut self, config: ProtonConfig) -> Result<Self, ProtonError> {
+        config.validate()?;
+        self.tls.lock().unwrap().transport = config;
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Serve up to `max` clients at once. With `preempt`, a client arriving at
+    /// capacity evicts the connected client with the lowest HELLO priority if
+    /// its own priority is higher; otherwise newcomers are turned away.
+    pub fn with_connection_limit(mut self, max: u32, preempt: bool) -> Result<Self, ProtonError> {
+        {
+            let mut admission = self.admission.lock().unwrap();
+            let duplicates = admission.duplicate_policy();
+            *admission = Admission::new(max, preempt);
+            admission.set_duplicate_policy(duplicates);
+            self.tls.lock().unwrap().connection_limit = admission.connection_slots();
+        }
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Apply `policy` when a client connects while already connected. Clients
+    /// are identified by their HELLO client id, else their tenant, else their
+    /// source address.
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> Result<Self, ProtonError> {
+        {
+            let mut admission = self.admission.lock().unwrap();
+            admission.set_duplicate_policy(Some(policy));
+            self.tls.lock().unwrap().connection_limit = admission.connection_slots();
+        }
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Enforce byte quotas per tenant. Requests from a tenant that has used up
+    /// its quota are answered with `QUOTA_EXCEEDED` instead of being processed.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.usage = Arc::new(UsageLedger::new(quota));
+        self
+    }
+
+    /// Deliver large event payloads to `handler` chunk by chunk. Without a
+    /// handler payloads are read and discarded.
+    pub fn with_payload_handler(mut self, handler: Arc<dyn PayloadHandler>) -> Self {
+        self.payloads = Some(handler);
+        self
+    }
+
+    /// Deliver the telemetry clients send as datagrams to `handler`. Without
+    /// a handler telemetry is counted and discarded.
+    pub fn with_datagram_handler(mut self, handler: Arc<dyn DatagramHandler>) -> Self {
+        self.datagrams = Some(handler);
+        self
+    }
+
+    /// Accept telemetry datagrams of up to `max` bytes, tag included, and
+    /// size the transport's datagram buffers for them (`MAX_DATAGRAM_SIZE`
+    /// by default). Larger datagrams are dropped.
+    pub fn with_max_datagram_size(mut self, max: usize) -> Result<Self, ProtonError> {
+        check_max_datagram_size(max)?;
+        self.tls.lock().unwrap().max_datagram_size = max;
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// Serve streams of `stream_type` alongside the built-in ones, each with a
+    /// handler made by `factory`. Clients open them once connected, after
+    /// registering the same type.
+    pub fn with_stream_type(
+        mut self,
+        stream_type: StreamType,
+        factory: Arc<dyn StreamHandlerFactory>,
+    ) -> Result<Self, ProtonError> {
+        self.metrics.stream(&stream_type.name);
+        Arc::make_mut(&mut self.streams).register(stream_type, Some(factory))?;
+        self.tls.lock().unwrap().stream_types = self.streams.registered().count() as u32;
+        self.reload_server_config()?;
+        Ok(self)
+    }
+
+    /// How to treat clients that deviate from the protocol: by default the
+    /// deviations the server can read past, such as an event out of order,
+    /// are logged and the request handled anyway. In strict mode any
+    /// deviation closes the connection with `CloseReason::ProtocolViolation` and a
+    /// diagnostic as the reason. Frames that cannot be read past, such as one
+    /// with a bad length, close the connection in either mode.
+    pub fn with_protocol_mode(mut self, mode: ProtocolMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Coalesce event and state commit responses so that many tiny frames
+    /// share stream writes and datagrams. Each response may be held back for
+    /// up to `config.max_delay`; actions are always sent immediately.
+    pub fn with_coalescing(mut self, config: CoalesceConfig) -> Self {
+        self.coalesce = Some(config);
+        self
+    }
+
+    /// Deliver events to the event sinks in id order, holding back events
+    /// that arrive early until the ids before them arrive or the window in
+    /// `config` is exceeded. Ids given up on and events arriving too late
+    /// are logged and counted in the metrics. Clients can only send events
+    /// out of order with `EventOrdering::Unordered`.
+    pub fn with_reordering(mut self, config: ReorderConfig) -> Self {
+        self.reorder = Some(config);
+        self
+    }
+
+    /// Most events a client may keep in flight on its event stream when it
+    /// asks for a window, `DEFAULT_MAX_EVENT_WINDOW` by default. Acks are
+    /// sent in order as each event is handled, so a window only saves the
+    /// client's round trips; 0 makes clients send one event at a time.
+    pub fn with_max_event_window(mut self, max: u32) -> Self {
+        self.max_event_window = max;
+        self
+    }
+
+    /// Park connections that have served no requests or telemetry for
+    /// `idle`: their coalescing write buffers are released and their client
+    /// address is checked every `PARKED_CHECK_INTERVAL` instead of every
+    /// second. The next request wakes a connection as it arrives. Parked
+    /// and active connections are counted in the metrics.
+    pub fn with_parking(mut self, idle: Duration) -> Self {
+        self.park_after = Some(idle);
+        self
+    }
+
+    /// Drain connections once they are `age` old, or up to a tenth younger
+    /// so those opened together are spread out: the server pushes a GOAWAY
+    /// on the control stream, and closes the connection with
+    /// `CLOSE_MAX_AGE` if the client has not after the drain timeout. Clients
+    /// reconnect meanwhile, authenticating again and possibly landing on
+    /// another server of a pool.
+    pub fn with_max_connection_age(mut self, age: Duration) -> Self {
+        self.max_connection_age = Some(age);
+        self
+    }
+
+    /// How long a response may stay unread before its client counts as
+    /// stalled, and what to do about it for each stream type. By default a
+    /// stall after 30s is logged and counted, and the write waits on until
+    /// the stream timeout.
+    pub fn with_stall_policy(mut self, config: StallConfig) -> Self {
+        self.stall = Arc::new(config);
+        self
+    }
+
+    /// Train zstd dictionaries on the event payloads received, as `config`
+    /// says, and push each to the clients that ask for compression in their
+    /// HELLO so they compress their payloads with it.
+    pub fn with_compression(mut self, config: CompressionConfig) -> Result<Self, ProtonError> {
+        if config.samples == 0 || config.dictionary_size > MAX_DICTIONARY_SIZE {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "compression needs at least one sample and a dictionary of at most {} bytes",
+                    MAX_DICTIONARY_SIZE
+                ),
+            )));
+        }
+        let trainer = DictionaryTrainer::new(config, Arc::clone(&self.metrics));
+        self.dictionaries = Some(Arc::new(trainer));
+        Ok(self)
+    }
+
+    /// Serve metrics and per tenant usage over HTTP on `addr`.
+    pub fn with_admin_addr(mut self, addr: SocketAddr) -> Self {
+        self.admin_addr = Some(addr);
+        self
+    }
+
+    /// Also export the metrics on their own HTTP listener, or as a summary
+    /// logged periodically.
+    pub fn with_metrics_export(mut self, export: MetricsExport) -> Self {
+        self.metrics_export = Some(export);
+        self
+    }
+
+    /// Mark every packet the server sends with `dscp`. All connections share
+    /// the server's socket, so the marking applies to all of them.
+    pub fn with_dscp(self, dscp: Dscp) -> Result<Self, ProtonError> {
+        self.marking.set(Some(dscp));
+        info!(
+            "Marking packets from {} with DSCP {}",
+            self.endpoint.local_addr()?,
+            dscp
         );
-        sleep(STARTUP_DELAY).await;
+        Ok(self)
+    }
+
+    /// Write a qlog trace of the packets of each client into `dir`, one
+    /// `.sqlog` file per client address, for loading into qvis. The trace
+    /// is taken at the server's socket, so it covers every connection.
+    pub fn with_qlog(self, dir: &Path) -> Result<Self, ProtonError> {
+        self.trace.enable(dir, Vantage::Server)?;
+        Ok(self)
+    }
+
+    /// Serve on `socket` instead of the address the server was created
+    /// with, e.g. a socket inherited from the process this one takes over
+    /// from, or from systemd socket activation.
+    pub fn with_socket(mut self, socket: std::net::UdpSocket) -> Result<Self, ProtonError> {
+        let server_config = Self::build_server_config(&self.tls.lock().unwrap())?;
+        let endpoint = dscp::bind_socket(
+            socket.try_clone()?,
+            Some(server_config),
+            self.marking.clone(),
+            self.trace.clone(),
+        )?;
+        self.endpoint.close(CloseReason::Normal.into(), b"");
+        self.drain = DrainHandle::new(endpoint.clone());
+        self.endpoint = endpoint;
+        self.socket = socket;
+        Ok(self)
+    }
+
+    /// How long a draining server waits for its connections to finish
+    /// before closing them.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Rate limit new connection attempts per source IP.
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limiter = Some(std::sync::Mutex::new(ConnectionRateLimiter::new(config)));
+        self
+    }
+
+    /// Handle to drain the server, after which `run` returns.
+    pub fn drain_handle(&self) -> DrainHandle {
+        self.drain.clone()
+    }
+
+    /// Descriptor of the listening socket, for a successor process to
+    /// inherit.
+    pub fn socket_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    /// Becomes true once `run` accepts connections.
+    pub fn listening(&self) -> watch::Receiver<bool> {
+        self.listening.subscribe()
+    }
+
+    /// Shared handle to the access list, which can be replaced at runtime
+    /// (e.g. on a configuration reload). New connection attempts are
+    /// evaluated against the current list.
+    pub fn access_list(&self) -> Arc<RwLock<AccessList>> {
+        Arc::clone(&self.access_list)
+    }
+
+    /// Handle to the recommended client settings. Replacing the value (e.g.
+    /// with `send_replace`) pushes it to every connected client.
+    pub fn client_settings(&self) -> Arc<watch::Sender<ClientSettings>> {
+        Arc::clone(&self.client_settings)
+    }
+
+    /// Clients currently connected, with the metadata from their HELLO.
+    pub fn peers(&self) -> Vec<ConnectedPeer> {
+        self.registry
+            .list()
+            .into_iter()
+            .map(|c| ConnectedPeer {
+                addr: c.addr(),
+                info: c.peer().clone(),
+                connected_at: c.connected_at(),
+            })
+            .collect()
+    }
+
+    /// Live connections, for inspecting, pushing actions to or closing
+    /// individual clients while the server runs.
+    pub fn registry(&self) -> Arc<ConnectionRegistry> {
+        Arc::clone(&self.registry)
+    }
+
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Application bytes exchanged with each tenant, for billing.
+    pub fn usage(&self) -> Arc<UsageLedger> {
+        Arc::clone(&self.usage)
+    }
 
-        println!("Server listening on {}", self.endpoint.local_addr()?);
+    /// Exports and imports the server's protocol state while it runs.
+    pub fn snapshots(&self) -> SnapshotHandle {
+        SnapshotHandle {
+            actions: Arc::clone(&self.actions),
+            usage: Arc::clone(&self.usage),
+            transfers: Arc::clone(&self.transfers),
+        }
+    }
 
-        // Only accept one connection at a time
-        while let Some(connecting) = self.endpoint.accept().await {
-            let active_connection = Arc::clone(&self.active_connection);
+    /// Start from state exported by another server, e.g. to seed a cold
+    /// standby or finish a migration.
+    pub fn with_snapshot(self, snapshot: &ServerSnapshot) -> Self {
+        self.snapshots().import(snapshot);
+        self
+    }
 
-            // Handle the new connection in a separate task
-            let connection_handle = tokio::spawn(async move {
-                match Self::handle_connection(connecting, active_connection).await {
-                    Ok(_) => println!("Connection handled successfully"),
-                    Err(e) => eprintln!("Connection error: {}", e),
+    fn check_cert_expiry(&self) -> Result<(), ProtonError> {
+        for (i, cert) in self.cert_chain.iter().enumerate() {
+            let days = cert.validity.days_until_expiry();
+            info!(
+                "{} '{}' issued by '{}' expires in {} days",
+                chain_position(i),
+                cert.subject,
+                cert.issuer,
+                days
+            );
+            if cert.validity.is_expired() {
+                if !self.allow_expired_cert {
+                    error!(
+                        "Refusing to start: {} '{}' has expired",
+                        chain_position(i),
+                        cert.subject
+                    );
+                    return Err(ProtonError::CertificateExpired);
                 }
-            });
+                warn!(
+                    "{} '{}' has expired, starting anyway",
+                    chain_position(i),
+                    cert.subject
+                );
+            } else if days < self.cert_expiry_warning_days {
+                warn!(
+                    "{} '{}' expires in {} days",
+                    chain_position(i),
+                    cert.subject,
+                    days
+                );
+            }
+        }
+        Ok(())
+    }
 
-            // Wait for this connection to complete before accepting another
-            if let Err(e) = connection_handle.await {
-                eprintln!("Connection task failed: {}", e);
+    fn spawn_cert_expiry_monitor(&self) {
+        let metrics = Arc::clone(&self.metrics);
+        let chain = self.cert_chain.clone();
+        let warning_days = self.cert_expiry_warning_days;
+        spawn_named("cert expiry monitor", async move {
+            loop {
+                sleep(CERT_EXPIRY_CHECK_INTERVAL).await;
+                metrics
+                    .cert_days_until_expiry
+                    .store(chain_days_until_expiry(&chain), Ordering::Relaxed);
+                for (i, cert) in chain.iter().enumerate() {
+                    let days = cert.validity.days_until_expiry();
+                    if days < warning_days {
+                        warn!(
+                            "{} '{}' expires in {} days",
+                            chain_position(i),
+                            cert.subject,
+                            days
+                        );
+                    }
+                }
             }
+        });
+    }
 
-            // Ensure connection is cleaned up
-            *self.active_connection.lock().await = None;
-            println!("Connection cleanup complete, ready for new connections");
+    pub async fn run(&self) -> Result<(), ProtonError> {
+        self.check_cert_expiry()?;
+        self.spawn_cert_expiry_monitor();
+        self.spawn_tls_refreshers();
+        let state = AdminState {
+            metrics: Arc::clone(&self.metrics),
+            usage: Arc::clone(&self.usage),
+            registry: Arc::clone(&self.registry),
+            misbehavior: Arc::clone(&self.misbehavior),
+            snapshots: self.snapshots(),
+            event_log: self.event_log.clone(),
+        };
+        if let Some(addr) = self.admin_addr {
+            let addr = admin::spawn(addr, state.clone()).await?;
+            info!("Admin endpoint listening on http://{}", addr);
+        }
+        match self.metrics_export {
+            Some(MetricsExport::Http(addr)) => {
+                let addr = admin::spawn_metrics(addr, state).await?;
+                info!("Metrics listening on http://{}/metrics", addr);
+            }
+            Some(MetricsExport::Log(interval)) => {
+                let metrics = Arc::clone(&self.metrics);
+                spawn_named("metrics log", async move {
+                    loop {
+                        sleep(interval).await;
+                        info!("Metrics: {}", metrics.summary());
+                    }
+                });
+            }
+            None => {}
+        }
+        if let Some(ref index) = self.dedupe {
+            if let FsyncPolicy::Interval(interval) = index.fsync() {
+                let index = Arc::clone(index);
+                spawn_named("dedupe flush", async move {
+                    loop {
+                        sleep(interval).await;
+                        if let Err(e) = index.flush() {
+                            warn!("Failed to sync the dedupe log: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+        if let Some(ref log) = self.event_log {
+            if let FsyncPolicy::Interval(interval) = log.fsync() {
+                let log = Arc::clone(log);
+                spawn_named("event log flush", async move {
+                    loop {
+                        sleep(interval).await;
+                        if let Err(e) = log.flush() {
+                            warn!("Failed to sync the event log: {}", e);
+                        }
+                    }
+                });
+            }
+            if !log.retention().is_unlimited() {
+                let log = Arc::clone(log);
+                spawn_named("event log compaction", async move {
+                    loop {
+                        sleep(eventlog::COMPACT_INTERVAL).await;
+                        let log = Arc::clone(&log);
+                        match tokio::task::spawn_blocking(move || log.compact()).await {
+                            Ok(Ok(compaction)) if compaction.events > 0 => {
+                                info!("Compacted the event log: {}", compaction)
+                            }
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => warn!("Failed to compact the event log: {}", e),
+                            Err(e) => warn!("Event log compaction panicked: {}", e),
+                        }
+                    }
+                });
+            }
+        }
+
+        let sink = (!self.sinks.is_empty())
+            .then(|| sink::spawn_forwarder(self.sinks.clone(), Arc::clone(&self.metrics)));
+
+        // Wait for startup delay to ensure old connections are cleaned up
+        let startup_delay = self.tls.lock().unwrap().transport.startup_delay;
+        info!("Waiting {:?} for startup delay...", startup_delay);
+        sleep(startup_delay).await;
+
+        info!("Server listening on {}", self.endpoint.local_addr()?);
+        self.listening.send_replace(true);
+
+        // Connection tasks in flight, with the peer each one serves
+        let mut connections = JoinSet::new();
+        let mut remotes = HashMap::new();
+        loop {
+            let connecting = tokio::select! {
+                connecting = self.endpoint.accept() => match connecting {
+                    Some(connecting) => connecting,
+                    None => break,
+                },
+                Some(ended) = connections.join_next_with_id() => {
+                    self.supervise(ended, &mut remotes);
+                    continue;
+                }
+                _ = self.drain.requested() => break,
+            };
+            // Evaluate the access list before the handshake completes
+            let remote = connecting.remote_address();
+            if !self.access_list.read().unwrap().is_allowed(remote.ip()) {
+                self.metrics
+                    .connections_rejected_access
+                    .fetch_add(1, Ordering::Relaxed);
+                if self.log_rejected {
+                    info!(
+                        "Rejecting connection from {}: denied by access list",
+                        remote
+                    );
+                }
+                // Dropping the handshake closes the connection attempt
+                drop(connecting);
+                continue;
+            }
+
+            if let Some(ref limiter) = self.rate_limiter {
+                let mut limiter = limiter.lock().unwrap();
+                let decision = limiter.check(remote.ip());
+                self.metrics
+                    .sources_banned
+                    .store(limiter.banned_sources() as i64, Ordering::Relaxed);
+                if decision != RateDecision::Allowed {
+                    self.metrics
+                        .connections_rate_limited
+                        .fetch_add(1, Ordering::Relaxed);
+                    if self.log_rejected {
+                        info!("Rejecting connection from {}: {:?}", remote, decision);
+                    }
+                    drop(connecting);
+                    continue;
+                }
+            }
+
+            // Refuse early rather than queue handshakes we cannot keep up with
+            let handshake = match Arc::clone(&self.handshakes).try_acquire_owned() {
+                Ok(permit) => HandshakeSlot::new(permit, Arc::clone(&self.metrics)),
+                Err(_) => {
+                    self.metrics
+                        .connections_refused_handshakes
+                        .fetch_add(1, Ordering::Relaxed);
+                    if self.log_rejected {
+                        info!(
+                            "Rejecting connection from {}: too many handshakes in flight",
+                            remote
+                        );
+                    }
+                    drop(connecting);
+                    continue;
+                }
+            };
+
+            // Everything logged for the connection carries its remote
+            // address, and its registry id once it is registered
+            let span = info_span!("connection", id = tracing::field::Empty, remote = %remote);
+            let context = {
+                let tls = self.tls.lock().unwrap();
+                ConnectionContext {
+                    tls_policy: tls.policy.clone(),
+                    psk: tls.psk.clone(),
+                    handshake_timeout: self.handshake_timeout,
+                    handshake: Some(handshake),
+                    metrics: Arc::clone(&self.metrics),
+                    actions: Arc::clone(&self.actions),
+                    interceptors: self.interceptors.clone(),
+                    sink: sink.clone(),
+                    handler: Arc::clone(&self.handler),
+                    commits: self.commits.clone(),
+                    misbehavior: Arc::clone(&self.misbehavior),
+                    ordering: Arc::clone(&self.ordering),
+                    registry: Arc::clone(&self.registry),
+                    settings: self.client_settings.subscribe(),
+                    admission: Arc::clone(&self.admission),
+                    usage: Arc::clone(&self.usage),
+                    payloads: self.payloads.clone(),
+                    transfers: Arc::clone(&self.transfers),
+                    coalesce: self.coalesce,
+                    reorder: self.reorder,
+                    dictionaries: self.dictionaries.clone(),
+                    dedupe: self.dedupe.clone(),
+                    event_log: self.event_log.clone(),
+                    max_event_window: self.max_event_window,
+                    park_after: self.park_after,
+                    max_connection_age: self.max_connection_age,
+                    goaway_grace: self.drain_timeout,
+                    stall: Arc::clone(&self.stall),
+                    streams: Arc::clone(&self.streams),
+                    mode: self.mode,
+                    datagrams: self.datagrams.clone(),
+                    max_datagram_size: tls.max_datagram_size,
+                    span: span.clone(),
+                }
+            };
+
+            // Handle the new connection in a separate task. The number of
+            // tasks is bounded by quinn's connection limit, and admission
+            // applies the configured limit after the HELLO.
+            let task = connections.spawn(
+                profiled(
+                    &format!("connection {}", remote),
+                    Self::handle_connection(connecting, context),
+                )
+                .instrument(span),
+            );
+            remotes.insert(task.id(), remote);
+            self.metrics
+                .connection_tasks
+                .fetch_add(1, Ordering::Relaxed);
         }
 
+        if self.drain.is_draining() {
+            info!(
+                "Draining {} connections for up to {:?}",
+                connections.len(),
+                self.drain_timeout
+            );
+            let drained = timeout(self.drain_timeout, async {
+                while let Some(ended) = connections.join_next_with_id().await {
+                    self.supervise(ended, &mut remotes);
+                }
+            })
+            .await;
+            if drained.is_err() {
+                warn!(
+                    "Closing {} connections still open after draining",
+                    connections.len()
+                );
+                self.endpoint
+                    .close(CloseReason::ServerRestarting.into(), b"server restarting");
+            }
+        }
+
+        // The endpoint was closed; let the remaining connections finish
+        while let Some(ended) = connections.join_next_with_id().await {
+            self.supervise(ended, &mut remotes);
+        }
+        let summary: Vec<String> = self
+            .metrics
+            .connection_outcomes()
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(outcome, count)| format!("{} {}", count, outcome))
+            .collect();
+        info!("Connections ended: {}", summary.join(", "));
+        if self.drain.is_draining() {
+            // Lets the closes go out before the process exits
+            self.endpoint.wait_idle().await;
+        }
         Ok(())
     }
 
+    // Report how a connection task ended and clean up after it, whether it
+    // returned or panicked
+    fn supervise(
+        &self,
+        ended: Result<(task::Id, Result<(), ProtonError>), JoinError>,
+        remotes: &mut HashMap<task::Id, SocketAddr>,
+    ) {
+        let (id, outcome, error) = match ended {
+            Ok((id, result)) => {
+                let error = match result {
+                    Ok(()) => {
+                        info!("Connection handled successfully");
+                        None
+                    }
+                    Err(ref e) => {
+                        error!("Connection error: {}", e);
+                        Some(e.to_string())
+                    }
+                };
+                (id, ConnectionOutcome::from_result(&result), error)
+            }
+            Err(e) => {
+                error!("Connection task failed: {}", e);
+                (e.id(), ConnectionOutcome::Panicked, Some(e.to_string()))
+            }
+        };
+        self.metrics.record_connection_outcome(outcome);
+        self.metrics
+            .connection_tasks
+            .fetch_sub(1, Ordering::Relaxed);
+        if let Some(remote) = remotes.remove(&id) {
+            if let Some(error) = error {
+                self.metrics.record_error(remote, error);
+            }
+            info!("Connection cleanup complete for {}", remote);
+        }
+    }
+
+    // Drive the handshake to completion, giving up after the deadline
+    async fn complete_handshake(
+        connecting: quinn::Connecting,
+        context: &ConnectionContext,
+    ) -> Result<QuinnConnection, ProtonError> {
+        let remote = connecting.remote_address();
+        let started = Instant::now();
+        match timeout(context.handshake_timeout, connecting).await {
+            Ok(Ok(connection)) => {
+                context
+                    .metrics
+                    .handshake_duration
+                    .observe(started.elapsed());
+                Ok(connection)
+            }
+            Ok(Err(e)) => {
+                let cause = HandshakeFailure::from_connection_error(&e);
+                context.metrics.record_handshake_failure(cause);
+                info!("Handshake with {} failed ({:?}): {}", remote, cause, e);
+                Err(e.into())
+            }
+            Err(_) => {
+                // Dropping the Connecting future aborts the handshake
+                context
+                    .metrics
+                    .record_handshake_failure(HandshakeFailure::Timeout);
+                info!(
+                    "Handshake with {} did not complete within {:?}",
+                    remote, context.handshake_timeout
+                );
+                Err(ProtonError::Timeout)
+            }
+        }
+    }
+
+    // The whole exchange is bounded by `deadline`, the handshake timeout,
+    // so a client that completes the TLS handshake and then stays silent is
+    // dropped as soon as one that never finishes it
+    async fn accept_psk_auth(
+        connection: &QuinnConnection,
+        psk: &[u8],
+        deadline: Duration,
+    ) -> Result<(), ProtonError> {
+        let auth = async {
+            let (send, mut recv) = connection.accept_bi().await?;
+            let mut discriminator = [0u8; 1];
+            recv.read_exact(&mut discriminator).await?;
+            if discriminator[0] != STREAM_AUTH {
+                return Err(ProtonError::AuthenticationFailed);
+            }
+            authenticate_server(connection, send, recv, psk).await
+        };
+        timeout(deadline, auth).await?
+    }
+
     async fn handle_connection(
         connecting: quinn::Connecting,
-        active_connection: Arc<Mutex<Option<ProtonStreamHandler>>>,
+        mut context: ConnectionContext,
     ) -> Result<(), ProtonError> {
-        let connection = connecting.await?;
-        println!(
-            "Connection established from {}",
-            connection.remote_address()
+        let connection = Self::complete_handshake(connecting, &context).await?;
+        context.handshake = None;
+        info!(
+            "Connection established from {} ({})",
+            connection.remote_address(),
+            context.tls_policy.negotiated(negotiated_alpn(&connection))
         );
 
-        // Check if there's already an active connection
-        let mut conn_guard = active_connection.lock().await;
-        if conn_guard.is_some() {
-            println!("Rejecting connection: another client is already connected");
-            drop(conn_guard);
-            connection.close(0u32.into(), b"Another client is already connected");
-            return Err(ProtonError::ConnectionError);
+        // With a pre-shared key the client must authenticate before anything else
+        if let Some(ref psk) = context.psk {
+            if let Err(e) = Self::accept_psk_auth(&connection, psk, context.handshake_timeout).await
+            {
+                info!("PSK authentication failed: {}", e);
+                connection.close(CloseReason::AuthFailed.into(), b"Authentication failed");
+                return Err(ProtonError::AuthenticationFailed);
+            }
+            info!("Client authenticated with pre-shared key");
         }
 
         // Create new stream handler
-        let mut stream_handler = ProtonStreamHandler::new();
+        let mut stream_handler = ProtonStreamHandler::new(
+            Arc::clone(&context.actions),
+            context.interceptors.clone(),
+            context.settings.clone(),
+            Arc::clone(&context.usage),
+            context.payloads.clone(),
+            Arc::clone(&context.transfers),
+            context.coalesce,
+        );
+        stream_handler.sink = context.sink.clone();
+        stream_handler.handler = Arc::clone(&context.handler);
+        stream_handler.commits = context.commits.clone();
+        stream_handler.misbehavior = Arc::clone(&context.misbehavior);
+        stream_handler.ordering = Arc::clone(&context.ordering);
+        stream_handler.order = context.ordering.check(EventOrdering::default());
+        stream_handler.streams = Arc::clone(&context.streams);
+        stream_handler.mode = context.mode;
+        stream_handler.metrics = Arc::clone(&context.metrics);
+        stream_handler.datagrams = context.datagrams.clone();
+        stream_handler.max_datagram_size = context.max_datagram_size;
+        stream_handler.dictionaries = context.dictionaries.clone();
+        stream_handler.max_event_window = context.max_event_window;
+        stream_handler.stall = Arc::clone(&context.stall);
+        stream_handler.event_log = context.event_log.clone();
+        stream_handler.goaway_at = context
+            .max_connection_age
+            .map(|age| Instant::now() + drain::connection_age(age));
+        stream_handler.goaway_grace = context.goaway_grace;
+        // Until the HELLO names a tenant
+        stream_handler.tenant = connection.remote_address().ip().to_string();
         let mut streams_established = 0;
 
         // Accept exactly 3 streams with timeout
         while streams_established < 3 {
             match timeout(std::time::Duration::from_secs(5), connection.accept_bi()).await {
-                Ok(Ok((send, recv))) => {
-                    if let Err(e) = stream_handler.handle_stream(send, recv).await {
-                        println!("Error handling stream: {}", e);
-                        *conn_guard = None;
-                        connection.close(1u32.into(), b"Stream setup error");
+                Ok(Ok((send, recv))) => match stream_handler.handle_stream(send, recv).await {
+                    Ok(STREAM_CONTROL) => {
+                        // The HELLO does not count towards the data streams
+                        if let Some(ref info) = stream_handler.peer {
+                            info!("Peer {} is {}", connection.remote_address(), info);
+                        }
+                    }
+                    Ok(_) => {
+                        streams_established += 1;
+                        info!("Stream {} established", streams_established);
+                    }
+                    Err(ProtonError::ProtocolViolation(violation))
+                        if context.mode == ProtocolMode::Lenient =>
+                    {
+                        info!(
+                            "Ignoring stream from {}: {}",
+                            connection.remote_address(),
+                            violation
+                        );
+                    }
+                    Err(ProtonError::ProtocolViolation(violation)) => {
+                        info!(
+                            "Closing connection from {}: {}",
+                            connection.remote_address(),
+                            violation
+                        );
+                        connection.close(
+                            CloseReason::ProtocolViolation.into(),
+                            &violation.close_reason(),
+                        );
+                        return Err(ProtonError::ProtocolViolation(violation));
+                    }
+                    Err(e) => {
+                        info!("Error handling stream: {}", e);
+                        connection.close(CloseReason::StreamSetup.into(), b"Stream setup error");
                         return Err(e);
                     }
-                    streams_established += 1;
-                    println!("Stream {} established", streams_established);
-                }
+                },
                 Ok(Err(e)) => {
-                    println!("Error accepting stream: {}", e);
-                    *conn_guard = None;
-                    connection.close(2u32.into(), b"Stream accept error");
+                    info!("Error accepting stream: {}", e);
+                    connection.close(CloseReason::StreamAccept.into(), b"Stream accept error");
                     return Err(ProtonError::ConnectionError);
                 }
                 Err(_) => {
-                    println!("Timeout waiting for stream establishment");
-                    *conn_guard = None;
-                    connection.close(3u32.into(), b"Stream setup timeout");
+                    info!("Timeout waiting for stream establishment");
+                    connection.close(CloseReason::SetupTimeout.into(), b"Stream setup timeout");
                     return Err(ProtonError::ConnectionError);
                 }
             }
         }
 
-        // Store the active connection
-        *conn_guard = Some(stream_handler);
-        let mut handler = conn_guard.take().unwrap();
-        // Drop the lock so we can acquire it again later
-        drop(conn_guard);
+        // Account usage to the tenant named in the HELLO, or the source address
+        let remote = connection.remote_address();
+        stream_handler.tenant = stream_handler
+            .peer
+            .as_ref()
+            .and_then(|p| p.tenant.clone())
+            .unwrap_or_else(|| remote.ip().to_string());
+        // Event ids are only unique per client, so only clients that say
+        // who they are share acks across connections
+        let client_id = stream_handler
+            .peer
+            .as_ref()
+            .and_then(|p| p.client_id.as_ref());
+        // Payloads are kept per client too. A client without an id is told
+        // apart by its address, which a reconnect from the same host keeps.
+        let ip = remote.ip().to_string();
+        stream_handler.payload_client = match client_id {
+            Some(client_id) => format!("{}/{}", stream_handler.tenant, client_id),
+            None if stream_handler.tenant == ip => ip,
+            None => format!("{}@{}", stream_handler.tenant, ip),
+        };
+        if let (Some(index), Some(client_id)) = (&context.dedupe, client_id) {
+            let client = format!("{}/{}", stream_handler.tenant, client_id);
+            stream_handler.order.persist(Arc::clone(index), client);
+        }
+        stream_handler.reorder = context.reorder.map(|config| {
+            spawn_reorderer(
+                config,
+                stream_handler.sink.clone(),
+                Arc::clone(&context.metrics),
+                stream_handler.tenant.clone(),
+            )
+        });
+
+        // Admit the client now that its HELLO priority and identity are known
+        let priority = stream_handler.peer.as_ref().map_or(0, |p| p.priority);
+        let identity = stream_handler
+            .peer
+            .as_ref()
+            .and_then(|p| p.client_id.clone())
+            .unwrap_or_else(|| stream_handler.tenant.clone());
+        let decision =
+            context
+                .admission
+                .lock()
+                .unwrap()
+                .admit(remote, &identity, priority, &connection);
+        if matches!(
+            decision,
+            AdmissionDecision::Duplicate | AdmissionDecision::Rejected
+        ) {
+            context
+                .metrics
+                .connections_rejected_admission
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            context
+                .metrics
+                .connections_accepted
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        let admission_id = match decision {
+            AdmissionDecision::Admitted(id) => id,
+            AdmissionDecision::Preempted {
+                id,
+                evicted,
+                evicted_addr,
+            } => {
+                info!(
+                    "Preempting {} for higher priority client {} (priority {})",
+                    evicted_addr, remote, priority
+                );
+                evicted.close(
+                    CloseReason::Preempted.into(),
+                    b"Preempted by higher priority client",
+                );
+                id
+            }
+            AdmissionDecision::Replaced {
+                id,
+                replaced,
+                replaced_addr,
+            } => {
+                info!(
+                    "Replacing connection from {} with a newer one from {} ({})",
+                    replaced_addr, identity, remote
+                );
+                replaced.close(
+                    CloseReason::Replaced.into(),
+                    b"Replaced by a newer connection from the same client",
+                );
+                id
+            }
+            AdmissionDecision::Duplicate => {
+                info!(
+                    "Rejecting connection from {}: {} is already connected",
+                    remote, identity
+                );
+                connection.close(CloseReason::Duplicate.into(), b"Client already connected");
+                return Err(ProtonError::ConnectionError);
+            }
+            AdmissionDecision::Rejected => {
+                info!("Rejecting connection from {}: server at capacity", remote);
+                connection.close(CloseReason::AtCapacity.into(), b"Server at capacity");
+                return Err(ProtonError::ConnectionError);
+            }
+        };
+
+        let registration = context.registry.register(
+            &connection,
+            stream_handler.peer.clone().unwrap_or_default(),
+            stream_handler.tenant.clone(),
+            SharedState {
+                streams: stream_handler.states.clone(),
+                heartbeat: Arc::clone(&stream_handler.heartbeat),
+                sent: stream_handler.sent.clone(),
+                parking: stream_handler.parking.clone(),
+            },
+        );
+        stream_handler.registered = Some(registration.handle().clone());
+        context.span.record("id", registration.handle().id());
+        let watch = spawn_named(
+            "connection watch",
+            watch_connection(
+                registration.handle().clone(),
+                Arc::clone(&context.metrics),
+                context.park_after,
+            ),
+        );
+        let experiment = stream_handler
+            .peer
+            .as_ref()
+            .and_then(|p| p.experiment.as_deref())
+            .map(|variant| context.metrics.experiment(variant));
+        if let Some(ref experiment) = experiment {
+            experiment.connections.fetch_add(1, Ordering::Relaxed);
+        }
 
         // Handle all streams in a single task
-        let stream_result = handler.handle_all_streams(&connection).await;
+        let stream_result = stream_handler.handle_all_streams(&connection).await;
+        if let (Some(experiment), Err(_)) = (experiment, &stream_result) {
+            experiment.errors.fetch_add(1, Ordering::Relaxed);
+        }
 
-        // Get the lock again to clear the connection state
-        let mut conn_guard = active_connection.lock().await;
-        *conn_guard = None;
-        drop(conn_guard);
-        println!("Connection state cleared");
+        watch.abort();
+        drop(registration);
+        context.admission.lock().unwrap().release(admission_id);
+        info!("Connection state cleared");
 
         // Handle the stream result and close the connection appropriately
-        match stream_result {
+        match &stream_result {
             Ok(_) => {
-                println!("Streams completed normally");
-                connection.close(0u32.into(), b"Streams completed");
+                info!("Streams completed normally");
+                connection.close(CloseReason::Normal.into(), b"Streams completed");
             }
             Err(ProtonError::Timeout) => {
-                eprintln!("Stream operation timed out");
-                connection.close(4u32.into(), b"Stream operation timeout");
+                warn!("Stream operation timed out");
+                context
+                    .metrics
+                    .stream_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                connection.close(
+                    CloseReason::StreamTimeout.into(),
+                    b"Stream operation timeout",
+                );
+            }
+            Err(ProtonError::ProtocolViolation(violation)) => {
+                warn!("Closing connection from {}: {}", remote, violation);
+                connection.close(
+                    CloseReason::ProtocolViolation.into(),
+                    &violation.close_reason(),
+                );
             }
             Err(e) => {
-                eprintln!("Stream error: {}", e);
-                connection.close(5u32.into(), b"Stream error");
+                error!("Stream error: {}", e);
+                connection.close(CloseReason::StreamError.into(), b"Stream error");
             }
         }
+        // Reported with the connection's outcome
+        if let Err(ProtonError::ProtocolViolation(_)) = stream_result {
+            return stream_result;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver(cursor: &Arc<std::sync::Mutex<ActionOffsets>>, count: u32) -> Vec<u32> {
+        let mut cursor = cursor.lock().unwrap();
+        (0..count).map(|_| cursor.deliver()).collect()
+    }
+
+    #[test]
+    fn clients_keep_their_own_action_cursor() {
+        let mut cursors = ActionCursors::default();
+        let a = cursors.cursor("acme/a");
+        assert_eq!(deliver(&a, 3), [1, 2, 3]);
+        a.lock().unwrap().ack_up_to(2);
+
+        // Another client connecting does not rewind the first one
+        let b = cursors.cursor("acme/b");
+        assert_eq!(deliver(&b, 1), [1]);
+        assert_eq!(deliver(&a, 1), [4]);
+
+        // Reconnecting redelivers what the client did not acknowledge
+        let a = cursors.cursor("acme/a");
+        assert_eq!(deliver(&a, 2), [3, 4]);
+        assert_eq!(
+            cursors.committed(),
+            [("acme/a".to_string(), 2), ("acme/b".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn acknowledgements_beyond_delivery_are_ignored() {
+        let mut cursors = ActionCursors::default();
+        let a = cursors.cursor("acme/a");
+        deliver(&a, 2);
+        a.lock().unwrap().ack_up_to(5);
+        a.lock().unwrap().ack_up_to(1);
+        a.lock().unwrap().ack_up_to(0);
+        assert_eq!(a.lock().unwrap().committed, 1);
+    }
+
+    #[test]
+    fn restored_offsets_only_move_forward() {
+        let mut cursors = ActionCursors::default();
+        cursors.restore("acme/a", 7);
+        cursors.restore("acme/a", 3);
+        let a = cursors.cursor("acme/a");
+        assert_eq!(deliver(&a, 1), [8]);
+    }
+
+    #[test]
+    fn anonymous_cursor_resumes_from_the_reported_offset() {
+        let cursor = anonymous_cursor();
+        cursor.lock().unwrap().ack_up_to(5);
+        assert_eq!(deliver(&cursor, 1), [6]);
+        // Only the first report is taken on trust
+        cursor.lock().unwrap().ack_up_to(9);
+        assert_eq!(cursor.lock().unwrap().committed, 5);
+    }
+}
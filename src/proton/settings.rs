@@ -0,0 +1,168 @@
+use crate::proton::frame::Headers;
+use crate::proton::wire::{
+    KEY_ACK_MODE, KEY_EVENT_BATCH_SIZE, KEY_EVENT_RATE_LIMIT, KEY_POWER_MODE,
+};
+use crate::proton::ProtonError;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the client acknowledges actions it has read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+    /// The application calls `ProtonConnection::ack_up_to`
+    Manual,
+    /// Every action is acknowledged as soon as it is read
+    Auto,
+}
+
+impl FromStr for AckMode {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(AckMode::Manual),
+            "auto" => Ok(AckMode::Auto),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown ack mode '{}', expected manual or auto", s),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for AckMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckMode::Manual => write!(f, "manual"),
+            AckMode::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// How hard the client tries to save power, e.g. on a battery powered device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerMode {
+    Normal,
+    /// Events wait for wakeups aligned to a fixed interval and go out
+    /// together, keepalives are stretched, and new connections ask for a
+    /// longer idle timeout. See `proton::power`.
+    Low,
+}
+
+impl FromStr for PowerMode {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(PowerMode::Normal),
+            "low" => Ok(PowerMode::Low),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown power mode '{}', expected normal or low", s),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PowerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerMode::Normal => write!(f, "normal"),
+            PowerMode::Low => write!(f, "low"),
+        }
+    }
+}
+
+/// Client tuning knobs. Unset fields fall back to the next source: settings
+/// set locally on the client win over those pushed by the server, which win
+/// over the defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClientSettings {
+    /// Events the durable outbox sends per batch, persisting its cursor after
+    /// each. Unset, the batch adapts to the connection's RTT and congestion
+    /// window.
+    pub event_batch_size: Option<u32>,
+    /// Maximum events per second sent by the client
+    pub event_rate_limit: Option<f64>,
+    pub ack_mode: Option<AckMode>,
+    pub power_mode: Option<PowerMode>,
+}
+
+impl ClientSettings {
+    /// Fields of `self`, with unset ones taken from `fallback`.
+    pub fn or(&self, fallback: &ClientSettings) -> ClientSettings {
+        ClientSettings {
+            event_batch_size: self.event_batch_size.or(fallback.event_batch_size),
+            event_rate_limit: self.event_rate_limit.or(fallback.event_rate_limit),
+            ack_mode: self.ack_mode.or(fallback.ack_mode),
+            power_mode: self.power_mode.or(fallback.power_mode),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ClientSettings::default()
+    }
+
+    /// Minimum spacing between events, if rate limited.
+    pub fn event_interval(&self) -> Option<Duration> {
+        self.event_rate_limit
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate))
+    }
+
+    pub fn ack_mode(&self) -> AckMode {
+        self.ack_mode.unwrap_or(AckMode::Manual)
+    }
+
+    pub fn power_mode(&self) -> PowerMode {
+        self.power_mode.unwrap_or(PowerMode::Normal)
+    }
+
+    pub fn to_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(size) = self.event_batch_size {
+            let _ = headers.insert(KEY_EVENT_BATCH_SIZE, &size.to_string());
+        }
+        if let Some(rate) = self.event_rate_limit {
+            let _ = headers.insert(KEY_EVENT_RATE_LIMIT, &rate.to_string());
+        }
+        if let Some(mode) = self.ack_mode {
+            let _ = headers.insert(KEY_ACK_MODE, &mode.to_string());
+        }
+        if let Some(mode) = self.power_mode {
+            let _ = headers.insert(KEY_POWER_MODE, &mode.to_string());
+        }
+        headers
+    }
+
+    /// Unknown keys and unparsable values are ignored so servers can push
+    /// settings that older clients do not understand.
+    pub fn from_headers(headers: &Headers) -> Self {
+        ClientSettings {
+            event_batch_size: headers
+                .get(KEY_EVENT_BATCH_SIZE)
+                .and_then(|v| v.parse().ok()),
+            event_rate_limit: headers
+                .get(KEY_EVENT_RATE_LIMIT)
+                .and_then(|v| v.parse().ok()),
+            ack_mode: headers.get(KEY_ACK_MODE).and_then(|v| v.parse().ok()),
+            power_mode: headers.get(KEY_POWER_MODE).and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+impl fmt::Display for ClientSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.event_batch_size {
+            Some(size) => write!(f, "batch size {}", size)?,
+            None => write!(f, "adaptive batching")?,
+        }
+        match self.event_rate_limit {
+            Some(rate) => write!(f, ", rate limit {}/s", rate)?,
+            None => write!(f, ", no rate limit")?,
+        }
+        write!(f, ", ack mode {}", self.ack_mode())?;
+        write!(f, ", power mode {}", self.power_mode())
+    }
+}
@@ -0,0 +1,309 @@
+use crate::proton::frame::Headers;
+use crate::proton::json::json_string;
+use crate::proton::metrics::ServerMetrics;
+use crate::proton::profile::spawn_named;
+use crate::proton::ProtonError;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+// Accepted events waiting to be forwarded; more are dropped and counted
+const SINK_QUEUE: usize = 4096;
+// Time allowed to forward one event to one sink
+#[cfg(any(feature = "webhook-sink", feature = "nats-sink"))]
+const SINK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// An event the server has accepted and acknowledged, as handed to sinks.
+#[derive(Debug, Clone)]
+pub struct SinkEvent {
+    pub event_id: u32,
+    pub tenant: String,
+    pub peer: SocketAddr,
+    pub headers: Headers,
+    /// Bytes the event carried, always empty unless the client uses
+    /// length-prefixed or typed framing
+    pub payload: Vec<u8>,
+    pub received_at: SystemTime,
+}
+
+impl SinkEvent {
+    /// One line JSON object, as published by the built-in sinks.
+    pub fn to_json(&self) -> String {
+        let received_at = self
+            .received_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut out = format!(
+            "{{\"event_id\":{},\"tenant\":{},\"peer\":{},\"received_at_ms\":{},\"headers\":{{",
+            self.event_id,
+            json_string(&self.tenant),
+            json_string(&self.peer.to_string()),
+            received_at
+        );
+        for (i, (key, value)) in self.headers.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}:{}", json_string(key), json_string(value));
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<(), ProtonError>> + Send + 'a>>;
+
+/// Forwards accepted events to an external system. Events are forwarded
+/// from a background task after they have been acknowledged, in the order
+/// they were accepted, so a slow sink never holds up clients.
+pub trait EventSink: Send + Sync {
+    /// Short name used in logs, e.g. `webhook`.
+    fn name(&self) -> &str;
+
+    fn forward<'a>(&'a self, event: &'a SinkEvent) -> SinkFuture<'a>;
+}
+
+// Feeds accepted events to the forwarding task
+#[derive(Clone)]
+pub(crate) struct SinkQueue {
+    tx: mpsc::Sender<SinkEvent>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl SinkQueue {
+    // Never waits: when the sinks fall behind, the event is dropped
+    pub(crate) fn push(&self, event: SinkEvent) {
+        if let Err(e) = self.tx.try_send(event) {
+            self.metrics
+                .sink_events_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Event sink queue full, dropping event {}",
+                e.into_inner().event_id
+            );
+        }
+    }
+}
+
+/// Starts the task forwarding events to `sinks` and returns the queue
+/// feeding it. A failed forward is logged and counted, and the event is not
+/// retried.
+pub(crate) fn spawn_forwarder(
+    sinks: Vec<Arc<dyn EventSink>>,
+    metrics: Arc<ServerMetrics>,
+) -> SinkQueue {
+    let (tx, mut rx) = mpsc::channel::<SinkEvent>(SINK_QUEUE);
+    let queue = SinkQueue {
+        tx,
+        metrics: Arc::clone(&metrics),
+    };
+    spawn_named("event sinks", async move {
+        while let Some(event) = rx.recv().await {
+            for sink in &sinks {
+                match sink.forward(&event).await {
+                    Ok(()) => {
+                        metrics.events_forwarded.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        metrics.sink_errors.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            "Failed to forward event {} to {}: {}",
+                            event.event_id,
+                            sink.name(),
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    });
+    queue
+}
+
+#[cfg(feature = "webhook-sink")]
+pub use webhook::WebhookSink;
+
+#[cfg(feature = "webhook-sink")]
+mod webhook {
+    use super::{EventSink, SinkEvent, SinkFuture, SINK_TIMEOUT};
+    use crate::proton::ProtonError;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    /// POSTs each event as JSON to a plain `http://` URL, one request per
+    /// event. Any 2xx response counts as delivered.
+    pub struct WebhookSink {
+        host: String,
+        path: String,
+    }
+
+    impl WebhookSink {
+        pub fn new(url: &str) -> Result<Self, ProtonError> {
+            let invalid = || {
+                ProtonError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("webhook URL must be http://host:port/path, got {}", url),
+                ))
+            };
+            let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+            let (host, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, "/"),
+            };
+            if host.is_empty() {
+                return Err(invalid());
+            }
+            let host = if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:80", host)
+            };
+            Ok(Self {
+                host,
+                path: path.to_string(),
+            })
+        }
+
+        async fn post(&self, body: String) -> Result<(), ProtonError> {
+            let mut stream = TcpStream::connect(&self.host).await?;
+            let request = format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                self.path,
+                self.host,
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            // HTTP/1.1 2xx ...
+            match response.get(9) {
+                Some(b'2') => Ok(()),
+                _ => {
+                    let status = String::from_utf8_lossy(&response);
+                    let status = status.lines().next().unwrap_or("no response");
+                    Err(ProtonError::IoError(std::io::Error::other(format!(
+                        "webhook answered {}",
+                        status
+                    ))))
+                }
+            }
+        }
+    }
+
+    impl EventSink for WebhookSink {
+        fn name(&self) -> &str {
+            "webhook"
+        }
+
+        fn forward<'a>(&'a self, event: &'a SinkEvent) -> SinkFuture<'a> {
+            Box::pin(async move { timeout(SINK_TIMEOUT, self.post(event.to_json())).await? })
+        }
+    }
+}
+
+#[cfg(feature = "nats-sink")]
+pub use nats::NatsSink;
+
+#[cfg(feature = "nats-sink")]
+mod nats {
+    use super::{EventSink, SinkEvent, SinkFuture, SINK_TIMEOUT};
+    use crate::proton::ProtonError;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    /// Publishes each event as JSON to a NATS subject over the plain text
+    /// protocol, on one connection that is reopened after a failure. Core
+    /// NATS publishes are fire and forget, so delivery is at most once.
+    pub struct NatsSink {
+        addr: String,
+        subject: String,
+        connection: Mutex<Option<TcpStream>>,
+    }
+
+    impl NatsSink {
+        pub fn new(addr: &str, subject: &str) -> Self {
+            Self {
+                addr: addr.to_string(),
+                subject: subject.to_string(),
+                connection: Mutex::new(None),
+            }
+        }
+
+        async fn connect(&self) -> Result<TcpStream, ProtonError> {
+            let mut stream = BufReader::new(TcpStream::connect(&self.addr).await?);
+            // The server greets with INFO before accepting CONNECT
+            let mut info = String::new();
+            stream.read_line(&mut info).await?;
+            if !info.starts_with("INFO") {
+                return Err(ProtonError::ConnectionError);
+            }
+            let mut stream = stream.into_inner();
+            stream
+                .write_all(
+                    b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"proton\"}\r\n",
+                )
+                .await?;
+            tracing::info!("Forwarding events to NATS at {}", self.addr);
+            Ok(stream)
+        }
+
+        // Answer the server's keepalive PINGs that arrived since the last
+        // publish. Returns false once the server has closed the connection.
+        async fn answer_pings(stream: &mut TcpStream) -> Result<bool, ProtonError> {
+            let mut buf = [0u8; 512];
+            loop {
+                match stream.try_read(&mut buf) {
+                    Ok(0) => return Ok(false),
+                    Ok(n) => {
+                        if buf[..n].windows(4).any(|w| w == b"PING") {
+                            stream.write_all(b"PONG\r\n").await?;
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(true),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        async fn publish(&self, payload: String) -> Result<(), ProtonError> {
+            let mut connection = self.connection.lock().await;
+            if let Some(stream) = connection.as_mut() {
+                if !matches!(Self::answer_pings(stream).await, Ok(true)) {
+                    *connection = None;
+                }
+            }
+            let stream = match connection.as_mut() {
+                Some(stream) => stream,
+                None => connection.insert(self.connect().await?),
+            };
+            let message = format!("PUB {} {}\r\n{}\r\n", self.subject, payload.len(), payload);
+            if let Err(e) = stream.write_all(message.as_bytes()).await {
+                // Reconnect on the next event
+                *connection = None;
+                return Err(e.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl EventSink for NatsSink {
+        fn name(&self) -> &str {
+            "nats"
+        }
+
+        fn forward<'a>(&'a self, event: &'a SinkEvent) -> SinkFuture<'a> {
+            Box::pin(async move { timeout(SINK_TIMEOUT, self.publish(event.to_json())).await? })
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::proton::journal::write_atomic;
+use crate::proton::payload::PayloadTransfers;
+use crate::proton::quota::UsageLedger;
+use crate::proton::server::ActionCursors;
+use crate::proton::ProtonError;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// Layout of the snapshot file. Snapshots from newer versions are refused.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+const KEY_VERSION: &str = "version";
+const KEY_ACTION_OFFSET: &str = "action_offset";
+const KEY_USAGE: &str = "usage";
+const KEY_TRANSFER: &str = "transfer";
+
+/// Byte usage of one tenant, with the quota windows it falls in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageRecord {
+    pub tenant: String,
+    pub received: u64,
+    pub sent: u64,
+    /// Day since the Unix epoch that `day_bytes` counts
+    pub day: i64,
+    pub day_bytes: u64,
+    /// Month since the Unix epoch that `month_bytes` counts
+    pub month: i64,
+    pub month_bytes: u64,
+}
+
+/// Highest action one client acknowledged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActionOffsetRecord {
+    /// The client, as "tenant/client id"
+    pub client: String,
+    pub committed: u32,
+}
+
+/// A payload transfer that was interrupted and may be resumed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferRecord {
+    /// Key of the client sending the payload, as passed to the
+    /// `PayloadHandler`
+    pub client: String,
+    pub event_id: u32,
+    pub len: u64,
+    /// Chunks received and verified so far
+    pub chunks: u32,
+}
+
+/// The server's protocol state, portable to another instance for a
+/// migration or to seed a cold standby.
+///
+/// The server keeps no log of accepted events, so none is included. The
+/// bytes of interrupted payloads live with the `PayloadHandler`, e.g. in a
+/// `FileSink` directory, and must be copied with the snapshot for their
+/// transfers to resume.
+///
+/// Written as `key=value` lines, one per tenant or transfer where there are
+/// several; unknown keys are ignored so newer servers can add state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerSnapshot {
+    /// Highest action id each named client acknowledged
+    pub action_offsets: Vec<ActionOffsetRecord>,
+    pub usage: Vec<UsageRecord>,
+    pub transfers: Vec<TransferRecord>,
+}
+
+impl ServerSnapshot {
+    pub fn load(path: &Path) -> Result<Self, ProtonError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Replaces the file at `path` atomically, synced to disk.
+    pub fn save(&self, path: &Path) -> Result<(), ProtonError> {
+        write_atomic(path, self.to_string().as_bytes())
+    }
+}
+
+impl fmt::Display for ServerSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Proton server snapshot")?;
+        writeln!(f, "{}={}", KEY_VERSION, SNAPSHOT_VERSION)?;
+        // The client and tenant go last, so they may contain commas
+        for a in &self.action_offsets {
+            writeln!(f, "{}={},{}", KEY_ACTION_OFFSET, a.committed, a.client)?;
+        }
+        for u in &self.usage {
+            writeln!(
+                f,
+                "{}={},{},{},{},{},{},{}",
+                KEY_USAGE, u.received, u.sent, u.day, u.day_bytes, u.month, u.month_bytes, u.tenant
+            )?;
+        }
+        for t in &self.transfers {
+            writeln!(
+                f,
+                "{}={},{},{},{}",
+                KEY_TRANSFER, t.event_id, t.len, t.chunks, t.client
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ServerSnapshot {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |line: &str| {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid snapshot line '{}'", line),
+            ))
+        };
+        let mut snapshot = ServerSnapshot::default();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid(line))?;
+            match key {
+                KEY_VERSION => {
+                    let version: u32 = value.parse().map_err(|_| invalid(line))?;
+                    if version > SNAPSHOT_VERSION {
+                        return Err(ProtonError::IoError(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "snapshot version {} is newer than this server's {}",
+                                version, SNAPSHOT_VERSION
+                            ),
+                        )));
+                    }
+                }
+                KEY_ACTION_OFFSET => {
+                    // Version 1 kept a single offset for all clients, which
+                    // no client can take over
+                    if let Some((committed, client)) = value.split_once(',') {
+                        snapshot.action_offsets.push(ActionOffsetRecord {
+                            client: client.to_string(),
+                            committed: committed.parse().map_err(|_| invalid(line))?,
+                        });
+                    }
+                }
+                KEY_USAGE => {
+                    let fields: Vec<&str> = value.splitn(7, ',').collect();
+                    let [received, sent, day, day_bytes, month, month_bytes, tenant] = fields[..]
+                    else {
+                        return Err(invalid(line));
+                    };
+                    let parse = || -> Result<UsageRecord, std::num::ParseIntError> {
+                        Ok(UsageRecord {
+                            tenant: tenant.to_string(),
+                            received: received.parse()?,
+                            sent: sent.parse()?,
+                            day: day.parse()?,
+                            day_bytes: day_bytes.parse()?,
+                            month: month.parse()?,
+                            month_bytes: month_bytes.parse()?,
+                        })
+                    };
+                    snapshot.usage.push(parse().map_err(|_| invalid(line))?);
+                }
+                KEY_TRANSFER => {
+                    let fields: Vec<&str> = value.splitn(4, ',').collect();
+                    let [event_id, len, chunks, client] = fields[..] else {
+                        return Err(invalid(line));
+                    };
+                    let parse = || -> Result<TransferRecord, std::num::ParseIntError> {
+                        Ok(TransferRecord {
+                            client: client.to_string(),
+                            event_id: event_id.parse()?,
+                            len: len.parse()?,
+                            chunks: chunks.parse()?,
+                        })
+                    };
+                    snapshot.transfers.push(parse().map_err(|_| invalid(line))?);
+                }
+                _ => {}
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Takes and restores snapshots of a running server's state.
+#[derive(Clone)]
+pub struct SnapshotHandle {
+    pub(crate) actions: Arc<Mutex<ActionCursors>>,
+    pub(crate) usage: Arc<UsageLedger>,
+    pub(crate) transfers: Arc<PayloadTransfers>,
+}
+
+impl SnapshotHandle {
+    pub fn export(&self) -> ServerSnapshot {
+        ServerSnapshot {
+            action_offsets: self
+                .actions
+                .lock()
+                .unwrap()
+                .committed()
+                .into_iter()
+                .map(|(client, committed)| ActionOffsetRecord { client, committed })
+                .collect(),
+            usage: self.usage.export(),
+            transfers: self.transfers.export(),
+        }
+    }
+
+    /// Merges `snapshot` into the server's state. Each client's acknowledged
+    /// actions only move forward, usage replaces that of the same tenant and
+    /// transfers those of the same client and event, so importing a
+    /// snapshot twice changes nothing.
+    pub fn import(&self, snapshot: &ServerSnapshot) {
+        let mut actions = self.actions.lock().unwrap();
+        for a in &snapshot.action_offsets {
+            actions.restore(&a.client, a.committed);
+        }
+        drop(actions);
+        self.usage.import(&snapshot.usage);
+        self.transfers.import(&snapshot.transfers);
+        info!(
+            "Imported snapshot: action offsets of {} clients, {} tenants, {} transfers",
+            snapshot.action_offsets.len(),
+            snapshot.usage.len(),
+            snapshot.transfers.len()
+        );
+    }
+}
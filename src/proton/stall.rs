@@ -0,0 +1,162 @@
+//! Half-open connections. A client that vanished without closing, or that
+//! stopped reading its streams, leaves the server's responses blocked on
+//! flow control; QUIC keeps such a connection open until its idle timeout,
+//! or for as long as the client's stack still acknowledges packets. A write
+//! blocked for longer than the stall timeout is counted and handled as the
+//! stream type's `StallPolicy` says.
+
+use crate::proton::coalesce::FrameWriter;
+use crate::proton::efficiency::SentFrames;
+use crate::proton::fsm::{StreamState, StreamStates};
+use crate::proton::metrics::StreamTypeMetrics;
+use crate::proton::wire::CLOSE_PEER_STALLED;
+use crate::proton::{CloseReason, ProtonError, STREAM_TIMEOUT};
+use quinn::{Connection as QuinnConnection, RecvStream, VarInt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::pin::pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// What the server does about a stream whose client stopped reading it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StallPolicy {
+    /// Log and keep waiting, until the stream timeout
+    #[default]
+    Log,
+    /// Reset the stream with `CLOSE_PEER_STALLED`; the connection carries on
+    /// without it
+    Reset,
+    /// Close the connection with `CLOSE_PEER_STALLED`
+    Close,
+}
+
+impl FromStr for StallPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(StallPolicy::Log),
+            "reset" => Ok(StallPolicy::Reset),
+            "close" => Ok(StallPolicy::Close),
+            _ => Err(format!(
+                "invalid stall policy '{}', expected log, reset or close",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StallPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StallPolicy::Log => write!(f, "log"),
+            StallPolicy::Reset => write!(f, "reset"),
+            StallPolicy::Close => write!(f, "close"),
+        }
+    }
+}
+
+/// When a blocked write counts as a stall, and the policy for each stream
+/// type, by name, e.g. `state commit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StallConfig {
+    pub after: Duration,
+    /// Policy for stream types not in `streams`
+    pub default: StallPolicy,
+    pub streams: BTreeMap<String, StallPolicy>,
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            after: Duration::from_secs(30),
+            default: StallPolicy::default(),
+            streams: BTreeMap::new(),
+        }
+    }
+}
+
+impl StallConfig {
+    pub fn policy(&self, stream: &str) -> StallPolicy {
+        self.streams.get(stream).copied().unwrap_or(self.default)
+    }
+}
+
+// Everything a stream's writes need to handle a stall
+pub(crate) struct StallWatch<'a> {
+    pub(crate) config: &'a StallConfig,
+    pub(crate) connection: &'a QuinnConnection,
+    pub(crate) states: &'a StreamStates,
+    pub(crate) sent: &'a SentFrames,
+}
+
+impl StallWatch<'_> {
+    /// Writes `frame` to the `stream` stream, flushing it if `flush`, and
+    /// fails with `ProtonError::Timeout` after `STREAM_TIMEOUT` like any
+    /// write. A stream reset for stalling never returns, since the
+    /// connection carries on without it; `recv` is the receiving half
+    /// stopped along with it, if the stream is bidirectional.
+    pub(crate) async fn write(
+        &self,
+        stream: &str,
+        send: &mut FrameWriter,
+        recv: Option<&mut RecvStream>,
+        frame: &[u8],
+        flush: bool,
+        metrics: &StreamTypeMetrics,
+    ) -> Result<(), ProtonError> {
+        let policy = {
+            let mut write = pin!(async {
+                send.write(frame).await?;
+                self.sent.record(1, frame.len());
+                if flush {
+                    send.flush().await?;
+                }
+                Ok(())
+            });
+            if let Ok(written) = timeout(self.config.after, &mut write).await {
+                return written;
+            }
+            metrics.stalls.fetch_add(1, Ordering::Relaxed);
+            let policy = self.config.policy(stream);
+            warn!(
+                "Client {} has not read its {} stream for {:?}: {}",
+                self.connection.remote_address(),
+                stream,
+                self.config.after,
+                match policy {
+                    StallPolicy::Log => "still waiting",
+                    StallPolicy::Reset => "resetting the stream",
+                    StallPolicy::Close => "closing the connection",
+                }
+            );
+            if policy == StallPolicy::Log {
+                let rest = STREAM_TIMEOUT.saturating_sub(self.config.after);
+                return timeout(rest, write)
+                    .await
+                    .unwrap_or(Err(ProtonError::Timeout));
+            }
+            policy
+        };
+
+        let id = send.id();
+        self.states.enter(
+            id,
+            StreamState::Errored("client stopped reading".to_string()),
+        );
+        if policy == StallPolicy::Close {
+            self.connection
+                .close(CloseReason::PeerStalled.into(), b"client stopped reading");
+            return Err(ProtonError::ConnectionError);
+        }
+        send.reset(CLOSE_PEER_STALLED).await;
+        if let Some(recv) = recv {
+            let _ = recv.stop(VarInt::from_u32(CLOSE_PEER_STALLED));
+        }
+        std::future::pending().await
+    }
+}
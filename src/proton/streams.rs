@@ -0,0 +1,314 @@
+use crate::proton::frame::Headers;
+use crate::proton::metrics::StreamTypeMetrics;
+use crate::proton::wire::{
+    decode_u32, encode_u32, FLAG_HEADERS, STREAM_ACTION, STREAM_AUTH, STREAM_CONTROL, STREAM_EVENT,
+    STREAM_PAYLOAD, STREAM_STATE_COMMIT,
+};
+use crate::proton::{ProtonError, STREAM_TIMEOUT};
+use quinn::{RecvStream, SendStream};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::timeout;
+
+// Largest read handed to a byte stream handler at once
+const MAX_READ: usize = 64 * 1024;
+
+/// What a stream type exchanges, which decides the channel a client gets
+/// when it opens one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// u32 requests each answered by a u32 response, like the event, state
+    /// commit and action streams. Requests carry headers when the client
+    /// sends them.
+    Request,
+    /// Opaque bytes in both directions.
+    Bytes,
+}
+
+impl FromStr for ChannelKind {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "request" => Ok(ChannelKind::Request),
+            "bytes" => Ok(ChannelKind::Bytes),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown channel kind '{}', expected request or bytes", s),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ChannelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelKind::Request => write!(f, "request"),
+            ChannelKind::Bytes => write!(f, "bytes"),
+        }
+    }
+}
+
+/// A kind of stream: the discriminator byte it opens with, a name for logs
+/// and lookups, and what it carries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamType {
+    pub discriminator: u8,
+    pub name: String,
+    pub kind: ChannelKind,
+}
+
+impl StreamType {
+    pub fn new(discriminator: u8, name: &str, kind: ChannelKind) -> Self {
+        Self {
+            discriminator,
+            name: name.to_string(),
+            kind,
+        }
+    }
+}
+
+/// Parses `name:discriminator:kind`, e.g. `echo:16:bytes`.
+impl FromStr for StreamType {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid stream type '{}', expected name:discriminator:kind",
+                    s
+                ),
+            ))
+        };
+        let mut parts = s.split(':');
+        let (Some(name), Some(discriminator), Some(kind), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let discriminator = discriminator.parse().map_err(|_| invalid())?;
+        Ok(StreamType::new(discriminator, name, kind.parse()?))
+    }
+}
+
+impl fmt::Display for StreamType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.discriminator, self.kind)
+    }
+}
+
+/// Serves one stream of a registered type on the server. A new handler is
+/// made for each stream the client opens.
+pub trait StreamHandler: Send {
+    /// Answers a request on a [`ChannelKind::Request`] stream.
+    fn on_request(&mut self, _request: u32, _headers: &Headers) -> Result<u32, ProtonError> {
+        Err(ProtonError::InvalidStream)
+    }
+
+    /// Bytes read from a [`ChannelKind::Bytes`] stream. Whatever is returned
+    /// is written back to the client.
+    fn on_bytes(&mut self, _data: &[u8]) -> Result<Vec<u8>, ProtonError> {
+        Err(ProtonError::InvalidStream)
+    }
+
+    /// The client finished the stream.
+    fn on_finish(&mut self) {}
+}
+
+/// Makes a [`StreamHandler`] for each stream of a registered type, given the
+/// tenant that opened it.
+pub trait StreamHandlerFactory: Send + Sync {
+    fn create(&self, tenant: &str) -> Box<dyn StreamHandler>;
+}
+
+impl<F> StreamHandlerFactory for F
+where
+    F: Fn(&str) -> Box<dyn StreamHandler> + Send + Sync,
+{
+    fn create(&self, tenant: &str) -> Box<dyn StreamHandler> {
+        self(tenant)
+    }
+}
+
+/// Answers every request with the request itself and every read with the
+/// bytes read.
+#[derive(Debug, Default)]
+pub struct EchoStream;
+
+impl StreamHandler for EchoStream {
+    fn on_request(&mut self, request: u32, _headers: &Headers) -> Result<u32, ProtonError> {
+        Ok(request)
+    }
+
+    fn on_bytes(&mut self, data: &[u8]) -> Result<Vec<u8>, ProtonError> {
+        Ok(data.to_vec())
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    stream_type: StreamType,
+    // None for the built-in streams, which the crate serves itself
+    factory: Option<Arc<dyn StreamHandlerFactory>>,
+}
+
+/// The stream types a server or client speaks: the built-in ones, plus any
+/// the application registers while building it.
+#[derive(Clone)]
+pub struct StreamRegistry {
+    entries: Vec<Entry>,
+}
+
+impl Default for StreamRegistry {
+    fn default() -> Self {
+        let builtin = [
+            (STREAM_EVENT, "event", ChannelKind::Request),
+            (STREAM_STATE_COMMIT, "state commit", ChannelKind::Request),
+            (STREAM_ACTION, "action", ChannelKind::Request),
+            (STREAM_AUTH, "auth", ChannelKind::Bytes),
+            (STREAM_CONTROL, "control", ChannelKind::Bytes),
+            (STREAM_PAYLOAD, "payload", ChannelKind::Bytes),
+        ];
+        Self {
+            entries: builtin
+                .into_iter()
+                .map(|(discriminator, name, kind)| Entry {
+                    stream_type: StreamType::new(discriminator, name, kind),
+                    factory: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for StreamRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|e| &e.stream_type))
+            .finish()
+    }
+}
+
+impl StreamRegistry {
+    /// Adds `stream_type`, served on the server by handlers from `factory`.
+    /// Clients register their types without one.
+    pub fn register(
+        &mut self,
+        stream_type: StreamType,
+        factory: Option<Arc<dyn StreamHandlerFactory>>,
+    ) -> Result<(), ProtonError> {
+        let conflict = self.entries.iter().find(|e| {
+            e.stream_type.discriminator == stream_type.discriminator
+                || e.stream_type.name == stream_type.name
+        });
+        let problem =
+            if stream_type.discriminator == 0 || stream_type.discriminator & FLAG_HEADERS != 0 {
+                Some("discriminator must be between 1 and 127".to_string())
+            } else if stream_type.name.is_empty() {
+                Some("name must not be empty".to_string())
+            } else {
+                conflict.map(|e| format!("conflicts with stream type {}", e.stream_type))
+            };
+        if let Some(problem) = problem {
+            return Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot register stream type {}: {}", stream_type, problem),
+            )));
+        }
+        self.entries.push(Entry {
+            stream_type,
+            factory,
+        });
+        Ok(())
+    }
+
+    pub fn get(&self, discriminator: u8) -> Option<&StreamType> {
+        self.entries
+            .iter()
+            .map(|e| &e.stream_type)
+            .find(|t| t.discriminator == discriminator)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&StreamType> {
+        self.entries
+            .iter()
+            .map(|e| &e.stream_type)
+            .find(|t| t.name == name)
+    }
+
+    /// Types registered by the application, in registration order.
+    pub fn registered(&self) -> impl Iterator<Item = &StreamType> {
+        self.entries
+            .iter()
+            .filter(|e| !is_builtin(e.stream_type.discriminator))
+            .map(|e| &e.stream_type)
+    }
+
+    // How to serve a registered stream, if the server knows the type
+    pub(crate) fn handler(
+        &self,
+        discriminator: u8,
+    ) -> Option<(StreamType, Arc<dyn StreamHandlerFactory>)> {
+        self.entries
+            .iter()
+            .find(|e| e.stream_type.discriminator == discriminator)
+            .and_then(|e| Some((e.stream_type.clone(), Arc::clone(e.factory.as_ref()?))))
+    }
+}
+
+fn is_builtin(discriminator: u8) -> bool {
+    (STREAM_EVENT..=STREAM_PAYLOAD).contains(&discriminator)
+}
+
+// Serve one registered stream until the client finishes it
+pub(crate) async fn serve_stream(
+    kind: ChannelKind,
+    mut handler: Box<dyn StreamHandler>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    with_headers: bool,
+    metrics: &StreamTypeMetrics,
+) -> Result<(), ProtonError> {
+    match kind {
+        ChannelKind::Request => {
+            let mut request = [0u8; 4];
+            loop {
+                match recv.read_exact(&mut request).await {
+                    Ok(()) => {}
+                    Err(quinn::ReadExactError::FinishedEarly) => break,
+                    Err(e) => return Err(e.into()),
+                }
+                let started = Instant::now();
+                let headers = if with_headers {
+                    Headers::read_from(&mut recv).await?
+                } else {
+                    Headers::new()
+                };
+                let response = handler.on_request(decode_u32(request), &headers)?;
+                timeout(STREAM_TIMEOUT, send.write_all(&encode_u32(response))).await??;
+                metrics.observe(started);
+            }
+        }
+        ChannelKind::Bytes => {
+            while let Some(chunk) = recv
+                .read_chunk(MAX_READ, true)
+                .await
+                .map_err(|_| ProtonError::ConnectionError)?
+            {
+                let started = Instant::now();
+                let reply = handler.on_bytes(&chunk.bytes)?;
+                if !reply.is_empty() {
+                    timeout(STREAM_TIMEOUT, send.write_all(&reply)).await??;
+                }
+                metrics.observe(started);
+            }
+        }
+    }
+    handler.on_finish();
+    send.finish().await?;
+    Ok(())
+}
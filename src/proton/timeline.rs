@@ -0,0 +1,234 @@
+use crate::proton::frame::{describe_frame, Direction, FrameInspector};
+use crate::proton::json::json_string;
+use crate::proton::{STREAM_ACTION, STREAM_EVENT, STREAM_STATE_COMMIT};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// File format a [`Timeline`] is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelineFormat {
+    /// Chrome trace-event JSON, for chrome://tracing or Perfetto
+    ChromeTrace,
+    /// Mermaid sequence diagram
+    Mermaid,
+}
+
+impl TimelineFormat {
+    /// Mermaid for `.mmd` and `.mermaid` files, Chrome trace JSON otherwise.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("mmd" | "mermaid") => TimelineFormat::Mermaid,
+            _ => TimelineFormat::ChromeTrace,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Entry {
+    Frame {
+        at: Duration,
+        direction: Direction,
+        stream: u8,
+        len: usize,
+        description: String,
+    },
+    Command {
+        at: Duration,
+        duration: Duration,
+        command: String,
+    },
+}
+
+impl Entry {
+    fn at(&self) -> Duration {
+        match self {
+            Entry::Frame { at, .. } | Entry::Command { at, .. } => *at,
+        }
+    }
+}
+
+/// Records every frame on a connection, and optionally the commands that
+/// caused them, with their timings so an interaction can be visualized and
+/// attached to a bug report.
+#[derive(Debug)]
+pub struct Timeline {
+    started: Instant,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeline {
+    /// Starts an empty timeline; times are relative to now.
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Records a command that ran from `started` until now.
+    pub fn record_command(&self, command: &str, started: Instant) {
+        self.entries.lock().unwrap().push(Entry::Command {
+            at: started.saturating_duration_since(self.started),
+            duration: started.elapsed(),
+            command: command.to_string(),
+        });
+    }
+
+    // Commands are recorded once they finish, after their own frames
+    fn sorted_entries(&self) -> std::sync::MutexGuard<'_, Vec<Entry>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.sort_by_key(Entry::at);
+        entries
+    }
+
+    pub fn write(&self, path: &Path, format: TimelineFormat) -> std::io::Result<()> {
+        let out = match format {
+            TimelineFormat::ChromeTrace => self.to_chrome_trace(),
+            TimelineFormat::Mermaid => self.to_mermaid(),
+        };
+        std::fs::write(path, out)
+    }
+
+    /// Chrome trace-event JSON. Each request is a span on its stream's track
+    /// lasting until its response arrived; commands are spans on a track of
+    /// their own.
+    pub fn to_chrome_trace(&self) -> String {
+        let entries = self.sorted_entries();
+        let mut events = Vec::new();
+        for (tid, name) in [
+            (0, "commands"),
+            (STREAM_EVENT, "event stream"),
+            (STREAM_STATE_COMMIT, "state commit stream"),
+            (STREAM_ACTION, "action stream"),
+        ] {
+            events.push(format!(
+                "{{\"ph\":\"M\",\"pid\":1,\"tid\":{},\"name\":\"thread_name\",\"args\":{{\"name\":{}}}}}",
+                tid,
+                json_string(name)
+            ));
+        }
+
+        // Responses arrive in request order on each stream
+        let mut pending: HashMap<u8, VecDeque<(Duration, usize, &str)>> = HashMap::new();
+        for entry in entries.iter() {
+            match entry {
+                Entry::Command {
+                    at,
+                    duration,
+                    command,
+                } => events.push(format!(
+                    "{{\"ph\":\"X\",\"pid\":1,\"tid\":0,\"cat\":\"command\",\"name\":{},\"ts\":{},\"dur\":{}}}",
+                    json_string(command),
+                    at.as_micros(),
+                    duration.as_micros()
+                )),
+                Entry::Frame {
+                    at,
+                    direction: Direction::Sent,
+                    stream,
+                    len,
+                    description,
+                } => pending
+                    .entry(*stream)
+                    .or_default()
+                    .push_back((*at, *len, description)),
+                Entry::Frame {
+                    at,
+                    direction: Direction::Received,
+                    stream,
+                    description,
+                    ..
+                } => match pending.get_mut(stream).and_then(|p| p.pop_front()) {
+                    Some((sent_at, len, request)) => events.push(format!(
+                        "{{\"ph\":\"X\",\"pid\":1,\"tid\":{},\"cat\":\"frame\",\"name\":{},\"ts\":{},\"dur\":{},\"args\":{{\"bytes\":{},\"response\":{}}}}}",
+                        stream,
+                        json_string(request),
+                        sent_at.as_micros(),
+                        at.saturating_sub(sent_at).as_micros(),
+                        len,
+                        json_string(description)
+                    )),
+                    None => events.push(instant(*stream, description, *at)),
+                },
+            }
+        }
+        // Requests that were never answered
+        for (stream, unanswered) in &pending {
+            for (at, _, request) in unanswered {
+                events.push(instant(*stream, &format!("{} (unanswered)", request), *at));
+            }
+        }
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"))
+    }
+
+    /// Mermaid sequence diagram with one arrow per frame.
+    pub fn to_mermaid(&self) -> String {
+        let entries = self.sorted_entries();
+        let mut out = String::from(
+            "sequenceDiagram\n    participant C as Client\n    participant S as Server\n",
+        );
+        for entry in entries.iter() {
+            // Semicolons end a statement in Mermaid
+            let _ = match entry {
+                Entry::Command { at, command, .. } => writeln!(
+                    out,
+                    "    Note over C: +{:.3}s {}",
+                    at.as_secs_f64(),
+                    command.replace(';', ",")
+                ),
+                Entry::Frame {
+                    at,
+                    direction,
+                    len,
+                    description,
+                    ..
+                } => {
+                    let arrow = match direction {
+                        Direction::Sent => "C->>S",
+                        Direction::Received => "S-->>C",
+                    };
+                    writeln!(
+                        out,
+                        "    {}: +{:.3}s {} ({} bytes)",
+                        arrow,
+                        at.as_secs_f64(),
+                        description.replace(';', ","),
+                        len
+                    )
+                }
+            };
+        }
+        out
+    }
+}
+
+fn instant(stream: u8, name: &str, at: Duration) -> String {
+    format!(
+        "{{\"ph\":\"i\",\"s\":\"t\",\"pid\":1,\"tid\":{},\"cat\":\"frame\",\"name\":{},\"ts\":{}}}",
+        stream,
+        json_string(name),
+        at.as_micros()
+    )
+}
+
+impl FrameInspector for Timeline {
+    fn on_frame(&self, direction: Direction, stream: u8, frame: &[u8]) {
+        let at = self.started.elapsed();
+        self.entries.lock().unwrap().push(Entry::Frame {
+            at,
+            direction,
+            stream,
+            len: frame.len(),
+            description: describe_frame(direction, stream, frame),
+        });
+    }
+}
@@ -0,0 +1,1404 @@
+use crate::proton::ProtonError;
+use rustls::server::{
+    AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier, UnparsedCertRevocationList,
+};
+use rustls::{
+    CertificateError, ConfigBuilder, DistinguishedName, RootCertStore, SignatureScheme,
+    SupportedCipherSuite, SupportedKxGroup, WantsVerifier, ALL_CIPHER_SUITES, ALL_KX_GROUPS,
+};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use webpki::CertRevocationList;
+
+// DER tags we need to walk a certificate
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_EXPLICIT_VERSION: u8 = 0xa0;
+
+/// Validity window of an X.509 certificate.
+#[derive(Debug, Clone, Copy)]
+pub struct CertificateValidity {
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+impl CertificateValidity {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() > self.not_after
+    }
+
+    pub fn is_not_yet_valid(&self) -> bool {
+        SystemTime::now() < self.not_before
+    }
+
+    /// Whole days until the certificate expires, negative once it has expired.
+    pub fn days_until_expiry(&self) -> i64 {
+        let now = SystemTime::now();
+        match self.not_after.duration_since(now) {
+            Ok(left) => (left.as_secs() / 86400) as i64,
+            Err(e) => -((e.duration().as_secs() / 86400) as i64) - 1,
+        }
+    }
+}
+
+fn invalid_cert(msg: &str) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid certificate: {}", msg),
+    ))
+}
+
+// Reads one DER TLV, returning (tag, contents, rest)
+fn read_tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8]), ProtonError> {
+    if input.len() < 2 {
+        return Err(invalid_cert("truncated element"));
+    }
+    let tag = input[0];
+    let (len, header) = match input[1] {
+        n if n < 0x80 => (n as usize, 2),
+        n => {
+            let count = (n & 0x7f) as usize;
+            if count == 0 || count > 4 || input.len() < 2 + count {
+                return Err(invalid_cert("bad length"));
+            }
+            let len = input[2..2 + count]
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (len, 2 + count)
+        }
+    };
+    if input.len() < header + len {
+        return Err(invalid_cert("truncated element"));
+    }
+    Ok((tag, &input[header..header + len], &input[header + len..]))
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse_time(tag: u8, value: &[u8]) -> Result<SystemTime, ProtonError> {
+    let mut text = value
+        .strip_suffix(b"Z")
+        .ok_or_else(|| invalid_cert("time not in UTC"))?;
+    // GeneralizedTime may carry fractional seconds, which are truncated
+    if tag == TAG_GENERALIZED_TIME && text.len() > 15 && text[14] == b'.' {
+        if !text[15..].iter().all(u8::is_ascii_digit) {
+            return Err(invalid_cert("bad time"));
+        }
+        text = &text[..14];
+    }
+    if !text.iter().all(u8::is_ascii_digit) {
+        return Err(invalid_cert("bad time"));
+    }
+    let number = |digits: &[u8]| {
+        digits
+            .iter()
+            .fold(0i64, |acc, d| acc * 10 + i64::from(d - b'0'))
+    };
+
+    let (year, rest) = match tag {
+        TAG_UTC_TIME if text.len() == 12 => {
+            let yy = number(&text[0..2]);
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, &text[2..])
+        }
+        TAG_GENERALIZED_TIME if text.len() == 14 => (number(&text[0..4]), &text[4..]),
+        _ => return Err(invalid_cert("unsupported time format")),
+    };
+    let month = number(&rest[0..2]);
+    let day = number(&rest[2..4]);
+    let hour = number(&rest[4..6]);
+    let minute = number(&rest[6..8]);
+    let second = number(&rest[8..10]);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid_cert("time out of range"));
+    }
+
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    if secs >= 0 {
+        Ok(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    } else {
+        Ok(UNIX_EPOCH - Duration::from_secs((-secs) as u64))
+    }
+}
+
+/// Extracts the validity window from a DER encoded certificate.
+pub fn certificate_validity(
+    cert: &rustls::Certificate,
+) -> Result<CertificateValidity, ProtonError> {
+    let (tag, certificate, _) = read_tlv(&cert.0)?;
+    if tag != TAG_SEQUENCE {
+        return Err(invalid_cert("not a sequence"));
+    }
+    let (tag, mut tbs, _) = read_tlv(certificate)?;
+    if tag != TAG_SEQUENCE {
+        return Err(invalid_cert("missing tbsCertificate"));
+    }
+
+    // Skip the optional version, serial number, signature algorithm and issuer
+    let (tag, _, rest) = read_tlv(tbs)?;
+    if tag == TAG_EXPLICIT_VERSION {
+        tbs = rest;
+        let (_, _, rest) = read_tlv(tbs)?;
+        tbs = rest;
+    } else {
+        tbs = rest;
+    }
+    for _ in 0..2 {
+        let (_, _, rest) = read_tlv(tbs)?;
+        tbs = rest;
+    }
+
+    let (tag, validity, _) = read_tlv(tbs)?;
+    if tag != TAG_SEQUENCE {
+        return Err(invalid_cert("missing validity"));
+    }
+    let (tag, value, rest) = read_tlv(validity)?;
+    let not_before = parse_time(tag, value)?;
+    let (tag, value, _) = read_tlv(rest)?;
+    let not_after = parse_time(tag, value)?;
+
+    Ok(CertificateValidity {
+        not_before,
+        not_after,
+    })
+}
+
+/// Checks that `key` is the private key belonging to `cert` by signing a probe
+/// message and verifying it against the certificate's public key.
+pub fn verify_key_pair(
+    cert: &rustls::Certificate,
+    key: &rustls::PrivateKey,
+) -> Result<(), ProtonError> {
+    let signing_key = rustls::sign::any_supported_type(key)
+        .map_err(|_| invalid_cert("unsupported private key type"))?;
+    let signer = signing_key
+        .choose_scheme(&[
+            SignatureScheme::ED25519,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .ok_or_else(|| invalid_cert("no usable signature scheme for private key"))?;
+
+    let algorithm: &webpki::SignatureAlgorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        _ => return Err(invalid_cert("unsupported signature scheme")),
+    };
+
+    let probe = b"proton key pair check";
+    let signature = signer
+        .sign(probe)
+        .map_err(|_| invalid_cert("failed to sign with private key"))?;
+    let end_entity = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|_| invalid_cert("unparseable end entity certificate"))?;
+    end_entity
+        .verify_signature(algorithm, probe, &signature)
+        .map_err(|_| invalid_cert("private key does not match certificate"))
+}
+
+/// Restricts the TLS parameters used by an endpoint. QUIC always runs TLS 1.3,
+/// so only TLS 1.3 cipher suites can be selected.
+#[derive(Debug, Clone)]
+pub struct TlsPolicy {
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+    pub kx_groups: Vec<&'static SupportedKxGroup>,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        Self {
+            cipher_suites: tls13_cipher_suites().collect(),
+            kx_groups: ALL_KX_GROUPS.to_vec(),
+        }
+    }
+}
+
+fn tls13_cipher_suites() -> impl Iterator<Item = SupportedCipherSuite> {
+    ALL_CIPHER_SUITES
+        .iter()
+        .copied()
+        .filter(|s| matches!(s, SupportedCipherSuite::Tls13(_)))
+}
+
+fn invalid_policy(msg: String) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))
+}
+
+impl TlsPolicy {
+    /// Builds a policy from cipher suite names (e.g. `TLS13_AES_256_GCM_SHA384`)
+    /// and key exchange group names (e.g. `secp384r1`). An empty list keeps the
+    /// default for that parameter.
+    pub fn from_names(suites: &[String], groups: &[String]) -> Result<Self, ProtonError> {
+        let mut policy = TlsPolicy::default();
+        if !suites.is_empty() {
+            policy.cipher_suites = suites
+                .iter()
+                .map(|name| {
+                    tls13_cipher_suites()
+                        .find(|s| format!("{:?}", s.suite()).eq_ignore_ascii_case(name))
+                        .ok_or_else(|| {
+                            invalid_policy(format!("unsupported TLS 1.3 cipher suite '{}'", name))
+                        })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if !groups.is_empty() {
+            policy.kx_groups = groups
+                .iter()
+                .map(|name| {
+                    ALL_KX_GROUPS
+                        .iter()
+                        .copied()
+                        .find(|g| format!("{:?}", g.name).eq_ignore_ascii_case(name))
+                        .ok_or_else(|| {
+                            invalid_policy(format!("unsupported key exchange group '{}'", name))
+                        })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(policy)
+    }
+
+    pub fn server_builder(
+        &self,
+    ) -> Result<ConfigBuilder<rustls::ServerConfig, WantsVerifier>, ProtonError> {
+        rustls::ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_kx_groups(&self.kx_groups)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| invalid_policy(e.to_string()))
+    }
+
+    pub fn client_builder(
+        &self,
+    ) -> Result<ConfigBuilder<rustls::ClientConfig, WantsVerifier>, ProtonError> {
+        rustls::ClientConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_kx_groups(&self.kx_groups)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|e| invalid_policy(e.to_string()))
+    }
+
+    /// Describes what a connection made under this policy negotiated. quinn
+    /// does not expose the selected suite, so it is only reported when the
+    /// policy pins a single choice.
+    pub fn negotiated(&self, alpn: Option<Vec<u8>>) -> NegotiatedTls {
+        NegotiatedTls {
+            protocol_version: "TLSv1.3",
+            alpn: alpn.map(|p| String::from_utf8_lossy(&p).into_owned()),
+            cipher_suite: match self.cipher_suites.as_slice() {
+                [only] => Some(format!("{:?}", only.suite())),
+                _ => None,
+            },
+            kx_group: match self.kx_groups.as_slice() {
+                [only] => Some(format!("{:?}", only.name)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// ALPN protocol agreed during the handshake.
+pub fn negotiated_alpn(connection: &quinn::Connection) -> Option<Vec<u8>> {
+    connection
+        .handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .protocol
+}
+
+/// TLS parameters of an established connection.
+#[derive(Debug, Clone)]
+pub struct NegotiatedTls {
+    pub protocol_version: &'static str,
+    pub alpn: Option<String>,
+    pub cipher_suite: Option<String>,
+    pub kx_group: Option<String>,
+}
+
+impl fmt::Display for NegotiatedTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "policy-dependent".to_string();
+        write!(
+            f,
+            "{} alpn={} suite={} kx={}",
+            self.protocol_version,
+            self.alpn.as_deref().unwrap_or("none"),
+            self.cipher_suite.as_ref().unwrap_or(&unknown),
+            self.kx_group.as_ref().unwrap_or(&unknown)
+        )
+    }
+}
+
+// OCSP response DER tags
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OID: u8 = 0x06;
+const TAG_SET: u8 = 0x31;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_CONTEXT_0: u8 = 0xa0;
+const TAG_CONTEXT_3: u8 = 0xa3;
+const TAG_CERT_STATUS_GOOD: u8 = 0x80;
+const TAG_CERT_STATUS_REVOKED: u8 = 0xa1;
+
+// Object identifiers (DER contents) needed to check an OCSP response
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_RSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_RSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OID_OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+
+/// Certificate status carried in a stapled OCSP response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OcspStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+fn invalid_ocsp(msg: &str) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid OCSP response: {}", msg),
+    ))
+}
+
+// Reads one DER element with the given tag, returning (contents, rest)
+fn expect<'a>(input: &'a [u8], tag: u8, what: &str) -> Result<(&'a [u8], &'a [u8]), ProtonError> {
+    match read_tlv(input) {
+        Ok((t, value, rest)) if t == tag => Ok((value, rest)),
+        _ => Err(invalid_ocsp(what)),
+    }
+}
+
+// Reads one DER element, returning (whole encoding, rest)
+fn read_element(input: &[u8]) -> Result<(&[u8], &[u8]), ProtonError> {
+    let (_, _, rest) = read_tlv(input)?;
+    Ok((&input[..input.len() - rest.len()], rest))
+}
+
+// Contents of a BIT STRING holding whole bytes
+fn bit_string_bytes(value: &[u8]) -> Result<&[u8], ProtonError> {
+    match value.split_first() {
+        Some((0, bytes)) => Ok(bytes),
+        _ => Err(invalid_cert("bad bit string")),
+    }
+}
+
+// Algorithm OID of an AlgorithmIdentifier
+fn algorithm_oid(input: &[u8]) -> Result<(&[u8], &[u8]), ProtonError> {
+    let (identifier, rest) = expect(input, TAG_SEQUENCE, "bad algorithm identifier")?;
+    let (oid, _) = expect(identifier, TAG_OID, "bad algorithm identifier")?;
+    Ok((oid, rest))
+}
+
+// The parts of an X.509 certificate needed to match and verify OCSP responses
+struct CertificateFields<'a> {
+    tbs: &'a [u8],
+    signature_algorithm: &'a [u8],
+    signature: &'a [u8],
+    serial: &'a [u8],
+    issuer: &'a [u8],
+    subject: &'a [u8],
+    public_key: &'a [u8],
+    extensions: &'a [u8],
+}
+
+fn certificate_fields(der: &[u8]) -> Result<CertificateFields<'_>, ProtonError> {
+    let (certificate, _) = expect(der, TAG_SEQUENCE, "bad certificate")?;
+    let (tbs, rest) = read_element(certificate)?;
+    let (signature_algorithm, rest) = algorithm_oid(rest)?;
+    let (signature, _) = expect(rest, TAG_BIT_STRING, "bad certificate signature")?;
+
+    let (mut fields, _) = expect(tbs, TAG_SEQUENCE, "bad tbsCertificate")?;
+    let (tag, _, rest) = read_tlv(fields)?;
+    if tag == TAG_EXPLICIT_VERSION {
+        fields = rest;
+    }
+    let (_, serial, rest) = read_tlv(fields)?;
+    let (_, rest) = read_element(rest)?; // signature
+    let (issuer, rest) = read_element(rest)?;
+    let (_, rest) = read_element(rest)?; // validity
+    let (subject, rest) = read_element(rest)?;
+    let (spki, mut rest) = expect(rest, TAG_SEQUENCE, "bad subjectPublicKeyInfo")?;
+    let (_, spki) = algorithm_oid(spki)?;
+    let (public_key, _) = expect(spki, TAG_BIT_STRING, "bad subjectPublicKeyInfo")?;
+
+    // Skip the unique identifiers to reach the extensions, if any
+    let mut extensions: &[u8] = &[];
+    while !rest.is_empty() {
+        let (tag, value, next) = read_tlv(rest)?;
+        if tag == TAG_CONTEXT_3 {
+            extensions = expect(value, TAG_SEQUENCE, "bad extensions")?.0;
+        }
+        rest = next;
+    }
+
+    Ok(CertificateFields {
+        tbs,
+        signature_algorithm,
+        signature: bit_string_bytes(signature)?,
+        serial,
+        issuer,
+        subject,
+        public_key: bit_string_bytes(public_key)?,
+        extensions,
+    })
+}
+
+// Whether the extended key usage extension allows signing OCSP responses
+fn allows_ocsp_signing(extensions: &[u8]) -> Result<bool, ProtonError> {
+    let mut rest = extensions;
+    while !rest.is_empty() {
+        let (extension, next) = expect(rest, TAG_SEQUENCE, "bad extension")?;
+        rest = next;
+        let (oid, mut value) = expect(extension, TAG_OID, "bad extension")?;
+        if oid != OID_EXTENDED_KEY_USAGE {
+            continue;
+        }
+        if let Ok((TAG_BOOLEAN, _, after)) = read_tlv(value) {
+            value = after;
+        }
+        let (usages, _) = expect(value, TAG_OCTET_STRING, "bad extended key usage")?;
+        let (mut usages, _) = expect(usages, TAG_SEQUENCE, "bad extended key usage")?;
+        while !usages.is_empty() {
+            let (usage, next) = expect(usages, TAG_OID, "bad extended key usage")?;
+            if usage == OID_OCSP_SIGNING {
+                return Ok(true);
+            }
+            usages = next;
+        }
+    }
+    Ok(false)
+}
+
+fn signature_algorithms(oid: &[u8]) -> Vec<&'static webpki::SignatureAlgorithm> {
+    match oid {
+        OID_ECDSA_SHA256 => vec![&webpki::ECDSA_P256_SHA256, &webpki::ECDSA_P384_SHA256],
+        OID_ECDSA_SHA384 => vec![&webpki::ECDSA_P384_SHA384, &webpki::ECDSA_P256_SHA384],
+        OID_RSA_SHA256 => vec![&webpki::RSA_PKCS1_2048_8192_SHA256],
+        OID_RSA_SHA384 => vec![&webpki::RSA_PKCS1_2048_8192_SHA384],
+        OID_RSA_SHA512 => vec![&webpki::RSA_PKCS1_2048_8192_SHA512],
+        OID_ED25519 => vec![&webpki::ED25519],
+        _ => vec![],
+    }
+}
+
+// Whether `signature` over `message` was made with the key of `signer`
+fn signed_by(signer: &[u8], algorithm: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(signer) = webpki::EndEntityCert::try_from(signer) else {
+        return false;
+    };
+    signature_algorithms(algorithm)
+        .iter()
+        .any(|alg| signer.verify_signature(alg, message, signature).is_ok())
+}
+
+// Whether `cert` was issued and signed by `issuer`
+fn issued_by(cert: &CertificateFields, issuer: &rustls::Certificate) -> bool {
+    certificate_fields(&issuer.0).is_ok_and(|fields| fields.subject == cert.issuer)
+        && signed_by(
+            &issuer.0,
+            cert.signature_algorithm,
+            cert.tbs,
+            cert.signature,
+        )
+}
+
+// The parts of a successful BasicOCSPResponse
+struct BasicOcspResponse<'a> {
+    tbs: &'a [u8],
+    responses: &'a [u8],
+    signature_algorithm: &'a [u8],
+    signature: &'a [u8],
+    certs: &'a [u8],
+}
+
+fn basic_ocsp_response(der: &[u8]) -> Result<BasicOcspResponse<'_>, ProtonError> {
+    let (response, _) = expect(der, TAG_SEQUENCE, "missing OCSPResponse")?;
+    let (status, rest) = expect(response, TAG_ENUMERATED, "missing responseStatus")?;
+    if status != [0] {
+        return Err(invalid_ocsp("responder did not return a successful status"));
+    }
+    let (response_bytes, _) = expect(rest, TAG_CONTEXT_0, "missing responseBytes")?;
+    let (response_bytes, _) = expect(response_bytes, TAG_SEQUENCE, "bad responseBytes")?;
+    let (_, rest) = expect(response_bytes, TAG_OID, "missing responseType")?;
+    let (basic, _) = expect(rest, TAG_OCTET_STRING, "missing BasicOCSPResponse")?;
+    let (basic, _) = expect(basic, TAG_SEQUENCE, "bad BasicOCSPResponse")?;
+    let (tbs, rest) = read_element(basic).map_err(|_| invalid_ocsp("missing tbsResponseData"))?;
+    let (signature_algorithm, rest) = algorithm_oid(rest)?;
+    let (signature, rest) = expect(rest, TAG_BIT_STRING, "missing signature")?;
+    let certs = match read_tlv(rest) {
+        Ok((TAG_CONTEXT_0, certs, _)) => expect(certs, TAG_SEQUENCE, "bad certs")?.0,
+        _ => &[],
+    };
+
+    // Skip the optional version, responderID and producedAt
+    let (mut data, _) = expect(tbs, TAG_SEQUENCE, "missing tbsResponseData")?;
+    let (tag, _, rest) = read_tlv(data)?;
+    if tag == TAG_CONTEXT_0 {
+        data = rest;
+    }
+    let (_, rest) = read_element(data)?;
+    let (_, rest) = read_element(rest)?;
+    let (responses, _) = expect(rest, TAG_SEQUENCE, "missing responses")?;
+    if responses.is_empty() {
+        return Err(invalid_ocsp("empty responses"));
+    }
+
+    Ok(BasicOcspResponse {
+        tbs,
+        responses,
+        signature_algorithm,
+        signature: bit_string_bytes(signature).map_err(|_| invalid_ocsp("bad signature"))?,
+        certs,
+    })
+}
+
+// Reads one SingleResponse, returning (certID contents, status, rest) after
+// checking that it is fresh
+fn single_response(input: &[u8]) -> Result<(&[u8], OcspStatus, &[u8]), ProtonError> {
+    let (single, rest) = expect(input, TAG_SEQUENCE, "bad SingleResponse")?;
+    let (cert_id, after) = expect(single, TAG_SEQUENCE, "missing certID")?;
+    let (cert_status, _, after) = read_tlv(after)?;
+    let (tag, value, after) = read_tlv(after)?;
+    let this_update = parse_time(tag, value)?;
+    if this_update > SystemTime::now() {
+        return Err(invalid_ocsp("thisUpdate is in the future"));
+    }
+    if let Ok((TAG_CONTEXT_0, next_update, _)) = read_tlv(after) {
+        let (tag, value, _) = read_tlv(next_update)?;
+        if parse_time(tag, value)? < SystemTime::now() {
+            return Err(invalid_ocsp("response is stale (nextUpdate has passed)"));
+        }
+    }
+    let status = match cert_status {
+        TAG_CERT_STATUS_GOOD => OcspStatus::Good,
+        TAG_CERT_STATUS_REVOKED => OcspStatus::Revoked,
+        _ => OcspStatus::Unknown,
+    };
+    Ok((cert_id, status, rest))
+}
+
+// Whether a CertID names `cert` as issued by `issuer`
+fn cert_id_matches(
+    cert_id: &[u8],
+    cert: &CertificateFields,
+    issuer: &CertificateFields,
+) -> Result<bool, ProtonError> {
+    let (hash, rest) = algorithm_oid(cert_id)?;
+    let (name_hash, rest) = expect(rest, TAG_OCTET_STRING, "bad issuerNameHash")?;
+    let (key_hash, rest) = expect(rest, TAG_OCTET_STRING, "bad issuerKeyHash")?;
+    let (_, serial, _) = read_tlv(rest)?;
+    let algorithm = match hash {
+        OID_SHA1 => &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        OID_SHA256 => &ring::digest::SHA256,
+        _ => return Ok(false),
+    };
+    Ok(serial == cert.serial
+        && ring::digest::digest(algorithm, issuer.subject).as_ref() == name_hash
+        && ring::digest::digest(algorithm, issuer.public_key).as_ref() == key_hash)
+}
+
+/// Checks the structure and freshness of a DER encoded OCSP response and
+/// returns the status of the first certificate it covers. The responder's
+/// signature is not verified, use [`verify_ocsp_response`] for responses
+/// received from a peer.
+pub fn check_ocsp_response(der: &[u8]) -> Result<OcspStatus, ProtonError> {
+    let response = basic_ocsp_response(der)?;
+    let (_, status, _) = single_response(response.responses)?;
+    Ok(status)
+}
+
+/// Verifies a DER encoded OCSP response for `cert` and returns its status.
+/// The response must be signed by the certificate's issuer, found among
+/// `issuers`, or by a responder the issuer delegated OCSP signing to, and
+/// must carry a fresh entry for the certificate's issuer and serial.
+pub fn verify_ocsp_response(
+    der: &[u8],
+    cert: &rustls::Certificate,
+    issuers: &[rustls::Certificate],
+) -> Result<OcspStatus, ProtonError> {
+    let response = basic_ocsp_response(der)?;
+    let fields = certificate_fields(&cert.0)?;
+    let issuer = issuers
+        .iter()
+        .find(|issuer| issued_by(&fields, issuer))
+        .ok_or_else(|| invalid_ocsp("issuer of the certificate is unknown"))?;
+    let issuer_fields = certificate_fields(&issuer.0)?;
+
+    let signed = |signer: &[u8]| {
+        signed_by(
+            signer,
+            response.signature_algorithm,
+            response.tbs,
+            response.signature,
+        )
+    };
+    if !signed(&issuer.0) {
+        let mut certs = response.certs;
+        let mut delegated = false;
+        while !certs.is_empty() && !delegated {
+            let (responder, rest) = read_element(certs)?;
+            certs = rest;
+            let responder_fields = certificate_fields(responder)?;
+            let responder_cert = rustls::Certificate(responder.to_vec());
+            delegated = issued_by(&responder_fields, issuer)
+                && allows_ocsp_signing(responder_fields.extensions)?
+                && certificate_validity(&responder_cert)
+                    .is_ok_and(|v| !v.is_expired() && !v.is_not_yet_valid())
+                && signed(responder);
+        }
+        if !delegated {
+            return Err(invalid_ocsp("not signed by the issuer or its responder"));
+        }
+    }
+
+    let mut responses = response.responses;
+    while !responses.is_empty() {
+        let (cert_id, status, rest) = single_response(responses)?;
+        if cert_id_matches(cert_id, &fields, &issuer_fields)? {
+            return Ok(status);
+        }
+        responses = rest;
+    }
+    Err(invalid_ocsp("no response for the certificate"))
+}
+
+// PEM files start with a "-----BEGIN" armour line, anything else is taken as DER
+fn is_pem(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b"-----BEGIN")
+}
+
+/// Loads every certificate from a PEM or DER file.
+pub fn load_certs(path: &Path) -> Result<Vec<rustls::Certificate>, ProtonError> {
+    let data = std::fs::read(path)?;
+    if !is_pem(&data) {
+        return Ok(vec![rustls::Certificate(data)]);
+    }
+    let certs = rustls_pemfile::certs(&mut data.as_slice())?;
+    if certs.is_empty() {
+        return Err(invalid_cert(&format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Loads the first PKCS#8, PKCS#1 or SEC1 private key from a PEM or DER file.
+pub fn load_private_key(path: &Path) -> Result<rustls::PrivateKey, ProtonError> {
+    let data = std::fs::read(path)?;
+    if !is_pem(&data) {
+        return Ok(rustls::PrivateKey(data));
+    }
+    for item in rustls_pemfile::read_all(&mut data.as_slice())? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid_cert(&format!(
+        "no private key in {}",
+        path.display()
+    )))
+}
+
+/// Loads certificate revocation lists from a PEM or DER file.
+pub fn load_crls(path: &Path) -> Result<Vec<Vec<u8>>, ProtonError> {
+    let data = std::fs::read(path)?;
+    if !is_pem(&data) {
+        return Ok(vec![data]);
+    }
+    Ok(rustls_pemfile::crls(&mut data.as_slice())?)
+}
+
+/// Builds a trust store from the certificates in a PEM or DER file.
+pub fn load_root_store(path: &Path) -> Result<RootCertStore, ProtonError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_cert(&format!("bad CA certificate: {}", e)))?;
+    }
+    Ok(roots)
+}
+
+// Attribute types shown when a distinguished name is displayed
+const NAME_ATTRIBUTES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x06], "C"),
+];
+
+// Renders a DER encoded Name as e.g. "CN=proton, O=Example", leaving out
+// attribute types not listed above
+fn display_name(name: &[u8]) -> Result<String, ProtonError> {
+    let (mut rdns, _) = expect(name, TAG_SEQUENCE, "bad name")?;
+    let mut parts = Vec::new();
+    while !rdns.is_empty() {
+        let (mut set, rest) = expect(rdns, TAG_SET, "bad name")?;
+        rdns = rest;
+        while !set.is_empty() {
+            let (attribute, rest) = expect(set, TAG_SEQUENCE, "bad name")?;
+            set = rest;
+            let (oid, value) = expect(attribute, TAG_OID, "bad name")?;
+            let (_, value, _) = read_tlv(value)?;
+            if let Some((_, short)) = NAME_ATTRIBUTES.iter().find(|(o, _)| *o == oid) {
+                parts.push(format!("{}={}", short, String::from_utf8_lossy(value)));
+            }
+        }
+    }
+    Ok(parts.join(", "))
+}
+
+/// One certificate of a chain: whom it was issued to and by, and when it
+/// is valid.
+#[derive(Debug, Clone)]
+pub struct ChainCertificate {
+    pub subject: String,
+    pub issuer: String,
+    pub validity: CertificateValidity,
+}
+
+/// Inspects every certificate of `chain`, leaf first.
+pub fn inspect_chain(chain: &[rustls::Certificate]) -> Result<Vec<ChainCertificate>, ProtonError> {
+    chain
+        .iter()
+        .map(|cert| {
+            let fields = certificate_fields(&cert.0)?;
+            Ok(ChainCertificate {
+                subject: display_name(fields.subject)?,
+                issuer: display_name(fields.issuer)?,
+                validity: certificate_validity(cert)?,
+            })
+        })
+        .collect()
+}
+
+/// Raw serial number bytes of a DER encoded certificate.
+pub fn certificate_serial(cert: &rustls::Certificate) -> Result<Vec<u8>, ProtonError> {
+    let (_, certificate, _) = read_tlv(&cert.0)?;
+    let (_, tbs, _) = read_tlv(certificate)?;
+    let (tag, value, rest) = read_tlv(tbs)?;
+    let serial = if tag == TAG_EXPLICIT_VERSION {
+        read_tlv(rest)?.1
+    } else {
+        value
+    };
+    Ok(serial.to_vec())
+}
+
+/// Client certificate verifier that checks the chain against `roots` and the
+/// configured CRLs, logging the CRL revocation reason when a client is
+/// rejected as revoked and returning it in the handshake error.
+pub struct RevocationCheckingVerifier {
+    inner: AllowAnyAuthenticatedClient,
+    crls: Vec<webpki::OwnedCertRevocationList>,
+}
+
+impl RevocationCheckingVerifier {
+    pub fn new(roots: RootCertStore, crls: &[Vec<u8>]) -> Result<Self, ProtonError> {
+        let inner = AllowAnyAuthenticatedClient::new(roots)
+            .with_crls(crls.iter().cloned().map(UnparsedCertRevocationList))
+            .map_err(|e| invalid_cert(&format!("bad CRL: {:?}", e)))?;
+        let crls = crls
+            .iter()
+            .map(|der| {
+                webpki::BorrowedCertRevocationList::from_der(der)
+                    .and_then(|crl| crl.to_owned())
+                    .map_err(|e| invalid_cert(&format!("bad CRL: {:?}", e)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { inner, crls })
+    }
+
+    fn revocation_reason(&self, cert: &rustls::Certificate) -> Option<String> {
+        let serial = certificate_serial(cert).ok()?;
+        self.crls.iter().find_map(|crl| {
+            let revoked = crl.find_serial(&serial).ok()??;
+            Some(format!(
+                "{:?}",
+                revoked
+                    .reason_code
+                    .unwrap_or(webpki::RevocationReason::Unspecified)
+            ))
+        })
+    }
+}
+
+impl ClientCertVerifier for RevocationCheckingVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let result = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now);
+        if let Err(rustls::Error::InvalidCertificate(CertificateError::Revoked)) = result {
+            let reason = self
+                .revocation_reason(end_entity)
+                .unwrap_or_else(|| "Unknown".to_string());
+            warn!("Rejecting client certificate: revoked ({})", reason);
+            // The message reaches the client in the CONNECTION_CLOSE reason
+            return Err(rustls::Error::General(format!(
+                "client certificate revoked: {}",
+                reason
+            )));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proton::handler::EchoHandler;
+    use crate::proton::{ProtonClient, ProtonConfig, ProtonServer};
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, CertificateRevocationList,
+        CertificateRevocationListParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+        RevocationReason, RevokedCertParams, SerialNumber,
+    };
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    const OID_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+    fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match contents.len() {
+            n if n < 0x80 => out.push(n as u8),
+            n if n <= 0xff => out.extend([0x81, n as u8]),
+            n => out.extend([0x82, (n >> 8) as u8, n as u8]),
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn time(tag: u8, text: &str) -> Result<SystemTime, ProtonError> {
+        parse_time(tag, text.as_bytes())
+    }
+
+    fn secs(text: &str) -> u64 {
+        time(TAG_GENERALIZED_TIME, text)
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn parses_utc_and_generalized_time() {
+        assert_eq!(secs("19700101000000Z"), 0);
+        assert_eq!(secs("20240229123456Z"), 1709210096);
+        let utc = time(TAG_UTC_TIME, "240229123456Z").unwrap();
+        assert_eq!(
+            utc.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            1709210096
+        );
+        let before_2000 = time(TAG_UTC_TIME, "991231235959Z").unwrap();
+        assert_eq!(before_2000, UNIX_EPOCH + Duration::from_secs(946684799));
+    }
+
+    #[test]
+    fn truncates_fractional_seconds() {
+        assert_eq!(secs("20240229123456.789Z"), 1709210096);
+        assert!(time(TAG_GENERALIZED_TIME, "20240229123456.Z").is_err());
+        assert!(time(TAG_GENERALIZED_TIME, "20240229123456.7x9Z").is_err());
+        assert!(time(TAG_UTC_TIME, "240229123456.7Z").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_time() {
+        for text in [
+            "20240229123456",   // no Z
+            "2024022912345Z",   // too short
+            "+0240229123456Z",  // sign
+            "2024022912+456Z",  // sign inside a field
+            "2024-02-2912345Z", // separators
+            "2024\u{e9}229123456Z",
+            "",
+        ] {
+            assert!(time(TAG_GENERALIZED_TIME, text).is_err(), "{:?}", text);
+        }
+        assert!(parse_time(TAG_UTC_TIME, &[0xff; 13]).is_err());
+        assert!(parse_time(TAG_SEQUENCE, b"20240229123456Z").is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_time() {
+        for text in [
+            "20241301000000Z",
+            "20240001000000Z",
+            "20240100000000Z",
+            "20230229000000Z",
+            "20240431000000Z",
+            "20240101240000Z",
+            "20240101006000Z",
+            "20240101000060Z",
+        ] {
+            assert!(time(TAG_GENERALIZED_TIME, text).is_err(), "{:?}", text);
+        }
+        assert!(time(TAG_GENERALIZED_TIME, "20000229000000Z").is_ok());
+        assert!(time(TAG_GENERALIZED_TIME, "21000229000000Z").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert!(read_tlv(&[]).is_err());
+        assert!(read_tlv(&[TAG_SEQUENCE]).is_err());
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x03, 0x00]).is_err());
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x80]).is_err());
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x85, 0, 0, 0, 0, 1]).is_err());
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x82, 0xff, 0xff]).is_err());
+        let (tag, value, rest) = read_tlv(&[TAG_SEQUENCE, 0x81, 0x01, 0x05, 0x06]).unwrap();
+        assert_eq!((tag, value, rest), (TAG_SEQUENCE, &[0x05][..], &[0x06][..]));
+    }
+
+    struct Pki {
+        ca: Certificate,
+        ca_der: Vec<u8>,
+        leaf_der: Vec<u8>,
+        leaf_key: Vec<u8>,
+    }
+
+    fn new_pki() -> Pki {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let ca_der = ca.serialize_der().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".into()]);
+        params.serial_number = Some(SerialNumber::from(0x1234u64));
+        let leaf = Certificate::from_params(params).unwrap();
+        let leaf_der = leaf.serialize_der_with_signer(&ca).unwrap();
+        Pki {
+            ca,
+            ca_der,
+            leaf_der,
+            leaf_key: leaf.serialize_private_key_der(),
+        }
+    }
+
+    fn cert_id(leaf: &[u8], issuer: &[u8]) -> Vec<u8> {
+        let leaf = certificate_fields(leaf).unwrap();
+        let issuer = certificate_fields(issuer).unwrap();
+        let sha256 = &ring::digest::SHA256;
+        [
+            encode(TAG_SEQUENCE, &encode(TAG_OID, OID_SHA256)),
+            encode(
+                TAG_OCTET_STRING,
+                ring::digest::digest(sha256, issuer.subject).as_ref(),
+            ),
+            encode(
+                TAG_OCTET_STRING,
+                ring::digest::digest(sha256, issuer.public_key).as_ref(),
+            ),
+            encode(0x02, leaf.serial),
+        ]
+        .concat()
+    }
+
+    fn single(cert_id: &[u8], status: &[u8], next_update: &str) -> Vec<u8> {
+        let single = [
+            encode(TAG_SEQUENCE, cert_id),
+            status.to_vec(),
+            encode(TAG_GENERALIZED_TIME, b"20200101000000Z"),
+            encode(
+                TAG_CONTEXT_0,
+                &encode(TAG_GENERALIZED_TIME, next_update.as_bytes()),
+            ),
+        ]
+        .concat();
+        encode(TAG_SEQUENCE, &single)
+    }
+
+    fn response(singles: &[Vec<u8>], signer: &Certificate, certs: &[Vec<u8>]) -> Vec<u8> {
+        let tbs = encode(
+            TAG_SEQUENCE,
+            &[
+                encode(0xa2, &encode(TAG_OCTET_STRING, &[0; 20])),
+                encode(TAG_GENERALIZED_TIME, b"20200101000000Z"),
+                encode(TAG_SEQUENCE, &singles.concat()),
+            ]
+            .concat(),
+        );
+        let rng = ring::rand::SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &signer.get_key_pair().serialize_der(),
+            &rng,
+        )
+        .unwrap();
+        let signature = key.sign(&rng, &tbs).unwrap();
+        let mut basic = [
+            tbs,
+            encode(TAG_SEQUENCE, &encode(TAG_OID, OID_ECDSA_SHA256)),
+            encode(TAG_BIT_STRING, &[&[0], signature.as_ref()].concat()),
+        ]
+        .concat();
+        if !certs.is_empty() {
+            basic.extend(encode(
+                TAG_CONTEXT_0,
+                &encode(TAG_SEQUENCE, &certs.concat()),
+            ));
+        }
+        let bytes = encode(
+            TAG_SEQUENCE,
+            &[
+                encode(TAG_OID, OID_OCSP_BASIC),
+                encode(TAG_OCTET_STRING, &encode(TAG_SEQUENCE, &basic)),
+            ]
+            .concat(),
+        );
+        encode(
+            TAG_SEQUENCE,
+            &[encode(TAG_ENUMERATED, &[0]), encode(TAG_CONTEXT_0, &bytes)].concat(),
+        )
+    }
+
+    fn verify(der: &[u8], pki: &Pki) -> Result<OcspStatus, ProtonError> {
+        verify_ocsp_response(
+            der,
+            &rustls::Certificate(pki.leaf_der.clone()),
+            &[rustls::Certificate(pki.ca_der.clone())],
+        )
+    }
+
+    const GOOD: &[u8] = &[TAG_CERT_STATUS_GOOD, 0];
+
+    #[test]
+    fn accepts_response_signed_by_issuer() {
+        let pki = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let der = response(&[single(&id, GOOD, "20991231000000Z")], &pki.ca, &[]);
+        assert_eq!(check_ocsp_response(&der).unwrap(), OcspStatus::Good);
+        assert_eq!(verify(&der, &pki).unwrap(), OcspStatus::Good);
+
+        let revoked = encode(
+            TAG_CERT_STATUS_REVOKED,
+            &encode(TAG_GENERALIZED_TIME, b"20200101000000Z"),
+        );
+        let der = response(&[single(&id, &revoked, "20991231000000Z")], &pki.ca, &[]);
+        assert_eq!(verify(&der, &pki).unwrap(), OcspStatus::Revoked);
+    }
+
+    #[test]
+    fn finds_the_matching_single_response() {
+        let pki = new_pki();
+        let other = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let other_id = cert_id(&other.leaf_der, &other.ca_der);
+        let der = response(
+            &[
+                single(&other_id, GOOD, "20991231000000Z"),
+                single(&id, GOOD, "20991231000000Z"),
+            ],
+            &pki.ca,
+            &[],
+        );
+        assert_eq!(verify(&der, &pki).unwrap(), OcspStatus::Good);
+
+        let der = response(&[single(&other_id, GOOD, "20991231000000Z")], &pki.ca, &[]);
+        assert!(verify(&der, &pki).is_err());
+    }
+
+    #[test]
+    fn rejects_response_from_another_signer() {
+        let pki = new_pki();
+        let other = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let der = response(&[single(&id, GOOD, "20991231000000Z")], &other.ca, &[]);
+        assert!(check_ocsp_response(&der).is_ok());
+        assert!(verify(&der, &pki).is_err());
+        // The issuer must be known as well
+        let der = response(&[single(&id, GOOD, "20991231000000Z")], &pki.ca, &[]);
+        assert!(verify_ocsp_response(
+            &der,
+            &rustls::Certificate(pki.leaf_der.clone()),
+            &[rustls::Certificate(other.ca_der.clone())],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn accepts_delegated_responder_only_with_ocsp_signing() {
+        let pki = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let responder = |usages| {
+            let mut params = CertificateParams::new(vec![]);
+            params.extended_key_usages = usages;
+            let cert = Certificate::from_params(params).unwrap();
+            let der = cert.serialize_der_with_signer(&pki.ca).unwrap();
+            (cert, der)
+        };
+
+        let (delegated, delegated_der) = responder(vec![ExtendedKeyUsagePurpose::OcspSigning]);
+        let der = response(
+            &[single(&id, GOOD, "20991231000000Z")],
+            &delegated,
+            &[delegated_der],
+        );
+        assert_eq!(verify(&der, &pki).unwrap(), OcspStatus::Good);
+
+        let (server, server_der) = responder(vec![ExtendedKeyUsagePurpose::ServerAuth]);
+        let der = response(
+            &[single(&id, GOOD, "20991231000000Z")],
+            &server,
+            &[server_der],
+        );
+        assert!(verify(&der, &pki).is_err());
+    }
+
+    #[test]
+    fn rejects_stale_response() {
+        let pki = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let der = response(&[single(&id, GOOD, "20210101000000Z")], &pki.ca, &[]);
+        assert!(check_ocsp_response(&der).is_err());
+        assert!(verify(&der, &pki).is_err());
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let pki = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let der = response(&[single(&id, GOOD, "20991231000000Z")], &pki.ca, &[]);
+        for len in 0..der.len() {
+            assert!(verify(&der[..len], &pki).is_err());
+        }
+        for len in 0..pki.leaf_der.len() {
+            let cert = rustls::Certificate(pki.leaf_der[..len].to_vec());
+            assert!(certificate_validity(&cert).is_err());
+            assert!(certificate_fields(&cert.0).is_err());
+        }
+    }
+
+    #[test]
+    fn inspects_every_certificate_of_a_chain() {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Proton Test CA");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example");
+        let ca = Certificate::from_params(params).unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".into()]);
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "localhost");
+        let leaf = Certificate::from_params(params).unwrap();
+        let chain = [
+            rustls::Certificate(leaf.serialize_der_with_signer(&ca).unwrap()),
+            rustls::Certificate(ca.serialize_der().unwrap()),
+        ];
+
+        let inspected = inspect_chain(&chain).unwrap();
+        assert_eq!(inspected.len(), 2);
+        assert_eq!(inspected[0].subject, "CN=localhost");
+        assert_eq!(inspected[0].issuer, "CN=Proton Test CA, O=Example");
+        assert_eq!(inspected[1].subject, inspected[1].issuer);
+        assert!(!inspected[1].validity.is_expired());
+    }
+
+    // A server for the echo handler presenting the leaf of `pki`, and the
+    // address it is to listen on
+    fn leaf_server(pki: &Pki) -> (ProtonServer, SocketAddr) {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = ProtonServer::new(
+            addr,
+            rustls::Certificate(pki.leaf_der.clone()),
+            rustls::PrivateKey(pki.leaf_key.clone()),
+            Arc::new(EchoHandler),
+        )
+        .unwrap()
+        .with_config(ProtonConfig {
+            startup_delay: Duration::ZERO,
+            ..ProtonConfig::default()
+        })
+        .unwrap();
+        (server, addr)
+    }
+
+    async fn serve(server: ProtonServer) {
+        let mut listening = server.listening();
+        tokio::spawn(async move { server.run().await });
+        listening.wait_for(|listening| *listening).await.unwrap();
+    }
+
+    // A client that makes one connection attempt, trusting `pki`'s CA
+    fn trusting_client(pki: &Pki) -> ProtonClient {
+        let mut roots = RootCertStore::empty();
+        roots.add(&rustls::Certificate(pki.ca_der.clone())).unwrap();
+        ProtonClient::new("127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .with_config(ProtonConfig {
+                connect_retries: 0,
+                ..ProtonConfig::default()
+            })
+            .unwrap()
+            .with_root_ca(roots)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn required_staple_must_be_present_and_good() {
+        let pki = new_pki();
+        let id = cert_id(&pki.leaf_der, &pki.ca_der);
+        let staple = std::env::temp_dir().join(format!("proton-tls-staple-{}", std::process::id()));
+        std::fs::write(
+            &staple,
+            response(&[single(&id, GOOD, "20991231000000Z")], &pki.ca, &[]),
+        )
+        .unwrap();
+        let (server, stapled) = leaf_server(&pki);
+        serve(
+            server
+                .with_ocsp_response_file(staple.clone(), Duration::from_secs(3600))
+                .unwrap(),
+        )
+        .await;
+        let (server, unstapled) = leaf_server(&pki);
+        serve(server).await;
+
+        let mut client = trusting_client(&pki)
+            .with_ocsp_issuers(vec![rustls::Certificate(pki.ca_der.clone())])
+            .unwrap()
+            .with_required_ocsp_staple(true)
+            .unwrap();
+        let mut connection = client.connect(stapled, Some(Duration::ZERO)).await.unwrap();
+        let ack = connection.send_event().await.unwrap();
+        assert_eq!(ack, connection.last_event_id());
+        connection.close().await;
+        assert!(client
+            .connect(unstapled, Some(Duration::ZERO))
+            .await
+            .is_err());
+        std::fs::remove_file(&staple).unwrap();
+    }
+
+    // A client certificate with `serial`, issued by `pki`'s CA
+    fn client_cert(pki: &Pki, serial: u64) -> (Vec<rustls::Certificate>, rustls::PrivateKey) {
+        let mut params = CertificateParams::new(vec![]);
+        params.serial_number = Some(SerialNumber::from(serial));
+        let cert = Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der_with_signer(&pki.ca).unwrap();
+        (
+            vec![rustls::Certificate(der)],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    #[tokio::test]
+    async fn revoked_client_certificates_are_refused() {
+        let pki = new_pki();
+        let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+            this_update: rcgen::date_time_ymd(2020, 1, 1),
+            next_update: rcgen::date_time_ymd(2099, 1, 1),
+            crl_number: SerialNumber::from(1u64),
+            issuing_distribution_point: None,
+            revoked_certs: vec![RevokedCertParams {
+                serial_number: SerialNumber::from(0x66u64),
+                revocation_time: rcgen::date_time_ymd(2020, 1, 1),
+                reason_code: Some(RevocationReason::KeyCompromise),
+                invalidity_date: None,
+            }],
+            alg: &rcgen::PKCS_ECDSA_P256_SHA256,
+            key_identifier_method: rcgen::KeyIdMethod::Sha256,
+        })
+        .unwrap();
+        let crl = crl.serialize_der_with_signer(&pki.ca).unwrap();
+        let (revoked, revoked_key) = client_cert(&pki, 0x66);
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&rustls::Certificate(pki.ca_der.clone())).unwrap();
+        let verifier =
+            RevocationCheckingVerifier::new(roots.clone(), std::slice::from_ref(&crl)).unwrap();
+        match verifier.verify_client_cert(&revoked[0], &[], SystemTime::now()) {
+            Err(rustls::Error::General(reason)) => {
+                assert_eq!(reason, "client certificate revoked: KeyCompromise")
+            }
+            other => panic!("revoked certificate verified as {:?}", other),
+        }
+
+        let path = std::env::temp_dir().join(format!("proton-tls-crl-{}", std::process::id()));
+        std::fs::write(&path, &crl).unwrap();
+        let (server, addr) = leaf_server(&pki);
+        serve(
+            server
+                .with_client_auth(roots)
+                .unwrap()
+                .with_crl_files(vec![path.clone()], Duration::from_secs(3600))
+                .unwrap(),
+        )
+        .await;
+
+        let (chain, key) = client_cert(&pki, 0x67);
+        let mut client = trusting_client(&pki).with_client_cert(chain, key).unwrap();
+        let mut connection = client.connect(addr, Some(Duration::ZERO)).await.unwrap();
+        connection.send_event().await.unwrap();
+        connection.close().await;
+
+        let mut client = trusting_client(&pki)
+            .with_client_cert(revoked, revoked_key)
+            .unwrap();
+        assert!(client.connect(addr, Some(Duration::ZERO)).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+use crate::proton::client::ProtonConnection;
+use crate::proton::decode::{parse_capture, parse_stream};
+use crate::proton::frame::{describe_frame, stream_name, Direction, FrameInspector, Headers};
+use crate::proton::wire::{decode_commit, STREAM_ACTION, STREAM_EVENT, STREAM_STATE_COMMIT};
+use crate::proton::ProtonError;
+use std::fmt::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep_until;
+use tracing::info;
+
+fn invalid_input(message: String) -> ProtonError {
+    ProtonError::IoError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        message,
+    ))
+}
+
+/// A frame in a transcript, as shown to a [`FrameInspector`]: the id and any
+/// headers section when sent, the 4-byte response when received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// When it went over the wire, from the start of the recording
+    pub at: Duration,
+    pub direction: Direction,
+    pub stream: u8,
+    pub bytes: Vec<u8>,
+}
+
+impl RecordedFrame {
+    pub fn describe(&self) -> String {
+        describe_frame(self.direction, self.stream, &self.bytes)
+    }
+}
+
+/// Records the raw frames on a connection with their timings, so the
+/// interaction can be replayed against a server later with [`replay`].
+///
+/// Written as text, one frame per line: the time in seconds, `>>` or `<<`,
+/// the stream and the frame in hex, followed by a decoded view as a comment.
+#[derive(Debug)]
+pub struct Transcript {
+    started: Instant,
+    frames: Mutex<Vec<RecordedFrame>>,
+}
+
+impl Default for Transcript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transcript {
+    /// Starts an empty transcript; times are relative to now.
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            frames: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn frames(&self) -> Vec<RecordedFrame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# proton transcript: seconds, direction, stream, frame\n");
+        for frame in self.frames.lock().unwrap().iter() {
+            let _ = write!(
+                out,
+                "{:.6} {} {} ",
+                frame.at.as_secs_f64(),
+                match frame.direction {
+                    Direction::Sent => ">>",
+                    Direction::Received => "<<",
+                },
+                match frame.stream {
+                    STREAM_STATE_COMMIT => "state-commit",
+                    stream => stream_name(stream),
+                }
+            );
+            for b in &frame.bytes {
+                let _ = write!(out, "{:02x}", b);
+            }
+            let _ = writeln!(out, "  # {}", frame.describe());
+        }
+        out
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    /// Parses a transcript written by [`Transcript::write`].
+    pub fn parse(text: &str) -> Result<Vec<RecordedFrame>, ProtonError> {
+        let mut frames = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(frame, _)| frame).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid =
+                |what: &str| invalid_input(format!("transcript line {}: invalid {}", n + 1, what));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [at, direction, stream, hex] = fields[..] else {
+                return Err(invalid("frame, expected seconds, >> or <<, stream and hex"));
+            };
+            let at = at
+                .parse::<f64>()
+                .ok()
+                .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                .ok_or_else(|| invalid("time"))?;
+            let direction = match direction {
+                ">>" => Direction::Sent,
+                "<<" => Direction::Received,
+                _ => return Err(invalid("direction")),
+            };
+            let stream = parse_stream(stream)
+                .ok()
+                .filter(|s| [STREAM_EVENT, STREAM_STATE_COMMIT, STREAM_ACTION].contains(s))
+                .ok_or_else(|| invalid("stream"))?;
+            let bytes = parse_capture(hex.as_bytes()).map_err(|_| invalid("frame hex"))?;
+            if bytes.len() < 4 {
+                return Err(invalid("frame, shorter than 4 bytes"));
+            }
+            frames.push(RecordedFrame {
+                at,
+                direction,
+                stream,
+                bytes,
+            });
+        }
+        Ok(frames)
+    }
+
+    pub fn read(path: &Path) -> Result<Vec<RecordedFrame>, ProtonError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl FrameInspector for Transcript {
+    fn on_frame(&self, direction: Direction, stream: u8, frame: &[u8]) {
+        let at = self.started.elapsed();
+        self.frames.lock().unwrap().push(RecordedFrame {
+            at,
+            direction,
+            stream,
+            bytes: frame.to_vec(),
+        });
+    }
+}
+
+/// How a replay went: the requests re-sent and every response that differed
+/// from the recording.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub requests: usize,
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        write!(
+            f,
+            "Replayed {} requests, {} responses differ from the recording",
+            self.requests,
+            self.differences.len()
+        )
+    }
+}
+
+/// Re-sends the requests of a recorded transcript on `connection`, spaced as
+/// they were recorded unless `timing` is off, then compares the responses on
+/// each stream, in order, with the recorded ones.
+///
+/// Recorded headers sections replace the connection's headers, which are
+/// only sent if the client was built `with_frame_headers`. The replay stops
+/// early if the server closes the connection.
+pub async fn replay(
+    connection: &mut ProtonConnection,
+    recorded: &[RecordedFrame],
+    timing: bool,
+) -> ReplayReport {
+    let transcript = Arc::new(Transcript::new());
+    connection.set_frame_inspector(Some(Arc::clone(&transcript) as Arc<dyn FrameInspector>));
+    let mut report = ReplayReport::default();
+    let started = tokio::time::Instant::now();
+    let offset = recorded.first().map_or(Duration::ZERO, |f| f.at);
+
+    for frame in recorded.iter().filter(|f| f.direction == Direction::Sent) {
+        if timing {
+            sleep_until(started + frame.at.saturating_sub(offset)).await;
+        }
+        let id = u32::from_le_bytes(frame.bytes[..4].try_into().unwrap());
+        if frame.bytes.len() > 4 {
+            match Headers::decode(&frame.bytes[4..]) {
+                Ok((headers, _)) => *connection.headers_mut() = headers,
+                Err(e) => info!("Ignoring headers of recorded {}: {}", frame.describe(), e),
+            }
+        }
+        report.requests += 1;
+        let result = match frame.stream {
+            STREAM_EVENT => connection.send_event_with_id(id).await,
+            STREAM_STATE_COMMIT => match decode_commit(id) {
+                (commit_id, true) => connection.abort_commit(commit_id).await,
+                (commit_id, false) => connection.send_state_commit(commit_id).await,
+            },
+            _ => {
+                connection.ack_up_to(id);
+                connection.read_action().await
+            }
+        };
+        if let Err(e) = result {
+            info!("Replayed {}: {}", frame.describe(), e);
+            if let Some(reason) = connection.close_reason() {
+                report
+                    .differences
+                    .push(format!("connection closed during the replay: {}", reason));
+                break;
+            }
+        }
+    }
+    connection.set_frame_inspector(None);
+
+    // Responses on a stream arrive in request order, so they pair up by
+    // position
+    let replayed = transcript.frames();
+    for stream in [STREAM_EVENT, STREAM_STATE_COMMIT, STREAM_ACTION] {
+        let responses = |frames: &[RecordedFrame]| -> Vec<String> {
+            frames
+                .iter()
+                .filter(|f| f.direction == Direction::Received && f.stream == stream)
+                .map(RecordedFrame::describe)
+                .collect()
+        };
+        let (expected, actual) = (responses(recorded), responses(&replayed));
+        for i in 0..expected.len().max(actual.len()) {
+            let (expected, actual) = (expected.get(i), actual.get(i));
+            if expected != actual {
+                report.differences.push(format!(
+                    "{} response {}: recorded {}, replayed {}",
+                    stream_name(stream),
+                    i + 1,
+                    expected.map_or("none", String::as_str),
+                    actual.map_or("none", String::as_str)
+                ));
+            }
+        }
+    }
+    report
+}
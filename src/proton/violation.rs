@@ -0,0 +1,109 @@
+use crate::proton::wire::MAX_FRAME_LEN;
+use crate::proton::{Framing, ProtonError};
+use std::fmt;
+use std::str::FromStr;
+
+// Longest diagnostic sent in a close frame, well within a single packet
+const MAX_REASON_LEN: usize = 512;
+
+/// How the server treats a client that deviates from the protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolMode {
+    /// Deviations the server can read past are logged and the request is
+    /// handled anyway, e.g. while bringing up a third-party implementation
+    #[default]
+    Lenient,
+    /// Any deviation closes the connection with a diagnostic
+    Strict,
+}
+
+impl FromStr for ProtocolMode {
+    type Err = ProtonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lenient" => Ok(ProtocolMode::Lenient),
+            "strict" => Ok(ProtocolMode::Strict),
+            _ => Err(ProtonError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown protocol mode '{}', expected lenient or strict", s),
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolMode::Lenient => write!(f, "lenient"),
+            ProtocolMode::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+/// A deviation from the protocol, described for whoever debugs the peer.
+/// The server sends it as the reason of the connection close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Stream it happened on, e.g. `event`
+    pub stream: String,
+    /// Byte offset in the stream where the offending frame starts, counting
+    /// the discriminator
+    pub offset: u64,
+    pub expected: String,
+    pub received: String,
+}
+
+impl Violation {
+    pub fn new(
+        stream: &str,
+        offset: u64,
+        expected: impl Into<String>,
+        received: impl Into<String>,
+    ) -> Self {
+        Self {
+            stream: stream.to_string(),
+            offset,
+            expected: expected.into(),
+            received: received.into(),
+        }
+    }
+
+    /// The violation in a length-prefixed or typed frame whose prefix was
+    /// `prefix`, if its length is out of bounds. The stream cannot be read
+    /// past it.
+    pub fn frame_len(stream: &str, offset: u64, framing: Framing, prefix: [u8; 4]) -> Option<Self> {
+        let len = u32::from_le_bytes(prefix);
+        (framing != Framing::Fixed && !(4..=MAX_FRAME_LEN).contains(&len)).then(|| {
+            Self::new(
+                stream,
+                offset,
+                format!("frame length 4..={}", MAX_FRAME_LEN),
+                format!("frame length {}", len),
+            )
+        })
+    }
+
+    /// The diagnostic as sent in a close frame, cut short if need be.
+    pub fn close_reason(&self) -> Vec<u8> {
+        let mut reason = self.to_string();
+        if reason.len() > MAX_REASON_LEN {
+            let mut end = MAX_REASON_LEN;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+        reason.into_bytes()
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "protocol violation on {} stream at offset {}: expected {}, received {}",
+            self.stream, self.offset, self.expected, self.received
+        )
+    }
+}
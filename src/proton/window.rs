@@ -0,0 +1,369 @@
+//! Windowed acknowledgement of events. Instead of waiting for each ack
+//! before sending the next event, a client keeps up to a window of events in
+//! flight on its event stream. A background task reads the acks, which the
+//! server sends in request order, and resolves each event's `PendingAck`;
+//! sending blocks while the window is full. An ack for any event but the
+//! oldest in flight is a protocol violation and closes the window.
+
+use crate::proton::efficiency::SentFrames;
+use crate::proton::frame::{Direction, FrameInspector, Headers};
+use crate::proton::fsm::{StreamState, StreamStates};
+use crate::proton::profile::spawn_named;
+use crate::proton::violation::Violation;
+use crate::proton::wire::{decode_response, encode_u32, QUOTA_EXCEEDED};
+use crate::proton::{Frame, Framing, MessageKind, ProtonError, STREAM_EVENT, STREAM_TIMEOUT};
+use quinn::{RecvStream, SendStream, StreamId};
+use smallvec::SmallVec;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore, TryAcquireError};
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+
+/// Largest window a server allows unless configured otherwise.
+pub const DEFAULT_MAX_EVENT_WINDOW: u32 = 256;
+
+// An event written but not yet acknowledged. It holds its slot in the
+// window until the ack arrives, even if nobody waits for it any more.
+struct InFlight {
+    event_id: u32,
+    ack: oneshot::Sender<Result<u32, ProtonError>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    in_flight: VecDeque<InFlight>,
+    // Set once the event stream has failed
+    closed: bool,
+}
+
+// State shared by the window and its ack reader
+struct Shared {
+    queue: Mutex<Queue>,
+    // One per free slot in the window
+    permits: Semaphore,
+    inspector: Mutex<Option<Arc<dyn FrameInspector>>>,
+    states: StreamStates,
+    id: StreamId,
+}
+
+impl Shared {
+    // The stream awaits the oldest ack in flight, if any
+    fn update_state(&self, queue: &Queue) {
+        let state = match queue.in_flight.front() {
+            Some(oldest) => StreamState::AwaitingResponse(oldest.event_id),
+            None => StreamState::Ready,
+        };
+        self.states.enter(self.id, state);
+    }
+
+    // Fails every event in flight and any waiting for room
+    fn close(&self, reason: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        queue.in_flight.clear();
+        self.permits.close();
+        self.states
+            .enter(self.id, StreamState::Errored(reason.to_string()));
+    }
+}
+
+/// The event stream of a connection in windowed mode.
+pub(crate) struct EventWindow {
+    send: SendStream,
+    framing: Framing,
+    // Whether each event is followed by a headers section
+    headers: bool,
+    size: u32,
+    shared: Arc<Shared>,
+    // Artificial latency added before each write
+    pub(crate) delay: Duration,
+    stalls: Arc<AtomicU64>,
+    sent: SentFrames,
+}
+
+impl EventWindow {
+    /// Takes over an event stream, reading its acks from a background task.
+    pub(crate) fn start(
+        send: SendStream,
+        recv: RecvStream,
+        framing: Framing,
+        headers: bool,
+        size: u32,
+        states: StreamStates,
+        sent: SentFrames,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            permits: Semaphore::new(size as usize),
+            inspector: Mutex::new(None),
+            states,
+            id: send.id(),
+        });
+        spawn_named("event acks", read_acks(recv, framing, Arc::clone(&shared)));
+        Self {
+            send,
+            framing,
+            headers,
+            size,
+            shared,
+            delay: Duration::ZERO,
+            stalls: Arc::new(AtomicU64::new(0)),
+            sent,
+        }
+    }
+
+    pub(crate) fn set_inspector(&self, inspector: Option<Arc<dyn FrameInspector>>) {
+        *self.shared.inspector.lock().unwrap() = inspector;
+    }
+
+    pub(crate) fn inspector(&self) -> Option<Arc<dyn FrameInspector>> {
+        self.shared.inspector.lock().unwrap().clone()
+    }
+
+    pub(crate) fn stats(&self) -> WindowStats {
+        WindowStats {
+            size: self.size,
+            in_flight: self.shared.queue.lock().unwrap().in_flight.len() as u32,
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Writes `event` once the window has room for it, returning a future
+    /// resolving with its ack.
+    pub(crate) async fn submit(
+        &mut self,
+        event: &Frame,
+        headers: &Headers,
+    ) -> Result<PendingAck, ProtonError> {
+        let permits = &self.shared.permits;
+        let permit = match permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => {
+                self.stalls.fetch_add(1, Ordering::Relaxed);
+                match timeout(STREAM_TIMEOUT, permits.acquire()).await {
+                    Ok(Ok(permit)) => permit,
+                    Ok(Err(_)) => return Err(ProtonError::ConnectionError),
+                    Err(_) => return Err(ProtonError::Timeout),
+                }
+            }
+            Err(TryAcquireError::Closed) => return Err(ProtonError::ConnectionError),
+        };
+
+        let mut frame: SmallVec<[u8; 64]> = SmallVec::new();
+        self.framing
+            .encode_into(MessageKind::Event, event, &mut frame)?;
+        let body_len = frame.len();
+        if self.headers {
+            headers.encode_into(&mut frame);
+        }
+        if let Some(ref inspector) = *self.shared.inspector.lock().unwrap() {
+            let mut shown: SmallVec<[u8; 64]> = SmallVec::from_slice(&encode_u32(event.id));
+            shown.extend_from_slice(&frame[body_len..]);
+            inspector.on_frame(Direction::Sent, STREAM_EVENT, &shown);
+        }
+        if !self.delay.is_zero() {
+            sleep(self.delay).await;
+        }
+
+        // Queued before it is written, so its ack cannot arrive first
+        let (ack, pending) = oneshot::channel();
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.closed {
+                return Err(ProtonError::ConnectionError);
+            }
+            // Given back by the ack reader once the ack arrives
+            permit.forget();
+            queue.in_flight.push_back(InFlight {
+                event_id: event.id,
+                ack,
+            });
+            self.shared.update_state(&queue);
+        }
+        match timeout(STREAM_TIMEOUT, self.send.write_all(&frame)).await {
+            Ok(Ok(())) => {
+                self.sent.record(1, frame.len());
+                Ok(PendingAck::new(event.id, pending))
+            }
+            // The stream is unusable once a write failed part way
+            Ok(Err(e)) => {
+                self.shared.close(&e.to_string());
+                Err(e.into())
+            }
+            Err(_) => {
+                self.shared.close("timed out writing an event");
+                Err(ProtonError::Timeout)
+            }
+        }
+    }
+}
+
+// The violation if `ack`, read at `offset` on the event stream, does not
+// answer `event_id`, the oldest event in flight. A refusal for being over
+// quota answers any event.
+fn ack_violation(offset: u64, event_id: u32, ack: u32) -> Option<Violation> {
+    (ack != event_id && ack != QUOTA_EXCEEDED).then(|| {
+        Violation::new(
+            "event",
+            offset,
+            format!("ack {}", event_id),
+            format!("ack {}", ack),
+        )
+    })
+}
+
+async fn read_acks(mut recv: RecvStream, framing: Framing, shared: Arc<Shared>) {
+    // Bytes of acks read so far, where the next one starts
+    let mut offset = 0u64;
+    let reason = loop {
+        let response = match framing.read_from(MessageKind::Ack, &mut recv).await {
+            Ok(response) => response,
+            Err(e) => {
+                let in_flight = shared.queue.lock().unwrap().in_flight.len();
+                if in_flight > 0 {
+                    warn!(
+                        "Event stream failed with {} events in flight: {}",
+                        in_flight, e
+                    );
+                }
+                break e.to_string();
+            }
+        };
+        if let Some(ref inspector) = *shared.inspector.lock().unwrap() {
+            inspector.on_frame(Direction::Received, STREAM_EVENT, &encode_u32(response.id));
+        }
+        let in_flight = {
+            let mut queue = shared.queue.lock().unwrap();
+            let in_flight = queue.in_flight.pop_front();
+            shared.update_state(&queue);
+            in_flight
+        };
+        let Some(in_flight) = in_flight else {
+            warn!("Ack {} received with no event in flight", response.id);
+            break format!("ack {} received with no event in flight", response.id);
+        };
+        if let Some(violation) = ack_violation(offset, in_flight.event_id, response.id) {
+            warn!("{}", violation);
+            let reason = violation.to_string();
+            let _ = in_flight
+                .ack
+                .send(Err(ProtonError::ProtocolViolation(violation)));
+            break reason;
+        }
+        offset += framing.encoded_len(MessageKind::Ack, &response) as u64;
+        shared.permits.add_permits(1);
+        // Nobody waits for an event whose ack timed out
+        let _ = in_flight.ack.send(decode_response(encode_u32(response.id)));
+    };
+    shared.close(&reason);
+    info!("Event ack reader stopped");
+}
+
+/// Resolves with the ack of an event sent in windowed mode, or fails if it
+/// does not arrive within `STREAM_TIMEOUT` or the connection is lost first.
+/// A timed out event still holds its slot in the window until its ack
+/// arrives.
+pub struct PendingAck {
+    event_id: u32,
+    ack: Pin<Box<dyn Future<Output = Result<u32, ProtonError>> + Send>>,
+}
+
+impl PendingAck {
+    fn new(event_id: u32, ack: oneshot::Receiver<Result<u32, ProtonError>>) -> Self {
+        let ack = async move {
+            match timeout(STREAM_TIMEOUT, ack).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(ProtonError::ConnectionError),
+                Err(_) => Err(ProtonError::Timeout),
+            }
+        };
+        Self {
+            event_id,
+            ack: Box::pin(ack),
+        }
+    }
+
+    // An ack already received, for events sent without a window
+    pub(crate) fn ready(event_id: u32, ack: Result<u32, ProtonError>) -> Self {
+        Self {
+            event_id,
+            ack: Box::pin(std::future::ready(ack)),
+        }
+    }
+
+    pub fn event_id(&self) -> u32 {
+        self.event_id
+    }
+}
+
+impl Future for PendingAck {
+    type Output = Result<u32, ProtonError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.ack.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for PendingAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingAck")
+            .field("event_id", &self.event_id)
+            .finish()
+    }
+}
+
+/// Occupancy of a connection's event window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Events the server allows in flight
+    pub size: u32,
+    pub in_flight: u32,
+    /// Sends that waited for room in the window
+    pub stalls: u64,
+}
+
+impl fmt::Display for WindowStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} events in flight, {} stalls",
+            self.in_flight, self.size, self.stalls
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_must_answer_the_oldest_event() {
+        assert_eq!(ack_violation(0, 7, 7), None);
+        let violation = ack_violation(8, 7, 8).unwrap();
+        assert_eq!(violation, Violation::new("event", 8, "ack 7", "ack 8"));
+        assert_eq!(
+            violation.to_string(),
+            "protocol violation on event stream at offset 8: expected ack 7, received ack 8"
+        );
+    }
+
+    #[test]
+    fn quota_refusal_answers_any_event() {
+        assert_eq!(ack_violation(0, 7, QUOTA_EXCEEDED), None);
+        assert_eq!(ack_violation(0, QUOTA_EXCEEDED, QUOTA_EXCEEDED), None);
+    }
+
+    #[test]
+    fn ids_wrap_without_violation() {
+        assert_eq!(ack_violation(0, u32::MAX - 1, u32::MAX - 1), None);
+        assert_eq!(ack_violation(4, 0, 0), None);
+        assert!(ack_violation(4, 0, u32::MAX - 1).is_some());
+    }
+}
@@ -0,0 +1,218 @@
+//! Every value that appears on the wire, in one place, with the helpers
+//! that encode and decode them. Integers are little-endian throughout.
+//!
+//! These values are the protocol: changing one breaks every deployed peer,
+//! so `tests/wire.rs` pins each of them.
+
+use crate::proton::ProtonError;
+
+/// Version of the stream layout and framing spoken by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Stream discriminators, the first byte written on each stream
+pub const STREAM_EVENT: u8 = 1;
+pub const STREAM_STATE_COMMIT: u8 = 2;
+pub const STREAM_ACTION: u8 = 3;
+pub const STREAM_AUTH: u8 = 4;
+pub const STREAM_CONTROL: u8 = 5;
+pub const STREAM_PAYLOAD: u8 = 6;
+pub const STREAM_HEARTBEAT: u8 = 7;
+/// Unidirectional stream the server opens to push actions on, when the
+/// client asked for pushed actions in its HELLO.
+pub const STREAM_ACTION_PUSH: u8 = 8;
+
+/// Set on a stream's discriminator byte when every request frame on that
+/// stream is followed by a headers section.
+pub const FLAG_HEADERS: u8 = 0x80;
+
+/// Sent instead of the response when the tenant has used up its quota.
+pub const QUOTA_EXCEEDED: u32 = u32::MAX;
+
+/// Set on the id in a state commit frame to abort that commit instead of
+/// making it. Commit ids therefore use the low 31 bits.
+pub const ABORT_COMMIT: u32 = 0x8000_0000;
+
+/// Sent instead of the commit id when an abort is refused, e.g. because the
+/// commit is unknown or its compensation failed.
+pub const ABORT_REFUSED: u32 = u32::MAX - 1;
+
+/// Event id (u32) and payload length (u64) opening a payload stream.
+pub const PAYLOAD_HEADER_LEN: usize = 12;
+
+/// First byte of a heartbeat frame asking to be echoed, and of its echo.
+/// The kind is followed by a u32 sequence number and the sender's u64
+/// timestamp in microseconds, which the echo carries back unchanged.
+pub const HEARTBEAT_PING: u8 = 0;
+pub const HEARTBEAT_PONG: u8 = 1;
+pub const HEARTBEAT_FRAME_LEN: usize = 13;
+
+/// Largest body of a length-prefixed frame: the u32 id and its payload.
+pub const MAX_FRAME_LEN: u32 = 1 << 20;
+
+/// First byte of a typed frame's body, the version of the message encoding.
+pub const MESSAGE_VERSION: u8 = 1;
+
+// Connection close codes
+pub const CLOSE_NORMAL: u32 = 0;
+pub const CLOSE_STREAM_SETUP: u32 = 1;
+pub const CLOSE_STREAM_ACCEPT: u32 = 2;
+pub const CLOSE_SETUP_TIMEOUT: u32 = 3;
+pub const CLOSE_STREAM_TIMEOUT: u32 = 4;
+pub const CLOSE_STREAM_ERROR: u32 = 5;
+pub const CLOSE_AUTH_FAILED: u32 = 6;
+/// A higher priority client took the connection's slot.
+pub const CLOSE_PREEMPTED: u32 = 7;
+/// The server is at its connection limit and nothing could be preempted.
+pub const CLOSE_AT_CAPACITY: u32 = 8;
+/// An operator closed the connection through the control API.
+pub const CLOSE_BY_OPERATOR: u32 = 9;
+/// A newer connection from the same client took over under the
+/// `replace-old` duplicate policy.
+pub const CLOSE_REPLACED: u32 = 11;
+/// The client already has as many connections as the duplicate policy
+/// allows.
+pub const CLOSE_DUPLICATE: u32 = 12;
+/// The client deviated from the protocol; the close reason says how.
+pub const CLOSE_PROTOCOL_VIOLATION: u32 = 13;
+/// The client stopped reading a stream for longer than the server's stall
+/// timeout. Also the code the stream is reset with under `StallPolicy::Reset`.
+pub const CLOSE_PEER_STALLED: u32 = 14;
+/// The peer answered no heartbeat for several heartbeat intervals.
+pub const CLOSE_HEARTBEAT_LOST: u32 = 15;
+/// The server is restarting and stopped waiting for the connection to
+/// finish; reconnecting reaches the process that took over.
+pub const CLOSE_SERVER_RESTARTING: u32 = 16;
+/// The connection reached the server's maximum connection age and was not
+/// closed by the client within the grace period of its GOAWAY; reconnecting
+/// starts a fresh one.
+pub const CLOSE_MAX_AGE: u32 = 17;
+
+/// First byte of a datagram that only keeps the connection alive.
+pub const DATAGRAM_KEEPALIVE: u8 = 0;
+/// First byte of a datagram carrying telemetry, opaque to Proton.
+pub const DATAGRAM_TELEMETRY: u8 = 1;
+
+/// Error code a stream is reset with when the server misbehaves on purpose.
+pub const RESET_BY_MISBEHAVIOR: u32 = 10;
+
+// HELLO keys, exchanged on the control stream
+pub const KEY_USER_AGENT: &str = "user-agent";
+pub const KEY_CRATE_VERSION: &str = "crate-version";
+pub const KEY_PROTOCOL: &str = "protocol";
+pub const KEY_OS: &str = "os";
+pub const KEY_ARCH: &str = "arch";
+pub const KEY_PRIORITY: &str = "priority";
+pub const KEY_TENANT: &str = "tenant";
+pub const KEY_CLIENT_ID: &str = "client-id";
+pub const KEY_EVENT_ORDERING: &str = "event-ordering";
+pub const KEY_ID_SCHEME: &str = "id-scheme";
+pub const KEY_FRAMING: &str = "framing";
+pub const KEY_EXPERIMENT: &str = "experiment";
+pub const KEY_COMPRESSION: &str = "compression";
+pub const KEY_EVENT_WINDOW: &str = "event-window";
+pub const KEY_HEARTBEAT: &str = "heartbeat";
+pub const KEY_ACTION_PUSH: &str = "action-push";
+pub const KEY_LAST_EVENT_ID: &str = "last-event-id";
+pub const KEY_REPLAY_FROM: &str = "replay-from";
+
+// Settings keys, pushed by the server on the control stream
+pub const KEY_EVENT_BATCH_SIZE: &str = "event-batch-size";
+pub const KEY_EVENT_RATE_LIMIT: &str = "event-rate-limit";
+pub const KEY_ACK_MODE: &str = "ack-mode";
+pub const KEY_POWER_MODE: &str = "power-mode";
+
+// Compression dictionary keys, pushed by the server on the control stream.
+// The dictionary's bytes are hex encoded across `dictionary-0` onwards.
+pub const KEY_DICTIONARY_ID: &str = "dictionary-id";
+pub const KEY_DICTIONARY: &str = "dictionary";
+
+// GOAWAY key, pushed by the server on the control stream alongside the
+// current settings. The value is the grace period in milliseconds before the
+// server closes the connection; the client should reconnect within it.
+pub const KEY_GOAWAY: &str = "goaway";
+
+/// Dictionary id opening an event payload that was sent uncompressed.
+pub const DICTIONARY_NONE: u8 = 0;
+
+/// Schema version (u16) opening a payload encoded by a `schema::Schema`.
+pub const SCHEMA_VERSION_LEN: usize = 2;
+
+/// The first byte of a `kind` stream, flagged if its frames carry headers.
+pub fn encode_discriminator(kind: u8, headers: bool) -> u8 {
+    if headers {
+        kind | FLAG_HEADERS
+    } else {
+        kind
+    }
+}
+
+/// Splits a discriminator byte into the stream kind and the headers flag.
+pub fn decode_discriminator(byte: u8) -> (u8, bool) {
+    (byte & !FLAG_HEADERS, byte & FLAG_HEADERS != 0)
+}
+
+/// A request or response frame: an id, offset or ack.
+pub fn encode_u32(value: u32) -> [u8; 4] {
+    value.to_le_bytes()
+}
+
+pub fn decode_u32(bytes: [u8; 4]) -> u32 {
+    u32::from_le_bytes(bytes)
+}
+
+/// A response frame, or the error a sentinel in its place stands for.
+pub fn decode_response(bytes: [u8; 4]) -> Result<u32, ProtonError> {
+    match decode_u32(bytes) {
+        QUOTA_EXCEEDED => Err(ProtonError::QuotaExceeded),
+        response => Ok(response),
+    }
+}
+
+/// The frame making, or with `abort` aborting, `commit_id`.
+pub fn encode_commit(commit_id: u32, abort: bool) -> u32 {
+    if abort {
+        commit_id | ABORT_COMMIT
+    } else {
+        commit_id
+    }
+}
+
+/// Splits a state commit frame into the commit id and whether it is an
+/// abort.
+pub fn decode_commit(frame: u32) -> (u32, bool) {
+    (frame & !ABORT_COMMIT, frame & ABORT_COMMIT != 0)
+}
+
+pub fn encode_payload_header(event_id: u32, len: u64) -> [u8; PAYLOAD_HEADER_LEN] {
+    let mut header = [0u8; PAYLOAD_HEADER_LEN];
+    header[..4].copy_from_slice(&event_id.to_le_bytes());
+    header[4..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+/// The event id and payload length from a payload stream's header.
+pub fn decode_payload_header(header: [u8; PAYLOAD_HEADER_LEN]) -> (u32, u64) {
+    let event_id = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u64::from_le_bytes(header[4..].try_into().unwrap());
+    (event_id, len)
+}
+
+/// A payload whose body was encoded with schema `version`.
+pub fn encode_versioned(version: u16, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(SCHEMA_VERSION_LEN + body.len());
+    payload.extend_from_slice(&version.to_le_bytes());
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Splits a versioned payload into its schema version and body.
+pub fn decode_versioned(payload: &[u8]) -> Result<(u16, &[u8]), ProtonError> {
+    if payload.len() < SCHEMA_VERSION_LEN {
+        return Err(ProtonError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("payload of {} bytes has no schema version", payload.len()),
+        )));
+    }
+    let (version, body) = payload.split_at(SCHEMA_VERSION_LEN);
+    Ok((u16::from_le_bytes(version.try_into().unwrap()), body))
+}
@@ -0,0 +1,192 @@
+//! `server` zero-downtime restarts. On SIGUSR2 the server starts a new copy
+//! of its binary with the same arguments, which inherits the listening
+//! socket through `--socket-fd`. Once the new process listens, it says so
+//! on the pipe passed as `--ready-fd` and the old one drains; if it exits
+//! first, the old one carries on serving. A socket from systemd socket
+//! activation is picked up the same way.
+
+use quic_rs_debug::{ProtonError, ProtonServer};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::UdpSocket;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+// First descriptor systemd passes with socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// How long the new process has to start listening
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn check_udp_socket(fd: RawFd) -> Result<(), ProtonError> {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if kind != libc::SOCK_DGRAM {
+        return Err(ProtonError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("descriptor {} is not a UDP socket", fd),
+        )));
+    }
+    Ok(())
+}
+
+/// The listening socket passed as `--socket-fd`, or by systemd socket
+/// activation, if any.
+pub fn inherited_socket(fd: Option<RawFd>) -> Result<Option<UdpSocket>, ProtonError> {
+    let fd = match fd {
+        Some(fd) => fd,
+        None => {
+            // Only meant for this process, not for ones it starts
+            let pid = std::env::var("LISTEN_PID").ok();
+            let fds = std::env::var("LISTEN_FDS").ok();
+            std::env::remove_var("LISTEN_PID");
+            std::env::remove_var("LISTEN_FDS");
+            let ours = pid.and_then(|pid| pid.parse().ok()) == Some(std::process::id());
+            match fds.and_then(|fds| fds.parse::<u32>().ok()) {
+                Some(fds) if ours && fds > 0 => {
+                    if fds > 1 {
+                        warn!("systemd passed {} sockets; serving on the first", fds);
+                    }
+                    SD_LISTEN_FDS_START
+                }
+                _ => return Ok(None),
+            }
+        }
+    };
+    check_udp_socket(fd)?;
+    // Owned by nothing else in this process, it was inherited
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    info!(
+        "Serving on inherited socket {} bound to {}",
+        fd,
+        socket.local_addr()?
+    );
+    Ok(Some(socket))
+}
+
+/// Tells the process handing over to this one, through `--ready-fd`, that
+/// the server listens.
+pub fn notify_ready(server: &ProtonServer, fd: RawFd) {
+    let mut listening = server.listening();
+    // Owned by nothing else in this process, it was inherited
+    let mut ready = unsafe { File::from_raw_fd(fd) };
+    tokio::spawn(async move {
+        if listening.wait_for(|listening| *listening).await.is_ok() {
+            if let Err(e) = ready.write_all(&[1]) {
+                warn!("Failed to tell the previous server we listen: {}", e);
+            }
+        }
+    });
+}
+
+// Our own arguments, without the descriptors a previous handover added
+fn successor_args() -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut skip = false;
+    for arg in std::env::args_os().skip(1) {
+        let text = arg.to_string_lossy();
+        if std::mem::take(&mut skip) {
+            continue;
+        }
+        match text.as_ref() {
+            "--socket-fd" | "--ready-fd" => skip = true,
+            _ if text.starts_with("--socket-fd=") || text.starts_with("--ready-fd=") => {}
+            _ => args.push(arg),
+        }
+    }
+    args
+}
+
+// Starts the new process, returning once it listens
+async fn hand_over(socket_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let (ready, notify) = unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let notify_fd = notify.as_raw_fd();
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(successor_args())
+        .arg("--socket-fd")
+        .arg(socket_fd.to_string())
+        .arg("--ready-fd")
+        .arg(notify_fd.to_string());
+    // Both descriptors are close-on-exec, except in the new process
+    unsafe {
+        command.pre_exec(move || {
+            for fd in [socket_fd, notify_fd] {
+                if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // The new process holds the only write end now, so the pipe ends with it
+    drop(notify);
+    info!("Started server {} to take over", child.id());
+
+    let read = tokio::task::spawn_blocking(move || {
+        let mut byte = [0u8; 1];
+        (&ready).read(&mut byte)
+    });
+    let ready: Result<(), Box<dyn Error>> = match timeout(READY_TIMEOUT, read).await {
+        Ok(Ok(Ok(1))) => return Ok(()),
+        Ok(Ok(Ok(_))) => Err(format!("server {} exited before listening", child.id()).into()),
+        Ok(Ok(Err(e))) => Err(e.into()),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(format!(
+            "server {} did not listen within {:?}",
+            child.id(),
+            READY_TIMEOUT
+        )
+        .into()),
+    };
+    // It must not share the socket with us after all
+    let _ = child.kill();
+    let _ = child.wait();
+    ready
+}
+
+/// Hands the server's socket over to a new process on each SIGUSR2, and
+/// drains the server once that process listens.
+pub fn handle_handover_signal(server: &ProtonServer) -> Result<(), Box<dyn Error>> {
+    let socket_fd = server.socket_fd();
+    let drain = server.drain_handle();
+    let mut user2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while user2.recv().await.is_some() {
+            if drain.is_draining() {
+                continue;
+            }
+            info!("Handing the listening socket over to a new server");
+            match hand_over(socket_fd).await {
+                Ok(()) => drain.drain(),
+                Err(e) => error!("Handover failed, still serving: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! `client --reconnect-storm`: many connections opened and closed at once,
+//! at random moments, to exercise the server's admission control, its
+//! cleanup of closed connections and its rejection paths.
+
+use quic_rs_debug::proton::retry::RetryPolicy;
+use quic_rs_debug::{ProtonClient, ProtonError};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+
+// How one connection of the storm ended
+enum Outcome {
+    // Sent an event and held the connection until closing it
+    Accepted,
+    // The server turned it away while it was being established
+    Refused,
+    // The server closed it, with this reason
+    Closed(String),
+    // Never established, or lost without a close from the server
+    Failed(String),
+}
+
+/// Counts of how the storm's connections ended.
+#[derive(Default)]
+pub struct StormSummary {
+    connections: u32,
+    elapsed: Duration,
+    accepted: u32,
+    refused: u32,
+    closed: BTreeMap<String, u32>,
+    failed: BTreeMap<String, u32>,
+}
+
+impl fmt::Display for StormSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Reconnect storm: {} connections in {:.2?}",
+            self.connections, self.elapsed
+        )?;
+        write!(f, "  accepted: {}", self.accepted)?;
+        write!(f, "\n  refused: {}", self.refused)?;
+        for (title, counts) in [
+            ("closed by the server", &self.closed),
+            ("failed", &self.failed),
+        ] {
+            write!(f, "\n  {}: {}", title, counts.values().sum::<u32>())?;
+            for (reason, count) in counts {
+                write!(f, "\n    {}: {}", reason, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Uniformly random up to `max`
+fn jitter(max: Duration) -> Duration {
+    let mut bytes = [0u8; 4];
+    // Without randomness, everything happens at once
+    let _ = SystemRandom::new().fill(&mut bytes);
+    max.mul_f64(f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX))
+}
+
+async fn attempt(mut client: ProtonClient, server_addr: SocketAddr, spread: Duration) -> Outcome {
+    sleep(jitter(spread)).await;
+    let mut connection = match client.connect(server_addr, Some(Duration::ZERO)).await {
+        Ok(connection) => connection,
+        Err(ProtonError::ConnectionRefused) => return Outcome::Refused,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    // A connection closed right after it was admitted only shows on its
+    // first request
+    let outcome = match connection.send_event().await {
+        Ok(_) => {
+            sleep(jitter(spread)).await;
+            // An admitted connection may still be preempted or replaced
+            match connection.close_reason() {
+                Some(reason) => Outcome::Closed(reason),
+                None => Outcome::Accepted,
+            }
+        }
+        Err(e) => match (connection.close_code(), connection.close_reason()) {
+            (Some(_), Some(reason)) => Outcome::Closed(reason),
+            _ => Outcome::Failed(e.to_string()),
+        },
+    };
+    connection.close().await;
+    outcome
+}
+
+/// Opens `connections` connections to `server_addr`, each at a random
+/// moment within `spread` and held for a random time within it, then
+/// closed. Failed connects are not retried.
+pub async fn run(
+    client: ProtonClient,
+    server_addr: SocketAddr,
+    connections: u32,
+    spread: Duration,
+) -> Result<StormSummary, Box<dyn Error>> {
+    let client = client.with_connect_retry(RetryPolicy {
+        max_attempts: 1,
+        ..RetryPolicy::connect()
+    })?;
+    let started = Instant::now();
+    let mut attempts = JoinSet::new();
+    for _ in 0..connections {
+        attempts.spawn(attempt(client.clone(), server_addr, spread));
+    }
+
+    let mut summary = StormSummary {
+        connections,
+        ..StormSummary::default()
+    };
+    while let Some(outcome) = attempts.join_next().await {
+        match outcome? {
+            Outcome::Accepted => summary.accepted += 1,
+            Outcome::Refused => summary.refused += 1,
+            Outcome::Closed(reason) => *summary.closed.entry(reason).or_default() += 1,
+            Outcome::Failed(error) => *summary.failed.entry(error).or_default() += 1,
+        }
+    }
+    summary.elapsed = started.elapsed();
+    Ok(summary)
+}
@@ -0,0 +1,37 @@
+//! Pins the library's public surface: the types the binaries are built on
+//! are reachable from the crate root and can live in user structs and move
+//! between tasks.
+
+use quic_rs_debug::proton::drain::DrainHandle;
+use quic_rs_debug::proton::eventlog::EventLog;
+use quic_rs_debug::proton::push::ActionStream;
+use quic_rs_debug::{ProtonClient, ProtonConfig, ProtonConnection, ProtonError, ProtonServer};
+
+fn assert_owned<T: Send + 'static>() {}
+
+#[test]
+fn public_types_are_owned_and_send() {
+    assert_owned::<ProtonClient>();
+    assert_owned::<ProtonServer>();
+    assert_owned::<ProtonConnection>();
+    assert_owned::<ProtonError>();
+    assert_owned::<ProtonConfig>();
+    assert_owned::<ActionStream>();
+    assert_owned::<EventLog>();
+    assert_owned::<DrainHandle>();
+}
+
+#[test]
+fn connection_moves_into_a_task() {
+    // Compiles only if a connection can be held across an await in a
+    // spawned task
+    #[allow(dead_code)]
+    struct App {
+        connection: ProtonConnection,
+    }
+    #[allow(dead_code)]
+    async fn run(mut app: App) -> Result<u32, ProtonError> {
+        app.connection.send_event().await
+    }
+    let _ = |app: App| tokio::spawn(run(app));
+}
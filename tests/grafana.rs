@@ -0,0 +1,23 @@
+//! The shipped dashboard must be regenerated whenever a metric is added or
+//! changed: `quic-rs-debug grafana > dashboards/proton.json`.
+
+use quic_rs_debug::proton::grafana::dashboard;
+use quic_rs_debug::proton::metrics::METRICS;
+
+#[test]
+fn shipped_dashboard_is_up_to_date() {
+    let shipped = include_str!("../dashboards/proton.json");
+    assert_eq!(shipped, dashboard(METRICS));
+}
+
+#[test]
+fn every_metric_has_a_panel() {
+    let generated = dashboard(METRICS);
+    for metric in METRICS {
+        assert!(
+            generated.contains(metric.name),
+            "no panel for {}",
+            metric.name
+        );
+    }
+}
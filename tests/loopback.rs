@@ -0,0 +1,391 @@
+//! End-to-end tests: a real server and client talking over loopback, for
+//! behaviour that only shows once both ends and QUIC are involved.
+
+use futures_core::Stream;
+use quic_rs_debug::proton::admission::{DuplicatePolicy, CLOSE_PREEMPTED, CLOSE_REPLACED};
+use quic_rs_debug::proton::handler::EchoHandler;
+use quic_rs_debug::proton::payload::{FileSink, PayloadHandler, PAYLOAD_CHUNK_SIZE};
+use quic_rs_debug::proton::push::ActionStream;
+use quic_rs_debug::proton::{CloseReason, Frame};
+use quic_rs_debug::{ProtonClient, ProtonConfig, ProtonConnection, ProtonError, ProtonServer};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long anything the tests wait on may take before they fail
+const WAIT: Duration = Duration::from_secs(5);
+
+// A server on a free loopback port with a certificate for "localhost",
+// returned so that raw QUIC clients can trust it
+fn server() -> (ProtonServer, SocketAddr, rustls::Certificate) {
+    let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let der = rustls::Certificate(cert.serialize_der().unwrap());
+    let server = ProtonServer::new(
+        addr,
+        der.clone(),
+        rustls::PrivateKey(cert.serialize_private_key_der()),
+        Arc::new(EchoHandler),
+    )
+    .unwrap()
+    .with_config(ProtonConfig {
+        startup_delay: Duration::ZERO,
+        ..ProtonConfig::default()
+    })
+    .unwrap();
+    (server, addr, der)
+}
+
+async fn serve(server: ProtonServer) {
+    let mut listening = server.listening();
+    tokio::spawn(async move { server.run().await });
+    listening.wait_for(|listening| *listening).await.unwrap();
+}
+
+// A client that makes one connection attempt
+fn client() -> ProtonClient {
+    ProtonClient::new("127.0.0.1:0".parse().unwrap())
+        .unwrap()
+        .with_config(ProtonConfig {
+            connect_retries: 0,
+            ..ProtonConfig::default()
+        })
+        .unwrap()
+}
+
+// The code the server closed `connection` with, once it has
+async fn close_code(connection: &ProtonConnection) -> u32 {
+    let started = Instant::now();
+    loop {
+        if let Some(code) = connection.close_code() {
+            return code;
+        }
+        assert!(
+            started.elapsed() < WAIT,
+            "the server never closed the connection"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn silent_psk_clients_are_closed_at_the_handshake_timeout() {
+    let deadline = Duration::from_millis(300);
+    let (server, addr, cert) = server();
+    serve(
+        server
+            .with_psk(b"shared secret".to_vec())
+            .unwrap()
+            .with_handshake_timeout(deadline)
+            .with_connection_limit(2, false)
+            .unwrap(),
+    )
+    .await;
+
+    // A client with the key authenticates and is served
+    let mut connection = client()
+        .with_psk(b"shared secret".to_vec())
+        .unwrap()
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    assert_eq!(connection.send_event().await.unwrap(), 1);
+
+    // One that completes the TLS handshake and then sends nothing is
+    // dropped once the handshake timeout runs out
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&cert).unwrap();
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"proton".to_vec()];
+    let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+    let silent = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+    let connected = Instant::now();
+    let closed = tokio::time::timeout(WAIT, silent.closed()).await.unwrap();
+    assert_eq!(
+        CloseReason::from_error(&closed),
+        Some(CloseReason::AuthFailed)
+    );
+    assert!(connected.elapsed() >= deadline / 2);
+}
+
+#[tokio::test]
+async fn higher_priority_clients_preempt_lower_ones_at_capacity() {
+    let (server, addr, _) = server();
+    let server = server.with_connection_limit(1, true).unwrap();
+    let metrics = server.metrics();
+    serve(server).await;
+
+    let low = client()
+        .with_priority(1)
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    let mut high = client()
+        .with_priority(5)
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    assert_eq!(close_code(&low).await, CLOSE_PREEMPTED);
+
+    // Nobody connected has a lower priority than this one, so it is turned
+    // away while its streams are set up. Until the preempted connection has
+    // drained, QUIC refuses it even before that.
+    let started = Instant::now();
+    while metrics
+        .connections_rejected_admission
+        .load(Ordering::Relaxed)
+        == 0
+    {
+        assert!(started.elapsed() < WAIT, "the client was never turned away");
+        let rejected = client()
+            .with_priority(1)
+            .connect(addr, Some(Duration::ZERO))
+            .await;
+        assert!(matches!(rejected, Err(ProtonError::ConnectionRefused)));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        metrics
+            .connections_rejected_admission
+            .load(Ordering::Relaxed),
+        1
+    );
+    assert_eq!(metrics.connections_accepted.load(Ordering::Relaxed), 2);
+    assert_eq!(high.close_code(), None);
+    assert_eq!(high.send_event().await.unwrap(), 1);
+}
+
+// An empty directory of its own for each test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("proton-loopback-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+// Bytes that differ from chunk to chunk, so a chunk written in the wrong
+// place shows
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn payloads_stream_to_the_handler_in_chunks() {
+    let dir = scratch("stream");
+    let (server, addr, _) = server();
+    serve(server.with_payload_handler(Arc::new(FileSink::new(&dir).unwrap()))).await;
+
+    let data = payload(3 * PAYLOAD_CHUNK_SIZE + 1000);
+    let mut connection = client().connect(addr, Some(Duration::ZERO)).await.unwrap();
+    let ack = connection
+        .send_event_stream(&data[..], data.len() as u64)
+        .await
+        .unwrap();
+    assert_eq!(ack, connection.last_event_id());
+    let received = dir.join("127.0.0.1").join(format!("event-{}.bin", ack));
+    assert_eq!(std::fs::read(received).unwrap(), data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Hands payloads on to a file sink, noting where each transfer started and
+// whether it was aborted
+struct Recorder {
+    sink: FileSink,
+    seen: Mutex<Vec<String>>,
+}
+
+impl PayloadHandler for Recorder {
+    fn on_start(
+        &self,
+        client: &str,
+        event_id: u32,
+        len: u64,
+        offset: u64,
+    ) -> Result<(), ProtonError> {
+        self.seen
+            .lock()
+            .unwrap()
+            .push(format!("start at {}", offset));
+        self.sink.on_start(client, event_id, len, offset)
+    }
+
+    fn on_chunk(
+        &self,
+        client: &str,
+        event_id: u32,
+        offset: u64,
+        chunk: &[u8],
+    ) -> Result<(), ProtonError> {
+        self.sink.on_chunk(client, event_id, offset, chunk)
+    }
+
+    fn on_complete(&self, client: &str, event_id: u32, len: u64) -> Result<(), ProtonError> {
+        self.seen.lock().unwrap().push("complete".to_string());
+        self.sink.on_complete(client, event_id, len)
+    }
+
+    fn on_abort(&self, client: &str, event_id: u32) {
+        self.seen.lock().unwrap().push("abort".to_string());
+        self.sink.on_abort(client, event_id)
+    }
+}
+
+#[tokio::test]
+async fn interrupted_payloads_resume_after_the_verified_chunks() {
+    let dir = scratch("resume");
+    let recorder = Arc::new(Recorder {
+        sink: FileSink::new(&dir).unwrap(),
+        seen: Mutex::new(Vec::new()),
+    });
+    let (server, addr, _) = server();
+    serve(
+        server
+            .with_payload_handler(Arc::clone(&recorder) as Arc<dyn PayloadHandler>)
+            .with_connection_limit(2, false)
+            .unwrap(),
+    )
+    .await;
+
+    // The source runs dry part way into the third chunk
+    let data = payload(3 * PAYLOAD_CHUNK_SIZE + 1000);
+    let mut connection = client().connect(addr, Some(Duration::ZERO)).await.unwrap();
+    let cut = 2 * PAYLOAD_CHUNK_SIZE + 100;
+    assert!(connection
+        .send_event_stream(&data[..cut], data.len() as u64)
+        .await
+        .is_err());
+    let event_id = connection.last_event_id();
+    let started = Instant::now();
+    while !recorder.seen.lock().unwrap().contains(&"abort".to_string()) {
+        assert!(started.elapsed() < WAIT, "the transfer was never aborted");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Resuming from a new connection sends only the chunks after the two
+    // the server verified
+    connection.close().await;
+    let mut connection = client().connect(addr, Some(Duration::ZERO)).await.unwrap();
+    let ack = connection
+        .resume_event_stream(event_id, &data[..], data.len() as u64)
+        .await
+        .unwrap();
+    assert_eq!(ack, event_id);
+    assert_eq!(
+        *recorder.seen.lock().unwrap(),
+        [
+            "start at 0".to_string(),
+            "abort".to_string(),
+            format!("start at {}", 2 * PAYLOAD_CHUNK_SIZE),
+            "complete".to_string(),
+        ]
+    );
+    let received = dir.join("127.0.0.1").join(format!("event-{}.bin", ack));
+    assert_eq!(std::fs::read(received).unwrap(), data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn duplicate_clients_are_turned_away_under_reject_new() {
+    let (server, addr, _) = server();
+    let server = server
+        .with_connection_limit(4, false)
+        .unwrap()
+        .with_duplicate_policy(DuplicatePolicy::RejectNew)
+        .unwrap();
+    let metrics = server.metrics();
+    serve(server).await;
+
+    let mut first = client()
+        .with_client_id("sensor-1")
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    let second = client()
+        .with_client_id("sensor-1")
+        .connect(addr, Some(Duration::ZERO))
+        .await;
+    assert!(matches!(second, Err(ProtonError::ConnectionRefused)));
+    assert_eq!(
+        metrics
+            .connections_rejected_admission
+            .load(Ordering::Relaxed),
+        1
+    );
+    assert_eq!(first.send_event().await.unwrap(), 1);
+
+    // Another client is unaffected
+    client()
+        .with_client_id("sensor-2")
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn duplicate_clients_replace_their_old_connection_under_replace_old() {
+    let (server, addr, _) = server();
+    serve(
+        server
+            .with_duplicate_policy(DuplicatePolicy::ReplaceOld)
+            .unwrap(),
+    )
+    .await;
+
+    let old = client()
+        .with_client_id("sensor-1")
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    let mut new = client()
+        .with_client_id("sensor-1")
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    assert_eq!(close_code(&old).await, CLOSE_REPLACED);
+    assert_eq!(new.send_event().await.unwrap(), 1);
+}
+
+// The next action pushed, or None once the stream has ended
+async fn next_action(actions: &mut ActionStream) -> Option<Frame> {
+    std::future::poll_fn(|cx| Pin::new(&mut *actions).poll_next(cx)).await
+}
+
+#[tokio::test]
+async fn pushed_actions_stop_at_the_window_until_acknowledged() {
+    let (server, addr, _) = server();
+    serve(server).await;
+
+    let mut connection = client()
+        .with_action_push(4)
+        .unwrap()
+        .connect(addr, Some(Duration::ZERO))
+        .await
+        .unwrap();
+    let mut actions = connection.actions().unwrap();
+    for id in 1..=4 {
+        let action = tokio::time::timeout(WAIT, next_action(&mut actions))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(action.id, id);
+    }
+
+    // The window is full, so nothing more comes until some are acknowledged
+    let waited = tokio::time::timeout(Duration::from_millis(300), next_action(&mut actions)).await;
+    assert!(waited.is_err());
+    actions.ack_up_to(4);
+    let action = tokio::time::timeout(WAIT, next_action(&mut actions))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(action.id, 5);
+}
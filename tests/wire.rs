@@ -0,0 +1,338 @@
+//! Pins every on-the-wire value. A failure here means a change would break
+//! deployed peers: bump `PROTOCOL_VERSION` and update the snapshot only if
+//! that is intended.
+
+use quic_rs_debug::proton::wire::*;
+
+const SNAPSHOT: &str = "\
+PROTOCOL_VERSION=1
+STREAM_EVENT=0x01
+STREAM_STATE_COMMIT=0x02
+STREAM_ACTION=0x03
+STREAM_AUTH=0x04
+STREAM_CONTROL=0x05
+STREAM_PAYLOAD=0x06
+STREAM_HEARTBEAT=0x07
+STREAM_ACTION_PUSH=0x08
+FLAG_HEADERS=0x80
+QUOTA_EXCEEDED=0xffffffff
+ABORT_COMMIT=0x80000000
+ABORT_REFUSED=0xfffffffe
+PAYLOAD_HEADER_LEN=12
+HEARTBEAT_PING=0x00
+HEARTBEAT_PONG=0x01
+HEARTBEAT_FRAME_LEN=13
+MAX_FRAME_LEN=1048576
+MESSAGE_VERSION=0x01
+DATAGRAM_KEEPALIVE=0x00
+DATAGRAM_TELEMETRY=0x01
+CLOSE_NORMAL=0
+CLOSE_STREAM_SETUP=1
+CLOSE_STREAM_ACCEPT=2
+CLOSE_SETUP_TIMEOUT=3
+CLOSE_STREAM_TIMEOUT=4
+CLOSE_STREAM_ERROR=5
+CLOSE_AUTH_FAILED=6
+CLOSE_PREEMPTED=7
+CLOSE_AT_CAPACITY=8
+CLOSE_BY_OPERATOR=9
+RESET_BY_MISBEHAVIOR=10
+CLOSE_REPLACED=11
+CLOSE_DUPLICATE=12
+CLOSE_PROTOCOL_VIOLATION=13
+CLOSE_PEER_STALLED=14
+CLOSE_HEARTBEAT_LOST=15
+CLOSE_SERVER_RESTARTING=16
+CLOSE_MAX_AGE=17
+KEY_USER_AGENT=user-agent
+KEY_CRATE_VERSION=crate-version
+KEY_PROTOCOL=protocol
+KEY_OS=os
+KEY_ARCH=arch
+KEY_PRIORITY=priority
+KEY_TENANT=tenant
+KEY_CLIENT_ID=client-id
+KEY_EVENT_ORDERING=event-ordering
+KEY_ID_SCHEME=id-scheme
+KEY_FRAMING=framing
+KEY_EXPERIMENT=experiment
+KEY_COMPRESSION=compression
+KEY_EVENT_WINDOW=event-window
+KEY_HEARTBEAT=heartbeat
+KEY_ACTION_PUSH=action-push
+KEY_LAST_EVENT_ID=last-event-id
+KEY_REPLAY_FROM=replay-from
+KEY_EVENT_BATCH_SIZE=event-batch-size
+KEY_EVENT_RATE_LIMIT=event-rate-limit
+KEY_ACK_MODE=ack-mode
+KEY_POWER_MODE=power-mode
+KEY_DICTIONARY_ID=dictionary-id
+KEY_DICTIONARY=dictionary
+KEY_GOAWAY=goaway
+DICTIONARY_NONE=0x00
+SCHEMA_VERSION_LEN=2
+";
+
+fn current() -> String {
+    let mut out = format!("PROTOCOL_VERSION={}\n", PROTOCOL_VERSION);
+    for (name, value) in [
+        ("STREAM_EVENT", STREAM_EVENT),
+        ("STREAM_STATE_COMMIT", STREAM_STATE_COMMIT),
+        ("STREAM_ACTION", STREAM_ACTION),
+        ("STREAM_AUTH", STREAM_AUTH),
+        ("STREAM_CONTROL", STREAM_CONTROL),
+        ("STREAM_PAYLOAD", STREAM_PAYLOAD),
+        ("STREAM_HEARTBEAT", STREAM_HEARTBEAT),
+        ("STREAM_ACTION_PUSH", STREAM_ACTION_PUSH),
+        ("FLAG_HEADERS", FLAG_HEADERS),
+    ] {
+        out += &format!("{}={:#04x}\n", name, value);
+    }
+    for (name, value) in [
+        ("QUOTA_EXCEEDED", QUOTA_EXCEEDED),
+        ("ABORT_COMMIT", ABORT_COMMIT),
+        ("ABORT_REFUSED", ABORT_REFUSED),
+    ] {
+        out += &format!("{}={:#010x}\n", name, value);
+    }
+    out += &format!("PAYLOAD_HEADER_LEN={}\n", PAYLOAD_HEADER_LEN);
+    out += &format!("HEARTBEAT_PING={:#04x}\n", HEARTBEAT_PING);
+    out += &format!("HEARTBEAT_PONG={:#04x}\n", HEARTBEAT_PONG);
+    out += &format!("HEARTBEAT_FRAME_LEN={}\n", HEARTBEAT_FRAME_LEN);
+    out += &format!("MAX_FRAME_LEN={}\n", MAX_FRAME_LEN);
+    out += &format!("MESSAGE_VERSION={:#04x}\n", MESSAGE_VERSION);
+    out += &format!("DATAGRAM_KEEPALIVE={:#04x}\n", DATAGRAM_KEEPALIVE);
+    out += &format!("DATAGRAM_TELEMETRY={:#04x}\n", DATAGRAM_TELEMETRY);
+    for (name, value) in [
+        ("CLOSE_NORMAL", CLOSE_NORMAL),
+        ("CLOSE_STREAM_SETUP", CLOSE_STREAM_SETUP),
+        ("CLOSE_STREAM_ACCEPT", CLOSE_STREAM_ACCEPT),
+        ("CLOSE_SETUP_TIMEOUT", CLOSE_SETUP_TIMEOUT),
+        ("CLOSE_STREAM_TIMEOUT", CLOSE_STREAM_TIMEOUT),
+        ("CLOSE_STREAM_ERROR", CLOSE_STREAM_ERROR),
+        ("CLOSE_AUTH_FAILED", CLOSE_AUTH_FAILED),
+        ("CLOSE_PREEMPTED", CLOSE_PREEMPTED),
+        ("CLOSE_AT_CAPACITY", CLOSE_AT_CAPACITY),
+        ("CLOSE_BY_OPERATOR", CLOSE_BY_OPERATOR),
+        ("RESET_BY_MISBEHAVIOR", RESET_BY_MISBEHAVIOR),
+        ("CLOSE_REPLACED", CLOSE_REPLACED),
+        ("CLOSE_DUPLICATE", CLOSE_DUPLICATE),
+        ("CLOSE_PROTOCOL_VIOLATION", CLOSE_PROTOCOL_VIOLATION),
+        ("CLOSE_PEER_STALLED", CLOSE_PEER_STALLED),
+        ("CLOSE_HEARTBEAT_LOST", CLOSE_HEARTBEAT_LOST),
+        ("CLOSE_SERVER_RESTARTING", CLOSE_SERVER_RESTARTING),
+        ("CLOSE_MAX_AGE", CLOSE_MAX_AGE),
+    ] {
+        out += &format!("{}={}\n", name, value);
+    }
+    for (name, value) in [
+        ("KEY_USER_AGENT", KEY_USER_AGENT),
+        ("KEY_CRATE_VERSION", KEY_CRATE_VERSION),
+        ("KEY_PROTOCOL", KEY_PROTOCOL),
+        ("KEY_OS", KEY_OS),
+        ("KEY_ARCH", KEY_ARCH),
+        ("KEY_PRIORITY", KEY_PRIORITY),
+        ("KEY_TENANT", KEY_TENANT),
+        ("KEY_CLIENT_ID", KEY_CLIENT_ID),
+        ("KEY_EVENT_ORDERING", KEY_EVENT_ORDERING),
+        ("KEY_ID_SCHEME", KEY_ID_SCHEME),
+        ("KEY_FRAMING", KEY_FRAMING),
+        ("KEY_EXPERIMENT", KEY_EXPERIMENT),
+        ("KEY_COMPRESSION", KEY_COMPRESSION),
+        ("KEY_EVENT_WINDOW", KEY_EVENT_WINDOW),
+        ("KEY_HEARTBEAT", KEY_HEARTBEAT),
+        ("KEY_ACTION_PUSH", KEY_ACTION_PUSH),
+        ("KEY_LAST_EVENT_ID", KEY_LAST_EVENT_ID),
+        ("KEY_REPLAY_FROM", KEY_REPLAY_FROM),
+        ("KEY_EVENT_BATCH_SIZE", KEY_EVENT_BATCH_SIZE),
+        ("KEY_EVENT_RATE_LIMIT", KEY_EVENT_RATE_LIMIT),
+        ("KEY_ACK_MODE", KEY_ACK_MODE),
+        ("KEY_POWER_MODE", KEY_POWER_MODE),
+        ("KEY_DICTIONARY_ID", KEY_DICTIONARY_ID),
+        ("KEY_DICTIONARY", KEY_DICTIONARY),
+        ("KEY_GOAWAY", KEY_GOAWAY),
+    ] {
+        out += &format!("{}={}\n", name, value);
+    }
+    out += &format!("DICTIONARY_NONE={:#04x}\n", DICTIONARY_NONE);
+    out += &format!("SCHEMA_VERSION_LEN={}\n", SCHEMA_VERSION_LEN);
+    out
+}
+
+#[test]
+fn constants_match_snapshot() {
+    assert_eq!(current(), SNAPSHOT);
+}
+
+#[test]
+fn close_codes_are_distinct() {
+    let mut codes = [
+        CLOSE_NORMAL,
+        CLOSE_STREAM_SETUP,
+        CLOSE_STREAM_ACCEPT,
+        CLOSE_SETUP_TIMEOUT,
+        CLOSE_STREAM_TIMEOUT,
+        CLOSE_STREAM_ERROR,
+        CLOSE_AUTH_FAILED,
+        CLOSE_PREEMPTED,
+        CLOSE_AT_CAPACITY,
+        CLOSE_BY_OPERATOR,
+        RESET_BY_MISBEHAVIOR,
+        CLOSE_REPLACED,
+        CLOSE_DUPLICATE,
+        CLOSE_PROTOCOL_VIOLATION,
+        CLOSE_PEER_STALLED,
+        CLOSE_HEARTBEAT_LOST,
+        CLOSE_SERVER_RESTARTING,
+        CLOSE_MAX_AGE,
+    ];
+    codes.sort_unstable();
+    assert!(codes.windows(2).all(|pair| pair[0] != pair[1]));
+}
+
+#[test]
+fn close_reasons_round_trip() {
+    use quic_rs_debug::proton::CloseReason;
+    assert_eq!(CloseReason::AtCapacity.code(), CLOSE_AT_CAPACITY);
+    assert_eq!(
+        CloseReason::from_code(CLOSE_SERVER_RESTARTING),
+        CloseReason::ServerRestarting
+    );
+    assert_eq!(
+        CloseReason::from_code(RESET_BY_MISBEHAVIOR),
+        CloseReason::Unknown(RESET_BY_MISBEHAVIOR)
+    );
+    for code in 0..=32 {
+        assert_eq!(CloseReason::from_code(code).code(), code);
+    }
+    assert_eq!(
+        CloseReason::Preempted.to_string(),
+        format!("preempted (code {})", CLOSE_PREEMPTED)
+    );
+}
+
+#[test]
+fn discriminator_bytes() {
+    assert_eq!(encode_discriminator(STREAM_EVENT, false), 0x01);
+    assert_eq!(encode_discriminator(STREAM_PAYLOAD, true), 0x86);
+    assert_eq!(decode_discriminator(0x82), (STREAM_STATE_COMMIT, true));
+    assert_eq!(decode_discriminator(0x05), (STREAM_CONTROL, false));
+}
+
+#[test]
+fn frame_bytes() {
+    assert_eq!(encode_u32(0x0403_0201), [1, 2, 3, 4]);
+    assert_eq!(decode_u32([1, 2, 3, 4]), 0x0403_0201);
+    assert_eq!(decode_response([7, 0, 0, 0]).unwrap(), 7);
+    assert!(decode_response([0xff; 4]).is_err());
+}
+
+#[test]
+fn commit_frames() {
+    assert_eq!(encode_commit(5, false), 5);
+    assert_eq!(encode_commit(5, true), 0x8000_0005);
+    assert_eq!(decode_commit(0x8000_0005), (5, true));
+    assert_eq!(decode_commit(5), (5, false));
+}
+
+#[test]
+fn payload_header_bytes() {
+    let header = encode_payload_header(0x0102_0304, 0x0a0b_0c0d_0e0f);
+    assert_eq!(
+        header,
+        [4, 3, 2, 1, 0x0f, 0x0e, 0x0d, 0x0c, 0x0b, 0x0a, 0, 0]
+    );
+    assert_eq!(
+        decode_payload_header(header),
+        (0x0102_0304, 0x0a0b_0c0d_0e0f)
+    );
+}
+
+#[test]
+fn length_prefixed_frame_bytes() {
+    use quic_rs_debug::proton::Frame;
+    let frame = Frame::with_payload(7, b"hi".to_vec());
+    let bytes = frame.encode();
+    assert_eq!(bytes, [6, 0, 0, 0, 7, 0, 0, 0, b'h', b'i']);
+    assert_eq!(Frame::decode(&bytes).unwrap(), (frame, bytes.len()));
+    assert!(Frame::decode(&bytes[..9]).is_err());
+    assert!(Frame::decode(&[3, 0, 0, 0, 7, 0, 0]).is_err());
+}
+
+#[test]
+fn versioned_payload_bytes() {
+    let payload = encode_versioned(0x0102, b"hi");
+    assert_eq!(payload, [2, 1, b'h', b'i']);
+    assert_eq!(decode_versioned(&payload).unwrap(), (0x0102, &b"hi"[..]));
+    assert!(decode_versioned(&[2]).is_err());
+}
+
+#[test]
+fn typed_message_bytes() {
+    use quic_rs_debug::proton::{Event, Frame, Framing, Message, MessageKind};
+    let event = Frame::with_payload(7, b"hi".to_vec());
+    let mut bytes = Vec::new();
+    Framing::Typed
+        .encode_into(MessageKind::Event, &event, &mut bytes)
+        .unwrap();
+    assert_eq!(
+        bytes,
+        [19, 0, 0, 0, 1, 0, 0, 0, 0, 7, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']
+    );
+    assert_eq!(
+        Framing::Typed.encoded_len(MessageKind::Event, &event),
+        bytes.len()
+    );
+    let message = Message::Event(Event {
+        id: 7,
+        payload: b"hi".to_vec(),
+    });
+    assert_eq!(Message::decode(&bytes).unwrap(), (message, bytes.len()));
+
+    let abort = Frame::new(encode_commit(5, true));
+    let mut bytes = Vec::new();
+    Framing::Typed
+        .encode_into(MessageKind::StateCommit, &abort, &mut bytes)
+        .unwrap();
+    assert_eq!(
+        bytes,
+        [18, 0, 0, 0, 1, 1, 0, 0, 0, 5, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        Framing::Typed
+            .decode(MessageKind::StateCommit, &bytes)
+            .unwrap(),
+        (abort, bytes.len())
+    );
+
+    let ack = [9, 0, 0, 0, 1, 3, 0, 0, 0, 9, 0, 0, 0];
+    assert_eq!(
+        Framing::Typed.decode(MessageKind::Ack, &ack).unwrap(),
+        (Frame::new(9), ack.len())
+    );
+    assert_eq!(
+        Framing::Typed.encoded_len(MessageKind::Ack, &Frame::new(9)),
+        ack.len()
+    );
+}
+
+#[test]
+fn typed_messages_are_checked() {
+    use quic_rs_debug::proton::{Frame, Framing, MessageKind};
+    let ack = [9, 0, 0, 0, 1, 3, 0, 0, 0, 9, 0, 0, 0];
+    // Another kind than the stream carries
+    assert!(Framing::Typed.decode(MessageKind::Action, &ack).is_err());
+    // An unknown version
+    let mut future = ack;
+    future[4] = 2;
+    assert!(Framing::Typed.decode(MessageKind::Ack, &future).is_err());
+    // Bytes left over in the body
+    let long = [10, 0, 0, 0, 1, 3, 0, 0, 0, 9, 0, 0, 0, 0];
+    assert!(Framing::Typed.decode(MessageKind::Ack, &long).is_err());
+    assert!(Framing::Typed.decode(MessageKind::Ack, &ack[..12]).is_err());
+    // Acks have no room for a payload
+    let mut out = Vec::new();
+    assert!(Framing::Typed
+        .encode_into(MessageKind::Ack, &Frame::with_payload(1, vec![1]), &mut out)
+        .is_err());
+}